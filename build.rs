@@ -0,0 +1,38 @@
+//! Links the kernel with our own linker script, `linker.ld`, see `src/sections.rs`, and builds
+//! the disk the test kernels boot with.
+//!
+//! The disk is a FAT32 image of `tests/fixtures/disk`, written to `target/test-disk.img` where
+//! `Cargo.toml` hands it to QEMU as a virtio disk. If `RUST_OS_INITRAMFS` names a file, it goes
+//! in as well (see `mkfs::INITRAMFS_PATH`).
+
+use std::env;
+use std::path::PathBuf;
+
+const FIXTURE: &str = "tests/fixtures/disk";
+
+fn main() {
+    println!("cargo:rerun-if-changed=linker.ld");
+    // only the kernel itself is laid out by it; a build for the host keeps the host's layout
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rustc-link-arg=-T{}/linker.ld", dir);
+        test_disk(PathBuf::from(dir));
+    }
+}
+
+fn test_disk(dir: PathBuf) {
+    println!("cargo:rerun-if-changed={}", FIXTURE);
+    println!("cargo:rerun-if-env-changed=RUST_OS_INITRAMFS");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let mut tree = mkfs::Tree::read(&dir.join(FIXTURE)).expect("failed to read the test disk");
+    if let Some(initramfs) = env::var_os("RUST_OS_INITRAMFS") {
+        let contents = std::fs::read(initramfs).expect("failed to read RUST_OS_INITRAMFS");
+        tree.insert_initramfs(contents)
+            .expect("failed to put the initramfs on the test disk");
+    }
+    let options = mkfs::Options::new(mkfs::Format::Fat32);
+    let image = mkfs::build(&tree, &options).expect("failed to build the test disk");
+    let target = env::var_os("CARGO_TARGET_DIR").map_or(dir.join("target"), PathBuf::from);
+    std::fs::create_dir_all(&target).expect("failed to create the target directory");
+    mkfs::write(&target.join("test-disk.img"), &image).expect("failed to write the test disk");
+}
@@ -0,0 +1,132 @@
+//! The manifest of what this kernel offers programs: its ABI version, its system calls by number
+//! and its optional features.
+//!
+//! The manifest is put together by the compiler from the system call table, so it can't drift
+//! from what the kernel actually does. Programs get it with the `abi` system call, people from
+//! `/proc/abi`. It's plain text, one item per line, fields separated by spaces:
+//!
+//! ```text
+//! rust_os-abi 1
+//! kernel 0.1.0
+//! syscall 0 read
+//! ...
+//! feature debug-assertions
+//! ```
+//!
+//! The first line is the one to check: a program built for another ABI version must not go on.
+//! `VERSION` goes up whenever an existing call changes meaning; new calls and features alone
+//! don't need it, a program can look for them.
+
+use crate::{signing, syscall};
+
+/// The ABI version; see the module documentation for when it changes
+pub const VERSION: u32 = 1;
+
+/// The manifest's first line, what a program compares against what it was built for
+pub const HEADER: &str = "rust_os-abi 1\n";
+
+/// Optional features of this build
+const FEATURES: &[(&str, bool)] = &[
+    ("debug-assertions", cfg!(debug_assertions)),
+    ("fat32", cfg!(feature = "fat32")),
+    ("gdbstub", cfg!(feature = "gdbstub")),
+    ("gfx", cfg!(feature = "gfx")),
+    ("net", cfg!(feature = "net")),
+    ("p9", cfg!(feature = "p9")),
+    ("sfs", cfg!(feature = "sfs")),
+    ("signing-key", signing::HAS_KEY),
+    ("smp", cfg!(feature = "smp")),
+];
+
+/// Collects the manifest's bytes; with `N` 0 it only counts them
+struct Writer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Writer<N> {
+    const fn str(mut self, text: &str) -> Self {
+        let text = text.as_bytes();
+        let mut index = 0;
+        while index < text.len() {
+            if N > 0 {
+                self.bytes[self.len] = text[index];
+            }
+            self.len += 1;
+            index += 1;
+        }
+        self
+    }
+
+    const fn number(mut self, mut value: usize) -> Self {
+        let mut digits = [0u8; 20];
+        let mut count = 0;
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        while count > 0 {
+            count -= 1;
+            if N > 0 {
+                self.bytes[self.len] = digits[count];
+            }
+            self.len += 1;
+        }
+        self
+    }
+}
+
+const fn write<const N: usize>() -> Writer<N> {
+    let mut writer = Writer {
+        bytes: [0; N],
+        len: 0,
+    }
+    .str("rust_os-abi ")
+    .number(VERSION as usize)
+    .str("\nkernel ")
+    .str(env!("CARGO_PKG_VERSION"))
+    .str("\n");
+    let mut number = 0;
+    while number < syscall::NAMES.len() {
+        writer = writer
+            .str("syscall ")
+            .number(number)
+            .str(" ")
+            .str(syscall::NAMES[number])
+            .str("\n");
+        number += 1;
+    }
+    let mut index = 0;
+    while index < FEATURES.len() {
+        if FEATURES[index].1 {
+            writer = writer.str("feature ").str(FEATURES[index].0).str("\n");
+        }
+        index += 1;
+    }
+    writer
+}
+
+const LEN: usize = write::<0>().len;
+const BYTES: [u8; LEN] = write::<LEN>().bytes;
+
+/// The whole manifest
+pub const MANIFEST: &str = match core::str::from_utf8(&BYTES) {
+    Ok(manifest) => manifest,
+    Err(_) => panic!("the ABI manifest isn't UTF-8"),
+};
+
+const _: () = {
+    let (header, manifest) = (HEADER.as_bytes(), MANIFEST.as_bytes());
+    let mut index = 0;
+    while index < header.len() {
+        assert!(
+            header[index] == manifest[index],
+            "HEADER doesn't match VERSION"
+        );
+        index += 1;
+    }
+};
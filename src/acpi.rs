@@ -0,0 +1,650 @@
+//! Discovery of the ACPI tables the firmware leaves in memory.
+//!
+//! Tables are read in place through the physical memory mapping; nothing here writes to them.
+//! The tables other subsystems need are parsed once, on first use, and cached for the lifetime of
+//! the kernel: see `madt`, `fadt`, `hpet`, `srat`, `slit` and `ecdt`. The DSDT holds AML, which
+//! isn't interpreted; `sleep_type` and `smbus_hc_base` pick the few constants out of it the kernel
+//! needs.
+
+use crate::bootinfo;
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// Common header in front of every System Description Table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: u64 = core::mem::size_of::<SdtHeader>() as u64;
+
+/// Reads a `T` from physical memory, which may sit at any alignment
+///
+/// # Safety
+///
+/// `addr..addr + size_of::<T>()` must be mapped physical memory holding a valid `T`.
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    core::ptr::read_unaligned(phys_to_virt(addr).as_ptr())
+}
+
+/// ACPI structures are valid when all their bytes sum to zero
+fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    let sum = (0..len).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read_phys::<u8>(addr + i) })
+    });
+    sum == 0
+}
+
+/// The Root System Description Pointer the loader passed, or else the one in the places BIOS
+/// firmware may put it: the first KiB of the Extended BIOS Data Area and the read-only BIOS area
+/// below 1 MiB
+fn find_rsdp() -> Option<PhysAddr> {
+    let given = bootinfo::get().and_then(|info| info.rsdp);
+    if let Some(rsdp) = given.filter(|&rsdp| checksum_ok(rsdp, 20)) {
+        return Some(rsdp);
+    }
+    let ebda = (unsafe { read_phys::<u16>(PhysAddr::new(0x40E)) } as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE_0000, 0x10_0000)];
+
+    areas
+        .iter()
+        .filter(|(start, _)| *start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16)) // the RSDP is 16-byte aligned
+        .map(PhysAddr::new)
+        .find(
+            |&addr| unsafe { read_phys::<[u8; 8]>(addr) } == *b"RSD PTR " && checksum_ok(addr, 20),
+        )
+}
+
+/// Returns the physical addresses of every table the RSDT/XSDT lists
+pub fn tables() -> Vec<PhysAddr> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return Vec::new(),
+    };
+    let revision: u8 = unsafe { read_phys(rsdp + 15u64) };
+    let xsdt: u64 = if revision >= 2 {
+        unsafe { read_phys(rsdp + 24u64) }
+    } else {
+        0
+    };
+
+    // ACPI 2.0+ firmware provides the XSDT with 64-bit pointers; prefer it over the RSDT
+    let (root, entry_size) = if xsdt != 0 {
+        (PhysAddr::new(xsdt), 8)
+    } else {
+        let rsdt: u32 = unsafe { read_phys(rsdp + 16u64) };
+        (PhysAddr::new(rsdt as u64), 4)
+    };
+    let header: SdtHeader = unsafe { read_phys(root) };
+    if !checksum_ok(root, header.length as u64) {
+        return Vec::new();
+    }
+
+    let entries = (header.length as u64 - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE + i * entry_size;
+            match entry_size {
+                8 => PhysAddr::new(unsafe { read_phys::<u64>(entry) }),
+                _ => PhysAddr::new(unsafe { read_phys::<u32>(entry) } as u64),
+            }
+        })
+        .collect()
+}
+
+/// Finds the first valid table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    tables().into_iter().find(|&addr| {
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        header.signature == *signature && checksum_ok(addr, header.length as u64)
+    })
+}
+
+/// A processor entry of the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u32,
+    /// Disabled processors may not be started
+    pub enabled: bool,
+}
+
+/// An IO-APIC entry of the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    /// First global system interrupt (GSI) this IO-APIC handles
+    pub gsi_base: u32,
+}
+
+/// An Interrupt Source Override: ISA interrupt `source` is wired to `gsi` instead of the GSI with
+/// the same number, possibly with non-ISA polarity or trigger mode
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The parts of the Multiple APIC Description Table ("APIC") the kernel uses
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    /// Set when the board also has 8259 PICs, which must be masked before using the APIC
+    pub has_legacy_pics: bool,
+}
+
+impl Madt {
+    pub fn parse() -> Option<Madt> {
+        let addr = find_table(b"APIC")?;
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        let local_apic_address: u32 = unsafe { read_phys(addr + SDT_HEADER_SIZE) };
+        let flags: u32 = unsafe { read_phys(addr + SDT_HEADER_SIZE + 4u64) };
+        let mut madt = Madt {
+            local_apic_address: local_apic_address as u64,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            has_legacy_pics: flags & 1 != 0,
+        };
+
+        // variable-length entries follow the 4-byte address and 4-byte flags
+        let end = addr + header.length as u64;
+        let mut entry = addr + SDT_HEADER_SIZE + 8u64;
+        while entry + 2u64 <= end {
+            let kind: u8 = unsafe { read_phys(entry) };
+            let len: u8 = unsafe { read_phys(entry + 1u64) };
+            if len < 2 {
+                break; // malformed, avoid looping forever
+            }
+            match kind {
+                0 => {
+                    let flags: u32 = unsafe { read_phys(entry + 4u64) };
+                    madt.processors.push(Processor {
+                        acpi_id: unsafe { read_phys(entry + 2u64) },
+                        apic_id: unsafe { read_phys::<u8>(entry + 3u64) } as u32,
+                        enabled: flags & 1 != 0,
+                    });
+                }
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: unsafe { read_phys(entry + 2u64) },
+                    address: unsafe { read_phys::<u32>(entry + 4u64) } as u64,
+                    gsi_base: unsafe { read_phys(entry + 8u64) },
+                }),
+                2 => {
+                    // polarity and trigger mode are 2-bit fields; 0b00 means "as the bus says"
+                    let flags: u16 = unsafe { read_phys(entry + 8u64) };
+                    madt.overrides.push(InterruptOverride {
+                        source: unsafe { read_phys(entry + 3u64) },
+                        gsi: unsafe { read_phys(entry + 4u64) },
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+                5 => madt.local_apic_address = unsafe { read_phys(entry + 4u64) },
+                9 => {
+                    // x2APIC processor, used for APIC ids above 254
+                    let flags: u32 = unsafe { read_phys(entry + 8u64) };
+                    madt.processors.push(Processor {
+                        acpi_id: 0,
+                        apic_id: unsafe { read_phys(entry + 4u64) },
+                        enabled: flags & 1 != 0,
+                    });
+                }
+                _ => {}
+            }
+            entry += len as u64;
+        }
+        Some(madt)
+    }
+
+    /// Where ISA interrupt `irq` arrives, taking the source overrides into account
+    pub fn isa_route(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.source == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                source: irq,
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+/// A register described by an ACPI Generic Address Structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenericAddress {
+    Memory(u64),
+    Io(u16),
+}
+
+impl GenericAddress {
+    /// # Safety
+    ///
+    /// `addr` must point at a 12-byte Generic Address Structure.
+    unsafe fn read(addr: PhysAddr) -> Option<GenericAddress> {
+        let space: u8 = read_phys(addr);
+        let address: u64 = read_phys(addr + 4u64);
+        match (space, address) {
+            (_, 0) => None,
+            (0, address) => Some(GenericAddress::Memory(address)),
+            (1, address) => Some(GenericAddress::Io(address as u16)),
+            _ => None, // PCI configuration space and friends aren't used by the kernel
+        }
+    }
+}
+
+/// The parts of the Fixed ACPI Description Table ("FACP") the kernel uses
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Interrupt line of the System Control Interrupt (ISA numbering)
+    pub sci_interrupt: u16,
+    /// Port that switches the chipset between legacy and ACPI mode, 0 when it's always in ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// Physical address of the Firmware ACPI Control Structure, which holds the waking vector
+    pub firmware_ctrl: Option<PhysAddr>,
+    /// Physical address of the Differentiated System Description Table
+    pub dsdt: Option<PhysAddr>,
+    /// I/O ports of the PM1 event blocks; the status register is the first half of each
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    /// Size of each PM1 event block in bytes: the status register, then the enable register
+    pub pm1_event_length: u8,
+    /// I/O ports of the general-purpose event blocks, laid out the same way, 0 if absent
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub gpe0_block_length: u8,
+    pub gpe1_block_length: u8,
+    /// Number of the first event in GPE1; GPE0's start at 0
+    pub gpe1_base: u8,
+    /// Whether the power and sleep buttons are fixed features, reported in the PM1 registers,
+    /// rather than devices in the namespace (or missing)
+    pub fixed_power_button: bool,
+    pub fixed_sleep_button: bool,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    /// I/O port of the 3.579545 MHz ACPI power management timer
+    pub pm_timer_block: u32,
+    /// Whether the PM timer counts with 32 bits instead of 24
+    pub pm_timer_32bit: bool,
+    /// CMOS index of the RTC century register, 0 if there's none
+    pub century_register: u8,
+    /// IA-PC boot architecture flags, e.g. whether an 8042 keyboard controller is present
+    pub boot_architecture_flags: u16,
+    /// Register and value that reset the machine, if the firmware supports it
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// IA-PC boot architecture flag set when the board has an 8042 (PS/2) controller
+    pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+    pub fn parse() -> Option<Fadt> {
+        let addr = find_table(b"FACP")?;
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        let len = header.length as u64;
+        // fields past the ACPI 1.0 layout are only valid if the table is long enough to hold them
+        let flags: u32 = unsafe { read_phys(addr + 112u64) };
+        let reset = if len >= 129 && flags & (1 << 10) != 0 {
+            let value: u8 = unsafe { read_phys(addr + 128u64) };
+            unsafe { GenericAddress::read(addr + 116u64) }.map(|register| (register, value))
+        } else {
+            None
+        };
+
+        // the 64-bit X_ fields win over the 32-bit ones where the table has them and they're set
+        let pointer = |offset: u64, x_offset: u64| {
+            let x_address: u64 = match len >= x_offset + 8 {
+                true => unsafe { read_phys(addr + x_offset) },
+                false => 0,
+            };
+            let address: u32 = unsafe { read_phys(addr + offset) };
+            match (x_address, address) {
+                (0, 0) => None,
+                (0, address) => Some(PhysAddr::new(address as u64)),
+                (x_address, _) => Some(PhysAddr::new(x_address)),
+            }
+        };
+
+        unsafe {
+            Some(Fadt {
+                sci_interrupt: read_phys(addr + 46u64),
+                smi_command_port: read_phys(addr + 48u64),
+                acpi_enable: read_phys(addr + 52u64),
+                acpi_disable: read_phys(addr + 53u64),
+                firmware_ctrl: pointer(36, 132),
+                dsdt: pointer(40, 140),
+                pm1a_event_block: read_phys(addr + 56u64),
+                pm1b_event_block: read_phys(addr + 60u64),
+                pm1_event_length: read_phys(addr + 88u64),
+                gpe0_block: read_phys(addr + 80u64),
+                gpe1_block: read_phys(addr + 84u64),
+                gpe0_block_length: read_phys(addr + 92u64),
+                gpe1_block_length: read_phys(addr + 93u64),
+                gpe1_base: read_phys(addr + 94u64),
+                fixed_power_button: flags & (1 << 4) == 0,
+                fixed_sleep_button: flags & (1 << 5) == 0,
+                pm1a_control_block: read_phys(addr + 64u64),
+                pm1b_control_block: read_phys(addr + 68u64),
+                pm_timer_block: read_phys(addr + 76u64),
+                pm_timer_32bit: flags & (1 << 8) != 0,
+                century_register: read_phys(addr + 108u64),
+                boot_architecture_flags: if header.revision >= 2 {
+                    read_phys(addr + 109u64)
+                } else {
+                    Self::BOOT_ARCH_8042 // ACPI 1.0 predates the flag, assume a PC/AT
+                },
+                reset,
+            })
+        }
+    }
+}
+
+/// The `SLP_TYPa` and `SLP_TYPb` values that put the machine into sleep state `state` (3 for
+/// S3), from the `\_Sx_` package in the DSDT.
+///
+/// There's no AML interpreter, so this looks for the package's bytes rather than evaluating the
+/// object. That finds it where firmware declares it the usual way, as a constant package at the
+/// top level; one built at run time, or declared in an SSDT, isn't found.
+pub fn sleep_type(state: u8) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+
+    let aml = dsdt_aml()?;
+    let name = [b'_', b'S', b'0' + state, b'_'];
+
+    (1..aml.len().saturating_sub(3)).find_map(|at| {
+        if aml[at..at + 4] != name {
+            return None;
+        }
+        // `Name (_S3, ...)` or `Name (\_S3, ...)`
+        let op = match aml[at - 1] {
+            b'\\' if at >= 2 => aml[at - 2],
+            op => op,
+        };
+        let package = &aml[at + 4..];
+        if op != NAME_OP || package.first() != Some(&PACKAGE_OP) {
+            return None;
+        }
+        // the package length's first byte says how many more bytes it has, then comes the count
+        let length_bytes = 1 + (*package.get(1)? >> 6) as usize;
+        let mut elements = package.get(2 + length_bytes..)?.iter().copied();
+        Some((aml_byte(&mut elements)?, aml_byte(&mut elements)?))
+    })
+}
+
+/// Where the SMBus host controller's registers start in embedded controller space, the upper byte
+/// of its `_EC` object (the lower one is its query number).
+///
+/// Found the way `sleep_type` finds its package: as `Name (_EC, <word>)` anywhere in the DSDT,
+/// which is how firmware declares it. Only the SMBus host controller device has an `_EC`.
+pub fn smbus_hc_base() -> Option<u8> {
+    const NAME_OP: u8 = 0x08;
+    const WORD_PREFIX: u8 = 0x0B;
+
+    let aml = dsdt_aml()?;
+    aml.windows(8).find_map(|window| match window {
+        [NAME_OP, b'_', b'E', b'C', b'_', WORD_PREFIX, _query, base] => Some(*base),
+        _ => None,
+    })
+}
+
+/// The AML of the DSDT, if the FADT points at a valid one
+fn dsdt_aml() -> Option<Vec<u8>> {
+    let dsdt = fadt()?.dsdt?;
+    let header: SdtHeader = unsafe { read_phys(dsdt) };
+    if header.signature != *b"DSDT" || !checksum_ok(dsdt, header.length as u64) {
+        return None;
+    }
+    Some(
+        (SDT_HEADER_SIZE..header.length as u64)
+            .map(|offset| unsafe { read_phys(dsdt + offset) })
+            .collect(),
+    )
+}
+
+/// Reads an AML integer that fits in a byte, which the elements of a sleep package are
+fn aml_byte(aml: &mut impl Iterator<Item = u8>) -> Option<u8> {
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    match aml.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => aml.next(),
+        _ => None,
+    }
+}
+
+/// The High Precision Event Timer description table ("HPET")
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// Physical address of the HPET register block
+    pub address: u64,
+    pub hpet_number: u8,
+    /// Smallest period, in main counter ticks, the timers can be programmed to in periodic mode
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse() -> Option<Hpet> {
+        let addr = find_table(b"HPET")?;
+        match unsafe { GenericAddress::read(addr + SDT_HEADER_SIZE + 4u64) } {
+            Some(GenericAddress::Memory(address)) => unsafe {
+                Some(Hpet {
+                    address,
+                    hpet_number: read_phys(addr + SDT_HEADER_SIZE + 16u64),
+                    minimum_tick: read_phys(addr + SDT_HEADER_SIZE + 17u64),
+                })
+            },
+            _ => None, // an HPET is always memory mapped
+        }
+    }
+}
+
+/// The Embedded Controller Boot Resources Table ("ECDT"): the embedded controller's ports, so it
+/// can be used without finding its device in the namespace
+#[derive(Debug, Clone, Copy)]
+pub struct Ecdt {
+    /// Port of the command register when written, the status register when read
+    pub command_port: u16,
+    pub data_port: u16,
+}
+
+impl Ecdt {
+    pub fn parse() -> Option<Ecdt> {
+        let addr = find_table(b"ECDT")?;
+        let control = unsafe { GenericAddress::read(addr + SDT_HEADER_SIZE) };
+        let data = unsafe { GenericAddress::read(addr + SDT_HEADER_SIZE + 12u64) };
+        match (control, data) {
+            (Some(GenericAddress::Io(command_port)), Some(GenericAddress::Io(data_port))) => {
+                Some(Ecdt {
+                    command_port,
+                    data_port,
+                })
+            }
+            _ => None, // an embedded controller is always on I/O ports
+        }
+    }
+}
+
+/// A processor's entry in the SRAT
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorAffinity {
+    pub apic_id: u32,
+    pub proximity_domain: u32,
+}
+
+/// A memory range's entry in the SRAT
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    pub base: u64,
+    pub length: u64,
+    pub proximity_domain: u32,
+    pub hot_pluggable: bool,
+}
+
+/// The System Resource Affinity Table ("SRAT"): which proximity domain, i.e. NUMA node, each
+/// processor and memory range belongs to. Disabled entries are left out.
+#[derive(Debug, Clone)]
+pub struct Srat {
+    pub processors: Vec<ProcessorAffinity>,
+    pub memory: Vec<MemoryAffinity>,
+}
+
+impl Srat {
+    pub fn parse() -> Option<Srat> {
+        let addr = find_table(b"SRAT")?;
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        let mut srat = Srat {
+            processors: Vec::new(),
+            memory: Vec::new(),
+        };
+
+        // entries follow 12 reserved bytes
+        let end = addr + header.length as u64;
+        let mut entry = addr + SDT_HEADER_SIZE + 12u64;
+        while entry + 2u64 <= end {
+            let kind: u8 = unsafe { read_phys(entry) };
+            let len: u8 = unsafe { read_phys(entry + 1u64) };
+            if len < 2 {
+                break;
+            }
+            match kind {
+                0 => {
+                    let flags: u32 = unsafe { read_phys(entry + 4u64) };
+                    // the domain's low byte comes first, its upper three bytes further on
+                    let low: u8 = unsafe { read_phys(entry + 2u64) };
+                    let high: [u8; 3] = unsafe { read_phys(entry + 9u64) };
+                    if flags & 1 != 0 {
+                        srat.processors.push(ProcessorAffinity {
+                            apic_id: unsafe { read_phys::<u8>(entry + 3u64) } as u32,
+                            proximity_domain: u32::from_le_bytes([low, high[0], high[1], high[2]]),
+                        });
+                    }
+                }
+                1 => {
+                    let flags: u32 = unsafe { read_phys(entry + 28u64) };
+                    if flags & 1 != 0 {
+                        srat.memory.push(MemoryAffinity {
+                            base: unsafe { read_phys(entry + 8u64) },
+                            length: unsafe { read_phys(entry + 16u64) },
+                            proximity_domain: unsafe { read_phys(entry + 2u64) },
+                            hot_pluggable: flags & 2 != 0,
+                        });
+                    }
+                }
+                2 => {
+                    // x2APIC processor
+                    let flags: u32 = unsafe { read_phys(entry + 12u64) };
+                    if flags & 1 != 0 {
+                        srat.processors.push(ProcessorAffinity {
+                            apic_id: unsafe { read_phys(entry + 8u64) },
+                            proximity_domain: unsafe { read_phys(entry + 4u64) },
+                        });
+                    }
+                }
+                _ => {}
+            }
+            entry += len as u64;
+        }
+        Some(srat)
+    }
+}
+
+/// The System Locality Information Table ("SLIT"): the relative cost of reaching one proximity
+/// domain's memory from another, 10 meaning local
+#[derive(Debug, Clone)]
+pub struct Slit {
+    pub localities: usize,
+    /// `localities` rows of `localities` distances, from the row's domain to the column's
+    pub distances: Vec<u8>,
+}
+
+impl Slit {
+    pub fn parse() -> Option<Slit> {
+        let addr = find_table(b"SLIT")?;
+        let header: SdtHeader = unsafe { read_phys(addr) };
+        let localities: u64 = unsafe { read_phys(addr + SDT_HEADER_SIZE) };
+        let matrix = addr + SDT_HEADER_SIZE + 8u64;
+        let size = localities.checked_mul(localities)?;
+        if SDT_HEADER_SIZE + 8 + size > header.length as u64 {
+            return None;
+        }
+        let distances = (0..size)
+            .map(|index| unsafe { read_phys::<u8>(matrix + index) })
+            .collect();
+        Some(Slit {
+            localities: localities as usize,
+            distances,
+        })
+    }
+
+    /// Distance from domain `from` to domain `to`, if the table covers both
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as usize, to as usize);
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+        Some(self.distances[from * self.localities + to])
+    }
+}
+
+static MADT: Once<Option<Madt>> = Once::new();
+static FADT: Once<Option<Fadt>> = Once::new();
+static HPET: Once<Option<Hpet>> = Once::new();
+static SRAT: Once<Option<Srat>> = Once::new();
+static SLIT: Once<Option<Slit>> = Once::new();
+static ECDT: Once<Option<Ecdt>> = Once::new();
+
+/// The MADT, parsed on first use. Physical memory must already be mapped.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.call_once(Madt::parse).as_ref()
+}
+
+/// The FADT, parsed on first use. Physical memory must already be mapped.
+pub fn fadt() -> Option<&'static Fadt> {
+    FADT.call_once(Fadt::parse).as_ref()
+}
+
+/// The HPET table, parsed on first use. Physical memory must already be mapped.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.call_once(Hpet::parse).as_ref()
+}
+
+/// The SRAT, parsed on first use. Physical memory must already be mapped.
+pub fn srat() -> Option<&'static Srat> {
+    SRAT.call_once(Srat::parse).as_ref()
+}
+
+/// The SLIT, parsed on first use. Physical memory must already be mapped.
+pub fn slit() -> Option<&'static Slit> {
+    SLIT.call_once(Slit::parse).as_ref()
+}
+
+/// The ECDT, parsed on first use. Physical memory must already be mapped.
+pub fn ecdt() -> Option<&'static Ecdt> {
+    ECDT.call_once(Ecdt::parse).as_ref()
+}
@@ -0,0 +1,302 @@
+use crate::memory::{self, layout};
+use crate::sync::watched::{LockState, WatchedGuard, WatchedMutex};
+use crate::{cmdline, println, sysinfo};
+use alloc::vec::Vec;
+pub use fixed_size_block::Stats;
+use fixed_size_block::{FixedSizeBlockAllocator, MAX_BLOCK_SIZES};
+use quota::Accounted;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+
+pub mod fixed_size_block;
+#[cfg(feature = "alloc-profile")]
+pub mod profile;
+pub mod quota;
+pub mod slab;
+
+pub const HEAP_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+#[global_allocator]
+static ALLOCATOR: Accounted<Locked<FixedSizeBlockAllocator>> =
+    Accounted::new(Locked::new("heap", FixedSizeBlockAllocator::new()));
+
+/// Maps the heap pages to fresh frames and hands the region to the global allocator; the heap
+/// starts where `memory::layout` says, and its block sizes are `heap_blocks` on the command line
+/// if it's there
+#[link_section = ".init.text"]
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    layout::init(HEAP_SIZE as u64);
+    let heap_start = layout::heap_start();
+    let mut mapper = memory::MAPPER.lock();
+    let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed), // memory::init hasn't run yet
+    };
+
+    let page_range = {
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
+        let heap_end_page = Page::<Size4KiB>::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    unsafe {
+        ALLOCATOR
+            .inner()
+            .lock()
+            .init(heap_start.as_u64() as usize, HEAP_SIZE);
+    }
+    if let Some(Err(err)) = cmdline::early_value("heap_blocks", set_block_sizes) {
+        println!(
+            "heap: heap_blocks: {}, keeping the default block sizes",
+            err
+        );
+    }
+
+    Ok(())
+}
+
+/// Parses `text`, block sizes separated by commas, and has the allocator use them; before the
+/// first allocation only
+#[link_section = ".init.text"]
+fn set_block_sizes(text: &str) -> Result<(), &'static str> {
+    let mut sizes = [0; MAX_BLOCK_SIZES];
+    let mut count = 0;
+    for size in text.split(',') {
+        let slot = sizes.get_mut(count).ok_or("too many block sizes")?;
+        *slot = size.parse().map_err(|_| "block sizes must be numbers")?;
+        count += 1;
+    }
+    interrupts::without_interrupts(|| ALLOCATOR.inner().lock().set_block_sizes(&sizes[..count]))
+}
+
+/// The block sizes, smallest first
+pub fn block_sizes() -> Vec<usize> {
+    let mut sizes = [0; MAX_BLOCK_SIZES];
+    let count = interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.inner().lock();
+        let count = allocator.block_sizes().len();
+        sizes[..count].copy_from_slice(allocator.block_sizes());
+        count
+    });
+    sizes[..count].to_vec()
+}
+
+/// The heap's lock, for the watchdog to keep an eye on
+pub fn lock_state() -> &'static LockState {
+    ALLOCATOR.inner().state()
+}
+
+/// Checks the heap's free lists, see `FixedSizeBlockAllocator::check`; `None` if the heap is
+/// locked at the moment
+pub fn check_free_lists() -> Option<Result<(), u64>> {
+    ALLOCATOR
+        .inner()
+        .try_lock()
+        .map(|allocator| allocator.check())
+}
+
+/// What the heap has done since boot, see `Stats`
+pub fn stats() -> Stats {
+    interrupts::without_interrupts(|| ALLOCATOR.inner().lock().stats())
+}
+
+/// Like `stats`, but `None` if the heap is locked at the moment
+pub fn try_stats() -> Option<Stats> {
+    interrupts::without_interrupts(|| {
+        ALLOCATOR
+            .inner()
+            .try_lock()
+            .map(|allocator| allocator.stats())
+    })
+}
+
+/// Turns the heap's debug mode on or off, see `FixedSizeBlockAllocator`
+pub fn set_debug(debug: bool) {
+    interrupts::without_interrupts(|| ALLOCATOR.inner().lock().set_debug(debug));
+}
+
+/// `mem [debug on|off | profile]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {}
+        ["debug", "on"] => set_debug(true),
+        ["debug", "off"] => set_debug(false),
+        ["profile"] => return print_profile(),
+        _ => return Err("usage: mem [debug on|off | profile]"),
+    }
+    let block_sizes = block_sizes();
+    // copied out first, as printing may allocate
+    let (stats, (size, free), debug) = interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.inner().lock();
+        (
+            allocator.stats(),
+            allocator.fallback_usage(),
+            allocator.is_debug(),
+        )
+    });
+    if let Some(memory) = sysinfo::memory() {
+        println!("ram: {}", memory);
+    }
+    println!(
+        "heap: {} KiB, {} KiB free outside the block lists",
+        size / 1024,
+        free / 1024
+    );
+    println!(
+        "in use: {} bytes, peak {} bytes; {} bytes allocated and {} freed since boot",
+        stats.in_use(),
+        stats.peak,
+        stats.allocated,
+        stats.freed
+    );
+    println!("{:>8}  ALLOCATIONS", "SIZE");
+    for (size, count) in block_sizes.iter().zip(stats.allocations) {
+        println!("{:>8}  {}", size, count);
+    }
+    println!("{:>8}  {}", "larger", stats.fallback_allocations());
+    println!("failed allocations: {}", stats.failures);
+    match debug {
+        true => println!(
+            "debug mode: on; {} double frees, {} freed blocks written to, the last at {:#x}",
+            stats.double_frees, stats.damaged_free_blocks, stats.last_bad_block
+        ),
+        false => println!("debug mode: off"),
+    }
+    memory::pressure::print();
+    for cache in slab::caches() {
+        let stats = cache.stats();
+        println!(
+            "slab {}: {} of {} objects of {} bytes in use, {} slabs",
+            cache.name(),
+            stats.in_use(),
+            stats.slots,
+            stats.object_size,
+            stats.slabs
+        );
+    }
+    Ok(())
+}
+
+/// Prints what `profile` counted: the most frequent allocation sizes, the bytes each block size
+/// wastes on the sizes it serves, and how long allocations lived
+#[cfg(feature = "alloc-profile")]
+fn print_profile() -> Result<(), &'static str> {
+    use profile::{bucket_size, LIFETIME_BUCKETS, SIZE_BUCKETS, SIZE_STEP};
+
+    // made before the heap is locked, as copying into them mustn't allocate
+    let mut sizes = Vec::with_capacity(SIZE_BUCKETS);
+    let mut lifetimes = Vec::with_capacity(LIFETIME_BUCKETS);
+    let (live, untracked) = interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.inner().lock();
+        sizes.extend_from_slice(allocator.profile().sizes());
+        lifetimes.extend_from_slice(allocator.profile().lifetimes());
+        allocator.profile().tracked()
+    });
+    let block_sizes = block_sizes();
+
+    let mut frequent: Vec<(usize, u64)> = sizes
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    frequent.sort_unstable_by_key(|&(_, count)| core::cmp::Reverse(count));
+    println!("most frequent sizes:");
+    for &(index, count) in frequent.iter().take(10) {
+        match bucket_size(index) {
+            Some(size) => println!(
+                "  {:>4} to {:>4} bytes  {}",
+                size + 1 - SIZE_STEP,
+                size,
+                count
+            ),
+            None => println!("  {:>12} bytes  {}", "larger", count),
+        }
+    }
+
+    // a size is taken to be the largest of its bucket, so the waste is at least this
+    let mut wasted = alloc::vec![(0u64, 0u64); block_sizes.len()];
+    for (index, &count) in sizes.iter().enumerate() {
+        let Some(size) = bucket_size(index) else {
+            continue;
+        };
+        if let Some(block) = block_sizes.iter().position(|&block| block >= size) {
+            wasted[block].0 += count;
+            wasted[block].1 += count * (block_sizes[block] - size) as u64;
+        }
+    }
+    println!("{:>8}  {:>11}  WASTED BYTES", "BLOCK", "ALLOCATIONS");
+    for (size, (count, bytes)) in block_sizes.iter().zip(wasted) {
+        println!("{:>8}  {:>11}  {}", size, count, bytes);
+    }
+
+    println!("lifetimes of freed allocations, in timer ticks:");
+    for (index, &count) in lifetimes
+        .iter()
+        .enumerate()
+        .filter(|&(_, &count)| count > 0)
+    {
+        match index {
+            0 => println!("  {:>12}  {}", "0", count),
+            _ if index == LIFETIME_BUCKETS - 1 => {
+                println!(
+                    "  {:>12}  {}",
+                    alloc::format!(">= {}", 1u64 << (index - 1)),
+                    count
+                )
+            }
+            _ => println!(
+                "  {:>12}  {}",
+                alloc::format!("{} to {}", 1u64 << (index - 1), (1u64 << index) - 1),
+                count
+            ),
+        }
+    }
+    println!(
+        "{} live allocations measured, {} too many to measure",
+        live, untracked
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "alloc-profile"))]
+fn print_profile() -> Result<(), &'static str> {
+    Err("the kernel was built without the alloc-profile feature")
+}
+
+/// A wrapper around a lock to permit trait implementations (GlobalAlloc has to be implemented on
+/// a type defined in this crate)
+pub struct Locked<A> {
+    inner: WatchedMutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(name: &'static str, inner: A) -> Self {
+        Locked {
+            inner: WatchedMutex::new(name, inner),
+        }
+    }
+
+    pub fn lock(&self) -> WatchedGuard<'_, A> {
+        self.inner.lock()
+    }
+
+    pub fn try_lock(&self) -> Option<WatchedGuard<'_, A>> {
+        self.inner.try_lock()
+    }
+
+    pub fn state(&self) -> &LockState {
+        self.inner.state()
+    }
+}
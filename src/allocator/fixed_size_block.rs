@@ -0,0 +1,351 @@
+#[cfg(feature = "alloc-profile")]
+use super::profile::Profile;
+use super::Locked;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, ptr::NonNull};
+
+/// The block sizes used unless `set_block_sizes` picks others
+pub const DEFAULT_BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The most block sizes there can be
+pub const MAX_BLOCK_SIZES: usize = 16;
+
+/// The largest block size there can be
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+/// Blocks of each free list `check` follows at most
+const CHECK_DEPTH: usize = 64;
+
+/// What freed memory is filled with in debug mode
+const POISON: u8 = 0x6b;
+
+/// The alignment of blocks of `size` bytes: the largest power of 2 that divides it, so blocks of
+/// the power of 2 sizes are aligned to their size and a 48 byte block to 16 bytes
+fn block_align(size: usize) -> usize {
+    1 << size.trailing_zeros()
+}
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Counters of what the allocator has done since boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes handed out and given back, as asked for
+    pub allocated: u64,
+    pub freed: u64,
+    /// The most bytes that were ever out at once
+    pub peak: u64,
+    /// Allocations served from each block size, in the order of `block_sizes` and 0 past them,
+    /// and last those no block fits, served by the fallback allocator
+    pub allocations: [u64; MAX_BLOCK_SIZES + 1],
+    /// Allocations that found no memory
+    pub failures: u64,
+    /// In debug mode: blocks freed twice, and free blocks found written to when they were handed
+    /// out again, with the address of the last of either
+    pub double_frees: u64,
+    pub damaged_free_blocks: u64,
+    pub last_bad_block: u64,
+}
+
+impl Stats {
+    /// Bytes out at the moment
+    pub fn in_use(&self) -> u64 {
+        self.allocated - self.freed
+    }
+
+    /// Allocations no block fits
+    pub fn fallback_allocations(&self) -> u64 {
+        self.allocations[MAX_BLOCK_SIZES]
+    }
+}
+
+/// Serves small allocations from per-size free lists and everything else (plus list refills)
+/// from a linked list fallback allocator.
+///
+/// In debug mode freed memory is filled with `POISON`. A block whose poison is intact when it's
+/// freed is looked for in its free list, which catches double frees before they link a block into
+/// the list twice; a block whose poison is damaged when it's handed out again was written to
+/// after it was freed. Allocations too big for a block are poisoned too, but not checked.
+///
+/// The block sizes are `DEFAULT_BLOCK_SIZES` unless they're changed before the first allocation,
+/// see `set_block_sizes`. With the `alloc-profile` feature every allocation is also counted by
+/// size and lifetime, see `profile`.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; MAX_BLOCK_SIZES],
+    /// The first `block_count` are in use
+    block_sizes: [usize; MAX_BLOCK_SIZES],
+    block_count: usize,
+    fallback_allocator: linked_list_allocator::Heap,
+    stats: Stats,
+    debug: bool,
+    #[cfg(feature = "alloc-profile")]
+    profile: Profile,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        let mut block_sizes = [0; MAX_BLOCK_SIZES];
+        let mut index = 0;
+        while index < DEFAULT_BLOCK_SIZES.len() {
+            block_sizes[index] = DEFAULT_BLOCK_SIZES[index];
+            index += 1;
+        }
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; MAX_BLOCK_SIZES],
+            block_sizes,
+            block_count: DEFAULT_BLOCK_SIZES.len(),
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            stats: Stats {
+                allocated: 0,
+                freed: 0,
+                peak: 0,
+                allocations: [0; MAX_BLOCK_SIZES + 1],
+                failures: 0,
+                double_frees: 0,
+                damaged_free_blocks: 0,
+                last_bad_block: 0,
+            },
+            debug: false,
+            #[cfg(feature = "alloc-profile")]
+            profile: Profile::new(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// The block sizes, smallest first
+    pub fn block_sizes(&self) -> &[usize] {
+        &self.block_sizes[..self.block_count]
+    }
+
+    /// Makes the blocks `sizes` bytes big: at most `MAX_BLOCK_SIZES` of them, smallest first, each
+    /// a multiple of 8 up to `MAX_BLOCK_SIZE`. Only before the first allocation, as the blocks out
+    /// by then would go back to the wrong free lists.
+    pub fn set_block_sizes(&mut self, sizes: &[usize]) -> Result<(), &'static str> {
+        if self.stats.allocated != 0 || self.stats.failures != 0 {
+            return Err("the heap is in use already");
+        }
+        if sizes.is_empty() || sizes.len() > MAX_BLOCK_SIZES {
+            return Err("there must be 1 to 16 block sizes");
+        }
+        if sizes
+            .iter()
+            .any(|&size| size == 0 || !size.is_multiple_of(8) || size > MAX_BLOCK_SIZE)
+        {
+            return Err("block sizes must be multiples of 8 up to 4096");
+        }
+        if sizes.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("block sizes must go from the smallest to the largest");
+        }
+        self.block_sizes[..sizes.len()].copy_from_slice(sizes);
+        self.block_count = sizes.len();
+        Ok(())
+    }
+
+    /// The free list of the smallest block that fits `layout`, `None` if none does
+    fn list_index(&self, layout: &Layout) -> Option<usize> {
+        self.block_sizes()
+            .iter()
+            .position(|&size| size >= layout.size() && block_align(size) >= layout.align())
+    }
+
+    /// What the allocations since boot were, see `Profile`
+    #[cfg(feature = "alloc-profile")]
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Bytes the fallback allocator manages and how many of those are free; the free lists'
+    /// blocks count as used
+    pub fn fallback_usage(&self) -> (usize, usize) {
+        (
+            self.fallback_allocator.size(),
+            self.fallback_allocator.free(),
+        )
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.debug
+    }
+
+    /// Turns debug mode on or off; turning it on poisons every block in the free lists, so they
+    /// pass the checks
+    pub fn set_debug(&mut self, debug: bool) {
+        if debug && !self.debug {
+            let sizes = &self.block_sizes[..self.block_count];
+            for (head, &size) in self.list_heads.iter_mut().zip(sizes) {
+                let mut node = head.as_deref_mut();
+                while let Some(current) = node {
+                    unsafe { poison(current as *mut ListNode as *mut u8, size) };
+                    node = current.next.as_deref_mut();
+                }
+            }
+        }
+        self.debug = debug;
+    }
+
+    /// Whether the free list `index` holds the block at `ptr`
+    fn is_free(&self, index: usize, ptr: *mut u8) -> bool {
+        let mut node = self.list_heads[index].as_deref();
+        while let Some(current) = node {
+            if ptr::eq(current as *const ListNode as *const u8, ptr) {
+                return true;
+            }
+            node = current.next.as_deref();
+        }
+        false
+    }
+
+    fn bad_block(&mut self, ptr: *mut u8) {
+        self.stats.last_bad_block = ptr as u64;
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid and that the heap is
+    /// unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator
+            .init(heap_start as *mut u8, heap_size);
+    }
+
+    /// Follows the first blocks of every free list, checking that each lies in the heap and is
+    /// aligned as its size says, as a block written to after it was freed usually isn't. Returns
+    /// the address of the first bad block.
+    pub fn check(&self) -> Result<(), u64> {
+        let heap =
+            self.fallback_allocator.bottom() as usize..self.fallback_allocator.top() as usize;
+        for (head, &size) in self.list_heads.iter().zip(self.block_sizes()) {
+            let mut node = head.as_deref();
+            for _ in 0..CHECK_DEPTH {
+                let Some(current) = node else {
+                    break;
+                };
+                // checked before anything is read through it
+                let address = current as *const ListNode as usize;
+                if !heap.contains(&address) || !address.is_multiple_of(block_align(size)) {
+                    return Err(address as u64);
+                }
+                node = current.next.as_deref();
+            }
+        }
+        Ok(())
+    }
+
+    fn count_allocation(&mut self, layout: &Layout, index: Option<usize>, ptr: *mut u8) {
+        if ptr.is_null() {
+            self.stats.failures += 1;
+            return;
+        }
+        self.stats.allocations[index.unwrap_or(MAX_BLOCK_SIZES)] += 1;
+        self.stats.allocated += layout.size() as u64;
+        self.stats.peak = self.stats.peak.max(self.stats.in_use());
+        #[cfg(feature = "alloc-profile")]
+        self.profile.allocated(ptr, layout.size());
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+/// Fills the block at `ptr` of `size` bytes with `POISON`, but for the free list's link
+unsafe fn poison(ptr: *mut u8, size: usize) {
+    let link = mem::size_of::<ListNode>().min(size);
+    ptr::write_bytes(ptr.add(link), POISON, size - link);
+}
+
+/// Whether the block at `ptr` of `size` bytes is still poisoned as `poison` left it
+unsafe fn is_poisoned(ptr: *mut u8, size: usize) -> bool {
+    let link = mem::size_of::<ListNode>().min(size);
+    core::slice::from_raw_parts(ptr.add(link), size - link)
+        .iter()
+        .all(|&byte| byte == POISON)
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let index = allocator.list_index(&layout);
+        let ptr = match index {
+            Some(index) => {
+                match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        allocator.list_heads[index] = node.next.take();
+                        let ptr = node as *mut ListNode as *mut u8;
+                        if allocator.debug && !is_poisoned(ptr, allocator.block_sizes[index]) {
+                            allocator.stats.damaged_free_blocks += 1;
+                            allocator.bad_block(ptr);
+                        }
+                        ptr
+                    }
+                    None => {
+                        // no block exists in list => allocate new block
+                        let block_size = allocator.block_sizes[index];
+                        let layout =
+                            Layout::from_size_align(block_size, block_align(block_size)).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                }
+            }
+            None => allocator.fallback_alloc(layout),
+        };
+        allocator.count_allocation(&layout, index, ptr);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        #[cfg(feature = "alloc-profile")]
+        allocator.profile.freed(ptr);
+        match allocator.list_index(&layout) {
+            Some(index) => {
+                let size = allocator.block_sizes[index];
+                if allocator.debug {
+                    if is_poisoned(ptr, size) && allocator.is_free(index, ptr) {
+                        // linking it in again would hand it out twice
+                        allocator.stats.double_frees += 1;
+                        allocator.bad_block(ptr);
+                        return;
+                    }
+                    poison(ptr, size);
+                }
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= size);
+                assert!(mem::align_of::<ListNode>() <= size);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                if allocator.debug {
+                    ptr::write_bytes(ptr, POISON, layout.size());
+                }
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+        allocator.stats.freed += layout.size() as u64;
+    }
+}
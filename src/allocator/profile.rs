@@ -0,0 +1,125 @@
+//! What the heap's allocations look like, to pick block sizes by: how many there were of each
+//! size, and how long they lived before they were freed.
+//!
+//! Only built with the `alloc-profile` feature, as it costs every allocation and free a lookup in
+//! a table. Sizes are counted in steps of `SIZE_STEP` bytes up to `MAX_BLOCK_SIZE`, where the
+//! blocks end. Lifetimes are in timer ticks, counted in powers of 2; they're measured for the
+//! allocations that find a slot in the table of live ones, which has room for `LIVE_SLOTS`, and
+//! the ones that don't are counted as untracked.
+
+use super::fixed_size_block::MAX_BLOCK_SIZE;
+use crate::interrupts;
+
+/// Bytes of allocation size a size bucket covers
+pub const SIZE_STEP: usize = 8;
+
+/// Size buckets: up to `SIZE_STEP` bytes, up to twice that and so on, and last the larger ones
+pub const SIZE_BUCKETS: usize = MAX_BLOCK_SIZE / SIZE_STEP + 1;
+
+/// Lifetime buckets: freed in the tick they were made, after 1 tick, 2 to 3, 4 to 7 and so on
+pub const LIFETIME_BUCKETS: usize = 32;
+
+/// Live allocations whose lifetime can be measured at once
+const LIVE_SLOTS: usize = 1024;
+
+/// Slots an allocation may go in, from the one its address hashes to
+const PROBES: usize = 8;
+
+/// The allocation at `address`, made at `tick`; an address of 0 is a free slot
+#[derive(Clone, Copy)]
+struct Live {
+    address: usize,
+    tick: u64,
+}
+
+pub struct Profile {
+    sizes: [u64; SIZE_BUCKETS],
+    lifetimes: [u64; LIFETIME_BUCKETS],
+    live: [Live; LIVE_SLOTS],
+    /// Allocations that found no free slot in `live`
+    untracked: u64,
+}
+
+impl Profile {
+    pub const fn new() -> Self {
+        Profile {
+            sizes: [0; SIZE_BUCKETS],
+            lifetimes: [0; LIFETIME_BUCKETS],
+            live: [Live {
+                address: 0,
+                tick: 0,
+            }; LIVE_SLOTS],
+            untracked: 0,
+        }
+    }
+
+    /// Counts an allocation of `size` bytes at `ptr`
+    pub fn allocated(&mut self, ptr: *mut u8, size: usize) {
+        self.sizes[size_bucket(size)] += 1;
+        let address = ptr as usize;
+        match slots(address).find(|&slot| self.live[slot].address == 0) {
+            Some(slot) => {
+                self.live[slot] = Live {
+                    address,
+                    tick: interrupts::ticks(),
+                }
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    /// Counts the lifetime of the allocation at `ptr`, if it found a slot when it was made
+    pub fn freed(&mut self, ptr: *mut u8) {
+        let address = ptr as usize;
+        if let Some(slot) = slots(address).find(|&slot| self.live[slot].address == address) {
+            let ticks = interrupts::ticks().saturating_sub(self.live[slot].tick);
+            self.lifetimes[lifetime_bucket(ticks)] += 1;
+            self.live[slot].address = 0;
+        }
+    }
+
+    /// Allocations by size, see `SIZE_BUCKETS`
+    pub fn sizes(&self) -> &[u64; SIZE_BUCKETS] {
+        &self.sizes
+    }
+
+    /// Freed allocations by lifetime, see `LIFETIME_BUCKETS`
+    pub fn lifetimes(&self) -> &[u64; LIFETIME_BUCKETS] {
+        &self.lifetimes
+    }
+
+    /// Allocations being measured at the moment, and those that couldn't be
+    pub fn tracked(&self) -> (usize, u64) {
+        let live = self.live.iter().filter(|live| live.address != 0).count();
+        (live, self.untracked)
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The slots of `live` an allocation at `address` may go in
+fn slots(address: usize) -> impl Iterator<Item = usize> {
+    // allocations are at least 8 bytes apart; the multiplication spreads the rest
+    let hash = (address >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+    (0..PROBES).map(move |probe| (hash + probe) % LIVE_SLOTS)
+}
+
+fn size_bucket(size: usize) -> usize {
+    match size {
+        ..=MAX_BLOCK_SIZE => size.saturating_sub(1) / SIZE_STEP,
+        _ => SIZE_BUCKETS - 1,
+    }
+}
+
+/// The largest size counted in size bucket `index`, `None` for the last
+pub fn bucket_size(index: usize) -> Option<usize> {
+    (index < SIZE_BUCKETS - 1).then(|| (index + 1) * SIZE_STEP)
+}
+
+fn lifetime_bucket(ticks: u64) -> usize {
+    ((u64::BITS - ticks.leading_zeros()) as usize).min(LIFETIME_BUCKETS - 1)
+}
@@ -0,0 +1,287 @@
+//! Attributes kernel heap usage to accounts, optionally capped by quotas.
+//!
+//! Every allocation is charged to the account current on the allocating CPU: the one passed to
+//! `with_account`, such as the group account of the process thread running (see
+//! `process::group`), the one of the task being polled (see `Task::with_account`), or otherwise
+//! the kernel's own. The account is remembered in a pointer-sized trailer behind the allocation, so
+//! the bytes are credited back to the right account whichever context frees them, and an account
+//! lives on until the last allocation charged to it is gone. A canary in the trailer catches
+//! allocations overrun by their owner: a free that finds it changed is reported to `watchdog`.
+//!
+//! An allocation that would push its account past its limit, or all accounts but the kernel's
+//! past the global limit (the `heap.global_limit_kib` sysctl), fails. Code running under a quota should therefore allocate fallibly
+//! (`try_reserve`, ...); an infallible allocation failing still ends in the allocation error
+//! handler, quota or not. Before an allocation fails for want of memory, the caches are asked to
+//! give some back (see `memory::pressure`), and it's tried once more.
+
+use crate::allocator::HEAP_SIZE;
+use crate::memory::pressure;
+use crate::sysctl::Tunable;
+use crate::trace::{self, Event};
+use crate::{percpu, watchdog};
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Usage counters of one account; reached through a `HeapAccount`
+pub struct Account {
+    name: &'static str,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    /// 0 means unlimited
+    limit: AtomicUsize,
+    failures: AtomicU64,
+}
+
+impl Account {
+    const fn new(name: &'static str, limit: usize) -> Self {
+        Account {
+            name,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(limit),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    fn is_kernel(&self) -> bool {
+        ptr::eq(self, &KERNEL)
+    }
+
+    /// Reserves `size` bytes against this account's limit and the global one
+    fn try_charge(&self, size: usize) -> bool {
+        let used = self.used.fetch_add(size, Ordering::Relaxed) + size;
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit != 0 && used > limit {
+            self.used.fetch_sub(size, Ordering::Relaxed);
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if !self.is_kernel() {
+            let total = NON_KERNEL_USED.fetch_add(size, Ordering::Relaxed) + size;
+            let global_limit = GLOBAL_LIMIT.get() as usize * 1024;
+            if global_limit != 0 && total > global_limit {
+                NON_KERNEL_USED.fetch_sub(size, Ordering::Relaxed);
+                self.used.fetch_sub(size, Ordering::Relaxed);
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        self.peak.fetch_max(used, Ordering::Relaxed);
+        true
+    }
+
+    fn uncharge(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+        if !self.is_kernel() {
+            NON_KERNEL_USED.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> AccountStats {
+        let limit = self.limit.load(Ordering::Relaxed);
+        AccountStats {
+            name: self.name,
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            limit: (limit != 0).then_some(limit),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What an account has been charged for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountStats {
+    pub name: &'static str,
+    /// Bytes currently allocated
+    pub used: usize,
+    /// Highest `used` has been
+    pub peak: usize,
+    pub limit: Option<usize>,
+    /// Allocations refused because of a quota
+    pub failures: u64,
+}
+
+/// Everything not charged elsewhere, including the allocator's own bookkeeping before boot
+static KERNEL: Account = Account::new("kernel", 0);
+/// Bytes charged to every account but the kernel's, which the global limit caps
+static NON_KERNEL_USED: AtomicUsize = AtomicUsize::new(0);
+/// Set once any account other than the kernel's has been made current; until then `alloc`
+/// doesn't need to look at the per-CPU data, which doesn't exist in early boot
+static ACCOUNTS_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Every account created, for `accounts`; dead ones are pruned as they're found
+static ACCOUNTS: Mutex<Vec<Weak<Account>>> = Mutex::new(Vec::new());
+
+/// A handle to an account that allocations can be charged to
+#[derive(Clone)]
+pub struct HeapAccount(Arc<Account>);
+
+impl HeapAccount {
+    /// A fresh account, capped at `limit` bytes if given
+    pub fn new(name: &'static str, limit: Option<usize>) -> Self {
+        let account = Arc::new(Account::new(name, limit.unwrap_or(0)));
+        let mut accounts = ACCOUNTS.lock();
+        accounts.retain(|account| account.strong_count() > 0);
+        accounts.push(Arc::downgrade(&account));
+        HeapAccount(account)
+    }
+
+    /// Changes the cap; lowering it below current usage only stops further growth
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.0.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> AccountStats {
+        self.0.stats()
+    }
+}
+
+/// The kernel's own account
+pub fn kernel_stats() -> AccountStats {
+    KERNEL.stats()
+}
+
+/// Stats of every account still alive, the kernel's first
+pub fn accounts() -> Vec<AccountStats> {
+    let live: Vec<Arc<Account>> = ACCOUNTS.lock().iter().filter_map(Weak::upgrade).collect();
+    let mut stats = Vec::with_capacity(live.len() + 1);
+    stats.push(KERNEL.stats());
+    stats.extend(live.iter().map(|account| account.stats()));
+    stats
+}
+
+/// Caps the heap all accounts except the kernel's may use together, keeping the rest in reserve
+/// for the kernel itself
+pub static GLOBAL_LIMIT: Tunable = Tunable::new(
+    "heap.global_limit_kib",
+    "KiB of heap all accounts but the kernel's may use together, 0 for no limit",
+    0,
+    0,
+    (HEAP_SIZE / 1024) as u64,
+);
+
+fn current_account() -> *const Account {
+    if !ACCOUNTS_ACTIVE.load(Ordering::Relaxed) {
+        return &KERNEL;
+    }
+    match percpu::try_current() {
+        Some(cpu) => match cpu.heap_account.load(Ordering::Relaxed) {
+            account if account.is_null() => &KERNEL,
+            account => account,
+        },
+        None => &KERNEL,
+    }
+}
+
+/// The account allocations on this CPU are currently charged to, `None` for the kernel's
+pub fn current() -> Option<HeapAccount> {
+    let account = current_account();
+    if ptr::eq(account, &KERNEL) {
+        return None;
+    }
+    // the account is kept alive by whoever made it current
+    unsafe {
+        Arc::increment_strong_count(account);
+        Some(HeapAccount(Arc::from_raw(account)))
+    }
+}
+
+/// Runs `f` with every allocation it makes on this CPU charged to `account`
+pub fn with_account<R>(account: &HeapAccount, f: impl FnOnce() -> R) -> R {
+    ACCOUNTS_ACTIVE.store(true, Ordering::Relaxed);
+    let slot = &percpu::current().heap_account;
+    let account = Arc::as_ptr(&account.0) as *mut Account;
+    let previous = slot.swap(account, Ordering::Relaxed);
+    let result = f();
+    slot.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Follows every allocation: the account it's charged to, and a canary that an overrun of the
+/// allocation overwrites first
+#[repr(C)]
+struct Trailer {
+    canary: u64,
+    account: *const Account,
+}
+
+/// XORed with the allocation's address, so a trailer copied elsewhere doesn't pass
+const CANARY: u64 = 0x5afe_c0de_a110_ca7e;
+
+/// Where the trailer sits in an allocation of `layout`, and the layout actually allocated
+fn with_trailer(layout: Layout) -> Option<(Layout, usize)> {
+    layout.extend(Layout::new::<Trailer>()).ok()
+}
+
+/// Wraps the real allocator and does the accounting
+pub struct Accounted<A> {
+    inner: A,
+}
+
+impl<A> Accounted<A> {
+    pub const fn new(inner: A) -> Self {
+        Accounted { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Accounted<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (full, trailer) = match with_trailer(layout) {
+            Some(layout) => layout,
+            None => return ptr::null_mut(),
+        };
+        let account = &*current_account();
+        if !account.try_charge(layout.size()) {
+            return ptr::null_mut();
+        }
+        let mut ptr = self.inner.alloc(full);
+        if ptr.is_null() && pressure::reclaim(full.size()) > 0 {
+            ptr = self.inner.alloc(full);
+        }
+        if ptr.is_null() {
+            account.uncharge(layout.size());
+            return ptr;
+        }
+        if !account.is_kernel() {
+            // the allocation keeps its account alive
+            Arc::increment_strong_count(account as *const Account);
+        }
+        ptr.add(trailer).cast::<Trailer>().write_unaligned(Trailer {
+            canary: CANARY ^ ptr as u64,
+            account,
+        });
+        trace::record(Event::Alloc {
+            address: ptr as u64,
+            size: layout.size() as u64,
+        });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        trace::record(Event::Free {
+            address: ptr as u64,
+            size: layout.size() as u64,
+        });
+        let (full, trailer) = with_trailer(layout).unwrap();
+        let Trailer { canary, account } = ptr.add(trailer).cast::<Trailer>().read_unaligned();
+        self.inner.dealloc(ptr, full);
+        if canary != CANARY ^ ptr as u64 {
+            // the account pointer is as likely overwritten; leave the charge where it is
+            watchdog::heap_corrupted(ptr as u64);
+            return;
+        }
+        (*account).uncharge(layout.size());
+        if !ptr::eq(account, &KERNEL) {
+            // may free the account itself, which comes back through here
+            drop(Arc::from_raw(account));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Typed caches of objects that are allocated and freed over and over, like received frames.
+//!
+//! A `SlabCache<T>` hands out room for one `T` at a time from slabs, chunks of `SLAB_SIZE` bytes
+//! or more taken from the heap and cut into equal slots. Freed slots go back to the cache, never
+//! to the heap, so a busy cache costs one heap allocation per slab rather than per object, and
+//! its objects don't break up the heap's free lists. A cache keeps its slabs for good; it is
+//! meant to be a `static`, sized by how many objects are ever alive at once.
+//!
+//! Every CPU has a magazine of up to `MAGAZINE_SIZE` free slots in front of the cache's shared
+//! free list, the depot: objects are taken from and returned to the calling CPU's magazine, and
+//! the depot is only locked to move half a magazine at a time. Before a CPU has per-CPU data, the
+//! depot is used directly.
+//!
+//! `alloc` returns a `SlabBox`, which owns the object like a `Box` and gives the slot back when
+//! dropped. `caches` lists the caches in use, for `mem`.
+
+use crate::percpu::{self, MAX_CPUS};
+use crate::sync::spinlock::IrqSpinLock;
+use alloc::alloc::{alloc, Layout};
+use alloc::vec::Vec;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Smallest slab; slabs for large objects hold `MIN_OBJECTS_PER_SLAB` of them instead
+const SLAB_SIZE: usize = 4096;
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+/// Free slots a CPU keeps to itself
+const MAGAZINE_SIZE: usize = 16;
+
+/// A free slot, linked to the next in the depot
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+/// The free slots shared by every CPU
+struct Depot {
+    free: *mut FreeSlot,
+    /// Slabs taken from the heap so far
+    slabs: u64,
+    /// Slots in them
+    slots: u64,
+}
+
+// the slots belong to the cache, whichever CPU has them on its list
+unsafe impl Send for Depot {}
+
+/// A CPU's free slots
+struct Magazine {
+    slots: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+unsafe impl Send for Magazine {}
+
+#[allow(clippy::declare_interior_mutable_const)] // only used to build the array below
+const EMPTY_MAGAZINE: IrqSpinLock<Magazine> = IrqSpinLock::new(Magazine {
+    slots: [ptr::null_mut(); MAGAZINE_SIZE],
+    len: 0,
+});
+
+/// What a cache has done, for `mem`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub slabs: u64,
+    /// Slots in all slabs, in use or not
+    pub slots: u64,
+    pub allocated: u64,
+    pub freed: u64,
+}
+
+impl CacheStats {
+    pub fn in_use(&self) -> u64 {
+        self.allocated - self.freed
+    }
+}
+
+/// The part of a cache that doesn't depend on its type, for the list of caches
+pub trait Cache: Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> CacheStats;
+}
+
+/// Caches that have taken a slab, in the order they did
+static CACHES: Mutex<Vec<&'static dyn Cache>> = Mutex::new(Vec::new());
+
+/// Every cache in use
+pub fn caches() -> Vec<&'static dyn Cache> {
+    CACHES.lock().clone()
+}
+
+pub struct SlabCache<T> {
+    name: &'static str,
+    depot: IrqSpinLock<Depot>,
+    magazines: [IrqSpinLock<Magazine>; MAX_CPUS],
+    allocated: AtomicU64,
+    freed: AtomicU64,
+    _objects: PhantomData<T>,
+}
+
+// objects only pass through the cache between one owner and the next
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T: Send + 'static> SlabCache<T> {
+    pub const fn new(name: &'static str) -> SlabCache<T> {
+        SlabCache {
+            name,
+            depot: IrqSpinLock::new(Depot {
+                free: ptr::null_mut(),
+                slabs: 0,
+                slots: 0,
+            }),
+            magazines: [EMPTY_MAGAZINE; MAX_CPUS],
+            allocated: AtomicU64::new(0),
+            freed: AtomicU64::new(0),
+            _objects: PhantomData,
+        }
+    }
+
+    /// Size and alignment of a slot: a `T`, with room for the free list's link when it's free
+    fn slot_layout() -> Layout {
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeSlot>());
+        let align = mem::align_of::<T>().max(mem::align_of::<FreeSlot>());
+        Layout::from_size_align(size, align).unwrap().pad_to_align()
+    }
+
+    /// Moves `value` into a slot of the cache; gives it back if there's no slot and the heap has
+    /// no room for another slab
+    pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, T> {
+        let slot = match self.take() {
+            Some(slot) => slot.cast::<T>(),
+            None => return Err(value),
+        };
+        unsafe { slot.as_ptr().write(value) };
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        Ok(SlabBox {
+            cache: self,
+            object: slot,
+        })
+    }
+
+    /// A free slot, from the calling CPU's magazine if it has one
+    fn take(&'static self) -> Option<NonNull<u8>> {
+        let cpu = match percpu::try_current() {
+            Some(cpu) => cpu.index,
+            None => return self.take_from_depot(),
+        };
+        let mut magazine = self.magazines[cpu].lock();
+        if magazine.len == 0 {
+            let mut depot = self.depot.lock();
+            while magazine.len < MAGAZINE_SIZE / 2 {
+                match self.pop(&mut depot) {
+                    Some(slot) => {
+                        let len = magazine.len;
+                        magazine.slots[len] = slot.as_ptr();
+                        magazine.len += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+        if magazine.len == 0 {
+            return None;
+        }
+        magazine.len -= 1;
+        NonNull::new(magazine.slots[magazine.len])
+    }
+
+    fn take_from_depot(&'static self) -> Option<NonNull<u8>> {
+        let mut depot = self.depot.lock();
+        self.pop(&mut depot)
+    }
+
+    /// Takes a slot off the depot's list, adding a slab to it if it's empty
+    fn pop(&'static self, depot: &mut Depot) -> Option<NonNull<u8>> {
+        if depot.free.is_null() {
+            self.grow(depot)?;
+        }
+        let slot = depot.free;
+        depot.free = unsafe { (*slot).next };
+        NonNull::new(slot.cast())
+    }
+
+    /// Takes a slab from the heap and puts its slots on the depot's list
+    fn grow(&'static self, depot: &mut Depot) -> Option<()> {
+        let slot = Self::slot_layout();
+        let count = (SLAB_SIZE / slot.size()).max(MIN_OBJECTS_PER_SLAB);
+        let layout = Layout::from_size_align(slot.size() * count, slot.align()).ok()?;
+        let slab = NonNull::new(unsafe { alloc(layout) })?;
+        for index in (0..count).rev() {
+            let free = unsafe { slab.as_ptr().add(index * slot.size()) }.cast::<FreeSlot>();
+            unsafe { free.write(FreeSlot { next: depot.free }) };
+            depot.free = free;
+        }
+        if depot.slabs == 0 {
+            CACHES.lock().push(self);
+        }
+        depot.slabs += 1;
+        depot.slots += count as u64;
+        Some(())
+    }
+
+    /// Puts a slot back, on the calling CPU's magazine if it has room
+    fn give_back(&self, slot: NonNull<u8>) {
+        self.freed.fetch_add(1, Ordering::Relaxed);
+        let cpu = match percpu::try_current() {
+            Some(cpu) => cpu.index,
+            None => return self.push(&mut self.depot.lock(), slot.as_ptr()),
+        };
+        let mut magazine = self.magazines[cpu].lock();
+        if magazine.len == MAGAZINE_SIZE {
+            let mut depot = self.depot.lock();
+            while magazine.len > MAGAZINE_SIZE / 2 {
+                magazine.len -= 1;
+                let len = magazine.len;
+                self.push(&mut depot, magazine.slots[len]);
+            }
+        }
+        let len = magazine.len;
+        magazine.slots[len] = slot.as_ptr();
+        magazine.len += 1;
+    }
+
+    fn push(&self, depot: &mut Depot, slot: *mut u8) {
+        let slot = slot.cast::<FreeSlot>();
+        unsafe { slot.write(FreeSlot { next: depot.free }) };
+        depot.free = slot;
+    }
+}
+
+impl<T: Send + 'static> Cache for SlabCache<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> CacheStats {
+        let depot = self.depot.lock();
+        CacheStats {
+            object_size: Self::slot_layout().size(),
+            slabs: depot.slabs,
+            slots: depot.slots,
+            allocated: self.allocated.load(Ordering::Relaxed),
+            freed: self.freed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An object in a `SlabCache`, given back to it when dropped
+pub struct SlabBox<T: Send + 'static> {
+    cache: &'static SlabCache<T>,
+    object: NonNull<T>,
+}
+
+unsafe impl<T: Send + 'static> Send for SlabBox<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for SlabBox<T> {}
+
+impl<T: Send + 'static> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T: Send + 'static> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: Send + 'static> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.object.as_ptr()) };
+        self.cache.give_back(self.object.cast());
+    }
+}
+
+impl<T: Send + fmt::Debug + 'static> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,394 @@
+use crate::{acpi, cmdline, memory, pit};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11; // global enable bit in IA32_APIC_BASE
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Where the (first) IO-APIC lives on practically every PC, used when there's no MADT
+pub const DEFAULT_IO_APIC_ADDRESS: u64 = 0xFEC0_0000;
+
+/// Vector the Local APIC delivers spurious interrupts to; they must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Vector of the IPI that only wakes a halted CPU, so it looks for something to do
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+
+/// Local APIC register offsets from the MMIO base
+mod reg {
+    pub const ID: usize = 0x020;
+    pub const TASK_PRIORITY: usize = 0x080;
+    pub const EOI: usize = 0x0B0;
+    pub const SPURIOUS: usize = 0x0F0;
+    pub const LVT_TIMER: usize = 0x320;
+    pub const LVT_LINT0: usize = 0x350;
+    pub const LVT_LINT1: usize = 0x360;
+    pub const LVT_ERROR: usize = 0x370;
+    pub const TIMER_INITIAL_COUNT: usize = 0x380;
+    pub const TIMER_CURRENT_COUNT: usize = 0x390;
+    pub const TIMER_DIVIDE: usize = 0x3E0;
+    pub const ICR_LOW: usize = 0x300;
+    pub const ICR_HIGH: usize = 0x310;
+}
+
+const LVT_MASKED: u32 = 1 << 16;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+/// Virtual address of the Local APIC registers, 0 while the legacy PIC is in use
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer ticks (at divide-by-16) per millisecond, measured against the PIT
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+pub static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+/// Handle to the Local APIC of the current CPU.
+///
+/// The registers sit at the same address on every core, so this is just the mapped base address
+/// and can be copied into interrupt handlers without taking a lock.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: usize) -> u32 {
+        core::ptr::read_volatile((self.base.as_u64() as usize + register) as *const u32)
+    }
+
+    unsafe fn write(&self, register: usize, value: u32) {
+        core::ptr::write_volatile((self.base.as_u64() as usize + register) as *mut u32, value)
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(reg::ID) >> 24 }
+    }
+
+    /// Signals the end of the interrupt currently being serviced
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(reg::EOI, 0) }
+    }
+
+    fn enable(&self) {
+        unsafe {
+            self.write(reg::TASK_PRIORITY, 0); // accept every interrupt class
+            self.write(reg::LVT_LINT0, LVT_MASKED);
+            self.write(reg::LVT_LINT1, LVT_MASKED);
+            self.write(reg::LVT_ERROR, LVT_MASKED);
+            self.write(reg::SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        }
+    }
+
+    /// Counts how many timer ticks elapse in 10ms of PIT time
+    fn calibrate_timer(&self) -> u32 {
+        unsafe {
+            self.write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(reg::LVT_TIMER, LVT_MASKED);
+            self.write(reg::TIMER_INITIAL_COUNT, u32::MAX);
+            pit::wait_ms(10);
+            let elapsed = u32::MAX - self.read(reg::TIMER_CURRENT_COUNT);
+            self.write(reg::TIMER_INITIAL_COUNT, 0); // stop the timer again
+            elapsed / 10
+        }
+    }
+
+    /// Sends an inter-processor interrupt and waits until the APIC has accepted it
+    fn send_ipi(&self, apic_id: u32, command: u32) {
+        unsafe {
+            self.write(reg::ICR_HIGH, apic_id << 24);
+            self.write(reg::ICR_LOW, command); // writing the low half sends the IPI
+            while self.read(reg::ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Puts the CPU with `apic_id` into its wait-for-startup state
+    pub fn send_init(&self, apic_id: u32) {
+        self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Starts the CPU with `apic_id` in real mode at physical address `page << 12`
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.send_ipi(
+            apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32,
+        );
+    }
+
+    /// Delivers `vector` to the CPU with `apic_id`
+    pub fn send_fixed(&self, apic_id: u32, vector: u8) {
+        self.send_ipi(apic_id, ICR_LEVEL_ASSERT | vector as u32);
+    }
+
+    /// Raises a non-maskable interrupt on the CPU with `apic_id`, which gets through even with
+    /// its interrupts disabled
+    pub fn send_nmi(&self, apic_id: u32) {
+        self.send_ipi(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+    }
+
+    /// Nanoseconds since the periodic timer last fired, read off its current count; `None` while
+    /// it isn't running periodically. A count that ran out again since can't be told apart.
+    pub fn timer_elapsed_ns(&self) -> Option<u64> {
+        let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Relaxed) as u64;
+        let (mode, initial, current) = unsafe {
+            (
+                self.read(reg::LVT_TIMER),
+                self.read(reg::TIMER_INITIAL_COUNT),
+                self.read(reg::TIMER_CURRENT_COUNT),
+            )
+        };
+        if ticks_per_ms == 0 || initial == 0 || mode & LVT_TIMER_PERIODIC == 0 {
+            return None;
+        }
+        Some(initial.saturating_sub(current) as u64 * 1_000_000 / ticks_per_ms)
+    }
+
+    /// Fires `vector` `hz` times per second on this CPU
+    pub fn start_periodic_timer(&self, vector: u8, hz: u32) {
+        unsafe {
+            self.write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(reg::LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+            self.write(reg::TIMER_INITIAL_COUNT, timer_period(hz));
+        }
+    }
+
+    /// Fires `vector` once, after `count` timer ticks (see `timer_period`)
+    pub fn start_oneshot_timer(&self, vector: u8, count: u32) {
+        unsafe {
+            self.write(reg::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(reg::LVT_TIMER, vector as u32);
+            self.write(reg::TIMER_INITIAL_COUNT, count.max(1));
+        }
+    }
+
+    /// Stops this CPU's timer, whichever mode it was in
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(reg::LVT_TIMER, LVT_MASKED);
+            self.write(reg::TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    /// Timer ticks left until the timer next fires; 0 once a one-shot has
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read(reg::TIMER_CURRENT_COUNT) }
+    }
+
+    /// What the timer counts down from
+    pub fn timer_initial_count(&self) -> u32 {
+        unsafe { self.read(reg::TIMER_INITIAL_COUNT) }
+    }
+}
+
+/// Local APIC timer ticks (at divide-by-16) between two interrupts at `hz` per second
+pub fn timer_period(hz: u32) -> u32 {
+    (TIMER_TICKS_PER_MS.load(Ordering::Relaxed) * 1000 / hz).max(1)
+}
+
+/// An IO-APIC, which routes external (ISA and PCI) interrupt lines to Local APIC vectors
+pub struct IoApic {
+    base: VirtAddr,
+    redirection_entries: u8,
+}
+
+impl IoApic {
+    const IOREGSEL: usize = 0x00;
+    const IOWIN: usize = 0x10;
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    /// # Safety
+    ///
+    /// `phys` must be the physical address of an IO-APIC register window.
+    pub unsafe fn new(phys: PhysAddr) -> Result<IoApic, &'static str> {
+        let base = memory::map_mmio(phys, 0x20).map_err(|_| "failed to map the IO-APIC")?;
+        let mut io_apic = IoApic {
+            base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(Self::VERSION) >> 16) & 0xFF) as u8 + 1;
+        Ok(io_apic)
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        let base = self.base.as_u64() as usize;
+        core::ptr::write_volatile((base + Self::IOREGSEL) as *mut u32, register);
+        core::ptr::read_volatile((base + Self::IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        let base = self.base.as_u64() as usize;
+        core::ptr::write_volatile((base + Self::IOREGSEL) as *mut u32, register);
+        core::ptr::write_volatile((base + Self::IOWIN) as *mut u32, value);
+    }
+
+    pub fn redirection_entries(&self) -> u8 {
+        self.redirection_entries
+    }
+
+    /// Routes input `irq` to `vector` on the Local APIC with id `destination` (fixed delivery,
+    /// edge triggered, active high like the ISA interrupts)
+    pub fn set_redirection(&mut self, irq: u8, vector: u8, destination: u32) {
+        self.set_redirection_with(irq, vector, destination, false, false)
+    }
+
+    /// Like `set_redirection`, for inputs whose polarity or trigger mode the MADT overrides
+    pub fn set_redirection_with(
+        &mut self,
+        irq: u8,
+        vector: u8,
+        destination: u32,
+        active_low: bool,
+        level_triggered: bool,
+    ) {
+        if irq >= self.redirection_entries {
+            return;
+        }
+        let mut low = vector as u32;
+        if active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let register = Self::REDIRECTION_TABLE + irq as u32 * 2;
+        unsafe {
+            self.write(register + 1, destination << 24);
+            self.write(register, low);
+        }
+    }
+
+    /// Sends every unmasked input that goes to the Local APIC with id `from` to the one with id
+    /// `to` instead, and returns how many there were
+    pub fn retarget(&mut self, from: u32, to: u32) -> usize {
+        let mut moved = 0;
+        for irq in 0..self.redirection_entries {
+            let register = Self::REDIRECTION_TABLE + irq as u32 * 2;
+            unsafe {
+                let masked = self.read(register) & LVT_MASKED != 0;
+                if !masked && self.read(register + 1) >> 24 == from {
+                    self.write(register + 1, to << 24);
+                    moved += 1;
+                }
+            }
+        }
+        moved
+    }
+
+    /// Every redirection entry, high half first, for `restore` to put back after the IO-APIC lost
+    /// them in sleep
+    pub fn save(&self) -> Vec<u64> {
+        (0..self.redirection_entries as u32)
+            .map(|irq| {
+                let register = Self::REDIRECTION_TABLE + irq * 2;
+                unsafe { (self.read(register + 1) as u64) << 32 | self.read(register) as u64 }
+            })
+            .collect()
+    }
+
+    pub fn restore(&mut self, entries: &[u64]) {
+        for (irq, &entry) in (0..self.redirection_entries as u32).zip(entries) {
+            let register = Self::REDIRECTION_TABLE + irq * 2;
+            unsafe {
+                self.write(register, LVT_MASKED); // don't fire half-configured
+                self.write(register + 1, (entry >> 32) as u32);
+                self.write(register, entry as u32);
+            }
+        }
+    }
+
+    pub fn mask(&mut self, irq: u8) {
+        if irq >= self.redirection_entries {
+            return;
+        }
+        let register = Self::REDIRECTION_TABLE + irq as u32 * 2;
+        unsafe {
+            let low = self.read(register);
+            self.write(register, low | LVT_MASKED);
+        }
+    }
+}
+
+/// Whether the CPU reports an on-chip APIC
+pub fn is_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .is_some_and(|features| features.has_apic())
+}
+
+/// Returns the Local APIC if `init` switched the system over to it
+pub fn local_apic() -> Option<LocalApic> {
+    match LOCAL_APIC_BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(LocalApic {
+            base: VirtAddr::new(base),
+        }),
+    }
+}
+
+/// Enables the Local APIC of the boot CPU, calibrates its timer and masks every IO-APIC input.
+///
+/// On error nothing was switched over and the caller should keep using the 8259 PIC.
+pub fn init() -> Result<(), &'static str> {
+    if cmdline::flag("noapic") {
+        return Err("disabled on the command line");
+    }
+    if !is_supported() {
+        return Err("no APIC reported by CPUID");
+    }
+
+    let mut apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    let apic_base = unsafe { apic_base_msr.read() };
+    let phys = PhysAddr::new(apic_base & APIC_BASE_ADDRESS_MASK);
+    let base = memory::map_mmio(phys, 0x1000).map_err(|_| "failed to map the Local APIC")?;
+    // only the IO-APIC serving GSI 0 is used, which covers the ISA interrupts
+    let io_apic_address = acpi::madt()
+        .and_then(|madt| madt.io_apics.iter().find(|io_apic| io_apic.gsi_base == 0))
+        .map_or(DEFAULT_IO_APIC_ADDRESS, |io_apic| io_apic.address);
+    let mut io_apic = unsafe { IoApic::new(PhysAddr::new(io_apic_address))? };
+
+    unsafe { apic_base_msr.write(apic_base | APIC_BASE_ENABLE) };
+    let local_apic = LocalApic { base };
+    local_apic.enable();
+    TIMER_TICKS_PER_MS.store(local_apic.calibrate_timer(), Ordering::Relaxed);
+
+    for irq in 0..io_apic.redirection_entries() {
+        io_apic.mask(irq); // drivers unmask their line through interrupts::enable_irq
+    }
+    *IO_APIC.lock() = Some(io_apic);
+
+    LOCAL_APIC_BASE.store(base.as_u64(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Enables the Local APIC of an application processor, or of the boot CPU waking from sleep, and
+/// starts its timer. The register window and the timer calibration are shared with the boot CPU.
+pub fn init_ap() {
+    if let Some(local_apic) = local_apic() {
+        let mut apic_base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        unsafe {
+            let apic_base = apic_base_msr.read();
+            apic_base_msr.write(apic_base | APIC_BASE_ENABLE);
+        }
+        local_apic.enable();
+        local_apic.start_periodic_timer(
+            crate::interrupts::InterruptIndex::Timer.as_u8(),
+            crate::interrupts::TIMER_HZ,
+        );
+    }
+}
@@ -0,0 +1,20 @@
+//! Thin wrappers over x86_64 instructions that drivers would otherwise write as inline assembly.
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+pub mod port;
+
+/// Lets the calling CPU execute SSE instructions, which AES-NI needs; `fpu::init` does it on
+/// every CPU.
+///
+/// The kernel is compiled without SSE and interrupts don't save the XMM registers, so kernel code
+/// may only touch them in `fpu::with_kernel_fpu` or in inline assembly that restores them.
+pub fn enable_sse() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
@@ -0,0 +1,202 @@
+//! Port-mapped I/O.
+//!
+//! Creating a port is `unsafe`: whoever does it promises the port really belongs to the device
+//! being driven and that accesses through it can't break memory safety (e.g. by reprogramming DMA).
+//! Reads and writes through an existing port are then safe. The access mode is part of the type,
+//! so writing to a `ReadOnlyPort` doesn't compile.
+//!
+//! `inb`/`outb` and friends are the unchecked escape hatches for one-off accesses.
+
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+
+/// Reads a byte from `port`
+///
+/// # Safety
+///
+/// The read must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a byte to `port`
+///
+/// # Safety
+///
+/// The write must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a 16-bit word from `port`
+///
+/// # Safety
+///
+/// The read must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a 16-bit word to `port`
+///
+/// # Safety
+///
+/// The write must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn outw(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a 32-bit doubleword from `port`
+///
+/// # Safety
+///
+/// The read must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a 32-bit doubleword to `port`
+///
+/// # Safety
+///
+/// The write must not have side effects that violate memory safety.
+#[inline]
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// A value that can be transferred with a single `in`/`out` instruction
+pub trait PortValue: Copy {
+    /// # Safety
+    ///
+    /// See `inb`.
+    unsafe fn read_from_port(port: u16) -> Self;
+
+    /// # Safety
+    ///
+    /// See `outb`.
+    unsafe fn write_to_port(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from_port(port: u16) -> u8 {
+        inb(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u8) {
+        outb(port, value)
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from_port(port: u16) -> u16 {
+        inw(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u16) {
+        outw(port, value)
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from_port(port: u16) -> u32 {
+        inl(port)
+    }
+
+    unsafe fn write_to_port(port: u16, value: u32) {
+        outl(port, value)
+    }
+}
+
+mod sealed {
+    pub trait Access {
+        const DEBUG_NAME: &'static str;
+    }
+}
+
+/// Marker for port access modes that allow reading
+pub trait Readable: sealed::Access {}
+/// Marker for port access modes that allow writing
+pub trait Writable: sealed::Access {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOnly;
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOnly;
+#[derive(Debug, Clone, Copy)]
+pub struct ReadWrite;
+
+impl sealed::Access for ReadOnly {
+    const DEBUG_NAME: &'static str = "ReadOnlyPort";
+}
+impl sealed::Access for WriteOnly {
+    const DEBUG_NAME: &'static str = "WriteOnlyPort";
+}
+impl sealed::Access for ReadWrite {
+    const DEBUG_NAME: &'static str = "Port";
+}
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// An I/O port carrying values of type `T`, with access mode `A`
+pub struct PortGeneric<T, A> {
+    port: u16,
+    phantom: PhantomData<(T, A)>,
+}
+
+pub type Port<T> = PortGeneric<T, ReadWrite>;
+pub type ReadOnlyPort<T> = PortGeneric<T, ReadOnly>;
+pub type WriteOnlyPort<T> = PortGeneric<T, WriteOnly>;
+
+impl<T, A> PortGeneric<T, A> {
+    /// # Safety
+    ///
+    /// `port` must belong to the device the caller drives, and accessing it with values of type
+    /// `T` must not be able to violate memory safety.
+    pub const unsafe fn new(port: u16) -> Self {
+        PortGeneric {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    pub const fn number(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortValue, A: Readable> PortGeneric<T, A> {
+    #[inline]
+    pub fn read(&mut self) -> T {
+        unsafe { T::read_from_port(self.port) }
+    }
+}
+
+impl<T: PortValue, A: Writable> PortGeneric<T, A> {
+    #[inline]
+    pub fn write(&mut self, value: T) {
+        unsafe { T::write_to_port(self.port, value) }
+    }
+}
+
+impl<T, A: sealed::Access> fmt::Debug for PortGeneric<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(A::DEBUG_NAME)
+            .field("port", &format_args!("{:#x}", self.port))
+            .finish()
+    }
+}
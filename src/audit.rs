@@ -0,0 +1,177 @@
+//! The audit log: what userspace did that touches the system's security, for looking back on.
+//!
+//! The kernel log says what the kernel printed; this says which programs were started, which
+//! system calls changed the system as a whole (`sysctl` writes, `reboot`), which calls were refused
+//! for lack of permission, and which modules and initramfs images were loaded or refused by the
+//! signature check. Each event is a fixed-size `Record`, so recording one doesn't allocate and
+//! works in any context a spin lock does, stamped with a sequence number, the tick it happened
+//! at and the process it happened in.
+//!
+//! The last `CAPACITY` records are kept in a ring; older ones are overwritten, and the sequence
+//! numbers show how many were lost. `read_since` gives a reader the records after the last one it
+//! saw, so it can follow the log; `/proc/audit` and the `audit` command show it whole.
+
+use crate::interrupts::{self, TIMER_HZ};
+use crate::process::{self, Pid};
+use crate::signing::{ArtifactKind, SignatureError};
+use crate::sync::spinlock::IrqSpinLock;
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+/// Records kept
+const CAPACITY: usize = 256;
+/// Bytes of a name kept in a record; longer names are cut short
+const NAME_SIZE: usize = 32;
+
+/// A name copied into a record
+#[derive(Clone, Copy)]
+pub struct Name {
+    bytes: [u8; NAME_SIZE],
+    len: u8,
+}
+
+impl Name {
+    pub fn new(name: &str) -> Name {
+        let mut len = name.len().min(NAME_SIZE);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; NAME_SIZE];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Name {
+            bytes,
+            len: len as u8,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What happened
+#[derive(Clone, Copy)]
+pub enum Event {
+    /// A program was loaded as process `pid`, under the name of its first argument
+    Exec { pid: Pid, name: Name },
+    /// A kernel parameter was changed through the system call
+    Sysctl { name: Name, old: u64, new: u64 },
+    /// The program asked to restart or power off
+    Reboot { command: u64 },
+    /// A system call was refused for lack of permission
+    Denied { syscall: &'static str },
+    /// A module or initramfs went through the signature check, `Ok` if it was let in
+    Load {
+        kind: ArtifactKind,
+        result: Result<(), SignatureError>,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Exec { pid, name } => write!(f, "exec {} as pid {}", name, pid),
+            Event::Sysctl { name, old, new } => write!(f, "sysctl {} {} -> {}", name, old, new),
+            Event::Reboot { command } => write!(f, "reboot {:#x}", command),
+            Event::Denied { syscall } => write!(f, "denied {}", syscall),
+            Event::Load {
+                kind,
+                result: Ok(()),
+            } => write!(f, "load {}", kind),
+            Event::Load {
+                kind,
+                result: Err(err),
+            } => write!(f, "load {} refused: {}", kind, err),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    /// Events recorded before this one since boot
+    pub sequence: u64,
+    /// Timer ticks since boot
+    pub ticks: u64,
+    /// The process the event happened in, `None` for the kernel itself
+    pub pid: Option<Pid>,
+    pub event: Event,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hz = TIMER_HZ as u64;
+        write!(
+            f,
+            "#{} [{:>5}.{:02}] ",
+            self.sequence,
+            self.ticks / hz,
+            self.ticks % hz * 100 / hz
+        )?;
+        match self.pid {
+            Some(pid) => write!(f, "pid {}: ", pid)?,
+            None => f.write_str("kernel: ")?,
+        }
+        write!(f, "{}", self.event)
+    }
+}
+
+struct Ring {
+    records: [Option<Record>; CAPACITY],
+    /// Events ever recorded, and so the sequence number of the next
+    next: u64,
+}
+
+static LOG: IrqSpinLock<Ring> = IrqSpinLock::new(Ring {
+    records: [None; CAPACITY],
+    next: 0,
+});
+
+/// Adds `event` to the log, as happening in the current process
+pub fn record(event: Event) {
+    let pid = process::current().map(|process| process.pid());
+    let ticks = interrupts::ticks();
+    let mut log = LOG.lock();
+    let sequence = log.next;
+    log.records[sequence as usize % CAPACITY] = Some(Record {
+        sequence,
+        ticks,
+        pid,
+        event,
+    });
+    log.next += 1;
+}
+
+/// Calls `f` with each record still kept whose sequence number is `sequence` or later, oldest
+/// first, and returns the sequence number to pass next time to see only newer ones
+pub fn read_since(sequence: u64, mut f: impl FnMut(&Record)) -> u64 {
+    let log = LOG.lock();
+    let oldest = log.next.saturating_sub(CAPACITY as u64);
+    for sequence in sequence.max(oldest)..log.next {
+        if let Some(record) = &log.records[sequence as usize % CAPACITY] {
+            f(record);
+        }
+    }
+    log.next
+}
+
+/// The whole log as text, a line per record
+pub fn text() -> String {
+    let mut text = String::new();
+    read_since(0, |record| {
+        let _ = writeln!(text, "{}", record);
+    });
+    text
+}
+
+/// `audit`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    // copied out first, to print without holding the lock
+    crate::print!("{}", text());
+    Ok(())
+}
@@ -0,0 +1,430 @@
+//! Benchmarks of the kernel's primitives, counted in TSC cycles: scrolling the VGA console,
+//! heap allocation patterns, switching to ring 3 and back, system call round trips and spinlocks
+//! with and without contention.
+//!
+//! Only built with the `bench` feature. `bench [<filter>]` runs them from the shell, and the
+//! `bench` test kernel (`cargo test --features bench --test bench`) runs them all in QEMU and
+//! prints the table on COM1.
+//!
+//! Every benchmark is timed `SAMPLES` times, each sample running it a number of iterations
+//! chosen so that reading the TSC costs next to nothing next to them; the table has the cycles
+//! per iteration of the fastest, the median and the mean sample. The minimum is what to compare
+//! between builds: interrupts and the host preempting QEMU only ever add cycles.
+
+use crate::clock::tsc;
+use crate::percpu;
+use crate::smp;
+use crate::sync::spinlock::{IrqSpinLock, SpinLock};
+use crate::usermode::{self, Transition, UserExit};
+use crate::vga_buffer::WRITER;
+use crate::{memory, println};
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::hint::{black_box, spin_loop};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Times each benchmark is timed
+const SAMPLES: usize = 32;
+
+global_asm!(
+    r#"
+    .pushsection .text.bench_user, "ax"
+    .global bench_user_start
+    bench_user_start:
+
+    .global bench_breakpoint
+    bench_breakpoint:
+        int3
+
+    # (count): makes `count` system calls that don't exist, the cheapest there are
+    .global bench_syscalls
+    bench_syscalls:
+        testq %rdi, %rdi
+        jz 2f
+    1:  movq $-1, %rax
+        syscall
+        decq %rdi
+        jnz 1b
+    2:  int3
+
+    .global bench_user_end
+    bench_user_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static bench_user_start: u8;
+    static bench_breakpoint: u8;
+    static bench_syscalls: u8;
+    static bench_user_end: u8;
+}
+
+/// Where the ring 3 benchmarks' code and stack are mapped, clear of the demo program's
+const USER_CODE: u64 = memory::USER_START + 0x20_0000;
+const USER_STACK: u64 = memory::USER_START + 0x21_0000;
+const USER_STACK_SIZE: u64 = 4096 * 4;
+
+static USER_MAPPED: Once<Result<(), &'static str>> = Once::new();
+
+pub struct Benchmark {
+    pub name: &'static str,
+    /// Iterations per sample
+    iterations: u32,
+    /// Runs the given number of iterations; `Err` if the benchmark can't run on this machine
+    run: fn(u32) -> Result<(), &'static str>,
+    setup: Option<Setup>,
+}
+
+/// Runs before a benchmark's samples are taken and after, with `true` and `false`
+type Setup = fn(bool) -> Result<(), &'static str>;
+
+pub static BENCHMARKS: &[Benchmark] = &[
+    Benchmark {
+        name: "vga scroll",
+        iterations: 16,
+        run: vga_scroll,
+        setup: None,
+    },
+    Benchmark {
+        name: "heap alloc+free 16 B",
+        iterations: 256,
+        run: |n| alloc_free(n, 16),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap alloc+free 256 B",
+        iterations: 256,
+        run: |n| alloc_free(n, 256),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap alloc+free 4 KiB",
+        iterations: 256,
+        run: |n| alloc_free(n, 4096),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap alloc+free 16 KiB (fallback)",
+        iterations: 64,
+        run: |n| alloc_free(n, 16 * 1024),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap 64 x 64 B, freed LIFO",
+        iterations: 16,
+        run: |n| alloc_batch(n, false),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap 64 x 64 B, freed FIFO",
+        iterations: 16,
+        run: |n| alloc_batch(n, true),
+        setup: None,
+    },
+    Benchmark {
+        name: "heap Vec grown to 4 KiB",
+        iterations: 16,
+        run: vec_growth,
+        setup: None,
+    },
+    Benchmark {
+        name: "ring 3 round trip (iretq)",
+        iterations: 16,
+        run: |n| ring3_round_trip(n, Transition::Iretq),
+        setup: None,
+    },
+    Benchmark {
+        name: "ring 3 round trip (sysretq)",
+        iterations: 16,
+        run: |n| ring3_round_trip(n, Transition::Sysretq),
+        setup: None,
+    },
+    Benchmark {
+        name: "syscall round trip",
+        iterations: 256,
+        run: syscalls,
+        setup: None,
+    },
+    Benchmark {
+        name: "spinlock uncontended",
+        iterations: 1024,
+        run: spinlock,
+        setup: None,
+    },
+    Benchmark {
+        name: "irq spinlock uncontended",
+        iterations: 1024,
+        run: irq_spinlock,
+        setup: None,
+    },
+    Benchmark {
+        name: "spinlock contended",
+        iterations: 1024,
+        run: spinlock,
+        setup: Some(contend),
+    },
+];
+
+/// Cycles per iteration of a benchmark's samples
+#[derive(Debug, Clone, Copy)]
+pub struct Cycles {
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+}
+
+impl Benchmark {
+    /// Takes the samples; `Err` if the benchmark couldn't run
+    pub fn run(&self) -> Result<Cycles, &'static str> {
+        if let Some(setup) = self.setup {
+            setup(true)?;
+        }
+        let samples = self.samples();
+        if let Some(setup) = self.setup {
+            setup(false)?;
+        }
+        let mut samples = samples?;
+        samples.sort_unstable();
+        Ok(Cycles {
+            min: samples[0],
+            median: samples[SAMPLES / 2],
+            mean: samples.iter().sum::<u64>() / SAMPLES as u64,
+        })
+    }
+
+    /// Cycles per iteration of each sample, in the order they were taken
+    fn samples(&self) -> Result<Vec<u64>, &'static str> {
+        // one run first, so the samples don't count faulting in memory or filling caches
+        (self.run)(self.iterations)?;
+        let mut samples = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            let start = tsc::read();
+            (self.run)(self.iterations)?;
+            samples.push((tsc::read() - start) / u64::from(self.iterations));
+        }
+        Ok(samples)
+    }
+}
+
+/// Runs the benchmarks whose name contains `filter` and prints a line for each
+pub fn run_all(filter: &str) {
+    let mhz = tsc::ticks_per_ms() / 1000;
+    println!(
+        "{:<34} {:>9} {:>9} {:>9}  (cycles per iteration, TSC at {} MHz)",
+        "BENCHMARK", "MIN", "MEDIAN", "MEAN", mhz
+    );
+    for benchmark in BENCHMARKS.iter().filter(|b| b.name.contains(filter)) {
+        match benchmark.run() {
+            Ok(cycles) => println!(
+                "{:<34} {:>9} {:>9} {:>9}",
+                benchmark.name, cycles.min, cycles.median, cycles.mean
+            ),
+            Err(err) => println!("{:<34} skipped: {}", benchmark.name, err),
+        }
+    }
+}
+
+/// `bench [<filter>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => run_all(""),
+        [filter] => {
+            if !BENCHMARKS.iter().any(|b| b.name.contains(filter)) {
+                return Err("no benchmark matches");
+            }
+            run_all(filter)
+        }
+        _ => return Err("usage: bench [<filter>]"),
+    }
+    Ok(())
+}
+
+// --- the benchmarks --------------------------------------------------------------------------
+
+/// Newlines at the bottom of the screen, each of which moves every row up one
+fn vga_scroll(iterations: u32) -> Result<(), &'static str> {
+    let mut writer = WRITER.lock();
+    for _ in 0..iterations {
+        writer.write_byte(b'\n');
+    }
+    Ok(())
+}
+
+fn alloc_free(iterations: u32, size: usize) -> Result<(), &'static str> {
+    let layout = Layout::from_size_align(size, 8).map_err(|_| "bad layout")?;
+    for _ in 0..iterations {
+        let block = unsafe { alloc(layout) };
+        if block.is_null() {
+            return Err("out of memory");
+        }
+        unsafe { dealloc(black_box(block), layout) };
+    }
+    Ok(())
+}
+
+/// Allocates 64 blocks, then frees them in the reverse order or in the same
+fn alloc_batch(iterations: u32, fifo: bool) -> Result<(), &'static str> {
+    const COUNT: usize = 64;
+    let layout = Layout::from_size_align(64, 8).map_err(|_| "bad layout")?;
+    let mut blocks = [ptr::null_mut(); COUNT];
+    for _ in 0..iterations {
+        for block in blocks.iter_mut() {
+            *block = unsafe { alloc(layout) };
+        }
+        let free = |block: &mut *mut u8| {
+            if !block.is_null() {
+                unsafe { dealloc(black_box(*block), layout) };
+            }
+        };
+        match fifo {
+            true => blocks.iter_mut().for_each(free),
+            false => blocks.iter_mut().rev().for_each(free),
+        }
+        if blocks.iter().any(|block| block.is_null()) {
+            return Err("out of memory");
+        }
+    }
+    Ok(())
+}
+
+/// A `Vec` pushed to byte by byte, which reallocates it every time it doubles
+fn vec_growth(iterations: u32) -> Result<(), &'static str> {
+    for _ in 0..iterations {
+        let mut bytes = Vec::new();
+        for byte in 0..4096 {
+            bytes.push(byte as u8);
+        }
+        black_box(bytes);
+    }
+    Ok(())
+}
+
+/// Into ring 3 and straight back through `int3`: the switches a program costs the kernel
+fn ring3_round_trip(iterations: u32, transition: Transition) -> Result<(), &'static str> {
+    let entry = user_entry(ptr::addr_of!(bench_breakpoint))?;
+    for _ in 0..iterations {
+        let exit = usermode::run(entry, user_stack_top(), 0, transition);
+        if !matches!(exit, UserExit::Breakpoint { .. }) {
+            return Err("the ring 3 program didn't come back through int3");
+        }
+    }
+    Ok(())
+}
+
+/// System calls from a program already in ring 3; its one way in and out is in the count too,
+/// but spread over all of them
+fn syscalls(iterations: u32) -> Result<(), &'static str> {
+    let entry = user_entry(ptr::addr_of!(bench_syscalls))?;
+    let exit = usermode::run(
+        entry,
+        user_stack_top(),
+        iterations.into(),
+        Transition::Sysretq,
+    );
+    match exit {
+        UserExit::Breakpoint { .. } => Ok(()),
+        _ => Err("the system call program didn't finish"),
+    }
+}
+
+static LOCK: SpinLock<u64> = SpinLock::new(0);
+static IRQ_LOCK: IrqSpinLock<u64> = IrqSpinLock::new(0);
+
+fn spinlock(iterations: u32) -> Result<(), &'static str> {
+    for _ in 0..iterations {
+        *LOCK.lock() += 1;
+    }
+    Ok(())
+}
+
+fn irq_spinlock(iterations: u32) -> Result<(), &'static str> {
+    for _ in 0..iterations {
+        *IRQ_LOCK.lock() += 1;
+    }
+    Ok(())
+}
+
+/// Set while the other CPUs are to keep taking `LOCK`
+static CONTENDING: AtomicBool = AtomicBool::new(false);
+/// The other CPUs that have started taking it
+static CONTENDERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Has every other online CPU take `LOCK` over and over, or stops them again
+fn contend(start: bool) -> Result<(), &'static str> {
+    let others = || (1..percpu::count()).filter(|&index| percpu::is_online(index));
+    if !start {
+        CONTENDING.store(false, Ordering::SeqCst);
+        while others().any(smp::is_working) {
+            spin_loop();
+        }
+        return Ok(());
+    }
+    if others().next().is_none() {
+        return Err("needs a second CPU");
+    }
+    CONTENDING.store(true, Ordering::SeqCst);
+    CONTENDERS.store(0, Ordering::SeqCst);
+    let started = others()
+        .filter(|&index| smp::run_on(index, contender).is_ok())
+        .count();
+    if started == 0 {
+        CONTENDING.store(false, Ordering::SeqCst);
+        return Err("no other CPU is idle");
+    }
+    while CONTENDERS.load(Ordering::SeqCst) < started {
+        spin_loop();
+    }
+    Ok(())
+}
+
+/// What each other CPU runs while `contend` has them: takes `LOCK`, and leaves it a moment so
+/// the boot CPU gets its turns
+fn contender() {
+    CONTENDERS.fetch_add(1, Ordering::SeqCst);
+    while CONTENDING.load(Ordering::Relaxed) {
+        *LOCK.lock() += 1;
+        for _ in 0..16 {
+            spin_loop();
+        }
+    }
+}
+
+// --- ring 3 ----------------------------------------------------------------------------------
+
+/// Copies the ring 3 programs to user memory the first time they're needed
+fn map_user() -> Result<(), &'static str> {
+    *USER_MAPPED.call_once(|| {
+        let code = unsafe {
+            let start = ptr::addr_of!(bench_user_start);
+            let length = ptr::addr_of!(bench_user_end) as usize - start as usize;
+            core::slice::from_raw_parts(start, length)
+        };
+        let stack = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        memory::map_user(
+            VirtAddr::new(USER_CODE),
+            code.len() as u64,
+            code,
+            PageTableFlags::empty(),
+        )
+        .and_then(|()| memory::map_user(VirtAddr::new(USER_STACK), USER_STACK_SIZE, &[], stack))
+        .map_err(|_| "failed to map the ring 3 programs")
+    })
+}
+
+/// Address of a program in the user mapping, mapping them first if they aren't yet
+fn user_entry(symbol: *const u8) -> Result<VirtAddr, &'static str> {
+    map_user()?;
+    let offset = symbol as u64 - ptr::addr_of!(bench_user_start) as u64;
+    Ok(VirtAddr::new(USER_CODE + offset))
+}
+
+fn user_stack_top() -> VirtAddr {
+    VirtAddr::new(USER_STACK + USER_STACK_SIZE)
+}
@@ -0,0 +1,133 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub mod crypt;
+pub mod journal;
+#[cfg(feature = "sfs")]
+pub mod loopback;
+pub mod ramdisk;
+pub mod zram;
+
+/// Errors a block device, or a layer stacked on top of one, can report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block index lies past the end of the device
+    OutOfRange,
+    /// The buffer isn't exactly one block long
+    BadBufferSize,
+    /// The device (or this part of it) can't be written
+    ReadOnly,
+    /// The device reported a transfer failure
+    Io,
+    /// An on-disk structure failed validation
+    Corrupted,
+    /// There is no room left for the request
+    NoSpace,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            BlockError::OutOfRange => "block out of range",
+            BlockError::BadBufferSize => "buffer is not one block long",
+            BlockError::ReadOnly => "device is read-only",
+            BlockError::Io => "I/O error",
+            BlockError::Corrupted => "on-disk structure is corrupted",
+            BlockError::NoSpace => "no space left",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A random-access device made of fixed-size blocks.
+///
+/// The methods take `&self` so a device can be shared (`Arc<dyn BlockDevice>`) between the
+/// filesystem on top of it and the layers stacked in between; implementors lock internally.
+pub trait BlockDevice: Send + Sync {
+    /// Size of one block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Reads block `block` into `buf`, which must be exactly `block_size()` bytes long
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, which must be exactly `block_size()` bytes long, to block `block`
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Returns once every completed write has reached stable storage
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Arc<T> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_block(block, buf)
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        (**self).write_block(block, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        (**self).flush()
+    }
+}
+
+/// Validates a request against a device's geometry, for use at the top of `read_block`/`write_block`
+pub fn check_request<D: BlockDevice + ?Sized>(
+    device: &D,
+    block: u64,
+    buf_len: usize,
+) -> Result<(), BlockError> {
+    if block >= device.block_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    if buf_len != device.block_size() {
+        return Err(BlockError::BadBufferSize);
+    }
+    Ok(())
+}
+
+/// Block devices known to the kernel by name (`ram0`, ...)
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Makes `device` available as `name`; returns false, and registers nothing, if the name is taken
+#[must_use]
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> bool {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return false;
+    }
+    devices.insert(String::from(name), device);
+    true
+}
+
+/// Looks up a registered device by name
+pub fn device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Like `device`, but `None` rather than waiting if the table is locked; for the panic handler
+pub fn try_device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.try_lock()?.get(name).cloned()
+}
+
+/// Names of every registered device, sorted
+pub fn device_names() -> Vec<String> {
+    DEVICES.lock().keys().cloned().collect()
+}
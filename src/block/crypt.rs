@@ -0,0 +1,218 @@
+//! Encrypted block devices in the spirit of dm-crypt, with a minimal LUKS-like header.
+//!
+//! Block 0 of the backing device holds the header: the salt and iteration count for deriving the
+//! key from a passphrase with PBKDF2-HMAC-SHA-256, and a check value that tells a wrong
+//! passphrase apart from a right one. The remaining blocks are encrypted with XTS-AES-256 and
+//! show up as blocks 0.. of the decrypted device, whose block number is also the XTS tweak
+//! (dm-crypt's "plain64").
+//!
+//! `crypt unlock` registers the decrypted view of a device, which `mount` can then mount like any
+//! other.
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::crypto::{self, pbkdf2_sha256, AesXts};
+use crate::println;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::fmt;
+
+const MAGIC: &[u8; 8] = b"RSCRYPT\0";
+const VERSION: u32 = 1;
+const SALT_SIZE: usize = 32;
+const HEADER_SIZE: usize = 80;
+/// XTS-AES-256: a 256-bit data key and a 256-bit tweak key
+const KEY_SIZE: usize = 64;
+const KEY_CHECK_CONTEXT: &[u8] = b"rust_os crypt key check";
+
+/// PBKDF2 iterations `format` uses unless told otherwise
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptError {
+    /// The backing device has no (or an unsupported) header
+    NotEncrypted,
+    WrongPassphrase,
+    /// Blocks must be a multiple of the AES block size and large enough for the header
+    UnsupportedBlockSize,
+    NoSuchDevice,
+    /// The decrypted view is already registered
+    AlreadyUnlocked,
+    Block(BlockError),
+}
+
+impl From<BlockError> for CryptError {
+    fn from(err: BlockError) -> Self {
+        CryptError::Block(err)
+    }
+}
+
+impl fmt::Display for CryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptError::NotEncrypted => f.write_str("no encryption header"),
+            CryptError::WrongPassphrase => f.write_str("wrong passphrase"),
+            CryptError::UnsupportedBlockSize => f.write_str("unsupported block size"),
+            CryptError::NoSuchDevice => f.write_str("no such block device"),
+            CryptError::AlreadyUnlocked => f.write_str("device is already unlocked"),
+            CryptError::Block(err) => write!(f, "block device: {}", err),
+        }
+    }
+}
+
+struct Header {
+    iterations: u32,
+    salt: [u8; SALT_SIZE],
+    key_check: [u8; 32],
+}
+
+impl Header {
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..HEADER_SIZE].fill(0);
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.iterations.to_le_bytes());
+        buf[16..48].copy_from_slice(&self.salt);
+        buf[48..80].copy_from_slice(&self.key_check);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Header> {
+        if &buf[..8] != MAGIC || u32::from_le_bytes(buf[8..12].try_into().unwrap()) != VERSION {
+            return None;
+        }
+        Some(Header {
+            iterations: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            salt: buf[16..48].try_into().unwrap(),
+            key_check: buf[48..80].try_into().unwrap(),
+        })
+    }
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8; SALT_SIZE], iterations: u32) -> [u8; KEY_SIZE] {
+    let mut key = [0; KEY_SIZE];
+    pbkdf2_sha256(passphrase, salt, iterations, &mut key);
+    key
+}
+
+fn key_check(key: &[u8; KEY_SIZE]) -> [u8; 32] {
+    crypto::hmac_sha256(key, KEY_CHECK_CONTEXT)
+}
+
+/// The decrypted view of an encrypted device
+pub struct CryptDevice<D: BlockDevice> {
+    device: D,
+    cipher: AesXts,
+}
+
+impl<D: BlockDevice> CryptDevice<D> {
+    /// Writes a new header protected by `passphrase`. Whatever was on the device becomes
+    /// unreadable garbage; blocks read back before being written are not zeros.
+    pub fn format(device: D, passphrase: &[u8], iterations: u32) -> Result<Self, CryptError> {
+        let block_size = device.block_size();
+        if block_size < HEADER_SIZE || !block_size.is_multiple_of(crypto::aes::BLOCK_SIZE) {
+            return Err(CryptError::UnsupportedBlockSize);
+        }
+        if device.block_count() < 2 {
+            return Err(CryptError::Block(BlockError::NoSpace));
+        }
+        let salt = crypto::random_seed();
+        let key = derive_key(passphrase, &salt, iterations.max(1));
+        let header = Header {
+            iterations: iterations.max(1),
+            salt,
+            key_check: key_check(&key),
+        };
+        let mut buf = vec![0; block_size];
+        header.encode(&mut buf);
+        device.write_block(0, &buf)?;
+        device.flush()?;
+        Ok(CryptDevice {
+            device,
+            cipher: AesXts::new(&key).unwrap(),
+        })
+    }
+
+    /// Unlocks a device set up by `format`
+    pub fn open(device: D, passphrase: &[u8]) -> Result<Self, CryptError> {
+        let block_size = device.block_size();
+        if block_size < HEADER_SIZE || !block_size.is_multiple_of(crypto::aes::BLOCK_SIZE) {
+            return Err(CryptError::UnsupportedBlockSize);
+        }
+        let mut buf = vec![0; block_size];
+        device.read_block(0, &mut buf)?;
+        let header = Header::decode(&buf).ok_or(CryptError::NotEncrypted)?;
+        let key = derive_key(passphrase, &header.salt, header.iterations);
+        if key_check(&key) != header.key_check {
+            return Err(CryptError::WrongPassphrase);
+        }
+        Ok(CryptDevice {
+            device,
+            cipher: AesXts::new(&key).unwrap(),
+        })
+    }
+
+    /// Gives the backing device back
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for CryptDevice<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count() - 1
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        self.device.read_block(block + 1, buf)?;
+        self.cipher.decrypt_sector(block, buf);
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let mut encrypted = buf.to_vec();
+        self.cipher.encrypt_sector(block, &mut encrypted);
+        self.device.write_block(block + 1, &encrypted)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Unlocks the registered device `name` and registers its decrypted view as `<name>-crypt`
+pub fn unlock(name: &str, passphrase: &[u8]) -> Result<String, CryptError> {
+    let backing = super::device(name).ok_or(CryptError::NoSuchDevice)?;
+    let crypt_name = format!("{}-crypt", name);
+    // check before spending the key derivation on it
+    if super::device(&crypt_name).is_some() {
+        return Err(CryptError::AlreadyUnlocked);
+    }
+    let device = Arc::new(CryptDevice::open(backing, passphrase)?);
+    if !super::register(&crypt_name, device) {
+        return Err(CryptError::AlreadyUnlocked);
+    }
+    Ok(crypt_name)
+}
+
+/// `crypt unlock <device> <passphrase>`; the passphrase is the rest of the line, its words joined
+/// by single spaces
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let ["unlock", name, passphrase @ ..] = args else {
+        return Err("usage: crypt unlock <device> <passphrase>");
+    };
+    if passphrase.is_empty() {
+        return Err("usage: crypt unlock <device> <passphrase>");
+    }
+    match unlock(name, passphrase.join(" ").as_bytes()) {
+        Ok(crypt_name) => println!("crypt: {} unlocked as {}", name, crypt_name),
+        Err(err) => println!("crypt: {}: {}", name, err),
+    }
+    Ok(())
+}
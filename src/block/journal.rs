@@ -0,0 +1,267 @@
+//! Ordered-mode block journal.
+//!
+//! Data blocks are written in place before a transaction commits, while metadata blocks are first
+//! logged to the journal area and only copied to their home location once the commit record is on
+//! disk. After an unclean shutdown `Journal::open` replays the committed transaction, so the
+//! filesystem metadata is either entirely before or entirely after it, never half-written.
+//!
+//! Journal area layout (relative to its first block):
+//!
+//! ```text
+//! 0       journal superblock (magic, version, length, next sequence number)
+//! 1       descriptor block   (sequence, count, home block of each logged block)
+//! 2..=n+1 copies of the n logged metadata blocks
+//! n+2     commit block       (sequence, count, checksum over the logged copies)
+//! ```
+//!
+//! Each commit is checkpointed straight away, so the log never holds more than one transaction.
+
+use super::{BlockDevice, BlockError};
+use crate::crypto::Crc32c;
+use crate::endian::{read_u32_le, read_u64_le, write_u32_le, write_u64_le};
+use alloc::vec;
+use alloc::vec::Vec;
+use layout::{decode_superblock, encode_superblock, FIRST_SEQUENCE, JOURNAL_MAGIC};
+
+mod layout;
+
+const KIND_DESCRIPTOR: u32 = 1;
+const KIND_COMMIT: u32 = 2;
+
+/// Bytes in front of the home block list of a descriptor block
+const RECORD_HEADER_SIZE: usize = 24;
+
+/// Metadata updates that become visible on disk all at once
+pub struct Transaction {
+    block_size: usize,
+    blocks: Vec<(u64, Vec<u8>)>,
+}
+
+impl Transaction {
+    /// Stages the new contents of metadata block `block`; a later write to the same block
+    /// replaces this one. `data` must be exactly one block.
+    pub fn write_metadata(&mut self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.len() != self.block_size {
+            return Err(BlockError::BadBufferSize);
+        }
+        match self.blocks.iter_mut().find(|(home, _)| *home == block) {
+            Some((_, contents)) => contents.copy_from_slice(data),
+            None => self.blocks.push((block, data.to_vec())),
+        }
+        Ok(())
+    }
+
+    /// Returns the staged contents of `block`, so readers see their own uncommitted writes
+    pub fn staged(&self, block: u64) -> Option<&[u8]> {
+        self.blocks
+            .iter()
+            .find(|(home, _)| *home == block)
+            .map(|(_, contents)| contents.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// A journal living in blocks `start..start + len` of `device`
+pub struct Journal<D: BlockDevice> {
+    device: D,
+    start: u64,
+    len: u64,
+    sequence: u64, // sequence number the next transaction will get
+}
+
+impl<D: BlockDevice> Journal<D> {
+    /// Writes an empty journal superblock, discarding whatever the area held before
+    pub fn format(device: D, start: u64, len: u64) -> Result<Self, BlockError> {
+        let journal = Journal::new(device, start, len, FIRST_SEQUENCE)?;
+        journal.write_superblock()?;
+        journal.device.flush()?;
+        Ok(journal)
+    }
+
+    /// Opens an existing journal and replays a transaction that committed but was not
+    /// checkpointed before the last shutdown
+    pub fn open(device: D, start: u64, len: u64) -> Result<Self, BlockError> {
+        let mut buf = vec![0; device.block_size()];
+        device.read_block(start, &mut buf)?;
+        let sequence = decode_superblock(&buf, len).ok_or(BlockError::Corrupted)?;
+        let mut journal = Journal::new(device, start, len, sequence)?;
+        if let Some(transaction) = journal.read_logged_transaction()? {
+            journal.checkpoint(&transaction)?;
+        }
+        Ok(journal)
+    }
+
+    fn new(device: D, start: u64, len: u64, sequence: u64) -> Result<Self, BlockError> {
+        // superblock, descriptor, commit and room for at least one logged block
+        if len < 4 || start + len > device.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        if device.block_size() < RECORD_HEADER_SIZE + 8 {
+            return Err(BlockError::BadBufferSize);
+        }
+        Ok(Journal {
+            device,
+            start,
+            len,
+            sequence,
+        })
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Most metadata blocks a single transaction can carry
+    pub fn capacity(&self) -> usize {
+        let by_area = (self.len - 3) as usize;
+        let by_descriptor = (self.device.block_size() - RECORD_HEADER_SIZE) / 8;
+        by_area.min(by_descriptor)
+    }
+
+    pub fn begin(&self) -> Transaction {
+        Transaction {
+            block_size: self.device.block_size(),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Writes a data block in place. In ordered mode data goes to disk before the transaction
+    /// that references it commits, so metadata never points at stale contents.
+    pub fn write_data(&self, block: u64, data: &[u8]) -> Result<(), BlockError> {
+        if self.in_journal_area(block) {
+            return Err(BlockError::ReadOnly);
+        }
+        self.device.write_block(block, data)
+    }
+
+    /// Makes every metadata write in `transaction` durable as a unit
+    pub fn commit(&mut self, transaction: Transaction) -> Result<(), BlockError> {
+        if transaction.is_empty() {
+            return Ok(());
+        }
+        if transaction.len() > self.capacity() {
+            return Err(BlockError::NoSpace);
+        }
+        let block_size = self.device.block_size();
+        for (home, contents) in transaction.blocks.iter() {
+            if self.in_journal_area(*home) || *home >= self.device.block_count() {
+                return Err(BlockError::OutOfRange);
+            }
+            if contents.len() != block_size {
+                return Err(BlockError::BadBufferSize);
+            }
+        }
+
+        // ordered data written through write_data must be stable before the commit record
+        self.device.flush()?;
+
+        let mut descriptor = vec![0; block_size];
+        self.write_record_header(&mut descriptor, KIND_DESCRIPTOR, transaction.len());
+        for (i, (home, _)) in transaction.blocks.iter().enumerate() {
+            write_u64_le(&mut descriptor, RECORD_HEADER_SIZE + i * 8, *home);
+        }
+        self.device.write_block(self.start + 1, &descriptor)?;
+        for (i, (_, contents)) in transaction.blocks.iter().enumerate() {
+            self.device
+                .write_block(self.start + 2 + i as u64, contents)?;
+        }
+        self.device.flush()?;
+
+        let mut commit = vec![0; block_size];
+        self.write_record_header(&mut commit, KIND_COMMIT, transaction.len());
+        write_u32_le(&mut commit, 20, checksum(&transaction.blocks));
+        self.device
+            .write_block(self.start + 2 + transaction.len() as u64, &commit)?;
+        self.device.flush()?; // the transaction is durable from here on
+
+        self.checkpoint(&transaction)
+    }
+
+    /// Copies logged blocks to their home locations and retires the transaction
+    fn checkpoint(&mut self, transaction: &Transaction) -> Result<(), BlockError> {
+        for (home, contents) in transaction.blocks.iter() {
+            self.device.write_block(*home, contents)?;
+        }
+        self.device.flush()?;
+
+        self.sequence += 1;
+        self.write_superblock()?;
+        self.device.flush()
+    }
+
+    /// Reads back the transaction in the log if it is complete and not yet checkpointed
+    fn read_logged_transaction(&self) -> Result<Option<Transaction>, BlockError> {
+        let block_size = self.device.block_size();
+        let mut descriptor = vec![0; block_size];
+        self.device.read_block(self.start + 1, &mut descriptor)?;
+        if !self.is_record(&descriptor, KIND_DESCRIPTOR) {
+            return Ok(None);
+        }
+        let count = read_u32_le(&descriptor, 16) as usize;
+        if count == 0 || count > self.capacity() {
+            return Ok(None);
+        }
+
+        let mut transaction = self.begin();
+        for i in 0..count {
+            let home = read_u64_le(&descriptor, RECORD_HEADER_SIZE + i * 8);
+            let mut contents = vec![0; block_size];
+            self.device
+                .read_block(self.start + 2 + i as u64, &mut contents)?;
+            transaction.blocks.push((home, contents));
+        }
+
+        let mut commit = vec![0; block_size];
+        self.device
+            .read_block(self.start + 2 + count as u64, &mut commit)?;
+        let complete = self.is_record(&commit, KIND_COMMIT)
+            && read_u32_le(&commit, 16) as usize == count
+            && read_u32_le(&commit, 20) == checksum(&transaction.blocks);
+        Ok(if complete { Some(transaction) } else { None })
+    }
+
+    fn write_superblock(&self) -> Result<(), BlockError> {
+        let mut buf = vec![0; self.device.block_size()];
+        encode_superblock(&mut buf, self.len, self.sequence);
+        self.device.write_block(self.start, &buf)
+    }
+
+    fn write_record_header(&self, buf: &mut [u8], kind: u32, count: usize) {
+        write_u32_le(buf, 0, JOURNAL_MAGIC);
+        write_u32_le(buf, 4, kind);
+        write_u64_le(buf, 8, self.sequence);
+        write_u32_le(buf, 16, count as u32);
+    }
+
+    /// Records from earlier (already checkpointed) transactions carry an older sequence number
+    fn is_record(&self, buf: &[u8], kind: u32) -> bool {
+        read_u32_le(buf, 0) == JOURNAL_MAGIC
+            && read_u32_le(buf, 4) == kind
+            && read_u64_le(buf, 8) == self.sequence
+    }
+
+    fn in_journal_area(&self, block: u64) -> bool {
+        (self.start..self.start + self.len).contains(&block)
+    }
+}
+
+/// CRC-32C over the home block numbers and contents of a transaction
+fn checksum(blocks: &[(u64, Vec<u8>)]) -> u32 {
+    let mut crc = Crc32c::new();
+    for (home, contents) in blocks {
+        crc.update(&home.to_le_bytes());
+        crc.update(contents);
+    }
+    crc.finish()
+}
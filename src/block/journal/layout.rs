@@ -0,0 +1,34 @@
+//! The journal superblock, the one part of a journal that `tools/mkfs` writes: its SFS images
+//! start out with an empty journal.
+//!
+//! Shared with mkfs, which includes this file; it depends on nothing but `core` and
+//! `crate::endian`, which mkfs includes as well.
+
+use crate::endian::{read_u32_le, read_u64_le, write_u32_le, write_u64_le};
+
+pub const JOURNAL_MAGIC: u32 = 0x4A52_4E4C; // "JRNL"
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// Sequence number of the first transaction in a new journal
+pub const FIRST_SEQUENCE: u64 = 1;
+
+/// Fills `buf` as the superblock of a journal `len` blocks long whose next transaction gets
+/// `sequence`
+pub fn encode_superblock(buf: &mut [u8], len: u64, sequence: u64) {
+    write_u32_le(buf, 0, JOURNAL_MAGIC);
+    write_u32_le(buf, 4, JOURNAL_VERSION);
+    write_u64_le(buf, 8, len);
+    write_u64_le(buf, 16, sequence);
+}
+
+/// The sequence number the next transaction gets, if `buf` is the superblock of a journal `len`
+/// blocks long
+pub fn decode_superblock(buf: &[u8], len: u64) -> Option<u64> {
+    if read_u32_le(buf, 0) != JOURNAL_MAGIC
+        || read_u32_le(buf, 4) != JOURNAL_VERSION
+        || read_u64_le(buf, 8) != len
+    {
+        return None;
+    }
+    Some(read_u64_le(buf, 16))
+}
@@ -0,0 +1,118 @@
+//! Loop devices: a regular file on a mounted filesystem used as a block device, so images can be
+//! built, formatted and mounted without leaving the running kernel.
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::fs::sfs::Sfs;
+use crate::fs::{FileKind, FsError};
+use alloc::sync::Arc;
+use spin::Mutex;
+
+/// Exposes file `inode` of a shared SFS volume as a device of `block_size`-byte blocks.
+///
+/// The geometry is fixed when the device is set up; a trailing partial block is not exposed.
+pub struct LoopDevice<D: BlockDevice> {
+    fs: Arc<Mutex<Sfs<D>>>,
+    inode: u64,
+    block_size: usize,
+    block_count: u64,
+    read_only: bool,
+}
+
+impl<D: BlockDevice> LoopDevice<D> {
+    /// Attaches to an existing file
+    pub fn new(
+        fs: Arc<Mutex<Sfs<D>>>,
+        inode: u64,
+        block_size: usize,
+        read_only: bool,
+    ) -> Result<Self, FsError> {
+        if block_size == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let metadata = fs.lock().stat(inode)?;
+        if metadata.kind != FileKind::File {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(LoopDevice {
+            fs,
+            inode,
+            block_size,
+            block_count: metadata.size / block_size as u64,
+            read_only,
+        })
+    }
+
+    /// Creates `name` in directory `dir`, sized to `block_count` zeroed blocks, and attaches to it
+    pub fn create(
+        fs: Arc<Mutex<Sfs<D>>>,
+        dir: u64,
+        name: &str,
+        block_size: usize,
+        block_count: u64,
+    ) -> Result<Self, FsError> {
+        let size = block_count
+            .checked_mul(block_size as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        let inode = {
+            let mut fs = fs.lock();
+            let inode = fs.create(dir, name, FileKind::File)?;
+            fs.truncate(inode, size)?;
+            inode
+        };
+        LoopDevice::new(fs, inode, block_size, false)
+    }
+
+    /// The backing file
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+}
+
+/// Filesystem errors seen through the block device interface
+fn to_block_error(err: FsError) -> BlockError {
+    match err {
+        FsError::Block(err) => err,
+        FsError::NoSpace => BlockError::NoSpace,
+        FsError::Corrupted => BlockError::Corrupted,
+        _ => BlockError::Io, // e.g. the backing file was removed underneath us
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for LoopDevice<D> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let offset = block * self.block_size as u64;
+        let read = self
+            .fs
+            .lock()
+            .read(self.inode, offset, buf)
+            .map_err(to_block_error)?;
+        buf[read..].fill(0); // the file was truncated since the device was set up
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let offset = block * self.block_size as u64;
+        self.fs
+            .lock()
+            .write(self.inode, offset, buf)
+            .map_err(to_block_error)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.fs.lock().sync().map_err(to_block_error)
+    }
+}
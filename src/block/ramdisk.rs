@@ -0,0 +1,120 @@
+//! RAM-backed block devices (`ram0`, `ram1`, ...).
+//!
+//! The contents live in physical frames taken straight from the frame allocator and accessed
+//! through the physical memory mapping, so a disk can be far larger than the kernel heap. Those
+//! frames are never returned, as disks are never removed.
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::memory::{self, FRAME_ALLOCATOR};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Size of the `ram0` disk created at boot
+pub const BOOT_RAM_DISK_SIZE: u64 = 4 * 1024 * 1024;
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+pub struct RamDisk {
+    /// Frames holding the contents, in order; guarded so concurrent block copies don't tear
+    pages: Mutex<Vec<PhysFrame>>,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl RamDisk {
+    /// Allocates and zeroes a disk of `block_count` blocks; `block_size` must divide the page size
+    pub fn new(block_size: usize, block_count: u64) -> Result<RamDisk, BlockError> {
+        if block_size == 0 || !PAGE_SIZE.is_multiple_of(block_size) {
+            return Err(BlockError::BadBufferSize);
+        }
+        let bytes = block_count
+            .checked_mul(block_size as u64)
+            .ok_or(BlockError::NoSpace)?;
+        let page_count = bytes.div_ceil(PAGE_SIZE as u64) as usize;
+
+        let mut pages = Vec::new();
+        pages
+            .try_reserve_exact(page_count)
+            .map_err(|_| BlockError::NoSpace)?;
+        {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator.as_mut().ok_or(BlockError::Io)?;
+            for _ in 0..page_count {
+                let frame: PhysFrame<Size4KiB> = frame_allocator
+                    .allocate_frame()
+                    .ok_or(BlockError::NoSpace)?;
+                pages.push(frame);
+            }
+        }
+        for frame in pages.iter() {
+            unsafe { page_ptr(*frame).write_bytes(0, PAGE_SIZE) };
+        }
+
+        Ok(RamDisk {
+            pages: Mutex::new(pages),
+            block_size,
+            block_count,
+        })
+    }
+
+    /// Where block `block` starts, as a frame and an offset within it
+    fn locate(&self, block: u64) -> (usize, usize) {
+        let byte = block * self.block_size as u64;
+        (
+            (byte / PAGE_SIZE as u64) as usize,
+            (byte % PAGE_SIZE as u64) as usize,
+        )
+    }
+}
+
+fn page_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let (page, offset) = self.locate(block);
+        let pages = self.pages.lock();
+        unsafe {
+            let src = page_ptr(pages[page]).add(offset);
+            core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let (page, offset) = self.locate(block);
+        let pages = self.pages.lock();
+        unsafe {
+            let dst = page_ptr(pages[page]).add(offset);
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+        }
+        Ok(())
+    }
+}
+
+/// Creates a RAM disk of at least `size` bytes and registers it under the next free `ramN` name
+pub fn create(size: u64) -> Result<(String, Arc<RamDisk>), BlockError> {
+    let block_count = size.div_ceil(DEFAULT_BLOCK_SIZE as u64);
+    let disk = Arc::new(RamDisk::new(DEFAULT_BLOCK_SIZE, block_count)?);
+    let name = (0..)
+        .map(|index| format!("ram{}", index))
+        .find(|name| super::register(name, disk.clone()))
+        .expect("RAM disk names exhausted");
+    Ok((name, disk))
+}
@@ -0,0 +1,188 @@
+//! Compressed RAM disks (`zram0`, ...).
+//!
+//! Each block is LZ4-compressed into its own heap allocation when written. Blocks that are all
+//! zeros take no memory at all, and blocks that don't compress are stored as they are, so a block
+//! never costs more than its size plus a small header.
+//!
+//! `zram=<KiB>` on the command line creates one at boot, and the `zram` shell command creates more
+//! and shows how well each one compresses.
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::compress::lz4;
+use crate::println;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Block size of the devices made at boot and by the shell
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+/// Every device `create` made, for the shell's listing
+static DEVICES: Mutex<Vec<(String, Arc<ZramDevice>)>> = Mutex::new(Vec::new());
+
+enum Slot {
+    Zero,
+    Compressed(Box<[u8]>),
+    /// Compression didn't save anything
+    Raw(Box<[u8]>),
+}
+
+/// Memory use of a compressed RAM disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZramStats {
+    /// Blocks holding data other than zeros
+    pub stored_blocks: u64,
+    /// Uncompressed size of those blocks
+    pub original_bytes: u64,
+    /// Heap bytes spent storing them
+    pub stored_bytes: u64,
+}
+
+pub struct ZramDevice {
+    slots: Mutex<Vec<Slot>>,
+    block_size: usize,
+}
+
+impl ZramDevice {
+    /// Creates an all-zero device; memory is only used as blocks are written
+    pub fn new(block_size: usize, block_count: u64) -> Result<ZramDevice, BlockError> {
+        if block_size == 0 || block_size > lz4::MAX_INPUT_SIZE {
+            return Err(BlockError::BadBufferSize);
+        }
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(block_count as usize)
+            .map_err(|_| BlockError::NoSpace)?;
+        slots.resize_with(block_count as usize, || Slot::Zero);
+        Ok(ZramDevice {
+            slots: Mutex::new(slots),
+            block_size,
+        })
+    }
+
+    pub fn stats(&self) -> ZramStats {
+        let slots = self.slots.lock();
+        let mut stats = ZramStats::default();
+        for slot in slots.iter() {
+            let stored = match slot {
+                Slot::Zero => continue,
+                Slot::Compressed(data) | Slot::Raw(data) => data.len(),
+            };
+            stats.stored_blocks += 1;
+            stats.original_bytes += self.block_size as u64;
+            stats.stored_bytes += stored as u64;
+        }
+        stats
+    }
+
+    /// Drops the contents of `block`, which then reads as zeros (discard/TRIM)
+    pub fn discard(&self, block: u64) -> Result<(), BlockError> {
+        let mut slots = self.slots.lock();
+        let slot = slots
+            .get_mut(block as usize)
+            .ok_or(BlockError::OutOfRange)?;
+        *slot = Slot::Zero;
+        Ok(())
+    }
+}
+
+impl BlockDevice for ZramDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.slots.lock().len() as u64
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let slots = self.slots.lock();
+        match &slots[block as usize] {
+            Slot::Zero => buf.fill(0),
+            Slot::Raw(data) => buf.copy_from_slice(data),
+            Slot::Compressed(data) => match lz4::decompress(data, buf) {
+                Ok(len) if len == buf.len() => {}
+                _ => return Err(BlockError::Corrupted),
+            },
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        // compress before taking the lock, it's the slow part
+        let slot = if buf.iter().all(|&byte| byte == 0) {
+            Slot::Zero
+        } else {
+            let mut compressed = Vec::new();
+            compressed
+                .try_reserve(lz4::max_compressed_size(buf.len()))
+                .map_err(|_| BlockError::NoSpace)?;
+            lz4::compress(buf, &mut compressed);
+            if compressed.len() < buf.len() {
+                Slot::Compressed(compressed.into_boxed_slice())
+            } else {
+                Slot::Raw(Box::from(buf))
+            }
+        };
+        self.slots.lock()[block as usize] = slot;
+        Ok(())
+    }
+}
+
+/// Creates a compressed RAM disk of `size` bytes and registers it under the next free `zramN` name
+pub fn create(size: u64, block_size: usize) -> Result<(String, Arc<ZramDevice>), BlockError> {
+    let block_count = size.div_ceil(block_size as u64);
+    let device = Arc::new(ZramDevice::new(block_size, block_count)?);
+    let name = (0..)
+        .map(|index| format!("zram{}", index))
+        .find(|name| super::register(name, device.clone()))
+        .expect("zram device names exhausted");
+    DEVICES.lock().push((name.clone(), device.clone()));
+    Ok((name, device))
+}
+
+/// `zram [create <KiB>]`: lists the compressed RAM disks with their memory use, or makes a new one
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            println!(
+                "{:<8} {:>10} {:>10} {:>10} {:>10}",
+                "DEVICE", "SIZE KiB", "DATA KiB", "USED KiB", "RATIO"
+            );
+            for (name, device) in DEVICES.lock().iter() {
+                let stats = device.stats();
+                let size = device.block_count() * device.block_size() as u64;
+                let ratio = match stats.stored_bytes {
+                    0 => 0,
+                    stored => stats.original_bytes * 100 / stored,
+                };
+                println!(
+                    "{:<8} {:>10} {:>10} {:>10} {:>7}.{:02}",
+                    name,
+                    size / 1024,
+                    stats.original_bytes / 1024,
+                    stats.stored_bytes / 1024,
+                    ratio / 100,
+                    ratio % 100
+                );
+            }
+        }
+        ["create", kib] => {
+            let kib = match kib.parse::<u64>() {
+                Ok(kib) if kib > 0 => kib,
+                _ => return Err("zram: the size is a number of KiB"),
+            };
+            match create(kib * 1024, DEFAULT_BLOCK_SIZE) {
+                Ok((name, _)) => println!("zram: {} created, {} KiB", name, kib),
+                Err(err) => println!("zram: {}", err),
+            }
+        }
+        _ => return Err("usage: zram [create <KiB>]"),
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! Bringing the kernel up, one phase after the other.
+//!
+//! `run` goes through `PHASES` in order and then hands the CPU to the executor. A phase that
+//! can't go on returns an error, and the boot stops there with a report naming the phase, the
+//! error and the phases that completed; a panic during a phase names the phase too (see
+//! `phase`). Problems the kernel can live with, like a missing device, a phase just prints.
+//!
+//! Code that only the boot runs, like the phases themselves, goes in the `.init.text` section
+//! (see `sections`), which is unmapped and its memory reused once the phases are done. Before
+//! that the kernel's code and read-only data are made read-only, and its data non-executable,
+//! see `memory::protect_kernel`.
+//!
+//! Before the first phase a bare IDT is loaded, `interrupts::early`, so that even a fault in the
+//! early console phase is reported rather than reset the machine. That phase then loads the GDT
+//! and the real IDT before anything else, so that a fault in a later phase ends up in a handler
+//! that can say more.
+//!
+//! `run_until` runs only the first phases, for the test kernels in `tests/`.
+//!
+//! The boot also counts itself as in progress in `nvram` until the phases are done, and follows
+//! the settings kept there: with `loglevel=quiet` the display stays blank unless it fails. When
+//! the boots before it keep failing, it falls back to `safe_mode`, so that an experiment that
+//! breaks the boot on real hardware still leaves a kernel that comes up to fix it from.
+
+use crate::bootinfo::BootInfo;
+use crate::drivers::{pcspeaker, serial, virtio};
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub;
+#[cfg(feature = "gfx")]
+use crate::gfx;
+#[cfg(feature = "net")]
+use crate::net;
+use crate::nvram::LogLevel;
+#[cfg(feature = "smp")]
+use crate::smp;
+use crate::task::executor::Executor;
+use crate::task::Task;
+use crate::{
+    allocator, block, clock, cmdline, console, cputime, crash, drivers, errorln, fpu, fs, gdt,
+    interrupts, klog, memory, numa, nvram, pci, percpu, power, println, println_colored, process,
+    profile, scrub, signing, syscall, sysinfo, task, time, trace, tty, usermode, warnln, watchdog,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// What the phases work with
+struct Context {
+    boot_info: &'static BootInfo,
+    /// Made by the scheduler phase, once there's a heap for it
+    executor: Option<Executor>,
+}
+
+struct Phase {
+    name: &'static str,
+    run: fn(&mut Context) -> Result<(), &'static str>,
+}
+
+static PHASES: &[Phase] = &[
+    Phase {
+        name: "early console",
+        run: early_console,
+    },
+    Phase {
+        name: "memory",
+        run: memory,
+    },
+    Phase {
+        name: "interrupts",
+        run: interrupts,
+    },
+    Phase {
+        name: "drivers",
+        run: drivers,
+    },
+    Phase {
+        name: "scheduler",
+        run: scheduler,
+    },
+    Phase {
+        name: "userspace",
+        run: userspace,
+    },
+];
+
+/// Boots in a row that didn't complete before the next is in safe mode
+const SAFE_MODE_AFTER: u8 = 3;
+
+/// Index of the phase running, `PHASES.len()` once they're all done
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The phase the boot is in, `None` once it's over
+pub fn phase() -> Option<&'static str> {
+    PHASES
+        .get(CURRENT.load(Ordering::Relaxed))
+        .map(|phase| phase.name)
+}
+
+/// Whether the boot is in safe mode, see `safe_mode`
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the kernel boots with the fewest moving parts: the boot CPU only, the text and serial
+/// consoles, and only the drivers for them and the keyboard (see `drivers::init`). It does after
+/// `SAFE_MODE_AFTER` failed boots in a row, or with `safe_mode` on the command line.
+pub fn safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// Runs every phase, then the executor; stops with a report if a phase fails. The kernel's
+/// entry calls it once it has the boot information, see `bootinfo`.
+pub fn run(boot_info: &'static BootInfo) -> ! {
+    interrupts::early::init();
+    memory::init_offset(boot_info);
+    nvram::init();
+    if nvram::failed_boots() >= SAFE_MODE_AFTER {
+        SAFE_MODE.store(true, Ordering::Relaxed);
+    } else if nvram::settings().log_level == LogLevel::Quiet {
+        console::set_quiet(true);
+    }
+    let mut context = Context {
+        boot_info,
+        executor: None,
+    };
+    run_phases(&mut context, PHASES);
+    if let Err(page) = memory::protect_kernel() {
+        warnln!("boot: couldn't protect the kernel page at {:#x}", page);
+    }
+    println!(
+        "boot: freed {} KiB of init memory",
+        memory::reclaim_init() / 1024
+    );
+    sysinfo::print_banner();
+    nvram::boot_complete();
+    console::set_quiet(false);
+    match context.executor {
+        Some(mut executor) => executor.run(),
+        None => fail(PHASES.len() - 1, "no phase started the executor"),
+    }
+}
+
+/// Runs the phases up to and including the one called `last`, and returns. For test kernels,
+/// which check a part of the kernel and don't need the rest: the boot isn't counted in `nvram`,
+/// and the init memory stays mapped.
+pub fn run_until(boot_info: &'static BootInfo, last: &str) {
+    interrupts::early::init();
+    memory::init_offset(boot_info);
+    let end = PHASES
+        .iter()
+        .position(|phase| phase.name == last)
+        .expect("no boot phase of that name");
+    let mut context = Context {
+        boot_info,
+        executor: None,
+    };
+    run_phases(&mut context, &PHASES[..=end]);
+}
+
+/// Runs `phases`, the first of `PHASES` up to some point, stopping with a report if one fails
+fn run_phases(context: &mut Context, phases: &[Phase]) {
+    for (index, phase) in phases.iter().enumerate() {
+        CURRENT.store(index, Ordering::Relaxed);
+        if let Err(err) = (phase.run)(context) {
+            fail(index, err);
+        }
+    }
+    CURRENT.store(PHASES.len(), Ordering::Relaxed);
+}
+
+/// Reports that the phase at `index` failed with `err` and halts
+fn fail(index: usize, err: &str) -> ! {
+    let phase = &PHASES[index];
+    console::set_quiet(false);
+    println!();
+    errorln!(
+        "boot failed in phase {} of {}, {}: {}",
+        index + 1,
+        PHASES.len(),
+        phase.name,
+        err
+    );
+    for done in &PHASES[..index] {
+        println!("  done: {}", done.name);
+    }
+    crate::hlt_loop();
+}
+
+/// The banner, and the CPU tables that make faults reportable
+#[link_section = ".init.text"]
+fn early_console(_context: &mut Context) -> Result<(), &'static str> {
+    println_colored!(console::theme("boot"); "Hello World{}", "!");
+    gdt::init();
+    interrupts::init_idt();
+    fpu::init();
+    usermode::emulate::init();
+    Ok(())
+}
+
+/// Paging, the frame allocator and the heap, then the command line, which needs the heap and
+/// which the later phases look at, and what needs the heap to watch memory
+#[link_section = ".init.text"]
+fn memory(context: &mut Context) -> Result<(), &'static str> {
+    memory::caching::init();
+    unsafe { memory::init(context.boot_info) };
+    allocator::init_heap().map_err(|_| "failed to map the heap")?;
+    cmdline::init();
+    if cmdline::flag("safe_mode") {
+        SAFE_MODE.store(true, Ordering::Relaxed);
+        console::set_quiet(false);
+    }
+    if safe_mode() {
+        println!(
+            "boot: safe mode, {} boots in a row didn't complete",
+            nvram::failed_boots()
+        );
+    }
+    crash::init();
+    profile::init();
+    if cmdline::flag("heap_debug") {
+        allocator::set_debug(true);
+    }
+    if cmdline::flag("allow_unsigned") {
+        signing::allow_unsigned(true);
+    }
+    numa::init();
+    gdt::protect();
+    interrupts::protect();
+    scrub::register(&memory::PAGE_TABLE_ROOTS);
+    scrub::register(&process::PROCESSES);
+    scrub::register(&klog::HEADER);
+    if let Err(err) = block::ramdisk::create(block::ramdisk::BOOT_RAM_DISK_SIZE) {
+        warnln!("ramdisk: {}", err);
+    }
+    if let Some(kib) = cmdline::parse::<u64>("zram") {
+        if let Err(err) = block::zram::create(kib * 1024, block::zram::DEFAULT_BLOCK_SIZE) {
+            warnln!("zram: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// The interrupt controller and the clocks driven by it; interrupts are on afterwards
+#[link_section = ".init.text"]
+fn interrupts(_context: &mut Context) -> Result<(), &'static str> {
+    percpu::init(0, boot_apic_id());
+    syscall::init();
+    interrupts::init_controller();
+    x86_64::instructions::interrupts::enable();
+    clock::init();
+    cputime::init();
+    time::init();
+    interrupts::latency::init();
+    watchdog::init();
+    Ok(())
+}
+
+/// Filesystems, the built-in drivers and the buses they attach to, and the other CPUs
+#[link_section = ".init.text"]
+fn drivers(_context: &mut Context) -> Result<(), &'static str> {
+    fs::init();
+    drivers::init();
+    pci::init();
+    fs::mount_disks();
+    power::events::init();
+    power::battery::sbs::init();
+    #[cfg(feature = "gdbstub")]
+    if cmdline::flag("gdb") {
+        if let Err(err) = gdbstub::attach() {
+            warnln!("gdb: {}", err);
+        }
+    }
+    if safe_mode() {
+        return Ok(());
+    }
+    #[cfg(feature = "smp")]
+    smp::init();
+    #[cfg(feature = "gfx")]
+    if nvram::settings().console == nvram::Console::Graphics {
+        if let Err(err) = gfx::enable(1024, 768) {
+            warnln!("gfx: {}, staying on the text console", err);
+        }
+    }
+    Ok(())
+}
+
+/// The executor with the kernel's tasks, the process scheduler among them
+#[link_section = ".init.text"]
+fn scheduler(context: &mut Context) -> Result<(), &'static str> {
+    let mut executor = Executor::new();
+    task::shared::spawn(scrub::scrubber());
+    executor.spawn(Task::new(process::scheduler()));
+    task::shared::spawn(power::battery::monitor());
+    executor.spawn(Task::new(virtio::console::receiver()));
+    executor.spawn(Task::new(tty::input()));
+    executor.spawn(Task::new(serial::xmodem::receiver()));
+    executor.spawn(Task::new(pcspeaker::player()));
+    executor.spawn(Task::new(virtio::balloon::worker()));
+    #[cfg(feature = "net")]
+    {
+        executor.spawn(Task::new(net::nic::receiver()));
+        executor.spawn(Task::new(net::stack::worker()));
+        executor.spawn(Task::new(net::dhcp::client()));
+    }
+    executor.spawn(Task::new(trace::streamer()));
+    executor.spawn(Task::new(profile::streamer()));
+    executor.spawn(Task::new(watchdog::monitor()));
+    executor.spawn(Task::new(memory::inspect::watcher()));
+    executor.spawn(Task::new(memory::pressure::monitor()));
+    context.executor = Some(executor);
+    Ok(())
+}
+
+/// The first programs in ring 3
+#[link_section = ".init.text"]
+fn userspace(_context: &mut Context) -> Result<(), &'static str> {
+    if let Err(err) = usermode::run_demo() {
+        errorln!("usermode: {}", err);
+    }
+    Ok(())
+}
+
+/// APIC id of the boot CPU as reported by CPUID, usable before the Local APIC is mapped
+#[link_section = ".init.text"]
+fn boot_apic_id() -> u32 {
+    raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(0, |features| features.initial_local_apic_id() as u32)
+}
@@ -0,0 +1,250 @@
+//! What the bootloader hands the kernel, whichever bootloader it was.
+//!
+//! Each boot protocol describes the machine its own way; `BootInfo` is the one the rest of the
+//! kernel reads: the memory map, where physical memory is mapped, the framebuffer, the ACPI RSDP,
+//! the command line and the initrd. The protocols fill in what they know and leave the rest
+//! `None`, which the kernel then finds out for itself (the RSDP by scanning the BIOS areas, the
+//! command line from fw_cfg, see `cmdline`).
+//!
+//! The one protocol so far is the `bootloader` crate's (`rust_bootloader`), whose `BootInfo`
+//! `main.rs` is started with; it knows the memory map and the physical memory offset only.
+//! Another, like Multiboot2 for GRUB or a UEFI stub, comes with its own entry, which builds a
+//! `BootInfo` before anything else runs, so it's read without locks and before there's a heap;
+//! everything in it is copied out of the loader's structures, whose memory the frame allocator
+//! may hand out later.
+
+use crate::println;
+use core::fmt;
+use spin::Once;
+use x86_64::PhysAddr;
+
+pub mod rust_bootloader;
+
+/// Most memory map entries kept; the `bootloader` crate passes at most 64
+pub const MAX_REGIONS: usize = 128;
+/// Longest command line kept, the rest is cut off
+pub const CMDLINE_SIZE: usize = 256;
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Which protocol the kernel was booted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    RustBootloader,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::RustBootloader => "bootloader crate",
+        }
+    }
+}
+
+/// What a region of physical memory is used for when the kernel starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free for the kernel to use
+    Usable,
+    /// RAM that's taken, for something the loader doesn't say
+    InUse,
+    /// The kernel image and its boot stack
+    Kernel,
+    /// What the loader set up for the kernel: page tables, the boot information
+    Bootloader,
+    /// Files the loader put in memory, like the initrd
+    Module,
+    /// ACPI tables, RAM once they're read
+    AcpiReclaimable,
+    /// Firmware's, kept across sleep
+    AcpiNvs,
+    Bad,
+    /// Not RAM, or not for the kernel: firmware ranges, holes, device memory
+    Reserved,
+}
+
+impl MemoryKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryKind::Usable => "usable",
+            MemoryKind::InUse => "in use",
+            MemoryKind::Kernel => "kernel",
+            MemoryKind::Bootloader => "bootloader",
+            MemoryKind::Module => "module",
+            MemoryKind::AcpiReclaimable => "ACPI tables",
+            MemoryKind::AcpiNvs => "ACPI NVS",
+            MemoryKind::Bad => "bad",
+            MemoryKind::Reserved => "reserved",
+        }
+    }
+
+    /// Whether regions of this kind are RAM, as opposed to holes and firmware's ranges
+    pub fn is_ram(self) -> bool {
+        !matches!(self, MemoryKind::Reserved | MemoryKind::AcpiNvs)
+    }
+}
+
+/// Physical memory from `start` up to `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    const EMPTY: MemoryRegion = MemoryRegion {
+        start: 0,
+        end: 0,
+        kind: MemoryKind::Reserved,
+    };
+}
+
+/// The regions of physical memory, by address once the boot information is kept
+#[derive(Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    const fn new() -> MemoryMap {
+        MemoryMap {
+            regions: [MemoryRegion::EMPTY; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions[..self.len].iter()
+    }
+
+    /// Adds a region; one past `MAX_REGIONS` is dropped, so its memory goes unused
+    fn push(&mut self, region: MemoryRegion) {
+        if region.start >= region.end {
+            return;
+        }
+        match self.regions.get_mut(self.len) {
+            Some(slot) => {
+                *slot = region;
+                self.len += 1;
+            }
+            None => println!(
+                "bootinfo: memory map full, ignoring {:#x}..{:#x}",
+                region.start, region.end
+            ),
+        }
+    }
+}
+
+/// A linear framebuffer the loader set a graphics mode up for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub address: PhysAddr,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes from one line to the next
+    pub pitch: u32,
+    pub bits_per_pixel: u8,
+}
+
+/// What the kernel was told at boot, see the module docs
+#[derive(Clone)]
+pub struct BootInfo {
+    pub protocol: Protocol,
+    /// Where all of physical memory is mapped, see `memory::phys_to_virt`
+    pub physical_memory_offset: u64,
+    pub memory_map: MemoryMap,
+    pub framebuffer: Option<Framebuffer>,
+    /// Where the ACPI Root System Description Pointer, or a copy of it, is
+    pub rsdp: Option<PhysAddr>,
+    /// The initrd's start and size in bytes
+    pub initrd: Option<(PhysAddr, u64)>,
+    cmdline: [u8; CMDLINE_SIZE],
+    cmdline_len: usize,
+}
+
+impl BootInfo {
+    fn new(protocol: Protocol, physical_memory_offset: u64) -> BootInfo {
+        BootInfo {
+            protocol,
+            physical_memory_offset,
+            memory_map: MemoryMap::new(),
+            framebuffer: None,
+            rsdp: None,
+            initrd: None,
+            cmdline: [0; CMDLINE_SIZE],
+            cmdline_len: 0,
+        }
+    }
+
+    /// The command line the loader passed, if it passed one
+    pub fn cmdline(&self) -> Option<&str> {
+        let text = core::str::from_utf8(&self.cmdline[..self.cmdline_len]).ok()?;
+        (!text.trim().is_empty()).then_some(text)
+    }
+}
+
+/// Keeps `info` as the boot information; the first thing the kernel does
+fn set(mut info: BootInfo) -> &'static BootInfo {
+    let map = &mut info.memory_map;
+    map.regions[..map.len].sort_unstable_by_key(|region| region.start);
+    BOOT_INFO.call_once(|| info)
+}
+
+/// Takes the boot information from the `bootloader` crate's
+pub fn from_bootloader(info: &'static ::bootloader::BootInfo) -> &'static BootInfo {
+    set(rust_bootloader::parse(info))
+}
+
+/// The boot information, `None` only before the kernel's entry has set it
+pub fn get() -> Option<&'static BootInfo> {
+    BOOT_INFO.r#try()
+}
+
+impl fmt::Display for BootInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "protocol:         {}", self.protocol.name())?;
+        writeln!(
+            f,
+            "physical memory:  mapped at {:#x}",
+            self.physical_memory_offset
+        )?;
+        writeln!(f, "memory map:       {} regions", self.memory_map.len)?;
+        match self.framebuffer {
+            Some(fb) => writeln!(
+                f,
+                "framebuffer:      {}x{}x{} at {:#x}, pitch {}",
+                fb.width,
+                fb.height,
+                fb.bits_per_pixel,
+                fb.address.as_u64(),
+                fb.pitch
+            )?,
+            None => writeln!(f, "framebuffer:      none")?,
+        }
+        match self.rsdp {
+            Some(rsdp) => writeln!(f, "ACPI RSDP:        {:#x}", rsdp.as_u64())?,
+            None => writeln!(f, "ACPI RSDP:        not given")?,
+        }
+        match self.initrd {
+            Some((start, size)) => writeln!(
+                f,
+                "initrd:           {} KiB at {:#x}",
+                size / 1024,
+                start.as_u64()
+            )?,
+            None => writeln!(f, "initrd:           none")?,
+        }
+        write!(f, "command line:     {}", self.cmdline().unwrap_or("none"))
+    }
+}
+
+/// `bootinfo`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err("usage: bootinfo");
+    }
+    println!("{}", get().ok_or("no boot information")?);
+    Ok(())
+}
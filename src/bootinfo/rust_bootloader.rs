@@ -0,0 +1,37 @@
+//! The `bootloader` crate's boot information: a memory map and where physical memory is mapped
+//! (see `[package.metadata.bootloader]` in `Cargo.toml`), nothing else.
+
+use super::{BootInfo, MemoryKind, MemoryRegion, Protocol};
+use bootloader::bootinfo::MemoryRegionType;
+
+pub(super) fn parse(info: &bootloader::BootInfo) -> BootInfo {
+    let mut boot_info = BootInfo::new(Protocol::RustBootloader, info.physical_memory_offset);
+    for region in info.memory_map.iter() {
+        boot_info.memory_map.push(MemoryRegion {
+            start: region.range.start_addr(),
+            end: region.range.end_addr(),
+            kind: kind(region.region_type),
+        });
+    }
+    boot_info
+}
+
+fn kind(region_type: MemoryRegionType) -> MemoryKind {
+    match region_type {
+        MemoryRegionType::Usable => MemoryKind::Usable,
+        // frame zero is RAM held back so that null stays unmapped
+        MemoryRegionType::InUse | MemoryRegionType::FrameZero => MemoryKind::InUse,
+        MemoryRegionType::Kernel | MemoryRegionType::KernelStack => MemoryKind::Kernel,
+        MemoryRegionType::PageTable | MemoryRegionType::Bootloader | MemoryRegionType::BootInfo => {
+            MemoryKind::Bootloader
+        }
+        MemoryRegionType::Package => MemoryKind::Module,
+        MemoryRegionType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+        MemoryRegionType::AcpiNvs => MemoryKind::AcpiNvs,
+        MemoryRegionType::BadMemory => MemoryKind::Bad,
+        MemoryRegionType::Reserved
+        | MemoryRegionType::Empty
+        | MemoryRegionType::UnknownUefi(_)
+        | MemoryRegionType::UnknownBios(_) => MemoryKind::Reserved,
+    }
+}
@@ -0,0 +1,159 @@
+//! High-resolution monotonic time.
+//!
+//! `init` picks the best `ClockSource` the machine has. In a virtual machine that's the clock the
+//! hypervisor keeps for its guests, kvmclock or Hyper-V's reference TSC page, which stay right
+//! across migrations and host load where the emulated timers drift. Otherwise it's the HPET if
+//! ACPI describes one, then an invariant TSC calibrated against the PIT, and as a last resort the
+//! timer tick count. The hypervisor's clocks are passed over if `paravirt` says not to use them.
+//! `Instant` reads whichever source was chosen; spans of time use `core::time::Duration`. The
+//! clock stands still while the machine sleeps.
+
+use crate::paravirt::{self, Feature};
+use alloc::boxed::Box;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Once;
+
+pub mod hpet;
+pub mod hyperv;
+pub mod kvmclock;
+pub mod tsc;
+
+/// A monotonic counter that can be converted to nanoseconds
+pub trait ClockSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Nanoseconds since an arbitrary fixed point; never goes backwards
+    fn nanos(&self) -> u64;
+
+    /// Smallest step the counter advances by, in nanoseconds
+    fn resolution_ns(&self) -> u64;
+
+    /// Starts the counter again after sleep stopped or reset it, carrying on from `nanos`, the
+    /// last reading before sleep. Counters that sleep doesn't touch have nothing to do.
+    fn resume(&self, _nanos: u64) {}
+}
+
+/// Counts timer interrupts, so it only advances every `1 / TIMER_HZ` seconds
+pub struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "ticks"
+    }
+
+    fn nanos(&self) -> u64 {
+        crate::interrupts::ticks() * self.resolution_ns()
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        1_000_000_000 / crate::interrupts::TIMER_HZ as u64
+    }
+}
+
+static CLOCK: Once<Box<dyn ClockSource>> = Once::new();
+
+/// Selects the clock source. ACPI must be reachable, i.e. physical memory mapped.
+pub fn init() {
+    CLOCK.call_once(|| {
+        if paravirt::enabled(Feature::Clock) {
+            if let Some(kvmclock) = kvmclock::KvmClock::new() {
+                return Box::new(kvmclock);
+            }
+            if let Some(hyperv) = hyperv::HyperVClock::new() {
+                return Box::new(hyperv);
+            }
+        }
+        if let Some(hpet) = hpet::Hpet::new() {
+            return Box::new(hpet);
+        }
+        if let Some(tsc) = tsc::Tsc::new() {
+            return Box::new(tsc);
+        }
+        Box::new(TickClock)
+    });
+}
+
+/// The clock's reading when the machine went to sleep
+static SUSPENDED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Notes the time before the machine goes to sleep. Monotonic time doesn't count the time asleep:
+/// `resume` carries on from here.
+pub fn suspend() {
+    SUSPENDED_AT.store(source().nanos(), Ordering::SeqCst);
+}
+
+/// Restarts the clock source after waking from sleep
+pub fn resume() {
+    source().resume(SUSPENDED_AT.load(Ordering::SeqCst));
+}
+
+/// The clock source in use, the tick counter until `init` ran
+pub fn source() -> &'static dyn ClockSource {
+    static FALLBACK: TickClock = TickClock;
+    match CLOCK.r#try() {
+        Some(clock) => clock.as_ref(),
+        None => &FALLBACK,
+    }
+}
+
+/// A point in time as measured by the clock source, only meaningful relative to other `Instant`s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(source().nanos())
+    }
+
+    /// Time passed since `earlier`, zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+
+    /// Nanoseconds since the clock source's zero point
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
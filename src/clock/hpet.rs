@@ -0,0 +1,83 @@
+use super::ClockSource;
+use crate::{acpi, memory};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CAPABILITIES_64BIT_COUNTER: u64 = 1 << 13;
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+/// The High Precision Event Timer's main counter, which runs at 10 MHz or more
+pub struct Hpet {
+    base: VirtAddr,
+    /// Length of one counter tick in femtoseconds
+    period_fs: u64,
+    /// Added to the counter's time, which starts over from 0 after sleep
+    offset_ns: AtomicU64,
+}
+
+impl Hpet {
+    /// Maps and starts the HPET described by ACPI, if there is one
+    pub fn new() -> Option<Hpet> {
+        let table = acpi::hpet()?;
+        let base = memory::map_mmio(PhysAddr::new(table.address), 0x400).ok()?;
+        let mut hpet = Hpet {
+            base,
+            period_fs: 0,
+            offset_ns: AtomicU64::new(0),
+        };
+
+        let capabilities = unsafe { hpet.read(GENERAL_CAPABILITIES) };
+        hpet.period_fs = capabilities >> 32;
+        // a 32-bit counter wraps within minutes, which nanos() can't paper over
+        if hpet.period_fs == 0 || capabilities & CAPABILITIES_64BIT_COUNTER == 0 {
+            return None;
+        }
+        hpet.enable();
+        Some(hpet)
+    }
+
+    fn enable(&self) {
+        unsafe {
+            let configuration = self.read(GENERAL_CONFIGURATION);
+            self.write(GENERAL_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        }
+    }
+
+    fn counter_ns(&self) -> u64 {
+        let counter = unsafe { self.read(MAIN_COUNTER) };
+        (counter as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND as u128) as u64
+    }
+
+    unsafe fn read(&self, register: usize) -> u64 {
+        core::ptr::read_volatile((self.base.as_u64() as usize + register) as *const u64)
+    }
+
+    unsafe fn write(&self, register: usize, value: u64) {
+        core::ptr::write_volatile((self.base.as_u64() as usize + register) as *mut u64, value)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn nanos(&self) -> u64 {
+        self.counter_ns() + self.offset_ns.load(Ordering::Relaxed)
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        self.period_fs.div_ceil(FEMTOSECONDS_PER_NANOSECOND)
+    }
+
+    fn resume(&self, nanos: u64) {
+        self.enable();
+        let offset = nanos.saturating_sub(self.counter_ns());
+        self.offset_ns.store(offset, Ordering::Relaxed);
+    }
+}
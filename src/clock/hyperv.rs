@@ -0,0 +1,120 @@
+use super::ClockSource;
+use crate::memory::{self, FRAME_ALLOCATOR};
+use crate::paravirt;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Leaf after the base with the partition's privileges
+const FEATURES_LEAF: u32 = 3;
+const ACCESS_PARTITION_REFERENCE_COUNTER: u32 = 1 << 1;
+const ACCESS_PARTITION_REFERENCE_TSC: u32 = 1 << 9;
+
+/// Identifies the guest to the hypervisor; must be set before using most of its interfaces
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+/// The reference time in 100 ns units, read from the hypervisor, slowly
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+/// Takes the physical address of the reference TSC page, bit 0 enabling it
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const REFERENCE_TSC_ENABLE: u64 = 1 << 0;
+
+/// Bit 63 marks an open source guest, the rest is left for a vendor to fill in
+const GUEST_OS_ID: u64 = 1 << 63;
+const NANOSECONDS_PER_UNIT: u64 = 100;
+
+/// How the hypervisor scales the TSC to the partition's reference time
+#[repr(C)]
+struct ReferenceTscPage {
+    /// Changes when the rest does; 0 while the page can't be used and the MSR has to be read
+    sequence: u32,
+    _reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+/// Hyper-V's reference time, read through the reference TSC page. Like kvmclock it follows the
+/// TSC across migrations, as the hypervisor updates the page then.
+pub struct HyperVClock {
+    page: *const ReferenceTscPage,
+    /// Where `page` is, for the hypervisor
+    phys: PhysAddr,
+    /// Added to the reference time, so that sleep doesn't show
+    offset_ns: AtomicU64,
+}
+
+// the hypervisor writes `page`, the kernel only reads it
+unsafe impl Send for HyperVClock {}
+unsafe impl Sync for HyperVClock {}
+
+impl HyperVClock {
+    /// Sets up the reference TSC page, if running on Hyper-V, or on a hypervisor that acts like it
+    pub fn new() -> Option<HyperVClock> {
+        let base = paravirt::get().hyperv_base?;
+        let privileges = raw_cpuid::cpuid!(base + FEATURES_LEAF).eax;
+        let wanted = ACCESS_PARTITION_REFERENCE_COUNTER | ACCESS_PARTITION_REFERENCE_TSC;
+        if privileges & wanted != wanted {
+            return None;
+        }
+        let frame: PhysFrame<Size4KiB> = FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()?;
+        let page = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<ReferenceTscPage>();
+        unsafe { page.write_bytes(0, 1) };
+        let clock = HyperVClock {
+            page,
+            phys: frame.start_address(),
+            offset_ns: AtomicU64::new(0),
+        };
+        clock.enable();
+        Some(clock)
+    }
+
+    fn enable(&self) {
+        unsafe {
+            Msr::new(HV_X64_MSR_GUEST_OS_ID).write(GUEST_OS_ID);
+            Msr::new(HV_X64_MSR_REFERENCE_TSC).write(self.phys.as_u64() | REFERENCE_TSC_ENABLE);
+        }
+    }
+
+    /// The reference time in nanoseconds
+    fn read(&self) -> u64 {
+        let units = loop {
+            let sequence = unsafe { core::ptr::addr_of!((*self.page).sequence).read_volatile() };
+            if sequence == 0 {
+                break unsafe { Msr::new(HV_X64_MSR_TIME_REF_COUNT).read() };
+            }
+            fence(Ordering::Acquire);
+            let scale = unsafe { core::ptr::addr_of!((*self.page).scale).read_volatile() };
+            let offset = unsafe { core::ptr::addr_of!((*self.page).offset).read_volatile() };
+            let tsc = unsafe { _rdtsc() };
+            fence(Ordering::Acquire);
+            let again = unsafe { core::ptr::addr_of!((*self.page).sequence).read_volatile() };
+            if again == sequence {
+                let scaled = ((tsc as u128 * scale as u128) >> 64) as u64;
+                break scaled.wrapping_add_signed(offset);
+            }
+        };
+        units.wrapping_mul(NANOSECONDS_PER_UNIT)
+    }
+}
+
+impl ClockSource for HyperVClock {
+    fn name(&self) -> &'static str {
+        "hyperv-tsc"
+    }
+
+    fn nanos(&self) -> u64 {
+        self.read()
+            .wrapping_add(self.offset_ns.load(Ordering::Relaxed))
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        NANOSECONDS_PER_UNIT
+    }
+
+    fn resume(&self, nanos: u64) {
+        self.enable();
+        let offset = nanos.wrapping_sub(self.read());
+        self.offset_ns.store(offset, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,138 @@
+use super::ClockSource;
+use crate::memory::{self, FRAME_ALLOCATOR};
+use crate::paravirt;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Leaf after the base with the paravirtual features
+const FEATURES_LEAF: u32 = 1;
+const FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const FEATURE_CLOCKSOURCE_STABLE: u32 = 1 << 24;
+
+/// Takes the physical address of a vCPU's time information, bit 0 enabling it
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+/// Set in `flags` when every vCPU's information gives the same time
+const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+
+/// What the host keeps up to date for a vCPU: the host's monotonic time at a TSC reading and
+/// how to scale TSC ticks since then to nanoseconds
+#[repr(C)]
+struct TimeInfo {
+    /// Odd while the host is updating the rest
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad1: [u8; 2],
+}
+
+/// KVM's paravirtual clock. The host rewrites its parameters whenever the TSC's rate or offset
+/// changes, as they do when the VM migrates, so it stays right where the raw TSC wouldn't.
+///
+/// Every vCPU would normally have its own `TimeInfo`; only the boot CPU's is registered and all
+/// CPUs read it, which is only right when the host says the clock is stable across vCPUs.
+pub struct KvmClock {
+    info: *const TimeInfo,
+    /// Where `info` is, for the host
+    phys: PhysAddr,
+    /// Added to the host's time, so that sleep doesn't show
+    offset_ns: AtomicU64,
+}
+
+// the host writes `info`, the kernel only reads it
+unsafe impl Send for KvmClock {}
+unsafe impl Sync for KvmClock {}
+
+impl KvmClock {
+    /// Registers the boot CPU's time information with KVM, if running on KVM with a stable clock
+    pub fn new() -> Option<KvmClock> {
+        let base = paravirt::get().kvm_base?;
+        let features = raw_cpuid::cpuid!(base + FEATURES_LEAF).eax;
+        let wanted = FEATURE_CLOCKSOURCE2 | FEATURE_CLOCKSOURCE_STABLE;
+        if features & wanted != wanted {
+            return None;
+        }
+        let frame: PhysFrame<Size4KiB> = FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()?;
+        let info = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<TimeInfo>();
+        unsafe { info.write_bytes(0, 1) };
+        let clock = KvmClock {
+            info,
+            phys: frame.start_address(),
+            offset_ns: AtomicU64::new(0),
+        };
+        clock.register();
+        match clock.read().1 & PVCLOCK_TSC_STABLE {
+            0 => {
+                clock.unregister();
+                None
+            }
+            _ => Some(clock),
+        }
+    }
+
+    fn register(&self) {
+        let value = self.phys.as_u64() | SYSTEM_TIME_ENABLE;
+        unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(value) };
+    }
+
+    fn unregister(&self) {
+        unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(0) };
+    }
+
+    /// The host's time in nanoseconds, and the flags
+    fn read(&self) -> (u64, u8) {
+        loop {
+            let version = unsafe { core::ptr::addr_of!((*self.info).version).read_volatile() };
+            if version & 1 == 1 {
+                core::hint::spin_loop();
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let info = unsafe { self.info.read_volatile() };
+            let tsc = unsafe { _rdtsc() };
+            fence(Ordering::Acquire);
+            let again = unsafe { core::ptr::addr_of!((*self.info).version).read_volatile() };
+            if again != version {
+                continue;
+            }
+            let mut delta = tsc.wrapping_sub(info.tsc_timestamp);
+            delta = match info.tsc_shift {
+                shift if shift < 0 => delta >> -shift,
+                shift => delta << shift,
+            };
+            let scaled = (delta as u128 * info.tsc_to_system_mul as u128) >> 32;
+            return (info.system_time.wrapping_add(scaled as u64), info.flags);
+        }
+    }
+}
+
+impl ClockSource for KvmClock {
+    fn name(&self) -> &'static str {
+        "kvmclock"
+    }
+
+    fn nanos(&self) -> u64 {
+        self.read()
+            .0
+            .wrapping_add(self.offset_ns.load(Ordering::Relaxed))
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        1
+    }
+
+    fn resume(&self, nanos: u64) {
+        // the registration doesn't survive the vCPU's reset, and the host's time went on
+        self.register();
+        let offset = nanos.wrapping_sub(self.read().0);
+        self.offset_ns.store(offset, Ordering::Relaxed);
+    }
+}
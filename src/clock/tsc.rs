@@ -0,0 +1,75 @@
+use super::ClockSource;
+use crate::pit;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use spin::Once;
+
+const CALIBRATION_MS: u64 = 50;
+
+/// The time stamp counter, only used when it's invariant (runs at a constant rate in every
+/// P-state and C-state)
+pub struct Tsc {
+    /// TSC ticks per millisecond
+    ticks_per_ms: u64,
+    /// Added to the counter's time, which starts over from 0 after sleep
+    offset_ns: AtomicU64,
+}
+
+impl Tsc {
+    /// Calibrates the TSC against the PIT, or returns `None` if its rate isn't constant
+    pub fn new() -> Option<Tsc> {
+        let invariant = CpuId::new()
+            .get_advanced_power_mgmt_info()
+            .is_some_and(|info| info.has_invariant_tsc());
+        if !invariant {
+            return None;
+        }
+        match ticks_per_ms() {
+            0 => None,
+            ticks_per_ms => Some(Tsc {
+                ticks_per_ms,
+                offset_ns: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn counter_ns(&self) -> u64 {
+        (read() as u128 * 1_000_000 / self.ticks_per_ms as u128) as u64
+    }
+}
+
+/// TSC ticks per millisecond, measured against the PIT the first time it's asked for. Busy-waits
+/// for the calibration, so the first call shouldn't come from an interrupt handler.
+pub fn ticks_per_ms() -> u64 {
+    static TICKS_PER_MS: Once<u64> = Once::new();
+    *TICKS_PER_MS.call_once(|| {
+        let start = read();
+        pit::wait_ms(CALIBRATION_MS as u32);
+        (read() - start) / CALIBRATION_MS
+    })
+}
+
+/// The raw counter
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn nanos(&self) -> u64 {
+        self.counter_ns() + self.offset_ns.load(Ordering::Relaxed)
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        1_000_000_u64.div_ceil(self.ticks_per_ms)
+    }
+
+    fn resume(&self, nanos: u64) {
+        let offset = nanos.saturating_sub(self.counter_ns());
+        self.offset_ns.store(offset, Ordering::Relaxed);
+    }
+}
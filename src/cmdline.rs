@@ -0,0 +1,149 @@
+//! The kernel command line: settings picked at boot rather than at build time.
+//!
+//! It's the one the loader passed, if it passed one (see `bootinfo`). The `bootloader` crate has no
+//! command line of its own, so then it comes from QEMU's fw_cfg device: the `-append` text when
+//! QEMU loads the kernel itself, or else the file `opt/rust_os/cmdline` (`-fw_cfg
+//! name=opt/rust_os/cmdline,string="noapic kdb"`). Parameters are separated by whitespace, and are
+//! either flags, like `noapic`, or `name=value` pairs, like `skip_drivers=bga,ps2`; there's no
+//! quoting, so values can't contain spaces. A parameter given twice counts the last time.
+//!
+//! `init` reads it once the heap is up; before that, and on machines without fw_cfg, it's empty,
+//! except to `early_flag` and `early_value`, which look at the loader's or the `-append` text only.
+//! What the kernel looks at:
+//!
+//! - `noapic`: use the 8259 PICs even if there's an APIC
+//! - `skip_drivers=<name>,...`: leave these built-in drivers out, e.g. one that hangs the boot
+//! - `kdb`: enter the crash debugger on a panic
+//! - `panic=<action>`: what a panic does, `halt`, `reboot`, `kdb` or `dump` (see `crash`)
+//! - `panic_delay=<seconds>`: how long a panic waits before rebooting
+//! - `crashdump=<device>`: the block device a panic's crash dump is written to
+//! - `gdb`: wait for gdb on COM2 once the drivers are up (see `gdbstub`)
+//! - `keymap=<name>`: the keyboard layout, `us`, `uk`, `de` or `dvorak` (see `ps2::keymap`)
+//! - `tz=<offset>`: the local time zone as an offset from UTC, like `+01:00` (see `time`)
+//! - `heap_blocks=<size>,...`: the heap's block sizes, smallest first (see `allocator`);
+//!   not from `opt/rust_os/cmdline`
+//! - `heap_debug`: poison freed heap memory and look for double frees (see `allocator`)
+//! - `kaslr`: put the heap at a random address (see `memory::layout`); not from
+//!   `opt/rust_os/cmdline`
+//! - `allow_unsigned`: load modules and an initrd that aren't signed, with a warning (see
+//!   `signing`)
+//! - `profile[=<ticks>]`: sample where the CPUs are and stream it to COM1 from the start (see
+//!   `profile`)
+//! - `safe_mode`: boot with the boot CPU and the console drivers only (see `boot::safe_mode`)
+//! - `zram=<KiB>`: create a compressed RAM disk of that size at boot (see `block::zram`)
+
+use crate::bootinfo;
+use crate::drivers::fw_cfg;
+use crate::{println, warnln};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str::FromStr;
+use spin::Once;
+
+/// The text as given, and its parameters split into names and values
+struct Cmdline {
+    text: String,
+    parameters: Vec<(String, Option<String>)>,
+}
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// Reads and parses the command line; needs the heap
+#[link_section = ".init.text"]
+pub fn init() {
+    let text = bootinfo::get()
+        .and_then(|info| info.cmdline())
+        .map(String::from)
+        .or_else(fw_cfg::cmdline)
+        .or_else(|| fw_cfg::option("cmdline"))
+        .unwrap_or_default();
+    let parameters = text
+        .split_whitespace()
+        .map(|parameter| match parameter.split_once('=') {
+            Some((name, value)) => (String::from(name), Some(String::from(value))),
+            None => (String::from(parameter), None),
+        })
+        .collect();
+    let cmdline = CMDLINE.call_once(|| Cmdline { text, parameters });
+    if !cmdline.text.is_empty() {
+        println!("command line: {}", cmdline.text);
+    }
+}
+
+/// Longest `-append` text `early_flag` looks at
+const EARLY_SIZE: usize = 256;
+
+/// Calls `f` with the loader's command line, or else the `-append` text, without the heap
+#[link_section = ".init.text"]
+fn early_text<R>(f: impl FnOnce(&str) -> R) -> Option<R> {
+    if let Some(text) = bootinfo::get().and_then(|info| info.cmdline()) {
+        return Some(f(text));
+    }
+    let mut text = [0; EARLY_SIZE];
+    let len = fw_cfg::cmdline_into(&mut text);
+    core::str::from_utf8(&text[..len]).ok().map(f)
+}
+
+/// Whether the flag `name` is in the loader's or the `-append` text, for before the heap is up
+#[link_section = ".init.text"]
+pub fn early_flag(name: &str) -> bool {
+    early_text(|text| text.split_whitespace().any(|parameter| parameter == name)).unwrap_or(false)
+}
+
+/// Calls `f` with the value of `name=value` in the loader's or the `-append` text, for before the
+/// heap is up
+#[link_section = ".init.text"]
+pub fn early_value<R>(name: &str, f: impl FnOnce(&str) -> R) -> Option<R> {
+    early_text(|text| {
+        let value = text
+            .split_whitespace()
+            .rev()
+            .find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))?;
+        Some(f(value))
+    })?
+}
+
+/// The whole command line, empty if there's none
+pub fn text() -> &'static str {
+    CMDLINE.r#try().map_or("", |cmdline| &cmdline.text)
+}
+
+/// The last parameter called `name`: `Some(None)` for a flag, `Some(Some(value))` for a pair
+fn find(name: &str) -> Option<Option<&'static str>> {
+    CMDLINE
+        .r#try()?
+        .parameters
+        .iter()
+        .rev()
+        .find(|(parameter, _)| parameter == name)
+        .map(|(_, value)| value.as_deref())
+}
+
+/// Whether `name` was given, with a value or without
+pub fn flag(name: &str) -> bool {
+    find(name).is_some()
+}
+
+/// The value of `name=value`
+pub fn get(name: &str) -> Option<&'static str> {
+    find(name)?
+}
+
+/// The value of `name=value` parsed as a `T`; `None` if it's missing or doesn't parse, which
+/// is said on the console, as a typo there is easy to miss
+pub fn parse<T: FromStr>(name: &str) -> Option<T> {
+    let value = get(name)?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warnln!("cmdline: ignoring {}={}, which isn't valid", name, value);
+    }
+    parsed
+}
+
+/// The comma-separated values of `name=a,b,c`; empty if it's missing
+pub fn list(name: &str) -> impl Iterator<Item = &'static str> {
+    get(name)
+        .unwrap_or("")
+        .split(',')
+        .filter(|item| !item.is_empty())
+}
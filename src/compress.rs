@@ -0,0 +1,3 @@
+//! Data compression for kernel-internal use (compressed RAM disks, ...).
+
+pub mod lz4;
@@ -0,0 +1,164 @@
+//! The LZ4 block format (no frame header, no checksums).
+//!
+//! The compressor is the simple greedy single-probe variant: fast, a little worse ratio than the
+//! reference implementation, but its output decodes with any LZ4 decoder.
+
+use alloc::vec::Vec;
+
+const MIN_MATCH: usize = 4;
+/// The last match must start this many bytes before the end of the input
+const MF_LIMIT: usize = 12;
+/// The last this many bytes are always literals
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const HASH_BITS: u32 = 12;
+
+/// Largest input `compress` accepts; lets the match table hold 16-bit positions
+pub const MAX_INPUT_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The input ends in the middle of a sequence
+    Truncated,
+    /// A match refers to data before the start of the output
+    BadOffset,
+    /// The output doesn't fit in the buffer
+    OutputTooSmall,
+}
+
+/// Worst-case compressed size of `len` input bytes
+pub const fn max_compressed_size(len: usize) -> usize {
+    len + len / 255 + 16
+}
+
+fn read_u32(input: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([input[at], input[at + 1], input[at + 2], input[at + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], match_: Option<(usize, usize)>) {
+    let literal_nibble = literals.len().min(15);
+    let match_nibble = match_.map_or(0, |(_, len)| (len - MIN_MATCH).min(15));
+    out.push((literal_nibble << 4 | match_nibble) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((offset, len)) = match_ {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if len - MIN_MATCH >= 15 {
+            write_length(out, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+/// Appends the compressed form of `input` (at most `MAX_INPUT_SIZE` bytes) to `out`
+pub fn compress(input: &[u8], out: &mut Vec<u8>) {
+    assert!(input.len() <= MAX_INPUT_SIZE, "lz4: input too large");
+    let mut table = [0u16; 1 << HASH_BITS]; // position + 1 of the last occurrence, 0 for none
+    let mut anchor = 0;
+    let mut position = 0;
+
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        while position < match_limit {
+            let sequence = read_u32(input, position);
+            let slot = &mut table[hash(sequence)];
+            let candidate = *slot as usize;
+            *slot = (position + 1) as u16;
+
+            if candidate != 0 {
+                let candidate = candidate - 1;
+                if position - candidate <= MAX_OFFSET && read_u32(input, candidate) == sequence {
+                    let mut len = MIN_MATCH;
+                    while position + len < input.len() - LAST_LITERALS
+                        && input[candidate + len] == input[position + len]
+                    {
+                        len += 1;
+                    }
+                    write_sequence(
+                        out,
+                        &input[anchor..position],
+                        Some((position - candidate, len)),
+                    );
+                    position += len;
+                    anchor = position;
+                    continue;
+                }
+            }
+            position += 1;
+        }
+    }
+    write_sequence(out, &input[anchor..], None);
+}
+
+fn read_length(input: &[u8], at: &mut usize, nibble: usize) -> Result<usize, DecompressError> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let byte = *input.get(*at).ok_or(DecompressError::Truncated)?;
+            *at += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompresses `input` into `output` and returns the number of bytes produced
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut at = 0;
+    let mut written = 0;
+    while at < input.len() {
+        let token = input[at] as usize;
+        at += 1;
+
+        let literals = read_length(input, &mut at, token >> 4)?;
+        let source = input
+            .get(at..at + literals)
+            .ok_or(DecompressError::Truncated)?;
+        output
+            .get_mut(written..written + literals)
+            .ok_or(DecompressError::OutputTooSmall)?
+            .copy_from_slice(source);
+        at += literals;
+        written += literals;
+        if at == input.len() {
+            break; // the last sequence has no match
+        }
+
+        let offset = input
+            .get(at..at + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or(DecompressError::Truncated)?;
+        at += 2;
+        if offset == 0 || offset > written {
+            return Err(DecompressError::BadOffset);
+        }
+        let len = read_length(input, &mut at, token & 0xF)? + MIN_MATCH;
+        if written + len > output.len() {
+            return Err(DecompressError::OutputTooSmall);
+        }
+        // byte by byte: the source may overlap the bytes being written (run-length style)
+        for i in 0..len {
+            output[written + i] = output[written + i - offset];
+        }
+        written += len;
+    }
+    Ok(written)
+}
@@ -0,0 +1,352 @@
+//! The kernel console, which `print!` and `println!` write to.
+//!
+//! Output goes to the VGA text buffer until another `Backend` takes over with `set_backend`, as
+//! `gfx::TextConsole` does when the display switches to graphics; code that prints doesn't need
+//! to know which one is active. Backends `attach`ed besides, like the VM's console channel, get a
+//! copy of everything printed, for when nobody can see the display.
+//!
+//! Everything printed is also kept in the kernel log, see `klog`.
+//!
+//! `print_colored!` and `println_colored!` print in other colors than the usual, such as a
+//! subsystem's from `theme`. `warnln!` and `errorln!` print lines tagged `[WARN]` and `[ERROR]`,
+//! in the colors for warnings and errors; the tags go to the kernel log too. Each backend shows
+//! colors its own way, the VGA buffer in its attribute bytes, a `Terminal` with ANSI escapes,
+//! and the kernel log not at all.
+//!
+//! When printing is out of the question, early in the boot or in a fault handler that can't trust
+//! memory, `raw` writes without locks or formatting.
+//!
+//! Input from every console, whatever the device, arrives on `INPUT`.
+
+use crate::klog;
+use crate::sync::watched::{LockState, WatchedMutex};
+use crate::task::stream::InterruptStream;
+use crate::vga_buffer::{self, Color};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+pub mod cp437;
+pub mod raw;
+pub mod unicode;
+
+/// Bytes typed that may wait for a reader before new ones are dropped
+const INPUT_SIZE: usize = 1024;
+
+/// A text display with a cursor, which scrolls when the cursor moves past the last line
+pub trait Backend: Send {
+    /// Writes `text` at the cursor; `'\n'` starts a new line
+    fn write(&mut self, text: &str);
+
+    /// Colors of the text written from now on
+    fn set_colors(&mut self, foreground: Color, background: Color);
+
+    /// Back to `Colors::DEFAULT`, or whatever the display's own are
+    fn reset_colors(&mut self) {
+        self.set_colors(Colors::DEFAULT.foreground, Colors::DEFAULT.background);
+    }
+    /// Blanks the screen and moves the cursor to the top left
+    fn clear(&mut self);
+
+    /// Whether output still goes anywhere; attached backends that say no are dropped
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+/// The backend in use, `None` for the VGA text buffer
+static BACKEND: WatchedMutex<Option<Box<dyn Backend>>> = WatchedMutex::new("console", None);
+/// Backends that get a copy of the output; locked after `BACKEND`
+static ATTACHED: WatchedMutex<Vec<Box<dyn Backend>>> =
+    WatchedMutex::new("attached consoles", Vec::new());
+
+/// See `set_quiet`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// What was typed at any console, byte by byte
+pub static INPUT: InterruptStream<u8, INPUT_SIZE> = InterruptStream::new();
+
+/// Keeps the output off the display, the backend in use, while set; attached backends and the
+/// kernel log still get it
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Sends all console output to `backend` from now on
+pub fn set_backend(backend: Box<dyn Backend>) {
+    interrupts::without_interrupts(|| *BACKEND.lock() = Some(backend));
+}
+
+/// Sends a copy of all console output to `backend` from now on, until it's no longer open
+pub fn attach(backend: Box<dyn Backend>) {
+    interrupts::without_interrupts(|| ATTACHED.lock().push(backend));
+}
+
+/// Releases the console's locks, whoever holds them, so a crash can still be reported.
+///
+/// # Safety
+///
+/// Whoever held them must never run again, e.g. because the CPUs are stopped for good.
+pub unsafe fn force_unlock() {
+    BACKEND.force_unlock();
+    ATTACHED.force_unlock();
+    vga_buffer::WRITER.force_unlock();
+    #[cfg(feature = "gfx")]
+    crate::gfx::force_unlock();
+    crate::klog::force_unlock();
+}
+
+/// Waits for the next byte typed at a console
+pub async fn read_byte() -> u8 {
+    INPUT.next().await
+}
+
+/// Runs `f` on the active backend. Interrupts are disabled meanwhile, as an interrupt handler
+/// that prints while the lock is held would spin forever.
+pub fn with_backend<R>(f: impl FnOnce(&mut dyn Backend) -> R) -> R {
+    interrupts::without_interrupts(|| match BACKEND.lock().as_deref_mut() {
+        Some(backend) => f(backend),
+        None => f(&mut *vga_buffer::WRITER.lock()),
+    })
+}
+
+/// Like `with_backend`, but gives up rather than wait if the backend is locked
+pub fn try_with_backend<R>(f: impl FnOnce(&mut dyn Backend) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut backend = BACKEND.try_lock()?;
+        match backend.as_deref_mut() {
+            Some(backend) => Some(f(backend)),
+            None => Some(f(&mut *vga_buffer::WRITER.try_lock()?)),
+        }
+    })
+}
+
+/// The console's locks, for the watchdog to keep an eye on
+pub fn lock_states() -> [&'static LockState; 2] {
+    [BACKEND.state(), ATTACHED.state()]
+}
+
+/// Lets `write_fmt` format straight into a backend
+struct Formatter<'a>(&'a mut dyn Backend);
+
+impl fmt::Write for Formatter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.0.write(text);
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Something that takes bytes for a terminal, e.g. a serial line or a network connection
+pub trait Sink: Send {
+    fn send(&mut self, data: &[u8]);
+
+    /// Whether the bytes still go anywhere
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+/// A `Backend` for the terminal at the end of a `Sink`: lines end in CRLF, and colors and
+/// clearing are ANSI escape sequences
+pub struct Terminal<S>(pub S);
+
+impl<S: Sink> Backend for Terminal<S> {
+    fn write(&mut self, text: &str) {
+        // terminals move down on '\n' but only return to the first column on '\r'
+        for (index, line) in text.split('\n').enumerate() {
+            if index > 0 {
+                self.0.send(b"\r\n");
+            }
+            self.0.send(line.as_bytes());
+        }
+    }
+
+    fn set_colors(&mut self, foreground: Color, background: Color) {
+        // VGA numbers the colors blue-green-red from the low bit, ANSI red-green-blue
+        const ANSI: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let code = |color: Color, normal: u8, bright: u8| {
+            let index = color as u8;
+            let base = if index >= 8 { bright } else { normal };
+            base + ANSI[index as usize % 8]
+        };
+        let sequence = format!(
+            "\x1b[{};{}m",
+            code(foreground, 30, 90),
+            code(background, 40, 100)
+        );
+        self.0.send(sequence.as_bytes());
+    }
+
+    fn reset_colors(&mut self) {
+        // the terminal's own colors, which needn't be the display's
+        self.0.send(b"\x1b[0m");
+    }
+
+    fn clear(&mut self) {
+        self.0.send(b"\x1b[2J\x1b[H");
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.is_open()
+    }
+}
+
+/// Text colors, foreground on background. Subsystems that want their output to stand out have
+/// theirs in `THEMES` and print with `print_colored!` and `println_colored!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colors {
+    pub foreground: Color,
+    pub background: Color,
+}
+
+impl Colors {
+    pub const fn new(foreground: Color, background: Color) -> Colors {
+        Colors {
+            foreground,
+            background,
+        }
+    }
+
+    /// What the consoles start with
+    pub const DEFAULT: Colors = Colors::new(Color::Yellow, Color::Black);
+    /// `warnln!`: yellow like the rest, so it's the background that makes it stand out
+    pub const WARNING: Colors = Colors::new(Color::Black, Color::Yellow);
+    /// `errorln!`
+    pub const ERROR: Colors = Colors::new(Color::LightRed, Color::Black);
+}
+
+/// The colors a subsystem prints what should stand out in
+pub struct Theme {
+    pub subsystem: &'static str,
+    pub colors: Colors,
+}
+
+/// Every subsystem's theme, see `theme`
+static THEMES: &[Theme] = &[
+    Theme {
+        subsystem: "battery",
+        colors: Colors::WARNING,
+    },
+    Theme {
+        subsystem: "boot",
+        colors: Colors::new(Color::LightGreen, Color::Black),
+    },
+];
+
+/// The colors of `subsystem`, `Colors::DEFAULT` if it has no theme
+pub fn theme(subsystem: &str) -> Colors {
+    THEMES
+        .iter()
+        .find(|theme| theme.subsystem == subsystem)
+        .map_or(Colors::DEFAULT, |theme| theme.colors)
+}
+
+#[macro_export]
+macro_rules! print_colored {
+    ($colors:expr; $($arg:tt)*) => {
+        $crate::console::_print_colored($colors, format_args!($($arg)*), false)
+    };
+    ($foreground:expr, $background:expr, $($arg:tt)*) => {
+        $crate::print_colored!(
+            $crate::console::Colors::new($foreground, $background);
+            $($arg)*
+        )
+    };
+}
+
+/// Like `println!` in other colors, given as `foreground, background, ...` or as `colors; ...`.
+/// The colors go back to normal before the newline, so the line scrolled in isn't painted.
+#[macro_export]
+macro_rules! println_colored {
+    ($colors:expr; $($arg:tt)*) => {
+        $crate::console::_print_colored($colors, format_args!($($arg)*), true)
+    };
+    ($foreground:expr, $background:expr, $($arg:tt)*) => {
+        $crate::println_colored!(
+            $crate::console::Colors::new($foreground, $background);
+            $($arg)*
+        )
+    };
+}
+
+/// `println!` for something that went wrong but that the kernel gets over, tagged `[WARN]`
+#[macro_export]
+macro_rules! warnln {
+    ($($arg:tt)*) => ($crate::println_colored!(
+        $crate::console::Colors::WARNING;
+        "[WARN] {}",
+        format_args!($($arg)*)
+    ));
+}
+
+/// `println!` for something the kernel doesn't get over, tagged `[ERROR]`
+#[macro_export]
+macro_rules! errorln {
+    ($($arg:tt)*) => ($crate::println_colored!(
+        $crate::console::Colors::ERROR;
+        "[ERROR] {}",
+        format_args!($($arg)*)
+    ));
+}
+
+/// Writes `args` to `backend` in `colors`, if there are any, followed by a newline in the
+/// backend's usual colors if `newline` is set
+fn write_to(
+    backend: &mut dyn Backend,
+    colors: Option<Colors>,
+    args: fmt::Arguments,
+    newline: bool,
+) {
+    use core::fmt::Write;
+    if let Some(colors) = colors {
+        backend.set_colors(colors.foreground, colors.background);
+    }
+    Formatter(&mut *backend).write_fmt(args).unwrap();
+    if colors.is_some() {
+        backend.reset_colors();
+    }
+    if newline {
+        backend.write("\n");
+    }
+}
+
+fn print(colors: Option<Colors>, args: fmt::Arguments, newline: bool) {
+    with_backend(|backend| {
+        if !QUIET.load(Ordering::Relaxed) {
+            write_to(backend, colors, args, newline);
+        }
+        let mut attached = ATTACHED.lock();
+        for backend in attached.iter_mut() {
+            write_to(&mut **backend, colors, args, newline);
+        }
+        attached.retain(|backend| backend.is_open());
+        match newline {
+            true => klog::write(klog::source(), format_args!("{}\n", args)),
+            false => klog::write(klog::source(), args),
+        }
+    });
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    print(None, args, false);
+}
+
+#[doc(hidden)]
+pub fn _print_colored(colors: Colors, args: fmt::Arguments, newline: bool) {
+    print(Some(colors), args, newline);
+}
@@ -0,0 +1,114 @@
+//! Code page 437, the character set of the VGA card's own font.
+//!
+//! Text mode draws one byte per cell with the glyph of that number in the card's font, which is
+//! the IBM PC's: ASCII, box drawing, shades, accented Latin letters, some Greek and maths, and
+//! symbols where ASCII has control characters. `encode` finds the byte for a character, or for
+//! one that looks close enough (curly quotes as straight ones, `ã` as `a`), and leaves the rest
+//! to the caller.
+
+/// The characters of 0x80 to 0xff, in order
+const HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+                    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+                    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// The symbols of 0x01 to 0x1f, in order; written to the text buffer they're drawn like any
+/// other byte
+const LOW: &str = "☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+/// 0x7f
+const HOUSE: char = '⌂';
+
+/// Characters without a glyph of their own and the byte of one that stands in for them
+const LOOKALIKES: &[(char, u8)] = &[
+    ('‘', b'\''),
+    ('’', b'\''),
+    ('‚', b','),
+    ('′', b'\''),
+    ('“', b'"'),
+    ('”', b'"'),
+    ('„', b'"'),
+    ('″', b'"'),
+    ('‐', b'-'),
+    ('‑', b'-'),
+    ('–', b'-'),
+    ('—', b'-'),
+    ('−', b'-'),
+    ('‹', b'<'),
+    ('›', b'>'),
+    ('×', b'x'),
+    ('∗', b'*'),
+    ('β', 0xe1),
+    ('μ', 0xe6),
+    ('Ω', 0xea),
+    ('∑', 0xe4),
+    ('∈', 0xee),
+    ('ε', 0xee),
+    ('Ø', 0xed),
+    ('ø', 0xed),
+    ('¦', 0xb3),
+    ('À', b'A'),
+    ('Á', b'A'),
+    ('Â', b'A'),
+    ('Ã', b'A'),
+    ('ã', b'a'),
+    ('È', b'E'),
+    ('Ê', b'E'),
+    ('Ë', b'E'),
+    ('Ì', b'I'),
+    ('Í', b'I'),
+    ('Î', b'I'),
+    ('Ï', b'I'),
+    ('Ò', b'O'),
+    ('Ó', b'O'),
+    ('Ô', b'O'),
+    ('Õ', b'O'),
+    ('õ', b'o'),
+    ('Ù', b'U'),
+    ('Ú', b'U'),
+    ('Û', b'U'),
+    ('Ý', b'Y'),
+    ('ý', b'y'),
+    ('Ÿ', b'Y'),
+    ('Œ', b'O'),
+    ('œ', b'o'),
+    ('€', b'E'),
+];
+
+/// The byte of `ch`'s glyph in code page 437, if it has one; printable ASCII maps to itself
+pub fn glyph(ch: char) -> Option<u8> {
+    match ch {
+        ' '..='~' => Some(ch as u8),
+        HOUSE => Some(0x7f),
+        _ => position(HIGH, ch)
+            .map(|index| 0x80 + index)
+            .or_else(|| position(LOW, ch).map(|index| 0x01 + index)),
+    }
+}
+
+/// Like `glyph`, and failing that the byte of a character that looks like `ch`
+pub fn encode(ch: char) -> Option<u8> {
+    glyph(ch).or_else(|| {
+        LOOKALIKES
+            .iter()
+            .find(|&&(lookalike, _)| lookalike == ch)
+            .map(|&(_, byte)| byte)
+    })
+}
+
+/// The character of the glyph at `byte`, for fonts laid out in code page 437; 0 is none
+pub fn decode(byte: u8) -> Option<char> {
+    match byte {
+        0 => None,
+        0x01..=0x1f => LOW.chars().nth(byte as usize - 0x01),
+        0x7f => Some(HOUSE),
+        0x80..=0xff => HIGH.chars().nth(byte as usize - 0x80),
+        _ => Some(byte as char),
+    }
+}
+
+fn position(table: &str, ch: char) -> Option<u8> {
+    table
+        .chars()
+        .position(|entry| entry == ch)
+        .map(|index| index as u8)
+}
@@ -0,0 +1,127 @@
+//! Output for when `print!` can't be trusted: numbers and text written straight to the VGA text
+//! buffer and COM1, without `core::fmt`, locks or the heap.
+//!
+//! Formatting goes through vtables and a good deal of code, any of which may fault if memory is
+//! corrupt, and the console's locks may be held by the code that crashed. `Writer` needs neither,
+//! and works from the first instruction of the boot: the VGA buffer is always mapped, in the
+//! physical memory window or, before the boot knows where that is, by the bootloader, and COM1
+//! takes bytes whether it was set up or not. It scrolls the bottom line of the text buffer on its
+//! own, so what it writes may end up mixed with what the console wrote, and it's invisible once
+//! the display is in graphics mode; the serial line still has it.
+
+use crate::drivers::serial::{SerialPort, COM1};
+use crate::memory;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::PhysAddr;
+
+/// Physical address of the text buffer
+const VGA_BUFFER: u64 = 0xb8000;
+const WIDTH: usize = 80;
+const HEIGHT: usize = 25;
+/// White on red, to tell it apart from the console
+const ATTRIBUTE: u16 = 0x4f00;
+
+/// Column on the bottom line written next
+static COLUMN: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes without formatting or locks; calls chain, as in
+/// `Writer.str("rip ").hex(rip).str("\n")`
+pub struct Writer;
+
+impl Writer {
+    pub fn byte(&mut self, byte: u8) -> &mut Writer {
+        let port = SerialPort::assume(COM1);
+        if byte == b'\n' {
+            port.send_byte(b'\r');
+        }
+        port.send_byte(byte);
+        vga_byte(byte);
+        self
+    }
+
+    pub fn str(&mut self, text: &str) -> &mut Writer {
+        for byte in text.bytes() {
+            self.byte(byte);
+        }
+        self
+    }
+
+    /// `value` as `0x` and 16 hex digits
+    pub fn hex(&mut self, value: u64) -> &mut Writer {
+        self.str("0x");
+        for shift in (0..16).rev() {
+            self.byte(b"0123456789abcdef"[(value >> (shift * 4)) as usize & 0xf]);
+        }
+        self
+    }
+
+    /// `value` in decimal
+    pub fn dec(&mut self, mut value: u64) -> &mut Writer {
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (value % 10) as u8;
+            len += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        for &digit in digits[..len].iter().rev() {
+            self.byte(digit);
+        }
+        self
+    }
+}
+
+pub fn print_str(text: &str) {
+    Writer.str(text);
+}
+
+pub fn print_hex(value: u64) {
+    Writer.hex(value);
+}
+
+pub fn print_dec(value: u64) {
+    Writer.dec(value);
+}
+
+fn vga_byte(byte: u8) {
+    let mut column = COLUMN.load(Ordering::Relaxed);
+    if byte == b'\n' || column >= WIDTH {
+        scroll();
+        column = 0;
+    }
+    if byte != b'\n' {
+        let byte = match byte {
+            0x20..=0x7e => byte,
+            _ => 0xfe,
+        };
+        let cell = (HEIGHT - 1) * WIDTH + column;
+        unsafe {
+            vga_buffer()
+                .add(cell)
+                .write_volatile(ATTRIBUTE | byte as u16)
+        };
+        column += 1;
+    }
+    COLUMN.store(column, Ordering::Relaxed);
+}
+
+fn vga_buffer() -> *mut u16 {
+    memory::phys_to_virt(PhysAddr::new(VGA_BUFFER)).as_mut_ptr()
+}
+
+/// Moves every line up one and blanks the bottom one
+fn scroll() {
+    let buffer = vga_buffer();
+    for cell in WIDTH..WIDTH * HEIGHT {
+        unsafe {
+            let value = buffer.add(cell).read_volatile();
+            buffer.add(cell - WIDTH).write_volatile(value);
+        }
+    }
+    for cell in (HEIGHT - 1) * WIDTH..HEIGHT * WIDTH {
+        unsafe { buffer.add(cell).write_volatile(ATTRIBUTE | b' ' as u16) };
+    }
+}
@@ -0,0 +1,148 @@
+//! How many cells of a text console a character takes, in the spirit of `wcwidth`.
+//!
+//! Most characters take one. East Asian wide characters, CJK ideographs, Hangul syllables,
+//! fullwidth forms and most emoji, take two, as every terminal draws them; combining marks,
+//! zero-width joiners and spaces and variation selectors take none, as they belong to the
+//! character before them. Consoles that follow `width` keep their cursor where a terminal
+//! showing the same text would have it, whatever they can actually draw.
+//!
+//! The tables cover the ranges in common use rather than all of Unicode: a character missing
+//! from them takes one cell, which is right for the scripts written left to right without
+//! combining, and no worse than counting every character as one for the others.
+
+/// Combining marks and other characters that take no cell of their own, sorted
+static ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036F), // combining diacritical marks
+    (0x0483, 0x0489),
+    (0x0591, 0x05BD), // Hebrew points
+    (0x05BF, 0x05BF),
+    (0x05C1, 0x05C2),
+    (0x05C4, 0x05C5),
+    (0x05C7, 0x05C7),
+    (0x0610, 0x061A), // Arabic marks
+    (0x064B, 0x065F),
+    (0x0670, 0x0670),
+    (0x06D6, 0x06DC),
+    (0x06DF, 0x06E4),
+    (0x06E7, 0x06E8),
+    (0x06EA, 0x06ED),
+    (0x0900, 0x0902), // Devanagari signs
+    (0x093A, 0x093A),
+    (0x093C, 0x093C),
+    (0x0941, 0x0948),
+    (0x094D, 0x094D),
+    (0x0951, 0x0957),
+    (0x0E31, 0x0E31), // Thai vowels and tone marks
+    (0x0E34, 0x0E3A),
+    (0x0E47, 0x0E4E),
+    (0x1160, 0x11FF), // Hangul medial vowels and final consonants
+    (0x1AB0, 0x1AFF), // combining diacritical marks extended
+    (0x1DC0, 0x1DFF), // combining diacritical marks supplement
+    (0x200B, 0x200F), // zero-width space, non-joiner, joiner, direction marks
+    (0x2028, 0x202E),
+    (0x2060, 0x2064),
+    (0x20D0, 0x20FF), // combining marks for symbols
+    (0x302A, 0x302D),
+    (0x3099, 0x309A),   // kana voicing marks
+    (0xFE00, 0xFE0F),   // variation selectors
+    (0xFE20, 0xFE2F),   // combining half marks
+    (0xFEFF, 0xFEFF),   // zero-width no-break space
+    (0x1F3FB, 0x1F3FF), // emoji skin tones
+    (0xE0001, 0xE007F), // tags
+    (0xE0100, 0xE01EF), // variation selectors supplement
+];
+
+/// Characters that take two cells, sorted
+static WIDE: &[(u32, u32)] = &[
+    (0x1100, 0x115F), // Hangul initial consonants
+    (0x231A, 0x231B),
+    (0x2329, 0x232A),
+    (0x23E9, 0x23EC),
+    (0x23F0, 0x23F0),
+    (0x23F3, 0x23F3),
+    (0x25FD, 0x25FE),
+    (0x2614, 0x2615),
+    (0x2648, 0x2653),
+    (0x267F, 0x267F),
+    (0x2693, 0x2693),
+    (0x26A1, 0x26A1),
+    (0x26AA, 0x26AB),
+    (0x26BD, 0x26BE),
+    (0x26C4, 0x26C5),
+    (0x26CE, 0x26CE),
+    (0x26D4, 0x26D4),
+    (0x26EA, 0x26EA),
+    (0x26F2, 0x26F3),
+    (0x26F5, 0x26F5),
+    (0x26FA, 0x26FA),
+    (0x26FD, 0x26FD),
+    (0x2705, 0x2705),
+    (0x270A, 0x270B),
+    (0x2728, 0x2728),
+    (0x274C, 0x274C),
+    (0x274E, 0x274E),
+    (0x2753, 0x2755),
+    (0x2757, 0x2757),
+    (0x2795, 0x2797),
+    (0x27B0, 0x27B0),
+    (0x27BF, 0x27BF),
+    (0x2B1B, 0x2B1C),
+    (0x2B50, 0x2B50),
+    (0x2B55, 0x2B55),
+    (0x2E80, 0x3029), // CJK radicals, punctuation
+    (0x302E, 0x303E),
+    (0x3041, 0x3096), // kana
+    (0x309B, 0x33FF),
+    (0x3400, 0x4DBF), // CJK ideographs extension A
+    (0x4E00, 0x9FFF), // CJK unified ideographs
+    (0xA000, 0xA4CF), // Yi
+    (0xA960, 0xA97F),
+    (0xAC00, 0xD7A3), // Hangul syllables
+    (0xF900, 0xFAFF), // CJK compatibility ideographs
+    (0xFE10, 0xFE19),
+    (0xFE30, 0xFE6F), // CJK compatibility forms
+    (0xFF00, 0xFF60), // fullwidth forms
+    (0xFFE0, 0xFFE6),
+    (0x16FE0, 0x16FE4),
+    (0x17000, 0x18CFF), // Tangut
+    (0x1B000, 0x1B2FF), // kana supplement
+    (0x1F004, 0x1F004),
+    (0x1F0CF, 0x1F0CF),
+    (0x1F18E, 0x1F18E),
+    (0x1F191, 0x1F19A),
+    (0x1F200, 0x1F251),
+    (0x1F300, 0x1F3FA), // emoji
+    (0x1F400, 0x1F64F),
+    (0x1F680, 0x1F6FF),
+    (0x1F7E0, 0x1F7EB),
+    (0x1F900, 0x1F9FF),
+    (0x1FA70, 0x1FAFF),
+    (0x20000, 0x2FFFD), // CJK ideographs extensions
+    (0x30000, 0x3FFFD),
+];
+
+fn contains(table: &[(u32, u32)], ch: char) -> bool {
+    let code = ch as u32;
+    table
+        .binary_search_by(|&(first, last)| {
+            if last < code {
+                core::cmp::Ordering::Less
+            } else if first > code {
+                core::cmp::Ordering::Greater
+            } else {
+                core::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+/// Cells `ch` takes: 0, 1 or 2. Control characters take none; what they do is up to the console.
+pub fn width(ch: char) -> usize {
+    match ch {
+        ' '..='~' => 1,
+        '\0'..='\x1F' | '\x7F'..='\u{9F}' => 0,
+        ch if contains(ZERO_WIDTH, ch) => 0,
+        ch if contains(WIDE, ch) => 2,
+        _ => 1,
+    }
+}
@@ -0,0 +1,204 @@
+//! CPU time accounting: what each CPU spent its time on.
+//!
+//! Time is counted in TSC cycles where the CPU changes hands and turned into `Duration`s when
+//! asked for. Three kinds are counted:
+//!
+//! - process time: the time slices their threads ran, the system calls they made included,
+//!   charged by `process::run_slice` (see `Process::cpu_time`);
+//! - task time: how long polling each kernel task took, charged by the executor;
+//! - idle time: how long each CPU was halted in `tickless::idle`, interrupts it handled while
+//!   halted included.
+//!
+//! The rest of a CPU's time since `init` went to interrupt handlers and the executor's own work.
+//! Process time is spent inside the scheduler task's polls, so it's part of that task's time too.
+
+use crate::clock::{tsc, Instant};
+use crate::percpu::{self, MAX_CPUS};
+use crate::println;
+use crate::task::TaskId;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+
+/// Cycles each CPU spent halted
+static IDLE: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// The tasks that still exist, by id
+static TASKS: Mutex<BTreeMap<TaskId, TaskTime>> = Mutex::new(BTreeMap::new());
+/// Cycles of the tasks that completed
+static ENDED_TASKS: AtomicU64 = AtomicU64::new(0);
+
+/// When accounting started, the start of uptime for the percentages
+static START: Once<Instant> = Once::new();
+
+/// A kernel task's time
+#[derive(Debug, Clone, Copy)]
+pub struct TaskTime {
+    /// What the task runs, see `task_name`
+    pub name: &'static str,
+    pub cycles: u64,
+    /// How often it was polled
+    pub polls: u64,
+}
+
+impl TaskTime {
+    pub fn time(&self) -> Duration {
+        cycles_to_duration(self.cycles)
+    }
+}
+
+/// Starts the clock for `uptime`; once the clock source is set up
+pub fn init() {
+    START.call_once(Instant::now);
+}
+
+/// Time since `init`
+pub fn uptime() -> Duration {
+    START.r#try().map_or(Duration::ZERO, Instant::elapsed)
+}
+
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let nanos = cycles as u128 * 1_000_000 / tsc::ticks_per_ms().max(1) as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Charges `cycles` halted to this CPU
+pub fn charge_idle(cycles: u64) {
+    if let Some(cpu) = percpu::try_current() {
+        IDLE[cpu.index].fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+/// How long CPU `index` was halted
+pub fn idle(index: usize) -> Duration {
+    IDLE.get(index).map_or(Duration::ZERO, |cycles| {
+        cycles_to_duration(cycles.load(Ordering::Relaxed))
+    })
+}
+
+/// How long all CPUs together were halted
+pub fn total_idle() -> Duration {
+    (0..percpu::count()).map(idle).sum()
+}
+
+/// Charges one poll of `cycles` to task `id`, named `name`
+pub fn charge_task(id: TaskId, name: &'static str, cycles: u64) {
+    let mut tasks = TASKS.lock();
+    let time = tasks.entry(id).or_insert(TaskTime {
+        name,
+        cycles: 0,
+        polls: 0,
+    });
+    time.cycles += cycles;
+    time.polls += 1;
+}
+
+/// Moves the time of task `id`, which completed, to the total of the ended ones
+pub fn task_ended(id: TaskId) {
+    if let Some(time) = TASKS.lock().remove(&id) {
+        ENDED_TASKS.fetch_add(time.cycles, Ordering::Relaxed);
+    }
+}
+
+/// The tasks that still exist and their time, most first
+pub fn tasks() -> Vec<(TaskId, TaskTime)> {
+    let mut tasks: Vec<_> = TASKS.lock().iter().map(|(&id, &time)| (id, time)).collect();
+    tasks.sort_by_key(|(_, time)| core::cmp::Reverse(time.cycles));
+    tasks
+}
+
+/// The time of every task, completed ones included
+pub fn total_tasks() -> Duration {
+    let running: u64 = TASKS.lock().values().map(|time| time.cycles).sum();
+    cycles_to_duration(running + ENDED_TASKS.load(Ordering::Relaxed))
+}
+
+/// The function a task was made from, out of its future's type name: "net::stack::worker" for
+/// `rust_os::net::stack::worker::{{closure}}`
+pub fn task_name(type_name: &'static str) -> &'static str {
+    let mut name = type_name;
+    while let Some(outer) = name.strip_suffix("::{{closure}}") {
+        name = outer;
+    }
+    name.strip_prefix("rust_os::").unwrap_or(name)
+}
+
+/// `duration` as a percentage of `total`, in tenths
+fn per_mille(duration: Duration, total: Duration) -> u128 {
+    match total.as_micros() {
+        0 => 0,
+        total => duration.as_micros() * 1000 / total,
+    }
+}
+
+/// Seconds with milliseconds
+struct Seconds(Duration);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = format!("{}.{:03}", self.0.as_secs(), self.0.subsec_millis());
+        f.pad(&text)
+    }
+}
+
+/// Percentage with one decimal
+struct Percent(u128);
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = format!("{}.{}%", self.0 / 10, self.0 % 10);
+        f.pad(&text)
+    }
+}
+
+/// `top`: where the CPU time since boot went, per CPU, kernel task and process
+pub fn top_command(_args: &[&str]) -> Result<(), &'static str> {
+    let uptime = uptime();
+    println!("up {} s", Seconds(uptime));
+
+    println!("{:>4} {:>12} {:>7}", "CPU", "IDLE", "BUSY");
+    for index in (0..percpu::count()).filter(|&index| percpu::is_online(index)) {
+        let idle = idle(index);
+        println!(
+            "{:>4} {:>12} {:>7}",
+            index,
+            Seconds(idle),
+            Percent(1000u128.saturating_sub(per_mille(idle, uptime)))
+        );
+    }
+
+    println!();
+    println!(
+        "{:>5} {:>12} {:>7} {:>9}  TASK",
+        "ID", "TIME", "CPU", "POLLS"
+    );
+    for (id, time) in tasks() {
+        println!(
+            "{:>5} {:>12} {:>7} {:>9}  {}",
+            id.as_u64(),
+            Seconds(time.time()),
+            Percent(per_mille(time.time(), uptime)),
+            time.polls,
+            time.name
+        );
+    }
+
+    println!();
+    println!("{:>5} {:>12} {:>7}  NAME", "PID", "TIME", "CPU");
+    let mut processes = crate::process::list();
+    processes.sort_by_key(|process| core::cmp::Reverse(process.cpu_time()));
+    for process in processes {
+        println!(
+            "{:>5} {:>12} {:>7}  {}",
+            process.pid(),
+            Seconds(process.cpu_time()),
+            Percent(per_mille(process.cpu_time(), uptime)),
+            process.name()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! What a panic does: halt, reboot, enter the crash debugger or write a crash dump and reboot.
+//!
+//! Debugging at the keyboard wants to look around after a panic, a CI run wants the machine to
+//! get on with the next test, and a soak test on unattended hardware wants a record of what
+//! happened and the machine back up. The `panic.action` sysctl picks, or `panic=` on the command
+//! line (`kdb` is short for `panic=kdb`):
+//!
+//! - `halt` (0): report the panic and stop, the default
+//! - `reboot` (1): report it, wait `panic.reboot_delay_s` seconds and restart
+//! - `kdb` (2): enter the crash debugger instead (see `kdb`)
+//! - `dump` (3): report it, write a crash dump and reboot as above
+//!
+//! The dump goes to the block device `crashdump=` names, from block 0 on, so it should be a disk
+//! of its own. It's text: a `MAGIC` line, the report, the backtrace and the kernel log, ended by
+//! a zero byte. Nothing is allocated while writing it and the device table is only looked at if
+//! it isn't locked, but a panic in the disk's driver may still not get its dump written. After
+//! the reboot, `crashdump` shows it.
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::sysctl::Tunable;
+use crate::unwind::Backtrace;
+use crate::{boot, cmdline, console, errorln, kdb, klog, pit, power, println, warnln};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub static ACTION: Tunable = Tunable::new(
+    "panic.action",
+    "what a panic does: 0 halts, 1 reboots, 2 enters the crash debugger, 3 writes a crash dump and reboots",
+    Action::Halt as u64,
+    0,
+    Action::Dump as u64,
+);
+
+pub static REBOOT_DELAY: Tunable = Tunable::new(
+    "panic.reboot_delay_s",
+    "seconds between a panic's report and the reboot, to read it",
+    10,
+    0,
+    3600,
+);
+
+/// First line of a crash dump
+const MAGIC: &str = "rust_os crash dump";
+/// Largest block size a dump can be written with
+const MAX_BLOCK_SIZE: usize = 4096;
+/// Most blocks a dump takes, the end included; enough for the whole kernel log
+const MAX_BLOCKS: u64 = 128;
+
+/// Set by the first panic; one during the handling of it only halts
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The block being written of a dump
+static BLOCK: Mutex<[u8; MAX_BLOCK_SIZE]> = Mutex::new([0; MAX_BLOCK_SIZE]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    Halt = 0,
+    Reboot = 1,
+    Debugger = 2,
+    Dump = 3,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [Action::Halt, Action::Reboot, Action::Debugger, Action::Dump];
+
+    /// As `panic=` names it
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Halt => "halt",
+            Action::Reboot => "reboot",
+            Action::Debugger => "kdb",
+            Action::Dump => "dump",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+
+    /// "a panic {}"
+    pub fn describe(self) -> &'static str {
+        match self {
+            Action::Halt => "halts the machine",
+            Action::Reboot => "reboots the machine",
+            Action::Debugger => "enters the crash debugger",
+            Action::Dump => "writes a crash dump and reboots the machine",
+        }
+    }
+}
+
+pub fn action() -> Action {
+    Action::ALL
+        .into_iter()
+        .find(|action| *action as u64 == ACTION.get())
+        .unwrap_or(Action::Halt)
+}
+
+pub fn set_action(action: Action) {
+    ACTION.set(action as u64).expect("every action is in range");
+}
+
+/// Takes the action and reboot delay from the command line
+pub fn init() {
+    if cmdline::flag("kdb") {
+        set_action(Action::Debugger);
+    }
+    if let Some(name) = cmdline::get("panic") {
+        match Action::parse(name) {
+            Some(action) => set_action(action),
+            None => println!("crash: no panic action called {}", name),
+        }
+    }
+    if let Some(seconds) = cmdline::parse("panic_delay") {
+        if REBOOT_DELAY.set(seconds).is_err() {
+            warnln!("crash: panic_delay out of range");
+        }
+    }
+}
+
+/// Reports the panic `info` and does what `action` says; the kernel's panic handler
+pub fn handle(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        crate::hlt_loop();
+    }
+    console::set_quiet(false);
+    let action = action();
+    if action == Action::Debugger {
+        kdb::enter(info);
+    }
+    klog::set_source(klog::Source::Panic);
+    errorln!("{}", info);
+    println!("{}", Backtrace::here());
+    if let Some(phase) = boot::phase() {
+        errorln!("boot failed: panic in the {} phase", phase);
+    }
+    match action {
+        Action::Halt | Action::Debugger => crate::hlt_loop(),
+        Action::Reboot => reboot_after_delay(),
+        Action::Dump => {
+            match write_dump(info) {
+                Ok(name) => println!("crash: dump written to {}", name),
+                Err(err) => errorln!("crash: no dump written: {}", err),
+            }
+            reboot_after_delay();
+        }
+    }
+}
+
+fn reboot_after_delay() -> ! {
+    x86_64::instructions::interrupts::disable();
+    let seconds = REBOOT_DELAY.get();
+    println!("rebooting in {} s", seconds);
+    for _ in 0..seconds * 10 {
+        pit::wait_ms(100);
+    }
+    power::reboot();
+}
+
+/// Writes the dump to the device `crashdump=` names and returns its name
+fn write_dump(info: &PanicInfo) -> Result<&'static str, &'static str> {
+    let name = cmdline::get("crashdump").ok_or("no device, boot with `crashdump=<device>`")?;
+    let device = block::try_device(name).ok_or("no such block device, or its table is locked")?;
+    let mut block = BLOCK.try_lock().ok_or("the dump buffer is in use")?;
+    let mut writer = DumpWriter::new(&*device, &mut block[..])?;
+    let _ = write!(writer, "{}\n{}\n{}\n", MAGIC, info, Backtrace::here());
+    let _ = writeln!(writer, "kernel log:");
+    if !klog::try_for_each(|record| {
+        let _ = writeln!(writer, "{}", record);
+    }) {
+        let _ = writeln!(writer, "(locked)");
+    }
+    writer.finish().map_err(|_| "the device refused a write")?;
+    Ok(name)
+}
+
+/// Writes text to a device a block at a time, from block 0 on, up to `MAX_BLOCKS`
+struct DumpWriter<'a> {
+    device: &'a dyn BlockDevice,
+    block: &'a mut [u8],
+    len: usize,
+    next: u64,
+    error: Option<BlockError>,
+}
+
+impl<'a> DumpWriter<'a> {
+    fn new(device: &'a dyn BlockDevice, buffer: &'a mut [u8]) -> Result<Self, &'static str> {
+        let size = device.block_size();
+        if size > buffer.len() {
+            return Err("the device's blocks are too large");
+        }
+        Ok(DumpWriter {
+            device,
+            block: &mut buffer[..size],
+            len: 0,
+            next: 0,
+            error: None,
+        })
+    }
+
+    fn write_block(&mut self) -> Result<(), BlockError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.block[self.len..].fill(0);
+        let written = self.device.write_block(self.next, self.block);
+        self.error = written.err();
+        self.next += 1;
+        self.len = 0;
+        written
+    }
+
+    /// Writes the last block, with the zero that ends the dump, and flushes the device
+    fn finish(mut self) -> Result<(), BlockError> {
+        if self.len == self.block.len() {
+            match self.next + 1 < MAX_BLOCKS {
+                true => self.write_block()?,
+                false => self.len -= 1, // cut off; the last byte makes way for the end
+            }
+        }
+        self.write_block()?;
+        self.device.flush()
+    }
+}
+
+impl Write for DumpWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for &byte in text.as_bytes() {
+            if self.len == self.block.len() {
+                if self.next + 1 == MAX_BLOCKS {
+                    return Err(fmt::Error); // the last block is for the end
+                }
+                self.write_block().map_err(|_| fmt::Error)?;
+            }
+            self.block[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The dump on `device`, if it has one
+fn read_dump(device: &dyn BlockDevice) -> Result<Option<String>, BlockError> {
+    let mut text = Vec::new();
+    let mut block = alloc::vec![0; device.block_size()];
+    for index in 0..MAX_BLOCKS.min(device.block_count()) {
+        device.read_block(index, &mut block)?;
+        match block.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                text.extend_from_slice(&block[..end]);
+                break;
+            }
+            None => text.extend_from_slice(&block),
+        }
+    }
+    Ok(text
+        .starts_with(MAGIC.as_bytes())
+        .then(|| String::from_utf8_lossy(&text).into_owned()))
+}
+
+/// `crashdump [clear]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let name = cmdline::get("crashdump").ok_or("no crash dump device, see `crashdump=`")?;
+    let device = block::device(name).ok_or("no such block device")?;
+    match args {
+        [] => match read_dump(&*device).map_err(|_| "failed to read the device")? {
+            Some(dump) => println!("{}", dump),
+            None => println!("crashdump: {} holds no crash dump", name),
+        },
+        ["clear"] => {
+            let zeros = alloc::vec![0; device.block_size()];
+            device
+                .write_block(0, &zeros)
+                .and_then(|()| device.flush())
+                .map_err(|_| "failed to write the device")?;
+        }
+        _ => return Err("usage: crashdump [clear]"),
+    }
+    Ok(())
+}
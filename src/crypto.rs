@@ -0,0 +1,29 @@
+//! Checksums, hashes, ciphers, key exchange and signature verification.
+//!
+//! Everything here works on plain byte slices and needs no heap, so it can be used from early
+//! boot (verifying an initramfs) as well as from drivers and filesystems.
+
+use crate::rand;
+
+pub mod aes;
+pub mod chacha20poly1305;
+pub mod crc32;
+mod curve25519;
+pub mod ed25519;
+pub mod hmac;
+pub mod sha256;
+pub mod sha512;
+pub mod x25519;
+
+pub use aes::{Aes, AesXts};
+pub use crc32::{crc32, crc32c, Crc32, Crc32c};
+pub use hmac::{hkdf_expand, hkdf_extract, hmac_sha256, pbkdf2_sha256, HmacSha256};
+pub use sha256::{sha256, Sha256};
+pub use sha512::{sha512, Sha512};
+
+/// 32 unpredictable bytes for salts and keys, from `rand`
+pub fn random_seed() -> [u8; 32] {
+    let mut seed = [0; 32];
+    rand::fill_bytes(&mut seed);
+    seed
+}
@@ -0,0 +1,320 @@
+//! AES (FIPS 197) with 128, 192 and 256-bit keys, and the XTS mode disk encryption uses.
+//!
+//! Blocks go through AES-NI when the CPU has it and through a straightforward software implementation
+//! otherwise. The kernel is compiled without SSE and never saves XMM registers, so the AES-NI
+//! paths save and restore the two registers they use themselves.
+
+use core::arch::asm;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+pub const BLOCK_SIZE: usize = 16;
+const MAX_ROUNDS: usize = 14;
+
+type Block = [u8; BLOCK_SIZE];
+
+#[rustfmt::skip]
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+#[rustfmt::skip]
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+const ROUND_CONSTANTS: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Multiplication by x in GF(2^8)
+fn xtime(a: u8) -> u8 {
+    (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+fn xor_block(state: &mut Block, key: &Block) {
+    for (byte, key) in state.iter_mut().zip(key) {
+        *byte ^= key;
+    }
+}
+
+fn shift_rows(state: &mut Block, inverse: bool) {
+    let old = *state;
+    for row in 1..4 {
+        for column in 0..4 {
+            let from = if inverse {
+                (column + 4 - row) % 4
+            } else {
+                (column + row) % 4
+            };
+            state[row + 4 * column] = old[row + 4 * from];
+        }
+    }
+}
+
+fn mix_columns(state: &mut Block, coefficients: [u8; 4]) {
+    for column in state.chunks_exact_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        for (row, byte) in column.iter_mut().enumerate() {
+            *byte = (0..4).fold(0, |acc, i| acc ^ gf_mul(a[(row + i) % 4], coefficients[i]));
+        }
+    }
+}
+
+const MIX: [u8; 4] = [2, 3, 1, 1];
+const INV_MIX: [u8; 4] = [14, 11, 13, 9];
+
+/// Whether the CPU has the AES-NI instructions, checked once
+fn has_aesni() -> bool {
+    static AESNI: Once<bool> = Once::new();
+    *AESNI.call_once(|| {
+        CpuId::new()
+            .get_feature_info()
+            .is_some_and(|info| info.has_aesni())
+    })
+}
+
+/// An expanded AES key
+#[derive(Clone)]
+pub struct Aes {
+    rounds: usize,
+    /// Round keys for encryption
+    encrypt_keys: [Block; MAX_ROUNDS + 1],
+    /// Round keys for the equivalent inverse cipher, which AESDEC expects
+    decrypt_keys: [Block; MAX_ROUNDS + 1],
+    hardware: bool,
+}
+
+impl Aes {
+    /// Expands a 16, 24 or 32-byte key; `None` for any other length
+    pub fn new(key: &[u8]) -> Option<Aes> {
+        let key_words = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = key_words + 6;
+
+        let mut words = [[0u8; 4]; 4 * (MAX_ROUNDS + 1)];
+        for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+            word.copy_from_slice(bytes);
+        }
+        for i in key_words..4 * (rounds + 1) {
+            let mut temp = words[i - 1];
+            if i.is_multiple_of(key_words) {
+                temp.rotate_left(1);
+                temp = temp.map(|byte| SBOX[byte as usize]);
+                temp[0] ^= ROUND_CONSTANTS[i / key_words - 1];
+            } else if key_words > 6 && i % key_words == 4 {
+                temp = temp.map(|byte| SBOX[byte as usize]);
+            }
+            for byte in 0..4 {
+                words[i][byte] = words[i - key_words][byte] ^ temp[byte];
+            }
+        }
+
+        let mut encrypt_keys = [[0; BLOCK_SIZE]; MAX_ROUNDS + 1];
+        for (round, key) in encrypt_keys.iter_mut().take(rounds + 1).enumerate() {
+            for (column, word) in words[4 * round..4 * round + 4].iter().enumerate() {
+                key[4 * column..4 * column + 4].copy_from_slice(word);
+            }
+        }
+        let mut decrypt_keys = [[0; BLOCK_SIZE]; MAX_ROUNDS + 1];
+        for round in 0..=rounds {
+            decrypt_keys[round] = encrypt_keys[rounds - round];
+            if round != 0 && round != rounds {
+                mix_columns(&mut decrypt_keys[round], INV_MIX);
+            }
+        }
+
+        Some(Aes {
+            rounds,
+            encrypt_keys,
+            decrypt_keys,
+            hardware: has_aesni(),
+        })
+    }
+
+    pub fn encrypt_block(&self, block: &mut Block) {
+        if self.hardware {
+            unsafe { aesni_encrypt(&self.encrypt_keys, self.rounds, block) };
+            return;
+        }
+        xor_block(block, &self.encrypt_keys[0]);
+        for round in 1..=self.rounds {
+            *block = block.map(|byte| SBOX[byte as usize]);
+            shift_rows(block, false);
+            if round != self.rounds {
+                mix_columns(block, MIX);
+            }
+            xor_block(block, &self.encrypt_keys[round]);
+        }
+    }
+
+    pub fn decrypt_block(&self, block: &mut Block) {
+        if self.hardware {
+            unsafe { aesni_decrypt(&self.decrypt_keys, self.rounds, block) };
+            return;
+        }
+        xor_block(block, &self.decrypt_keys[0]);
+        for round in 1..=self.rounds {
+            *block = block.map(|byte| INV_SBOX[byte as usize]);
+            shift_rows(block, true);
+            if round != self.rounds {
+                mix_columns(block, INV_MIX);
+            }
+            xor_block(block, &self.decrypt_keys[round]);
+        }
+    }
+}
+
+/// # Safety
+///
+/// The CPU must support AES-NI and have SSE enabled (`arch::enable_sse`).
+unsafe fn aesni_encrypt(keys: &[Block; MAX_ROUNDS + 1], rounds: usize, block: &mut Block) {
+    asm!(
+        "sub rsp, 32",
+        "movdqu [rsp], xmm0",
+        "movdqu [rsp + 16], xmm1",
+        "movdqu xmm0, [{block}]",
+        "movdqu xmm1, [{keys}]",
+        "pxor xmm0, xmm1",
+        "2:",
+        "add {keys}, 16",
+        "movdqu xmm1, [{keys}]",
+        "aesenc xmm0, xmm1",
+        "dec {rounds}",
+        "jnz 2b",
+        "movdqu xmm1, [{keys} + 16]",
+        "aesenclast xmm0, xmm1",
+        "movdqu [{block}], xmm0",
+        "movdqu xmm0, [rsp]",
+        "movdqu xmm1, [rsp + 16]",
+        "add rsp, 32",
+        block = in(reg) block.as_mut_ptr(),
+        keys = inout(reg) keys.as_ptr() => _,
+        rounds = inout(reg) rounds - 1 => _,
+    );
+}
+
+/// # Safety
+///
+/// The CPU must support AES-NI and have SSE enabled (`arch::enable_sse`).
+unsafe fn aesni_decrypt(keys: &[Block; MAX_ROUNDS + 1], rounds: usize, block: &mut Block) {
+    asm!(
+        "sub rsp, 32",
+        "movdqu [rsp], xmm0",
+        "movdqu [rsp + 16], xmm1",
+        "movdqu xmm0, [{block}]",
+        "movdqu xmm1, [{keys}]",
+        "pxor xmm0, xmm1",
+        "2:",
+        "add {keys}, 16",
+        "movdqu xmm1, [{keys}]",
+        "aesdec xmm0, xmm1",
+        "dec {rounds}",
+        "jnz 2b",
+        "movdqu xmm1, [{keys} + 16]",
+        "aesdeclast xmm0, xmm1",
+        "movdqu [{block}], xmm0",
+        "movdqu xmm0, [rsp]",
+        "movdqu xmm1, [rsp + 16]",
+        "add rsp, 32",
+        block = in(reg) block.as_mut_ptr(),
+        keys = inout(reg) keys.as_ptr() => _,
+        rounds = inout(reg) rounds - 1 => _,
+    );
+}
+
+/// XTS-AES (IEEE 1619), the usual mode for encrypting disk sectors in place
+pub struct AesXts {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl AesXts {
+    /// `key` is the data key followed by the tweak key: 32 bytes for XTS-AES-128, 64 for -256
+    pub fn new(key: &[u8]) -> Option<AesXts> {
+        if key.len() != 32 && key.len() != 64 {
+            return None;
+        }
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Some(AesXts {
+            data: Aes::new(data)?,
+            tweak: Aes::new(tweak)?,
+        })
+    }
+
+    /// Encrypts sector `sector` in place; `buf` must be a whole number of AES blocks
+    pub fn encrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        self.process(sector, buf, |block| self.data.encrypt_block(block));
+    }
+
+    /// Decrypts sector `sector` in place; `buf` must be a whole number of AES blocks
+    pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        self.process(sector, buf, |block| self.data.decrypt_block(block));
+    }
+
+    fn process(&self, sector: u64, buf: &mut [u8], cipher: impl Fn(&mut Block)) {
+        assert!(
+            buf.len().is_multiple_of(BLOCK_SIZE),
+            "XTS without ciphertext stealing"
+        );
+        let mut tweak = [0; BLOCK_SIZE];
+        tweak[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+
+        for chunk in buf.chunks_exact_mut(BLOCK_SIZE) {
+            let block: &mut Block = chunk.try_into().unwrap();
+            xor_block(block, &tweak);
+            cipher(block);
+            xor_block(block, &tweak);
+
+            // multiply the tweak by x in GF(2^128), little endian
+            let carry = tweak[BLOCK_SIZE - 1] >> 7;
+            for i in (1..BLOCK_SIZE).rev() {
+                tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+            }
+            tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+        }
+    }
+}
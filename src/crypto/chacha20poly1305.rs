@@ -0,0 +1,260 @@
+//! ChaCha20-Poly1305 authenticated encryption (RFC 8439).
+//!
+//! Pure integer arithmetic, so unlike AES-NI it needs nothing from the CPU. Poly1305 uses five
+//! 26-bit limbs so every product fits in a `u64`.
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+// --- ChaCha20 ------------------------------------------------------------------------------
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One 64-byte block of keystream
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; 64] {
+    let word =
+        |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        initial[4 + i] = word(key, i);
+    }
+    initial[12] = counter;
+    for i in 0..3 {
+        initial[13 + i] = word(nonce, i);
+    }
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut out = [0; 64];
+    for i in 0..16 {
+        let value = state[i].wrapping_add(initial[i]);
+        out[4 * i..4 * i + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    out
+}
+
+/// XORs `data` with the ChaCha20 keystream starting at block `counter`
+pub fn chacha20(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let keystream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, key_byte) in chunk.iter_mut().zip(keystream) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+// --- Poly1305 ------------------------------------------------------------------------------
+
+/// Incremental Poly1305 one-time authenticator
+pub struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+}
+
+impl Poly1305 {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let word = |i: usize| u32::from_le_bytes(key[i..i + 4].try_into().unwrap());
+        // r with the bits RFC 8439 clamps cleared, split into 26-bit limbs
+        let r = [
+            word(0) & 0x03ff_ffff,
+            (word(3) >> 2) & 0x03ff_ff03,
+            (word(6) >> 4) & 0x03ff_c0ff,
+            (word(9) >> 6) & 0x03f0_3fff,
+            (word(12) >> 8) & 0x000f_ffff,
+        ];
+        Poly1305 {
+            r,
+            h: [0; 5],
+            pad: [word(16), word(20), word(24), word(28)],
+            buffer: [0; 16],
+            buffered: 0,
+        }
+    }
+
+    /// Absorbs one 16-byte block; `high` is the bit set just above the block (0 for the padded
+    /// final block, which carries its own 0x01 byte)
+    fn block(&mut self, block: &[u8; 16], high: u32) {
+        let word = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        let h0 = (h[0] + (word(0) & 0x03ff_ffff)) as u64;
+        let h1 = (h[1] + ((word(3) >> 2) & 0x03ff_ffff)) as u64;
+        let h2 = (h[2] + ((word(6) >> 4) & 0x03ff_ffff)) as u64;
+        let h3 = (h[3] + ((word(9) >> 6) & 0x03ff_ffff)) as u64;
+        let h4 = (h[4] + ((word(12) >> 8) | high)) as u64;
+
+        let d0 = h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3;
+        let mut d3 = h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4;
+        let mut d4 = h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0;
+
+        let mut c = d0 >> 26;
+        h[0] = d0 as u32 & 0x03ff_ffff;
+        d1 += c;
+        c = d1 >> 26;
+        h[1] = d1 as u32 & 0x03ff_ffff;
+        d2 += c;
+        c = d2 >> 26;
+        h[2] = d2 as u32 & 0x03ff_ffff;
+        d3 += c;
+        c = d3 >> 26;
+        h[3] = d3 as u32 & 0x03ff_ffff;
+        d4 += c;
+        c = d4 >> 26;
+        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += c as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let take = (16 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.block(&block, 1 << 24);
+            self.buffered = 0;
+        }
+        let mut chunks = data.chunks_exact(16);
+        for chunk in &mut chunks {
+            self.block(chunk.try_into().unwrap(), 1 << 24);
+        }
+        let rest = chunks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub fn finish(mut self) -> [u8; TAG_SIZE] {
+        if self.buffered > 0 {
+            let mut block = [0; 16];
+            block[..self.buffered].copy_from_slice(&self.buffer[..self.buffered]);
+            block[self.buffered] = 1;
+            self.block(&block, 0);
+        }
+
+        // fully carry h, then compute h - p and keep it if it didn't go negative
+        let mut h = self.h;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= 0x03ff_ffff;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= 0x03ff_ffff;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+
+        let mut g = [0u32; 5];
+        let mut c = 5;
+        for i in 0..4 {
+            g[i] = h[i] + c;
+            c = g[i] >> 26;
+            g[i] &= 0x03ff_ffff;
+        }
+        g[4] = (h[4] + c).wrapping_sub(1 << 26);
+        let keep_h = (g[4] >> 31).wrapping_neg();
+        for i in 0..5 {
+            h[i] = (h[i] & keep_h) | (g[i] & !keep_h);
+        }
+
+        // back to 32-bit words, then add the pad mod 2^128
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0; TAG_SIZE];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let sum = words[i] as u64 + self.pad[i] as u64 + carry;
+            tag[4 * i..4 * i + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
+
+// --- the AEAD ------------------------------------------------------------------------------
+
+fn tag(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_SIZE] {
+    let mut poly_key = [0; 32];
+    poly_key.copy_from_slice(&chacha20_block(key, 0, nonce)[..32]);
+    let mut mac = Poly1305::new(&poly_key);
+    let zeros = [0; 16];
+    mac.update(aad);
+    mac.update(&zeros[..(16 - aad.len() % 16) % 16]);
+    mac.update(ciphertext);
+    mac.update(&zeros[..(16 - ciphertext.len() % 16) % 16]);
+    mac.update(&(aad.len() as u64).to_le_bytes());
+    mac.update(&(ciphertext.len() as u64).to_le_bytes());
+    mac.finish()
+}
+
+/// Encrypts `data` in place and returns the tag authenticating it together with `aad`
+pub fn seal(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    data: &mut [u8],
+) -> [u8; TAG_SIZE] {
+    chacha20(key, 1, nonce, data);
+    tag(key, nonce, aad, data)
+}
+
+/// Checks `tag` and decrypts `data` in place; on a mismatch `data` is left encrypted and false is
+/// returned
+#[must_use]
+pub fn open(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    data: &mut [u8],
+    expected: &[u8; TAG_SIZE],
+) -> bool {
+    let actual = tag(key, nonce, aad, data);
+    // constant time, so a forger learns nothing from how long the comparison took
+    let difference = actual
+        .iter()
+        .zip(expected)
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    if difference != 0 {
+        return false;
+    }
+    chacha20(key, 1, nonce, data);
+    true
+}
@@ -0,0 +1,154 @@
+//! The two CRC-32 variants the kernel runs into.
+//!
+//! * CRC-32 (IEEE 802.3), used by GPT headers, Ethernet, gzip and zip;
+//! * CRC-32C (Castagnoli), used by iSCSI, ext4 and btrfs metadata, and by the kernel's own
+//!   on-disk structures. The SSE4.2 `crc32` instruction computes this one, so it's used whenever
+//!   the CPU has it.
+//!
+//! Both are the reflected variants with an all-ones initial value and final xor, i.e. the values
+//! other tools print.
+
+use core::arch::asm;
+use raw_cpuid::CpuId;
+use spin::Once;
+
+/// Reversed polynomial of CRC-32 (IEEE)
+const IEEE_POLYNOMIAL: u32 = 0xEDB8_8320;
+/// Reversed polynomial of CRC-32C (Castagnoli)
+const CASTAGNOLI_POLYNOMIAL: u32 = 0x82F6_3B78;
+
+const fn make_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ polynomial
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static IEEE_TABLE: [u32; 256] = make_table(IEEE_POLYNOMIAL);
+static CASTAGNOLI_TABLE: [u32; 256] = make_table(CASTAGNOLI_POLYNOMIAL);
+
+fn update_table(table: &[u32; 256], mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Incremental CRC-32 (IEEE)
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = update_table(&IEEE_TABLE, self.state, data);
+    }
+
+    /// The checksum of everything passed to `update` so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 (IEEE) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Whether the CPU has SSE4.2 and with it the `crc32` instruction, checked once
+fn has_sse42() -> bool {
+    static SSE42: Once<bool> = Once::new();
+    *SSE42.call_once(|| {
+        CpuId::new()
+            .get_feature_info()
+            .is_some_and(|info| info.has_sse42())
+    })
+}
+
+/// CRC-32C using the SSE4.2 `crc32` instruction.
+///
+/// Written as inline assembly rather than with the `_mm_crc32_*` intrinsics because the kernel
+/// target disables SSE; the instruction itself only touches general-purpose registers.
+///
+/// # Safety
+///
+/// The CPU must support SSE4.2.
+unsafe fn update_sse42(mut crc: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(8);
+    let mut crc64 = crc as u64;
+    for word in &mut words {
+        let word = u64::from_le_bytes(word.try_into().unwrap());
+        asm!("crc32 {crc}, {word}", crc = inout(reg) crc64, word = in(reg) word,
+            options(pure, nomem, nostack));
+    }
+    crc = crc64 as u32;
+    for &byte in words.remainder() {
+        asm!("crc32 {crc:e}, {byte}", crc = inout(reg) crc, byte = in(reg_byte) byte,
+            options(pure, nomem, nostack));
+    }
+    crc
+}
+
+/// Incremental CRC-32C (Castagnoli), hardware accelerated when possible
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32c {
+    state: u32,
+}
+
+impl Crc32c {
+    pub const fn new() -> Self {
+        Crc32c { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = if has_sse42() {
+            unsafe { update_sse42(self.state, data) }
+        } else {
+            update_table(&CASTAGNOLI_TABLE, self.state, data)
+        };
+    }
+
+    /// The checksum of everything passed to `update` so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32C (Castagnoli) of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
@@ -0,0 +1,133 @@
+//! Arithmetic in GF(2^255 - 19), the field under both Ed25519 and X25519.
+//!
+//! Follows TweetNaCl: an element is sixteen 16-bit limbs held in `i64`s, with enough headroom
+//! that additions never need a carry and products only need two. Nothing branches on the values,
+//! so the arithmetic runs in constant time, which X25519 relies on.
+
+/// An element of GF(2^255 - 19)
+pub(super) type Fe = [i64; 16];
+
+pub(super) const ZERO: Fe = [0; 16];
+pub(super) const ONE: Fe = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Propagates carries so every limb is back in 16 bits, folding the top carry back in times 38
+fn carry(o: &mut Fe) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// Swaps `p` and `q` if `swap` is set
+pub(super) fn select(p: &mut Fe, q: &mut Fe, swap: bool) {
+    let mask = -(swap as i64);
+    for i in 0..16 {
+        let t = mask & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+/// The canonical 32-byte little-endian encoding
+pub(super) fn pack_fe(n: &Fe) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    // subtract p twice if that doesn't go negative
+    for _ in 0..2 {
+        let mut m = ZERO;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let borrow = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, borrow == 0);
+    }
+    let mut out = [0; 32];
+    for i in 0..16 {
+        out[2 * i] = t[i] as u8;
+        out[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    out
+}
+
+pub(super) fn unpack_fe(bytes: &[u8; 32]) -> Fe {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = bytes[2 * i] as i64 + ((bytes[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+pub(super) fn fe_eq(a: &Fe, b: &Fe) -> bool {
+    pack_fe(a) == pack_fe(b)
+}
+
+/// The "sign" of x, its lowest bit
+pub(super) fn parity(a: &Fe) -> u8 {
+    pack_fe(a)[0] & 1
+}
+
+pub(super) fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    core::array::from_fn(|i| a[i] + b[i])
+}
+
+pub(super) fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    core::array::from_fn(|i| a[i] - b[i])
+}
+
+pub(super) fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    // 2^256 = 38 (mod p)
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o: Fe = core::array::from_fn(|i| t[i]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+pub(super) fn fe_square(a: &Fe) -> Fe {
+    fe_mul(a, a)
+}
+
+/// a^(p - 2) = 1/a
+pub(super) fn fe_invert(a: &Fe) -> Fe {
+    let mut c = *a;
+    for bit in (0..=253).rev() {
+        c = fe_square(&c);
+        if bit != 2 && bit != 4 {
+            c = fe_mul(&c, a);
+        }
+    }
+    c
+}
+
+/// a^((p - 5) / 8), the core of the square root
+pub(super) fn fe_pow2523(a: &Fe) -> Fe {
+    let mut c = *a;
+    for bit in (0..=250).rev() {
+        c = fe_square(&c);
+        if bit != 1 {
+            c = fe_mul(&c, a);
+        }
+    }
+    c
+}
@@ -0,0 +1,232 @@
+//! Ed25519 signature verification (RFC 8032).
+//!
+//! Only verification is needed in the kernel: artifacts are signed on the build host. The group
+//! arithmetic follows TweetNaCl, with points in extended twisted Edwards coordinates. Nothing
+//! here handles secrets, so it isn't written to run in constant time.
+
+use super::curve25519::{
+    fe_add, fe_eq, fe_invert, fe_mul, fe_pow2523, fe_square, fe_sub, pack_fe, parity, select,
+    unpack_fe, Fe, ONE, ZERO,
+};
+use super::sha512::Sha512;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// A curve point as (X, Y, Z, T) with x = X/Z, y = Y/Z and x * y = T/Z
+type Point = [Fe; 4];
+
+/// The curve constant d = -121665/121666
+#[rustfmt::skip]
+const D: Fe = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070,
+    0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+
+/// 2 * d
+#[rustfmt::skip]
+const D2: Fe = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+    0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+
+/// A square root of -1
+#[rustfmt::skip]
+const SQRT_M1: Fe = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+    0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+
+/// Coordinates of the base point B
+#[rustfmt::skip]
+const BASE_X: Fe = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+    0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+#[rustfmt::skip]
+const BASE_Y: Fe = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+];
+
+/// The group order L = 2^252 + 27742317777372353535851937790883648493, little endian
+#[rustfmt::skip]
+const ORDER: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+// --- group arithmetic ----------------------------------------------------------------------
+
+/// p += q
+fn point_add(p: &mut Point, q: &Point) {
+    let a = fe_mul(&fe_sub(&p[1], &p[0]), &fe_sub(&q[1], &q[0]));
+    let b = fe_mul(&fe_add(&p[0], &p[1]), &fe_add(&q[0], &q[1]));
+    let c = fe_mul(&fe_mul(&p[3], &q[3]), &D2);
+    let d = fe_mul(&p[2], &q[2]);
+    let d = fe_add(&d, &d);
+    let e = fe_sub(&b, &a);
+    let f = fe_sub(&d, &c);
+    let g = fe_add(&d, &c);
+    let h = fe_add(&b, &a);
+
+    p[0] = fe_mul(&e, &f);
+    p[1] = fe_mul(&h, &g);
+    p[2] = fe_mul(&g, &f);
+    p[3] = fe_mul(&e, &h);
+}
+
+fn point_swap(p: &mut Point, q: &mut Point, swap: bool) {
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        select(p, q, swap);
+    }
+}
+
+/// The 32-byte encoding: y with the parity of x in the top bit
+fn pack_point(p: &Point) -> [u8; 32] {
+    let z_inverse = fe_invert(&p[2]);
+    let x = fe_mul(&p[0], &z_inverse);
+    let y = fe_mul(&p[1], &z_inverse);
+    let mut out = pack_fe(&y);
+    out[31] ^= parity(&x) << 7;
+    out
+}
+
+/// scalar * q, with the scalar as 32 little-endian bytes
+fn scalar_mul(q: &Point, scalar: &[u8; 32]) -> Point {
+    let mut p = [ZERO, ONE, ONE, ZERO];
+    let mut q = *q;
+    for bit in (0..256).rev() {
+        let set = (scalar[bit / 8] >> (bit & 7)) & 1 == 1;
+        point_swap(&mut p, &mut q, set);
+        point_add(&mut q, &p);
+        let double = p;
+        point_add(&mut p, &double);
+        point_swap(&mut p, &mut q, set);
+    }
+    p
+}
+
+fn scalar_mul_base(scalar: &[u8; 32]) -> Point {
+    let base = [BASE_X, BASE_Y, ONE, fe_mul(&BASE_X, &BASE_Y)];
+    scalar_mul(&base, scalar)
+}
+
+/// Decodes a point and negates it, or returns `None` if the encoding isn't on the curve
+fn unpack_negated(bytes: &[u8; 32]) -> Option<Point> {
+    let y = unpack_fe(bytes);
+    // x^2 = (y^2 - 1) / (d y^2 + 1)
+    let y2 = fe_square(&y);
+    let num = fe_sub(&y2, &ONE);
+    let den = fe_add(&ONE, &fe_mul(&y2, &D));
+
+    let den2 = fe_square(&den);
+    let den4 = fe_square(&den2);
+    let den6 = fe_mul(&den4, &den2);
+    let t = fe_mul(&fe_mul(&den6, &num), &den);
+    let t = fe_pow2523(&t);
+    let t = fe_mul(&fe_mul(&t, &num), &den);
+    let t = fe_mul(&fe_mul(&t, &den), &den);
+    let mut x = t;
+
+    if !fe_eq(&fe_mul(&fe_square(&x), &den), &num) {
+        x = fe_mul(&x, &SQRT_M1);
+    }
+    if !fe_eq(&fe_mul(&fe_square(&x), &den), &num) {
+        return None;
+    }
+    if parity(&x) == bytes[31] >> 7 {
+        x = fe_sub(&ZERO, &x);
+    }
+    let t = fe_mul(&x, &y);
+    Some([x, y, ONE, t])
+}
+
+// --- scalars -------------------------------------------------------------------------------
+
+/// Reduces a 512-bit little-endian number modulo the group order
+fn reduce(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * ORDER[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * ORDER[j];
+        carry = x[j] >> 8;
+        x[j] &= 0xff;
+    }
+    for j in 0..32 {
+        x[j] -= carry * ORDER[j];
+    }
+    let mut out = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        out[i] = x[i] as u8;
+    }
+    out
+}
+
+/// Whether a little-endian scalar is below the group order, as RFC 8032 requires of S
+fn is_canonical(scalar: &[u8; 32]) -> bool {
+    for i in (0..32).rev() {
+        let order = ORDER[i] as u8;
+        if scalar[i] != order {
+            return scalar[i] < order;
+        }
+    }
+    false
+}
+
+/// Checks `signature` over the concatenation of `message` against `public_key`.
+///
+/// Taking the message in pieces lets callers prepend a context string without copying.
+pub fn verify_parts(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    message: &[&[u8]],
+    signature: &[u8; SIGNATURE_SIZE],
+) -> bool {
+    let r: &[u8; 32] = signature[..32].try_into().unwrap();
+    let s: &[u8; 32] = signature[32..].try_into().unwrap();
+    if !is_canonical(s) {
+        return false;
+    }
+    let minus_a = match unpack_negated(public_key) {
+        Some(point) => point,
+        None => return false,
+    };
+
+    let mut hasher = Sha512::new();
+    hasher.update(r);
+    hasher.update(public_key);
+    for part in message {
+        hasher.update(part);
+    }
+    let mut wide = [0i64; 64];
+    for (limb, byte) in wide.iter_mut().zip(hasher.finish()) {
+        *limb = byte as i64;
+    }
+    let h = reduce(&mut wide);
+
+    // R must equal S*B - h*A
+    let mut check = scalar_mul(&minus_a, &h);
+    point_add(&mut check, &scalar_mul_base(s));
+    pack_point(&check) == *r
+}
+
+/// Checks `signature` over `message` against `public_key`
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_SIZE],
+) -> bool {
+    verify_parts(public_key, &[message], signature)
+}
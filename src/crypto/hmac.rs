@@ -0,0 +1,91 @@
+//! HMAC-SHA-256 (RFC 2104) and the key derivation functions built on it: PBKDF2 (RFC 8018) for
+//! passphrases and HKDF (RFC 5869) for key exchange output.
+
+use super::sha256::{Sha256, BLOCK_SIZE, DIGEST_SIZE};
+
+/// Incremental HMAC-SHA-256
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut block_key = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block_key[..DIGEST_SIZE].copy_from_slice(&super::sha256(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block_key.map(|byte| byte ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block_key.map(|byte| byte ^ 0x5c));
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> [u8; DIGEST_SIZE] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+/// HMAC-SHA-256 of `message` under `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(message);
+    mac.finish()
+}
+
+/// Derives `out.len()` bytes of key material from a password with PBKDF2-HMAC-SHA-256
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    // the keyed state is the same for every block and iteration, only set it up once
+    let keyed = HmacSha256::new(password);
+    for (index, chunk) in out.chunks_mut(DIGEST_SIZE).enumerate() {
+        let mut mac = keyed.clone();
+        mac.update(salt);
+        mac.update(&(index as u32 + 1).to_be_bytes());
+        let mut block = mac.finish();
+        let mut result = block;
+        for _ in 1..iterations {
+            let mut mac = keyed.clone();
+            mac.update(&block);
+            block = mac.finish();
+            for (result, byte) in result.iter_mut().zip(block) {
+                *result ^= byte;
+            }
+        }
+        chunk.copy_from_slice(&result[..chunk.len()]);
+    }
+}
+
+/// HKDF-Extract: condenses input keying material into a pseudorandom key
+pub fn hkdf_extract(salt: &[u8], input: &[u8]) -> [u8; DIGEST_SIZE] {
+    hmac_sha256(salt, input)
+}
+
+/// HKDF-Expand: stretches a pseudorandom key into `out.len()` bytes (at most 255 digests) bound
+/// to `info`
+pub fn hkdf_expand(prk: &[u8; DIGEST_SIZE], info: &[u8], out: &mut [u8]) {
+    assert!(out.len() <= 255 * DIGEST_SIZE, "HKDF output too long");
+    let keyed = HmacSha256::new(prk);
+    let mut previous: Option<[u8; DIGEST_SIZE]> = None;
+    for (index, chunk) in out.chunks_mut(DIGEST_SIZE).enumerate() {
+        let mut mac = keyed.clone();
+        if let Some(previous) = &previous {
+            mac.update(previous);
+        }
+        mac.update(info);
+        mac.update(&[index as u8 + 1]);
+        let block = mac.finish();
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        previous = Some(block);
+    }
+}
@@ -0,0 +1,60 @@
+//! X25519 Diffie-Hellman (RFC 7748).
+//!
+//! A Montgomery ladder over the u-coordinate, as in TweetNaCl's `crypto_scalarmult`. Private keys
+//! are secret here, so the ladder swaps with masks rather than branches.
+
+use super::curve25519::{
+    fe_add, fe_invert, fe_mul, fe_square, fe_sub, pack_fe, select, unpack_fe, Fe, ONE, ZERO,
+};
+
+pub const KEY_SIZE: usize = 32;
+
+/// The u-coordinate of the base point
+const BASE_POINT: [u8; KEY_SIZE] = {
+    let mut point = [0; KEY_SIZE];
+    point[0] = 9;
+    point
+};
+
+/// (A - 2) / 4 for the curve constant A = 486662
+const A24: Fe = [0xdb41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// `scalar` times the point with u-coordinate `point`; the scalar is clamped first
+pub fn x25519(scalar: &[u8; KEY_SIZE], point: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    let mut z = *scalar;
+    z[0] &= 248;
+    z[31] = (z[31] & 127) | 64;
+
+    let x = unpack_fe(point);
+    let (mut a, mut b, mut c, mut d) = (ONE, x, ZERO, ONE);
+    for bit in (0..255).rev() {
+        let set = (z[bit / 8] >> (bit & 7)) & 1 == 1;
+        select(&mut a, &mut b, set);
+        select(&mut c, &mut d, set);
+        let e = fe_add(&a, &c);
+        let a2 = fe_sub(&a, &c);
+        let c2 = fe_add(&b, &d);
+        let b2 = fe_sub(&b, &d);
+        let d2 = fe_square(&e);
+        let f = fe_square(&a2);
+        let a3 = fe_mul(&c2, &a2);
+        let c3 = fe_mul(&b2, &e);
+        let e2 = fe_add(&a3, &c3);
+        let a4 = fe_sub(&a3, &c3);
+        b = fe_square(&a4);
+        let c4 = fe_sub(&d2, &f);
+        let a5 = fe_add(&fe_mul(&c4, &A24), &d2);
+        c = fe_mul(&c4, &a5);
+        a = fe_mul(&d2, &f);
+        d = fe_mul(&b, &x);
+        b = fe_square(&e2);
+        select(&mut a, &mut b, set);
+        select(&mut c, &mut d, set);
+    }
+    pack_fe(&fe_mul(&a, &fe_invert(&c)))
+}
+
+/// The public key belonging to `private_key`
+pub fn public_key(private_key: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    x25519(private_key, &BASE_POINT)
+}
@@ -0,0 +1,183 @@
+//! Device drivers that attach to buses found at boot.
+//!
+//! Every built-in driver declares itself with a `Driver` in its module, and `DRIVERS` lists them
+//! all. `init` walks that list at boot, each driver after the ones it names in `after`: PCI
+//! drivers register with the bus so devices are claimed during `pci::init`, platform drivers, for
+//! devices on no bus that can be enumerated, look for their device right away. Drivers that log
+//! something come after `serial`, so their messages make it to the serial console too. A driver
+//! that misbehaves can be left out with `skip_drivers` on the command line; in safe mode, all but
+//! a few are.
+
+use crate::{boot, cmdline, pci, println, warnln};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[cfg(feature = "gfx")]
+pub mod bga;
+pub mod fw_cfg;
+pub mod pcspeaker;
+pub mod ps2;
+pub mod serial;
+pub mod virtio;
+
+/// How a driver finds its devices
+pub enum Attach {
+    /// The PCI devices the driver matches, probed by the bus
+    Pci(&'static pci::Driver),
+    /// A device on no bus: the function sets it up, or says why it can't
+    Platform(fn() -> Result<(), &'static str>),
+}
+
+/// A built-in driver
+pub struct Driver {
+    pub name: &'static str,
+    /// Drivers set up before this one, by name
+    pub after: &'static [&'static str],
+    pub attach: Attach,
+}
+
+impl Driver {
+    pub const fn pci(driver: &'static pci::Driver, after: &'static [&'static str]) -> Driver {
+        Driver {
+            name: driver.name,
+            after,
+            attach: Attach::Pci(driver),
+        }
+    }
+
+    pub const fn platform(
+        name: &'static str,
+        after: &'static [&'static str],
+        init: fn() -> Result<(), &'static str>,
+    ) -> Driver {
+        Driver {
+            name,
+            after,
+            attach: Attach::Platform(init),
+        }
+    }
+}
+
+/// Every built-in driver, in no particular order
+static DRIVERS: &[&Driver] = &[
+    #[cfg(feature = "gfx")]
+    &bga::DRIVER,
+    &fw_cfg::DRIVER,
+    &ps2::DRIVER,
+    &serial::DRIVER,
+    &virtio::balloon::DRIVER,
+    &virtio::blk::DRIVER,
+    &virtio::console::DRIVER,
+    #[cfg(feature = "net")]
+    &virtio::net::DRIVER,
+    #[cfg(feature = "p9")]
+    &virtio::p9::DRIVER,
+];
+
+/// The drivers set up in safe mode: the consoles and the keyboard, and fw_cfg for the command line
+const SAFE_MODE_DRIVERS: &[&str] = &["fw_cfg", "ps2", "serial"];
+
+/// What became of each platform driver's `init`, in the order they ran
+static RESULTS: Mutex<Vec<(&'static str, Result<(), &'static str>)>> = Mutex::new(Vec::new());
+
+/// `DRIVERS` with every driver after those in its `after`; panics if a driver names one that
+/// doesn't exist or the dependencies go round in a circle, both mistakes in the list
+fn dependency_order() -> Vec<&'static Driver> {
+    fn visit(driver: &'static Driver, visiting: &mut Vec<&str>, order: &mut Vec<&'static Driver>) {
+        if order.iter().any(|done| done.name == driver.name) {
+            return;
+        }
+        if visiting.contains(&driver.name) {
+            panic!("drivers: {} is part of a dependency cycle", driver.name);
+        }
+        visiting.push(driver.name);
+        for &name in driver.after {
+            let Some(dependency) = find(name) else {
+                panic!(
+                    "drivers: {} comes after {}, which doesn't exist",
+                    driver.name, name
+                );
+            };
+            visit(dependency, visiting, order);
+        }
+        visiting.pop();
+        order.push(driver);
+    }
+
+    let mut order = Vec::with_capacity(DRIVERS.len());
+    for driver in DRIVERS {
+        visit(driver, &mut Vec::new(), &mut order);
+    }
+    order
+}
+
+/// The built-in driver called `name`
+pub fn find(name: &str) -> Option<&'static Driver> {
+    DRIVERS.iter().copied().find(|driver| driver.name == name)
+}
+
+/// Sets up every built-in driver but those the command line's `skip_drivers` names, and in safe
+/// mode those not in `SAFE_MODE_DRIVERS`; call before the buses are scanned so devices are claimed
+/// during `pci::init`
+#[link_section = ".init.text"]
+pub fn init() {
+    for driver in dependency_order() {
+        let skipped = if cmdline::list("skip_drivers").any(|name| name == driver.name) {
+            Some("skipped on the command line")
+        } else if boot::safe_mode() && !SAFE_MODE_DRIVERS.contains(&driver.name) {
+            Some("left out in safe mode")
+        } else {
+            None
+        };
+        if let Some(reason) = skipped {
+            println!("drivers: skipping {}", driver.name);
+            if let Attach::Platform(_) = driver.attach {
+                RESULTS.lock().push((driver.name, Err(reason)));
+            }
+            continue;
+        }
+        match driver.attach {
+            Attach::Pci(pci_driver) => pci::register_driver(pci_driver),
+            Attach::Platform(init) => {
+                let result = init();
+                if let Err(err) = result {
+                    warnln!("drivers: {}: {}", driver.name, err);
+                }
+                RESULTS.lock().push((driver.name, result));
+            }
+        }
+    }
+}
+
+/// `drivers`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    println!("{:<16} {:<9} STATUS", "NAME", "BUS");
+    for driver in dependency_order() {
+        match driver.attach {
+            Attach::Pci(_) => {
+                let devices = pci::devices()
+                    .filter(|device| pci::driver_of(device.address) == Some(driver.name))
+                    .count();
+                let noun = match devices {
+                    1 => "device",
+                    _ => "devices",
+                };
+                println!("{:<16} {:<9} {} {}", driver.name, "pci", devices, noun);
+            }
+            Attach::Platform(_) => {
+                let result = RESULTS
+                    .lock()
+                    .iter()
+                    .find(|(name, _)| *name == driver.name)
+                    .map(|(_, result)| *result);
+                let status = match result {
+                    Some(Ok(())) => "ready",
+                    Some(Err(err)) => err,
+                    None => "not set up",
+                };
+                println!("{:<16} {:<9} {}", driver.name, "platform", status);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! The Bochs graphics adapter: QEMU's standard VGA (`-vga std`), and Bochs's and VirtualBox's
+//! display.
+//!
+//! It's VGA compatible, so the boot text mode works on it unchanged, and it adds the "DISPI"
+//! interface: a few 16-bit registers behind an index and a data port that set a resolution and
+//! switch to a linear framebuffer, which BAR 0 points to.
+
+use crate::arch::port::Port;
+use crate::drivers;
+use crate::gfx::{self, Adapter, Mode, PixelFormat};
+use crate::pci::{Bar, DeviceMatch, Driver, PciDevice};
+use alloc::boxed::Box;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const INDEX_PORT: u16 = 0x1ce;
+const DATA_PORT: u16 = 0x1cf;
+
+/// DISPI registers
+mod index {
+    pub const ID: u16 = 0;
+    pub const XRES: u16 = 1;
+    pub const YRES: u16 = 2;
+    pub const BPP: u16 = 3;
+    pub const ENABLE: u16 = 4;
+    pub const VIRT_WIDTH: u16 = 6;
+    pub const X_OFFSET: u16 = 8;
+    pub const Y_OFFSET: u16 = 9;
+}
+
+const ENABLED: u16 = 0x01;
+const LFB_ENABLED: u16 = 0x40;
+
+/// Versions answer 0xb0c0 to 0xb0c5; 32-bit pixels and the linear framebuffer need 0xb0c2
+const ID_MIN: u16 = 0xb0c2;
+const ID_MAX: u16 = 0xb0c5;
+
+/// Largest resolution the adapters take
+const MAX_WIDTH: usize = 2560;
+const MAX_HEIGHT: usize = 1600;
+
+struct Bga {
+    framebuffer: PhysAddr,
+    framebuffer_size: u64,
+    ports: Mutex<(Port<u16>, Port<u16>)>,
+}
+
+impl Bga {
+    fn read(&self, register: u16) -> u16 {
+        let mut ports = self.ports.lock();
+        ports.0.write(register);
+        ports.1.read()
+    }
+
+    fn write(&self, register: u16, value: u16) {
+        let mut ports = self.ports.lock();
+        ports.0.write(register);
+        ports.1.write(value);
+    }
+}
+
+impl Adapter for Bga {
+    fn name(&self) -> &'static str {
+        "bga"
+    }
+
+    fn set_mode(&self, width: usize, height: usize) -> Result<Mode, &'static str> {
+        if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+            return Err("resolution out of range");
+        }
+        if (width * height * 4) as u64 > self.framebuffer_size {
+            return Err("resolution needs more video memory than there is");
+        }
+        self.write(index::ENABLE, 0);
+        self.write(index::XRES, width as u16);
+        self.write(index::YRES, height as u16);
+        self.write(index::BPP, 32);
+        self.write(index::VIRT_WIDTH, width as u16);
+        self.write(index::X_OFFSET, 0);
+        self.write(index::Y_OFFSET, 0);
+        self.write(index::ENABLE, ENABLED | LFB_ENABLED);
+        if self.read(index::XRES) as usize != width || self.read(index::YRES) as usize != height {
+            return Err("the adapter refused the resolution");
+        }
+        Ok(Mode {
+            address: self.framebuffer,
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgr,
+        })
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    let Some(Bar::Memory { address, size, .. }) = device.bars[0] else {
+        return Err("BAR 0 is not the framebuffer");
+    };
+    let bga = Bga {
+        framebuffer: PhysAddr::new(address),
+        framebuffer_size: size,
+        ports: Mutex::new(unsafe { (Port::new(INDEX_PORT), Port::new(DATA_PORT)) }),
+    };
+    let id = bga.read(index::ID);
+    if !(ID_MIN..=ID_MAX).contains(&id) {
+        return Err("DISPI interface missing or too old");
+    }
+    device.enable();
+    crate::println!(
+        "bga: at {}, {} MiB of video memory",
+        device.address,
+        size / (1024 * 1024)
+    );
+    gfx::register_adapter(Box::leak(Box::new(bga)));
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: 0x1234,
+        device_id: Some(0x1111),
+    },
+    DeviceMatch::Id {
+        vendor_id: 0x80ee,
+        device_id: Some(0xbeef),
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "bga",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
@@ -0,0 +1,190 @@
+//! QEMU's firmware configuration device: a channel through which the host hands the guest the
+//! kernel command line (`-append`) and any files it was told to (`-fw_cfg name=opt/...`).
+//!
+//! Every item has a 16-bit selector: written to the selector port, it makes the data port stream
+//! the item's bytes. Fixed items have well-known selectors, files are found through the file
+//! directory. Settings for this kernel, a test harness's among them, go in files named
+//! `opt/rust_os/<name>`, e.g. `-fw_cfg name=opt/rust_os/test,string=exceptions`, and are read
+//! with `option`.
+
+use crate::arch::port::{ReadOnlyPort, WriteOnlyPort};
+use crate::drivers;
+use crate::println;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+/// Fixed items
+mod item {
+    pub const SIGNATURE: u16 = 0x0000;
+    pub const CMDLINE_SIZE: u16 = 0x0014;
+    pub const CMDLINE_DATA: u16 = 0x0015;
+    pub const FILE_DIR: u16 = 0x0019;
+}
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+/// Longest file name, NUL included
+const NAME_SIZE: usize = 56;
+/// Where `option` looks
+const OPTION_PREFIX: &str = "opt/rust_os/";
+
+/// One entry of the file directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u32,
+    pub selector: u16,
+}
+
+struct FwCfg {
+    selector: WriteOnlyPort<u16>,
+    data: ReadOnlyPort<u8>,
+}
+
+impl FwCfg {
+    /// Selects `item` and reads the first `buf.len()` bytes of it
+    fn read(&mut self, item: u16, buf: &mut [u8]) {
+        self.selector.write(item);
+        self.read_more(buf);
+    }
+
+    /// Reads on from where the last read stopped
+    fn read_more(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.data.read();
+        }
+    }
+
+    /// The file directory, which is big-endian unlike everything else here
+    fn files(&mut self) -> Vec<FileEntry> {
+        let mut count = [0; 4];
+        self.read(item::FILE_DIR, &mut count);
+        (0..u32::from_be_bytes(count))
+            .map(|_| {
+                let mut entry = [0; 8 + NAME_SIZE];
+                self.read_more(&mut entry);
+                let name = &entry[8..];
+                let len = name.iter().position(|&byte| byte == 0).unwrap_or(NAME_SIZE);
+                FileEntry {
+                    name: String::from_utf8_lossy(&name[..len]).into(),
+                    size: u32::from_be_bytes(entry[0..4].try_into().unwrap()),
+                    selector: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The device, looked for the first time it's needed
+static DEVICE: Once<Option<Mutex<FwCfg>>> = Once::new();
+
+/// The device, if there is one; looks for it by its signature the first time, so it can be read
+/// before the drivers are set up, e.g. for the command line
+fn device() -> Option<&'static Mutex<FwCfg>> {
+    DEVICE
+        .call_once(|| {
+            let mut device = unsafe {
+                FwCfg {
+                    selector: WriteOnlyPort::new(SELECTOR_PORT),
+                    data: ReadOnlyPort::new(DATA_PORT),
+                }
+            };
+            let mut signature = [0; 4];
+            device.read(item::SIGNATURE, &mut signature);
+            (signature == *SIGNATURE).then(|| Mutex::new(device))
+        })
+        .as_ref()
+}
+
+pub static DRIVER: drivers::Driver = drivers::Driver::platform("fw_cfg", &["serial"], init);
+
+fn init() -> Result<(), &'static str> {
+    let device = device().ok_or("no device")?;
+    println!("fw_cfg: {} files", device.lock().files().len());
+    Ok(())
+}
+
+pub fn present() -> bool {
+    device().is_some()
+}
+
+/// The host's file directory; empty without the device
+pub fn files() -> Vec<FileEntry> {
+    device().map_or_else(Vec::new, |device| device.lock().files())
+}
+
+/// The contents of the file called `name`
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    let mut device = device()?.lock();
+    let entry = device.files().into_iter().find(|file| file.name == name)?;
+    let mut data = vec![0; entry.size as usize];
+    device.read(entry.selector, &mut data);
+    Some(data)
+}
+
+/// Copies as much of the `-append` text as fits into `buf`, without the heap; returns how many
+/// bytes it copied, 0 without the device
+pub fn cmdline_into(buf: &mut [u8]) -> usize {
+    let mut device = match device() {
+        Some(device) => device.lock(),
+        None => return 0,
+    };
+    let mut size = [0; 4];
+    device.read(item::CMDLINE_SIZE, &mut size);
+    let len = buf.len().min(u32::from_le_bytes(size) as usize);
+    device.read(item::CMDLINE_DATA, &mut buf[..len]);
+    buf[..len].iter().position(|&byte| byte == 0).unwrap_or(len)
+}
+
+/// The kernel command line QEMU was given with `-append`, if any
+pub fn cmdline() -> Option<String> {
+    let mut device = device()?.lock();
+    let mut size = [0; 4];
+    device.read(item::CMDLINE_SIZE, &mut size);
+    let mut data = vec![0; u32::from_le_bytes(size) as usize];
+    device.read(item::CMDLINE_DATA, &mut data);
+    // the size counts the terminating NUL
+    let len = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    (len > 0).then(|| String::from_utf8_lossy(&data[..len]).into())
+}
+
+/// The setting `opt/rust_os/<name>` as text, without surrounding whitespace
+pub fn option(name: &str) -> Option<String> {
+    let mut path = String::from(OPTION_PREFIX);
+    path.push_str(name);
+    let data = read_file(&path)?;
+    Some(String::from_utf8_lossy(&data).trim().into())
+}
+
+/// `fwcfg [<file>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    if !present() {
+        return Err("no fw_cfg device");
+    }
+    match args {
+        [] => {
+            if let Some(cmdline) = cmdline() {
+                println!("command line: {}", cmdline);
+            }
+            for file in files() {
+                println!("{:#06x} {:>8}  {}", file.selector, file.size, file.name);
+            }
+        }
+        [name] => {
+            let data = read_file(name).ok_or("no such file")?;
+            match core::str::from_utf8(&data) {
+                Ok(text) => println!("{}", text),
+                Err(_) => println!("{} bytes of binary data", data.len()),
+            }
+        }
+        _ => return Err("usage: fwcfg [<file>]"),
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! The PC speaker, driven by PIT channel 2 as a square wave at the pitch of the note playing.
+//!
+//! `beep` queues a note and returns; `player`, a task, plays the queued notes one after the
+//! other, timing each with `timer::sleep`, so a beep takes no CPU while it sounds. There's nothing
+//! to probe: the speaker's port is on every PC, and one without a speaker stays quiet.
+
+use crate::sync::mpsc::Channel;
+use crate::{pit, println, timer};
+use core::time::Duration;
+
+/// Lowest and highest pitch played, in Hz: the PIT can't go lower, nobody hears higher
+pub const MIN_FREQUENCY: u32 = 20;
+pub const MAX_FREQUENCY: u32 = 20_000;
+/// Longest note
+pub const MAX_DURATION: Duration = Duration::from_secs(10);
+
+/// Notes queued for `player`
+static NOTES: Channel<Note, 16> = Channel::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Note {
+    frequency: u32,
+    duration: Duration,
+}
+
+/// Queues a tone of `frequency` Hz lasting `duration`, after the ones queued before it
+pub fn beep(frequency: u32, duration: Duration) -> Result<(), &'static str> {
+    if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency) {
+        return Err("frequency out of range");
+    }
+    if duration > MAX_DURATION {
+        return Err("duration too long");
+    }
+    NOTES
+        .send(Note {
+            frequency,
+            duration,
+        })
+        .map_err(|_| "too many notes queued")
+}
+
+/// Plays the notes `beep` queues, forever; meant to be spawned as a task
+pub async fn player() {
+    loop {
+        let note = NOTES.recv().await;
+        pit::start_tone(note.frequency);
+        timer::sleep(note.duration).await;
+        pit::stop_tone();
+    }
+}
+
+/// `beep [<hz> [<ms>]]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: beep [<hz> [<ms>]]";
+    let parse = |text: Option<&&str>, default| {
+        text.map_or(Ok(default), |text| text.parse().map_err(|_| USAGE))
+    };
+    if args.len() > 2 {
+        return Err(USAGE);
+    }
+    let frequency = parse(args.first(), 880)?;
+    let ms = parse(args.get(1), 200)?;
+    beep(frequency, Duration::from_millis(ms as u64))?;
+    println!("beep: {} Hz for {} ms", frequency, ms);
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! The 8042 PS/2 controller, which the keyboard and the mouse hang off.
+//!
+//! Commands for the controller itself go to port 0x64, which also reads as its status; bytes to
+//! and from the devices pass through port 0x60. The controller buffers one byte each way, so every
+//! write waits for the input buffer to drain and every read for the output buffer to fill. Bytes
+//! from the second ("auxiliary") port, the mouse's, are marked in the status register.
+
+use crate::acpi::{self, Fadt};
+use crate::arch::port::{inb, outb};
+use crate::drivers;
+use crate::{pit, warnln};
+
+pub mod keyboard;
+pub mod keymap;
+pub mod mouse;
+
+const DATA: u16 = 0x60;
+/// Status register when read, command register when written
+const CONTROL: u16 = 0x64;
+
+/// Status register bits
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+const AUX_DATA: u8 = 1 << 5;
+
+/// Controller commands
+mod command {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const ENABLE_AUX: u8 = 0xa8;
+    /// The next data byte goes to the auxiliary device
+    pub const WRITE_AUX: u8 = 0xd4;
+}
+
+/// Bits of the configuration byte
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+/// How long the controller and the devices get to respond, in milliseconds
+const TIMEOUT_MS: u32 = 100;
+
+/// Whether the board has an 8042; firmware that doesn't say is taken to have one
+pub fn present() -> bool {
+    acpi::fadt().is_none_or(|fadt| fadt.boot_architecture_flags & Fadt::BOOT_ARCH_8042 != 0)
+}
+
+pub static DRIVER: drivers::Driver = drivers::Driver::platform("ps2", &["serial"], init);
+
+/// Sets up the devices on the controller. The keyboard's interrupt comes on last, so its handler
+/// can't take the controller's answers to the commands setting up the mouse.
+fn init() -> Result<(), &'static str> {
+    if !present() {
+        return Err("no 8042 controller");
+    }
+    keymap::init();
+    if let Err(err) = mouse::init() {
+        warnln!("ps2: mouse: {}", err);
+    }
+    keyboard::init()
+}
+
+fn status() -> u8 {
+    unsafe { inb(CONTROL) }
+}
+
+/// Waits until `ready` holds for the status register
+fn wait(ready: impl Fn(u8) -> bool) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_MS {
+        if ready(status()) {
+            return Ok(());
+        }
+        pit::wait_ms(1);
+    }
+    Err("the PS/2 controller doesn't respond")
+}
+
+fn send_command(command: u8) -> Result<(), &'static str> {
+    wait(|status| status & INPUT_FULL == 0)?;
+    unsafe { outb(CONTROL, command) };
+    Ok(())
+}
+
+fn write_data(value: u8) -> Result<(), &'static str> {
+    wait(|status| status & INPUT_FULL == 0)?;
+    unsafe { outb(DATA, value) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    wait(|status| status & OUTPUT_FULL != 0)?;
+    Ok(unsafe { inb(DATA) })
+}
+
+/// Drops whatever the devices sent that nobody read
+fn flush() {
+    while status() & OUTPUT_FULL != 0 {
+        unsafe { inb(DATA) };
+    }
+}
+
+/// Changes the configuration byte to `update(config)`
+fn update_config(update: impl FnOnce(u8) -> u8) -> Result<(), &'static str> {
+    send_command(command::READ_CONFIG)?;
+    let config = read_data()?;
+    send_command(command::WRITE_CONFIG)?;
+    write_data(update(config))
+}
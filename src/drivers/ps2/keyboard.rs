@@ -0,0 +1,307 @@
+//! The PS/2 keyboard on the controller's first port, interrupting on IRQ 1.
+//!
+//! The controller translates whatever the keyboard speaks into scan code set 1: one byte per key
+//! press, the same with the top bit set on release, and 0xe0 in front of the keys the AT added.
+//! `Decoder` turns that into `KeyEvent`s and characters through the keymap in use (see
+//! `keymap`); `poll` reads the controller directly, for when interrupts are off.
+//!
+//! The interrupt handler types the characters on `console::INPUT`, unless a task has the keys to
+//! itself with a `ScancodeStream`, which gets every press and release instead.
+
+use super::keymap::{self, Layer};
+use super::{AUX_DATA, CONTROL, DATA, OUTPUT_FULL};
+use crate::arch::port::inb;
+use crate::task::stream::InterruptStream;
+use crate::{console, interrupts};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const IRQ: u8 = 1;
+
+const RELEASED: u8 = 0x80;
+const EXTENDED: u8 = 0xe0;
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CAPS_LOCK: u8 = 0x3a;
+/// Left Control, and right Control after the 0xe0 prefix
+const CTRL: u8 = 0x1d;
+/// Left Alt, and right Alt, i.e. AltGr, after the 0xe0 prefix
+const ALT: u8 = 0x38;
+/// Keypad Enter and keypad slash, after the 0xe0 prefix
+const KEYPAD_ENTER: u8 = 0x1c;
+const KEYPAD_SLASH: u8 = 0x35;
+
+/// Key events the consumer may fall behind by before new ones are dropped
+const QUEUE_SIZE: usize = 128;
+
+/// The characters one key press typed: none, one, or a dead key's accent and a character it
+/// didn't go on
+#[derive(Debug, Clone, Default)]
+pub struct Typed {
+    chars: [Option<char>; 2],
+}
+
+impl Typed {
+    fn none() -> Typed {
+        Typed::default()
+    }
+
+    fn one(ch: char) -> Typed {
+        Typed {
+            chars: [Some(ch), None],
+        }
+    }
+
+    fn two(first: char, second: char) -> Typed {
+        Typed {
+            chars: [Some(first), Some(second)],
+        }
+    }
+}
+
+impl Iterator for Typed {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars[0].take();
+        self.chars.swap(0, 1);
+        ch
+    }
+}
+
+/// The modifier keys held down, and whether caps lock is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// The left Alt key; the right one is `alt_gr`
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+}
+
+/// A key going down or coming up
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    /// The key's scan code without the release bit, with the 0xe0 prefix in the high byte for the
+    /// AT's extra keys, e.g. 0xe048 for the up arrow
+    pub key: u16,
+    pub pressed: bool,
+    /// The modifiers as they are after the event
+    pub modifiers: Modifiers,
+    /// What the key typed; always nothing on release
+    pub typed: Typed,
+}
+
+/// Turns scan codes into key events and characters, keeping track of the modifiers and dead keys
+#[derive(Debug, Default)]
+pub struct Decoder {
+    modifiers: Modifiers,
+    /// The previous byte was the 0xe0 prefix
+    extended: bool,
+    /// Accent of the dead key pressed last, waiting for the next key
+    dead: Option<char>,
+}
+
+impl Decoder {
+    pub const fn new() -> Decoder {
+        Decoder {
+            modifiers: Modifiers {
+                shift: false,
+                ctrl: false,
+                alt: false,
+                alt_gr: false,
+                caps_lock: false,
+            },
+            extended: false,
+            dead: None,
+        }
+    }
+
+    /// Takes the next scan code, returning the characters typed if it completes a key press
+    pub fn feed(&mut self, code: u8) -> Typed {
+        self.key(code).map_or_else(Typed::none, |event| event.typed)
+    }
+
+    /// Takes the next scan code, returning the event if it completes one
+    pub fn key(&mut self, code: u8) -> Option<KeyEvent> {
+        if code == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        let pressed = code & RELEASED == 0;
+        let key = code & !RELEASED;
+        let extended = core::mem::take(&mut self.extended);
+        let typed = if extended {
+            // of the AT's extra keys (arrows, right control and the like) only these type or
+            // change anything
+            match key {
+                CTRL => self.modifiers.ctrl = pressed,
+                ALT => self.modifiers.alt_gr = pressed,
+                _ => {}
+            }
+            match key {
+                KEYPAD_ENTER if pressed => self.typed('\n'),
+                KEYPAD_SLASH if pressed => self.typed('/'),
+                _ => Typed::none(),
+            }
+        } else {
+            match key {
+                LEFT_SHIFT | RIGHT_SHIFT => self.modifiers.shift = pressed,
+                CTRL => self.modifiers.ctrl = pressed,
+                ALT => self.modifiers.alt = pressed,
+                CAPS_LOCK if pressed => self.modifiers.caps_lock = !self.modifiers.caps_lock,
+                _ => {}
+            }
+            match self.lookup(key) {
+                Some(ch) if pressed => self.typed(ch),
+                _ => Typed::none(),
+            }
+        };
+        Some(KeyEvent {
+            key: if extended {
+                0xe000 | key as u16
+            } else {
+                key as u16
+            },
+            pressed,
+            modifiers: self.modifiers,
+            typed,
+        })
+    }
+
+    /// The character `key` types with the modifiers held
+    fn lookup(&self, key: u8) -> Option<char> {
+        let keymap = keymap::current();
+        if self.modifiers.alt_gr {
+            return keymap.get(key, Layer::AltGr);
+        }
+        let layer = match self.modifiers.shift {
+            true => Layer::Shifted,
+            false => Layer::Normal,
+        };
+        let ch = keymap.get(key, layer)?;
+        // caps lock swaps the layers of the letter keys only
+        if self.modifiers.caps_lock && ch.is_alphabetic() {
+            let other = match layer {
+                Layer::Shifted => Layer::Normal,
+                _ => Layer::Shifted,
+            };
+            return keymap.get(key, other).or(Some(ch));
+        }
+        Some(ch)
+    }
+
+    /// Puts `ch` through a pending dead key
+    fn typed(&mut self, ch: char) -> Typed {
+        match (self.dead.take(), ch) {
+            (None, ch) if keymap::is_dead(ch) => {
+                self.dead = Some(ch);
+                Typed::none()
+            }
+            (None, ch) => Typed::one(ch),
+            // a space or the dead key again types the accent itself
+            (Some(accent), ' ') => Typed::one(keymap::spacing(accent)),
+            (Some(accent), ch) if ch == accent => Typed::one(keymap::spacing(accent)),
+            (Some(accent), ch) => match keymap::compose(accent, ch) {
+                Some(composed) => Typed::one(composed),
+                None if keymap::is_dead(ch) => {
+                    self.dead = Some(ch);
+                    Typed::one(keymap::spacing(accent))
+                }
+                None => Typed::two(keymap::spacing(accent), ch),
+            },
+        }
+    }
+}
+
+/// The decoder behind `poll`, and the character a key press typed that `poll` hasn't returned yet
+static POLL_DECODER: Mutex<(Decoder, Option<char>)> = Mutex::new((Decoder::new(), None));
+
+/// The next character typed, read straight from the controller; for when interrupts are off
+pub fn poll() -> Option<char> {
+    let mut state = POLL_DECODER.try_lock()?;
+    let (decoder, queued) = &mut *state;
+    if let Some(ch) = queued.take() {
+        return Some(ch);
+    }
+    let status = unsafe { inb(CONTROL) };
+    if status & (OUTPUT_FULL | AUX_DATA) != OUTPUT_FULL {
+        return None;
+    }
+    let code = unsafe { inb(DATA) };
+    let mut typed = decoder.feed(code);
+    let ch = typed.next();
+    *queued = typed.next();
+    ch
+}
+
+/// Only locked with interrupts disabled, as the interrupt handler takes it
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+static EVENTS: InterruptStream<KeyEvent, QUEUE_SIZE> = InterruptStream::new();
+
+/// Whether a `ScancodeStream` is open, and the keys go to it rather than the console
+static STREAM_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Every key event, for the one task that has the keyboard to itself; typing goes nowhere else
+/// while it's open. Closes when dropped.
+pub struct ScancodeStream(());
+
+impl ScancodeStream {
+    pub fn open() -> Result<ScancodeStream, &'static str> {
+        if STREAM_OPEN
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err("another task is reading the keyboard");
+        }
+        while EVENTS.try_next().is_some() {} // left from the last stream
+        Ok(ScancodeStream(()))
+    }
+
+    /// Waits for the next key to go down or come up. Never `None`, as a PS/2 keyboard can't go
+    /// away, but it makes `while let Some(key) = keys.next().await` read like any other stream.
+    pub async fn next(&mut self) -> Option<KeyEvent> {
+        Some(EVENTS.next().await)
+    }
+
+    /// Number of key events dropped because the task didn't take them in time
+    pub fn dropped(&self) -> u64 {
+        EVENTS.dropped()
+    }
+}
+
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        STREAM_OPEN.store(false, Ordering::Release);
+    }
+}
+
+/// Starts taking key presses on the interrupt
+pub(super) fn init() -> Result<(), &'static str> {
+    super::update_config(|config| config | super::CONFIG_KEYBOARD_INTERRUPT)?;
+    interrupts::set_irq_handler(IRQ, interrupt)
+}
+
+fn interrupt() {
+    let status = unsafe { inb(CONTROL) };
+    if status & (OUTPUT_FULL | AUX_DATA) != OUTPUT_FULL {
+        return; // the mouse's, or already read
+    }
+    let code = unsafe { inb(DATA) };
+    let Some(event) = DECODER.lock().key(code) else {
+        return;
+    };
+    if STREAM_OPEN.load(Ordering::Acquire) {
+        EVENTS.push(event);
+        return;
+    }
+    for ch in event.typed {
+        let mut utf8 = [0; 4];
+        for &byte in ch.encode_utf8(&mut utf8).as_bytes() {
+            console::INPUT.push(byte);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Keyboard layouts: which character each key types.
+//!
+//! A `Keymap` gives the characters of the main block of keys, 0x00 to 0x39 in scan code set 1,
+//! plain and with shift, the few more AltGr (the right Alt key) adds, and those of the extra key
+//! next to left shift that ISO keyboards have. A character in the combining diacritical marks
+//! block makes its key a dead key: it types nothing itself but puts its accent on the next
+//! letter, see `compose`.
+//!
+//! The layout in use is picked with `keymap=<name>` on the command line or with the `keymap`
+//! command, and is `us` otherwise.
+
+use crate::{cmdline, println};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keys each layer has a character for
+pub const KEYS: usize = 0x3a;
+/// Scan code of the key between left shift and Z on ISO keyboards
+pub const ISO_KEY: u8 = 0x56;
+
+pub struct Keymap {
+    pub name: &'static str,
+    /// One character per key, `'\0'` for keys without one
+    normal: &'static str,
+    shifted: &'static str,
+    /// Key and character, for the keys AltGr gives another
+    altgr: &'static [(u8, char)],
+    /// `ISO_KEY`'s characters, plain, shifted and with AltGr
+    iso: [char; 3],
+}
+
+/// The layer a key is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Normal,
+    Shifted,
+    AltGr,
+}
+
+impl Keymap {
+    /// The character `key` types on `layer`, or the dead key's accent, if it has one
+    pub fn get(&self, key: u8, layer: Layer) -> Option<char> {
+        let ch = match (key, layer) {
+            (ISO_KEY, layer) => self.iso[layer as usize],
+            (key, _) if key as usize >= KEYS => return None,
+            (key, Layer::Normal) => self.normal.chars().nth(key as usize)?,
+            (key, Layer::Shifted) => self.shifted.chars().nth(key as usize)?,
+            (key, Layer::AltGr) => self
+                .altgr
+                .iter()
+                .find(|&&(altgr_key, _)| altgr_key == key)
+                .map_or('\0', |&(_, ch)| ch),
+        };
+        (ch != '\0').then_some(ch)
+    }
+}
+
+pub static US: Keymap = Keymap {
+    name: "us",
+    normal: "\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ",
+    shifted: "\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ",
+    altgr: &[],
+    iso: ['\\', '|', '\0'],
+};
+
+pub static UK: Keymap = Keymap {
+    name: "uk",
+    normal: "\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0#zxcvbnm,./\0*\0 ",
+    shifted: "\0\x1b!\"£$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:@¬\0~ZXCVBNM<>?\0*\0 ",
+    altgr: &[
+        (0x05, '€'),
+        (0x12, 'é'),
+        (0x16, 'ú'),
+        (0x17, 'í'),
+        (0x18, 'ó'),
+        (0x1e, 'á'),
+        (0x29, '¦'),
+    ],
+    iso: ['\\', '|', '\0'],
+};
+
+pub static DE: Keymap = Keymap {
+    name: "de",
+    normal: "\0\x1b1234567890ß\u{301}\x08\tqwertzuiopü+\n\0asdfghjklöä\u{302}\0#yxcvbnm,.-\0*\0 ",
+    shifted: "\0\x1b!\"§$%&/()=?\u{300}\x08\tQWERTZUIOPÜ*\n\0ASDFGHJKLÖÄ°\0'YXCVBNM;:_\0*\0 ",
+    altgr: &[
+        (0x03, '²'),
+        (0x04, '³'),
+        (0x08, '{'),
+        (0x09, '['),
+        (0x0a, ']'),
+        (0x0b, '}'),
+        (0x0c, '\\'),
+        (0x10, '@'),
+        (0x12, '€'),
+        (0x1b, '~'),
+        (0x32, 'µ'),
+    ],
+    iso: ['<', '>', '|'],
+};
+
+pub static DVORAK: Keymap = Keymap {
+    name: "dvorak",
+    normal: "\0\x1b1234567890[]\x08\t',.pyfgcrl/=\n\0aoeuidhtns-`\0\\;qjkxbmwvz\0*\0 ",
+    shifted: "\0\x1b!@#$%^&*(){}\x08\t\"<>PYFGCRL?+\n\0AOEUIDHTNS_~\0|:QJKXBMWVZ\0*\0 ",
+    altgr: &[],
+    iso: ['\\', '|', '\0'],
+};
+
+static KEYMAPS: &[&Keymap] = &[&US, &UK, &DE, &DVORAK];
+
+/// Index in `KEYMAPS` of the layout in use
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn current() -> &'static Keymap {
+    KEYMAPS[CURRENT.load(Ordering::Relaxed)]
+}
+
+/// Switches to the layout called `name`
+pub fn set(name: &str) -> Result<(), &'static str> {
+    let index = KEYMAPS
+        .iter()
+        .position(|keymap| keymap.name == name)
+        .ok_or("no such keymap")?;
+    CURRENT.store(index, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Picks the layout the command line asks for
+pub fn init() {
+    if let Some(name) = cmdline::get("keymap") {
+        if let Err(err) = set(name) {
+            println!(
+                "keymap: {} ({}), staying with {}",
+                err,
+                name,
+                current().name
+            );
+        }
+    }
+}
+
+/// Whether `ch` is a dead key's accent
+pub fn is_dead(ch: char) -> bool {
+    ('\u{300}'..='\u{36f}').contains(&ch)
+}
+
+/// The letters each accent goes on, and what they turn into
+static COMPOSE: &[(char, &str, &str)] = &[
+    ('\u{300}', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('\u{301}', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('\u{302}', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('\u{303}', "anoANO", "ãñõÃÑÕ"),
+    ('\u{308}', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+/// The accent on its own, as a space after a dead key types it
+pub fn spacing(accent: char) -> char {
+    match accent {
+        '\u{300}' => '`',
+        '\u{301}' => '´',
+        '\u{302}' => '^',
+        '\u{303}' => '~',
+        '\u{308}' => '¨',
+        other => other,
+    }
+}
+
+/// `base` with `accent` on it, if there's such a character
+pub fn compose(accent: char, base: char) -> Option<char> {
+    let (_, bases, composed) = COMPOSE.iter().find(|(dead, _, _)| *dead == accent)?;
+    let index = bases.chars().position(|ch| ch == base)?;
+    composed.chars().nth(index)
+}
+
+/// `keymap [<name>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for keymap in KEYMAPS {
+                let marker = match core::ptr::eq(*keymap, current()) {
+                    true => '*',
+                    false => ' ',
+                };
+                println!("{} {}", marker, keymap.name);
+            }
+        }
+        [name] => set(name)?,
+        _ => return Err("usage: keymap [<name>]"),
+    }
+    Ok(())
+}
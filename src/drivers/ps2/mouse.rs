@@ -0,0 +1,176 @@
+//! The PS/2 mouse on the controller's auxiliary port, interrupting on IRQ 12.
+//!
+//! Once data reporting is on, the mouse sends a 3-byte packet whenever it moves or a button
+//! changes: the buttons with the sign and overflow bits of the movement, then the X and Y
+//! movement's low bytes. A wheel mouse sends a fourth byte with the wheel's movement, once it's
+//! switched to its IntelliMouse mode by setting the sample rates 200, 100 and 80 in a row, after
+//! which it identifies as 3 instead of 0.
+//!
+//! The interrupt handler decodes packets into `MouseEvent`s on `EVENTS`; tasks wait for them with
+//! `next_event`.
+
+use super::{command, AUX_DATA, CONTROL, DATA, OUTPUT_FULL};
+use crate::arch::port::inb;
+use crate::interrupts;
+use crate::task::stream::InterruptStream;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const IRQ: u8 = 12;
+
+/// Mouse commands
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+const SET_DEFAULTS: u8 = 0xf6;
+const GET_ID: u8 = 0xf2;
+
+/// The mouse's answer to every command it accepts
+const ACK: u8 = 0xfa;
+/// What `GET_ID` answers once the wheel is on
+const ID_WHEEL: u8 = 3;
+
+/// Bits of a packet's first byte
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set, which is how a packet's first byte is recognized
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Events the consumer may fall behind by before new ones are dropped
+const QUEUE_SIZE: usize = 128;
+
+/// The buttons held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub fn left(self) -> bool {
+        self.0 & LEFT != 0
+    }
+
+    pub fn right(self) -> bool {
+        self.0 & RIGHT != 0
+    }
+
+    pub fn middle(self) -> bool {
+        self.0 & MIDDLE != 0
+    }
+}
+
+/// One packet: how far the mouse moved since the last one and which buttons are down now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right
+    pub dx: i16,
+    /// Movement downwards, like the screen's y axis (the mouse counts upwards)
+    pub dy: i16,
+    /// Wheel clicks towards the user, always 0 without a wheel
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+pub static EVENTS: InterruptStream<MouseEvent, QUEUE_SIZE> = InterruptStream::new();
+
+/// Collects a packet's bytes as they arrive, one per interrupt
+struct Decoder {
+    packet: [u8; 4],
+    received: usize,
+    /// 3, or 4 with the wheel on
+    packet_size: usize,
+}
+
+impl Decoder {
+    /// Takes the next byte, and returns the event once the packet is complete
+    fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return None; // out of step, wait for something that could start a packet
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size {
+            return None;
+        }
+        self.received = 0;
+
+        let [flags, x, y, z] = self.packet;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None; // the movement is meaningless
+        }
+        // 9-bit two's complement, the sign bit in the first byte
+        let extend = |low: u8, sign: u8| low as i16 - if flags & sign != 0 { 0x100 } else { 0 };
+        Some(MouseEvent {
+            dx: extend(x, X_SIGN),
+            dy: -extend(y, Y_SIGN),
+            wheel: if self.packet_size == 4 { z as i8 } else { 0 },
+            buttons: Buttons(flags & (LEFT | RIGHT | MIDDLE)),
+        })
+    }
+}
+
+/// Only locked with interrupts disabled, as the interrupt handler takes it
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    packet: [0; 4],
+    received: 0,
+    packet_size: 3,
+});
+
+static WHEEL: AtomicBool = AtomicBool::new(false);
+
+/// Whether the mouse has a wheel, i.e. `MouseEvent::wheel` can be anything but 0
+pub fn has_wheel() -> bool {
+    WHEEL.load(Ordering::Relaxed)
+}
+
+/// Waits for the mouse to move or a button to change
+pub async fn next_event() -> MouseEvent {
+    EVENTS.next().await
+}
+
+/// Sends `byte` to the mouse and waits for it to acknowledge
+fn send(byte: u8) -> Result<(), &'static str> {
+    super::send_command(command::WRITE_AUX)?;
+    super::write_data(byte)?;
+    match super::read_data() {
+        Ok(ACK) => Ok(()),
+        Ok(_) => Err("the mouse rejected a command"),
+        Err(_) => Err("no mouse"),
+    }
+}
+
+/// Turns the auxiliary port and the wheel on, and starts the mouse reporting
+pub(super) fn init() -> Result<(), &'static str> {
+    super::flush();
+    super::send_command(command::ENABLE_AUX)?;
+    super::update_config(|config| {
+        (config | super::CONFIG_AUX_INTERRUPT) & !super::CONFIG_AUX_CLOCK_DISABLED
+    })?;
+    send(SET_DEFAULTS)?;
+
+    for rate in [200, 100, 80] {
+        send(SET_SAMPLE_RATE)?;
+        send(rate)?;
+    }
+    send(GET_ID)?;
+    let wheel = super::read_data()? == ID_WHEEL;
+    WHEEL.store(wheel, Ordering::Relaxed);
+    without_interrupts(|| DECODER.lock().packet_size = if wheel { 4 } else { 3 });
+
+    send(ENABLE_REPORTING)?;
+    interrupts::set_irq_handler(IRQ, interrupt)
+}
+
+fn interrupt() {
+    let status = unsafe { inb(CONTROL) };
+    if status & (OUTPUT_FULL | AUX_DATA) != OUTPUT_FULL | AUX_DATA {
+        return; // the keyboard's, or already read
+    }
+    let byte = unsafe { inb(DATA) };
+    if let Some(event) = DECODER.lock().feed(byte) {
+        EVENTS.push(event);
+    }
+}
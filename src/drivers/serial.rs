@@ -0,0 +1,165 @@
+//! 16550 UARTs, the PC's serial ports.
+//!
+//! COM1 is set to 115200 baud, 8N1, and attached as a console `Terminal`, so everything printed
+//! also goes out the serial line; what comes in raises IRQ 4 and lands on `console::INPUT`. The
+//! other ports are left for whoever wants them, e.g. the debugger in `gdbstub`, on COM2. `SerialPort` works by polling
+//! alone, which is what the crash debugger needs with interrupts off and locks possibly held.
+//!
+//! Files can be sent to the machine over COM1 with XMODEM, see `xmodem`.
+
+use crate::arch::port::{inb, outb};
+use crate::console::{self, Sink, Terminal};
+use crate::drivers;
+use crate::interrupts;
+use crate::paravirt::{self, Feature};
+use alloc::boxed::Box;
+use spin::Once;
+
+pub mod xmodem;
+
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+const COM1_IRQ: u8 = 4;
+
+/// Register offsets from the port's base
+mod reg {
+    pub const DATA: u16 = 0;
+    pub const INTERRUPT_ENABLE: u16 = 1;
+    /// The divisor's low and high byte while the line control's DLAB bit is set
+    pub const DIVISOR_LOW: u16 = 0;
+    pub const DIVISOR_HIGH: u16 = 1;
+    pub const FIFO_CONTROL: u16 = 2;
+    pub const LINE_CONTROL: u16 = 3;
+    pub const MODEM_CONTROL: u16 = 4;
+    pub const LINE_STATUS: u16 = 5;
+    pub const SCRATCH: u16 = 7;
+}
+
+const LINE_8N1: u8 = 0x03;
+const LINE_DLAB: u8 = 0x80;
+/// Enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2, which gates the interrupt line on PCs
+const MODEM_READY: u8 = 0x0b;
+const INTERRUPT_RECEIVED: u8 = 0x01;
+
+/// Line status bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// 115200 baud / 115200
+const DIVISOR: u16 = 1;
+
+/// Bytes the transmit FIFO holds
+const FIFO_SIZE: usize = 16;
+
+/// Polls for a free transmitter at most this often before giving up on a byte
+const TRANSMIT_SPINS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Sets the UART at `base` up, `None` if there's none: the scratch register doesn't keep
+    /// what's written to it
+    pub fn init(base: u16) -> Option<SerialPort> {
+        let port = SerialPort { base };
+        unsafe {
+            port.write(reg::SCRATCH, 0x5a);
+            if port.read(reg::SCRATCH) != 0x5a {
+                return None;
+            }
+            port.write(reg::INTERRUPT_ENABLE, 0);
+            port.write(reg::LINE_CONTROL, LINE_DLAB);
+            port.write(reg::DIVISOR_LOW, DIVISOR as u8);
+            port.write(reg::DIVISOR_HIGH, (DIVISOR >> 8) as u8);
+            port.write(reg::LINE_CONTROL, LINE_8N1);
+            port.write(reg::FIFO_CONTROL, FIFO_ENABLE);
+            port.write(reg::MODEM_CONTROL, MODEM_READY);
+        }
+        Some(port)
+    }
+
+    /// The UART at `base` as it is, set up or not: bytes sent to one that isn't there go nowhere
+    pub const fn assume(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    unsafe fn read(&self, register: u16) -> u8 {
+        inb(self.base + register)
+    }
+
+    unsafe fn write(&self, register: u16, value: u8) {
+        outb(self.base + register, value)
+    }
+
+    /// Sends `byte`, dropping it if the line is stuck
+    pub fn send_byte(&self, byte: u8) {
+        for _ in 0..TRANSMIT_SPINS {
+            if unsafe { self.read(reg::LINE_STATUS) } & TRANSMIT_EMPTY != 0 {
+                unsafe { self.write(reg::DATA, byte) };
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// The next received byte, if one is waiting
+    pub fn try_receive(&self) -> Option<u8> {
+        unsafe { (self.read(reg::LINE_STATUS) & DATA_READY != 0).then(|| self.read(reg::DATA)) }
+    }
+
+    /// Raises the port's interrupt line whenever a byte comes in
+    pub fn enable_receive_interrupt(&self) {
+        unsafe { self.write(reg::INTERRUPT_ENABLE, INTERRUPT_RECEIVED) };
+    }
+}
+
+impl Sink for SerialPort {
+    fn send(&mut self, data: &[u8]) {
+        if !paravirt::enabled(Feature::SerialBurst) {
+            for &byte in data {
+                self.send_byte(byte);
+            }
+            return;
+        }
+        // an empty transmitter takes a whole FIFO's worth
+        for chunk in data.chunks(FIFO_SIZE) {
+            let (&first, rest) = chunk.split_first().unwrap();
+            self.send_byte(first);
+            for &byte in rest {
+                unsafe { self.write(reg::DATA, byte) };
+            }
+        }
+    }
+}
+
+static CONSOLE_PORT: Once<SerialPort> = Once::new();
+
+/// COM1, if it exists
+pub fn console_port() -> Option<SerialPort> {
+    CONSOLE_PORT.r#try().copied()
+}
+
+pub static DRIVER: drivers::Driver = drivers::Driver::platform("serial", &[], init);
+
+/// Makes COM1 a console, if there is one
+fn init() -> Result<(), &'static str> {
+    let port = SerialPort::init(COM1).ok_or("no UART at COM1")?;
+    CONSOLE_PORT.call_once(|| port);
+    console::attach(Box::new(Terminal(port)));
+    if interrupts::set_irq_handler(COM1_IRQ, interrupt).is_ok() {
+        port.enable_receive_interrupt();
+    }
+    Ok(())
+}
+
+fn interrupt() {
+    if let Some(port) = console_port() {
+        while let Some(byte) = port.try_receive() {
+            console::INPUT.push(byte);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Receiving files over the serial console with XMODEM, for machines with nothing but a cable.
+//!
+//! `rx <path>` hands the path to `receiver`, which then asks the sender on COM1 to start: 'C' for
+//! XMODEM-CRC, falling back to NAK for the original checksum after a few tries. Blocks of 128
+//! bytes (SOH) and 1024 bytes (STX, XMODEM-1K) are both taken. A block that arrives damaged or
+//! not at all is asked for again, up to `MAX_ERRORS` times in a row; a repeated block, sent
+//! because our ACK got lost, is acknowledged and dropped. After EOT the file is written, minus the
+//! SUB bytes that pad the last block out to its size, which means a file that really ends in SUB
+//! loses those bytes too; XMODEM doesn't say how long files are.
+//!
+//! Bytes come from the active terminal, in raw mode without echo for the transfer (see `tty`),
+//! so nothing else may type on a console during a transfer, and anything the kernel prints
+//! meanwhile goes down the same line and may confuse the sender.
+
+use super::{console_port, SerialPort};
+use crate::sync::mpsc::Channel;
+use crate::timer::Timeout;
+use crate::tty::{self, Mode, Settings};
+use crate::{fs, println};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+/// Asks for CRCs instead of checksums
+const CRC_REQUEST: u8 = b'C';
+
+/// How long to wait for the sender to start before asking again, and how often to ask for each
+/// of the two modes
+const START_INTERVAL: Duration = Duration::from_secs(3);
+const CRC_ATTEMPTS: u32 = 4;
+const CHECKSUM_ATTEMPTS: u32 = 6;
+/// Longest pause inside a block
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bad blocks in a row before giving up
+const MAX_ERRORS: u32 = 10;
+
+/// Paths `rx` asked for, waiting for `receiver`
+static REQUESTS: Channel<String, 1> = Channel::new();
+
+/// CRC-16/XMODEM: polynomial 0x1021, no reflection, starting at zero
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// The next byte from the line, `None` if none comes within `timeout`
+async fn read(timeout: Duration) -> Option<u8> {
+    Timeout::wrap(tty::active().read_byte(), timeout)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Throws away what the sender still has in flight, until the line has been quiet a while
+async fn purge() {
+    while read(BYTE_TIMEOUT).await.is_some() {}
+}
+
+/// One transfer in progress
+struct Transfer {
+    port: SerialPort,
+    crc: bool,
+    /// The number of the block expected next, modulo 256
+    expected: u8,
+    data: Vec<u8>,
+    /// Length of `data` before the last block, to strip its padding from
+    last_block: usize,
+}
+
+/// What `Transfer::block` made of a block
+enum Block {
+    New,
+    Repeated,
+    Bad,
+}
+
+impl Transfer {
+    fn cancel(&self) {
+        for _ in 0..3 {
+            self.port.send_byte(CAN);
+        }
+    }
+
+    /// Asks the sender to start, first in CRC mode; returns the first byte of its first block
+    async fn start(&mut self) -> Result<u8, &'static str> {
+        for attempt in 0..CRC_ATTEMPTS + CHECKSUM_ATTEMPTS {
+            self.crc = attempt < CRC_ATTEMPTS;
+            self.port
+                .send_byte(if self.crc { CRC_REQUEST } else { NAK });
+            if let Some(byte) = read(START_INTERVAL).await {
+                return Ok(byte);
+            }
+        }
+        Err("the sender never started")
+    }
+
+    /// Reads the rest of a block that started with `header`
+    async fn block(&mut self, header: u8) -> Block {
+        let size = match header {
+            STX => 1024,
+            _ => 128,
+        };
+        let trailer = if self.crc { 2 } else { 1 };
+        let len = 2 + size + trailer;
+        let mut block = Vec::with_capacity(len);
+        while block.len() < len {
+            match read(BYTE_TIMEOUT).await {
+                Some(byte) => block.push(byte),
+                None => return Block::Bad,
+            }
+        }
+        let (number, complement) = (block[0], block[1]);
+        let data = &block[2..2 + size];
+        let intact = match self.crc {
+            true => crc16(data).to_be_bytes() == block[2 + size..],
+            false => data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == block[2 + size],
+        };
+        if number != !complement || !intact {
+            return Block::Bad;
+        }
+        if number == self.expected.wrapping_sub(1) {
+            return Block::Repeated;
+        }
+        if number != self.expected {
+            return Block::Bad;
+        }
+        self.last_block = self.data.len();
+        self.data.extend_from_slice(data);
+        self.expected = self.expected.wrapping_add(1);
+        Block::New
+    }
+
+    /// Receives blocks until EOT
+    async fn run(&mut self) -> Result<(), &'static str> {
+        let mut next = Some(self.start().await?);
+        let mut errors = 0;
+        loop {
+            let header = match next.take() {
+                Some(byte) => Some(byte),
+                None => read(START_INTERVAL).await,
+            };
+            let outcome = match header {
+                Some(header @ (SOH | STX)) => self.block(header).await,
+                Some(EOT) => {
+                    self.port.send_byte(ACK);
+                    return Ok(());
+                }
+                Some(CAN) => {
+                    if read(BYTE_TIMEOUT).await == Some(CAN) {
+                        return Err("the sender cancelled");
+                    }
+                    Block::Bad
+                }
+                _ => Block::Bad,
+            };
+            match outcome {
+                Block::New | Block::Repeated => {
+                    errors = 0;
+                    self.port.send_byte(ACK);
+                }
+                Block::Bad => {
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        self.cancel();
+                        return Err("too many bad blocks");
+                    }
+                    purge().await;
+                    self.port.send_byte(NAK);
+                }
+            }
+        }
+    }
+}
+
+/// Receives a file from the sender on COM1 and writes it to `path`; returns its size
+pub async fn receive(path: &str) -> Result<usize, &'static str> {
+    let port = console_port().ok_or("no serial port")?;
+    let tty = tty::active();
+    let settings = tty.settings();
+    tty.set_settings(Settings {
+        mode: Mode::Raw,
+        echo: false,
+    });
+    tty.flush_input(); // typed before the transfer
+    let mut transfer = Transfer {
+        port,
+        crc: true,
+        expected: 1,
+        data: Vec::new(),
+        last_block: 0,
+    };
+    let result = transfer.run().await;
+    tty.set_settings(settings);
+    result?;
+    let padding = transfer.data[transfer.last_block..]
+        .iter()
+        .rev()
+        .take_while(|&&byte| byte == SUB)
+        .count();
+    let len = transfer.data.len() - padding;
+    fs::write(path, &transfer.data[..len]).map_err(|_| "failed to write the file")?;
+    Ok(len)
+}
+
+/// Runs the transfers `rx` asks for, one at a time, forever; meant to be spawned as a task
+pub async fn receiver() {
+    loop {
+        let path = REQUESTS.recv().await;
+        match receive(&path).await {
+            Ok(len) => println!("rx: received {} bytes into {}", len, path),
+            Err(err) => println!("rx: {}: {}", path, err),
+        }
+    }
+}
+
+/// `rx <path>`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let [path] = args else {
+        return Err("usage: rx <path>");
+    };
+    console_port().ok_or("no serial port")?;
+    REQUESTS
+        .send(String::from(*path))
+        .map_err(|_| "a transfer is already waiting")?;
+    println!("rx: start the XMODEM upload on COM1");
+    Ok(())
+}
@@ -0,0 +1,314 @@
+//! Virtio devices on the PCI bus.
+//!
+//! Only the virtio 1.x ("modern") transport is implemented. Unlike the legacy interface it lets
+//! the driver pick the queue size and place each part of a queue in its own page, which suits a
+//! frame allocator that can't hand out physically contiguous runs. QEMU's default devices are
+//! transitional and offer both interfaces, so `-device virtio-blk-pci` works unchanged.
+
+use crate::memory::{self, FRAME_ALLOCATOR};
+use crate::pci::PciDevice;
+use core::mem::size_of;
+use queue::Virtqueue;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod balloon;
+pub mod blk;
+pub mod console;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "p9")]
+pub mod p9;
+pub mod queue;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Feature bit every modern device offers and every modern driver must accept
+pub const F_VERSION_1: u64 = 1 << 32;
+
+mod status {
+    pub const ACKNOWLEDGE: u8 = 1;
+    pub const DRIVER: u8 = 2;
+    pub const DRIVER_OK: u8 = 4;
+    pub const FEATURES_OK: u8 = 8;
+    pub const FAILED: u8 = 0x80;
+}
+
+/// Vendor-specific PCI capabilities describing where the transport's structures live
+mod capability {
+    pub const ID: u8 = 0x09;
+
+    pub const COMMON_CFG: u8 = 1;
+    pub const NOTIFY_CFG: u8 = 2;
+    pub const DEVICE_CFG: u8 = 4;
+}
+
+/// Offsets into the common configuration structure
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0C;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_ENABLE: usize = 0x1C;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1E;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+}
+
+/// A mapped register window, accessed with volatile loads and stores
+#[derive(Debug, Clone, Copy)]
+struct Mmio {
+    base: VirtAddr,
+    len: usize,
+}
+
+impl Mmio {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.len);
+        unsafe { core::ptr::read_volatile((self.base.as_u64() as usize + offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.len);
+        unsafe {
+            core::ptr::write_volatile((self.base.as_u64() as usize + offset) as *mut T, value)
+        }
+    }
+
+    /// 64-bit registers are written as two halves, which every device has to accept
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A zeroed page of physical memory for queues and buffers the device reads and writes directly.
+///
+/// Like RAM disk pages these are never given back.
+pub struct DmaPage {
+    frame: PhysFrame,
+}
+
+impl DmaPage {
+    pub const SIZE: usize = 4096;
+
+    pub fn new() -> Result<DmaPage, &'static str> {
+        let frame: PhysFrame<Size4KiB> = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or("out of physical memory")?;
+        let page = DmaPage { frame };
+        unsafe { page.as_mut_ptr().write_bytes(0, Self::SIZE) };
+        Ok(page)
+    }
+
+    /// The address to hand to the device
+    pub fn phys(&self) -> PhysAddr {
+        self.frame.start_address()
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        memory::phys_to_virt(self.phys()).as_mut_ptr()
+    }
+}
+
+/// The modern virtio PCI transport of one device
+pub struct Transport {
+    common: Mmio,
+    notify: Mmio,
+    notify_off_multiplier: u32,
+    device_config: Option<Mmio>,
+}
+
+impl Transport {
+    /// Finds and maps the transport structures advertised in `device`'s capability list
+    pub fn new(device: &PciDevice) -> Result<Transport, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut device_config = None;
+
+        let address = device.address;
+        for entry in device.capabilities() {
+            let offset = entry.offset;
+            if entry.id == capability::ID {
+                let cfg_type = address.read_config_u8(offset + 3);
+                // the spec says to use the first capability of each type the driver understands
+                let slot = match cfg_type {
+                    capability::COMMON_CFG => Some(&mut common),
+                    capability::NOTIFY_CFG => Some(&mut notify),
+                    capability::DEVICE_CFG => Some(&mut device_config),
+                    _ => None,
+                };
+                if let Some(slot @ None) = slot {
+                    let region = map_region(device, offset)?;
+                    let multiplier = match cfg_type {
+                        capability::NOTIFY_CFG => address.read_config(offset + 16),
+                        _ => 0,
+                    };
+                    *slot = Some((region, multiplier));
+                }
+            }
+        }
+
+        let (common, _) = common.ok_or("no virtio 1.x common configuration")?;
+        let (notify, notify_off_multiplier) = notify.ok_or("no virtio 1.x notify configuration")?;
+        Ok(Transport {
+            common,
+            notify,
+            notify_off_multiplier,
+            device_config: device_config.map(|(region, _)| region),
+        })
+    }
+
+    fn status(&self) -> u8 {
+        self.common.read(common::DEVICE_STATUS)
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.common
+            .write(common::DEVICE_STATUS, self.status() | bits);
+    }
+
+    /// Resets the device, which stops it using its queues and forgets them and the features
+    pub fn reset(&self) {
+        self.common.write(common::DEVICE_STATUS, 0u8);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the device and agrees on the features in `supported` it offers.
+    ///
+    /// `F_VERSION_1` is always requested; the negotiated set is returned. The device must be
+    /// finished with `driver_ok` after its queues have been set up.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, &'static str> {
+        self.reset();
+        self.add_status(status::ACKNOWLEDGE | status::DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2u32 {
+            self.common.write(common::DEVICE_FEATURE_SELECT, half);
+            offered |= (self.common.read::<u32>(common::DEVICE_FEATURE) as u64) << (32 * half);
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device doesn't support virtio 1.x");
+        }
+
+        let features = offered & (supported | F_VERSION_1);
+        for half in 0..2u32 {
+            self.common.write(common::DRIVER_FEATURE_SELECT, half);
+            self.common
+                .write(common::DRIVER_FEATURE, (features >> (32 * half)) as u32);
+        }
+        self.add_status(status::FEATURES_OK);
+        if self.status() & status::FEATURES_OK == 0 {
+            self.fail();
+            return Err("device rejected the negotiated features");
+        }
+        Ok(features)
+    }
+
+    /// Allocates queue `index` with up to `max_size` entries and hands it to the device
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, &'static str> {
+        let (size, notify_address) = self.select_queue(index, max_size)?;
+        let queue = Virtqueue::new(index, size, notify_address)?;
+        self.enable_queue(&queue);
+        Ok(queue)
+    }
+
+    /// Empties `queue` and hands it to the device again, after the device was reset (e.g. by
+    /// sleep) and features were negotiated anew
+    pub fn restore_queue(&self, queue: &mut Virtqueue) -> Result<(), &'static str> {
+        let (size, _) = self.select_queue(queue.index(), queue.size())?;
+        if size != queue.size() {
+            return Err("the device changed its queue size");
+        }
+        queue.reset();
+        self.enable_queue(queue);
+        Ok(())
+    }
+
+    /// Selects queue `index` and returns how many entries (up to `max_size`) to give it and where
+    /// to notify the device of new buffers in it
+    fn select_queue(&self, index: u16, max_size: u16) -> Result<(u16, VirtAddr), &'static str> {
+        self.common.write(common::QUEUE_SELECT, index);
+        let device_max: u16 = self.common.read(common::QUEUE_SIZE);
+        if device_max == 0 {
+            return Err("queue not available");
+        }
+        // both are powers of two, as split queues require
+        let size = device_max.min(max_size);
+        let notify_off: u16 = self.common.read(common::QUEUE_NOTIFY_OFF);
+        let notify_offset = notify_off as usize * self.notify_off_multiplier as usize;
+        if notify_offset + size_of::<u16>() > self.notify.len {
+            return Err("queue notify address outside the notify region");
+        }
+        Ok((size, self.notify.base + notify_offset as u64))
+    }
+
+    /// Tells the device where the selected queue lives and enables it
+    fn enable_queue(&self, queue: &Virtqueue) {
+        self.common.write(common::QUEUE_SIZE, queue.size());
+        self.common
+            .write_u64(common::QUEUE_DESC, queue.descriptor_area().as_u64());
+        self.common
+            .write_u64(common::QUEUE_DRIVER, queue.driver_area().as_u64());
+        self.common
+            .write_u64(common::QUEUE_DEVICE, queue.device_area().as_u64());
+        self.common.write(common::QUEUE_ENABLE, 1u16);
+    }
+
+    /// Tells the device setup is complete; it may start using its queues
+    pub fn driver_ok(&self) {
+        self.add_status(status::DRIVER_OK);
+    }
+
+    /// Tells the device the driver has given up on it
+    pub fn fail(&self) {
+        self.add_status(status::FAILED);
+    }
+
+    /// Reads a field of the device-specific configuration, `None` if the device has none there
+    pub fn read_device_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        let config = self.device_config?;
+        (offset + size_of::<T>() <= config.len).then(|| config.read(offset))
+    }
+
+    /// Writes a field of the device-specific configuration; `false` if the device has none there
+    pub fn write_device_config<T: Copy>(&self, offset: usize, value: T) -> bool {
+        let Some(config) = self.device_config else {
+            return false;
+        };
+        let fits = offset + size_of::<T>() <= config.len;
+        if fits {
+            config.write(offset, value);
+        }
+        fits
+    }
+}
+
+/// Maps the BAR window described by the virtio capability at `offset`
+fn map_region(device: &PciDevice, offset: u8) -> Result<Mmio, &'static str> {
+    let address = device.address;
+    let bar = address.read_config_u8(offset + 4) as usize;
+    let region_offset = address.read_config(offset + 8) as u64;
+    let len = address.read_config(offset + 12) as u64;
+    let base = match device.bars.get(bar).copied().flatten() {
+        Some(crate::pci::Bar::Memory { address, size, .. }) if region_offset + len <= size => {
+            address + region_offset
+        }
+        _ => return Err("virtio capability points outside a memory BAR"),
+    };
+    let base =
+        memory::map_mmio(PhysAddr::new(base), len).map_err(|_| "failed to map virtio registers")?;
+    Ok(Mmio {
+        base,
+        len: len as usize,
+    })
+}
@@ -0,0 +1,384 @@
+//! virtio-balloon: lets the host take back memory the guest doesn't need, e.g. QEMU's
+//! `-device virtio-balloon` driven by the monitor's `balloon <MiB>`.
+//!
+//! The host sets how many pages it wants in the balloon in the device configuration. `worker`
+//! looks every `POLL_INTERVAL`: to inflate it takes frames from the frame allocator and tells the
+//! host their numbers, after which the host may unmap them; to deflate it tells the host which
+//! frames it takes back and returns them to the allocator. If the device offers a statistics
+//! queue, the host gets the kernel's memory usage through it whenever it asks.
+//!
+//! Under memory pressure the balloon stops growing, whatever the host wants. At `Critical`, if
+//! the host allowed it with `F_DEFLATE_ON_OOM`, it also shrinks by a batch every poll, below the
+//! target if need be, so that the guest can go on.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{DmaPage, Transport, VENDOR_ID};
+use crate::drivers;
+use crate::memory::pressure::{self, Level};
+use crate::memory::{FrameStats, FRAME_ALLOCATOR};
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_INTERRUPT_DISABLE};
+use crate::{power, println, timer};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+const F_STATS_VQ: u64 = 1 << 1;
+const F_DEFLATE_ON_OOM: u64 = 1 << 2;
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+const STATS_QUEUE: u16 = 2;
+const QUEUE_SIZE: u16 = 16;
+
+/// Offsets in the device configuration: the pages the host wants, and the pages the driver has
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+
+/// The device counts in 4 KiB pages, whatever the guest's page size
+const PAGE_SHIFT: u64 = 12;
+/// Page numbers sent at once, as Linux does
+const BATCH: usize = 256;
+/// Batches moved per look at the configuration, so a large request doesn't hog the executor
+const BATCHES_PER_POLL: usize = 16;
+
+/// How often `worker` looks at the target and the statistics queue
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Statistics tags
+mod tag {
+    pub const MEMFREE: u16 = 4;
+    pub const MEMTOT: u16 = 5;
+    pub const AVAIL: u16 = 6;
+}
+/// A statistic on the wire: a `u16` tag and a `u64` value, packed
+const STAT_SIZE: usize = 10;
+
+struct Balloon {
+    inflate: Virtqueue,
+    deflate: Virtqueue,
+    /// The statistics queue and the page its buffer is in, if negotiated
+    stats: Option<(Virtqueue, DmaPage)>,
+    /// Where page numbers are staged
+    pfns: DmaPage,
+    /// Frames in the balloon
+    frames: Vec<PhysFrame>,
+}
+
+impl Balloon {
+    /// Sends the numbers of `frames` down `queue` and waits until the device took them
+    fn send(queue: &mut Virtqueue, pfns: &DmaPage, frames: &[PhysFrame]) {
+        let staging = pfns.as_mut_ptr().cast::<u32>();
+        for (index, frame) in frames.iter().enumerate() {
+            let pfn = frame.start_address().as_u64() >> PAGE_SHIFT;
+            unsafe { staging.add(index).write_volatile(pfn as u32) };
+        }
+        let buffer = Buffer {
+            address: pfns.phys(),
+            len: (frames.len() * 4) as u32,
+            device_writable: false,
+        };
+        let Some(head) = queue.submit(&[buffer]) else {
+            return;
+        };
+        queue.notify();
+        loop {
+            match queue.pop_used() {
+                Some((id, _)) if id == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Puts up to `BATCH` more frames in the balloon, fewer if memory runs out; returns how many
+    fn inflate(&mut self, count: usize) -> usize {
+        let mut batch = Vec::with_capacity(count.min(BATCH));
+        {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let Some(allocator) = allocator.as_mut() else {
+                return 0;
+            };
+            while batch.len() < count.min(BATCH) {
+                let Some(frame) = allocator.allocate_frame() else {
+                    break;
+                };
+                // the host numbers pages with 32 bits
+                if frame.start_address().as_u64() >> PAGE_SHIFT > u32::MAX as u64 {
+                    unsafe { allocator.deallocate_frame(frame) };
+                    break;
+                }
+                batch.push(frame);
+            }
+        }
+        if batch.is_empty() {
+            return 0;
+        }
+        Balloon::send(&mut self.inflate, &self.pfns, &batch);
+        self.frames.extend_from_slice(&batch);
+        batch.len()
+    }
+
+    /// Takes up to `BATCH` frames out of the balloon and gives them back; returns how many
+    fn deflate(&mut self, count: usize) -> usize {
+        let start = self.frames.len() - count.min(BATCH).min(self.frames.len());
+        let batch: Vec<PhysFrame> = self.frames.drain(start..).collect();
+        Balloon::send(&mut self.deflate, &self.pfns, &batch);
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            for &frame in &batch {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        }
+        batch.len()
+    }
+
+    /// Fills the statistics buffer and hands it to the device, which keeps it until it wants
+    /// the next report
+    fn post_stats(&mut self) {
+        let Some((queue, page)) = &mut self.stats else {
+            return;
+        };
+        let FrameStats { total, allocated } = match FRAME_ALLOCATOR.lock().as_ref() {
+            Some(allocator) => allocator.stats(),
+            None => return,
+        };
+        let bytes = |frames: usize| (frames as u64) << PAGE_SHIFT;
+        let stats = [
+            (tag::MEMFREE, bytes(total - allocated)),
+            (tag::MEMTOT, bytes(total)),
+            (tag::AVAIL, bytes(total - allocated)),
+        ];
+        let base = page.as_mut_ptr();
+        for (index, (tag, value)) in stats.into_iter().enumerate() {
+            unsafe {
+                let stat = base.add(index * STAT_SIZE);
+                stat.cast::<u16>().write_unaligned(tag);
+                stat.add(2).cast::<u64>().write_unaligned(value);
+            }
+        }
+        let buffer = Buffer {
+            address: page.phys(),
+            len: (stats.len() * STAT_SIZE) as u32,
+            device_writable: false,
+        };
+        if queue.submit(&[buffer]).is_some() {
+            queue.notify();
+        }
+    }
+}
+
+pub struct VirtioBalloon {
+    transport: Transport,
+    /// What `new` negotiated, asked for again after sleep
+    features: u64,
+    balloon: Mutex<Balloon>,
+}
+
+impl VirtioBalloon {
+    /// Negotiates with the device and sets up its queues, the balloon empty
+    pub fn new(device: &PciDevice) -> Result<VirtioBalloon, &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_STATS_VQ | F_DEFLATE_ON_OOM)?;
+        let setup = || -> Result<Balloon, &'static str> {
+            let stats = match features & F_STATS_VQ {
+                0 => None,
+                _ => Some((
+                    transport.setup_queue(STATS_QUEUE, QUEUE_SIZE)?,
+                    DmaPage::new()?,
+                )),
+            };
+            Ok(Balloon {
+                inflate: transport.setup_queue(INFLATE_QUEUE, QUEUE_SIZE)?,
+                deflate: transport.setup_queue(DEFLATE_QUEUE, QUEUE_SIZE)?,
+                stats,
+                pfns: DmaPage::new()?,
+                frames: Vec::new(),
+            })
+        };
+        let mut balloon = setup().inspect_err(|_| transport.fail())?;
+        transport.driver_ok();
+        balloon.post_stats();
+        let device = VirtioBalloon {
+            transport,
+            features,
+            balloon: Mutex::new(balloon),
+        };
+        device.set_actual(0);
+        Ok(device)
+    }
+
+    /// Pages the host wants in the balloon
+    pub fn target(&self) -> usize {
+        self.transport
+            .read_device_config::<u32>(CONFIG_NUM_PAGES)
+            .unwrap_or(0) as usize
+    }
+
+    /// Pages in the balloon
+    pub fn size(&self) -> usize {
+        self.balloon.lock().frames.len()
+    }
+
+    fn set_actual(&self, pages: usize) {
+        self.transport
+            .write_device_config(CONFIG_ACTUAL, pages as u32);
+    }
+
+    /// What the balloon's size should be at the current memory pressure, given its size now
+    fn wanted(&self, size: usize) -> usize {
+        let target = self.target();
+        match pressure::level() {
+            Level::None => target,
+            Level::Medium => target.min(size),
+            Level::Critical if self.features & F_DEFLATE_ON_OOM != 0 => {
+                target.min(size.saturating_sub(BATCH))
+            }
+            Level::Critical => target.min(size),
+        }
+    }
+
+    /// Moves the balloon's size towards what `wanted` says by a few batches and answers a request
+    /// for statistics
+    fn poll(&self) {
+        let mut balloon = self.balloon.lock();
+        if let Some((queue, _)) = &mut balloon.stats {
+            let mut asked = false;
+            while queue.pop_used().is_some() {
+                asked = true;
+            }
+            if asked {
+                balloon.post_stats();
+            }
+        }
+
+        let target = self.wanted(balloon.frames.len());
+        for _ in 0..BATCHES_PER_POLL {
+            let size = balloon.frames.len();
+            let moved = match target.cmp(&size) {
+                core::cmp::Ordering::Greater => balloon.inflate(target - size),
+                core::cmp::Ordering::Less => balloon.deflate(size - target),
+                core::cmp::Ordering::Equal => 0,
+            };
+            if moved == 0 {
+                break;
+            }
+        }
+        self.set_actual(balloon.frames.len());
+    }
+}
+
+impl power::Device for VirtioBalloon {
+    fn name(&self) -> &'static str {
+        "virtio-balloon"
+    }
+
+    /// The device comes out of sleep reset, having forgotten the balloon; the frames in it are
+    /// reported again
+    fn resume(&self) -> Result<(), &'static str> {
+        let mut balloon = self.balloon.lock();
+        let features = self.transport.negotiate(self.features)?;
+        if features != self.features {
+            self.transport.fail();
+            return Err("the device no longer offers the features it had");
+        }
+        let Balloon {
+            inflate,
+            deflate,
+            stats,
+            pfns,
+            frames,
+        } = &mut *balloon;
+        self.transport
+            .restore_queue(inflate)
+            .and_then(|()| self.transport.restore_queue(deflate))
+            .and_then(|()| match stats {
+                Some((queue, _)) => self.transport.restore_queue(queue),
+                None => Ok(()),
+            })
+            .inspect_err(|_| self.transport.fail())?;
+        self.transport.driver_ok();
+        for batch in frames.chunks(BATCH) {
+            Balloon::send(inflate, pfns, batch);
+        }
+        balloon.post_stats();
+        self.set_actual(balloon.frames.len());
+        Ok(())
+    }
+}
+
+/// The first balloon found; a second one would only fight over the same memory
+static BALLOON: Once<Arc<VirtioBalloon>> = Once::new();
+
+/// Keeps the balloon at the size the host asks for, looking every `POLL_INTERVAL`; meant to be
+/// spawned as a task. Returns at once if there's no device.
+pub async fn worker() {
+    let Some(device) = BALLOON.r#try() else {
+        return;
+    };
+    loop {
+        device.poll();
+        timer::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// `balloon`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    let device = BALLOON.r#try().ok_or("no virtio-balloon device")?;
+    let mib = |pages: usize| pages >> (20 - PAGE_SHIFT);
+    println!(
+        "balloon: {} MiB, the host wants {} MiB",
+        mib(device.size()),
+        mib(device.target())
+    );
+    if let Some(stats) = FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(|allocator| allocator.stats())
+    {
+        println!(
+            "memory: {} of {} MiB in use",
+            mib(stats.allocated),
+            mib(stats.total)
+        );
+    }
+    Ok(())
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    if BALLOON.r#try().is_some() {
+        return Err("a balloon is already in use");
+    }
+    device.enable();
+    // the configuration is polled, keep the legacy interrupt line quiet
+    device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+
+    let balloon = Arc::new(VirtioBalloon::new(device)?);
+    let stats = match balloon.features & F_STATS_VQ {
+        0 => "",
+        _ => ", reporting statistics",
+    };
+    BALLOON.call_once(|| balloon.clone());
+    power::register(balloon);
+    println!("virtio-balloon: at {}{}", device.address, stats);
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1002), // transitional
+    },
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1045), // modern only
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "virtio-balloon",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
@@ -0,0 +1,254 @@
+//! virtio-blk, exposed as a `BlockDevice` under `vda`, `vdb`, ...
+//!
+//! Requests are submitted one at a time and completion is polled for, which keeps the driver
+//! usable before interrupts are routed and is still far faster than programmed I/O: QEMU handles
+//! each request in a single exit.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{DmaPage, Transport, VENDOR_ID};
+use crate::block::{self, check_request, BlockDevice, BlockError};
+use crate::drivers;
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_INTERRUPT_DISABLE};
+use crate::power;
+use alloc::format;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// virtio-blk always addresses the disk in 512-byte sectors
+pub const SECTOR_SIZE: usize = 512;
+
+/// Entries in the request queue; a request never uses more than three
+const QUEUE_SIZE: u16 = 16;
+
+mod request {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+}
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Offset of the capacity (in sectors) in the device configuration
+const CONFIG_CAPACITY: usize = 0;
+
+// Layout of the page requests are staged in: the header, the status byte, then the data
+const HEADER_OFFSET: usize = 0;
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = SECTOR_SIZE;
+
+struct Channel {
+    queue: Virtqueue,
+    staging: DmaPage,
+}
+
+impl Channel {
+    /// Runs one request and waits for it; `data` is the buffer between header and status, if any
+    fn execute(&mut self, kind: u32, sector: u64, data: Option<Buffer>) -> Result<(), BlockError> {
+        let base = self.staging.as_mut_ptr();
+        unsafe {
+            let header = base.add(HEADER_OFFSET);
+            header.cast::<u32>().write_volatile(kind);
+            header.add(4).cast::<u32>().write_volatile(0);
+            header.add(8).cast::<u64>().write_volatile(sector);
+            base.add(STATUS_OFFSET).write_volatile(0xFF);
+        }
+
+        let staging = self.staging.phys();
+        let header = Buffer {
+            address: staging + HEADER_OFFSET,
+            len: HEADER_SIZE as u32,
+            device_writable: false,
+        };
+        let status = Buffer {
+            address: staging + STATUS_OFFSET,
+            len: 1,
+            device_writable: true,
+        };
+        let head = match data {
+            Some(data) => self.queue.submit(&[header, data, status]),
+            None => self.queue.submit(&[header, status]),
+        }
+        .ok_or(BlockError::Io)?;
+        self.queue.notify();
+
+        loop {
+            match self.queue.pop_used() {
+                Some((id, _)) if id == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+
+        match unsafe { base.add(STATUS_OFFSET).read_volatile() } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED if kind == request::FLUSH => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    fn data_buffer(&self, device_writable: bool) -> Buffer {
+        Buffer {
+            address: self.staging.phys() + DATA_OFFSET,
+            len: SECTOR_SIZE as u32,
+            device_writable,
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    /// What `new` negotiated, asked for again after sleep
+    features: u64,
+    channel: Mutex<Channel>,
+    sector_count: u64,
+    read_only: bool,
+    can_flush: bool,
+}
+
+impl VirtioBlk {
+    /// Negotiates with the device and sets up its request queue
+    pub fn new(device: &PciDevice) -> Result<VirtioBlk, &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let setup = || -> Result<(Virtqueue, DmaPage, u64), &'static str> {
+            let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+            let staging = DmaPage::new()?;
+            let sector_count = transport
+                .read_device_config::<u64>(CONFIG_CAPACITY)
+                .ok_or("no device configuration")?;
+            Ok((queue, staging, sector_count))
+        };
+        let (queue, staging, sector_count) = setup().inspect_err(|_| transport.fail())?;
+        transport.driver_ok();
+
+        Ok(VirtioBlk {
+            transport,
+            features,
+            channel: Mutex::new(Channel { queue, staging }),
+            sector_count,
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+        })
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let mut channel = self.channel.lock();
+        let data = channel.data_buffer(true);
+        channel.execute(request::IN, block, Some(data))?;
+        unsafe {
+            let src = channel.staging.as_mut_ptr().add(DATA_OFFSET);
+            core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), SECTOR_SIZE);
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut channel = self.channel.lock();
+        unsafe {
+            let dst = channel.staging.as_mut_ptr().add(DATA_OFFSET);
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), dst, SECTOR_SIZE);
+        }
+        let data = channel.data_buffer(false);
+        channel.execute(request::OUT, block, Some(data))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(()); // without the feature the device writes through
+        }
+        self.channel.lock().execute(request::FLUSH, 0, None)
+    }
+}
+
+impl power::Device for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn suspend(&self) -> Result<(), &'static str> {
+        self.flush()
+            .map_err(|_| "failed to flush the disk's write cache")
+    }
+
+    fn shutdown(&self) -> Result<(), &'static str> {
+        let flushed = self.suspend();
+        let _channel = self.channel.lock();
+        self.transport.reset();
+        flushed
+    }
+
+    /// The device comes out of sleep reset, with no queues and no features
+    fn resume(&self) -> Result<(), &'static str> {
+        let mut channel = self.channel.lock();
+        let features = self.transport.negotiate(self.features)?;
+        if features != self.features {
+            self.transport.fail();
+            return Err("the device no longer offers the features it had");
+        }
+        self.transport
+            .restore_queue(&mut channel.queue)
+            .inspect_err(|_| self.transport.fail())?;
+        self.transport.driver_ok();
+        Ok(())
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    device.enable();
+    // completions are polled, keep the legacy interrupt line quiet
+    device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+
+    let disk = Arc::new(VirtioBlk::new(device)?);
+    let size_mib = disk.sector_count * SECTOR_SIZE as u64 / (1024 * 1024);
+    let name = (b'a'..=b'z')
+        .map(|letter| format!("vd{}", letter as char))
+        .find(|name| block::register(name, disk.clone()))
+        .ok_or("block device names exhausted")?;
+    power::register(disk);
+    crate::println!(
+        "virtio-blk: {} at {}, {} MiB",
+        name,
+        device.address,
+        size_mib
+    );
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1001), // transitional
+    },
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1042), // modern only
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
@@ -0,0 +1,249 @@
+//! virtio-console: the VM's console channel, e.g. QEMU's
+//! `-device virtio-serial -device virtconsole,chardev=...`.
+//!
+//! Only port 0 is used, the console port every device has, so multiport isn't negotiated. Once
+//! the device is found, console output is mirrored to it. What arrives is polled for by
+//! `receiver`, like virtio-blk completions, and handed to `console::INPUT`.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{DmaPage, Transport, VENDOR_ID};
+use crate::console::{self, Sink, Terminal};
+use crate::drivers;
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_INTERRUPT_DISABLE};
+use crate::{power, println, timer};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::time::Duration;
+use spin::{Mutex, Once};
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+/// The receive page is split into this many buffers, all posted at once
+const RECEIVE_BUFFERS: usize = 8;
+const RECEIVE_BUFFER_SIZE: usize = DmaPage::SIZE / RECEIVE_BUFFERS;
+
+/// How often `receiver` looks for input
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+struct Transmit {
+    queue: Virtqueue,
+    staging: DmaPage,
+}
+
+impl Transmit {
+    /// Sends `data` and waits until the device has taken it
+    fn send(&mut self, data: &[u8]) {
+        for chunk in data.chunks(DmaPage::SIZE) {
+            unsafe {
+                let dst = self.staging.as_mut_ptr();
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), dst, chunk.len());
+            }
+            let buffer = Buffer {
+                address: self.staging.phys(),
+                len: chunk.len() as u32,
+                device_writable: false,
+            };
+            let Some(head) = self.queue.submit(&[buffer]) else {
+                return;
+            };
+            self.queue.notify();
+            loop {
+                match self.queue.pop_used() {
+                    Some((id, _)) if id == head => break,
+                    Some(_) => continue,
+                    None => core::hint::spin_loop(),
+                }
+            }
+        }
+    }
+}
+
+struct Receive {
+    queue: Virtqueue,
+    buffers: DmaPage,
+    /// Which of the buffers each chain in flight is
+    posted: BTreeMap<u16, usize>,
+}
+
+impl Receive {
+    fn post(&mut self, slot: usize) {
+        let buffer = Buffer {
+            address: self.buffers.phys() + (slot * RECEIVE_BUFFER_SIZE) as u64,
+            len: RECEIVE_BUFFER_SIZE as u32,
+            device_writable: true,
+        };
+        if let Some(head) = self.queue.submit(&[buffer]) {
+            self.posted.insert(head, slot);
+        }
+    }
+
+    /// Hands every buffer to the device; the queue must be empty
+    fn post_all(&mut self) {
+        self.posted.clear();
+        for slot in 0..RECEIVE_BUFFERS {
+            self.post(slot);
+        }
+        self.queue.notify();
+    }
+
+    /// Passes what the device wrote to `deliver` and posts the buffers again
+    fn poll(&mut self, mut deliver: impl FnMut(&[u8])) {
+        let mut reposted = false;
+        while let Some((id, len)) = self.queue.pop_used() {
+            let Some(slot) = self.posted.remove(&id) else {
+                continue;
+            };
+            let len = (len as usize).min(RECEIVE_BUFFER_SIZE);
+            let data = unsafe {
+                let base = self.buffers.as_mut_ptr().add(slot * RECEIVE_BUFFER_SIZE);
+                core::slice::from_raw_parts(base, len)
+            };
+            deliver(data);
+            self.post(slot);
+            reposted = true;
+        }
+        if reposted {
+            self.queue.notify();
+        }
+    }
+}
+
+pub struct VirtioConsole {
+    transport: Transport,
+    /// What `new` negotiated, asked for again after sleep
+    features: u64,
+    transmit: Mutex<Transmit>,
+    receive: Mutex<Receive>,
+}
+
+impl VirtioConsole {
+    /// Negotiates with the device, sets up port 0's queues and fills the receive queue
+    pub fn new(device: &PciDevice) -> Result<VirtioConsole, &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(0)?;
+        let setup = || -> Result<(Transmit, Receive), &'static str> {
+            let receive = Receive {
+                queue: transport.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)?,
+                buffers: DmaPage::new()?,
+                posted: BTreeMap::new(),
+            };
+            let transmit = Transmit {
+                queue: transport.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?,
+                staging: DmaPage::new()?,
+            };
+            Ok((transmit, receive))
+        };
+        let (transmit, mut receive) = setup().inspect_err(|_| transport.fail())?;
+        transport.driver_ok();
+        receive.post_all();
+
+        Ok(VirtioConsole {
+            transport,
+            features,
+            transmit: Mutex::new(transmit),
+            receive: Mutex::new(receive),
+        })
+    }
+
+    pub fn send(&self, data: &[u8]) {
+        self.transmit.lock().send(data);
+    }
+
+    /// Moves whatever arrived to `console::INPUT`
+    fn poll(&self) {
+        self.receive.lock().poll(|data| {
+            for &byte in data {
+                console::INPUT.push(byte);
+            }
+        });
+    }
+}
+
+impl power::Device for VirtioConsole {
+    fn name(&self) -> &'static str {
+        "virtio-console"
+    }
+
+    /// The device comes out of sleep reset, with no queues and no features
+    fn resume(&self) -> Result<(), &'static str> {
+        let mut transmit = self.transmit.lock();
+        let mut receive = self.receive.lock();
+        let features = self.transport.negotiate(self.features)?;
+        if features != self.features {
+            self.transport.fail();
+            return Err("the device no longer offers the features it had");
+        }
+        self.transport
+            .restore_queue(&mut receive.queue)
+            .and_then(|()| self.transport.restore_queue(&mut transmit.queue))
+            .inspect_err(|_| self.transport.fail())?;
+        self.transport.driver_ok();
+        receive.post_all();
+        Ok(())
+    }
+}
+
+/// The device as the sink of a console `Terminal`
+struct Port(Arc<VirtioConsole>);
+
+impl Sink for Port {
+    fn send(&mut self, data: &[u8]) {
+        self.0.send(data);
+    }
+}
+
+/// The first console found; others are left alone
+static CONSOLE: Once<Arc<VirtioConsole>> = Once::new();
+
+/// Feeds what arrives on the console channel to `console::INPUT` every `POLL_INTERVAL`; meant to
+/// be spawned as a task. Returns at once if there's no device.
+pub async fn receiver() {
+    let Some(device) = CONSOLE.r#try() else {
+        return;
+    };
+    loop {
+        device.poll();
+        timer::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    if CONSOLE.r#try().is_some() {
+        return Err("a console channel is already in use");
+    }
+    device.enable();
+    // input is polled for, keep the legacy interrupt line quiet
+    device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+
+    let console = Arc::new(VirtioConsole::new(device)?);
+    CONSOLE.call_once(|| console.clone());
+    power::register(console.clone());
+    println!(
+        "virtio-console: at {}, mirroring the console",
+        device.address
+    );
+    console::attach(Box::new(Terminal(Port(console))));
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1003), // transitional
+    },
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1043), // modern only
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "virtio-console",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
@@ -0,0 +1,310 @@
+//! virtio-net: the VM's network card, e.g. QEMU's `-device virtio-net-pci,netdev=...`.
+//!
+//! Only the first queue pair is used and no offloads are negotiated, so every frame is whole and
+//! its checksums are the stack's business. Each frame on the wire is preceded by a
+//! `virtio_net_hdr`, left zeroed on the way out and skipped on the way in. The device is handed
+//! to `net::nic` as an interface; its receive queue is emptied by `net::nic::receiver`.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{DmaPage, Transport, VENDOR_ID};
+use crate::drivers;
+use crate::net::nic::{self, MacAddress, Nic, MAX_FRAME_SIZE};
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_INTERRUPT_DISABLE};
+use crate::{power, println};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+/// Offsets in the device configuration: the MAC address, and the link status if `F_STATUS`
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const STATUS_LINK_UP: u16 = 1 << 0;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+/// `virtio_net_hdr` with `F_VERSION_1`, which always has the `num_buffers` field
+const HEADER_SIZE: usize = 12;
+
+/// Buffers hold a header and a whole frame, two to a page
+const BUFFER_SIZE: usize = DmaPage::SIZE / 2;
+const RECEIVE_BUFFERS: usize = 8;
+const TRANSMIT_BUFFERS: usize = 8;
+
+const _: () = assert!(HEADER_SIZE + MAX_FRAME_SIZE <= BUFFER_SIZE);
+
+/// Frame buffers in pages of their own
+struct Buffers {
+    pages: Vec<DmaPage>,
+}
+
+impl Buffers {
+    fn new(count: usize) -> Result<Buffers, &'static str> {
+        let pages = (0..count.div_ceil(2))
+            .map(|_| DmaPage::new())
+            .collect::<Result<_, _>>()?;
+        Ok(Buffers { pages })
+    }
+
+    fn buffer(&self, slot: usize, len: usize, device_writable: bool) -> Buffer {
+        Buffer {
+            address: self.pages[slot / 2].phys() + ((slot % 2) * BUFFER_SIZE) as u64,
+            len: len as u32,
+            device_writable,
+        }
+    }
+
+    fn as_mut_ptr(&self, slot: usize) -> *mut u8 {
+        unsafe {
+            self.pages[slot / 2]
+                .as_mut_ptr()
+                .add((slot % 2) * BUFFER_SIZE)
+        }
+    }
+}
+
+struct Transmit {
+    queue: Virtqueue,
+    buffers: Buffers,
+    /// Slots not handed to the device
+    free: Vec<usize>,
+    /// Which slot each chain in flight is
+    sending: BTreeMap<u16, usize>,
+}
+
+impl Transmit {
+    /// Takes back the slots of the frames the device has sent
+    fn reclaim(&mut self) {
+        while let Some((id, _)) = self.queue.pop_used() {
+            if let Some(slot) = self.sending.remove(&id) {
+                self.free.push(slot);
+            }
+        }
+    }
+
+    /// Every slot free again; after the queue was reset
+    fn reset(&mut self) {
+        self.sending.clear();
+        self.free = (0..TRANSMIT_BUFFERS).collect();
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        self.reclaim();
+        let slot = self.free.pop().ok_or("transmit queue full")?;
+        unsafe {
+            let base = self.buffers.as_mut_ptr(slot);
+            base.write_bytes(0, HEADER_SIZE);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), base.add(HEADER_SIZE), frame.len());
+        }
+        let buffer = self.buffers.buffer(slot, HEADER_SIZE + frame.len(), false);
+        let Some(head) = self.queue.submit(&[buffer]) else {
+            self.free.push(slot);
+            return Err("transmit queue full");
+        };
+        self.sending.insert(head, slot);
+        self.queue.notify();
+        Ok(())
+    }
+}
+
+struct Receive {
+    queue: Virtqueue,
+    buffers: Buffers,
+    /// Which slot each chain in flight is
+    posted: BTreeMap<u16, usize>,
+}
+
+impl Receive {
+    fn post(&mut self, slot: usize) {
+        let buffer = self.buffers.buffer(slot, BUFFER_SIZE, true);
+        if let Some(head) = self.queue.submit(&[buffer]) {
+            self.posted.insert(head, slot);
+        }
+    }
+
+    /// Hands every buffer to the device; the queue must be empty
+    fn post_all(&mut self) {
+        self.posted.clear();
+        for slot in 0..RECEIVE_BUFFERS {
+            self.post(slot);
+        }
+        self.queue.notify();
+    }
+
+    /// Passes the frames the device wrote to `deliver` and posts their buffers again
+    fn poll(&mut self, deliver: &mut dyn FnMut(&[u8])) {
+        let mut reposted = false;
+        while let Some((id, len)) = self.queue.pop_used() {
+            let Some(slot) = self.posted.remove(&id) else {
+                continue;
+            };
+            let len = (len as usize).min(BUFFER_SIZE);
+            if len > HEADER_SIZE {
+                let frame = unsafe {
+                    let base = self.buffers.as_mut_ptr(slot).add(HEADER_SIZE);
+                    core::slice::from_raw_parts(base, len - HEADER_SIZE)
+                };
+                deliver(frame);
+            }
+            self.post(slot);
+            reposted = true;
+        }
+        if reposted {
+            self.queue.notify();
+        }
+    }
+}
+
+pub struct VirtioNet {
+    transport: Transport,
+    /// What `new` negotiated, asked for again after sleep
+    features: u64,
+    mac: MacAddress,
+    transmit: Mutex<Transmit>,
+    receive: Mutex<Receive>,
+}
+
+impl VirtioNet {
+    /// Negotiates with the device, sets up the first queue pair and fills the receive queue
+    pub fn new(device: &PciDevice) -> Result<VirtioNet, &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_MAC | F_STATUS)?;
+        if features & F_MAC == 0 {
+            transport.fail();
+            return Err("the device has no MAC address");
+        }
+        let mut mac = [0; 6];
+        for (offset, byte) in mac.iter_mut().enumerate() {
+            *byte = transport
+                .read_device_config(CONFIG_MAC + offset)
+                .ok_or("no device configuration")
+                .inspect_err(|_| transport.fail())?;
+        }
+        let setup = || -> Result<(Transmit, Receive), &'static str> {
+            let receive = Receive {
+                queue: transport.setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)?,
+                buffers: Buffers::new(RECEIVE_BUFFERS)?,
+                posted: BTreeMap::new(),
+            };
+            let transmit = Transmit {
+                queue: transport.setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)?,
+                buffers: Buffers::new(TRANSMIT_BUFFERS)?,
+                free: (0..TRANSMIT_BUFFERS).collect(),
+                sending: BTreeMap::new(),
+            };
+            Ok((transmit, receive))
+        };
+        let (transmit, mut receive) = setup().inspect_err(|_| transport.fail())?;
+        transport.driver_ok();
+        receive.post_all();
+
+        Ok(VirtioNet {
+            transport,
+            features,
+            mac: MacAddress(mac),
+            transmit: Mutex::new(transmit),
+            receive: Mutex::new(receive),
+        })
+    }
+}
+
+impl Nic for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// Without `F_STATUS` the link is taken to be always up
+    fn link_up(&self) -> bool {
+        if self.features & F_STATUS == 0 {
+            return true;
+        }
+        self.transport
+            .read_device_config::<u16>(CONFIG_STATUS)
+            .is_some_and(|status| status & STATUS_LINK_UP != 0)
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), &'static str> {
+        self.transmit.lock().send(frame)
+    }
+
+    fn receive(&self, deliver: &mut dyn FnMut(&[u8])) {
+        self.receive.lock().poll(deliver);
+    }
+}
+
+impl power::Device for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    /// Frames still queued to be sent are dropped
+    fn shutdown(&self) -> Result<(), &'static str> {
+        let _transmit = self.transmit.lock();
+        let _receive = self.receive.lock();
+        self.transport.reset();
+        Ok(())
+    }
+
+    /// The device comes out of sleep reset, with no queues and no features; frames that were
+    /// being sent are lost
+    fn resume(&self) -> Result<(), &'static str> {
+        let mut transmit = self.transmit.lock();
+        let mut receive = self.receive.lock();
+        let features = self.transport.negotiate(self.features)?;
+        if features != self.features {
+            self.transport.fail();
+            return Err("the device no longer offers the features it had");
+        }
+        self.transport
+            .restore_queue(&mut receive.queue)
+            .and_then(|()| self.transport.restore_queue(&mut transmit.queue))
+            .inspect_err(|_| self.transport.fail())?;
+        self.transport.driver_ok();
+        receive.post_all();
+        transmit.reset();
+        Ok(())
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    device.enable();
+    // received frames are polled for, keep the legacy interrupt line quiet
+    device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+
+    let net = Arc::new(VirtioNet::new(device)?);
+    let interface = nic::register(net.clone())?;
+    power::register(net.clone());
+    println!(
+        "virtio-net: at {}, {} as {}",
+        device.address, net.mac, interface.name
+    );
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1000), // transitional
+    },
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1041), // modern only
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "virtio-net",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
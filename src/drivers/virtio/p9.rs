@@ -0,0 +1,195 @@
+//! virtio-9p: a directory shared by the host over 9P, e.g. QEMU's
+//! `-virtfs local,path=<dir>,mount_tag=<tag>,security_model=none`.
+//!
+//! Each device's share is mounted at `/mnt/<tag>` through the client in `fs::p9`. Like
+//! virtio-blk, the driver runs one request at a time and polls for its completion. A device reset
+//! makes the server forget every fid, and sleep resets the device, so the driver doesn't take
+//! part in suspend: shares stop working after a resume.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{DmaPage, Transport, VENDOR_ID};
+use crate::block::BlockError;
+use crate::drivers;
+use crate::fs::p9::{self, P9Fs};
+use crate::fs::{self, FileKind, FsError};
+use crate::pci::{DeviceMatch, Driver, PciDevice, COMMAND_INTERRUPT_DISABLE};
+use crate::println;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// The device configuration has a mount tag
+const F_MOUNT_TAG: u64 = 1 << 0;
+
+/// Offsets in the device configuration: the tag's length, then the tag
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+
+/// Entries in the request queue, enough for a request and its reply at their largest
+const QUEUE_SIZE: u16 = 16;
+/// Pages a message may span, either way
+const MESSAGE_PAGES: usize = 4;
+pub const MAX_MESSAGE: usize = MESSAGE_PAGES * DmaPage::SIZE;
+
+/// Where mounts go, `/mnt/<tag>`
+const MOUNT_DIRECTORY: &str = "/mnt";
+
+struct Channel {
+    queue: Virtqueue,
+    request: Vec<DmaPage>,
+    reply: Vec<DmaPage>,
+}
+
+impl Channel {
+    fn exchange(&mut self, request: &[u8]) -> Result<Vec<u8>, FsError> {
+        if request.len() > MAX_MESSAGE {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut buffers = Vec::with_capacity(2 * MESSAGE_PAGES);
+        for (page, chunk) in self.request.iter().zip(request.chunks(DmaPage::SIZE)) {
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), page.as_mut_ptr(), chunk.len())
+            };
+            buffers.push(Buffer {
+                address: page.phys(),
+                len: chunk.len() as u32,
+                device_writable: false,
+            });
+        }
+        buffers.extend(self.reply.iter().map(|page| Buffer {
+            address: page.phys(),
+            len: DmaPage::SIZE as u32,
+            device_writable: true,
+        }));
+        let head = self
+            .queue
+            .submit(&buffers)
+            .ok_or(FsError::Block(BlockError::Io))?;
+        self.queue.notify();
+
+        let len = loop {
+            match self.queue.pop_used() {
+                Some((id, len)) if id == head => break len as usize,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        };
+        let mut reply = Vec::with_capacity(len.min(MAX_MESSAGE));
+        for page in &self.reply {
+            let count = (len - reply.len()).min(DmaPage::SIZE);
+            if count == 0 {
+                break;
+            }
+            reply.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(page.as_mut_ptr(), count)
+            });
+        }
+        Ok(reply)
+    }
+}
+
+pub struct VirtioP9 {
+    channel: Mutex<Channel>,
+}
+
+impl VirtioP9 {
+    /// Negotiates with the device, sets up its request queue and returns it with its mount tag
+    pub fn new(device: &PciDevice) -> Result<(VirtioP9, String), &'static str> {
+        let transport = Transport::new(device)?;
+        let features = transport.negotiate(F_MOUNT_TAG)?;
+        let setup = || -> Result<(Channel, String), &'static str> {
+            if features & F_MOUNT_TAG == 0 {
+                return Err("the device has no mount tag");
+            }
+            let tag_len = transport
+                .read_device_config::<u16>(CONFIG_TAG_LEN)
+                .ok_or("no device configuration")?;
+            let tag = (0..tag_len as usize)
+                .map(|index| transport.read_device_config::<u8>(CONFIG_TAG + index))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or("the mount tag runs past the device configuration")?;
+            let tag = String::from_utf8(tag).map_err(|_| "the mount tag isn't UTF-8")?;
+            let pages = || {
+                (0..MESSAGE_PAGES)
+                    .map(|_| DmaPage::new())
+                    .collect::<Result<_, _>>()
+            };
+            let channel = Channel {
+                queue: transport.setup_queue(0, QUEUE_SIZE)?,
+                request: pages()?,
+                reply: pages()?,
+            };
+            Ok((channel, tag))
+        };
+        let (channel, tag) = setup().inspect_err(|_| transport.fail())?;
+        transport.driver_ok();
+        Ok((
+            VirtioP9 {
+                channel: Mutex::new(channel),
+            },
+            tag,
+        ))
+    }
+}
+
+impl p9::Channel for VirtioP9 {
+    fn max_message(&self) -> usize {
+        MAX_MESSAGE
+    }
+
+    fn exchange(&self, request: &[u8]) -> Result<Vec<u8>, FsError> {
+        self.channel.lock().exchange(request)
+    }
+}
+
+/// Creates the directory at `path` unless it's there already
+fn ensure_directory(path: &str) -> Result<(), FsError> {
+    match fs::create(path, FileKind::Directory) {
+        Err(FsError::AlreadyExists) => Ok(()),
+        result => result.map(drop),
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    device.enable();
+    // completions are polled, keep the legacy interrupt line quiet
+    device.set_command(device.command() | COMMAND_INTERRUPT_DISABLE);
+
+    let (channel, tag) = VirtioP9::new(device)?;
+    if tag.is_empty() || tag.contains('/') {
+        return Err("the mount tag can't name a directory");
+    }
+    let filesystem =
+        P9Fs::attach(Arc::new(channel), "").map_err(|_| "the 9P server refused to attach")?;
+    let path = format!("{}/{}", MOUNT_DIRECTORY, tag);
+    ensure_directory(MOUNT_DIRECTORY)
+        .and_then(|()| ensure_directory(&path))
+        .and_then(|()| fs::mount(&path, Arc::new(filesystem)))
+        .map_err(|_| "failed to mount the share")?;
+    println!(
+        "virtio-9p: {} at {} mounted on {}",
+        tag, device.address, path
+    );
+    Ok(())
+}
+
+static MATCHES: [DeviceMatch; 2] = [
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1009), // transitional
+    },
+    DeviceMatch::Id {
+        vendor_id: VENDOR_ID,
+        device_id: Some(0x1049), // modern only
+    },
+];
+
+static PCI_DRIVER: Driver = Driver {
+    name: "virtio-9p",
+    matches: &MATCHES,
+    probe,
+};
+
+pub static DRIVER: drivers::Driver = drivers::Driver::pci(&PCI_DRIVER, &[]);
@@ -0,0 +1,183 @@
+//! Split virtqueues: a descriptor table, the available ring the driver fills and the used ring
+//! the device returns finished chains on. Each part gets its own page.
+
+use super::DmaPage;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// Header of both rings: `flags: u16, idx: u16`
+const RING_HEADER: usize = 4;
+const USED_ELEMENT_SIZE: usize = 8;
+
+/// Largest queue whose descriptor table fits in one page
+pub const MAX_QUEUE_SIZE: u16 = (DmaPage::SIZE / DESCRIPTOR_SIZE) as u16;
+
+/// One element of a descriptor chain
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to (rather than reads from) this buffer
+    pub device_writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: DmaPage,
+    available: DmaPage,
+    used: DmaPage,
+    notify: VirtAddr,
+    /// Descriptors not part of any submitted chain
+    free: Vec<u16>,
+    /// Next value of the available ring's `idx`
+    next_available: u16,
+    /// Used ring `idx` up to which completions have been collected
+    last_used: u16,
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16, notify: VirtAddr) -> Result<Virtqueue, &'static str> {
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err("invalid queue size");
+        }
+        Ok(Virtqueue {
+            index,
+            size,
+            descriptors: DmaPage::new()?,
+            available: DmaPage::new()?,
+            used: DmaPage::new()?,
+            notify,
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    pub(super) fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub(super) fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Empties the queue, to hand it to a device again after the device was reset. Chains that
+    /// were in flight are forgotten.
+    pub(super) fn reset(&mut self) {
+        unsafe {
+            self.available.as_mut_ptr().write_bytes(0, DmaPage::SIZE);
+            self.used.as_mut_ptr().write_bytes(0, DmaPage::SIZE);
+        }
+        self.free = (0..self.size).rev().collect();
+        self.next_available = 0;
+        self.last_used = 0;
+    }
+
+    pub(super) fn descriptor_area(&self) -> PhysAddr {
+        self.descriptors.phys()
+    }
+
+    pub(super) fn driver_area(&self) -> PhysAddr {
+        self.available.phys()
+    }
+
+    pub(super) fn device_area(&self) -> PhysAddr {
+        self.used.phys()
+    }
+
+    /// Number of descriptors not in use
+    pub fn free_descriptors(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Chains `buffers` together and makes the chain available to the device, returning the id
+    /// `pop_used` will report it under. The device isn't told until `notify`.
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let chain: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+        for (position, (buffer, &descriptor)) in buffers.iter().zip(&chain).enumerate() {
+            let next = chain.get(position + 1).copied();
+            let mut flags = 0;
+            if buffer.device_writable {
+                flags |= DESC_F_WRITE;
+            }
+            if next.is_some() {
+                flags |= DESC_F_NEXT;
+            }
+            let base = descriptor as usize * DESCRIPTOR_SIZE;
+            unsafe {
+                write(&self.descriptors, base, buffer.address.as_u64());
+                write(&self.descriptors, base + 8, buffer.len);
+                write(&self.descriptors, base + 12, flags);
+                write(&self.descriptors, base + 14, next.unwrap_or(0));
+            }
+        }
+
+        let head = chain[0];
+        let slot = (self.next_available % self.size) as usize;
+        unsafe { write(&self.available, RING_HEADER + slot * 2, head) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // the device must see the ring entry before the index that publishes it
+        fence(Ordering::SeqCst);
+        unsafe { write(&self.available, 2, self.next_available) };
+        Some(head)
+    }
+
+    /// Tells the device there are new chains in the available ring
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { core::ptr::write_volatile(self.notify.as_mut_ptr::<u16>(), self.index) };
+    }
+
+    /// Takes the next chain the device has finished with, returning its id and the number of
+    /// bytes the device wrote into it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index: u16 = unsafe { read(&self.used, 2) };
+        if used_index == self.last_used {
+            return None;
+        }
+        // don't read the element before the index that says it's there
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used % self.size) as usize;
+        let element = RING_HEADER + slot * USED_ELEMENT_SIZE;
+        let (id, len): (u32, u32) =
+            unsafe { (read(&self.used, element), read(&self.used, element + 4)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut descriptor = id as u16;
+        loop {
+            self.free.push(descriptor);
+            let base = descriptor as usize * DESCRIPTOR_SIZE;
+            let flags: u16 = unsafe { read(&self.descriptors, base + 12) };
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            descriptor = unsafe { read(&self.descriptors, base + 14) };
+        }
+        Some((id as u16, len))
+    }
+}
+
+/// # Safety
+///
+/// `offset` plus the size of `T` must lie within the page.
+unsafe fn read<T: Copy>(page: &DmaPage, offset: usize) -> T {
+    core::ptr::read_volatile(page.as_mut_ptr().add(offset) as *const T)
+}
+
+/// # Safety
+///
+/// `offset` plus the size of `T` must lie within the page.
+unsafe fn write<T: Copy>(page: &DmaPage, offset: usize, value: T) {
+    core::ptr::write_volatile(page.as_mut_ptr().add(offset) as *mut T, value)
+}
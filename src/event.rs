@@ -0,0 +1,78 @@
+//! The kernel event bus: things that happened, posted from anywhere and delivered to whoever
+//! subscribed.
+//!
+//! Events are mostly posted from interrupt handlers, where nothing may lock or allocate, so
+//! `post` only pushes them onto a lock-free queue. The executor calls `dispatch` from task
+//! context after every wake-up, and subscribers run there; they're free to sleep the machine or
+//! turn it off.
+
+use crate::memory::pressure;
+use crate::sync::mpmc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Events that can be waiting for `dispatch` at once; later ones are dropped
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    PowerButton,
+    SleepButton,
+    LidClosed,
+    LidOpened,
+    /// The memory pressure level changed to this one
+    MemoryPressure(pressure::Level),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::PowerButton => f.write_str("power button"),
+            Event::SleepButton => f.write_str("sleep button"),
+            Event::LidClosed => f.write_str("lid closed"),
+            Event::LidOpened => f.write_str("lid opened"),
+            Event::MemoryPressure(level) => write!(f, "memory pressure {}", level),
+        }
+    }
+}
+
+static QUEUE: mpmc::Queue<Event, QUEUE_SIZE> = mpmc::Queue::new();
+static SUBSCRIBERS: Mutex<Vec<fn(Event)>> = Mutex::new(Vec::new());
+/// Events lost to a full queue
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Has `handler` called with every event from now on
+pub fn subscribe(handler: fn(Event)) {
+    SUBSCRIBERS.lock().push(handler);
+}
+
+/// Queues `event` for the subscribers; safe in interrupt handlers
+pub fn post(event: Event) {
+    if QUEUE.push(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether events are waiting for `dispatch`
+pub fn pending() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// Number of events dropped because nobody dispatched them in time
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Hands every queued event to the subscribers, in the order they were posted. Called by the
+/// executor, not from interrupts.
+pub fn dispatch() {
+    while let Some(event) = QUEUE.pop() {
+        // a copy, so subscribers can subscribe others
+        let subscribers = SUBSCRIBERS.lock().clone();
+        for subscriber in subscribers {
+            subscriber(event);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! The x87, SSE and AVX registers, and switching them between threads.
+//!
+//! The kernel is compiled for soft float, but programs in ring 3 use the FPU and the vector
+//! registers as they please, so every thread has an `FpuState` to keep them in. Switching is
+//! lazy: before a thread's time slice `switch_to` sets CR0.TS, unless this CPU's registers still
+//! hold that thread's state, and the thread's first FPU instruction then traps with #NM (device
+//! not available), which loads its state. After the slice `save` stores the state again if the
+//! thread had it loaded, so the thread can continue on any CPU.
+//!
+//! Kernel code that wants the FPU runs in `with_kernel_fpu`. Inline assembly that puts back every
+//! register it touches, like the AES-NI routines, can do without: an #NM from the kernel only
+//! clears TS.
+//!
+//! With XSAVE the save area covers every component enabled in XCR0, AVX's upper halves included;
+//! without it, it's the 512-byte FXSAVE image of the x87 and SSE registers.
+
+use crate::endian::{read_u32_le, read_u64_le};
+use crate::process::Thread;
+use crate::{arch, percpu};
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::arch::asm;
+use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+/// XSAVE wants its area 64-byte aligned, FXSAVE 16
+const AREA_ALIGN: usize = 64;
+const FXSAVE_SIZE: usize = 512;
+
+/// The x87 control word after FNINIT: every exception masked, 64-bit precision
+const DEFAULT_FCW: u16 = 0x037f;
+/// MXCSR after reset: every exception masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1f80;
+/// Where the two are in the legacy area both formats start with
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// MXCSR bits that may be set; loading any other faults
+const MXCSR_WRITABLE: u32 = 0xffff;
+/// The XSAVE header after the legacy area: the components present, then zeros in the standard
+/// format
+const XSAVE_HEADER_SIZE: usize = 64;
+
+/// How state is saved, decided on the boot CPU and the same on all of them
+struct Format {
+    /// Components enabled in XCR0, `None` without XSAVE
+    xsave: Option<XCr0Flags>,
+    size: usize,
+}
+
+static FORMAT: Once<Format> = Once::new();
+
+fn format() -> &'static Format {
+    FORMAT.call_once(|| {
+        let cpuid = CpuId::new();
+        let features = cpuid.get_feature_info();
+        if !features.as_ref().is_some_and(|info| info.has_xsave()) {
+            return Format {
+                xsave: None,
+                size: FXSAVE_SIZE,
+            };
+        }
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if features.is_some_and(|info| info.has_avx()) {
+            components |= XCr0Flags::AVX;
+        }
+        // large enough for every component the CPU has, whichever are enabled
+        let size = cpuid.get_extended_state_info().map_or(FXSAVE_SIZE, |info| {
+            info.xsave_area_size_supported_features() as usize
+        });
+        Format {
+            xsave: Some(components),
+            size: size.max(FXSAVE_SIZE),
+        }
+    })
+}
+
+/// Enables SSE, and XSAVE with AVX where the CPU has them; run once on every CPU, and again after
+/// sleep, which loses the registers
+pub fn init() {
+    arch::enable_sse();
+    if let Some(components) = format().xsave {
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+    }
+    if let Some(cpu) = percpu::try_current() {
+        cpu.fpu_owner.store(0, Ordering::SeqCst);
+    }
+}
+
+/// One thread's FPU and vector registers while they aren't loaded
+pub struct FpuState {
+    area: NonNull<u8>,
+    /// The CPU that last loaded the state; its registers still hold it if it's also that CPU's
+    /// `fpu_owner`
+    loaded_on: Option<usize>,
+}
+
+// only the CPU running the thread touches the area
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// The state a program starts with: everything zero and every exception masked
+    pub fn new() -> FpuState {
+        let layout = FpuState::layout();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        unsafe {
+            area.as_ptr()
+                .add(FCW_OFFSET)
+                .cast::<u16>()
+                .write(DEFAULT_FCW);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(DEFAULT_MXCSR);
+        }
+        FpuState {
+            area,
+            loaded_on: None,
+        }
+    }
+
+    /// The saved registers in the CPU's own format, e.g. to write them out
+    pub fn area(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), format().size) }
+    }
+
+    /// Replaces the registers with `area`, saved by `area` on a CPU with the same format; fails
+    /// if it isn't the size of this CPU's, or loading it would fault
+    pub fn set_area(&mut self, area: &[u8]) -> Result<(), &'static str> {
+        if area.len() != format().size {
+            return Err("the FPU state was saved on a CPU with other registers");
+        }
+        if read_u32_le(area, MXCSR_OFFSET) & !MXCSR_WRITABLE != 0 {
+            return Err("the FPU state sets reserved MXCSR bits");
+        }
+        if let Some(components) = format().xsave {
+            let header = &area[FXSAVE_SIZE..FXSAVE_SIZE + XSAVE_HEADER_SIZE];
+            let valid = read_u64_le(header, 0) & !components.bits() == 0
+                && header[8..].iter().all(|&byte| byte == 0);
+            if !valid {
+                return Err("the FPU state has an invalid XSAVE header");
+            }
+        }
+        unsafe { core::ptr::copy_nonoverlapping(area.as_ptr(), self.area.as_ptr(), area.len()) };
+        self.loaded_on = None;
+        Ok(())
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(format().size, AREA_ALIGN).unwrap()
+    }
+
+    /// Stores the registers in the area; TS must be clear
+    unsafe fn store(&mut self) {
+        let area = self.area.as_ptr();
+        match format().xsave {
+            Some(_) => asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            None => asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+        }
+    }
+
+    /// Loads the registers from the area; TS must be clear
+    unsafe fn load(&self) {
+        let area = self.area.as_ptr();
+        match format().xsave {
+            Some(_) => asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            None => asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> FpuState {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), FpuState::layout()) };
+    }
+}
+
+fn set_task_switched(set: bool) {
+    unsafe {
+        match set {
+            true => Cr0::update(|cr0| cr0.insert(Cr0Flags::TASK_SWITCHED)),
+            false => asm!("clts", options(nomem, nostack, preserves_flags)),
+        }
+    }
+}
+
+/// Whether this CPU's registers hold `thread`'s state
+fn is_loaded(cpu: &percpu::PerCpu, thread: &Thread, state: &FpuState) -> bool {
+    cpu.fpu_owner.load(Ordering::SeqCst) == thread.id() && state.loaded_on == Some(cpu.index)
+}
+
+/// Gets the FPU ready for `thread`, which is about to run on this CPU
+pub fn switch_to(thread: &Thread) {
+    without_interrupts(|| {
+        let cpu = percpu::current();
+        let loaded = is_loaded(cpu, thread, &thread.fpu().lock());
+        set_task_switched(!loaded);
+    });
+}
+
+/// Stores `thread`'s state if it loaded it during the time slice that just ended on this CPU
+pub fn save(thread: &Thread) {
+    without_interrupts(|| {
+        let cpu = percpu::current();
+        let mut state = thread.fpu().lock();
+        if is_loaded(cpu, thread, &state) {
+            set_task_switched(false);
+            unsafe { state.store() };
+        }
+    });
+}
+
+/// Forgets whose state the registers hold, for code about to overwrite them, like a program run
+/// outside any thread
+pub fn discard() {
+    percpu::current().fpu_owner.store(0, Ordering::SeqCst);
+}
+
+/// Handles #NM: code used the FPU while TS was set. In ring 3 that's the running thread's first
+/// FPU instruction of its time slice, and its state is loaded.
+pub fn device_not_available(from_user: bool) {
+    set_task_switched(false);
+    if !from_user {
+        return;
+    }
+    let cpu = percpu::current();
+    let Some(thread) = (unsafe { cpu.thread.load(Ordering::SeqCst).as_ref() }) else {
+        return; // a program run without a thread, which keeps whatever is in the registers
+    };
+    let mut state = thread.fpu().lock();
+    unsafe { state.load() };
+    state.loaded_on = Some(cpu.index);
+    cpu.fpu_owner.store(thread.id(), Ordering::SeqCst);
+}
+
+/// Runs `f` with the FPU to itself, in its initial state and with interrupts disabled. Whatever
+/// thread state the registers held is saved first and loaded again on the thread's next use.
+/// Calls must not nest.
+pub fn with_kernel_fpu<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| {
+        let cpu = percpu::current();
+        set_task_switched(false);
+        let owner = cpu.fpu_owner.swap(0, Ordering::SeqCst);
+        // in a system call, or an interrupt from ring 3, the running thread's state may be live
+        let running = unsafe { cpu.thread.load(Ordering::SeqCst).as_ref() };
+        if let Some(thread) = running.filter(|thread| thread.id() == owner) {
+            let mut state = thread.fpu().lock();
+            if state.loaded_on == Some(cpu.index) {
+                unsafe { state.store() };
+            }
+        }
+        unsafe {
+            asm!("fninit", options(nomem, nostack, preserves_flags));
+            asm!("ldmxcsr [{}]", in(reg) &DEFAULT_MXCSR, options(nostack, preserves_flags));
+        }
+        let result = f();
+        set_task_switched(true);
+        result
+    })
+}
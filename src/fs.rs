@@ -0,0 +1,617 @@
+//! Filesystems and the VFS layer that ties them into one tree.
+//!
+//! A filesystem implements `FileSystem`, hands out `Inode`s for its files and directories, and is
+//! attached somewhere in the tree with `mount`. Everything else (the shell, user programs) goes
+//! through the path-based functions at the bottom of this module and the `File` handles `open`
+//! returns, without knowing which filesystem is behind a path. A ramfs is mounted at `/` during
+//! boot, so there is always somewhere to put data, with the kernel's own files in `/proc`. If the
+//! kernel was booted with an initrd, that's the root instead, read-only, with ramfs on `/tmp` and
+//! `/mnt` (see `initrd`). Virtio disks holding an SFS or FAT32 volume are mounted on
+//! `/mnt/vda`, `/mnt/vdb` and so on once the drivers have found them, each through a write-back
+//! `cache::BlockCache`.
+//!
+//! Filesystems keep the modification and change times up to date themselves. Access times are
+//! the VFS's job: reads through it record one by the relatime rule (see `needs_atime_update`).
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::rcu::Rcu;
+use crate::time;
+use crate::{println, warnln};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+
+pub mod cache;
+#[cfg(feature = "fat32")]
+pub mod fat32;
+pub mod initrd;
+#[cfg(feature = "p9")]
+pub mod p9;
+pub mod procfs;
+pub mod ramfs;
+#[cfg(feature = "sfs")]
+pub mod sfs;
+
+/// Errors shared by every filesystem implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    NameTooLong,
+    InvalidArgument,
+    NoSpace,
+    /// The filesystem refused the operation, e.g. a server's access checks
+    PermissionDenied,
+    /// The target is in use, e.g. a directory something is mounted on
+    Busy,
+    /// On-disk structures failed validation
+    Corrupted,
+    /// The underlying block device failed
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::NoSpace => FsError::NoSpace,
+            BlockError::Corrupted => FsError::Corrupted,
+            err => FsError::Block(err),
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => f.write_str("no such file or directory"),
+            FsError::AlreadyExists => f.write_str("file exists"),
+            FsError::NotADirectory => f.write_str("not a directory"),
+            FsError::IsADirectory => f.write_str("is a directory"),
+            FsError::DirectoryNotEmpty => f.write_str("directory not empty"),
+            FsError::NameTooLong => f.write_str("file name too long"),
+            FsError::InvalidArgument => f.write_str("invalid argument"),
+            FsError::NoSpace => f.write_str("no space left on device"),
+            FsError::PermissionDenied => f.write_str("permission denied"),
+            FsError::Busy => f.write_str("resource busy"),
+            FsError::Corrupted => f.write_str("filesystem is corrupted"),
+            FsError::Block(err) => write!(f, "block device: {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Number of the inode, unique within its filesystem
+    pub inode: u64,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Last access, in seconds since the Unix epoch
+    pub atime: u64,
+    /// Last change to the contents
+    pub mtime: u64,
+    /// Last change to the contents or the inode itself
+    pub ctime: u64,
+}
+
+/// One entry returned by `readdir`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: u64,
+    /// `None` if the entry records a kind this version doesn't know
+    pub kind: Option<FileKind>,
+    pub name: String,
+    /// Cursor that resumes `readdir` right after this entry
+    pub next: u64,
+}
+
+/// A mountable filesystem
+pub trait FileSystem: Send + Sync {
+    /// Short type name, e.g. "ramfs"
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes back anything cached
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file or directory of some filesystem.
+///
+/// Operations that only make sense for one kind fail with `NotADirectory` or `IsADirectory` on
+/// the other.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// Finds `name` in this directory
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError>;
+
+    /// Creates an empty file or directory called `name` in this directory
+    fn create(&self, name: &str, kind: FileKind) -> Result<Arc<dyn Inode>, FsError>;
+
+    /// Removes `name` from this directory; directories must be empty
+    fn remove(&self, name: &str) -> Result<(), FsError>;
+
+    /// Streams entries starting at cursor `position` (0 for the start) until `emit` returns
+    /// `false`, and returns the cursor to resume from, with the same guarantees as
+    /// `sfs::Sfs::readdir`. `.` and `..` are not reported.
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError>;
+
+    /// Reads at `offset`, returning how many bytes were read (0 at the end)
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes at `offset`, growing the file as needed
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Grows (with zeros) or shrinks the file to `size` bytes
+    fn truncate(&self, size: u64) -> Result<(), FsError>;
+
+    /// Sets the access and/or modification time, in seconds since the Unix epoch; a new
+    /// modification time moves the change time to now as well
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError>;
+}
+
+/// An access time older than this is refreshed on the next read even if the file didn't change
+const ATIME_REFRESH_SECS: u64 = 24 * 60 * 60;
+
+/// relatime: whether a read at `now` should be recorded as the last access, which only happens if
+/// the recorded one predates the last change or is a day old, so reads rarely cause a write
+pub fn needs_atime_update(metadata: &Metadata, now: u64) -> bool {
+    if metadata.atime == now {
+        return false; // timestamps have one-second resolution, nothing would change
+    }
+    metadata.atime <= metadata.mtime
+        || metadata.atime <= metadata.ctime
+        || now >= metadata.atime + ATIME_REFRESH_SECS
+}
+
+/// Records a read of `inode` if `needs_atime_update` says so. The read succeeded either way, so
+/// a filesystem that can't record it, like the read-only initrd, just keeps the time it has.
+fn accessed(inode: &dyn Inode) {
+    let now = time::now();
+    if inode
+        .metadata()
+        .is_ok_and(|metadata| needs_atime_update(&metadata, now))
+    {
+        let _ = inode.set_times(Some(now), None);
+    }
+}
+
+/// Where `File::seek` measures from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file: something to read and write sequentially
+pub trait File: Send {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, FsError>;
+
+    /// Moves the position and returns it, measured from the start
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError>;
+
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// The inode the file reads from, for mapping it into memory; `None` if it has none
+    fn inode(&self) -> Option<Arc<dyn Inode>> {
+        None
+    }
+
+    /// The path it was opened by, if it's known
+    fn path(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The `File` for regular files: an inode plus a position
+pub struct InodeFile {
+    inode: Arc<dyn Inode>,
+    position: u64,
+    path: Option<String>,
+}
+
+impl InodeFile {
+    pub fn new(inode: Arc<dyn Inode>) -> Self {
+        InodeFile {
+            inode,
+            position: 0,
+            path: None,
+        }
+    }
+}
+
+impl File for InodeFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let read = self.inode.read_at(self.position, buf)?;
+        self.position += read as u64;
+        accessed(&*self.inode);
+        Ok(read)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        let written = self.inode.write_at(self.position, data)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.metadata()?.size.checked_add_signed(delta),
+        }
+        .ok_or(FsError::InvalidArgument)?;
+        Ok(self.position)
+    }
+
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        self.inode.metadata()
+    }
+
+    fn inode(&self) -> Option<Arc<dyn Inode>> {
+        Some(self.inode.clone())
+    }
+
+    fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+}
+
+lazy_static! {
+    /// Filesystems by the absolute, normalized path they're mounted on. Every path lookup reads
+    /// it and almost nothing changes it, so it's RCU-protected rather than locked.
+    static ref MOUNTS: Rcu<BTreeMap<String, Arc<dyn FileSystem>>> = Rcu::new(BTreeMap::new());
+}
+
+/// Mounts the initrd, or else an empty ramfs, at `/` and procfs at `/proc`
+#[link_section = ".init.text"]
+pub fn init() {
+    if !mount_initrd() {
+        mount("/", Arc::new(ramfs::RamFs::new())).expect("failed to mount the root filesystem");
+    }
+    ensure_directory("/proc")
+        .and_then(|()| mount("/proc", Arc::new(procfs::ProcFs)))
+        .expect("failed to mount /proc");
+}
+
+/// Mounts the initrd at `/` with ramfs on the writable places, if the kernel has one that reads
+#[link_section = ".init.text"]
+fn mount_initrd() -> bool {
+    let Some(image) = initrd::image() else {
+        return false;
+    };
+    let initrd = match initrd::InitrdFs::new(image) {
+        Ok(initrd) => initrd,
+        Err(err) => {
+            warnln!("initrd: {}, booting without it", err);
+            return false;
+        }
+    };
+    println!(
+        "initrd: {} KiB, {} files and directories, mounted read-only at /",
+        image.len() / 1024,
+        initrd.node_count() - 1
+    );
+    mount("/", Arc::new(initrd)).expect("failed to mount the initrd");
+    for path in ["/mnt", "/tmp"] {
+        mount(path, Arc::new(ramfs::RamFs::new())).expect("failed to mount a ramfs on the initrd");
+    }
+    true
+}
+
+/// Creates the directory `path` unless it's there already
+fn ensure_directory(path: &str) -> Result<(), FsError> {
+    match create(path, FileKind::Directory) {
+        Err(FsError::AlreadyExists) => Ok(()),
+        result => result.map(drop),
+    }
+}
+
+/// Splits an absolute path into its components, resolving `.` and `..` lexically
+fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidArgument);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+fn join(components: &[&str]) -> String {
+    let mut path = String::from("/");
+    path.push_str(&components.join("/"));
+    path
+}
+
+/// Resolves already split components through the mount table
+fn walk(components: &[&str]) -> Result<Arc<dyn Inode>, FsError> {
+    // the deepest mount that's a prefix of the path wins
+    let (depth, fs) = MOUNTS.read(|mounts| {
+        (0..=components.len())
+            .rev()
+            .find_map(|depth| {
+                mounts
+                    .get(&join(&components[..depth]))
+                    .map(|fs| (depth, fs.clone()))
+            })
+            .ok_or(FsError::NotFound)
+    })?;
+    let mut inode = fs.root();
+    for name in &components[depth..] {
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/// Splits off the last component, which must exist (the root has no parent)
+fn split_parent(path: &str) -> Result<(Vec<&str>, &str), FsError> {
+    let mut components = components(path)?;
+    let name = components.pop().ok_or(FsError::InvalidArgument)?;
+    Ok((components, name))
+}
+
+/// Attaches `fs` at `path`, which must be `/` or an existing directory nothing is mounted on yet
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let components = components(path)?;
+    if !components.is_empty() && walk(&components)?.metadata()?.kind != FileKind::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let path = join(&components);
+    MOUNTS.try_update(|mounts| {
+        if mounts.contains_key(&path) {
+            return Err(FsError::Busy);
+        }
+        mounts.insert(path, fs);
+        Ok(())
+    })
+}
+
+/// Detaches whatever is mounted at `path` after syncing it; fails with `Busy` while something is
+/// mounted below it
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = join(&components(path)?);
+    let prefix = if path == "/" {
+        path.clone()
+    } else {
+        path.clone() + "/"
+    };
+    MOUNTS.try_update(|mounts| {
+        if mounts
+            .keys()
+            .any(|mounted| *mounted != path && mounted.starts_with(&prefix))
+        {
+            return Err(FsError::Busy);
+        }
+        let fs = mounts.get(&path).ok_or(FsError::NotFound)?;
+        fs.sync()?;
+        mounts.remove(&path);
+        Ok(())
+    })
+}
+
+/// Writes back everything every mounted filesystem has cached, stopping at the first failure
+pub fn sync() -> Result<(), FsError> {
+    let filesystems: Vec<Arc<dyn FileSystem>> =
+        MOUNTS.read(|mounts| mounts.values().cloned().collect());
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
+
+/// Paths of the mount points and the type of filesystem mounted on each
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.read(|mounts| {
+        mounts
+            .iter()
+            .map(|(path, fs)| (path.clone(), fs.name()))
+            .collect()
+    })
+}
+
+/// Mounts the filesystem on the block device called `device` at `path`, trying each disk format
+/// the kernel has until one recognizes the volume, and returns the format's name
+pub fn mount_device(device: &str, path: &str) -> Result<&'static str, FsError> {
+    let fs = recognize(device)?;
+    let name = fs.name();
+    mount(path, fs)?;
+    Ok(name)
+}
+
+/// The filesystem on the block device called `device`; `InvalidArgument` if it's in no format
+/// the kernel knows
+fn recognize(device: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let device = block::device(device).ok_or(FsError::NotFound)?;
+    FORMATS
+        .iter()
+        .find_map(|format| format(device.clone()).ok())
+        .ok_or(FsError::InvalidArgument)
+}
+
+/// Mounts each virtio disk holding a filesystem on `/mnt/<device>`; disks without one are left
+/// alone, they may be raw storage or encrypted
+#[link_section = ".init.text"]
+pub fn mount_disks() {
+    for device in block::device_names() {
+        if !device.starts_with("vd") {
+            continue;
+        }
+        let Ok(fs) = recognize(&device) else {
+            continue;
+        };
+        let name = fs.name();
+        let path = format!("/mnt/{}", device);
+        match ensure_directory("/mnt")
+            .and_then(|()| ensure_directory(&path))
+            .and_then(|()| mount(&path, fs))
+        {
+            Ok(()) => println!("fs: {} ({}) mounted on {}", device, name, path),
+            Err(err) => warnln!("fs: couldn't mount {} on {}: {}", device, path, err),
+        }
+    }
+}
+
+/// Mounts a volume if it's in one particular disk format, and fails otherwise
+type Format = fn(Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError>;
+
+/// The disk formats `mount_device` tries, in order. Each volume sits on a `BlockCache` of its
+/// own, which the filesystem's `sync` writes back,, so `sync` and `power::shutdown` reach the disk.
+static FORMATS: &[Format] = &[
+    #[cfg(feature = "sfs")]
+    |device| Ok(Arc::new(sfs::SfsMount::mount(cached(device))?)),
+    #[cfg(feature = "fat32")]
+    |device| Ok(Arc::new(fat32::Fat32::mount(cached(device))?)),
+];
+
+/// `device` behind a write-back cache of the default size
+#[cfg(any(feature = "sfs", feature = "fat32"))]
+fn cached(device: Arc<dyn BlockDevice>) -> cache::BlockCache<Arc<dyn BlockDevice>> {
+    cache::BlockCache::new(device, cache::DEFAULT_CAPACITY)
+}
+
+/// Finds the inode at absolute path `path`
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    walk(&components(path)?)
+}
+
+/// Creates an empty file or directory at `path`; its parent must exist
+pub fn create(path: &str, kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+    let (parent, name) = split_parent(path)?;
+    walk(&parent)?.create(name, kind)
+}
+
+/// Removes the file or empty directory at `path`
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (parent, name) = split_parent(path)?;
+    let mut target = parent.clone();
+    target.push(name);
+    if MOUNTS.read(|mounts| mounts.contains_key(&join(&target))) {
+        return Err(FsError::Busy);
+    }
+    walk(&parent)?.remove(name)
+}
+
+/// Opens the file at `path` for reading and writing. A directory opens too, for listing it
+/// through `File::inode`; reading or writing it fails with `IsADirectory`.
+pub fn open(path: &str) -> Result<Box<dyn File>, FsError> {
+    let inode = lookup(path)?;
+    let mut file = InodeFile::new(inode);
+    file.path = Some(join(&components(path)?));
+    Ok(Box::new(file))
+}
+
+/// The whole contents of the regular file at `path`
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    let inode = lookup(path)?;
+    let metadata = inode.metadata()?;
+    if metadata.kind == FileKind::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    let mut data = vec![0; metadata.size as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        match inode.read_at(filled as u64, &mut data[filled..])? {
+            0 => break, // shrank while we were reading
+            read => filled += read,
+        }
+    }
+    data.truncate(filled);
+    accessed(&*inode);
+    Ok(data)
+}
+
+/// Replaces the contents of the regular file at `path` with `data`, creating the file if it
+/// doesn't exist
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) => create(path, FileKind::File)?,
+        Err(err) => return Err(err),
+    };
+    if inode.metadata()?.kind == FileKind::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    inode.truncate(0)?;
+    let mut written = 0;
+    while written < data.len() {
+        written += inode.write_at(written as u64, &data[written..])?;
+    }
+    Ok(())
+}
+
+/// Sets the access and/or modification time of the file or directory at `path`, see
+/// `Inode::set_times`
+pub fn set_times(path: &str, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError> {
+    lookup(path)?.set_times(atime, mtime)
+}
+
+/// Every entry of the directory at `path`
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dir = lookup(path)?;
+    let mut entries = Vec::new();
+    dir.readdir(0, &mut |entry| {
+        entries.push(entry);
+        true
+    })?;
+    Ok(entries)
+}
+
+/// `mount`: lists the mount points, or mounts a block device: `mount [<device> <path>]`
+pub fn mount_command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for (path, name) in mounts() {
+                println!("{:<20} {}", path, name);
+            }
+        }
+        [device, path] => match mount_device(device, path) {
+            Ok(name) => println!("mount: {} ({}) on {}", device, name, path),
+            Err(FsError::InvalidArgument) => {
+                println!("mount: {}: no filesystem recognized", device)
+            }
+            Err(err) => println!("mount: {}: {}", device, err),
+        },
+        _ => return Err("usage: mount [<device> <path>]"),
+    }
+    Ok(())
+}
+
+/// `umount <path>`: writes back and detaches the filesystem mounted at `path`
+pub fn umount_command(args: &[&str]) -> Result<(), &'static str> {
+    let [path] = args else {
+        return Err("usage: umount <path>");
+    };
+    if let Err(err) = unmount(path) {
+        println!("umount: {}: {}", path, err);
+    }
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! A write-back block cache to sit between a filesystem and its `BlockDevice`.
+//!
+//! `BlockCache` is itself a `BlockDevice`, so it slots in wherever a filesystem takes one:
+//! `Fat32::mount(BlockCache::new(device, 1024))`. Reads are served from memory once a block has
+//! been seen; writes only touch memory and mark the block dirty. Dirty blocks reach the device
+//! when they're evicted to make room (least recently used first) or on `sync`, which
+//! `BlockDevice::flush` maps to, so a filesystem's own `sync` writes everything back.
+//!
+//! A cache is also a `Shrinker`: one that lives for good can be `memory::pressure::register`ed,
+//! and then gives up its clean blocks, least recently used first, when memory runs short.
+
+use crate::block::{check_request, BlockDevice, BlockError};
+use crate::memory::pressure::{Level, Shrinker};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use spin::Mutex;
+
+/// Blocks kept when the caller has no better idea; 128 KiB with 512-byte blocks
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Counters for judging whether a cache is the right size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty blocks written to the device, on eviction or sync
+    pub writebacks: u64,
+    /// Blocks currently cached
+    pub cached: usize,
+    /// Cached blocks not yet written back
+    pub dirty: usize,
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Key of this block in `Inner::lru`
+    last_used: u64,
+}
+
+struct Inner {
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// Block numbers by the time they were last used, oldest first
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Inner {
+    /// Marks `block` as the most recently used
+    fn touch(&mut self, block: u64) {
+        self.clock += 1;
+        let cached = self.blocks.get_mut(&block).unwrap();
+        self.lru.remove(&cached.last_used);
+        cached.last_used = self.clock;
+        self.lru.insert(self.clock, block);
+    }
+}
+
+pub struct BlockCache<D: BlockDevice> {
+    device: D,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    /// Caches up to `capacity` blocks of `device` (at least one)
+    pub fn new(device: D, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            cached: inner.blocks.len(),
+            dirty: inner.blocks.values().filter(|cached| cached.dirty).count(),
+            ..inner.stats
+        }
+    }
+
+    /// Writes every dirty block back, in block order, then flushes the device
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        let mut written = 0;
+        for (&block, cached) in inner.blocks.iter_mut().filter(|(_, cached)| cached.dirty) {
+            self.device.write_block(block, &cached.data)?;
+            cached.dirty = false;
+            written += 1;
+        }
+        inner.stats.writebacks += written;
+        drop(inner);
+        self.device.flush()
+    }
+
+    /// Forgets every clean block, e.g. after the device was changed behind the cache's back
+    pub fn drop_clean(&self) {
+        let mut inner = self.inner.lock();
+        let Inner { blocks, lru, .. } = &mut *inner;
+        blocks.retain(|_, cached| {
+            if !cached.dirty {
+                lru.remove(&cached.last_used);
+            }
+            cached.dirty
+        });
+    }
+
+    /// Syncs and hands back the device
+    pub fn into_inner(self) -> Result<D, BlockError> {
+        self.sync()?;
+        Ok(self.device)
+    }
+
+    /// Makes room for one more block, writing the least recently used one back if it's dirty
+    fn evict(&self, inner: &mut Inner) -> Result<(), BlockError> {
+        while inner.blocks.len() >= self.capacity {
+            let (&stamp, &block) = inner.lru.iter().next().unwrap();
+            let cached = &inner.blocks[&block];
+            if cached.dirty {
+                // on failure the block stays cached and dirty, so nothing is lost
+                self.device.write_block(block, &cached.data)?;
+                inner.stats.writebacks += 1;
+            }
+            inner.lru.remove(&stamp);
+            inner.blocks.remove(&block);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, inner: &mut Inner, block: u64, data: Box<[u8]>, dirty: bool) {
+        inner.blocks.insert(
+            block,
+            CachedBlock {
+                data,
+                dirty,
+                last_used: 0,
+            },
+        );
+        inner.touch(block);
+    }
+}
+
+impl<D: BlockDevice> Shrinker for BlockCache<D> {
+    fn name(&self) -> &'static str {
+        "block cache"
+    }
+
+    fn count(&self) -> usize {
+        self.inner.try_lock().map_or(0, |inner| {
+            let clean = inner.blocks.values().filter(|cached| !cached.dirty).count();
+            clean * self.device.block_size()
+        })
+    }
+
+    /// Drops clean blocks, least recently used first; dirty ones stay, as writing them back could
+    /// take the device a while
+    fn shrink(&self, _level: Level, target: usize) -> usize {
+        let Some(mut inner) = self.inner.try_lock() else {
+            return 0;
+        };
+        let Inner { blocks, lru, .. } = &mut *inner;
+        let mut freed = 0;
+        while freed < target {
+            let Some((&stamp, &block)) = lru.iter().find(|(_, block)| !blocks[block].dirty) else {
+                break;
+            };
+            lru.remove(&stamp);
+            if let Some(cached) = blocks.remove(&block) {
+                freed += cached.data.len();
+            }
+        }
+        freed
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let mut inner = self.inner.lock();
+        if let Some(cached) = inner.blocks.get(&block) {
+            buf.copy_from_slice(&cached.data);
+            inner.stats.hits += 1;
+            inner.touch(block);
+            return Ok(());
+        }
+        inner.stats.misses += 1;
+        self.evict(&mut inner)?;
+        let mut data = vec![0; buf.len()].into_boxed_slice();
+        self.device.read_block(block, &mut data)?;
+        buf.copy_from_slice(&data);
+        self.insert(&mut inner, block, data, false);
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buf.len())?;
+        let mut inner = self.inner.lock();
+        if let Some(cached) = inner.blocks.get_mut(&block) {
+            cached.data.copy_from_slice(buf);
+            cached.dirty = true;
+            inner.touch(block);
+            return Ok(());
+        }
+        // a whole-block write needs nothing from the device, so it doesn't count as a miss
+        self.evict(&mut inner)?;
+        self.insert(&mut inner, block, buf.into(), true);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()
+    }
+}
@@ -0,0 +1,1060 @@
+//! FAT32, the interchange format for moving files between the host and the kernel.
+//!
+//! Supports long (VFAT) file names, nested directories, and reading, writing, growing and
+//! shrinking files. New entries whose name isn't a plain 8.3 name get a generated short alias
+//! (`LONGFI~1.TXT`) next to the long name, like Windows does. The volume's sector size must equal
+//! the device block size.
+//!
+//! FAT has no inode numbers; a file is identified by the position of its directory entry, which
+//! also serves as `Metadata::inode`. Timestamps are taken to be UTC, and last-access dates are
+//! only set on creation so that reads never write.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode, Metadata};
+use crate::block::BlockDevice;
+use crate::endian::{read_u16_le, read_u32_le, write_u16_le, write_u32_le};
+use crate::rtc::DateTime;
+use crate::time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+use layout::{
+    ATTR_ARCHIVE, ATTR_DIRECTORY, END_OF_CHAIN, FIRST_CLUSTER, FSINFO_FREE_COUNT,
+    FSINFO_LEAD_SIGNATURE, FSINFO_NEXT_FREE, FSINFO_STRUCT_OFFSET, FSINFO_STRUCT_SIGNATURE,
+    MIN_CLUSTERS,
+};
+use names::{
+    exact_short_name, generate_short_name, is_forbidden_char, long_entry_count,
+    short_name_checksum, unix_to_fat, write_long_entries, ATTR_LONG_NAME, ENTRY_SIZE, LFN_CHARS,
+    LFN_CHAR_OFFSETS, LFN_LAST, LOWERCASE_BASE, LOWERCASE_EXT, SLOT_FREE, SLOT_KANJI_E5,
+};
+use spin::Mutex;
+
+mod layout;
+mod names;
+
+pub use names::MAX_NAME_LEN;
+
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+/// FAT values at or above this end a chain
+const END_OF_CHAIN_MIN: u32 = 0x0FFF_FFF8;
+
+const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+/// First name byte of the entry after the last one in use
+const SLOT_END: u8 = 0x00;
+
+const LFN_ORDER_MASK: u8 = 0x1F;
+
+/// `Metadata::inode` of the root directory, which has no entry; no real entry can sit at
+/// index 1 because sector 0 is the boot sector
+pub const ROOT_INODE: u64 = 1;
+
+// --- timestamps ----------------------------------------------------------------------------
+
+/// Seconds since the Unix epoch at a FAT (date, time) pair; `names::unix_to_fat` goes the other way
+fn fat_to_unix(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0x0F) as u8,
+        day: (date & 0x1F) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    }
+    .to_unix_seconds()
+}
+
+// --- names ---------------------------------------------------------------------------------
+
+/// The short name as it is usually shown: `NAME.EXT`, lowercased where the NT flags say so
+fn display_short_name(short: &[u8; 11], nt_flags: u8) -> String {
+    let mut name = String::new();
+    let part = |bytes: &[u8], lower: bool, name: &mut String| {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte = if i == 0 && byte == SLOT_KANJI_E5 {
+                SLOT_FREE
+            } else {
+                byte
+            };
+            let c = if lower {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            };
+            name.push(c as char);
+        }
+    };
+    let base = trim_spaces(&short[..8]);
+    let ext = trim_spaces(&short[8..]);
+    part(base, nt_flags & LOWERCASE_BASE != 0, &mut name);
+    if !ext.is_empty() {
+        name.push('.');
+        part(ext, nt_flags & LOWERCASE_EXT != 0, &mut name);
+    }
+    name
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    &bytes[..len]
+}
+
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.chars().any(is_forbidden_char) {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+// --- directory entries ---------------------------------------------------------------------
+
+/// A decoded short entry with the long name that preceded it, if any
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Offset of the first slot belonging to the entry (its first long name slot)
+    start: u64,
+    /// Offset of the short entry itself, which holds everything but the long name
+    offset: u64,
+    name: String,
+    short: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+}
+
+impl Entry {
+    fn kind(&self) -> FileKind {
+        if self.attributes & ATTR_DIRECTORY != 0 {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        }
+    }
+
+    fn is_dot(&self) -> bool {
+        &self.short == b".          " || &self.short == b"..         "
+    }
+}
+
+fn slot_first_cluster(slot: &[u8]) -> u32 {
+    (read_u16_le(slot, 20) as u32) << 16 | read_u16_le(slot, 26) as u32
+}
+
+fn set_slot_first_cluster(slot: &mut [u8], cluster: u32) {
+    write_u16_le(slot, 20, (cluster >> 16) as u16);
+    write_u16_le(slot, 26, cluster as u16);
+}
+
+/// Collects long name slots until the short entry they belong to shows up
+#[derive(Default)]
+struct LongNameParts {
+    units: Vec<u16>,
+    start: u64,
+    checksum: u8,
+    /// Sequence number of the slot expected next, 0 once complete
+    expected: u8,
+    valid: bool,
+}
+
+impl LongNameParts {
+    fn push(&mut self, offset: u64, slot: &[u8]) {
+        let order = slot[0] & LFN_ORDER_MASK;
+        if slot[0] & LFN_LAST != 0 {
+            self.units = vec![0xFFFF; order as usize * LFN_CHARS];
+            self.start = offset;
+            self.checksum = slot[13];
+            self.expected = order;
+            self.valid = order != 0;
+        } else if !self.valid || order != self.expected || slot[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+        if !self.valid || order == 0 {
+            return;
+        }
+        let base = (order as usize - 1) * LFN_CHARS;
+        for (i, &at) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.units[base + i] = read_u16_le(slot, at);
+        }
+        self.expected = order - 1;
+    }
+
+    /// The long name if it is complete and belongs to `short`
+    fn take(&mut self, short: &[u8; 11]) -> Option<(u64, String)> {
+        let complete = self.valid && self.expected == 0;
+        self.valid = false;
+        if !complete || short_name_checksum(short) != self.checksum {
+            return None;
+        }
+        let len = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.units.len());
+        let name = char::decode_utf16(self.units[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some((self.start, name))
+    }
+}
+
+// --- the volume ----------------------------------------------------------------------------
+
+struct Volume<D: BlockDevice> {
+    device: D,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    /// The only FAT in use when mirroring is disabled
+    active_fat: Option<u64>,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info_sector: Option<u64>,
+    free_count: u32,
+    next_free: u32,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    fn mount(device: D) -> Result<Self, FsError> {
+        let mut boot = vec![0; device.block_size()];
+        if boot.len() < 512 {
+            return Err(FsError::InvalidArgument);
+        }
+        device.read_block(0, &mut boot)?;
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(FsError::InvalidArgument);
+        }
+        let bytes_per_sector = read_u16_le(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = read_u16_le(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entry_count = read_u16_le(&boot, 17);
+        let fat_sectors_16 = read_u16_le(&boot, 22);
+        let total_sectors = match read_u16_le(&boot, 19) {
+            0 => read_u32_le(&boot, 32) as u64,
+            count => count as u64,
+        };
+        let fat_sectors = read_u32_le(&boot, 36) as u64;
+        let ext_flags = read_u16_le(&boot, 40);
+        let root_cluster = read_u32_le(&boot, 44);
+        let fs_info = read_u16_le(&boot, 48) as u64;
+
+        if bytes_per_sector != device.block_size() {
+            return Err(FsError::InvalidArgument);
+        }
+        if !sectors_per_cluster.is_power_of_two() || fat_count == 0 || reserved_sectors == 0 {
+            return Err(FsError::Corrupted);
+        }
+        // FAT12/16 keep the root directory outside the data area and have 16-bit FAT sizes
+        if root_entry_count != 0 || fat_sectors_16 != 0 || fat_sectors == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        let total_sectors = total_sectors.min(device.block_count());
+        let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+        let fat_entries = fat_sectors * bytes_per_sector as u64 / 4 - FIRST_CLUSTER as u64;
+        let cluster_count = clusters.min(fat_entries).min(BAD_CLUSTER as u64 - 2) as u32;
+        if clusters < MIN_CLUSTERS as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut volume = Volume {
+            device,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            active_fat: (ext_flags & 0x80 != 0).then_some((ext_flags & 0x0F) as u64),
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info_sector: None,
+            free_count: UNKNOWN,
+            next_free: FIRST_CLUSTER,
+        };
+        if !volume.is_data_cluster(root_cluster) {
+            return Err(FsError::Corrupted);
+        }
+
+        if fs_info != 0 && fs_info < reserved_sectors {
+            let mut info = vec![0; bytes_per_sector];
+            volume.device.read_block(fs_info, &mut info)?;
+            if read_u32_le(&info, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32_le(&info, FSINFO_STRUCT_OFFSET) == FSINFO_STRUCT_SIGNATURE
+            {
+                volume.fs_info_sector = Some(fs_info);
+                let free_count = read_u32_le(&info, FSINFO_FREE_COUNT);
+                if free_count <= cluster_count {
+                    volume.free_count = free_count;
+                }
+                let next_free = read_u32_le(&info, FSINFO_NEXT_FREE);
+                if volume.is_data_cluster(next_free) {
+                    volume.next_free = next_free;
+                }
+            }
+        }
+        Ok(volume)
+    }
+
+    /// Writes the free cluster hints back and flushes the device
+    fn sync(&self) -> Result<(), FsError> {
+        if let Some(sector) = self.fs_info_sector {
+            let mut info = vec![0; self.bytes_per_sector];
+            self.device.read_block(sector, &mut info)?;
+            write_u32_le(&mut info, FSINFO_FREE_COUNT, self.free_count);
+            write_u32_le(&mut info, FSINFO_NEXT_FREE, self.next_free);
+            self.device.write_block(sector, &info)?;
+        }
+        Ok(self.device.flush()?)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * self.bytes_per_sector as u64
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    // --- the allocation table -----------------------------------------------------------
+
+    /// Sector and byte offset of `cluster`'s entry in FAT copy `fat`
+    fn fat_position(&self, fat: u64, cluster: u32) -> (u64, usize) {
+        let byte = cluster as u64 * 4;
+        let sector = self.fat_start + fat * self.fat_sectors + byte / self.bytes_per_sector as u64;
+        (sector, (byte % self.bytes_per_sector as u64) as usize)
+    }
+
+    fn read_fat(&self, cluster: u32) -> Result<u32, FsError> {
+        let (sector, offset) = self.fat_position(self.active_fat.unwrap_or(0), cluster);
+        let mut buf = vec![0; self.bytes_per_sector];
+        self.device.read_block(sector, &mut buf)?;
+        Ok(read_u32_le(&buf, offset) & CLUSTER_MASK)
+    }
+
+    /// Sets `cluster`'s entry in every FAT copy in use, keeping the reserved top bits
+    fn write_fat(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let fats = match self.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.fat_count,
+        };
+        let mut buf = vec![0; self.bytes_per_sector];
+        for fat in fats {
+            let (sector, offset) = self.fat_position(fat, cluster);
+            self.device.read_block(sector, &mut buf)?;
+            let old = read_u32_le(&buf, offset);
+            write_u32_le(
+                &mut buf,
+                offset,
+                (old & !CLUSTER_MASK) | (value & CLUSTER_MASK),
+            );
+            self.device.write_block(sector, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, empty for 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            if !self.is_data_cluster(cluster) || chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+            cluster = match self.read_fat(cluster)? {
+                next if next >= END_OF_CHAIN_MIN => 0,
+                0 => return Err(FsError::Corrupted),
+                next => next,
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Finds a free cluster, marks it as the end of a chain and zeroes it
+    fn allocate_cluster(&mut self) -> Result<u32, FsError> {
+        let mut buf = vec![0; self.bytes_per_sector];
+        let mut loaded = None;
+        let start = self.next_free - FIRST_CLUSTER;
+        for i in 0..self.cluster_count {
+            let cluster = FIRST_CLUSTER + (start + i) % self.cluster_count;
+            let (sector, offset) = self.fat_position(self.active_fat.unwrap_or(0), cluster);
+            if loaded != Some(sector) {
+                self.device.read_block(sector, &mut buf)?;
+                loaded = Some(sector);
+            }
+            if read_u32_le(&buf, offset) & CLUSTER_MASK != 0 {
+                continue;
+            }
+            self.write_fat(cluster, END_OF_CHAIN)?;
+            let zero = vec![0; self.bytes_per_sector];
+            let first_sector = self.cluster_sector(cluster);
+            for sector in first_sector..first_sector + self.sectors_per_cluster {
+                self.device.write_block(sector, &zero)?;
+            }
+            self.next_free = if cluster + 1 < FIRST_CLUSTER + self.cluster_count {
+                cluster + 1
+            } else {
+                FIRST_CLUSTER
+            };
+            if self.free_count != UNKNOWN {
+                self.free_count = self.free_count.saturating_sub(1);
+            }
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Appends `count` fresh clusters to `chain`
+    fn extend_chain(&mut self, chain: &mut Vec<u32>, count: u64) -> Result<(), FsError> {
+        for _ in 0..count {
+            let cluster = self.allocate_cluster()?;
+            if let Some(&last) = chain.last() {
+                self.write_fat(last, cluster)?;
+            }
+            chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    fn free_clusters(&mut self, clusters: &[u32]) -> Result<(), FsError> {
+        for &cluster in clusters {
+            self.write_fat(cluster, 0)?;
+            if self.free_count != UNKNOWN {
+                self.free_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // --- data ---------------------------------------------------------------------------
+
+    /// Runs `f` on each sector-sized piece of `chain` from `offset` on, with the sector, the
+    /// offset within it and the position within the whole request
+    fn for_each_piece(
+        &self,
+        chain: &[u32],
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, usize, core::ops::Range<usize>) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let cluster_size = self.cluster_size();
+        let sector_size = self.bytes_per_sector as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(FsError::Corrupted)?;
+            let within = position % cluster_size;
+            let sector = self.cluster_sector(cluster) + within / sector_size;
+            let sector_offset = (within % sector_size) as usize;
+            let n = (self.bytes_per_sector - sector_offset).min(len - done);
+            f(sector, sector_offset, done..done + n)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut sector_buf = vec![0; self.bytes_per_sector];
+        self.for_each_piece(chain, offset, buf.len(), |sector, at, range| {
+            self.device.read_block(sector, &mut sector_buf)?;
+            buf[range.clone()].copy_from_slice(&sector_buf[at..at + range.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_data(&self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut sector_buf = vec![0; self.bytes_per_sector];
+        self.for_each_piece(chain, offset, data.len(), |sector, at, range| {
+            if range.len() == self.bytes_per_sector {
+                return Ok(self.device.write_block(sector, &data[range])?);
+            }
+            self.device.read_block(sector, &mut sector_buf)?;
+            sector_buf[at..at + range.len()].copy_from_slice(&data[range]);
+            Ok(self.device.write_block(sector, &sector_buf)?)
+        })
+    }
+
+    /// Makes `chain` long enough for `size` bytes
+    fn reserve(&mut self, chain: &mut Vec<u32>, size: u64) -> Result<(), FsError> {
+        let needed = size.div_ceil(self.cluster_size());
+        let have = chain.len() as u64;
+        if needed > have {
+            self.extend_chain(chain, needed - have)?;
+        }
+        Ok(())
+    }
+
+    // --- directories --------------------------------------------------------------------
+
+    /// Calls `f` with the offset and contents of every 32-byte slot of a directory from
+    /// `from` on, until it breaks or the chain ends
+    fn for_each_slot<R>(
+        &self,
+        chain: &[u32],
+        from: u64,
+        mut f: impl FnMut(u64, &[u8]) -> ControlFlow<R>,
+    ) -> Result<Option<R>, FsError> {
+        let end = chain.len() as u64 * self.cluster_size();
+        let mut sector_buf = vec![0; self.bytes_per_sector];
+        let mut offset = from - from % ENTRY_SIZE as u64;
+        while offset < end {
+            let mut sector = 0;
+            self.for_each_piece(chain, offset, 1, |s, _, _| {
+                sector = s;
+                Ok(())
+            })?;
+            self.device.read_block(sector, &mut sector_buf)?;
+            let mut at = (offset % self.bytes_per_sector as u64) as usize;
+            while at < self.bytes_per_sector {
+                if let ControlFlow::Break(result) = f(offset, &sector_buf[at..at + ENTRY_SIZE]) {
+                    return Ok(Some(result));
+                }
+                at += ENTRY_SIZE;
+                offset += ENTRY_SIZE as u64;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls `f` with every entry in use from `from` on (`.` and `..` included) until it
+    /// breaks, stopping at the end-of-directory marker
+    fn for_each_entry<R>(
+        &self,
+        chain: &[u32],
+        from: u64,
+        mut f: impl FnMut(Entry) -> ControlFlow<R>,
+    ) -> Result<Option<R>, FsError> {
+        let mut long_name = LongNameParts::default();
+        let result = self.for_each_slot(chain, from, |offset, slot| {
+            match slot[0] {
+                SLOT_END => return ControlFlow::Break(None),
+                SLOT_FREE => {
+                    long_name.valid = false;
+                    return ControlFlow::Continue(());
+                }
+                _ => {}
+            }
+            let attributes = slot[11];
+            if attributes & 0x3F == ATTR_LONG_NAME {
+                long_name.push(offset, slot);
+                return ControlFlow::Continue(());
+            }
+            let short: [u8; 11] = slot[..11].try_into().unwrap();
+            let long = long_name.take(&short);
+            if attributes & ATTR_VOLUME_ID != 0 {
+                return ControlFlow::Continue(());
+            }
+            let (start, name) =
+                long.unwrap_or_else(|| (offset, display_short_name(&short, slot[12])));
+            let entry = Entry {
+                start,
+                offset,
+                name,
+                short,
+                attributes,
+                first_cluster: slot_first_cluster(slot),
+            };
+            f(entry).map_break(Some)
+        })?;
+        Ok(result.flatten())
+    }
+
+    fn find(&self, chain: &[u32], name: &str) -> Result<Option<Entry>, FsError> {
+        self.for_each_entry(chain, 0, |entry| {
+            let short_name = display_short_name(&entry.short, 0);
+            if !entry.is_dot() && (eq_ignore_case(&entry.name, name) || short_name == name) {
+                ControlFlow::Break(entry)
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    }
+
+    /// Reads one slot
+    fn read_slot(&self, chain: &[u32], offset: u64) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let mut slot = [0; ENTRY_SIZE];
+        self.read_data(chain, offset, &mut slot)?;
+        Ok(slot)
+    }
+
+    /// Absolute index of the slot at `offset` on the volume, used as an inode number
+    fn slot_id(&self, chain: &[u32], offset: u64) -> Result<u64, FsError> {
+        let mut id = 0;
+        self.for_each_piece(chain, offset, 1, |sector, at, _| {
+            id = (sector * self.bytes_per_sector as u64 + at as u64) / ENTRY_SIZE as u64;
+            Ok(())
+        })?;
+        Ok(id)
+    }
+
+    /// Writes a new entry for `name` into the directory, growing it if there's no room, and
+    /// returns the offset of its short entry
+    fn add_entry(
+        &mut self,
+        chain: &mut Vec<u32>,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+    ) -> Result<u64, FsError> {
+        let (short, nt_flags, long) = match exact_short_name(name) {
+            Some((short, flags)) => (short, flags, None),
+            None => {
+                let mut taken = Vec::new();
+                self.for_each_entry(chain, 0, |entry| {
+                    taken.push(entry.short);
+                    ControlFlow::<()>::Continue(())
+                })?;
+                let short = generate_short_name(name, |candidate| taken.contains(candidate));
+                let units: Vec<u16> = name.encode_utf16().collect();
+                (short, 0, Some(units))
+            }
+        };
+        let long_slots = long.as_deref().map_or(0, long_entry_count);
+        let needed = long_slots + 1;
+
+        // first run of `needed` free slots; everything from the end marker on counts as free
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut past_end = false;
+        let found = self.for_each_slot(chain, 0, |offset, slot| {
+            past_end |= slot[0] == SLOT_END;
+            if past_end || slot[0] == SLOT_FREE {
+                if run_len == 0 {
+                    run_start = offset;
+                }
+                run_len += 1;
+                if run_len == needed {
+                    return ControlFlow::Break(run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+            ControlFlow::Continue(())
+        })?;
+        let start = match found {
+            Some(start) => start,
+            None => {
+                let end = chain.len() as u64 * self.cluster_size();
+                let start = if run_len > 0 { run_start } else { end };
+                self.reserve(chain, start + (needed * ENTRY_SIZE) as u64)?;
+                start
+            }
+        };
+
+        let mut slots = vec![0u8; needed * ENTRY_SIZE];
+        if let Some(units) = &long {
+            write_long_entries(units, &short, &mut slots);
+        }
+        let short_offset = long_slots * ENTRY_SIZE;
+        let (date, time) = unix_to_fat(time::now());
+        let slot = &mut slots[short_offset..];
+        slot[..11].copy_from_slice(&short);
+        slot[11] = attributes;
+        slot[12] = nt_flags;
+        write_u16_le(slot, 14, time);
+        write_u16_le(slot, 16, date);
+        write_u16_le(slot, 18, date);
+        write_u16_le(slot, 22, time);
+        write_u16_le(slot, 24, date);
+        set_slot_first_cluster(slot, first_cluster);
+        self.write_data(chain, start, &slots)?;
+        Ok(start + short_offset as u64)
+    }
+
+    /// Marks every slot of `entry` as deleted
+    fn remove_entry(&mut self, chain: &[u32], entry: &Entry) -> Result<(), FsError> {
+        let mut offset = entry.start;
+        while offset <= entry.offset {
+            self.write_data(chain, offset, &[SLOT_FREE])?;
+            offset += ENTRY_SIZE as u64;
+        }
+        Ok(())
+    }
+}
+
+// --- VFS glue ------------------------------------------------------------------------------
+
+/// A mounted FAT32 volume
+pub struct Fat32<D: BlockDevice> {
+    volume: Arc<Mutex<Volume<D>>>,
+}
+
+impl<D: BlockDevice + 'static> Fat32<D> {
+    /// Mounts the volume on `device`; `InvalidArgument` if it isn't FAT32
+    pub fn mount(device: D) -> Result<Self, FsError> {
+        Ok(Fat32 {
+            volume: Arc::new(Mutex::new(Volume::mount(device)?)),
+        })
+    }
+}
+
+impl<D: BlockDevice + 'static> FileSystem for Fat32<D> {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let root_cluster = self.volume.lock().root_cluster;
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: None,
+            kind: FileKind::Directory,
+            cluster: root_cluster,
+        })
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.lock().sync()
+    }
+}
+
+/// Where an entry lives: the directory holding it and the offset of its short entry there
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    directory: u32,
+    offset: u64,
+}
+
+struct FatInode<D: BlockDevice> {
+    volume: Arc<Mutex<Volume<D>>>,
+    /// `None` for the root directory
+    location: Option<Location>,
+    kind: FileKind,
+    /// First cluster of a directory, which never changes; files read theirs from the entry
+    cluster: u32,
+}
+
+impl<D: BlockDevice + 'static> FatInode<D> {
+    fn child(&self, directory: u32, entry: &Entry) -> Arc<dyn Inode> {
+        Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: Some(Location {
+                directory,
+                offset: entry.offset,
+            }),
+            kind: entry.kind(),
+            cluster: entry.first_cluster,
+        })
+    }
+
+    fn directory_chain(&self, volume: &Volume<D>) -> Result<Vec<u32>, FsError> {
+        match self.kind {
+            FileKind::Directory => volume.chain(self.cluster),
+            FileKind::File => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// This file's short entry, re-read so concurrent changes through other handles are seen
+    fn file_slot(&self, volume: &Volume<D>) -> Result<(Vec<u32>, [u8; ENTRY_SIZE]), FsError> {
+        let location = match (self.kind, self.location) {
+            (FileKind::File, Some(location)) => location,
+            _ => return Err(FsError::IsADirectory),
+        };
+        let parent = volume.chain(location.directory)?;
+        let slot = volume.read_slot(&parent, location.offset)?;
+        if slot[0] == SLOT_FREE || slot[0] == SLOT_END {
+            return Err(FsError::NotFound); // removed meanwhile
+        }
+        Ok((parent, slot))
+    }
+
+    /// Records a file's new first cluster and size and stamps the modification time
+    fn update_file_slot(
+        &self,
+        volume: &Volume<D>,
+        parent: &[u32],
+        mut slot: [u8; ENTRY_SIZE],
+        first_cluster: u32,
+        size: u64,
+    ) -> Result<(), FsError> {
+        let (date, time) = unix_to_fat(time::now());
+        set_slot_first_cluster(&mut slot, first_cluster);
+        write_u32_le(&mut slot, 28, size as u32);
+        write_u16_le(&mut slot, 22, time);
+        write_u16_le(&mut slot, 24, date);
+        slot[11] |= ATTR_ARCHIVE;
+        volume.write_data(parent, self.location.unwrap().offset, &slot)
+    }
+}
+
+impl<D: BlockDevice + 'static> Inode for FatInode<D> {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let volume = self.volume.lock();
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                return Ok(Metadata {
+                    inode: ROOT_INODE,
+                    kind: FileKind::Directory,
+                    size: 0,
+                    atime: 0,
+                    mtime: 0,
+                    ctime: 0,
+                })
+            }
+        };
+        let parent = volume.chain(location.directory)?;
+        let slot = volume.read_slot(&parent, location.offset)?;
+        let mtime = fat_to_unix(read_u16_le(&slot, 24), read_u16_le(&slot, 22));
+        Ok(Metadata {
+            inode: volume.slot_id(&parent, location.offset)?,
+            kind: self.kind,
+            size: match self.kind {
+                FileKind::File => read_u32_le(&slot, 28) as u64,
+                FileKind::Directory => 0,
+            },
+            atime: fat_to_unix(read_u16_le(&slot, 18), 0),
+            mtime,
+            // FAT has no change time; the modification time is the closest thing
+            ctime: mtime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let volume = self.volume.lock();
+        let chain = self.directory_chain(&volume)?;
+        let entry = volume.find(&chain, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.child(self.cluster, &entry))
+    }
+
+    fn create(&self, name: &str, kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+        validate_name(name)?;
+        let mut volume = self.volume.lock();
+        let mut chain = self.directory_chain(&volume)?;
+        if volume.find(&chain, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (attributes, first_cluster) = match kind {
+            FileKind::File => (ATTR_ARCHIVE, 0),
+            FileKind::Directory => {
+                let cluster = volume.allocate_cluster()?;
+                // `..` of a directory in the root points at cluster 0 by convention
+                let parent = match self.location {
+                    None => 0,
+                    Some(_) => self.cluster,
+                };
+                let mut dots = [0u8; 2 * ENTRY_SIZE];
+                let (date, time) = unix_to_fat(time::now());
+                for (i, (name, target)) in [(b".          ", cluster), (b"..         ", parent)]
+                    .iter()
+                    .enumerate()
+                {
+                    let slot = &mut dots[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+                    slot[..11].copy_from_slice(*name);
+                    slot[11] = ATTR_DIRECTORY;
+                    write_u16_le(slot, 14, time);
+                    write_u16_le(slot, 16, date);
+                    write_u16_le(slot, 22, time);
+                    write_u16_le(slot, 24, date);
+                    set_slot_first_cluster(slot, *target);
+                }
+                volume.write_data(&[cluster], 0, &dots)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+        };
+
+        let offset = match volume.add_entry(&mut chain, name, attributes, first_cluster) {
+            Ok(offset) => offset,
+            Err(err) => {
+                if first_cluster != 0 {
+                    volume.free_clusters(&[first_cluster])?;
+                }
+                return Err(err);
+            }
+        };
+        Ok(Arc::new(FatInode {
+            volume: self.volume.clone(),
+            location: Some(Location {
+                directory: self.cluster,
+                offset,
+            }),
+            kind,
+            cluster: first_cluster,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        let mut volume = self.volume.lock();
+        let chain = self.directory_chain(&volume)?;
+        let entry = volume.find(&chain, name)?.ok_or(FsError::NotFound)?;
+        let clusters = volume.chain(entry.first_cluster)?;
+        if entry.kind() == FileKind::Directory {
+            let has_entries = volume
+                .for_each_entry(&clusters, 0, |child| {
+                    if child.is_dot() {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(())
+                    }
+                })?
+                .is_some();
+            if has_entries {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        volume.remove_entry(&chain, &entry)?;
+        volume.free_clusters(&clusters)
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        let volume = self.volume.lock();
+        let chain = self.directory_chain(&volume)?;
+        let mut failed = None;
+        let stopped = volume.for_each_entry(&chain, position, |entry| {
+            if entry.is_dot() {
+                return ControlFlow::Continue(());
+            }
+            let inode = match volume.slot_id(&chain, entry.offset) {
+                Ok(inode) => inode,
+                Err(err) => {
+                    failed = Some(err);
+                    return ControlFlow::Break(entry.start);
+                }
+            };
+            let next = entry.offset + ENTRY_SIZE as u64;
+            let dir_entry = DirEntry {
+                inode,
+                kind: Some(entry.kind()),
+                name: entry.name,
+                next,
+            };
+            if emit(dir_entry) {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(entry.start)
+            }
+        })?;
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        let end = chain.len() as u64 * volume.cluster_size();
+        Ok(stopped.unwrap_or(end.max(position)))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let volume = self.volume.lock();
+        let (_, slot) = self.file_slot(&volume)?;
+        let size = read_u32_le(&slot, 28) as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(size - offset) as usize;
+        let chain = volume.chain(slot_first_cluster(&slot))?;
+        volume.read_data(&chain, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut volume = self.volume.lock();
+        let (parent, slot) = self.file_slot(&volume)?;
+        let size = read_u32_le(&slot, 28) as u64;
+        let end = offset + data.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace); // FAT file sizes are 32-bit
+        }
+        let mut chain = volume.chain(slot_first_cluster(&slot))?;
+        let reserved = volume.reserve(&mut chain, end);
+        // whatever got allocated belongs to the file now, even if the volume filled up midway
+        let first_cluster = chain.first().copied().unwrap_or(0);
+        if let Err(err) = reserved {
+            self.update_file_slot(&volume, &parent, slot, first_cluster, size)?;
+            return Err(err);
+        }
+        if offset > size {
+            // the gap may hold stale data from before a shrink
+            let zeros = vec![0; (offset - size) as usize];
+            volume.write_data(&chain, size, &zeros)?;
+        }
+        volume.write_data(&chain, offset, data)?;
+        self.update_file_slot(&volume, &parent, slot, first_cluster, size.max(end))?;
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut volume = self.volume.lock();
+        let (parent, slot) = self.file_slot(&volume)?;
+        let old_size = read_u32_le(&slot, 28) as u64;
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let mut chain = volume.chain(slot_first_cluster(&slot))?;
+        if size > old_size {
+            volume.reserve(&mut chain, size)?;
+            let zeros = vec![0; (size - old_size) as usize];
+            volume.write_data(&chain, old_size, &zeros)?;
+        } else {
+            let keep = size.div_ceil(volume.cluster_size()) as usize;
+            if keep < chain.len() {
+                if keep > 0 {
+                    volume.write_fat(chain[keep - 1], END_OF_CHAIN)?;
+                }
+                let freed = chain.split_off(keep);
+                volume.free_clusters(&freed)?;
+            }
+        }
+        let first_cluster = chain.first().copied().unwrap_or(0);
+        self.update_file_slot(&volume, &parent, slot, first_cluster, size)
+    }
+
+    /// FAT keeps only the date of the last access. The root directory has no entry to keep
+    /// times in, so they're dropped there.
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError> {
+        let volume = self.volume.lock();
+        let Some(location) = self.location else {
+            return Ok(());
+        };
+        let parent = volume.chain(location.directory)?;
+        let mut slot = volume.read_slot(&parent, location.offset)?;
+        if slot[0] == SLOT_FREE || slot[0] == SLOT_END {
+            return Err(FsError::NotFound); // removed meanwhile
+        }
+        let original = slot;
+        if let Some(atime) = atime {
+            write_u16_le(&mut slot, 18, unix_to_fat(atime).0);
+        }
+        if let Some(mtime) = mtime {
+            let (date, time) = unix_to_fat(mtime);
+            write_u16_le(&mut slot, 22, time);
+            write_u16_le(&mut slot, 24, date);
+        }
+        if slot == original {
+            return Ok(()); // an access on a day already recorded, most often
+        }
+        volume.write_data(&parent, location.offset, &slot)
+    }
+}
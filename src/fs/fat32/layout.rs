@@ -0,0 +1,21 @@
+//! The FAT32 on-disk values that formatting a volume and mounting it both need: cluster numbers,
+//! entry attributes and the FSInfo sector.
+//!
+//! Shared with `tools/mkfs`, which includes this file like `names`; it depends on nothing at all.
+
+/// FAT32 needs at least this many clusters, fewer means FAT12/16
+pub const MIN_CLUSTERS: u32 = 65_525;
+/// Number of the first data cluster; FAT entries 0 and 1 are reserved
+pub const FIRST_CLUSTER: u32 = 2;
+pub const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+pub const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+/// Where the FSInfo sector has its second signature, and the free cluster count and next free
+/// cluster hints after it
+pub const FSINFO_STRUCT_OFFSET: usize = 484;
+pub const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+pub const FSINFO_FREE_COUNT: usize = 488;
+pub const FSINFO_NEXT_FREE: usize = 492;
+
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
@@ -0,0 +1,205 @@
+//! How FAT32 names and stamps a new directory entry: 8.3 short names, `~N` aliases for long names,
+//! the long name entries with their checksum, and packed dates.
+//!
+//! Shared with `tools/mkfs`, which includes this file, so images name files exactly the way the
+//! kernel would. It depends on nothing but `core` for that reason.
+
+/// Bytes in a directory entry, short or long
+pub const ENTRY_SIZE: usize = 32;
+
+/// Attributes of a long name entry: read-only, hidden, system and volume ID together
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a deleted entry
+pub const SLOT_FREE: u8 = 0xE5;
+/// Stands in for a leading 0xE5 in a name, which would read as a deleted entry
+pub const SLOT_KANJI_E5: u8 = 0x05;
+
+/// NT reserved byte flags that mark an all-lowercase base name or extension
+pub const LOWERCASE_BASE: u8 = 0x08;
+pub const LOWERCASE_EXT: u8 = 0x10;
+
+/// Order byte flag of the long name entry holding the end of the name, which comes first
+pub const LFN_LAST: u8 = 0x40;
+pub const LFN_CHARS: usize = 13;
+/// Where the 13 UCS-2 characters of a long name entry live
+pub const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest name, in UTF-16 units
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Whether `c` can't appear in a name at all
+pub fn is_forbidden_char(c: char) -> bool {
+    (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)
+}
+
+/// The checksum of the short name that each of its long name entries carries
+pub fn short_name_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Characters allowed in short names besides letters and digits
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c) || c >= 0x80
+}
+
+/// Case of one part of a candidate 8.3 name: `Some(true)` all lowercase, `Some(false)` no
+/// lowercase, `None` mixed (which short names can't express)
+fn uniform_case(part: &str) -> Option<bool> {
+    let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+    let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+    match (lower, upper) {
+        (true, true) => None,
+        (lower, _) => Some(lower),
+    }
+}
+
+/// The short name and NT flags if `name` can be stored as a plain 8.3 name without a long name
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || base.contains('.') {
+        return None;
+    }
+    let valid = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii() && is_short_name_char(b.to_ascii_uppercase()))
+    };
+    if !valid(base) || !valid(ext) || (name.ends_with('.') && ext.is_empty() && name != base) {
+        return None;
+    }
+    let mut flags = 0;
+    if uniform_case(base)? {
+        flags |= LOWERCASE_BASE;
+    }
+    if uniform_case(ext)? {
+        flags |= LOWERCASE_EXT;
+    }
+    let mut short = [b' '; 11];
+    for (slot, byte) in short.iter_mut().zip(base.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    for (slot, byte) in short[8..].iter_mut().zip(ext.bytes()) {
+        *slot = byte.to_ascii_uppercase();
+    }
+    if short[0] == SLOT_FREE {
+        short[0] = SLOT_KANJI_E5;
+    }
+    Some((short, flags))
+}
+
+/// Up to `N` characters of `part` for a short name, and how many there are: spaces and dots
+/// dropped, letters uppercased, and `_` for what a short name can't hold
+fn sanitize<const N: usize>(part: &str) -> ([u8; N], usize) {
+    let mut out = [0; N];
+    let mut len = 0;
+    for c in part.chars().filter(|&c| c != ' ' && c != '.').take(N) {
+        out[len] = match u8::try_from(c) {
+            Ok(b) if b.is_ascii() && is_short_name_char(b.to_ascii_uppercase()) => {
+                b.to_ascii_uppercase()
+            }
+            _ => b'_',
+        };
+        len += 1;
+    }
+    (out, len)
+}
+
+/// A `BASIS~N.EXT` alias for a long name that isn't taken according to `taken`
+pub fn generate_short_name(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> [u8; 11] {
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    let (mut basis, mut basis_len) = sanitize::<8>(base);
+    if basis_len == 0 {
+        basis[0] = b'_';
+        basis_len = 1;
+    }
+    let (ext, ext_len) = sanitize::<3>(ext);
+
+    let mut short = [b' '; 11];
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1u32.. {
+        let mut tail = [0u8; 10];
+        let mut digits = n;
+        let mut len = 0;
+        while digits > 0 {
+            tail[len] = b'0' + (digits % 10) as u8;
+            digits /= 10;
+            len += 1;
+        }
+        let keep = basis_len.min(8 - 1 - len);
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep] = b'~';
+        for i in 0..len {
+            short[keep + 1 + i] = tail[len - 1 - i];
+        }
+        if !taken(&short) {
+            break;
+        }
+    }
+    short
+}
+
+/// Long name entries a name of `units` UTF-16 units takes
+pub fn long_entry_count(units: &[u16]) -> usize {
+    units.len().div_ceil(LFN_CHARS)
+}
+
+/// Fills `slots`, `long_entry_count(units)` entries, with the long name entries for `units`, in
+/// the order they go before the short entry `short`
+pub fn write_long_entries(units: &[u16], short: &[u8; 11], slots: &mut [u8]) {
+    let count = long_entry_count(units);
+    let checksum = short_name_checksum(short);
+    for (i, slot) in slots.chunks_exact_mut(ENTRY_SIZE).take(count).enumerate() {
+        let order = (count - i) as u8;
+        slot[0] = order | if i == 0 { LFN_LAST } else { 0 };
+        slot[11] = ATTR_LONG_NAME;
+        slot[13] = checksum;
+        let base = (order as usize - 1) * LFN_CHARS;
+        for (j, &at) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            let unit = match units.get(base + j) {
+                Some(&unit) => unit,
+                None if base + j == units.len() => 0x0000,
+                None => 0xFFFF,
+            };
+            slot[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+}
+
+/// The (date, time) pair FAT stores for `seconds` since the Unix epoch, clamped to the years it
+/// can represent
+pub fn unix_to_fat(seconds: u64) -> (u16, u16) {
+    // civil_from_days, as in `rtc::DateTime::from_unix_seconds`, which mkfs can't reach
+    let days = (seconds / 86_400) as i64;
+    let second_of_day = seconds % 86_400;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    if year < 1980 {
+        return (1 << 5 | 1, 0); // 1980-01-01
+    }
+    let date = ((year - 1980).min(127) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let hour = second_of_day / 3600;
+    let minute = second_of_day / 60 % 60;
+    let time = (hour as u16) << 11 | (minute as u16) << 5 | (second_of_day % 60 / 2) as u16;
+    (date, time)
+}
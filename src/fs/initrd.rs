@@ -0,0 +1,414 @@
+//! initrd: an archive of files the loader put in memory, mounted read-only at `/` in place of the
+//! empty ramfs, so there are programs to run before any disk driver is up.
+//!
+//! The archive comes from the loader, if it passed one (see `bootinfo`), or else from the
+//! fw_cfg file `opt/rust_os/initrd`, which works with the `bootloader` crate too
+//! (`-fw_cfg name=opt/rust_os/initrd,file=initrd.cpio`). Like any initramfs it has to pass
+//! `signing::verify` first. Two formats are read, told apart by their first bytes:
+//!
+//! - cpio in the "new ASCII" format Linux uses (`find . | cpio -o -H newc`)
+//! - POSIX ustar tar (`tar --format=ustar -cf initrd.tar -C root .`)
+//!
+//! Regular files and directories are kept, anything else (links, devices) skipped. The files
+//! are read where the archive lies, nothing is copied. `MOUNT_POINTS` are always there, made
+//! empty if the archive lacks them, so `/proc` and the writable ramfs on `/tmp` and `/mnt` have
+//! somewhere to go.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode, Metadata};
+use crate::bootinfo;
+use crate::drivers::fw_cfg;
+use crate::memory::phys_to_virt;
+use crate::signing::{self, ArtifactKind};
+use crate::warnln;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Directories the root always has, for the filesystems `fs::init` mounts on top
+pub const MOUNT_POINTS: [&str; 3] = ["mnt", "proc", "tmp"];
+
+const CPIO_MAGIC: &[u8] = b"070701";
+/// The same with a checksum, which isn't checked
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// File type bits of a cpio mode
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+
+/// The archive the kernel was booted with, verified and without its signature; `None` if there's
+/// none or it was refused
+pub fn image() -> Option<&'static [u8]> {
+    let image: &'static [u8] = match bootinfo::get().and_then(|info| info.initrd) {
+        Some((start, size)) => unsafe {
+            core::slice::from_raw_parts(phys_to_virt(start).as_ptr(), size as usize)
+        },
+        None => fw_cfg::read_file("opt/rust_os/initrd")?.leak(),
+    };
+    match signing::verify(ArtifactKind::Initramfs, image) {
+        Ok(payload) => Some(payload),
+        Err(err) => {
+            warnln!("initrd: refused, {}", err);
+            None
+        }
+    }
+}
+
+/// One archive entry
+struct Entry {
+    /// Joined with `path` by a `/`; tar splits long paths in two
+    prefix: &'static str,
+    path: &'static str,
+    kind: FileKind,
+    data: &'static [u8],
+    mtime: u64,
+}
+
+/// A file or directory of the tree, by index
+struct Node {
+    kind: FileKind,
+    data: &'static [u8],
+    mtime: u64,
+    children: BTreeMap<String, usize>,
+}
+
+pub struct InitrdFs {
+    nodes: Arc<Vec<Node>>,
+}
+
+impl InitrdFs {
+    /// Reads the archive `image`, cpio or tar
+    pub fn new(image: &'static [u8]) -> Result<Self, FsError> {
+        let mut nodes = alloc::vec![Node {
+            kind: FileKind::Directory,
+            data: &[],
+            mtime: 0,
+            children: BTreeMap::new(),
+        }];
+        let mut add = |entry: Entry| insert(&mut nodes, entry);
+        if image.starts_with(CPIO_MAGIC) || image.starts_with(CPIO_CRC_MAGIC) {
+            parse_cpio(image, &mut add)?;
+        } else if image.get(257..262) == Some(TAR_MAGIC) {
+            parse_tar(image, &mut add)?;
+        } else {
+            return Err(FsError::Corrupted);
+        }
+        for name in MOUNT_POINTS {
+            if !nodes[0].children.contains_key(name) {
+                let mtime = nodes[0].mtime;
+                insert(
+                    &mut nodes,
+                    Entry {
+                        prefix: "",
+                        path: name,
+                        kind: FileKind::Directory,
+                        data: &[],
+                        mtime,
+                    },
+                )?;
+            }
+        }
+        Ok(InitrdFs {
+            nodes: Arc::new(nodes),
+        })
+    }
+
+    /// Number of files and directories, the root included
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+/// Puts `entry` into the tree, making the directories on its path that the archive didn't list
+/// before it
+fn insert(nodes: &mut Vec<Node>, entry: Entry) -> Result<(), FsError> {
+    let mut components = entry
+        .prefix
+        .split('/')
+        .chain(entry.path.split('/'))
+        .filter(|component| !component.is_empty() && *component != ".")
+        .peekable();
+    let mut parent = 0;
+    while let Some(name) = components.next() {
+        if name == ".." || name.len() > super::ramfs::MAX_NAME_LEN {
+            return Err(FsError::Corrupted);
+        }
+        if nodes[parent].kind != FileKind::Directory {
+            return Err(FsError::Corrupted);
+        }
+        let last = components.peek().is_none();
+        let index = match nodes[parent].children.get(name) {
+            Some(&index) => index,
+            None => {
+                nodes.push(Node {
+                    kind: FileKind::Directory,
+                    data: &[],
+                    mtime: entry.mtime,
+                    children: BTreeMap::new(),
+                });
+                let index = nodes.len() - 1;
+                nodes[parent].children.insert(String::from(name), index);
+                index
+            }
+        };
+        if last {
+            let node = &mut nodes[index];
+            if node.kind == FileKind::Directory && !node.children.is_empty() {
+                if entry.kind != FileKind::Directory {
+                    return Err(FsError::Corrupted);
+                }
+            } else {
+                node.kind = entry.kind;
+                node.data = entry.data;
+            }
+            node.mtime = entry.mtime;
+        }
+        parent = index;
+    }
+    // "." itself, the archive's root
+    if parent == 0 {
+        nodes[0].mtime = entry.mtime;
+    }
+    Ok(())
+}
+
+/// Field `index` of a cpio header, 8 hex digits after the magic
+fn hex_field(header: &[u8], index: usize) -> Result<u32, FsError> {
+    let offset = 6 + index * 8;
+    core::str::from_utf8(&header[offset..offset + 8])
+        .ok()
+        .and_then(|text| u32::from_str_radix(text, 16).ok())
+        .ok_or(FsError::Corrupted)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn parse_cpio(
+    image: &'static [u8],
+    add: &mut dyn FnMut(Entry) -> Result<(), FsError>,
+) -> Result<(), FsError> {
+    let mut offset = 0;
+    loop {
+        let header = image
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or(FsError::Corrupted)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(FsError::Corrupted);
+        }
+        let mode = hex_field(header, 1)?;
+        let mtime = hex_field(header, 5)?;
+        let size = hex_field(header, 6)? as usize;
+        let name_size = hex_field(header, 11)? as usize;
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = image
+            .get(name_start..name_start + name_size)
+            .and_then(|name| name.strip_suffix(&[0]))
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .ok_or(FsError::Corrupted)?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let data_start = align4(name_start + name_size);
+        let data = image
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        let kind = match mode & MODE_TYPE {
+            MODE_DIRECTORY => Some(FileKind::Directory),
+            MODE_REGULAR => Some(FileKind::File),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            add(Entry {
+                prefix: "",
+                path: name,
+                kind,
+                data,
+                mtime: mtime.into(),
+            })?;
+        }
+        offset = align4(data_start + size);
+    }
+}
+
+/// An octal number field of a tar header, NUL or space terminated
+fn octal_field(field: &[u8]) -> Result<u64, FsError> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0 || byte == b' ')
+        .unwrap_or(field.len());
+    let text = core::str::from_utf8(&field[..end]).map_err(|_| FsError::Corrupted)?;
+    match text.trim_start() {
+        "" => Ok(0),
+        digits => u64::from_str_radix(digits, 8).map_err(|_| FsError::Corrupted),
+    }
+}
+
+/// A NUL-terminated text field of a tar header
+fn text_field(field: &'static [u8]) -> Result<&'static str, FsError> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).map_err(|_| FsError::Corrupted)
+}
+
+fn parse_tar(
+    image: &'static [u8],
+    add: &mut dyn FnMut(Entry) -> Result<(), FsError>,
+) -> Result<(), FsError> {
+    let mut offset = 0;
+    // a block of zeros ends the archive, as does running out of blocks
+    while let Some(header) = image.get(offset..offset + TAR_BLOCK) {
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if &header[257..262] != TAR_MAGIC {
+            return Err(FsError::Corrupted);
+        }
+        let size = octal_field(&header[124..136])? as usize;
+        let mtime = octal_field(&header[136..148])?;
+        let kind = match header[156] {
+            b'0' | 0 => Some(FileKind::File),
+            b'5' => Some(FileKind::Directory),
+            _ => None,
+        };
+        let data_start = offset + TAR_BLOCK;
+        let data = image
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        if let Some(kind) = kind {
+            add(Entry {
+                prefix: text_field(&header[345..500])?,
+                path: text_field(&header[..100])?,
+                kind,
+                data: if kind == FileKind::File { data } else { &[] },
+                mtime,
+            })?;
+        }
+        offset = data_start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    }
+    Ok(())
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(InitrdInode {
+            nodes: self.nodes.clone(),
+            index: 0,
+        })
+    }
+}
+
+struct InitrdInode {
+    nodes: Arc<Vec<Node>>,
+    index: usize,
+}
+
+impl InitrdInode {
+    fn node(&self) -> &Node {
+        &self.nodes[self.index]
+    }
+
+    fn directory(&self) -> Result<&BTreeMap<String, usize>, FsError> {
+        match self.node().kind {
+            FileKind::Directory => Ok(&self.node().children),
+            FileKind::File => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let node = self.node();
+        Ok(Metadata {
+            inode: self.index as u64 + 1,
+            kind: node.kind,
+            size: node.data.len() as u64,
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let &index = self.directory()?.get(name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(InitrdInode {
+            nodes: self.nodes.clone(),
+            index,
+        }))
+    }
+
+    fn create(&self, name: &str, _kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+        match self.directory()?.contains_key(name) {
+            true => Err(FsError::AlreadyExists),
+            false => Err(FsError::PermissionDenied),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        self.directory()?.get(name).ok_or(FsError::NotFound)?;
+        Err(FsError::PermissionDenied)
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        let children = self.directory()?;
+        for (index, (name, &child)) in children.iter().enumerate().skip(position as usize) {
+            let entry = DirEntry {
+                inode: child as u64 + 1,
+                kind: Some(self.nodes[child].kind),
+                name: name.clone(),
+                next: index as u64 + 1,
+            };
+            if !emit(entry) {
+                return Ok(index as u64);
+            }
+        }
+        Ok(children.len().max(position as usize) as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.node().kind == FileKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let data = self.node().data;
+        let start = offset.min(data.len() as u64) as usize;
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        match self.node().kind {
+            FileKind::Directory => Err(FsError::IsADirectory),
+            FileKind::File => Err(FsError::PermissionDenied),
+        }
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        match self.node().kind {
+            FileKind::Directory => Err(FsError::IsADirectory),
+            FileKind::File => Err(FsError::PermissionDenied),
+        }
+    }
+
+    /// The archive's modification times are all there is, and they stay
+    fn set_times(&self, _atime: Option<u64>, _mtime: Option<u64>) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+}
@@ -0,0 +1,628 @@
+//! A 9P2000.L client: a directory tree served by another machine, in practice the host a VM runs
+//! on, mounted like any other filesystem.
+//!
+//! Every inode holds a fid, the server's handle on a path, walked to from its parent's and
+//! clunked when the inode is dropped. Reading and writing need the file opened too, which happens
+//! on first use through a second fid. Nothing is cached, every call is a round trip, so changes
+//! made on the host show up right away. Messages travel over a `Channel`, one request and its
+//! reply at a time; `drivers::virtio::p9` is one.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode, Metadata};
+use crate::block::BlockError;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+/// Carries 9P messages to a server and back
+pub trait Channel: Send + Sync {
+    /// Largest message the channel carries, either way
+    fn max_message(&self) -> usize;
+
+    /// Sends `request` and returns the server's reply
+    fn exchange(&self, request: &[u8]) -> Result<Vec<u8>, FsError>;
+}
+
+const VERSION: &str = "9P2000.L";
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+/// The tag of every request but `Tversion`; there's only ever one in flight
+const TAG: u16 = 1;
+
+/// Message types; each reply is its request's type plus one
+mod message {
+    pub const RLERROR: u8 = 7;
+    pub const TLOPEN: u8 = 12;
+    pub const TLCREATE: u8 = 14;
+    pub const TGETATTR: u8 = 24;
+    pub const TSETATTR: u8 = 26;
+    pub const TREADDIR: u8 = 40;
+    pub const TMKDIR: u8 = 72;
+    pub const TUNLINKAT: u8 = 76;
+    pub const TVERSION: u8 = 100;
+    pub const TATTACH: u8 = 104;
+    pub const TWALK: u8 = 110;
+    pub const TREAD: u8 = 116;
+    pub const TWRITE: u8 = 118;
+    pub const TCLUNK: u8 = 120;
+}
+
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+/// What an `Rread` or `Rreaddir` has besides the data: the header and count[4]
+const READ_OVERHEAD: usize = HEADER_SIZE + 4;
+/// What a `Twrite` has besides the data: the header, fid[4], offset[8] and count[4]
+const WRITE_OVERHEAD: usize = HEADER_SIZE + 16;
+
+/// `qid.type` of directories
+const QTDIR: u8 = 0x80;
+/// Directory entry types, as in Linux's `d_type`
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+/// Linux's open flags and file type bits, which 9P2000.L passes through
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const AT_REMOVEDIR: u32 = 0x200;
+/// `Tgetattr` mask for everything `stat` reports
+const GETATTR_BASIC: u64 = 0x7ff;
+/// `Tsetattr` bits for the size, and for times: one to change each, one to take it from the
+/// request rather than the server's clock
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const FILE_MODE: u32 = 0o644;
+const DIRECTORY_MODE: u32 = 0o755;
+
+/// The server's identity for a file
+#[derive(Debug, Clone, Copy)]
+struct Qid {
+    kind: u8,
+    path: u64,
+}
+
+impl Qid {
+    fn file_kind(&self) -> FileKind {
+        match self.kind & QTDIR {
+            0 => FileKind::File,
+            _ => FileKind::Directory,
+        }
+    }
+}
+
+/// A T-message being put together
+struct Request {
+    data: Vec<u8>,
+}
+
+impl Request {
+    fn new(kind: u8) -> Request {
+        Request::with_tag(kind, TAG)
+    }
+
+    fn with_tag(kind: u8, tag: u16) -> Request {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(&[0; 4]); // the size, once it's known
+        data.push(kind);
+        data.extend_from_slice(&tag.to_le_bytes());
+        Request { data }
+    }
+
+    fn kind(&self) -> u8 {
+        self.data[4]
+    }
+
+    fn u16(mut self, value: u16) -> Request {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Request {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Request {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Request {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Request {
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
+
+/// The body of an R-message, read front to back; running out is `Corrupted`
+struct Reply {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl Reply {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], FsError> {
+        let end = self.position.checked_add(len).ok_or(FsError::Corrupted)?;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(FsError::Corrupted)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], FsError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, FsError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FsError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, FsError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, FsError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, FsError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| FsError::Corrupted)
+    }
+
+    fn qid(&mut self) -> Result<Qid, FsError> {
+        let kind = self.u8()?;
+        let _version = self.u32()?;
+        let path = self.u64()?;
+        Ok(Qid { kind, path })
+    }
+}
+
+/// What an `Rlerror`'s Linux errno means here
+fn error(errno: u32) -> FsError {
+    match errno {
+        1 | 13 => FsError::PermissionDenied,
+        2 => FsError::NotFound,
+        16 => FsError::Busy,
+        17 => FsError::AlreadyExists,
+        20 => FsError::NotADirectory,
+        21 => FsError::IsADirectory,
+        22 => FsError::InvalidArgument,
+        28 => FsError::NoSpace,
+        30 => FsError::Block(BlockError::ReadOnly),
+        36 => FsError::NameTooLong,
+        39 => FsError::DirectoryNotEmpty,
+        _ => FsError::Block(BlockError::Io),
+    }
+}
+
+/// One session with a server
+struct Client {
+    channel: Arc<dyn Channel>,
+    /// The message size agreed on
+    msize: usize,
+    next_fid: AtomicU32,
+    /// Fids clunked and free to use again
+    free_fids: Mutex<Vec<u32>>,
+}
+
+impl Client {
+    /// Sends `request` and returns the body of the reply, turning `Rlerror` into an error
+    fn rpc(&self, request: Request) -> Result<Reply, FsError> {
+        let kind = request.kind();
+        let data = self.channel.exchange(&request.finish())?;
+        if data.len() < HEADER_SIZE {
+            return Err(FsError::Corrupted);
+        }
+        let mut reply = Reply {
+            data,
+            position: HEADER_SIZE,
+        };
+        match reply.data[4] {
+            message::RLERROR => Err(error(reply.u32()?)),
+            reply_kind if reply_kind == kind + 1 => Ok(reply),
+            _ => Err(FsError::Corrupted),
+        }
+    }
+
+    fn allocate_fid(&self) -> u32 {
+        self.free_fids
+            .lock()
+            .pop()
+            .unwrap_or_else(|| self.next_fid.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Tells the server it can forget `fid`. The fid is gone even if that fails.
+    fn clunk(&self, fid: u32) {
+        let _ = self.rpc(Request::new(message::TCLUNK).u32(fid));
+        self.free_fids.lock().push(fid);
+    }
+
+    /// A new fid for `name` in the directory `fid`, or for `fid`'s own file without a name
+    fn walk(&self, fid: u32, name: Option<&str>) -> Result<(u32, Option<Qid>), FsError> {
+        let new_fid = self.allocate_fid();
+        let request = Request::new(message::TWALK)
+            .u32(fid)
+            .u32(new_fid)
+            .u16(name.is_some() as u16);
+        let request = match name {
+            Some(name) => request.str(name),
+            None => request,
+        };
+        let walked = self.rpc(request).and_then(|mut reply| {
+            let count = reply.u16()?;
+            match (name, count) {
+                (None, 0) => Ok(None),
+                (Some(_), 1) => reply.qid().map(Some),
+                // a walk that stops short doesn't create the new fid
+                _ => Err(FsError::NotFound),
+            }
+        });
+        match walked {
+            Ok(qid) => Ok((new_fid, qid)),
+            Err(err) => {
+                self.free_fids.lock().push(new_fid);
+                Err(err)
+            }
+        }
+    }
+
+    /// A new fid for `fid`'s file, opened with `flags`
+    fn open(&self, fid: u32, flags: u32) -> Result<u32, FsError> {
+        let (new_fid, _) = self.walk(fid, None)?;
+        match self.rpc(Request::new(message::TLOPEN).u32(new_fid).u32(flags)) {
+            Ok(_) => Ok(new_fid),
+            Err(err) => {
+                self.clunk(new_fid);
+                Err(err)
+            }
+        }
+    }
+}
+
+pub struct P9Fs {
+    root: Arc<P9Inode>,
+}
+
+impl P9Fs {
+    /// Starts a session over `channel` and attaches to the tree the server exports as `aname`
+    /// (servers with a single export, like QEMU's, ignore it)
+    pub fn attach(channel: Arc<dyn Channel>, aname: &str) -> Result<P9Fs, FsError> {
+        let mut client = Client {
+            msize: channel.max_message(),
+            channel,
+            next_fid: AtomicU32::new(1),
+            free_fids: Mutex::new(Vec::new()),
+        };
+        let mut reply = client.rpc(
+            Request::with_tag(message::TVERSION, NOTAG)
+                .u32(client.msize as u32)
+                .str(VERSION),
+        )?;
+        let msize = reply.u32()? as usize;
+        if reply.string()? != VERSION || msize <= WRITE_OVERHEAD {
+            return Err(FsError::InvalidArgument); // the server doesn't speak 9P2000.L
+        }
+        client.msize = client.msize.min(msize);
+
+        let root_fid = 0;
+        let qid = client
+            .rpc(
+                Request::new(message::TATTACH)
+                    .u32(root_fid)
+                    .u32(NOFID)
+                    .str("root")
+                    .str(aname)
+                    .u32(0), // n_uname, root's uid
+            )?
+            .qid()?;
+        let root = P9Inode::new(Arc::new(client), root_fid, qid.file_kind());
+        Ok(P9Fs {
+            root: Arc::new(root),
+        })
+    }
+}
+
+impl FileSystem for P9Fs {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+pub struct P9Inode {
+    client: Arc<Client>,
+    fid: u32,
+    kind: FileKind,
+    /// The fid reads and writes go through, opened on first use; may be `fid` itself
+    io: Mutex<Option<u32>>,
+}
+
+impl P9Inode {
+    fn new(client: Arc<Client>, fid: u32, kind: FileKind) -> P9Inode {
+        P9Inode {
+            client,
+            fid,
+            kind,
+            io: Mutex::new(None),
+        }
+    }
+
+    /// The open fid, opening the file if it isn't yet: read-write if the server lets us,
+    /// read-only otherwise
+    fn io_fid(&self) -> Result<u32, FsError> {
+        if self.kind == FileKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut io = self.io.lock();
+        if let Some(fid) = *io {
+            return Ok(fid);
+        }
+        let fid = match self.client.open(self.fid, O_RDWR) {
+            Err(FsError::PermissionDenied | FsError::Block(BlockError::ReadOnly)) => {
+                self.client.open(self.fid, O_RDONLY)?
+            }
+            opened => opened?,
+        };
+        *io = Some(fid);
+        Ok(fid)
+    }
+}
+
+impl Drop for P9Inode {
+    fn drop(&mut self) {
+        if let Some(io) = self.io.lock().take().filter(|&io| io != self.fid) {
+            self.client.clunk(io);
+        }
+        self.client.clunk(self.fid);
+    }
+}
+
+impl Inode for P9Inode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let mut reply = self.client.rpc(
+            Request::new(message::TGETATTR)
+                .u32(self.fid)
+                .u64(GETATTR_BASIC),
+        )?;
+        let _valid = reply.u64()?;
+        let qid = reply.qid()?;
+        let mode = reply.u32()?;
+        // uid[4] gid[4] nlink[8] rdev[8]
+        reply.bytes(24)?;
+        let size = reply.u64()?;
+        // blksize[8] blocks[8]
+        reply.bytes(16)?;
+        let mut time = || -> Result<u64, FsError> {
+            let seconds = reply.u64()?;
+            let _nanoseconds = reply.u64()?;
+            Ok(seconds)
+        };
+        let (atime, mtime, ctime) = (time()?, time()?, time()?);
+        Ok(Metadata {
+            inode: qid.path,
+            kind: match mode & S_IFMT {
+                S_IFDIR => FileKind::Directory,
+                _ => FileKind::File,
+            },
+            size,
+            atime,
+            mtime,
+            ctime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.kind != FileKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let (fid, qid) = self.client.walk(self.fid, Some(name))?;
+        let kind = qid.map_or(FileKind::File, |qid| qid.file_kind());
+        Ok(Arc::new(P9Inode::new(self.client.clone(), fid, kind)))
+    }
+
+    fn create(&self, name: &str, kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+        if self.kind != FileKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match kind {
+            FileKind::Directory => {
+                self.client.rpc(
+                    Request::new(message::TMKDIR)
+                        .u32(self.fid)
+                        .str(name)
+                        .u32(DIRECTORY_MODE)
+                        .u32(0), // gid
+                )?;
+                self.lookup(name)
+            }
+            FileKind::File => {
+                // Tlcreate turns the directory fid it's given into one for the new, open file
+                let (fid, _) = self.client.walk(self.fid, None)?;
+                let created = self.client.rpc(
+                    Request::new(message::TLCREATE)
+                        .u32(fid)
+                        .str(name)
+                        .u32(O_RDWR | O_CREAT | O_EXCL)
+                        .u32(FILE_MODE)
+                        .u32(0), // gid
+                );
+                if let Err(err) = created {
+                    self.client.clunk(fid);
+                    return Err(err);
+                }
+                let inode = P9Inode::new(self.client.clone(), fid, FileKind::File);
+                *inode.io.lock() = Some(fid);
+                Ok(Arc::new(inode))
+            }
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        let unlink = |flags| {
+            self.client.rpc(
+                Request::new(message::TUNLINKAT)
+                    .u32(self.fid)
+                    .str(name)
+                    .u32(flags),
+            )
+        };
+        match unlink(0) {
+            Err(FsError::IsADirectory) => unlink(AT_REMOVEDIR).map(drop),
+            result => result.map(drop),
+        }
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        if self.kind != FileKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let fid = self.client.open(self.fid, O_RDONLY)?;
+        let count = (self.client.msize - READ_OVERHEAD) as u32;
+        let mut resume = position;
+        let result = (|| loop {
+            let mut reply = self.client.rpc(
+                Request::new(message::TREADDIR)
+                    .u32(fid)
+                    .u64(resume)
+                    .u32(count),
+            )?;
+            let len = reply.u32()? as usize;
+            if len == 0 {
+                return Ok(resume);
+            }
+            let end = reply.position + len;
+            while reply.position < end {
+                let qid = reply.qid()?;
+                let next = reply.u64()?;
+                let kind = reply.u8()?;
+                let name = reply.string()?;
+                if name != "." && name != ".." {
+                    let entry = DirEntry {
+                        inode: qid.path,
+                        kind: match kind {
+                            DT_DIR => Some(FileKind::Directory),
+                            DT_REG => Some(FileKind::File),
+                            _ => None,
+                        },
+                        name,
+                        next,
+                    };
+                    if !emit(entry) {
+                        return Ok(resume);
+                    }
+                }
+                resume = next;
+            }
+        })();
+        self.client.clunk(fid);
+        result
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let fid = self.io_fid()?;
+        let count = buf.len().min(self.client.msize - READ_OVERHEAD);
+        let mut reply = self.client.rpc(
+            Request::new(message::TREAD)
+                .u32(fid)
+                .u64(offset)
+                .u32(count as u32),
+        )?;
+        let read = (reply.u32()? as usize).min(count);
+        buf[..read].copy_from_slice(reply.bytes(read)?);
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let fid = self.io_fid()?;
+        let mut written = 0;
+        for chunk in data.chunks(self.client.msize - WRITE_OVERHEAD) {
+            let mut reply = self.client.rpc(
+                Request::new(message::TWRITE)
+                    .u32(fid)
+                    .u64(offset + written as u64)
+                    .u32(chunk.len() as u32)
+                    .bytes(chunk),
+            )?;
+            let count = reply.u32()? as usize;
+            written += count.min(chunk.len());
+            if count < chunk.len() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.kind == FileKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.client.rpc(
+            Request::new(message::TSETATTR)
+                .u32(self.fid)
+                .u32(SETATTR_SIZE)
+                .u32(0) // mode
+                .u32(0) // uid
+                .u32(0) // gid
+                .u64(size)
+                .u64(0) // atime
+                .u64(0)
+                .u64(0) // mtime
+                .u64(0),
+        )?;
+        Ok(())
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError> {
+        let mut valid = 0;
+        if atime.is_some() {
+            valid |= SETATTR_ATIME | SETATTR_ATIME_SET;
+        }
+        if mtime.is_some() {
+            valid |= SETATTR_MTIME | SETATTR_MTIME_SET;
+        }
+        self.client.rpc(
+            Request::new(message::TSETATTR)
+                .u32(self.fid)
+                .u32(valid)
+                .u32(0) // mode
+                .u32(0) // uid
+                .u32(0) // gid
+                .u64(0) // size
+                .u64(atime.unwrap_or(0))
+                .u64(0)
+                .u64(mtime.unwrap_or(0))
+                .u64(0),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! procfs: files whose contents the kernel generates when they're read, mounted at `/proc`.
+//!
+//! Each file is a function returning its text; it runs again on every read, so a file read in
+//! pieces should be read from a single `open` quickly, or the pieces may not fit together. The
+//! directories can't be changed, and the files are read-only but for those in `/proc/sys`.
+//!
+//! `/proc/sys` holds a file per kernel parameter (see `sysctl`), named like the parameter and
+//! containing its value and a newline. These can be written: the text written at the start of
+//! the file, a decimal number, becomes the parameter's new value.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode, Metadata};
+use crate::sysctl::{self, Tunable};
+use crate::{abi, audit, cmdline, interrupts, klog, pci, time};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+const ROOT_INODE: u64 = 1;
+const SYS_NAME: &str = "sys";
+
+/// One file: its name and what generates its contents
+struct Entry {
+    name: &'static str,
+    generate: fn() -> String,
+}
+
+static ENTRIES: &[Entry] = &[
+    Entry {
+        name: "abi",
+        generate: || String::from(abi::MANIFEST),
+    },
+    Entry {
+        name: "audit",
+        generate: audit::text,
+    },
+    Entry {
+        name: "cmdline",
+        generate: || format!("{}\n", cmdline::text()),
+    },
+    Entry {
+        name: "interrupts",
+        generate: interrupts::stats::text,
+    },
+    Entry {
+        name: "kmsg",
+        generate: klog::text,
+    },
+    Entry {
+        name: "pci",
+        generate: pci::text,
+    },
+];
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcInode::Root)
+    }
+}
+
+enum ProcInode {
+    Root,
+    /// Index into `ENTRIES`
+    File(usize),
+    /// `/proc/sys`
+    Sys,
+    /// Index into `sysctl::tunables()`
+    Tunable(usize),
+}
+
+impl ProcInode {
+    fn number(&self) -> u64 {
+        let sys = ROOT_INODE + 1 + ENTRIES.len() as u64;
+        match self {
+            ProcInode::Root => ROOT_INODE,
+            ProcInode::File(index) => ROOT_INODE + 1 + *index as u64,
+            ProcInode::Sys => sys,
+            ProcInode::Tunable(index) => sys + 1 + *index as u64,
+        }
+    }
+
+    fn is_directory(&self) -> bool {
+        matches!(self, ProcInode::Root | ProcInode::Sys)
+    }
+
+    fn kind(&self) -> FileKind {
+        if self.is_directory() {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        }
+    }
+
+    fn tunable(&self) -> Option<&'static Tunable> {
+        match self {
+            ProcInode::Tunable(index) => Some(sysctl::tunables()[*index]),
+            _ => None,
+        }
+    }
+
+    fn contents(&self) -> Result<String, FsError> {
+        match self {
+            ProcInode::Root | ProcInode::Sys => Err(FsError::IsADirectory),
+            ProcInode::File(index) => Ok((ENTRIES[*index].generate)()),
+            ProcInode::Tunable(index) => Ok(format!("{}\n", sysctl::tunables()[*index].get())),
+        }
+    }
+
+    /// The entries of this directory, in `readdir` order
+    fn children(&self) -> impl Iterator<Item = (ProcInode, &'static str)> {
+        let (files, sys, tunables) = match self {
+            ProcInode::Root => (ENTRIES.len(), true, 0),
+            ProcInode::Sys => (0, false, sysctl::tunables().len()),
+            _ => (0, false, 0),
+        };
+        let files = (0..files).map(|index| (ProcInode::File(index), ENTRIES[index].name));
+        let sys = sys.then_some((ProcInode::Sys, SYS_NAME));
+        let tunables =
+            (0..tunables).map(|index| (ProcInode::Tunable(index), sysctl::tunables()[index].name));
+        files.chain(sys).chain(tunables)
+    }
+}
+
+impl Inode for ProcInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let size = if self.is_directory() {
+            0
+        } else {
+            self.contents()?.len() as u64
+        };
+        // always freshly made
+        let now = time::now();
+        Ok(Metadata {
+            inode: self.number(),
+            kind: self.kind(),
+            size,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if !self.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let (inode, _) = self
+            .children()
+            .find(|(_, child)| *child == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(inode))
+    }
+
+    fn create(&self, _name: &str, _kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        if !self.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut count = 0;
+        for (index, (inode, name)) in self.children().enumerate() {
+            count = index + 1;
+            if index < position as usize {
+                continue;
+            }
+            let entry = DirEntry {
+                inode: inode.number(),
+                kind: Some(inode.kind()),
+                name: String::from(name),
+                next: index as u64 + 1,
+            };
+            if !emit(entry) {
+                return Ok(index as u64);
+            }
+        }
+        Ok(count.max(position as usize) as u64)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let contents = self.contents()?;
+        let contents = contents.as_bytes();
+        let start = offset.min(contents.len() as u64) as usize;
+        let read = buf.len().min(contents.len() - start);
+        buf[..read].copy_from_slice(&contents[start..start + read]);
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.contents()?;
+        let tunable = self.tunable().ok_or(FsError::PermissionDenied)?;
+        if offset != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let value = core::str::from_utf8(data)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .ok_or(FsError::InvalidArgument)?;
+        tunable.set(value).map_err(|_| FsError::InvalidArgument)?;
+        Ok(data.len())
+    }
+
+    /// Parameters can be truncated, so opening one for writing works; the value stays until
+    /// something is written
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        self.contents()?;
+        match self.tunable() {
+            Some(_) => Ok(()),
+            None => Err(FsError::PermissionDenied),
+        }
+    }
+
+    /// The times are always now
+    fn set_times(&self, _atime: Option<u64>, _mtime: Option<u64>) -> Result<(), FsError> {
+        Err(FsError::PermissionDenied)
+    }
+}
@@ -0,0 +1,266 @@
+//! ramfs, a filesystem that lives entirely on the kernel heap.
+//!
+//! Nothing is ever written anywhere, so the contents are gone at reboot. Removed files stay alive
+//! for as long as someone still holds their inode, like unlinked files on a disk filesystem.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode, Metadata};
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub const ROOT_INODE: u64 = 1;
+/// Longest name a directory entry can have, matching what disk filesystems usually allow
+pub const MAX_NAME_LEN: usize = 255;
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    /// Creates a filesystem holding just an empty root directory
+    pub fn new() -> Self {
+        let next_inode = Arc::new(AtomicU64::new(ROOT_INODE + 1));
+        RamFs {
+            root: Arc::new(RamInode::new(ROOT_INODE, FileKind::Directory, next_inode)),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct Directory {
+    /// Entries keyed by a per-directory sequence number, which doubles as the readdir cursor so
+    /// positions stay valid while entries come and go
+    entries: BTreeMap<u64, (String, Arc<RamInode>)>,
+    by_name: BTreeMap<String, u64>,
+    next_slot: u64,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(Directory),
+}
+
+struct Node {
+    contents: Contents,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl Node {
+    fn touch_modified(&mut self) {
+        let now = time::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    fn directory(&mut self) -> Result<&mut Directory, FsError> {
+        match &mut self.contents {
+            Contents::Directory(directory) => Ok(directory),
+            Contents::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn file(&mut self) -> Result<&mut Vec<u8>, FsError> {
+        match &mut self.contents {
+            Contents::File(data) => Ok(data),
+            Contents::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+pub struct RamInode {
+    inode: u64,
+    kind: FileKind,
+    /// Inode number allocator shared by the whole filesystem
+    next_inode: Arc<AtomicU64>,
+    node: Mutex<Node>,
+}
+
+impl RamInode {
+    fn new(inode: u64, kind: FileKind, next_inode: Arc<AtomicU64>) -> Self {
+        let contents = match kind {
+            FileKind::File => Contents::File(Vec::new()),
+            FileKind::Directory => Contents::Directory(Directory {
+                entries: BTreeMap::new(),
+                by_name: BTreeMap::new(),
+                next_slot: 0,
+            }),
+        };
+        let now = time::now();
+        RamInode {
+            inode,
+            kind,
+            next_inode,
+            node: Mutex::new(Node {
+                contents,
+                atime: now,
+                mtime: now,
+                ctime: now,
+            }),
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let node = self.node.lock();
+        let size = match &node.contents {
+            Contents::File(data) => data.len() as u64,
+            Contents::Directory(_) => 0,
+        };
+        Ok(Metadata {
+            inode: self.inode,
+            kind: self.kind,
+            size,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut node = self.node.lock();
+        let directory = node.directory()?;
+        let slot = directory.by_name.get(name).ok_or(FsError::NotFound)?;
+        Ok(directory.entries[slot].1.clone())
+    }
+
+    fn create(&self, name: &str, kind: FileKind) -> Result<Arc<dyn Inode>, FsError> {
+        validate_name(name)?;
+        let mut node = self.node.lock();
+        let directory = node.directory()?;
+        if directory.by_name.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        let child = Arc::new(RamInode::new(inode, kind, self.next_inode.clone()));
+        let slot = directory.next_slot;
+        directory.next_slot += 1;
+        directory.by_name.insert(String::from(name), slot);
+        directory
+            .entries
+            .insert(slot, (String::from(name), child.clone()));
+        node.touch_modified();
+        Ok(child)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        let directory = node.directory()?;
+        let slot = *directory.by_name.get(name).ok_or(FsError::NotFound)?;
+        if let Contents::Directory(child) = &directory.entries[&slot].1.node.lock().contents {
+            if !child.entries.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        directory.by_name.remove(name);
+        directory.entries.remove(&slot);
+        node.touch_modified();
+        Ok(())
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        let mut node = self.node.lock();
+        let directory = node.directory()?;
+        for (&slot, (name, child)) in directory.entries.range(position..) {
+            let entry = DirEntry {
+                inode: child.inode,
+                kind: Some(child.kind),
+                name: name.clone(),
+                next: slot + 1,
+            };
+            if !emit(entry) {
+                return Ok(slot);
+            }
+        }
+        Ok(directory.next_slot.max(position))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut node = self.node.lock();
+        let data = node.file()?;
+        let start = (offset.min(data.len() as u64)) as usize;
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut node = self.node.lock();
+        let contents = node.file()?;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or(FsError::InvalidArgument)?;
+        if end > contents.len() {
+            contents
+                .try_reserve(end - contents.len())
+                .map_err(|_| FsError::NoSpace)?;
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        node.touch_modified();
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        let contents = node.file()?;
+        let size = usize::try_from(size).map_err(|_| FsError::InvalidArgument)?;
+        if size > contents.len() {
+            contents
+                .try_reserve(size - contents.len())
+                .map_err(|_| FsError::NoSpace)?;
+        }
+        contents.resize(size, 0);
+        contents.shrink_to_fit();
+        node.touch_modified();
+        Ok(())
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        if let Some(atime) = atime {
+            node.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            node.mtime = mtime;
+            node.ctime = time::now();
+        }
+        Ok(())
+    }
+}
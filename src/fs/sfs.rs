@@ -0,0 +1,777 @@
+//! SFS, the kernel's native "simple filesystem".
+//!
+//! A small extent-based filesystem with 64-bit sizes whose metadata (superblock aside, which is
+//! only written by `format`) is updated exclusively through the ordered-mode block journal, so a
+//! crash at any point leaves either the old or the new version of every operation on disk.
+//!
+//! On-disk layout, in device blocks:
+//!
+//! ```text
+//! 0                      superblock
+//! 1 ..                   journal
+//! bitmap_start ..        block allocation bitmap, one bit per device block (1 = used)
+//! inode_table_start ..   inode table, 128 bytes per inode, inode 1 is the root directory
+//! data_start ..          file and directory contents
+//! ```
+//!
+//! Inodes describe their contents with up to `MAX_EXTENTS` runs of contiguous blocks. Directories
+//! are ordinary files made of 64-byte entries; a free slot has inode number 0.
+//!
+//! Every inode carries access, modification and change times in seconds since the Unix epoch.
+//! Reads don't write the access time themselves; the VFS does, through `set_times`, under its
+//! relatime rule (see `fs::needs_atime_update`), so reads rarely cause a journal commit.
+//!
+//! `Sfs` works with inode numbers; `SfsMount` puts a volume, shared with any loop devices on it,
+//! into the VFS tree.
+
+use super::{DirEntry, FileKind, FileSystem, FsError, Inode as VfsInode, Metadata};
+use crate::block::journal::{Journal, Transaction};
+use crate::block::BlockDevice;
+use crate::endian::read_u64_le;
+use crate::time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use layout::{
+    decode_entry, encode_entry, entry_kind, Extent, Inode, Layout, DIR_ENTRY_SIZE, INODE_SIZE,
+    KIND_DIRECTORY, KIND_FILE, KIND_FREE, MAX_EXTENTS,
+};
+use spin::Mutex;
+
+mod layout;
+
+pub use layout::{MAX_NAME_LEN, ROOT_INODE};
+
+fn kind_to_raw(kind: FileKind) -> u16 {
+    match kind {
+        FileKind::File => KIND_FILE,
+        FileKind::Directory => KIND_DIRECTORY,
+    }
+}
+
+fn kind_from_raw(raw: u16) -> Option<FileKind> {
+    match raw {
+        KIND_FILE => Some(FileKind::File),
+        KIND_DIRECTORY => Some(FileKind::Directory),
+        _ => None,
+    }
+}
+
+impl Inode {
+    fn new(kind: FileKind) -> Inode {
+        let now = time::now();
+        Inode {
+            kind: kind_to_raw(kind),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            ..Inode::default()
+        }
+    }
+
+    /// Records a change to the contents
+    fn touch_modified(&mut self) {
+        let now = time::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+/// A mounted SFS volume
+pub struct Sfs<D: BlockDevice> {
+    journal: Journal<D>,
+    layout: Layout,
+}
+
+impl<D: BlockDevice> Sfs<D> {
+    /// Creates an empty filesystem with room for `inode_count` files and directories (mkfs)
+    pub fn format(device: D, inode_count: u64) -> Result<Self, FsError> {
+        let block_size = device.block_size() as u64;
+        let block_count = device.block_count();
+        if block_size < 512 || !block_size.is_multiple_of(DIR_ENTRY_SIZE as u64) || inode_count == 0
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let layout = Layout::new(block_size, block_count, inode_count).ok_or(FsError::NoSpace)?;
+        let journal = Journal::format(device, layout.journal_start, layout.journal_len)?;
+        let zero = vec![0; block_size as usize];
+        for block in layout.bitmap_start..layout.data_start {
+            journal.write_data(block, &zero)?; // empty bitmap and inode table
+        }
+        let mut superblock = vec![0; block_size as usize];
+        layout.encode(&mut superblock);
+        journal.write_data(0, &superblock)?;
+
+        let mut fs = Sfs { journal, layout };
+        let mut tx = fs.journal.begin();
+        let metadata = Extent {
+            start: 0,
+            len: layout.data_start as u32,
+        };
+        fs.set_block_state(&mut tx, metadata, true)?;
+        fs.write_inode(&mut tx, ROOT_INODE, &Inode::new(FileKind::Directory))?;
+        fs.journal.commit(tx)?;
+        Ok(fs)
+    }
+
+    /// Mounts an existing volume, replaying the journal if the last shutdown was unclean
+    pub fn mount(device: D) -> Result<Self, FsError> {
+        let mut buf = vec![0; device.block_size()];
+        device.read_block(0, &mut buf)?;
+        let layout = Layout::decode(&buf).ok_or(FsError::Corrupted)?;
+        if layout.block_size != device.block_size() as u64
+            || layout.block_count > device.block_count()
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let journal = Journal::open(device, layout.journal_start, layout.journal_len)?;
+        Ok(Sfs { journal, layout })
+    }
+
+    /// Gives the block device back; every operation already committed, so nothing is lost
+    pub fn into_device(self) -> D {
+        self.journal.into_inner()
+    }
+
+    /// Waits until file data written in place has reached stable storage; metadata is already
+    /// durable once an operation returns
+    pub fn sync(&self) -> Result<(), FsError> {
+        Ok(self.journal.device().flush()?)
+    }
+
+    pub fn stat(&self, inode: u64) -> Result<Metadata, FsError> {
+        let tx = self.journal.begin();
+        let node = self.read_inode(&tx, inode)?;
+        Ok(Metadata {
+            inode,
+            kind: kind_from_raw(node.kind).ok_or(FsError::NotFound)?,
+            size: node.size,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+        })
+    }
+
+    /// Sets the access and/or modification time of `inode`; a new modification time moves the
+    /// change time to now as well
+    pub fn set_times(
+        &mut self,
+        inode: u64,
+        atime: Option<u64>,
+        mtime: Option<u64>,
+    ) -> Result<(), FsError> {
+        let mut tx = self.journal.begin();
+        let mut node = self.read_inode(&tx, inode)?;
+        if kind_from_raw(node.kind).is_none() {
+            return Err(FsError::NotFound);
+        }
+        if let Some(atime) = atime {
+            node.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            node.mtime = mtime;
+            node.ctime = time::now();
+        }
+        self.write_inode(&mut tx, inode, &node)?;
+        self.journal.commit(tx)?;
+        Ok(())
+    }
+
+    /// Finds `name` in directory `dir` and returns its inode number
+    pub fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        let tx = self.journal.begin();
+        let dir_node = self.read_directory(&tx, dir)?;
+        match self.find_entry(&tx, &dir_node, name)? {
+            Some((_, inode)) => Ok(inode),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// Streams the entries of directory `dir` starting at cursor `position` (0 for the start).
+    ///
+    /// `emit` is called for each entry until it returns `false`, meaning the entry didn't fit and
+    /// wasn't consumed. Returns the cursor to resume from; once it equals the directory size there
+    /// is nothing left. Entries never move, so a cursor stays valid while the directory changes:
+    /// entries created or removed meanwhile may or may not be seen, all others are seen once.
+    pub fn readdir(
+        &self,
+        dir: u64,
+        position: u64,
+        mut emit: impl FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        let tx = self.journal.begin();
+        let dir_node = self.read_directory(&tx, dir)?;
+        let start = position.div_ceil(DIR_ENTRY_SIZE as u64) * DIR_ENTRY_SIZE as u64;
+        let stopped = self.find_slot(&tx, &dir_node, start, |offset, slot| {
+            match decode_entry(slot) {
+                Some((inode, name)) => !emit(DirEntry {
+                    inode,
+                    kind: kind_from_raw(entry_kind(slot)),
+                    name: String::from(name),
+                    next: offset + DIR_ENTRY_SIZE as u64,
+                }),
+                None => false,
+            }
+        })?;
+        Ok(stopped.unwrap_or(dir_node.size.max(start)))
+    }
+
+    /// Creates an empty file or directory called `name` in directory `dir`
+    pub fn create(&mut self, dir: u64, name: &str, kind: FileKind) -> Result<u64, FsError> {
+        validate_name(name)?;
+        let mut tx = self.journal.begin();
+        let mut dir_node = self.read_directory(&tx, dir)?;
+        if self.find_entry(&tx, &dir_node, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = self.allocate_inode(&tx)?;
+        self.write_inode(&mut tx, inode, &Inode::new(kind))?;
+
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        encode_entry(&mut entry, inode, kind_to_raw(kind), name);
+        let slot = self.free_slot(&tx, &dir_node)?;
+        self.write_range(&mut tx, &mut dir_node, slot, &entry, true)?;
+        dir_node.touch_modified();
+        self.write_inode(&mut tx, dir, &dir_node)?;
+
+        self.journal.commit(tx)?;
+        Ok(inode)
+    }
+
+    /// Removes `name` from directory `dir` and frees its blocks; directories must be empty
+    pub fn remove(&mut self, dir: u64, name: &str) -> Result<(), FsError> {
+        let mut tx = self.journal.begin();
+        let mut dir_node = self.read_directory(&tx, dir)?;
+        let (slot, inode) = self
+            .find_entry(&tx, &dir_node, name)?
+            .ok_or(FsError::NotFound)?;
+
+        let node = self.read_inode(&tx, inode)?;
+        if node.kind == KIND_DIRECTORY
+            && self
+                .find_slot(&tx, &node, 0, |_, slot| decode_entry(slot).is_some())?
+                .is_some()
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        for extent in node.extents() {
+            self.set_block_state(&mut tx, *extent, false)?;
+        }
+        self.write_inode(&mut tx, inode, &Inode::default())?;
+        self.write_range(&mut tx, &mut dir_node, slot, &[0; DIR_ENTRY_SIZE], true)?;
+        dir_node.touch_modified();
+        self.write_inode(&mut tx, dir, &dir_node)?;
+
+        self.journal.commit(tx)?;
+        Ok(())
+    }
+
+    /// Reads from file `inode` at `offset`, returning how many bytes were read (0 at the end)
+    pub fn read(&self, inode: u64, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let tx = self.journal.begin();
+        let node = self.read_file(&tx, inode)?;
+        self.read_range(&tx, &node, offset, buf)
+    }
+
+    /// Writes `data` to file `inode` at `offset`, growing the file as needed
+    pub fn write(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut tx = self.journal.begin();
+        let mut node = self.read_file(&tx, inode)?;
+        self.write_range(&mut tx, &mut node, offset, data, false)?;
+        node.touch_modified();
+        self.write_inode(&mut tx, inode, &node)?;
+        self.journal.commit(tx)?;
+        Ok(data.len())
+    }
+
+    /// Grows (with zeros) or shrinks file `inode` to `size` bytes
+    pub fn truncate(&mut self, inode: u64, size: u64) -> Result<(), FsError> {
+        let mut tx = self.journal.begin();
+        let mut node = self.read_file(&tx, inode)?;
+        if size >= node.size {
+            self.write_range(&mut tx, &mut node, size, &[], false)?;
+        } else {
+            self.shrink(&mut tx, &mut node, size)?;
+        }
+        node.touch_modified();
+        self.write_inode(&mut tx, inode, &node)?;
+        self.journal.commit(tx)?;
+        Ok(())
+    }
+
+    // --- metadata access -------------------------------------------------------------------
+
+    /// Reads a block, preferring the copy staged in `tx` over the one on disk
+    fn read_block(&self, tx: &Transaction, block: u64) -> Result<Vec<u8>, FsError> {
+        if let Some(staged) = tx.staged(block) {
+            return Ok(staged.to_vec());
+        }
+        let mut buf = vec![0; self.layout.block_size as usize];
+        self.journal.device().read_block(block, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Writes a block either through the journal (metadata) or straight to disk (file data)
+    fn store_block(
+        &self,
+        tx: &mut Transaction,
+        block: u64,
+        buf: &[u8],
+        journaled: bool,
+    ) -> Result<(), FsError> {
+        if journaled {
+            Ok(tx.write_metadata(block, buf)?)
+        } else {
+            Ok(self.journal.write_data(block, buf)?)
+        }
+    }
+
+    fn inode_location(&self, inode: u64) -> Result<(u64, usize), FsError> {
+        if inode == 0 || inode > self.layout.inode_count {
+            return Err(FsError::NotFound);
+        }
+        Ok(self.layout.inode_location(inode))
+    }
+
+    fn read_inode(&self, tx: &Transaction, inode: u64) -> Result<Inode, FsError> {
+        let (block, offset) = self.inode_location(inode)?;
+        let buf = self.read_block(tx, block)?;
+        Inode::decode(&buf[offset..offset + INODE_SIZE]).ok_or(FsError::Corrupted)
+    }
+
+    fn write_inode(&self, tx: &mut Transaction, inode: u64, node: &Inode) -> Result<(), FsError> {
+        let (block, offset) = self.inode_location(inode)?;
+        let mut buf = self.read_block(tx, block)?;
+        node.encode(&mut buf[offset..offset + INODE_SIZE]);
+        Ok(tx.write_metadata(block, &buf)?)
+    }
+
+    fn read_file(&self, tx: &Transaction, inode: u64) -> Result<Inode, FsError> {
+        let node = self.read_inode(tx, inode)?;
+        match node.kind {
+            KIND_FILE => Ok(node),
+            KIND_DIRECTORY => Err(FsError::IsADirectory),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_directory(&self, tx: &Transaction, inode: u64) -> Result<Inode, FsError> {
+        let node = self.read_inode(tx, inode)?;
+        match node.kind {
+            KIND_DIRECTORY => Ok(node),
+            KIND_FILE => Err(FsError::NotADirectory),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn allocate_inode(&self, tx: &Transaction) -> Result<u64, FsError> {
+        for inode in ROOT_INODE + 1..=self.layout.inode_count {
+            if self.read_inode(tx, inode)?.kind == KIND_FREE {
+                return Ok(inode);
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    // --- block allocation ------------------------------------------------------------------
+
+    fn set_block_state(
+        &self,
+        tx: &mut Transaction,
+        extent: Extent,
+        used: bool,
+    ) -> Result<(), FsError> {
+        let bits = self.layout.bits_per_bitmap_block();
+        let end = extent.start + extent.len as u64;
+        let mut block = extent.start;
+        while block < end {
+            let bitmap_block = self.layout.bitmap_start + block / bits;
+            let chunk_end = end.min((block / bits + 1) * bits);
+            let mut buf = self.read_block(tx, bitmap_block)?;
+            for b in block..chunk_end {
+                let bit = (b % bits) as usize;
+                if used {
+                    buf[bit / 8] |= 1 << (bit % 8);
+                } else {
+                    buf[bit / 8] &= !(1 << (bit % 8));
+                }
+            }
+            tx.write_metadata(bitmap_block, &buf)?;
+            block = chunk_end;
+        }
+        Ok(())
+    }
+
+    /// Counts the free blocks directly at `start`, up to `max`
+    fn free_run_at(&self, tx: &Transaction, start: u64, max: u64) -> Result<u64, FsError> {
+        let bits = self.layout.bits_per_bitmap_block();
+        let mut count = 0;
+        let mut cached: Option<(u64, Vec<u8>)> = None;
+        while count < max && start + count < self.layout.block_count {
+            let block = start + count;
+            let bitmap_block = self.layout.bitmap_start + block / bits;
+            if cached.as_ref().map(|(b, _)| *b) != Some(bitmap_block) {
+                cached = Some((bitmap_block, self.read_block(tx, bitmap_block)?));
+            }
+            let buf = &cached.as_ref().unwrap().1;
+            let bit = (block % bits) as usize;
+            if buf[bit / 8] & (1 << (bit % 8)) != 0 {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// First run of `want` free blocks, or the longest run if there is none that long
+    fn find_free_run(&self, tx: &Transaction, want: u64) -> Result<Option<Extent>, FsError> {
+        let bits = self.layout.bits_per_bitmap_block();
+        let want = want.min(u32::MAX as u64);
+        let mut best = Extent::default();
+        let mut run = Extent::default();
+        for index in 0..self.layout.bitmap_blocks {
+            let buf = self.read_block(tx, self.layout.bitmap_start + index)?;
+            for bit in 0..bits {
+                let block = index * bits + bit;
+                if block >= self.layout.block_count {
+                    break;
+                }
+                if buf[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                    if run.len == 0 {
+                        run.start = block;
+                    }
+                    run.len += 1;
+                    if run.len as u64 == want {
+                        return Ok(Some(run));
+                    }
+                } else {
+                    if run.len > best.len {
+                        best = run;
+                    }
+                    run.len = 0;
+                }
+            }
+        }
+        if run.len > best.len {
+            best = run;
+        }
+        Ok(if best.len > 0 { Some(best) } else { None })
+    }
+
+    /// Allocates blocks until `node` owns `needed` of them, returning the new extents
+    fn grow(
+        &self,
+        tx: &mut Transaction,
+        node: &mut Inode,
+        needed: u64,
+    ) -> Result<Vec<Extent>, FsError> {
+        let mut added = Vec::new();
+        let mut have = node.block_count();
+        while have < needed {
+            let want = needed - have;
+
+            // extending the last extent in place keeps the file contiguous
+            if let Some(last) = node.extents[..node.extent_count].last_mut() {
+                let next = last.start + last.len as u64;
+                let room = (u32::MAX - last.len) as u64;
+                let count = self.free_run_at(tx, next, want.min(room))?;
+                if count > 0 {
+                    let extent = Extent {
+                        start: next,
+                        len: count as u32,
+                    };
+                    last.len += extent.len;
+                    self.set_block_state(tx, extent, true)?;
+                    added.push(extent);
+                    have += count;
+                    continue;
+                }
+            }
+
+            if node.extent_count == MAX_EXTENTS {
+                return Err(FsError::NoSpace); // too fragmented to describe
+            }
+            let extent = self.find_free_run(tx, want)?.ok_or(FsError::NoSpace)?;
+            self.set_block_state(tx, extent, true)?;
+            node.extents[node.extent_count] = extent;
+            node.extent_count += 1;
+            added.push(extent);
+            have += extent.len as u64;
+        }
+        Ok(added)
+    }
+
+    /// Frees every block past `size` and zeroes the tail of the new last block
+    fn shrink(&self, tx: &mut Transaction, node: &mut Inode, size: u64) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        let keep = size.div_ceil(block_size);
+        let mut surplus = node.block_count() - keep;
+        while surplus > 0 {
+            let last = &mut node.extents[node.extent_count - 1];
+            let cut = surplus.min(last.len as u64) as u32;
+            let freed = Extent {
+                start: last.start + (last.len - cut) as u64,
+                len: cut,
+            };
+            last.len -= cut;
+            if last.len == 0 {
+                node.extent_count -= 1;
+            }
+            self.set_block_state(tx, freed, false)?;
+            surplus -= cut as u64;
+        }
+
+        let tail = (size % block_size) as usize;
+        if tail != 0 {
+            let block = node.physical_block(keep - 1).ok_or(FsError::Corrupted)?;
+            let mut buf = self.read_block(tx, block)?;
+            buf[tail..].fill(0); // a later extension must read back zeros
+            self.store_block(tx, block, &buf, node.kind == KIND_DIRECTORY)?;
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    // --- file contents ---------------------------------------------------------------------
+
+    fn read_range(
+        &self,
+        tx: &Transaction,
+        node: &Inode,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if offset >= node.size {
+            return Ok(0);
+        }
+        let block_size = self.layout.block_size;
+        let len = (buf.len() as u64).min(node.size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (block_size as usize - within).min(len - done);
+            let block = node
+                .physical_block(position / block_size)
+                .ok_or(FsError::Corrupted)?;
+            let contents = self.read_block(tx, block)?;
+            buf[done..done + count].copy_from_slice(&contents[within..within + count]);
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Writes `data` at `offset`, allocating (and zero-filling) blocks as required.
+    ///
+    /// `journaled` selects whether the contents are metadata (directories) that must go through
+    /// the transaction, or file data written in place ahead of the commit.
+    fn write_range(
+        &self,
+        tx: &mut Transaction,
+        node: &mut Inode,
+        offset: u64,
+        data: &[u8],
+        journaled: bool,
+    ) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        let old_blocks = node.block_count();
+        self.grow(tx, node, end.div_ceil(block_size))?;
+
+        // new blocks that the data won't completely cover must not expose stale contents
+        let zero = vec![0; block_size as usize];
+        for logical in old_blocks..node.block_count() {
+            let covered = offset <= logical * block_size && (logical + 1) * block_size <= end;
+            if !covered {
+                let block = node.physical_block(logical).ok_or(FsError::Corrupted)?;
+                self.store_block(tx, block, &zero, journaled)?;
+            }
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (block_size as usize - within).min(data.len() - done);
+            let block = node
+                .physical_block(position / block_size)
+                .ok_or(FsError::Corrupted)?;
+            if count == block_size as usize {
+                self.store_block(tx, block, &data[done..done + count], journaled)?;
+            } else {
+                let mut contents = self.read_block(tx, block)?;
+                contents[within..within + count].copy_from_slice(&data[done..done + count]);
+                self.store_block(tx, block, &contents, journaled)?;
+            }
+            done += count;
+        }
+
+        node.size = node.size.max(end);
+        Ok(())
+    }
+
+    // --- directories -----------------------------------------------------------------------
+
+    /// Returns the byte offset and inode number of `name` in `dir`
+    fn find_entry(
+        &self,
+        tx: &Transaction,
+        dir: &Inode,
+        name: &str,
+    ) -> Result<Option<(u64, u64)>, FsError> {
+        let mut found = None;
+        let offset = self.find_slot(tx, dir, 0, |_, slot| match decode_entry(slot) {
+            Some((inode, entry_name)) if entry_name == name => {
+                found = Some(inode);
+                true
+            }
+            _ => false,
+        })?;
+        Ok(offset.zip(found))
+    }
+
+    /// Byte offset of the first unused entry in `dir`, or its end if every slot is taken
+    fn free_slot(&self, tx: &Transaction, dir: &Inode) -> Result<u64, FsError> {
+        let slot = self.find_slot(tx, dir, 0, |_, slot| read_u64_le(slot, 0) == 0)?;
+        Ok(slot.unwrap_or(dir.size))
+    }
+
+    /// Walks the entries of `dir` from byte offset `start` one block at a time and returns the
+    /// offset of the first slot `matches` accepts
+    fn find_slot(
+        &self,
+        tx: &Transaction,
+        dir: &Inode,
+        start: u64,
+        mut matches: impl FnMut(u64, &[u8]) -> bool,
+    ) -> Result<Option<u64>, FsError> {
+        let block_size = self.layout.block_size;
+        let mut offset = start;
+        while offset < dir.size {
+            let block = dir
+                .physical_block(offset / block_size)
+                .ok_or(FsError::Corrupted)?;
+            let contents = self.read_block(tx, block)?;
+            let block_end = (offset - offset % block_size + block_size).min(dir.size);
+            while offset + DIR_ENTRY_SIZE as u64 <= block_end {
+                let within = (offset % block_size) as usize;
+                if matches(offset, &contents[within..within + DIR_ENTRY_SIZE]) {
+                    return Ok(Some(offset));
+                }
+                offset += DIR_ENTRY_SIZE as u64;
+            }
+            offset = block_end; // skips a partial trailing entry, which a valid directory never has
+        }
+        Ok(None)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+// --- VFS glue ------------------------------------------------------------------------------
+
+/// A mounted SFS volume as the VFS sees it
+pub struct SfsMount<D: BlockDevice> {
+    fs: Arc<Mutex<Sfs<D>>>,
+}
+
+impl<D: BlockDevice + 'static> SfsMount<D> {
+    /// Mounts the volume on `device`
+    pub fn mount(device: D) -> Result<Self, FsError> {
+        Ok(Self::new(Arc::new(Mutex::new(Sfs::mount(device)?))))
+    }
+
+    /// Wraps a volume that may also be shared with loop devices
+    pub fn new(fs: Arc<Mutex<Sfs<D>>>) -> Self {
+        SfsMount { fs }
+    }
+}
+
+impl<D: BlockDevice + 'static> FileSystem for SfsMount<D> {
+    fn name(&self) -> &'static str {
+        "sfs"
+    }
+
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(SfsInode {
+            fs: self.fs.clone(),
+            inode: ROOT_INODE,
+        })
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.fs.lock().sync()
+    }
+}
+
+struct SfsInode<D: BlockDevice> {
+    fs: Arc<Mutex<Sfs<D>>>,
+    inode: u64,
+}
+
+impl<D: BlockDevice + 'static> SfsInode<D> {
+    fn child(&self, inode: u64) -> Arc<dyn VfsInode> {
+        Arc::new(SfsInode {
+            fs: self.fs.clone(),
+            inode,
+        })
+    }
+}
+
+impl<D: BlockDevice + 'static> VfsInode for SfsInode<D> {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        self.fs.lock().stat(self.inode)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VfsInode>, FsError> {
+        let inode = self.fs.lock().lookup(self.inode, name)?;
+        Ok(self.child(inode))
+    }
+
+    fn create(&self, name: &str, kind: FileKind) -> Result<Arc<dyn VfsInode>, FsError> {
+        let inode = self.fs.lock().create(self.inode, name, kind)?;
+        Ok(self.child(inode))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), FsError> {
+        self.fs.lock().remove(self.inode, name)
+    }
+
+    fn readdir(
+        &self,
+        position: u64,
+        emit: &mut dyn FnMut(DirEntry) -> bool,
+    ) -> Result<u64, FsError> {
+        self.fs.lock().readdir(self.inode, position, emit)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.fs.lock().read(self.inode, offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.fs.lock().write(self.inode, offset, data)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.fs.lock().truncate(self.inode, size)
+    }
+
+    fn set_times(&self, atime: Option<u64>, mtime: Option<u64>) -> Result<(), FsError> {
+        self.fs.lock().set_times(self.inode, atime, mtime)
+    }
+}
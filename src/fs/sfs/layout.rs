@@ -0,0 +1,216 @@
+//! The SFS on-disk format: the superblock, inodes and directory entries, and where `format` puts
+//! each area.
+//!
+//! Shared with `tools/mkfs`, which includes this file, so images are laid out exactly the way the
+//! kernel reads them. It depends on nothing but `core` and `crate::endian`, which mkfs includes
+//! as well.
+
+use crate::endian::{
+    read_u16_le, read_u32_le, read_u64_le, write_u16_le, write_u32_le, write_u64_le,
+};
+
+pub const SFS_MAGIC: u32 = 0x2153_4653; // "SFS!"
+pub const SFS_VERSION: u32 = 1;
+
+pub const INODE_SIZE: usize = 128;
+pub const MAX_EXTENTS: usize = 7;
+pub const EXTENTS_OFFSET: usize = 40;
+pub const EXTENT_SIZE: usize = 12;
+
+pub const DIR_ENTRY_SIZE: usize = 64;
+pub const DIR_NAME_OFFSET: usize = 10;
+pub const MAX_NAME_LEN: usize = DIR_ENTRY_SIZE - DIR_NAME_OFFSET;
+
+pub const ROOT_INODE: u64 = 1;
+pub const DEFAULT_JOURNAL_BLOCKS: u64 = 64;
+
+pub const KIND_FREE: u16 = 0;
+pub const KIND_FILE: u16 = 1;
+pub const KIND_DIRECTORY: u16 = 2;
+
+/// Where everything lives, as recorded in the superblock
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub block_size: u64,
+    pub block_count: u64,
+    pub journal_start: u64,
+    pub journal_len: u64,
+    pub bitmap_start: u64,
+    pub bitmap_blocks: u64,
+    pub inode_table_start: u64,
+    pub inode_count: u64,
+    pub data_start: u64,
+}
+
+impl Layout {
+    /// The areas of a new volume of `block_count` blocks with room for `inode_count` inodes;
+    /// `None` if the metadata alone doesn't fit
+    pub fn new(block_size: u64, block_count: u64, inode_count: u64) -> Option<Layout> {
+        let journal_len = DEFAULT_JOURNAL_BLOCKS.min(block_count / 8).max(4);
+        let bitmap_start = 1 + journal_len;
+        let bitmap_blocks = block_count.div_ceil(block_size * 8);
+        let inode_table_start = bitmap_start + bitmap_blocks;
+        let inode_table_blocks = (inode_count * INODE_SIZE as u64).div_ceil(block_size);
+        let data_start = inode_table_start + inode_table_blocks;
+        if data_start >= block_count || data_start > u32::MAX as u64 {
+            return None;
+        }
+        Some(Layout {
+            block_size,
+            block_count,
+            journal_start: 1,
+            journal_len,
+            bitmap_start,
+            bitmap_blocks,
+            inode_table_start,
+            inode_count,
+            data_start,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        write_u32_le(buf, 0, SFS_MAGIC);
+        write_u32_le(buf, 4, SFS_VERSION);
+        write_u64_le(buf, 8, self.block_size);
+        write_u64_le(buf, 16, self.block_count);
+        write_u64_le(buf, 24, self.journal_start);
+        write_u64_le(buf, 32, self.journal_len);
+        write_u64_le(buf, 40, self.bitmap_start);
+        write_u64_le(buf, 48, self.bitmap_blocks);
+        write_u64_le(buf, 56, self.inode_table_start);
+        write_u64_le(buf, 64, self.inode_count);
+        write_u64_le(buf, 72, self.data_start);
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Layout> {
+        if read_u32_le(buf, 0) != SFS_MAGIC || read_u32_le(buf, 4) != SFS_VERSION {
+            return None;
+        }
+        Some(Layout {
+            block_size: read_u64_le(buf, 8),
+            block_count: read_u64_le(buf, 16),
+            journal_start: read_u64_le(buf, 24),
+            journal_len: read_u64_le(buf, 32),
+            bitmap_start: read_u64_le(buf, 40),
+            bitmap_blocks: read_u64_le(buf, 48),
+            inode_table_start: read_u64_le(buf, 56),
+            inode_count: read_u64_le(buf, 64),
+            data_start: read_u64_le(buf, 72),
+        })
+    }
+
+    pub fn bits_per_bitmap_block(&self) -> u64 {
+        self.block_size * 8
+    }
+
+    /// The block holding `inode` and its byte offset there; `inode` must be in range
+    pub fn inode_location(&self, inode: u64) -> (u64, usize) {
+        let byte = (inode - 1) * INODE_SIZE as u64;
+        let block = self.inode_table_start + byte / self.block_size;
+        (block, (byte % self.block_size) as usize)
+    }
+}
+
+/// A run of contiguous device blocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Extent {
+    pub start: u64,
+    pub len: u32,
+}
+
+/// An entry of the inode table; `kind` is `KIND_FREE` for an unused one
+#[derive(Debug, Clone, Default)]
+pub struct Inode {
+    pub kind: u16,
+    pub size: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub extent_count: usize,
+    pub extents: [Extent; MAX_EXTENTS],
+}
+
+impl Inode {
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf.fill(0);
+        write_u16_le(buf, 0, self.kind);
+        write_u32_le(buf, 4, self.extent_count as u32);
+        write_u64_le(buf, 8, self.size);
+        write_u64_le(buf, 16, self.atime);
+        write_u64_le(buf, 24, self.mtime);
+        write_u64_le(buf, 32, self.ctime);
+        for (i, extent) in self.extents().iter().enumerate() {
+            let offset = EXTENTS_OFFSET + i * EXTENT_SIZE;
+            write_u64_le(buf, offset, extent.start);
+            write_u32_le(buf, offset + 8, extent.len);
+        }
+    }
+
+    /// `None` if it claims more extents than an inode holds
+    pub fn decode(buf: &[u8]) -> Option<Inode> {
+        let mut inode = Inode {
+            kind: read_u16_le(buf, 0),
+            extent_count: read_u32_le(buf, 4) as usize,
+            size: read_u64_le(buf, 8),
+            atime: read_u64_le(buf, 16),
+            mtime: read_u64_le(buf, 24),
+            ctime: read_u64_le(buf, 32),
+            extents: Default::default(),
+        };
+        if inode.extent_count > MAX_EXTENTS {
+            return None;
+        }
+        for i in 0..inode.extent_count {
+            let offset = EXTENTS_OFFSET + i * EXTENT_SIZE;
+            inode.extents[i] = Extent {
+                start: read_u64_le(buf, offset),
+                len: read_u32_le(buf, offset + 8),
+            };
+        }
+        Some(inode)
+    }
+
+    pub fn extents(&self) -> &[Extent] {
+        &self.extents[..self.extent_count]
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.extents().iter().map(|e| e.len as u64).sum()
+    }
+
+    /// Maps a block index within the file to a device block
+    pub fn physical_block(&self, logical: u64) -> Option<u64> {
+        let mut first = 0;
+        for extent in self.extents() {
+            if logical < first + extent.len as u64 {
+                return Some(extent.start + (logical - first));
+            }
+            first += extent.len as u64;
+        }
+        None
+    }
+}
+
+/// Fills directory entry `entry` for `name`, inode `inode` of kind `kind`; the name must fit
+pub fn encode_entry(entry: &mut [u8], inode: u64, kind: u16, name: &str) {
+    write_u64_le(entry, 0, inode);
+    entry[8] = kind as u8;
+    entry[9] = name.len() as u8;
+    entry[DIR_NAME_OFFSET..DIR_NAME_OFFSET + name.len()].copy_from_slice(name.as_bytes());
+}
+
+/// The inode number and name of a used directory entry
+pub fn decode_entry(entry: &[u8]) -> Option<(u64, &str)> {
+    let inode = read_u64_le(entry, 0);
+    let name_len = (entry[9] as usize).min(MAX_NAME_LEN);
+    if inode == 0 {
+        return None;
+    }
+    let name = core::str::from_utf8(&entry[DIR_NAME_OFFSET..DIR_NAME_OFFSET + name_len]).ok()?;
+    Some((inode, name))
+}
+
+/// The kind recorded in a directory entry, which `decode_entry` doesn't check
+pub fn entry_kind(entry: &[u8]) -> u16 {
+    entry[8] as u16
+}
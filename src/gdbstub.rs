@@ -0,0 +1,705 @@
+//! A GDB remote stub on COM2, to debug the running kernel with gdb where QEMU's own `-s` isn't
+//! there, e.g. on real hardware over a null-modem cable.
+//!
+//! `attach` sets COM2 up and stops the kernel in a breakpoint of its own, where it waits for gdb:
+//!
+//! ```text
+//! gdb target/x86_64-rust_os/debug/rust_os -ex 'target remote /dev/ttyUSB0'
+//! ```
+//!
+//! (with QEMU, `-serial stdio -serial pty` and the pty it names). From then on every `int3` and
+//! debug exception in the kernel stops the CPU it happened on and hands it to gdb, which can read
+//! and change the registers and memory, set breakpoints and watchpoints, step and continue; a
+//! Ctrl-C in gdb stops the kernel wherever it is. Breakpoints are `int3`s written over the code,
+//! through the physical memory window as the code itself is read-only; watchpoints and hardware
+//! breakpoints use the debug registers of the CPU that was stopped when they were set, so they
+//! only fire there.
+//!
+//! While one CPU is stopped the others run on, and stop in turn if they hit a breakpoint once
+//! the first one continues. The stub takes no locks besides its own and allocates nothing, so it
+//! works even when it stops the kernel in the middle of the heap or the console. The segment
+//! registers are shown but can't be changed.
+
+use crate::drivers::serial::{SerialPort, COM2};
+use crate::interrupts;
+use crate::memory;
+use crate::println;
+use crate::sync::spinlock::IrqSpinLock;
+use crate::usermode::Registers;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const COM2_IRQ: u8 = 3;
+/// Largest packet gdb is told it may send, in bytes
+const PACKET_SIZE: usize = 0x400;
+/// Room for a packet's data coming in or going out
+const BUFFER_SIZE: usize = PACKET_SIZE + 16;
+const MAX_BREAKPOINTS: usize = 64;
+/// What gdb sends to stop the kernel while it runs
+const INTERRUPT: u8 = 0x03;
+const INT3: u8 = 0xCC;
+
+/// RFLAGS bits
+const TRAP_FLAG: u64 = 1 << 8;
+const RESUME_FLAG: u64 = 1 << 16;
+
+/// DR6 bits: which of DR0-3 fired, and single steps
+const DR6_HIT: u64 = 0b1111;
+const DR6_STEP: u64 = 1 << 14;
+
+/// Why the kernel stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// An `int3`
+    Breakpoint,
+    /// A debug exception: a step, a hardware breakpoint or a watchpoint
+    Debug,
+}
+
+/// What a debug register watches for, as DR7 encodes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watch {
+    Execute = 0b00,
+    Write = 0b01,
+    Access = 0b11,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HardwareBreakpoint {
+    address: u64,
+    watch: Watch,
+    len: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    /// The byte the `int3` replaced
+    saved: u8,
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    hardware: [Option<HardwareBreakpoint>; 4],
+    /// Whether gdb waits to be told the kernel stopped, having let it continue or step
+    running: bool,
+    input: [u8; BUFFER_SIZE],
+    output: [u8; BUFFER_SIZE],
+}
+
+static STUB: IrqSpinLock<Option<Stub>> = IrqSpinLock::new(None);
+static PORT: Once<SerialPort> = Once::new();
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Whether breakpoints and debug exceptions go to gdb
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+/// Sets COM2 up for gdb and stops the kernel until gdb connects and lets it continue
+pub fn attach() -> Result<(), &'static str> {
+    if is_attached() {
+        return Err("gdb is attached already");
+    }
+    let port = SerialPort::init(COM2).ok_or("no UART at COM2")?;
+    PORT.call_once(|| port);
+    *STUB.lock() = Some(Stub {
+        port,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        hardware: [None; 4],
+        running: false,
+        input: [0; BUFFER_SIZE],
+        output: [0; BUFFER_SIZE],
+    });
+    if interrupts::set_irq_handler(COM2_IRQ, interrupt).is_ok() {
+        port.enable_receive_interrupt();
+    }
+    ATTACHED.store(true, Ordering::Relaxed);
+    println!("gdb: waiting on COM2");
+    x86_64::instructions::interrupts::int3();
+    Ok(())
+}
+
+/// `gdb`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    attach()
+}
+
+/// A Ctrl-C from gdb stops the kernel right here, in the interrupt handler
+fn interrupt() {
+    let Some(port) = PORT.r#try() else {
+        return;
+    };
+    while let Some(byte) = port.try_receive() {
+        if byte == INTERRUPT {
+            x86_64::instructions::interrupts::int3();
+        }
+    }
+}
+
+/// Hands the CPU stopped by `trap` to gdb until it continues; `registers` are the interrupted
+/// code's and are resumed with whatever gdb changed
+pub fn trap(registers: &mut Registers, trap: Trap) {
+    let mut stub = STUB.lock();
+    let Some(stub) = stub.as_mut() else {
+        return;
+    };
+    let dr6 = read_dr6();
+    write_dr6(0);
+    registers.rflags &= !TRAP_FLAG;
+    if trap == Trap::Breakpoint {
+        // resume at the breakpoint rather than after it, once gdb has put the byte back
+        let address = registers.rip.wrapping_sub(1);
+        if stub
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|b| b.address == address)
+        {
+            registers.rip = address;
+        }
+    }
+    if stub.running {
+        stub.running = false;
+        let watched = match trap {
+            Trap::Debug if dr6 & DR6_STEP == 0 => (0..4)
+                .filter(|index| dr6 & DR6_HIT & (1 << index) != 0)
+                .find_map(|index| stub.hardware[index])
+                .filter(|hardware| hardware.watch != Watch::Execute),
+            _ => None,
+        };
+        match watched {
+            Some(hardware) => {
+                let kind = match hardware.watch {
+                    Watch::Write => "watch",
+                    _ => "awatch",
+                };
+                stub.reply(format_args!("T05{}:{:x};", kind, hardware.address));
+            }
+            None => stub.reply(format_args!("S05")),
+        }
+    }
+    stub.serve(registers);
+}
+
+impl Stub {
+    /// Answers gdb's requests until it says to continue or step
+    fn serve(&mut self, registers: &mut Registers) {
+        loop {
+            let len = self.receive();
+            let mut packet = [0; BUFFER_SIZE];
+            packet[..len].copy_from_slice(&self.input[..len]);
+            let packet = &packet[..len];
+            let (&command, args) = match packet.split_first() {
+                Some(split) => split,
+                None => {
+                    self.reply(format_args!(""));
+                    continue;
+                }
+            };
+            match command {
+                b'?' => self.reply(format_args!("S05")),
+                b'g' => self.reply(format_args!("{}", AllRegisters(registers))),
+                b'G' => {
+                    set_registers(registers, args);
+                    self.reply(format_args!("OK"));
+                }
+                b'p' => match parse_hex(args).and_then(|n| register(registers, n as usize)) {
+                    Some((value, size)) => self.reply(format_args!("{}", Hex(value, size))),
+                    None => self.reply(format_args!("")),
+                },
+                b'P' => {
+                    let result = split(args, b'=').and_then(|(number, value)| {
+                        let number = parse_hex(number)? as usize;
+                        set_register(registers, number, &parse_bytes::<8>(value)?)
+                    });
+                    self.ok_or_error(result.is_some());
+                }
+                b'm' => self.read_memory(args),
+                b'M' => {
+                    let written = split(args, b':').and_then(|(range, data)| {
+                        let (address, len) = parse_range(range)?;
+                        write_memory(address, len, data)
+                    });
+                    self.ok_or_error(written.is_some());
+                }
+                b'Z' | b'z' => {
+                    let done = self.breakpoint(command == b'Z', args);
+                    match done {
+                        Some(done) => self.ok_or_error(done),
+                        None => self.reply(format_args!("")),
+                    }
+                }
+                b'c' | b's' => {
+                    if let Some(address) = parse_hex(args) {
+                        registers.rip = address;
+                    }
+                    if command == b's' {
+                        registers.rflags |= TRAP_FLAG;
+                    }
+                    // don't stop at a hardware breakpoint on the instruction resumed at
+                    registers.rflags |= RESUME_FLAG;
+                    self.running = true;
+                    return;
+                }
+                b'D' | b'k' => {
+                    self.detach();
+                    if command == b'D' {
+                        self.reply(format_args!("OK"));
+                    }
+                    return;
+                }
+                b'H' | b'T' => self.reply(format_args!("OK")),
+                b'q' if packet.starts_with(b"qSupported") => {
+                    self.reply(format_args!("PacketSize={:x}", PACKET_SIZE))
+                }
+                b'q' if packet == b"qAttached" => self.reply(format_args!("1")),
+                b'q' if packet == b"qC" => self.reply(format_args!("QC1")),
+                b'q' if packet == b"qfThreadInfo" => self.reply(format_args!("m1")),
+                b'q' if packet == b"qsThreadInfo" => self.reply(format_args!("l")),
+                _ => self.reply(format_args!("")),
+            }
+        }
+    }
+
+    fn ok_or_error(&mut self, ok: bool) {
+        match ok {
+            true => self.reply(format_args!("OK")),
+            false => self.reply(format_args!("E01")),
+        }
+    }
+
+    /// `m<address>,<length>`: as much of it as is mapped, an error if none is
+    fn read_memory(&mut self, args: &[u8]) {
+        let Some((address, len)) = parse_range(args) else {
+            return self.reply(format_args!("E01"));
+        };
+        let len = len.min(PACKET_SIZE as u64 / 2 - 8);
+        let readable = (0..len)
+            .take_while(|&offset| translate(address.wrapping_add(offset)).is_some())
+            .count() as u64;
+        match readable {
+            0 => self.reply(format_args!("E14")),
+            _ => self.reply(format_args!("{}", Memory(address, readable))),
+        }
+    }
+
+    /// `Z<type>,<address>,<kind>` and `z...`: whether it was done, `None` for types not supported
+    fn breakpoint(&mut self, insert: bool, args: &[u8]) -> Option<bool> {
+        let mut fields = args.split(|&byte| byte == b',');
+        let kind = fields.next()?;
+        let address = parse_hex(fields.next()?)?;
+        let len = parse_hex(fields.next()?)?;
+        let watch = match kind {
+            b"0" => return Some(self.software_breakpoint(insert, address)),
+            b"1" => Watch::Execute,
+            b"2" => Watch::Write,
+            b"4" => Watch::Access,
+            _ => return None,
+        };
+        let len = match watch {
+            Watch::Execute => 1,
+            _ => len,
+        };
+        Some(self.hardware_breakpoint(insert, address, watch, len))
+    }
+
+    fn software_breakpoint(&mut self, insert: bool, address: u64) -> bool {
+        let slot = self
+            .breakpoints
+            .iter()
+            .position(|b| b.is_some_and(|b| b.address == address));
+        match (insert, slot) {
+            (true, Some(_)) => true,
+            (true, None) => {
+                let Some(free) = self.breakpoints.iter().position(Option::is_none) else {
+                    return false;
+                };
+                let Some(saved) = read_byte(address) else {
+                    return false;
+                };
+                self.breakpoints[free] = Some(Breakpoint { address, saved });
+                poke(address, INT3)
+            }
+            (false, Some(slot)) => {
+                let breakpoint = self.breakpoints[slot].take().unwrap();
+                poke(address, breakpoint.saved)
+            }
+            (false, None) => true,
+        }
+    }
+
+    fn hardware_breakpoint(&mut self, insert: bool, address: u64, watch: Watch, len: u64) -> bool {
+        if !matches!(len, 1 | 2 | 4 | 8) || !address.is_multiple_of(len) {
+            return false;
+        }
+        let same = |hardware: &Option<HardwareBreakpoint>| {
+            hardware.is_some_and(|h| h.address == address && h.watch == watch && h.len == len)
+        };
+        let slot = match insert {
+            true => self.hardware.iter().position(Option::is_none),
+            false => self.hardware.iter().position(same),
+        };
+        let Some(slot) = slot else {
+            return !insert;
+        };
+        self.hardware[slot] = insert.then_some(HardwareBreakpoint {
+            address,
+            watch,
+            len,
+        });
+        self.load_debug_registers();
+        true
+    }
+
+    /// Programs DR0-3 and DR7 with `hardware`, on this CPU
+    fn load_debug_registers(&self) {
+        let mut dr7 = 0;
+        for (index, hardware) in self.hardware.iter().enumerate() {
+            let Some(hardware) = hardware else {
+                continue;
+            };
+            write_dr(index, hardware.address);
+            let len = match hardware.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            dr7 |= 1 << (index * 2); // local enable
+            dr7 |= (hardware.watch as u64 | len << 2) << (16 + index * 4);
+        }
+        write_dr7(dr7);
+    }
+
+    /// Takes every breakpoint out and lets the kernel run on without gdb
+    fn detach(&mut self) {
+        for breakpoint in self.breakpoints.iter_mut().filter_map(Option::take) {
+            poke(breakpoint.address, breakpoint.saved);
+        }
+        self.hardware = [None; 4];
+        self.load_debug_registers();
+        self.running = false;
+        ATTACHED.store(false, Ordering::Relaxed);
+    }
+
+    /// Waits for the next packet with a good checksum, acknowledging it; returns its length in
+    /// `input`
+    fn receive(&mut self) -> usize {
+        loop {
+            while self.read() != b'$' {}
+            let mut len = 0;
+            let mut sum = 0u8;
+            let complete = loop {
+                let byte = self.read();
+                if byte == b'#' {
+                    break true;
+                }
+                if len == BUFFER_SIZE {
+                    break false;
+                }
+                self.input[len] = byte;
+                len += 1;
+                sum = sum.wrapping_add(byte);
+            };
+            let checksum = [self.read(), self.read()];
+            if complete && parse_hex(&checksum) == Some(sum as u64) {
+                self.port.send_byte(b'+');
+                return len;
+            }
+            self.port.send_byte(b'-');
+        }
+    }
+
+    fn read(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Sends `data` as a packet, again until gdb acknowledges it
+    fn reply(&mut self, data: fmt::Arguments) {
+        let mut output = Output {
+            buffer: &mut self.output,
+            len: 0,
+        };
+        // what doesn't fit is cut off; gdb asks for no more than a packet's worth
+        let _ = output.write_fmt(data);
+        let len = output.len;
+        let sum = self.output[..len]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send_byte(b'$');
+            for &byte in &self.output[..len] {
+                self.port.send_byte(byte);
+            }
+            self.port.send_byte(b'#');
+            for digit in [sum >> 4, sum & 0xF] {
+                self.port.send_byte(HEX_DIGITS[digit as usize]);
+            }
+            match self.read() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Formats into a packet buffer, up to its end
+struct Output<'a> {
+    buffer: &'a mut [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let room = BUFFER_SIZE - self.len;
+        if text.len() > room {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..self.len + text.len()].copy_from_slice(text.as_bytes());
+        self.len += text.len();
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// The low `size` bytes of a value, in memory order as gdb wants them
+struct Hex(u64, usize);
+
+impl fmt::Display for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0.to_le_bytes()[..self.1] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// `len` bytes from `address`, all mapped
+struct Memory(u64, u64);
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for offset in 0..self.1 {
+            let byte = unsafe { (self.0.wrapping_add(offset) as *const u8).read_volatile() };
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Registers in the order of gdb's x86-64 `g` packet, as far as the stub knows them: the general
+/// purpose registers, RIP, then 32-bit RFLAGS and segment selectors
+const REGISTERS: usize = 24;
+
+/// Register `number` of `registers` and its size in bytes
+fn register(registers: &Registers, number: usize) -> Option<(u64, usize)> {
+    let r = registers;
+    let value = match number {
+        0 => r.rax,
+        1 => r.rbx,
+        2 => r.rcx,
+        3 => r.rdx,
+        4 => r.rsi,
+        5 => r.rdi,
+        6 => r.rbp,
+        7 => r.rsp,
+        8 => r.r8,
+        9 => r.r9,
+        10 => r.r10,
+        11 => r.r11,
+        12 => r.r12,
+        13 => r.r13,
+        14 => r.r14,
+        15 => r.r15,
+        16 => r.rip,
+        17 => return Some((r.rflags, 4)),
+        18 => return Some((r.cs, 4)),
+        19 => return Some((r.ss, 4)),
+        20..=23 => return Some((segment(number - 20) as u64, 4)),
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+/// Changes register `number`; the segment selectors stay as they are
+fn set_register(registers: &mut Registers, number: usize, value: &[u8]) -> Option<()> {
+    let (_, size) = register(registers, number)?;
+    let mut bytes = [0; 8];
+    bytes[..size].copy_from_slice(value.get(..size)?);
+    let value = u64::from_le_bytes(bytes);
+    let r = registers;
+    let slot = match number {
+        0 => &mut r.rax,
+        1 => &mut r.rbx,
+        2 => &mut r.rcx,
+        3 => &mut r.rdx,
+        4 => &mut r.rsi,
+        5 => &mut r.rdi,
+        6 => &mut r.rbp,
+        7 => &mut r.rsp,
+        8 => &mut r.r8,
+        9 => &mut r.r9,
+        10 => &mut r.r10,
+        11 => &mut r.r11,
+        12 => &mut r.r12,
+        13 => &mut r.r13,
+        14 => &mut r.r14,
+        15 => &mut r.r15,
+        16 => &mut r.rip,
+        17 => {
+            r.rflags = r.rflags & !0xFFFF_FFFF | value;
+            return Some(());
+        }
+        _ => return Some(()),
+    };
+    *slot = value;
+    Some(())
+}
+
+/// `G`: as many registers as the packet has, in `g` order
+fn set_registers(registers: &mut Registers, hex: &[u8]) {
+    let mut offset = 0;
+    for number in 0..REGISTERS {
+        let Some((_, size)) = register(registers, number) else {
+            break;
+        };
+        let Some(value) = hex.get(offset..offset + size * 2) else {
+            break;
+        };
+        if let Some(bytes) = parse_bytes::<8>(value) {
+            set_register(registers, number, &bytes);
+        }
+        offset += size * 2;
+    }
+}
+
+struct AllRegisters<'a>(&'a Registers);
+
+impl fmt::Display for AllRegisters<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for number in 0..REGISTERS {
+            let (value, size) = register(self.0, number).unwrap();
+            write!(f, "{}", Hex(value, size))?;
+        }
+        Ok(())
+    }
+}
+
+/// DS, ES, FS or GS
+fn segment(index: usize) -> u16 {
+    let value: u16;
+    unsafe {
+        match index {
+            0 => asm!("mov {:x}, ds", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {:x}, es", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {:x}, fs", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov {:x}, gs", out(reg) value, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    value
+}
+
+fn read_dr6() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+fn write_dr6(value: u64) {
+    unsafe { asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+fn write_dr7(value: u64) {
+    unsafe { asm!("mov dr7, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Sets DR0-3
+fn write_dr(index: usize, address: u64) {
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg) address, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov dr1, {}", in(reg) address, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov dr2, {}", in(reg) address, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mov dr3, {}", in(reg) address, options(nomem, nostack, preserves_flags)),
+        }
+    }
+}
+
+/// Where the byte at `address` is, and how it's mapped
+fn translate(address: u64) -> Option<(u64, PageTableFlags)> {
+    let address = VirtAddr::try_new(address).ok()?;
+    let (phys, flags) = memory::translate_in(Cr3::read().0, address)?;
+    Some((phys.as_u64(), flags))
+}
+
+fn read_byte(address: u64) -> Option<u8> {
+    translate(address)?;
+    Some(unsafe { (address as *const u8).read_volatile() })
+}
+
+/// Writes the byte at `address` through the physical memory window, so read-only code can take a
+/// breakpoint; returns whether it's mapped
+fn poke(address: u64, value: u8) -> bool {
+    let Some((phys, _)) = translate(address) else {
+        return false;
+    };
+    let window = memory::phys_to_virt(x86_64::PhysAddr::new(phys));
+    unsafe { window.as_mut_ptr::<u8>().write_volatile(value) };
+    true
+}
+
+/// `M`'s data, written byte by byte; `None` if some of it isn't mapped
+fn write_memory(address: u64, len: u64, hex: &[u8]) -> Option<()> {
+    if hex.len() as u64 != len * 2 {
+        return None;
+    }
+    for (offset, digits) in hex.chunks(2).enumerate() {
+        let [byte] = parse_bytes::<1>(digits)?;
+        poke(address.wrapping_add(offset as u64), byte).then_some(())?;
+    }
+    Some(())
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    let text = core::str::from_utf8(digits).ok()?;
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// `<address>,<length>`
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (address, len) = split(args, b',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+/// Up to `N` bytes given as pairs of hex digits, in memory order; the rest are zero
+fn parse_bytes<const N: usize>(hex: &[u8]) -> Option<[u8; N]> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = parse_hex(digits)? as u8;
+    }
+    Some(bytes)
+}
@@ -0,0 +1,145 @@
+use crate::percpu::MAX_CPUS;
+use crate::{memory, scrub};
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Interrupt Stack Table slot the double fault handler switches to, so a kernel stack overflow
+/// produces a double fault report instead of a triple fault
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const STACK_SIZE: usize = 4096 * 5;
+
+/// Size of the stack the CPU switches to when an interrupt or exception arrives in ring 3
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
+
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    /// Ring 3 segments; `sysret` needs the data segment right before the code segment
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(STACK));
+            stack_start + STACK_SIZE // stacks grow downwards, so we pass the end address
+        };
+        tss.privilege_stack_table[0] = {
+            static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+            VirtAddr::from_ptr(core::ptr::addr_of!(STACK)) + PRIVILEGE_STACK_SIZE
+        };
+        tss
+    };
+}
+
+/// Each application processor's TSS, by CPU index
+static AP_TSS: [AtomicPtr<TaskStateSegment>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build(&TSS);
+}
+
+/// Creates a GDT holding the kernel and user segments and `tss`.
+///
+/// Every CPU needs its own TSS (and therefore its own GDT), because loading a TSS marks its
+/// descriptor busy.
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+/// Loads the boot CPU's GDT and TSS
+#[link_section = ".init.text"]
+pub fn init() {
+    load(&GDT);
+}
+
+/// Loads the boot CPU's GDT and TSS again after the CPU lost them, i.e. on waking from sleep.
+/// The GDT in memory still has the TSS marked busy from the first `init`, which `ltr` won't
+/// accept, so the mark is cleared first.
+pub fn reload() {
+    /// Turns an available 64-bit TSS descriptor (type 9) into a busy one (type 11)
+    const TSS_BUSY: u64 = 1 << 41;
+
+    let index = GDT.1.tss_selector.index() as usize;
+    unsafe {
+        // the CPU itself writes the descriptor when it loads the TSS, the GDT isn't immutable
+        let descriptor = GDT.0.as_raw_slice().as_ptr().add(index) as *mut u64;
+        descriptor.write_volatile(descriptor.read_volatile() & !TSS_BUSY);
+    }
+    load(&GDT);
+}
+
+/// The segment selectors, which are the same on every CPU
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Puts the boot CPU's GDT and TSS under the scrubber's watch, once the heap is up
+pub fn protect() {
+    scrub::protect_static("boot GDT", &GDT.0);
+    scrub::protect_static("boot TSS", &*TSS);
+}
+
+/// Loads a GDT and TSS (with its own double fault and privilege stacks) on application processor
+/// `index`. The TSS is allocated the first time and kept for when the CPU comes back online; the
+/// GDT is new every time, as the old one has the TSS marked busy and `ltr` refuses it then.
+pub fn init_ap(index: usize) {
+    let tss = match unsafe { AP_TSS[index].load(Ordering::SeqCst).as_ref() } {
+        Some(tss) => tss,
+        None => {
+            let mut tss = TaskStateSegment::new();
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+                memory::allocate_kernel_stack(STACK_SIZE as u64)
+                    .expect("failed to map a double fault stack");
+            tss.privilege_stack_table[0] =
+                memory::allocate_kernel_stack(PRIVILEGE_STACK_SIZE as u64)
+                    .expect("failed to map a privilege stack");
+
+            let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+            AP_TSS[index].store(tss, Ordering::SeqCst);
+            tss
+        }
+    };
+    load(Box::leak(Box::new(build(tss))));
+}
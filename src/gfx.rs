@@ -0,0 +1,352 @@
+//! Graphics on a linear framebuffer: pixels, rectangles, images and text.
+//!
+//! The bootloader leaves the display in VGA text mode and hands over no framebuffer, so one comes
+//! from a display adapter's driver instead: the driver registers an `Adapter`, and `enable`
+//! switches it to a graphics mode, maps the framebuffer write-combining and makes it available
+//! through `framebuffer()`. The VGA text buffer isn't displayed from then on. Only 32-bit pixels
+//! are supported, which every adapter offers.
+//!
+//! Text is drawn with a `font::Font`. Unless another is loaded, that's the VGA card's own, read
+//! out of the card before it leaves text mode. Once `enable` has switched modes, the console
+//! continues on the framebuffer as a `TextConsole`.
+
+use crate::memory::{self, caching::MemoryType};
+use crate::vga_buffer::Color;
+use crate::{console, power, println};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use font::Font;
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod font;
+mod text_console;
+
+pub use text_console::TextConsole;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+impl From<Color> for Rgb {
+    /// The color of the standard VGA palette
+    fn from(color: Color) -> Rgb {
+        match color {
+            Color::Black => Rgb::new(0x00, 0x00, 0x00),
+            Color::Blue => Rgb::new(0x00, 0x00, 0xaa),
+            Color::Green => Rgb::new(0x00, 0xaa, 0x00),
+            Color::Cyan => Rgb::new(0x00, 0xaa, 0xaa),
+            Color::Red => Rgb::new(0xaa, 0x00, 0x00),
+            Color::Magenta => Rgb::new(0xaa, 0x00, 0xaa),
+            Color::Brown => Rgb::new(0xaa, 0x55, 0x00),
+            Color::LightGray => Rgb::new(0xaa, 0xaa, 0xaa),
+            Color::DarkGray => Rgb::new(0x55, 0x55, 0x55),
+            Color::LightBlue => Rgb::new(0x55, 0x55, 0xff),
+            Color::LightGreen => Rgb::new(0x55, 0xff, 0x55),
+            Color::LightCyan => Rgb::new(0x55, 0xff, 0xff),
+            Color::LightRed => Rgb::new(0xff, 0x55, 0x55),
+            Color::Pink => Rgb::new(0xff, 0x55, 0xff),
+            Color::Yellow => Rgb::new(0xff, 0xff, 0x55),
+            Color::White => Rgb::new(0xff, 0xff, 0xff),
+        }
+    }
+}
+
+/// Byte order of a 32-bit pixel in memory; the fourth byte is unused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Blue first, as VGA-compatible adapters have it
+    Bgr,
+    Rgb,
+}
+
+impl PixelFormat {
+    fn encode(self, color: Rgb) -> u32 {
+        let (first, last) = match self {
+            PixelFormat::Bgr => (color.b, color.r),
+            PixelFormat::Rgb => (color.r, color.b),
+        };
+        u32::from_le_bytes([first, color.g, last, 0])
+    }
+}
+
+/// A graphics mode an adapter switched to
+#[derive(Debug, Clone, Copy)]
+pub struct Mode {
+    /// Physical address of the framebuffer
+    pub address: PhysAddr,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of one line to the start of the next
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+/// A display controller with a linear framebuffer
+pub trait Adapter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Switches to a `width` x `height` mode with 32-bit pixels
+    fn set_mode(&self, width: usize, height: usize) -> Result<Mode, &'static str>;
+}
+
+static ADAPTER: Mutex<Option<&'static dyn Adapter>> = Mutex::new(None);
+static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
+static FONT: Once<Font> = Once::new();
+
+/// Makes `adapter` the one `enable` uses; the first to register wins
+pub fn register_adapter(adapter: &'static dyn Adapter) {
+    let mut registered = ADAPTER.lock();
+    if registered.is_none() {
+        *registered = Some(adapter);
+    }
+}
+
+/// Switches the display to a `width` x `height` graphics mode; the screen is black afterwards
+pub fn enable(width: usize, height: usize) -> Result<(), &'static str> {
+    let adapter = ADAPTER.lock().ok_or("no display adapter")?;
+    // the VGA font is gone once the card leaves text mode; a card without one reads as blank
+    let font = FONT.call_once(|| {
+        let vga = Font::vga();
+        match vga.glyph('A').iter().any(|&row| row != 0) {
+            true => vga,
+            false => Font::builtin(),
+        }
+    });
+    println!(
+        "gfx: switching {} to {}x{}, the console moves to the framebuffer",
+        adapter.name(),
+        width,
+        height
+    );
+    let mode = adapter.set_mode(width, height)?;
+    let size = (mode.stride * mode.height) as u64;
+    let base = memory::map_mmio_as(mode.address, size, MemoryType::WriteCombining)
+        .map_err(|_| "failed to map the framebuffer")?;
+    let mut framebuffer = unsafe { Framebuffer::new(base, mode) };
+    framebuffer.fill_rect(0, 0, mode.width, mode.height, Rgb::BLACK);
+    let console = TextConsole::new(&framebuffer, font);
+    if FRAMEBUFFER.lock().replace(framebuffer).is_none() {
+        power::register(Arc::new(Display));
+    }
+    console::set_backend(Box::new(console));
+    Ok(())
+}
+
+/// Switches the adapter back to the graphics mode after sleep, which leaves it in VGA text mode
+struct Display;
+
+impl power::Device for Display {
+    fn name(&self) -> &'static str {
+        "gfx"
+    }
+
+    fn resume(&self) -> Result<(), &'static str> {
+        let adapter = ADAPTER.lock().ok_or("no display adapter")?;
+        match FRAMEBUFFER.lock().as_ref() {
+            Some(framebuffer) => adapter
+                .set_mode(framebuffer.width(), framebuffer.height())
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The framebuffer, `None` while the display is in text mode
+pub fn framebuffer() -> MutexGuard<'static, Option<Framebuffer>> {
+    FRAMEBUFFER.lock()
+}
+
+/// Releases the framebuffer's lock for `console::force_unlock`
+pub(crate) unsafe fn force_unlock() {
+    FRAMEBUFFER.force_unlock();
+}
+
+/// The font to draw text with, once `enable` has read it or fallen back to the built-in one
+pub fn font() -> Option<&'static Font> {
+    FONT.r#try()
+}
+
+pub struct Framebuffer {
+    base: *mut u8,
+    mode: Mode,
+}
+
+// only reachable through the `FRAMEBUFFER` lock
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// # Safety
+    ///
+    /// `base` must map the whole framebuffer `mode` describes, for as long as this lives.
+    pub unsafe fn new(base: VirtAddr, mode: Mode) -> Framebuffer {
+        Framebuffer {
+            base: base.as_mut_ptr(),
+            mode,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.mode.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.mode.height
+    }
+
+    fn encode(&self, color: Rgb) -> u32 {
+        self.mode.format.encode(color)
+    }
+
+    /// The pixels of line `y` from `x` on, `width` of them; the caller clips
+    fn span(&mut self, x: usize, y: usize, width: usize) -> &mut [u32] {
+        let offset = y * self.mode.stride + x * 4;
+        unsafe { core::slice::from_raw_parts_mut(self.base.add(offset).cast(), width) }
+    }
+
+    /// Sets the pixel at `x`, `y`; off-screen pixels are ignored
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width() && y < self.height() {
+            let value = self.encode(color);
+            unsafe { ptr::write_volatile(self.span(x, y, 1).as_mut_ptr(), value) };
+        }
+    }
+
+    /// Fills the rectangle at `x`, `y` with `color`, as much of it as is on screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let width = width.min(self.width().saturating_sub(x));
+        let height = height.min(self.height().saturating_sub(y));
+        let value = self.encode(color);
+        for line in y..y + height {
+            self.span(x, line, width).fill(value);
+        }
+    }
+
+    /// Draws the image in `pixels`, `width` pixels per line, with its top left corner at `x`,
+    /// `y`; whatever falls off the screen is cut off
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[Rgb]) {
+        if width == 0 {
+            return;
+        }
+        let visible = width.min(self.width().saturating_sub(x));
+        let format = self.mode.format;
+        for (line, row) in (y..self.height()).zip(pixels.chunks(width)) {
+            let row = &row[..visible.min(row.len())];
+            for (pixel, &color) in self.span(x, line, row.len()).iter_mut().zip(row) {
+                *pixel = format.encode(color);
+            }
+        }
+    }
+
+    /// Draws `ch` in `font` with its top left corner at `x`, `y`
+    pub fn draw_char(&mut self, x: usize, y: usize, ch: char, font: &Font, fg: Rgb, bg: Rgb) {
+        let (fg, bg) = (self.encode(fg), self.encode(bg));
+        let visible = font.width().min(self.width().saturating_sub(x));
+        let glyph = font.glyph(ch);
+        for (line, bits) in (y..self.height()).zip(glyph.chunks(font.row_bytes())) {
+            let span = self.span(x, line, visible);
+            for (column, pixel) in span.iter_mut().enumerate() {
+                let set = bits[column / 8] & (0x80 >> (column % 8)) != 0;
+                *pixel = if set { fg } else { bg };
+            }
+        }
+    }
+
+    /// Draws the set pixels of `ch` only, over what's there, like a combining mark over the
+    /// character before it
+    pub fn overlay_char(&mut self, x: usize, y: usize, ch: char, font: &Font, fg: Rgb) {
+        let fg = self.encode(fg);
+        let visible = font.width().min(self.width().saturating_sub(x));
+        let glyph = font.glyph(ch);
+        for (line, bits) in (y..self.height()).zip(glyph.chunks(font.row_bytes())) {
+            let span = self.span(x, line, visible);
+            for (column, pixel) in span.iter_mut().enumerate() {
+                if bits[column / 8] & (0x80 >> (column % 8)) != 0 {
+                    *pixel = fg;
+                }
+            }
+        }
+    }
+
+    /// Moves everything on screen up by `lines` pixel lines and fills the lines that come free at
+    /// the bottom with `color`
+    pub fn scroll_up(&mut self, lines: usize, color: Rgb) {
+        let lines = lines.min(self.height());
+        let kept = self.height() - lines;
+        // one move of the whole block, the lines overlap when more than half the screen stays
+        unsafe {
+            let source = self.base.add(lines * self.mode.stride);
+            ptr::copy(source, self.base, kept * self.mode.stride);
+        }
+        self.fill_rect(0, kept, self.width(), lines, color);
+    }
+
+    /// Draws `text` in one line from `x`, `y` on, one glyph per character
+    pub fn draw_str(&mut self, x: usize, y: usize, text: &str, font: &Font, fg: Rgb, bg: Rgb) {
+        for (index, ch) in text.chars().enumerate() {
+            self.draw_char(x + index * font.width(), y, ch, font, fg, bg);
+        }
+    }
+}
+
+/// `gfx [<width> <height>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let (width, height) = match args {
+        [] => (1024, 768),
+        [width, height] => (
+            width.parse().map_err(|_| "bad width")?,
+            height.parse().map_err(|_| "bad height")?,
+        ),
+        _ => return Err("usage: gfx [<width> <height>]"),
+    };
+    enable(width, height)?;
+    let mut guard = framebuffer();
+    let framebuffer = guard.as_mut().ok_or("no framebuffer")?;
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+
+    // a test card: colour bars, a gradient and a caption
+    let bars = [
+        Rgb::WHITE,
+        Rgb::new(0xff, 0xff, 0),
+        Rgb::new(0, 0xff, 0xff),
+        Rgb::new(0, 0xff, 0),
+        Rgb::new(0xff, 0, 0xff),
+        Rgb::new(0xff, 0, 0),
+        Rgb::new(0, 0, 0xff),
+        Rgb::BLACK,
+    ];
+    let bar_width = width / bars.len();
+    for (index, &color) in bars.iter().enumerate() {
+        framebuffer.fill_rect(index * bar_width, 0, bar_width, height * 2 / 3, color);
+    }
+    let gradient: Vec<Rgb> = (0..width)
+        .map(|x| {
+            let level = (x * 255 / width.max(1)) as u8;
+            Rgb::new(level, level, level)
+        })
+        .collect();
+    for y in height * 2 / 3..height * 5 / 6 {
+        framebuffer.blit(0, y, width, &gradient);
+    }
+    if let Some(font) = font() {
+        let caption = format!("rust_os {}x{}", width, height);
+        let y = height * 5 / 6 + font.height();
+        framebuffer.draw_str(font.width(), y, &caption, font, Rgb::WHITE, Rgb::BLACK);
+    }
+    Ok(())
+}
@@ -0,0 +1,373 @@
+//! Bitmap fonts: PC Screen Font files (PSF1 and PSF2), and the font the VGA card itself uses in
+//! text mode.
+//!
+//! Every glyph is `height` rows of `width` pixels, each row padded to whole bytes with the
+//! leftmost pixel in the top bit. Fonts with a Unicode table map characters to glyphs through it;
+//! the VGA font is given code page 437's, and the others without one are taken to be in ASCII
+//! order as far as ASCII goes. `Font::builtin` is always there, compiled into the kernel.
+
+use crate::arch::port::{inb, outb};
+use crate::console::cp437;
+use crate::{memory, vga_buffer};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_START_SEQUENCE: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+/// Drawn for characters the font has no glyph for
+const REPLACEMENT: char = '?';
+
+/// The printable ASCII characters from ' ' on in a 5x7 dot matrix, one byte per column with the
+/// top row in the lowest bit; `Font::builtin` scales them up
+const BUILTIN_GLYPHS: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // "'"
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x01, 0x01], // 'F'
+    [0x3e, 0x41, 0x41, 0x51, 0x32], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x04, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x7f, 0x20, 0x18, 0x20, 0x7f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x03, 0x04, 0x78, 0x04, 0x03], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x04, 0x08, 0x04], // '~'
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The file ends before the glyphs it announces
+    Truncated,
+    /// Neither a PSF1 nor a PSF2 file
+    BadMagic,
+    /// A PSF file this renderer can't use; the reason says why
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Truncated => f.write_str("font file is truncated"),
+            FontError::BadMagic => f.write_str("not a PSF font"),
+            FontError::Unsupported(what) => write!(f, "unsupported font: {}", what),
+        }
+    }
+}
+
+pub struct Font {
+    width: usize,
+    height: usize,
+    /// Bytes in each row of a glyph
+    row_bytes: usize,
+    glyph_count: usize,
+    glyphs: Vec<u8>,
+    /// Glyph index of each character, from the font's Unicode table; empty without one
+    unicode: BTreeMap<char, usize>,
+}
+
+impl Font {
+    /// Parses a PSF1 or PSF2 font file
+    pub fn parse(bytes: &[u8]) -> Result<Font, FontError> {
+        if bytes.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(bytes)
+        } else if bytes.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(bytes)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(bytes: &[u8]) -> Result<Font, FontError> {
+        let [_, _, mode, height, ..] = *bytes else {
+            return Err(FontError::Truncated);
+        };
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let height = height as usize;
+        let glyphs_end = 4 + glyph_count * height;
+        let glyphs = bytes.get(4..glyphs_end).ok_or(FontError::Truncated)?;
+
+        let mut unicode = BTreeMap::new();
+        if mode & PSF1_MODE_HAS_TABLE != 0 {
+            let table = &bytes[glyphs_end..];
+            let mut entries = table
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+            for glyph in 0..glyph_count {
+                let mut in_sequence = false;
+                for entry in entries.by_ref() {
+                    match entry {
+                        PSF1_SEPARATOR => break,
+                        PSF1_START_SEQUENCE => in_sequence = true,
+                        _ if in_sequence => {} // combining sequences aren't drawn
+                        _ => {
+                            if let Some(ch) = char::from_u32(entry as u32) {
+                                unicode.entry(ch).or_insert(glyph);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Font::new(8, height, glyph_count, glyphs.to_vec(), unicode)
+    }
+
+    fn parse_psf2(bytes: &[u8]) -> Result<Font, FontError> {
+        let field = |index: usize| {
+            bytes
+                .get(4 * index..4 * index + 4)
+                .map(|field| u32::from_le_bytes(field.try_into().unwrap()) as usize)
+                .ok_or(FontError::Truncated)
+        };
+        let header_size = field(2)?;
+        let flags = field(3)? as u32;
+        let glyph_count = field(4)?;
+        let glyph_size = field(5)?;
+        let height = field(6)?;
+        let width = field(7)?;
+        if width == 0 || height == 0 || glyph_size != width.div_ceil(8) * height {
+            return Err(FontError::Unsupported(
+                "glyph size doesn't match its dimensions",
+            ));
+        }
+        let glyphs_end = glyph_count
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        let glyphs = bytes
+            .get(header_size..glyphs_end)
+            .ok_or(FontError::Truncated)?;
+
+        let mut unicode = BTreeMap::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let mut table = bytes[glyphs_end..].split(|&byte| byte == PSF2_SEPARATOR);
+            for (glyph, entry) in table.by_ref().take(glyph_count).enumerate() {
+                // single characters come first, then sequences, each introduced by 0xfe
+                let singles = entry.split(|&byte| byte == PSF2_START_SEQUENCE).next();
+                let text = singles.and_then(|singles| core::str::from_utf8(singles).ok());
+                for ch in text.into_iter().flat_map(str::chars) {
+                    unicode.entry(ch).or_insert(glyph);
+                }
+            }
+        }
+        Font::new(width, height, glyph_count, glyphs.to_vec(), unicode)
+    }
+
+    fn new(
+        width: usize,
+        height: usize,
+        glyph_count: usize,
+        glyphs: Vec<u8>,
+        unicode: BTreeMap<char, usize>,
+    ) -> Result<Font, FontError> {
+        if glyph_count == 0 || height == 0 {
+            return Err(FontError::Unsupported("no glyphs"));
+        }
+        Ok(Font {
+            width,
+            height,
+            row_bytes: width.div_ceil(8),
+            glyph_count,
+            glyphs,
+            unicode,
+        })
+    }
+
+    /// The 8-pixel-wide font of the VGA card, read out of its plane 2 where text mode keeps it,
+    /// with its glyphs mapped as code page 437 has them. Only works while the card is still in
+    /// text mode, i.e. before switching to graphics.
+    pub fn vga() -> Font {
+        const VGA_WINDOW: u64 = 0xa_0000;
+        /// Every glyph takes 32 bytes in plane 2, however many rows the font has
+        const SLOT: usize = 32;
+
+        // (index port, index, value) to read plane 2 linearly at VGA_WINDOW
+        let settings: [(u16, u8, u8); 5] = [
+            (0x3c4, 2, 0x04), // sequencer map mask: plane 2
+            (0x3c4, 4, 0x07), // sequencer memory mode: sequential addressing
+            (0x3ce, 4, 0x02), // graphics read map select: plane 2
+            (0x3ce, 5, 0x00), // graphics mode: no odd/even
+            (0x3ce, 6, 0x04), // graphics miscellaneous: 64 KiB at 0xa0000, no odd/even
+        ];
+        let height = unsafe {
+            outb(0x3d4, 0x09); // CRTC maximum scan line: glyph height - 1
+            (inb(0x3d5) & 0x1f) as usize + 1
+        };
+        let mut glyphs = Vec::with_capacity(256 * height);
+        interrupts::without_interrupts(|| unsafe {
+            // text written meanwhile would land in plane 2, right in the font
+            let _writer = vga_buffer::WRITER.lock();
+            let mut saved = [0u8; 5];
+            for (slot, &(port, index, value)) in saved.iter_mut().zip(&settings) {
+                outb(port, index);
+                *slot = inb(port + 1);
+                outb(port + 1, value);
+            }
+            let plane = memory::phys_to_virt(PhysAddr::new(VGA_WINDOW)).as_ptr::<u8>();
+            for glyph in 0..256 {
+                for row in 0..height {
+                    glyphs.push(core::ptr::read_volatile(plane.add(glyph * SLOT + row)));
+                }
+            }
+            for (&saved, &(port, index, _)) in saved.iter().zip(&settings) {
+                outb(port, index);
+                outb(port + 1, saved);
+            }
+        });
+        let unicode = (1..=255)
+            .filter_map(|byte| Some((cp437::decode(byte)?, byte as usize)))
+            .collect();
+        Font::new(8, height, 256, glyphs, unicode).expect("the VGA font has glyphs")
+    }
+
+    /// A 12x16 ASCII font compiled into the kernel, for when no other is at hand: the 5x7
+    /// `BUILTIN_GLYPHS` at twice their size, with a pixel of space left of each and two rows
+    /// below
+    pub fn builtin() -> Font {
+        const WIDTH: usize = 12;
+        const HEIGHT: usize = 16;
+        const ROW_BYTES: usize = WIDTH.div_ceil(8);
+
+        let mut glyphs = alloc::vec![0u8; 128 * HEIGHT * ROW_BYTES];
+        for (index, columns) in BUILTIN_GLYPHS.iter().enumerate() {
+            let glyph = &mut glyphs[(b' ' as usize + index) * HEIGHT * ROW_BYTES..];
+            for y in 0..HEIGHT - 2 {
+                let mut row = 0u16;
+                for (x, column) in columns.iter().enumerate() {
+                    if column >> (y / 2) & 1 != 0 {
+                        row |= 0b11 << (16 - 3 - 2 * x); // pixels 1 + 2x and 2 + 2x
+                    }
+                }
+                glyph[y * ROW_BYTES..(y + 1) * ROW_BYTES].copy_from_slice(&row.to_be_bytes());
+            }
+        }
+        Font::new(WIDTH, HEIGHT, 128, glyphs, BTreeMap::new())
+            .expect("the built-in font has glyphs")
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The bitmap for `ch`: `height` rows of `width.div_ceil(8)` bytes each. Characters the font
+    /// lacks get `REPLACEMENT`, or the first glyph if it lacks that too.
+    pub fn glyph(&self, ch: char) -> &[u8] {
+        let index = self
+            .index(ch)
+            .or_else(|| self.index(REPLACEMENT))
+            .unwrap_or(0);
+        let size = self.row_bytes * self.height;
+        &self.glyphs[index * size..(index + 1) * size]
+    }
+
+    /// Bytes in each row of a glyph
+    pub fn row_bytes(&self) -> usize {
+        self.row_bytes
+    }
+
+    fn index(&self, ch: char) -> Option<usize> {
+        match self.unicode.is_empty() {
+            true => ch.is_ascii().then_some(ch as usize),
+            false => self.unicode.get(&ch).copied(),
+        }
+        .filter(|&index| index < self.glyph_count)
+    }
+}
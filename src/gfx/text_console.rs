@@ -0,0 +1,138 @@
+//! The console on the framebuffer: a grid of font-sized cells with a cursor, what the VGA text
+//! buffer is in text mode.
+//!
+//! Characters take as many cells as `console::unicode::width` says, so the cursor stays where a
+//! terminal would put it: a wide character is drawn in the first of its two cells and a
+//! combining mark over the character before it, if that's on the same line.
+//!
+//! Drawing takes the `FRAMEBUFFER` lock while the console's own lock is held, so nothing may
+//! print while holding the framebuffer; that would deadlock.
+
+use super::{font::Font, Framebuffer, Rgb, FRAMEBUFFER};
+use crate::console::{unicode, Backend, Colors};
+use crate::vga_buffer::Color;
+
+/// Rows of the cursor, counted from the bottom of the cell
+const CURSOR_HEIGHT: usize = 2;
+
+pub struct TextConsole {
+    font: &'static Font,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    /// Column of the last character drawn on the cursor's row, for marks to combine with
+    last: Option<usize>,
+    foreground: Rgb,
+    background: Rgb,
+}
+
+impl TextConsole {
+    /// A console filling `framebuffer` with cells of `font`, the cursor on the top left, in the
+    /// default colors
+    pub fn new(framebuffer: &Framebuffer, font: &'static Font) -> TextConsole {
+        TextConsole {
+            font,
+            columns: (framebuffer.width() / font.width()).max(1),
+            rows: (framebuffer.height() / font.height()).max(1),
+            column: 0,
+            row: 0,
+            last: None,
+            foreground: Rgb::from(Colors::DEFAULT.foreground),
+            background: Rgb::from(Colors::DEFAULT.background),
+        }
+    }
+
+    /// Top left corner of the cell under the cursor, in pixels
+    fn cursor_position(&self) -> (usize, usize) {
+        (
+            self.column * self.font.width(),
+            self.row * self.font.height(),
+        )
+    }
+
+    fn draw_cursor(&self, framebuffer: &mut Framebuffer, color: Rgb) {
+        let (x, y) = self.cursor_position();
+        let y = y + self.font.height() - CURSOR_HEIGHT;
+        framebuffer.fill_rect(x, y, self.font.width(), CURSOR_HEIGHT, color);
+    }
+
+    fn new_line(&mut self, framebuffer: &mut Framebuffer) {
+        self.column = 0;
+        self.last = None;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            framebuffer.scroll_up(self.font.height(), self.background);
+        }
+    }
+
+    fn write_char(&mut self, framebuffer: &mut Framebuffer, ch: char) {
+        match ch {
+            '\n' => self.new_line(framebuffer),
+            '\r' => {
+                self.column = 0;
+                self.last = None;
+            }
+            '\x08' => {
+                self.column = self.column.saturating_sub(1);
+                self.last = None;
+            }
+            ch => match unicode::width(ch) {
+                0 => {
+                    if let Some(column) = self.last {
+                        let (x, y) = (column * self.font.width(), self.row * self.font.height());
+                        framebuffer.overlay_char(x, y, ch, self.font, self.foreground);
+                    }
+                }
+                width => {
+                    // a wide character doesn't start in the last column, it wraps whole
+                    if self.column + width > self.columns && self.column > 0 {
+                        self.new_line(framebuffer);
+                    }
+                    let (x, y) = self.cursor_position();
+                    let (fg, bg) = (self.foreground, self.background);
+                    framebuffer.draw_char(x, y, ch, self.font, fg, bg);
+                    if width == 2 && self.column + 1 < self.columns {
+                        let cell = self.font.width();
+                        framebuffer.fill_rect(x + cell, y, cell, self.font.height(), bg);
+                    }
+                    self.last = Some(self.column);
+                    self.column = (self.column + width).min(self.columns);
+                }
+            },
+        }
+    }
+}
+
+impl Backend for TextConsole {
+    fn write(&mut self, text: &str) {
+        let mut guard = FRAMEBUFFER.lock();
+        let Some(framebuffer) = guard.as_mut() else {
+            return;
+        };
+        self.draw_cursor(framebuffer, self.background);
+        for ch in text.chars() {
+            self.write_char(framebuffer, ch);
+        }
+        // past the last column the cursor waits for the next character to wrap
+        if self.column < self.columns {
+            self.draw_cursor(framebuffer, self.foreground);
+        }
+    }
+
+    fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.foreground = Rgb::from(foreground);
+        self.background = Rgb::from(background);
+    }
+
+    fn clear(&mut self) {
+        if let Some(framebuffer) = FRAMEBUFFER.lock().as_mut() {
+            let (width, height) = (framebuffer.width(), framebuffer.height());
+            framebuffer.fill_rect(0, 0, width, height, self.background);
+        }
+        self.column = 0;
+        self.row = 0;
+        self.last = None;
+    }
+}
@@ -0,0 +1,734 @@
+use crate::console::raw;
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub;
+use crate::symbols::Symbolized;
+use crate::trace::{self, Event};
+use crate::unwind::Frames;
+use crate::usermode::emulate::{self, Instruction};
+use crate::usermode::{self, Registers, UserExit};
+use crate::{
+    acpi, apic, fpu, gdt, kdb, memory, percpu, pit, println, profile, rcu, scrub, warnln, watchdog,
+};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+pub mod early;
+pub mod latency;
+pub mod page_fault;
+pub mod stats;
+pub mod tickless;
+
+/// The PICs are remapped past the 32 CPU exception vectors. The IO-APIC reuses the same layout
+/// (ISA IRQ n -> vector 32 + n) so handlers don't care which controller is active.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// `IA32_GS_BASE`, the GS base the CPU is using
+const GS_BASE_MSR: u32 = 0xc000_0101;
+
+/// RFLAGS bit that makes the CPU raise a debug exception after every instruction
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// Frequency of the timer interrupt, whichever device drives it
+pub const TIMER_HZ: u32 = 100;
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+/// Which interrupt controller `init_controller` settled on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// What a driver wants run when its ISA interrupt line fires
+pub type IrqHandler = fn();
+
+/// The drivers' handlers, by IRQ number
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; 16]> = Mutex::new([None; 16]);
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.debug
+                .set_handler_addr(VirtAddr::from_ptr(debug_entry as *const ()));
+            // user programs hand control back with int3
+            idt.breakpoint
+                .set_handler_addr(VirtAddr::from_ptr(breakpoint_entry as *const ()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        unsafe {
+            idt.divide_error
+                .set_handler_addr(VirtAddr::from_ptr(divide_error_entry as *const ()));
+        }
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_addr(VirtAddr::from_ptr(nmi_entry as *const ()));
+            idt.device_not_available
+                .set_handler_addr(VirtAddr::from_ptr(device_not_available_entry as *const ()));
+        }
+        unsafe {
+            // either may be an instruction `usermode::emulate` carries out
+            idt.invalid_opcode
+                .set_handler_addr(VirtAddr::from_ptr(invalid_opcode_entry as *const ()));
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::from_ptr(general_protection_entry as *const ()));
+        }
+        unsafe {
+            idt.alignment_check
+                .set_handler_addr(VirtAddr::from_ptr(alignment_check_entry as *const ()));
+            idt.page_fault
+                .set_handler_addr(VirtAddr::from_ptr(page_fault_entry as *const ()));
+        }
+        unsafe {
+            idt.double_fault
+                .set_handler_addr(VirtAddr::from_ptr(double_fault_entry as *const ()))
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        let irq_entries = VirtAddr::from_ptr(irq_entries as *const ());
+        for irq in 0..16 {
+            unsafe {
+                idt[usize::from(PIC_1_OFFSET) + irq]
+                    .set_handler_addr(irq_entries + irq as u64 * IRQ_ENTRY_SIZE);
+            }
+        }
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::from_ptr(timer_interrupt_entry as *const ()));
+            idt[apic::WAKEUP_VECTOR as usize]
+                .set_handler_addr(VirtAddr::from_ptr(wakeup_entry as *const ()));
+            idt[apic::SPURIOUS_VECTOR as usize]
+                .set_handler_addr(VirtAddr::from_ptr(spurious_entry as *const ()));
+        }
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Puts the IDT under the scrubber's watch, once the heap is up
+pub fn protect() {
+    scrub::protect_static("IDT", &*IDT);
+}
+
+/// Brings up the Local APIC/IO-APIC, or the 8259 PIC pair on machines without an APIC.
+///
+/// The PICs are always remapped first: even when they end up disabled, a spurious legacy
+/// interrupt must not land on a CPU exception vector.
+pub fn init_controller() -> Controller {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.write_masks(0xFF, 0xFF);
+    }
+
+    match apic::init() {
+        Ok(()) => {
+            if let Some(local_apic) = apic::local_apic() {
+                local_apic.start_periodic_timer(InterruptIndex::Timer.as_u8(), TIMER_HZ);
+            }
+            Controller::Apic
+        }
+        Err(err) => {
+            warnln!("apic: {}, falling back to the 8259 PIC", err);
+            pit::set_frequency(TIMER_HZ);
+            unsafe { PICS.lock().write_masks(!0b101, 0xFF) }; // timer and the cascade line
+            Controller::Pic
+        }
+    }
+}
+
+/// What the interrupt controllers were programmed with, saved by `save_controller` before sleep
+pub struct SavedController {
+    pic_masks: [u8; 2],
+    /// IO-APIC redirection entries, empty in PIC mode
+    redirections: Vec<u64>,
+}
+
+/// Saves the controller state that sleep wipes; call with interrupts disabled
+pub fn save_controller() -> SavedController {
+    SavedController {
+        pic_masks: unsafe { PICS.lock().read_masks() },
+        redirections: apic::IO_APIC
+            .lock()
+            .as_ref()
+            .map(|io_apic| io_apic.save())
+            .unwrap_or_default(),
+    }
+}
+
+/// Programs the controllers (and, in PIC mode, the PIT) on the boot CPU the way they were when
+/// `saved` was taken, after waking from sleep reset them
+pub fn restore_controller(saved: &SavedController) {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        let [mask1, mask2] = saved.pic_masks;
+        pics.write_masks(mask1, mask2);
+    }
+    match apic::IO_APIC.lock().as_mut() {
+        Some(io_apic) => {
+            apic::init_ap();
+            io_apic.restore(&saved.redirections);
+        }
+        None => pit::set_frequency(TIMER_HZ),
+    }
+}
+
+/// Unmasks ISA interrupt line `irq`, delivering it to vector `PIC_1_OFFSET + irq`
+pub fn enable_irq(irq: u8) {
+    let vector = PIC_1_OFFSET + irq;
+    // the timer handler takes the PICS lock for EOI, so it must not interrupt us while we hold it
+    interrupts::without_interrupts(|| {
+        if let Some(local_apic) = apic::local_apic() {
+            if let Some(io_apic) = apic::IO_APIC.lock().as_mut() {
+                // firmware may have wired the ISA line to another IO-APIC input (e.g. the PIT to 2)
+                match acpi::madt().map(|madt| madt.isa_route(irq)) {
+                    Some(route) => io_apic.set_redirection_with(
+                        route.gsi as u8,
+                        vector,
+                        local_apic.id(),
+                        route.active_low,
+                        route.level_triggered,
+                    ),
+                    None => io_apic.set_redirection(irq, vector, local_apic.id()),
+                }
+            }
+        } else {
+            let mut pics = PICS.lock();
+            unsafe {
+                let [mask1, mask2] = pics.read_masks();
+                match irq {
+                    0..=7 => pics.write_masks(mask1 & !(1 << irq), mask2),
+                    _ => pics.write_masks(mask1 & !0b100, mask2 & !(1 << (irq - 8))),
+                }
+            }
+        }
+    });
+}
+
+/// Runs `handler` whenever ISA interrupt line `irq` fires, and unmasks the line. The handler runs
+/// in interrupt context; the interrupt is acknowledged after it returns.
+pub fn set_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), &'static str> {
+    if irq == 0 || irq == 2 || irq >= 16 {
+        return Err("not an ISA interrupt line a driver can have");
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err("the interrupt line is taken");
+        }
+        handlers[irq as usize] = Some(handler);
+        Ok(())
+    })?;
+    enable_irq(irq);
+    Ok(())
+}
+
+extern "C" fn irq_trap(_registers: &mut Registers, irq: u64) {
+    irq_interrupt(irq as u8);
+}
+
+fn irq_interrupt(irq: u8) {
+    let vector = PIC_1_OFFSET + irq;
+    stats::count(vector);
+    trace::record(Event::IrqEntry { vector });
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    end_of_interrupt(vector);
+    trace::record(Event::IrqExit { vector });
+}
+
+/// Acknowledges an interrupt at whichever controller delivered it
+pub fn end_of_interrupt(vector: u8) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+    }
+}
+
+/// Number of timer interrupts (on the boot CPU) since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+global_asm!(
+    r#"
+    # Exceptions land here with all registers saved as a `Registers`, which the handler may
+    # change: for gdb (see `gdbstub`), to emulate an instruction (see `usermode::emulate`), or
+    # to keep them for a program's signal handler (see `usermode::fault`). From ring 3, `swapgs`
+    # puts the kernel's GS base in place first, and the program's back before the `iretq`, with
+    # interrupts off in between, as the handler may have turned them on.
+    .macro TRAP_ENTRY name, handler
+    .global \name
+    \name:
+        testb $3, 8(%rsp)           # RPL of the interrupted code segment
+        jz 1f
+        swapgs
+    1:  pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+        movq %rsp, %rdi
+        cld
+        callq \handler
+        TRAP_EXIT
+    .endm
+    # The same for exceptions with an error code, which is passed as the second argument. r15
+    # takes its place on the stack so the registers still end with the interrupt frame.
+    .macro TRAP_ENTRY_ERROR name, handler
+    .global \name
+    \name:
+        testb $3, 16(%rsp)
+        jz 1f
+        swapgs
+    1:  xchgq (%rsp), %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+        movq %rsp, %rdi
+        movq %r15, %rsi
+        cld
+        callq \handler
+        TRAP_EXIT
+    .endm
+    .macro TRAP_EXIT
+        cli
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        testb $3, 8(%rsp)
+        jz 2f
+        swapgs
+    2:  iretq
+    .endm
+    TRAP_ENTRY breakpoint_entry, {breakpoint}
+    TRAP_ENTRY debug_entry, {debug}
+    TRAP_ENTRY divide_error_entry, {divide_error}
+    TRAP_ENTRY device_not_available_entry, {device_not_available}
+    TRAP_ENTRY invalid_opcode_entry, {invalid_opcode}
+    TRAP_ENTRY_ERROR general_protection_entry, {general_protection}
+    TRAP_ENTRY_ERROR page_fault_entry, {page_fault}
+    TRAP_ENTRY_ERROR alignment_check_entry, {alignment_check}
+    TRAP_ENTRY wakeup_entry, {wakeup}
+    TRAP_ENTRY spurious_entry, {spurious}
+
+    # One 16-byte entry per ISA line, passing its IRQ number where an error code would be
+    .balign 16
+    .global irq_entries
+    irq_entries:
+    .irp irq, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+        .balign 16
+        pushq $\irq
+        jmp irq_common_entry
+    .endr
+    TRAP_ENTRY_ERROR irq_common_entry, {irq}
+
+    # NMIs arrive anywhere, even between a `swapgs` and the `iretq` or `sysretq` after it, so
+    # the interrupted code segment doesn't say whose GS base is loaded. The base itself does:
+    # the kernel's is in the upper half, and a program's never is, as it can only load one from
+    # a segment descriptor. rbx remembers whether it was swapped across the call.
+    .global nmi_entry
+    nmi_entry:
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+        movl ${gs_base}, %ecx
+        rdmsr
+        xorl %ebx, %ebx
+        testl %edx, %edx
+        js 1f
+        swapgs
+        movl $1, %ebx
+    1:  movq %rsp, %rdi
+        cld
+        callq {nmi}
+        testl %ebx, %ebx
+        jz 2f
+        swapgs
+    2:  popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        iretq
+
+    # A double fault can hit the same windows, and doesn't return
+    .global double_fault_entry
+    double_fault_entry:
+        pushq %rax
+        pushq %rcx
+        pushq %rdx
+        movl ${gs_base}, %ecx
+        rdmsr
+        testl %edx, %edx
+        js 1f
+        swapgs
+    1:  popq %rdx
+        popq %rcx
+        popq %rax
+        jmp {double_fault}
+    "#,
+    breakpoint = sym breakpoint_trap,
+    debug = sym debug_trap,
+    divide_error = sym divide_error_trap,
+    device_not_available = sym device_not_available_trap,
+    invalid_opcode = sym invalid_opcode_trap,
+    general_protection = sym general_protection_trap,
+    page_fault = sym page_fault_trap,
+    alignment_check = sym alignment_check_trap,
+    wakeup = sym wakeup_trap,
+    spurious = sym spurious_trap,
+    irq = sym irq_trap,
+    nmi = sym nmi_trap,
+    double_fault = sym double_fault_handler,
+    gs_base = const GS_BASE_MSR,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn breakpoint_entry();
+    fn debug_entry();
+    fn divide_error_entry();
+    fn device_not_available_entry();
+    fn invalid_opcode_entry();
+    fn general_protection_entry();
+    fn page_fault_entry();
+    fn alignment_check_entry();
+    fn wakeup_entry();
+    fn spurious_entry();
+    fn irq_entries();
+    fn nmi_entry();
+    fn double_fault_entry();
+}
+
+/// Bytes from one of `irq_entries` to the next
+const IRQ_ENTRY_SIZE: u64 = 16;
+
+extern "C" fn breakpoint_trap(registers: &mut Registers) {
+    stats::count(3);
+    if registers.cs & 3 == 3 {
+        usermode::fault(registers, UserExit::Breakpoint { rip: registers.rip });
+    }
+    #[cfg(feature = "gdbstub")]
+    if gdbstub::is_attached() {
+        return gdbstub::trap(registers, gdbstub::Trap::Breakpoint);
+    }
+    println!("EXCEPTION: BREAKPOINT at {}", Symbolized(registers.rip));
+}
+
+/// Single steps and hardware breakpoints; without gdb there's nobody to hand them to, so the
+/// step is ended and the code runs on
+extern "C" fn debug_trap(registers: &mut Registers) {
+    stats::count(1);
+    #[cfg(feature = "gdbstub")]
+    if registers.cs & 3 == 0 && gdbstub::is_attached() {
+        return gdbstub::trap(registers, gdbstub::Trap::Debug);
+    }
+    registers.rflags &= !TRAP_FLAG;
+    println!("EXCEPTION: DEBUG at {}", Symbolized(registers.rip));
+}
+
+extern "C" fn divide_error_trap(registers: &mut Registers) {
+    stats::count(0);
+    if registers.cs & 3 == 3 {
+        usermode::fault(registers, UserExit::DivideError { rip: registers.rip });
+    }
+    panic!(
+        "EXCEPTION: DIVIDE ERROR at {}\n{:#x?}",
+        Symbolized(registers.rip),
+        registers
+    );
+}
+
+/// The crash debugger stops the other CPUs with an NMI; any other is ignored
+extern "C" fn nmi_trap(_registers: &mut Registers) {
+    stats::count(2);
+    if kdb::is_active() {
+        kdb::park();
+    }
+}
+
+/// The FPU was used while CR0.TS was set, see `fpu`
+extern "C" fn device_not_available_trap(registers: &mut Registers) {
+    stats::count(7);
+    fpu::device_not_available(registers.cs & 3 == 3);
+}
+
+extern "C" fn invalid_opcode_trap(registers: &mut Registers) {
+    stats::count(6);
+    if registers.cs & 3 == 3 {
+        if emulate::invalid_opcode(registers) {
+            return;
+        }
+        let reason = UserExit::InvalidOpcode {
+            rip: registers.rip,
+            instruction: Instruction::fetch(registers.rip),
+        };
+        usermode::fault(registers, reason);
+    }
+    panic!(
+        "EXCEPTION: INVALID OPCODE at {}\n{:#x?}",
+        Symbolized(registers.rip),
+        registers
+    );
+}
+
+extern "C" fn general_protection_trap(registers: &mut Registers, error_code: u64) {
+    stats::count(13);
+    if registers.cs & 3 == 3 {
+        if emulate::general_protection(registers, error_code) {
+            return;
+        }
+        let reason = UserExit::GeneralProtection {
+            error_code,
+            rip: registers.rip,
+        };
+        usermode::fault(registers, reason);
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x}) at {}\n{:#x?}",
+        error_code,
+        Symbolized(registers.rip),
+        registers
+    );
+}
+
+/// Only raised in ring 3, with RFLAGS.AC set, which programs may do (see
+/// `usermode::USER_RFLAGS_WRITABLE`)
+extern "C" fn alignment_check_trap(registers: &mut Registers, _error_code: u64) {
+    stats::count(17);
+    if registers.cs & 3 == 3 {
+        usermode::fault(registers, UserExit::AlignmentCheck { rip: registers.rip });
+    }
+    panic!(
+        "EXCEPTION: ALIGNMENT CHECK at {}\n{:#x?}",
+        Symbolized(registers.rip),
+        registers
+    );
+}
+
+extern "C" fn page_fault_trap(registers: &mut Registers, error_code: u64) {
+    stats::count(14);
+    page_fault::handle(
+        registers,
+        PageFaultErrorCode::from_bits_truncate(error_code),
+    );
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    stats::count(8);
+    // a page fault that couldn't push its frame leaves its address in CR2
+    let address = Cr2::read();
+    // said before panicking, which formats and takes locks and may well fault again
+    raw::Writer
+        .str("\ndouble fault at rip ")
+        .hex(stack_frame.instruction_pointer.as_u64())
+        .str(", rsp ")
+        .hex(stack_frame.stack_pointer.as_u64())
+        .str(", cr2 ")
+        .hex(address.as_u64())
+        .str("\n");
+    if memory::in_kernel_stack_guard(address) {
+        panic!(
+            "EXCEPTION: KERNEL STACK OVERFLOW accessing {:?} at {}\n{:#?}",
+            address,
+            Symbolized(stack_frame.instruction_pointer.as_u64()),
+            stack_frame
+        );
+    }
+    panic!(
+        "EXCEPTION: DOUBLE FAULT at {}\n{:#?}",
+        Symbolized(stack_frame.instruction_pointer.as_u64()),
+        stack_frame
+    );
+}
+
+global_asm!(
+    r#"
+    # The timer's vector lands here. From ring 0 it's an ordinary interrupt handler; from ring 3
+    # the GS base is swapped as for the exceptions and the program's registers are saved as a
+    # `Registers` first, so it can be preempted.
+    .global timer_interrupt_entry
+    timer_interrupt_entry:
+        testb $3, 8(%rsp)           # RPL of the interrupted code segment
+        jz {kernel}
+        swapgs
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %r11
+        pushq %r10
+        pushq %r9
+        pushq %r8
+        pushq %rbp
+        pushq %rdi
+        pushq %rsi
+        pushq %rdx
+        pushq %rcx
+        pushq %rbx
+        pushq %rax
+        movq %rsp, %rdi             # 15 registers after the 5-word frame keep rsp 16-byte aligned
+        cld
+        callq {user}
+        cli
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        swapgs
+        iretq
+    "#,
+    kernel = sym timer_interrupt_handler,
+    user = sym user_timer_interrupt,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn timer_interrupt_entry();
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // here, so the walk starts at the interrupted code
+    profile::kernel_tick(stack_frame.instruction_pointer.as_u64(), Frames::here());
+    timer_tick();
+    usermode::kernel_timer_tick();
+}
+
+/// The timer interrupted ring 3; returning continues the program
+extern "C" fn user_timer_interrupt(registers: &Registers) {
+    profile::user_tick(registers.rip);
+    timer_tick();
+    usermode::timer_tick(registers);
+}
+
+fn timer_tick() {
+    let entered = latency::timer_interrupt();
+    let vector = InterruptIndex::Timer.as_u8();
+    stats::count(vector);
+    trace::record(Event::IrqEntry { vector });
+    let ticks = tickless::timer_interrupt();
+    if percpu::current().index == 0 {
+        // every CPU has a timer, but time is kept by one
+        latency::tick_entered(TICKS.load(Ordering::Relaxed) + ticks, entered);
+        TICKS.fetch_add(ticks, Ordering::Relaxed);
+    }
+    rcu::quiescent_state(); // also how idle CPUs keep grace periods moving
+    watchdog::tick();
+    end_of_interrupt(vector);
+    trace::record(Event::IrqExit { vector });
+}
+
+/// Nothing to do but end the `hlt` it arrived in (see `smp::offline`)
+extern "C" fn wakeup_trap(_registers: &mut Registers) {
+    stats::count(apic::WAKEUP_VECTOR);
+    end_of_interrupt(apic::WAKEUP_VECTOR);
+}
+
+/// Spurious APIC interrupts are not real interrupts and must not be acknowledged
+extern "C" fn spurious_trap(_registers: &mut Registers) {
+    stats::count(apic::SPURIOUS_VECTOR);
+}
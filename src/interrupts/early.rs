@@ -0,0 +1,128 @@
+//! The IDT in place from the first instructions of the boot until `init_idt` loads the real one.
+//!
+//! Without an IDT any exception is a triple fault, and the machine resets without a word, over
+//! and over if the fault happens on every boot. The handlers here say which exception it was and
+//! where, through `console::raw` since nothing else can be relied on yet, and halt. They use no
+//! IST stacks, as there's no TSS yet, so a fault from running out of stack still resets. All of it
+//! is init code, gone once the boot is over.
+
+use crate::console::raw;
+use spin::Once;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+static IDT: Once<InterruptDescriptorTable> = Once::new();
+
+/// Loads the early IDT; the first thing the kernel does
+#[link_section = ".init.text"]
+pub fn init() {
+    IDT.call_once(|| {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error);
+        idt.debug.set_handler_fn(debug);
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt);
+        idt.breakpoint.set_handler_fn(breakpoint);
+        idt.overflow.set_handler_fn(overflow);
+        idt.bound_range_exceeded
+            .set_handler_fn(bound_range_exceeded);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode);
+        idt.device_not_available
+            .set_handler_fn(device_not_available);
+        idt.double_fault.set_handler_fn(double_fault);
+        idt.invalid_tss.set_handler_fn(invalid_tss);
+        idt.segment_not_present.set_handler_fn(segment_not_present);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault);
+        idt.page_fault.set_handler_fn(page_fault);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point);
+        idt.alignment_check.set_handler_fn(alignment_check);
+        idt.machine_check.set_handler_fn(machine_check);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point);
+        idt
+    })
+    .load();
+}
+
+/// Says what happened and stops for good
+#[link_section = ".init.text"]
+fn report(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    let mut out = raw::Writer;
+    out.str("\nEARLY EXCEPTION: ")
+        .str(name)
+        .str(" at rip ")
+        .hex(stack_frame.instruction_pointer.as_u64())
+        .str(", rsp ")
+        .hex(stack_frame.stack_pointer.as_u64());
+    if let Some(error_code) = error_code {
+        out.str(", error code ").hex(error_code);
+    }
+    out.str("\n");
+    x86_64::instructions::interrupts::disable();
+    crate::hlt_loop();
+}
+
+/// Handlers for the exceptions that push no error code
+macro_rules! without_error_code {
+    ($($handler:ident => $name:literal),* $(,)?) => {
+        $(
+            #[link_section = ".init.text"]
+            extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+                report($name, &stack_frame, None);
+            }
+        )*
+    };
+}
+
+/// Handlers for the exceptions that push one
+macro_rules! with_error_code {
+    ($($handler:ident => $name:literal),* $(,)?) => {
+        $(
+            #[link_section = ".init.text"]
+            extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+                report($name, &stack_frame, Some(error_code));
+            }
+        )*
+    };
+}
+
+without_error_code! {
+    divide_error => "DIVIDE ERROR",
+    debug => "DEBUG",
+    non_maskable_interrupt => "NMI",
+    breakpoint => "BREAKPOINT",
+    overflow => "OVERFLOW",
+    bound_range_exceeded => "BOUND RANGE EXCEEDED",
+    invalid_opcode => "INVALID OPCODE",
+    device_not_available => "DEVICE NOT AVAILABLE",
+    x87_floating_point => "X87 FLOATING POINT",
+    simd_floating_point => "SIMD FLOATING POINT",
+}
+
+with_error_code! {
+    invalid_tss => "INVALID TSS",
+    segment_not_present => "SEGMENT NOT PRESENT",
+    stack_segment_fault => "STACK SEGMENT FAULT",
+    general_protection_fault => "GENERAL PROTECTION FAULT",
+    alignment_check => "ALIGNMENT CHECK",
+}
+
+#[link_section = ".init.text"]
+extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    report("DOUBLE FAULT", &stack_frame, Some(error_code));
+}
+
+#[link_section = ".init.text"]
+extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
+    report("MACHINE CHECK", &stack_frame, None);
+}
+
+#[link_section = ".init.text"]
+extern "x86-interrupt" fn page_fault(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    raw::Writer.str("\naccessing ").hex(Cr2::read().as_u64());
+    report("PAGE FAULT", &stack_frame, Some(error_code.bits()));
+}
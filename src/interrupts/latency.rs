@@ -0,0 +1,253 @@
+//! Interrupt latency: how long the kernel takes to react to the timer.
+//!
+//! Two delays are measured on every tick. `TIMER_EXPIRY` is the time from the timer reaching its
+//! terminal count to the handler's first instruction, read off the Local APIC timer's (or the
+//! PIT's) count; it grows whenever interrupts are disabled, i.e. with every long critical section.
+//! `WAKEUP` is the time from the handler entry of the tick a sleep was waiting for to the executor
+//! calling the sleeping task's waker, taken with the TSC; it adds whatever the executor was busy
+//! with. Both go into log2 histograms that the `latency` shell command prints.
+
+use crate::clock::tsc;
+use crate::{apic, interrupts, pit, print, println};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of histogram buckets; bucket `i` counts samples of `2^i` up to `2^(i+1)` nanoseconds,
+/// and the last one everything from about two seconds up
+pub const BUCKETS: usize = 32;
+
+/// How many ticks' handler entry times are remembered for `record_wakeup`
+const TICK_HISTORY: usize = 64;
+
+/// Timer expiry to timer handler entry, on every CPU
+pub static TIMER_EXPIRY: Histogram = Histogram::new();
+/// Timer handler entry to task wake-up
+pub static WAKEUP: Histogram = Histogram::new();
+
+/// TSC ticks per millisecond, 0 until `init`
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at the handler entry of tick `n`, at index `n % TICK_HISTORY`
+static TICK_ENTERED: [AtomicU64; TICK_HISTORY] = [const { AtomicU64::new(0) }; TICK_HISTORY];
+
+/// Calibrates the TSC so wake-up latencies can be recorded; they're dropped until then
+pub fn init() {
+    TSC_PER_MS.store(tsc::ticks_per_ms(), Ordering::Relaxed);
+}
+
+/// Called first thing in the timer handler; records how late it runs and returns the TSC value
+/// at entry
+pub fn timer_interrupt() -> u64 {
+    let entered = tsc::read();
+    let since_expiry = match apic::local_apic() {
+        Some(local_apic) => local_apic.timer_elapsed_ns(),
+        None => pit::elapsed_ns(),
+    };
+    if let Some(nanos) = since_expiry {
+        TIMER_EXPIRY.record(nanos);
+    }
+    entered
+}
+
+/// Remembers when the handler for tick `tick` was entered; called before the tick count moves on
+pub fn tick_entered(tick: u64, entered: u64) {
+    TICK_ENTERED[tick as usize % TICK_HISTORY].store(entered, Ordering::Release);
+}
+
+/// Records the wake-up of a task whose sleep ended at tick `deadline`
+pub fn record_wakeup(deadline: u64) {
+    let tsc_per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    let entered = TICK_ENTERED[deadline as usize % TICK_HISTORY].load(Ordering::Acquire);
+    // the slot has been reused by a later tick if the executor was that far behind
+    if tsc_per_ms == 0 || interrupts::ticks() - deadline >= TICK_HISTORY as u64 {
+        return;
+    }
+    let cycles = tsc::read().saturating_sub(entered);
+    WAKEUP.record((cycles as u128 * 1_000_000 / tsc_per_ms as u128) as u64);
+}
+
+/// A log2 histogram of latencies in nanoseconds that can be updated from interrupt handlers
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    /// For the standard deviation; saturates rather than wrapping
+    sum_of_squares: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            sum_of_squares: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, nanos: u64) {
+        let bucket = (63 - (nanos | 1).leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        let square = nanos.saturating_mul(nanos);
+        let _ = self
+            .sum_of_squares
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some(sum.saturating_add(square))
+            });
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.sum_of_squares.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+
+    /// The counts as they are now; samples recorded meanwhile may be half included
+    pub fn summary(&self) -> Summary {
+        let count = self.count.load(Ordering::Relaxed);
+        let (mean, jitter) = match count {
+            0 => (0, 0),
+            count => {
+                let mean = self.sum.load(Ordering::Relaxed) / count;
+                let mean_square = self.sum_of_squares.load(Ordering::Relaxed) / count;
+                (
+                    mean,
+                    mean_square
+                        .saturating_sub(mean.saturating_mul(mean))
+                        .isqrt(),
+                )
+            }
+        };
+        Summary {
+            count,
+            min: match count {
+                0 => 0,
+                _ => self.min.load(Ordering::Relaxed),
+            },
+            max: self.max.load(Ordering::Relaxed),
+            mean,
+            jitter,
+            buckets: core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A snapshot of a `Histogram`, all times in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    /// Standard deviation
+    pub jitter: u64,
+    pub buckets: [u64; BUCKETS],
+}
+
+impl Summary {
+    /// Upper bound of the bucket holding the `per_mille`th sample (500 is the median)
+    pub fn percentile(&self, per_mille: u64) -> u64 {
+        let rank = (self.count * per_mille).div_ceil(1000);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 2 << bucket;
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 0 {
+            return f.write_str("  no samples\n");
+        }
+        writeln!(
+            f,
+            "  {} samples, min {} avg {} max {} jitter {}",
+            self.count,
+            Nanos(self.min),
+            Nanos(self.mean),
+            Nanos(self.max),
+            Nanos(self.jitter)
+        )?;
+        writeln!(
+            f,
+            "  p50 < {} p99 < {} p99.9 < {}",
+            Nanos(self.percentile(500)),
+            Nanos(self.percentile(990)),
+            Nanos(self.percentile(999))
+        )?;
+        const BAR: &str = "########################################";
+        let largest = self.buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let width = (count * BAR.len() as u64).div_ceil(largest) as usize;
+            writeln!(
+                f,
+                "  {:>6} {:<40} {}",
+                Nanos(1 << bucket),
+                &BAR[..width],
+                count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A duration in nanoseconds, shown in the largest unit it has at least one of
+struct Nanos(u64);
+
+impl fmt::Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self.0 {
+            0..1_000 => alloc::format!("{}ns", self.0),
+            1_000..1_000_000 => alloc::format!("{}us", self.0 / 1_000),
+            1_000_000..1_000_000_000 => alloc::format!("{}ms", self.0 / 1_000_000),
+            _ => alloc::format!("{}s", self.0 / 1_000_000_000),
+        };
+        f.pad(&text)
+    }
+}
+
+/// `latency [reset]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            println!("timer expiry -> handler entry");
+            print!("{}", TIMER_EXPIRY.summary());
+            println!("tick -> task wake-up");
+            print!("{}", WAKEUP.summary());
+            Ok(())
+        }
+        ["reset"] => {
+            TIMER_EXPIRY.reset();
+            WAKEUP.reset();
+            Ok(())
+        }
+        _ => Err("usage: latency [reset]"),
+    }
+}
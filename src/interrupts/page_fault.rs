@@ -0,0 +1,186 @@
+//! The page fault path.
+//!
+//! Most page faults aren't errors: a write to a copy-on-write page, or the first touch of memory
+//! a program reserved, its stack included, is how the kernel finds out it has work to do. `handle`
+//! offers every fault to the handlers in `HANDLERS`, in order, and the first that resolves it
+//! has the faulting instruction run again. A new kind of on-demand memory adds its handler there.
+//!
+//! A fault nobody resolves leaves the program it came from, reported as a stack overflow if it hit
+//! the guard page below one of its stacks, which ends it unless it handles SIGSEGV (see
+//! `process::signal`); in the kernel it's a panic. Either way the report
+//! says what was accessed how, from where, and by whom, see `Fault`.
+
+use crate::memory::{self, address_space};
+use crate::symbols::Symbolized;
+use crate::usermode::{self, Registers, UserExit};
+use crate::{boot, percpu, process};
+use core::fmt;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+/// A page fault as the CPU reported it
+pub struct Fault {
+    /// The address accessed, from CR2
+    pub address: VirtAddr,
+    pub error_code: PageFaultErrorCode,
+    pub rip: u64,
+    pub rsp: u64,
+    /// Whether ring 3 faulted
+    pub user: bool,
+}
+
+impl Fault {
+    pub fn is_write(&self) -> bool {
+        self.error_code
+            .contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+    }
+
+    /// Whether the page was mapped and the access broke its permissions, rather than it missing
+    pub fn is_present(&self) -> bool {
+        self.error_code
+            .contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    }
+}
+
+/// What the error code says about the access, e.g. "kernel write to a read-only page"
+pub struct Cause(pub PageFaultErrorCode);
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let who = match code.contains(PageFaultErrorCode::USER_MODE) {
+            true => "user",
+            false => "kernel",
+        };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a page that isn't mapped"
+        } else if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "a non-executable page"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "a read-only page"
+        } else {
+            "a page it may not access"
+        };
+        write!(f, "{} {} {}", who, access, page)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", with a reserved bit set in a page table")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", refused by its protection key")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the faulting code was: a process's thread, or the kernel itself
+struct Culprit;
+
+impl fmt::Display for Culprit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpu = percpu::try_current().map_or(0, |cpu| cpu.index);
+        match (process::current(), boot::phase()) {
+            (Some(process), _) => write!(
+                f,
+                "process {} ({}) on cpu {}",
+                process.pid(),
+                process.name(),
+                cpu
+            ),
+            (None, Some(phase)) => write!(f, "the boot, in the {} phase", phase),
+            (None, None) => write!(f, "a kernel task on cpu {}", cpu),
+        }
+    }
+}
+
+/// How the page at the faulting address is mapped right now
+struct Mapping(VirtAddr);
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match memory::translate_in(Cr3::read().0, self.0) {
+            Some((phys, flags)) => write!(f, "mapped to {:#x}, {:?}", phys.as_u64(), flags),
+            None => write!(f, "not mapped"),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "page fault: {} at {:#x}",
+            Cause(self.error_code),
+            self.address.as_u64()
+        )?;
+        writeln!(f, "  by {}", Culprit)?;
+        writeln!(f, "  at {}, rsp {:#x}", Symbolized(self.rip), self.rsp)?;
+        write!(f, "  the page is {}", Mapping(self.address))
+    }
+}
+
+/// Something that can make a faulting access work
+struct Handler {
+    /// Whether it applies at all, checked before `resolve`
+    applies: fn(&Fault) -> bool,
+    /// Returns whether the access can be retried
+    resolve: fn(&Fault) -> bool,
+}
+
+static HANDLERS: &[Handler] = &[
+    // a write to a page shared with another space since a fork
+    Handler {
+        applies: |fault| fault.is_write() && fault.is_present(),
+        resolve: |fault| address_space::resolve_write_fault(fault.address),
+    },
+    // the first touch of reserved memory: mmap regions, and stacks growing
+    Handler {
+        applies: |fault| !fault.is_present(),
+        resolve: |fault| process::resolve_fault(fault.address),
+    },
+];
+
+/// Whether a handler resolved `fault`
+fn dispatch(fault: &Fault) -> bool {
+    HANDLERS
+        .iter()
+        .any(|handler| (handler.applies)(fault) && (handler.resolve)(fault))
+}
+
+/// Handles the page fault the IDT entry got; returns if the access can be retried
+pub(super) fn handle(registers: &Registers, error_code: PageFaultErrorCode) {
+    let fault = Fault {
+        address: Cr2::read(),
+        error_code,
+        rip: registers.rip,
+        rsp: registers.rsp,
+        user: registers.cs & 3 == 3,
+    };
+    if dispatch(&fault) {
+        return;
+    }
+    if fault.user {
+        if !fault.is_present() && process::in_stack_guard(fault.address) {
+            let reason = UserExit::StackOverflow {
+                address: fault.address.as_u64(),
+                rsp: fault.rsp,
+                rip: fault.rip,
+            };
+            usermode::fault(registers, reason);
+        }
+        let reason = UserExit::PageFault {
+            address: fault.address.as_u64(),
+            error_code: error_code.bits(),
+            rip: fault.rip,
+        };
+        usermode::fault(registers, reason);
+    }
+    panic!("EXCEPTION: {}\n{:#x?}", fault, registers);
+}
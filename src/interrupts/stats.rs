@@ -0,0 +1,118 @@
+//! Interrupt counts: how often each vector was taken, on each CPU, like Linux's
+//! `/proc/interrupts`.
+//!
+//! Every entry point calls `count` first thing, exceptions included, so a line that keeps firing
+//! (an interrupt storm, or a device whose interrupt is never cleared) shows as a count racing
+//! ahead of the others. The `irqs` shell command and `/proc/interrupts` print them.
+
+use super::{InterruptIndex, PIC_1_OFFSET};
+use crate::percpu::{self, MAX_CPUS};
+use crate::{apic, println};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const VECTORS: usize = 256;
+
+/// Times each CPU took each vector
+static COUNTS: [[AtomicU64; VECTORS]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; VECTORS] }; MAX_CPUS];
+
+/// Counts vector `vector` on this CPU; called by its handler on entry
+pub fn count(vector: u8) {
+    // before the per-CPU area is set up, only the boot CPU runs
+    let cpu = percpu::try_current().map_or(0, |cpu| cpu.index);
+    COUNTS[cpu][vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often one vector was taken
+#[derive(Debug, Clone)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub name: String,
+    /// By CPU index, one per CPU brought up
+    pub per_cpu: Vec<u64>,
+}
+
+impl VectorStats {
+    pub fn total(&self) -> u64 {
+        self.per_cpu.iter().sum()
+    }
+}
+
+/// What vector `vector` is for, if the kernel handles it
+fn name(vector: u8) -> Option<String> {
+    const EXCEPTIONS: [(u8, &str); 10] = [
+        (0, "#DE divide error"),
+        (1, "#DB debug"),
+        (2, "NMI"),
+        (3, "#BP breakpoint"),
+        (6, "#UD invalid opcode"),
+        (7, "#NM device not available"),
+        (8, "#DF double fault"),
+        (13, "#GP general protection"),
+        (14, "#PF page fault"),
+        (17, "#AC alignment check"),
+    ];
+    if let Some((_, name)) = EXCEPTIONS.iter().find(|(number, _)| *number == vector) {
+        return Some(String::from(*name));
+    }
+    Some(match vector {
+        _ if vector == InterruptIndex::Timer.as_u8() => String::from("timer"),
+        _ if (PIC_1_OFFSET..PIC_1_OFFSET + 16).contains(&vector) => {
+            alloc::format!("IRQ {}", vector - PIC_1_OFFSET)
+        }
+        apic::WAKEUP_VECTOR => String::from("wakeup"),
+        apic::SPURIOUS_VECTOR => String::from("spurious"),
+        _ => return None,
+    })
+}
+
+/// The vectors that were taken at least once, by vector number
+pub fn stats() -> Vec<VectorStats> {
+    let cpus = percpu::count().clamp(1, MAX_CPUS);
+    (0..VECTORS)
+        .filter_map(|vector| {
+            let per_cpu: Vec<u64> = COUNTS[..cpus]
+                .iter()
+                .map(|counts| counts[vector].load(Ordering::Relaxed))
+                .collect();
+            if per_cpu.iter().all(|&count| count == 0) {
+                return None;
+            }
+            let vector = vector as u8;
+            Some(VectorStats {
+                vector,
+                name: name(vector).unwrap_or_else(|| String::from("unexpected")),
+                per_cpu,
+            })
+        })
+        .collect()
+}
+
+/// A line per vector taken, with a column per CPU
+pub fn text() -> String {
+    let mut text = String::from("VEC");
+    for index in 0..percpu::count().clamp(1, MAX_CPUS) {
+        let _ = write!(text, " {:>10}", alloc::format!("CPU{}", index));
+    }
+    text.push_str("  NAME\n");
+    for stats in stats() {
+        let _ = write!(text, "{:>3}", stats.vector);
+        for count in &stats.per_cpu {
+            let _ = write!(text, " {:>10}", count);
+        }
+        let _ = writeln!(text, "  {}", stats.name);
+    }
+    text
+}
+
+/// `irqs`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err("usage: irqs");
+    }
+    println!("{}", text().trim_end());
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Tickless idle: no timer interrupts while a CPU has nothing to do.
+//!
+//! The Local APIC timer normally fires `TIMER_HZ` times a second on every CPU. A CPU about to
+//! halt with nothing to run calls `idle` instead, which switches its timer to one-shot mode for
+//! the ticks until the nearest `timer` deadline, at most `MAX_IDLE_TICKS`, and back to the
+//! periodic tick when the one-shot fires. If another interrupt ends the halt first, the ticks that
+//! went by are counted right away and a one-shot covering the rest of the current tick keeps the
+//! periodic tick in phase. Only the boot CPU counts ticks; the others have no deadlines and idle
+//! for as long as they may.
+//!
+//! With the 8259 PIC, the PIT is the only timer, and `idle` just halts.
+
+use super::{InterruptIndex, TICKS, TIMER_HZ};
+use crate::clock::tsc;
+use crate::percpu::{self, MAX_CPUS};
+use crate::{apic, cputime, rcu, timer};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Longest a CPU goes without a timer interrupt; it also keeps the one-shot's count in range
+pub const MAX_IDLE_TICKS: u64 = TIMER_HZ as u64;
+
+/// Ticks each CPU's armed one-shot covers, 0 while its timer is periodic
+static ARMED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Halts until the next interrupt, without timer interrupts until the next deadline. Must be
+/// called with interrupts disabled, so that a wake-up queued after the caller last looked for
+/// work isn't slept through; returns with them enabled. The time is charged as idle (see
+/// `cputime`).
+pub fn idle() {
+    let start = tsc::read();
+    halt();
+    cputime::charge_idle(tsc::read().saturating_sub(start));
+}
+
+fn halt() {
+    let Some(local_apic) = apic::local_apic() else {
+        interrupts::enable_and_hlt();
+        return;
+    };
+    let cpu = percpu::current().index;
+    let ticks = match cpu {
+        0 => timer::next_deadline().map_or(u64::MAX, |deadline| {
+            deadline.saturating_sub(TICKS.load(Ordering::Relaxed))
+        }),
+        _ => u64::MAX,
+    }
+    .min(MAX_IDLE_TICKS);
+    if ticks <= 1 {
+        interrupts::enable_and_hlt(); // the next tick is due anyway
+        return;
+    }
+
+    let period = apic::timer_period(TIMER_HZ) as u64;
+    let count = (ticks * period).min(u32::MAX as u64) as u32;
+    let ticks = count as u64 / period;
+    ARMED[cpu].store(ticks, Ordering::SeqCst);
+    local_apic.start_oneshot_timer(InterruptIndex::Timer.as_u8(), count);
+    rcu::enter_idle();
+    interrupts::enable_and_hlt();
+    interrupts::disable();
+    rcu::exit_idle();
+
+    if ARMED[cpu].load(Ordering::SeqCst) != 0 {
+        // woken early: count the whole ticks that passed and fire at the end of the current one
+        let elapsed = count.saturating_sub(local_apic.timer_current_count()) as u64;
+        let passed = (elapsed / period).min(ticks - 1);
+        if cpu == 0 {
+            advance(passed);
+        }
+        ARMED[cpu].store(1, Ordering::SeqCst);
+        let rest = (passed + 1) * period - elapsed;
+        local_apic.start_oneshot_timer(InterruptIndex::Timer.as_u8(), rest as u32);
+    }
+    interrupts::enable();
+}
+
+/// Called from the timer interrupt; returns how many ticks it stands for and goes back to the
+/// periodic tick if a one-shot fired
+pub(super) fn timer_interrupt() -> u64 {
+    let Some(cpu) = percpu::try_current() else {
+        return 1;
+    };
+    match ARMED[cpu.index].swap(0, Ordering::SeqCst) {
+        0 => 1,
+        ticks => {
+            if let Some(local_apic) = apic::local_apic() {
+                local_apic.start_periodic_timer(InterruptIndex::Timer.as_u8(), TIMER_HZ);
+            }
+            ticks
+        }
+    }
+}
+
+/// Adds ticks that passed without an interrupt; the boot CPU only. No deadline falls among them,
+/// the one-shot was armed for the nearest.
+fn advance(ticks: u64) {
+    TICKS.fetch_add(ticks, Ordering::Relaxed);
+}
@@ -0,0 +1,6 @@
+//! Inter-process communication.
+//!
+//! Processes reach these objects through descriptors in their `FileTable`, kernel tasks through
+//! the async methods on the handles.
+
+pub mod pipe;
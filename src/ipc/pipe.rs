@@ -0,0 +1,196 @@
+//! Pipes: a bounded byte stream from the `Writer` ends to the `Reader` ends.
+//!
+//! Both ends can be cloned and shared. Reading an empty pipe blocks until something is written
+//! or every `Writer` is gone, which reads as end of file; writing to a full one blocks until
+//! there's room, and fails once every `Reader` is gone. The `try_` methods never block and report
+//! `PipeError::WouldBlock` instead, which is what the system calls use.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// How many bytes a pipe holds before writers have to wait
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// Nothing could be transferred without waiting
+    WouldBlock,
+    /// Writing to a pipe nobody can read from any more
+    Broken,
+}
+
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipeError::WouldBlock => f.write_str("operation would block"),
+            PipeError::Broken => f.write_str("broken pipe"),
+        }
+    }
+}
+
+struct Shared {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    readers: usize,
+    writers: usize,
+    /// Tasks waiting for data
+    read_wakers: Vec<Waker>,
+    /// Tasks waiting for room
+    write_wakers: Vec<Waker>,
+}
+
+impl Shared {
+    fn wake_readers(&mut self) {
+        self.read_wakers.drain(..).for_each(Waker::wake);
+    }
+
+    fn wake_writers(&mut self) {
+        self.write_wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// A new pipe holding up to `capacity` bytes
+pub fn pipe(capacity: usize) -> (Reader, Writer) {
+    let shared = Arc::new(Mutex::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        readers: 1,
+        writers: 1,
+        read_wakers: Vec::new(),
+        write_wakers: Vec::new(),
+    }));
+    (
+        Reader {
+            shared: shared.clone(),
+        },
+        Writer { shared },
+    )
+}
+
+/// The receiving end
+pub struct Reader {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Reader {
+    /// Moves whatever is buffered, up to `buf.len()` bytes, into `buf`; 0 means end of file
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        self.poll_read(buf, None)
+    }
+
+    /// Like `try_read`, but waits for data instead of failing
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|context| match self.poll_read(buf, Some(context)) {
+            Ok(read) => Poll::Ready(read),
+            Err(_) => Poll::Pending,
+        })
+        .await
+    }
+
+    fn poll_read(&self, buf: &mut [u8], context: Option<&mut Context>) -> Result<usize, PipeError> {
+        let mut shared = self.shared.lock();
+        if shared.buffer.is_empty() {
+            if shared.writers == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            if let Some(context) = context {
+                shared.read_wakers.push(context.waker().clone());
+            }
+            return Err(PipeError::WouldBlock);
+        }
+        let read = buf.len().min(shared.buffer.len());
+        for (byte, value) in buf.iter_mut().zip(shared.buffer.drain(..read)) {
+            *byte = value;
+        }
+        shared.wake_writers();
+        Ok(read)
+    }
+}
+
+impl Clone for Reader {
+    fn clone(&self) -> Self {
+        self.shared.lock().readers += 1;
+        Reader {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.readers -= 1;
+        if shared.readers == 0 {
+            shared.wake_writers(); // to find the pipe broken
+        }
+    }
+}
+
+/// The sending end
+pub struct Writer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Writer {
+    /// Appends as much of `data` as there's room for, returning how much that was
+    pub fn try_write(&self, data: &[u8]) -> Result<usize, PipeError> {
+        self.poll_write(data, None)
+    }
+
+    /// Writes all of `data`, waiting for room as often as needed
+    pub async fn write(&self, data: &[u8]) -> Result<(), PipeError> {
+        let mut written = 0;
+        while written < data.len() {
+            written += poll_fn(
+                |context| match self.poll_write(&data[written..], Some(context)) {
+                    Err(PipeError::WouldBlock) => Poll::Pending,
+                    result => Poll::Ready(result),
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn poll_write(&self, data: &[u8], context: Option<&mut Context>) -> Result<usize, PipeError> {
+        let mut shared = self.shared.lock();
+        if shared.readers == 0 {
+            return Err(PipeError::Broken);
+        }
+        let room = shared.capacity - shared.buffer.len();
+        if room == 0 && !data.is_empty() {
+            if let Some(context) = context {
+                shared.write_wakers.push(context.waker().clone());
+            }
+            return Err(PipeError::WouldBlock);
+        }
+        let written = room.min(data.len());
+        shared.buffer.extend(&data[..written]);
+        shared.wake_readers();
+        Ok(written)
+    }
+}
+
+impl Clone for Writer {
+    fn clone(&self) -> Self {
+        self.shared.lock().writers += 1;
+        Writer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.writers -= 1;
+        if shared.writers == 0 {
+            shared.wake_readers(); // to see end of file
+        }
+    }
+}
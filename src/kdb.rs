@@ -0,0 +1,364 @@
+//! A crash debugger in the spirit of kdb: when enabled (see `crash`), a panic drops into a small
+//! command loop on the display and COM1 instead of halting, to look around before rebooting.
+//!
+//! Nothing the panic left behind can be trusted, so the debugger leans on as little as it can.
+//! The other CPUs are stopped with an NMI, the console's locks are broken, keys are polled from
+//! the keyboard controller and the UART, memory is read only where the page tables map it, and
+//! nothing is allocated, as the heap's lock may be held. Output skips the attached consoles,
+//! which may need locks of their own, and goes to the serial line directly instead.
+
+use crate::console::{self, Backend, Terminal};
+use crate::crash::{self, Action};
+use crate::drivers::ps2::keyboard;
+use crate::drivers::serial::{self, SerialPort};
+use crate::unwind::Backtrace;
+use crate::{apic, klog, memory, percpu, power, println, process};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::VirtAddr;
+
+/// Longest command line taken
+const LINE_SIZE: usize = 80;
+/// Bytes `mem` shows when not told
+const DEFAULT_DUMP: u64 = 64;
+/// Most bytes `mem` shows at once
+const MAX_DUMP: u64 = 4096;
+
+/// Set once a CPU has entered the debugger
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// CPUs that took the debugger's NMI and stopped
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a panic enters the debugger, i.e. the panic action is `crash::Action::Debugger`
+pub fn is_enabled() -> bool {
+    crash::action() == Action::Debugger
+}
+
+/// Has a panic enter the debugger, or halt if it did
+pub fn set_enabled(enabled: bool) {
+    match enabled {
+        true => crash::set_action(Action::Debugger),
+        false if is_enabled() => crash::set_action(Action::Halt),
+        false => {}
+    }
+}
+
+/// Whether a CPU is in the debugger, and the others should stay out of its way
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Stops the calling CPU for good; where the other CPUs go when the debugger takes over
+pub fn park() -> ! {
+    x86_64::instructions::interrupts::disable();
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// The registers as they were when the debugger was entered
+struct Registers {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    fn capture() -> Registers {
+        let (rsp, rbp): (u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "mov {}, rsp",
+                "mov {}, rbp",
+                out(reg) rsp,
+                out(reg) rbp,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        Registers {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+/// Where the debugger writes: the active console backend and COM1
+struct Output {
+    serial: Option<Terminal<SerialPort>>,
+}
+
+impl Output {
+    /// Takes back the last character echoed, on the serial line only: the display backends
+    /// can't move their cursor back
+    fn erase(&mut self) {
+        if let Some(serial) = &mut self.serial {
+            // a terminal takes a backspace as a cursor move only
+            serial.write("\x08 \x08");
+        }
+    }
+}
+
+impl Write for Output {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        console::with_backend(|backend| backend.write(text));
+        if let Some(serial) = &mut self.serial {
+            serial.write(text);
+        }
+        Ok(())
+    }
+}
+
+/// Takes over the machine after the panic `info` and runs the debugger until told to reboot
+/// or halt
+pub fn enter(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let registers = Registers::capture();
+    if ACTIVE.swap(true, Ordering::SeqCst) {
+        // a panic in the debugger, or another CPU panicking at the same time
+        park();
+    }
+    stop_other_cpus();
+    unsafe { console::force_unlock() };
+
+    let mut out = Output {
+        serial: serial::console_port().map(Terminal),
+    };
+    let _ = writeln!(out, "\n{}", info);
+    let _ = writeln!(out, "kdb: entered on panic; `help` lists the commands");
+    let mut line = [0u8; LINE_SIZE];
+    loop {
+        let _ = write!(out, "kdb> ");
+        let len = read_line(&mut out, &mut line);
+        let text = core::str::from_utf8(&line[..len]).unwrap_or("");
+        let mut words = text.split_whitespace();
+        let Some(name) = words.next() else {
+            continue;
+        };
+        let result = match name {
+            "help" => help(&mut out),
+            "regs" => regs(&mut out, &registers),
+            "bt" => backtrace(&mut out, &registers),
+            "mem" => dump(&mut out, words.next(), words.next()),
+            "ps" => ps(&mut out),
+            "cpus" => cpus(&mut out),
+            "log" => log(&mut out),
+            "reboot" => power::reboot(),
+            "halt" => {
+                let _ = writeln!(out, "halted");
+                park();
+            }
+            _ => Err("unknown command"),
+        };
+        if let Err(err) = result {
+            let _ = writeln!(out, "{}", err);
+        }
+    }
+}
+
+/// Sends every other CPU that's online an NMI and waits a little for them to stop
+fn stop_other_cpus() {
+    let Some(local_apic) = apic::local_apic() else {
+        return; // no APIC, no other CPUs
+    };
+    let me = local_apic.id();
+    let mut others = 0;
+    for index in (0..percpu::count()).filter(|&index| percpu::is_online(index)) {
+        if let Some(cpu) = percpu::get(index).filter(|cpu| cpu.apic_id != me) {
+            local_apic.send_nmi(cpu.apic_id);
+            others += 1;
+        }
+    }
+    for _ in 0..1_000_000 {
+        if PARKED.load(Ordering::SeqCst) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The next key from the keyboard or the serial line, waiting for one
+fn read_key() -> u8 {
+    let port = serial::console_port();
+    loop {
+        if let Some(ch) = keyboard::poll().filter(char::is_ascii) {
+            return ch as u8;
+        }
+        if let Some(byte) = port.and_then(|port| port.try_receive()) {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Reads a line into `line`, echoing it, and returns its length
+fn read_line(out: &mut Output, line: &mut [u8; LINE_SIZE]) -> usize {
+    let mut len = 0;
+    loop {
+        match read_key() {
+            b'\r' | b'\n' => {
+                let _ = writeln!(out);
+                return len;
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                out.erase();
+            }
+            byte @ 0x20..0x7f if len < LINE_SIZE => {
+                line[len] = byte;
+                len += 1;
+                let _ = out.write_char(byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn help(out: &mut Output) -> Result<(), &'static str> {
+    let _ = writeln!(
+        out,
+        "regs                  registers on entry\n\
+         bt                    the frame pointer chain\n\
+         mem <address> [len]   hex dump of mapped memory\n\
+         ps                    processes\n\
+         cpus                  CPUs and what they were running\n\
+         log                   the kernel log, what was printed up to the panic\n\
+         reboot                restart the machine\n\
+         halt                  stop here for good"
+    );
+    Ok(())
+}
+
+fn regs(out: &mut Output, registers: &Registers) -> Result<(), &'static str> {
+    let _ = writeln!(
+        out,
+        "rsp {:#018x}  rbp {:#018x}  rflags {:#x}",
+        registers.rsp, registers.rbp, registers.rflags
+    );
+    let _ = writeln!(
+        out,
+        "cr0 {:#018x}  cr2 {:#018x}\ncr3 {:#018x}  cr4 {:#018x}",
+        registers.cr0, registers.cr2, registers.cr3, registers.cr4
+    );
+    Ok(())
+}
+
+fn is_mapped(address: u64) -> bool {
+    let Ok(address) = VirtAddr::try_new(address) else {
+        return false;
+    };
+    memory::translate_in(Cr3::read().0, address).is_some()
+}
+
+/// The stack from the debugger's own frame out, naming the functions if the image carries its
+/// symbols
+fn backtrace(out: &mut Output, registers: &Registers) -> Result<(), &'static str> {
+    let _ = writeln!(out, "{}", Backtrace::from_frame_pointer(registers.rbp));
+    Ok(())
+}
+
+fn parse_number(text: &str) -> Result<u64, &'static str> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| "not a number")
+}
+
+/// `mem <address> [len]`
+fn dump(out: &mut Output, address: Option<&str>, len: Option<&str>) -> Result<(), &'static str> {
+    let start = parse_number(address.ok_or("usage: mem <address> [len]")?)?;
+    let len = len.map_or(Ok(DEFAULT_DUMP), parse_number)?.min(MAX_DUMP);
+    let end = start.checked_add(len).ok_or("the range wraps around")?;
+    for line in (start & !15..end).step_by(16) {
+        let _ = write!(out, "{:016x} ", line);
+        for address in line..line + 16 {
+            if !(start..end).contains(&address) {
+                let _ = write!(out, "   ");
+            } else if is_mapped(address) {
+                let byte = unsafe { (address as *const u8).read_volatile() };
+                let _ = write!(out, " {:02x}", byte);
+            } else {
+                let _ = write!(out, " ??");
+            }
+        }
+        let _ = writeln!(out);
+    }
+    Ok(())
+}
+
+fn ps(out: &mut Output) -> Result<(), &'static str> {
+    let _ = writeln!(out, "{:>5}  NAME", "PID");
+    let listed = process::try_for_each(|process| {
+        let _ = writeln!(out, "{:>5}  {}", process.pid().0, process.name());
+    });
+    match listed {
+        true => Ok(()),
+        false => Err("the process table was locked when the kernel panicked"),
+    }
+}
+
+fn log(out: &mut Output) -> Result<(), &'static str> {
+    let listed = klog::try_for_each(|record| {
+        let _ = writeln!(out, "{}", record);
+    });
+    match listed {
+        true => Ok(()),
+        false => Err("the kernel log was locked when the kernel panicked"),
+    }
+}
+
+fn cpus(out: &mut Output) -> Result<(), &'static str> {
+    for index in 0..percpu::count() {
+        let Some(cpu) = percpu::get(index) else {
+            continue;
+        };
+        let _ = write!(out, "cpu {:<3} apic {:<3}", index, cpu.apic_id);
+        if !percpu::is_online(index) {
+            let _ = writeln!(out, " offline");
+            continue;
+        }
+        let thread = cpu.thread.load(Ordering::SeqCst);
+        match unsafe { thread.as_ref() } {
+            Some(thread) => {
+                let process = thread.process();
+                let _ = writeln!(
+                    out,
+                    " running pid {} ({}) thread {}",
+                    process.pid().0,
+                    process.name(),
+                    thread.id()
+                );
+            }
+            None => {
+                let _ = writeln!(out, " in the kernel");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `kdb [on|off]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {}
+        ["on"] => set_enabled(true),
+        ["off"] => set_enabled(false),
+        _ => return Err("usage: kdb [on|off]"),
+    }
+    println!("kdb: a panic {}", crash::action().describe());
+    Ok(())
+}
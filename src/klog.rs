@@ -0,0 +1,304 @@
+//! The kernel log: a copy of everything printed on the console, kept in memory.
+//!
+//! `console::_print` hands each piece of output to `write` besides the display, so the log holds
+//! what was printed from the first line on, before the heap, the serial port or any other console
+//! was set up, and up to a panic. Lines are kept in a ring buffer of `SIZE` bytes in the kernel
+//! image, the oldest making room for new ones, each with the tick it was finished at and the
+//! `Source` it came from. `dmesg` and `/proc/kmsg` show it, and so does the crash debugger's `log`.
+
+use crate::interrupts::{self, TIMER_HZ};
+use crate::scrub::Sealed;
+use crate::time;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+/// Bytes of the ring buffer, headers included
+const SIZE: usize = 32 * 1024;
+/// Longest line kept whole; longer ones are broken up
+const LINE_SIZE: usize = 200;
+/// Source, length and tick in front of every line
+const HEADER_SIZE: usize = 1 + 1 + 8;
+
+/// Who printed a line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Source {
+    /// `print!` and `println!`
+    Console = 0,
+    /// The panic handler's report
+    Panic = 1,
+    /// System calls of traced processes, see `syscall::strace`
+    Strace = 2,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Console => "console",
+            Source::Panic => "panic",
+            Source::Strace => "strace",
+        }
+    }
+
+    fn from_u8(value: u8) -> Source {
+        match value {
+            1 => Source::Panic,
+            2 => Source::Strace,
+            _ => Source::Console,
+        }
+    }
+}
+
+/// A line in the log
+pub struct Record<'a> {
+    pub source: Source,
+    /// When the line was finished, in timer ticks since boot
+    pub ticks: u64,
+    pub text: &'a str,
+}
+
+impl Record<'_> {
+    /// When the line was finished, in the local time zone; the ticks count from about when the
+    /// wall clock's boot time does
+    pub fn local_time(&self) -> time::Local {
+        time::Local(time::boot_time() + self.ticks / TIMER_HZ as u64)
+    }
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hz = TIMER_HZ as u64;
+        write!(
+            f,
+            "[{:>5}.{:02}] {}: {}",
+            self.ticks / hz,
+            self.ticks % hz * 100 / hz,
+            self.source.name(),
+            self.text
+        )
+    }
+}
+
+struct Log {
+    buffer: [u8; SIZE],
+    /// The line being printed, not in `buffer` until it's finished
+    line: [u8; LINE_SIZE],
+    line_len: usize,
+    line_source: Source,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    buffer: [0; SIZE],
+    line: [0; LINE_SIZE],
+    line_len: 0,
+    line_source: Source::Console,
+});
+
+/// Where the lines in the ring buffer are; kept apart from `Log` and sealed, as a stray write
+/// here would garble every line rather than one
+#[derive(Hash)]
+pub struct LogHeader {
+    /// Bytes ever written to the buffer when its oldest line was, and in all
+    start: u64,
+    end: u64,
+}
+
+/// Only changed with `LOG` locked
+pub static HEADER: Sealed<LogHeader> = Sealed::new("klog header", LogHeader { start: 0, end: 0 });
+
+/// What `console::_print` tags its output with
+static SOURCE: AtomicU8 = AtomicU8::new(Source::Console as u8);
+
+impl Log {
+    fn put(&mut self, at: u64, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.buffer[(at as usize + offset) % SIZE] = byte;
+        }
+    }
+
+    fn get(&self, at: u64, bytes: &mut [u8]) {
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.buffer[(at as usize + offset) % SIZE];
+        }
+    }
+
+    /// Source, length and tick of the line at `at`
+    fn header(&self, at: u64) -> (Source, usize, u64) {
+        let mut header = [0; HEADER_SIZE];
+        self.get(at, &mut header);
+        let mut ticks = [0; 8];
+        ticks.copy_from_slice(&header[2..]);
+        (
+            Source::from_u8(header[0]),
+            header[1] as usize,
+            u64::from_le_bytes(ticks),
+        )
+    }
+
+    fn push(&mut self, source: Source, text: &str) {
+        if source != self.line_source && self.line_len > 0 {
+            self.finish_line();
+        }
+        self.line_source = source;
+        for ch in text.chars() {
+            if ch == '\n' {
+                self.finish_line();
+                continue;
+            }
+            let mut encoded = [0; 4];
+            let encoded = ch.encode_utf8(&mut encoded).as_bytes();
+            if self.line_len + encoded.len() > LINE_SIZE {
+                self.finish_line();
+            }
+            self.line[self.line_len..self.line_len + encoded.len()].copy_from_slice(encoded);
+            self.line_len += encoded.len();
+        }
+    }
+
+    /// Moves the line being printed into the ring buffer, dropping the oldest lines to make room
+    fn finish_line(&mut self) {
+        let len = self.line_len;
+        let size = (HEADER_SIZE + len) as u64;
+        let (mut start, end) = HEADER.read(|header| (header.start, header.end));
+        while end + size - start > SIZE as u64 {
+            let (_, oldest, _) = self.header(start);
+            start += (HEADER_SIZE + oldest) as u64;
+        }
+        let mut header = [0; HEADER_SIZE];
+        header[0] = self.line_source as u8;
+        header[1] = len as u8;
+        header[2..].copy_from_slice(&interrupts::ticks().to_le_bytes());
+        self.put(end, &header);
+        let line = self.line;
+        self.put(end + HEADER_SIZE as u64, &line[..len]);
+        HEADER.update(|header| {
+            header.start = start;
+            header.end = end + size;
+        });
+        self.line_len = 0;
+    }
+
+    fn for_each(&self, header: &LogHeader, mut f: impl FnMut(Record)) {
+        let mut text = [0; LINE_SIZE];
+        let mut at = header.start;
+        while at < header.end {
+            let (source, len, ticks) = self.header(at);
+            self.get(at + HEADER_SIZE as u64, &mut text[..len]);
+            f(Record {
+                source,
+                ticks,
+                text: as_str(&text[..len]),
+            });
+            at += (HEADER_SIZE + len) as u64;
+        }
+        if self.line_len > 0 {
+            f(Record {
+                source: self.line_source,
+                ticks: interrupts::ticks(),
+                text: as_str(&self.line[..self.line_len]),
+            });
+        }
+    }
+}
+
+/// The text of a line; lines are only broken between characters, but one torn by a crash while
+/// it was being written may end in half of one
+fn as_str(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap(),
+    }
+}
+
+/// Adds `args` to the log, from `source`
+pub fn write(source: Source, args: fmt::Arguments) {
+    struct Writer<'a>(&'a mut Log, Source);
+
+    impl Write for Writer<'_> {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            self.0.push(self.1, text);
+            Ok(())
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = Writer(&mut LOG.lock(), source).write_fmt(args);
+    });
+}
+
+/// The source console output is tagged with from now on
+pub fn set_source(source: Source) {
+    SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// The source console output is tagged with
+pub fn source() -> Source {
+    Source::from_u8(SOURCE.load(Ordering::Relaxed))
+}
+
+/// Runs `f` on every line in the log, oldest first, the unfinished one last; gives up and
+/// returns false if the log is locked. Allocates nothing, for the crash debugger.
+pub fn try_for_each(f: impl FnMut(Record)) -> bool {
+    match LOG.try_lock() {
+        Some(log) => HEADER.try_read(|header| log.for_each(header, f)).is_some(),
+        None => false,
+    }
+}
+
+/// The whole log as text, a line per record
+pub fn text() -> String {
+    let mut text = String::new();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let log = LOG.lock();
+        HEADER.read(|header| {
+            log.for_each(header, |record| {
+                let _ = writeln!(text, "{}", record);
+            })
+        });
+    });
+    text
+}
+
+/// The log as `text` has it, with the local time of day on each line instead of the time since
+/// boot
+fn text_local_time() -> String {
+    let mut text = String::new();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let log = LOG.lock();
+        HEADER.read(|header| {
+            log.for_each(header, |record| {
+                let _ = writeln!(
+                    text,
+                    "[{}] {}: {}",
+                    record.local_time(),
+                    record.source.name(),
+                    record.text
+                );
+            })
+        });
+    });
+    text
+}
+
+/// Releases the log's lock, whoever holds it, so a crash can still be reported.
+///
+/// # Safety
+///
+/// Whoever held it must never run again, e.g. because the CPUs are stopped for good.
+pub unsafe fn force_unlock() {
+    LOG.force_unlock();
+}
+
+/// `dmesg [-T]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    // copied out first: printing adds to the log, and would wait for the lock held meanwhile
+    let text = match args {
+        [] => text(),
+        ["-T"] => text_local_time(),
+        _ => return Err("usage: dmesg [-T]"),
+    };
+    crate::print!("{}", text);
+    Ok(())
+}
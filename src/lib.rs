@@ -0,0 +1,79 @@
+#![no_std]
+#![feature(abi_x86_interrupt)] // the x86-interrupt calling convention is still unstable
+
+extern crate alloc;
+
+pub mod abi;
+pub mod acpi;
+pub mod allocator;
+pub mod apic;
+pub mod arch;
+pub mod audit;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod block;
+pub mod boot;
+pub mod bootinfo;
+pub mod clock;
+pub mod cmdline;
+pub mod compress;
+pub mod console;
+pub mod cputime;
+pub mod crash;
+pub mod crypto;
+pub mod drivers;
+pub mod endian;
+pub mod event;
+pub mod fpu;
+pub mod fs;
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
+pub mod gdt;
+#[cfg(feature = "gfx")]
+pub mod gfx;
+pub mod interrupts;
+pub mod ipc;
+pub mod kdb;
+pub mod klog;
+pub mod loader;
+pub mod memory;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod numa;
+pub mod nvram;
+pub mod paravirt;
+pub mod pci;
+pub mod percpu;
+pub mod pit;
+pub mod power;
+pub mod process;
+pub mod profile;
+pub mod rand;
+pub mod rcu;
+pub mod rtc;
+pub mod scrub;
+pub mod sections;
+pub mod shell;
+pub mod signing;
+pub mod smp;
+pub mod sync;
+pub mod symbols;
+pub mod syscall;
+pub mod sysctl;
+pub mod sysinfo;
+pub mod task;
+pub mod time;
+pub mod timer;
+pub mod trace;
+pub mod tty;
+pub mod unwind;
+pub mod usermode;
+pub mod vga_buffer;
+pub mod watchdog;
+
+/// Halts the CPU until the next interrupt instead of spinning at 100% load
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,53 @@
+//! Loading executables from the filesystem into address spaces of their own.
+
+use crate::fs::{self, FsError};
+use crate::memory::address_space::AddressSpace;
+use crate::usermode::{self, Transition, UserExit};
+use core::fmt;
+
+pub mod elf;
+
+/// Why `run_file` couldn't start a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Fs(FsError),
+    Elf(elf::ElfError),
+    /// No frame was left for the new address space's page tables
+    OutOfMemory,
+}
+
+impl From<FsError> for LoadError {
+    fn from(err: FsError) -> Self {
+        LoadError::Fs(err)
+    }
+}
+
+impl From<elf::ElfError> for LoadError {
+    fn from(err: elf::ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Fs(err) => write!(f, "{}", err),
+            LoadError::Elf(err) => write!(f, "{}", err),
+            LoadError::OutOfMemory => f.write_str("out of memory"),
+        }
+    }
+}
+
+/// Loads the executable at `path` into a fresh address space
+pub fn load_file(path: &str, argv: &[&str]) -> Result<(AddressSpace, elf::Image), LoadError> {
+    let bytes = fs::read(path)?;
+    let mut space = AddressSpace::new().map_err(|_| LoadError::OutOfMemory)?;
+    let image = elf::load(&mut space, &bytes, argv)?;
+    Ok((space, image))
+}
+
+/// Loads the executable at `path` and runs it to completion in ring 3 on this CPU
+pub fn run_file(path: &str, argv: &[&str]) -> Result<UserExit, LoadError> {
+    let (space, image) = load_file(path, argv)?;
+    Ok(space.enter(|| usermode::run(image.entry, image.stack_pointer, 0, Transition::Sysretq)))
+}
@@ -0,0 +1,335 @@
+//! ELF64 executables for x86_64.
+//!
+//! `parse` checks the file header and collects the `PT_LOAD` segments; `load` maps them into an
+//! `AddressSpace` with the permissions their flags ask for and builds the initial stack the
+//! System V ABI describes: argc, the argv pointers, an empty environment and an auxiliary vector.
+//! Zero-filled `.bss` is reserved rather than mapped, so a large one costs nothing until used,
+//! and so is the stack, which grows as far as `STACK_LIMIT`.
+//!
+//! Executables must be static: there's no dynamic linker to run for a `PT_INTERP` segment.
+//! `ET_EXEC` files are loaded at the addresses they were linked for, which must lie in the user
+//! part of the address space (link with `-Ttext-segment=0x200000400000`, say); `ET_DYN` files
+//! (static PIE) are loaded at `DYN_BASE` and relocate themselves.
+
+use crate::endian::{read_u16_le, read_u32_le, read_u64_le};
+use crate::memory::address_space::{AddressSpace, Backing};
+use crate::memory::{USER_END, USER_START};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Where position-independent executables are placed
+pub const DYN_BASE: u64 = USER_START + 0x40_0000;
+
+/// The stack sits right below the end of the user part, with an unmapped guard page above it
+pub const STACK_TOP: u64 = USER_END - 0x1000;
+/// How far the stack may grow down from `STACK_TOP`; the page below that is its guard page
+pub const STACK_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Most of the stack the arguments and auxiliary vector may take up
+const MAX_ARGS_SIZE: u64 = 32 * 1024;
+
+/// Why an executable couldn't be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes
+    Truncated,
+    /// Not an ELF file at all
+    BadMagic,
+    /// An ELF file this kernel can't run: the reason says which part
+    Unsupported(&'static str),
+    /// A segment is inconsistent or reaches outside the user part of the address space
+    BadSegment,
+    /// Mapping the segments or the stack failed
+    OutOfMemory,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => f.write_str("file is truncated"),
+            ElfError::BadMagic => f.write_str("not an ELF file"),
+            ElfError::Unsupported(what) => write!(f, "unsupported {}", what),
+            ElfError::BadSegment => f.write_str("bad segment"),
+            ElfError::OutOfMemory => f.write_str("out of memory"),
+        }
+    }
+}
+
+/// A `PT_LOAD` segment, at its link-time address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub vaddr: u64,
+    /// Size in memory; the part past `data` is zero-filled (`.bss`)
+    pub memsz: u64,
+    pub data: &'a [u8],
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// A parsed executable, still referring to the file's bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf<'a> {
+    /// Position independent (`ET_DYN`), to be loaded at `DYN_BASE`
+    pub relocatable: bool,
+    pub entry: u64,
+    pub segments: Vec<Segment<'a>>,
+    /// Link-time address of the program headers, if a segment maps them
+    pub program_headers: Option<u64>,
+    pub program_header_count: u16,
+}
+
+/// Checks the header of `bytes` and collects the loadable segments
+pub fn parse(bytes: &[u8]) -> Result<Elf<'_>, ElfError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[..4] != *b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[4] != 2 {
+        return Err(ElfError::Unsupported("class (not 64-bit)"));
+    }
+    if bytes[5] != 1 {
+        return Err(ElfError::Unsupported("byte order (not little-endian)"));
+    }
+    if read_u16_le(bytes, 18) != EM_X86_64 {
+        return Err(ElfError::Unsupported("machine (not x86_64)"));
+    }
+    let relocatable = match read_u16_le(bytes, 16) {
+        ET_EXEC => false,
+        ET_DYN => true,
+        _ => return Err(ElfError::Unsupported("file type (not an executable)")),
+    };
+
+    let entry = read_u64_le(bytes, 24);
+    let phoff = read_u64_le(bytes, 32);
+    let phentsize = read_u16_le(bytes, 54) as usize;
+    let phnum = read_u16_le(bytes, 56);
+    if phentsize < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Unsupported("program header size"));
+    }
+    let table_end = (phnum as u64)
+        .checked_mul(phentsize as u64)
+        .and_then(|size| size.checked_add(phoff))
+        .ok_or(ElfError::Truncated)?;
+    if table_end > bytes.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+
+    let mut segments = Vec::new();
+    let mut program_headers = None;
+    for index in 0..phnum as usize {
+        let header = &bytes[phoff as usize + index * phentsize..][..PROGRAM_HEADER_SIZE];
+        let kind = read_u32_le(header, 0);
+        let flags = read_u32_le(header, 4);
+        let offset = read_u64_le(header, 8);
+        let vaddr = read_u64_le(header, 16);
+        let filesz = read_u64_le(header, 32);
+        let memsz = read_u64_le(header, 40);
+        match kind {
+            PT_INTERP => return Err(ElfError::Unsupported("dynamically linked executable")),
+            PT_PHDR => program_headers = Some(vaddr),
+            PT_LOAD => {
+                if filesz > memsz {
+                    return Err(ElfError::BadSegment);
+                }
+                let end = offset.checked_add(filesz).ok_or(ElfError::Truncated)?;
+                if end > bytes.len() as u64 {
+                    return Err(ElfError::Truncated);
+                }
+                segments.push(Segment {
+                    vaddr,
+                    memsz,
+                    data: &bytes[offset as usize..end as usize],
+                    writable: flags & PF_W != 0,
+                    executable: flags & PF_X != 0,
+                });
+                // without PT_PHDR, find the headers in whichever segment holds that file range
+                if program_headers.is_none() && (offset..end).contains(&phoff) {
+                    program_headers = Some(vaddr + (phoff - offset));
+                }
+            }
+            _ => {}
+        }
+    }
+    if segments.is_empty() {
+        return Err(ElfError::BadSegment);
+    }
+
+    Ok(Elf {
+        relocatable,
+        entry,
+        segments,
+        program_headers,
+        program_header_count: phnum,
+    })
+}
+
+/// A loaded executable, ready for `usermode::run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub entry: VirtAddr,
+    /// Points at argc on the initial stack
+    pub stack_pointer: VirtAddr,
+}
+
+/// Maps the executable in `bytes` into `space` and sets up its stack with `argv`
+pub fn load(space: &mut AddressSpace, bytes: &[u8], argv: &[&str]) -> Result<Image, ElfError> {
+    let elf = parse(bytes)?;
+    let bias = if elf.relocatable { DYN_BASE } else { 0 };
+    let in_user_part = |address: u64| (USER_START..=USER_END).contains(&address);
+
+    for segment in &elf.segments {
+        let start = segment
+            .vaddr
+            .checked_add(bias)
+            .ok_or(ElfError::BadSegment)?;
+        let end = start
+            .checked_add(segment.memsz)
+            .ok_or(ElfError::BadSegment)?;
+        if !in_user_part(start) || !in_user_part(end) || end > STACK_TOP - STACK_LIMIT - 0x1000 {
+            return Err(ElfError::BadSegment);
+        }
+        let mut flags = PageTableFlags::empty();
+        if segment.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        map_segment(space, start, end, segment.data, flags).map_err(|_| ElfError::OutOfMemory)?;
+    }
+
+    let entry = elf.entry.checked_add(bias).ok_or(ElfError::BadSegment)?;
+    if !in_user_part(entry) {
+        return Err(ElfError::BadSegment);
+    }
+
+    let mut auxv = vec![
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, entry),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_header_count as u64),
+    ];
+    if let Some(program_headers) = elf.program_headers {
+        auxv.push((AT_PHDR, program_headers + bias));
+    }
+    auxv.push((AT_NULL, 0));
+
+    let stack_pointer = build_stack(space, argv, &auxv)?;
+    Ok(Image {
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Maps the segment from `start` to `end` holding `data`. The whole pages of `.bss` past the
+/// data are only reserved, to be mapped as the program touches them; the partial ones on either
+/// side may share a page with another segment and are mapped right away.
+fn map_segment(
+    space: &mut AddressSpace,
+    start: u64,
+    end: u64,
+    data: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), &'static str> {
+    let lazy_start = (start + data.len() as u64).next_multiple_of(4096);
+    let lazy_end = end & !0xfff;
+    if lazy_end <= lazy_start {
+        return space.map(VirtAddr::new(start), end - start, data, flags);
+    }
+    if lazy_start > start {
+        space.map(VirtAddr::new(start), lazy_start - start, data, flags)?;
+    }
+    space.reserve(
+        VirtAddr::new(lazy_start),
+        lazy_end - lazy_start,
+        flags,
+        Backing::Anonymous,
+    )?;
+    if end > lazy_end {
+        space.map(VirtAddr::new(lazy_end), end - lazy_end, &[], flags)?;
+    }
+    Ok(())
+}
+
+/// Reserves the stack and writes the initial process state to its top, mapping just the pages
+/// that takes:
+///
+/// ```text
+/// STACK_TOP  argv strings
+///            (padding)
+///            auxv pairs, ending with AT_NULL
+///            NULL (the end of the empty environment)
+///            NULL, argv[argc - 1], ..., argv[0]
+/// sp         argc                                    (16-byte aligned)
+/// ```
+fn build_stack(
+    space: &mut AddressSpace,
+    argv: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let strings_size: u64 = argv.iter().map(|arg| arg.len() as u64 + 1).sum();
+    let strings_start = STACK_TOP - strings_size;
+    let words = 1 + (argv.len() + 1) + 1 + 2 * auxv.len();
+    let stack_pointer = (strings_start - 8 * words as u64) & !0xf;
+    if STACK_TOP - stack_pointer > MAX_ARGS_SIZE {
+        return Err(ElfError::Unsupported("argument list (too long)"));
+    }
+
+    let mut image = vec![0u8; (STACK_TOP - stack_pointer) as usize];
+    let mut words = Vec::with_capacity(words);
+    words.push(argv.len() as u64);
+    let mut string_address = strings_start;
+    for arg in argv {
+        let at = (string_address - stack_pointer) as usize;
+        image[at..at + arg.len()].copy_from_slice(arg.as_bytes()); // the NUL is already there
+        words.push(string_address);
+        string_address += arg.len() as u64 + 1;
+    }
+    words.push(0);
+    words.push(0);
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    for (index, word) in words.iter().enumerate() {
+        image[8 * index..8 * index + 8].copy_from_slice(&word.to_le_bytes());
+    }
+
+    space
+        .reserve_stack(VirtAddr::new(STACK_TOP), STACK_LIMIT)
+        .map_err(|_| ElfError::BadSegment)?;
+    space
+        .populate(VirtAddr::new(stack_pointer), STACK_TOP - stack_pointer)
+        .map_err(|_| ElfError::OutOfMemory)?;
+    space
+        .write(VirtAddr::new(stack_pointer), &image)
+        .map_err(|_| ElfError::OutOfMemory)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
@@ -1,22 +1,20 @@
-
 #![no_std] // Don't link the Rust standard library
 #![no_main] // Disable rust entry points
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-mod vga_buffer;
-/// Because there's no std library, we must handle errors if they occur
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
-    loop {}
-}
+use rust_os::{boot, bootinfo, crash};
 
-/// No mangle ensures that rust does not output the function with a cryptic name to differentiate it
-/// from other functions, instead it will remain _start to make it easier to reference the entry point
-/// to the linker
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    println!("Hello World{}", "!");
-    loop {}
+/// Because there's no std library, we must handle errors if they occur; what happens then is up
+/// to the panic action (see `crash`)
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crash::handle(info)
 }
 
+// entry_point! type-checks the signature of kernel_main and exports it as the _start symbol the
+// bootloader jumps to, so we no longer need #[no_mangle] on an extern "C" fn ourselves
+entry_point!(kernel_main);
 
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    boot::run(bootinfo::from_bootloader(boot_info))
+}
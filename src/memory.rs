@@ -0,0 +1,572 @@
+use crate::bootinfo::{BootInfo, MemoryKind, MemoryMap};
+use crate::scrub::Sealed;
+use crate::sync::watched::WatchedMutex;
+use crate::{numa, percpu, println, sections};
+use alloc::vec::Vec;
+use caching::MemoryType;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, Translate, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod address_space;
+pub mod caching;
+pub mod inspect;
+pub mod layout;
+pub mod pressure;
+
+/// Virtual address at which the bootloader maps the complete physical memory; 0 until
+/// `init_offset`, which leaves `phys_to_virt` with the bootloader's identity mapping of low memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The memory map the kernel was booted with, once `init` ran
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+/// Frames below 1 MiB are never handed out, they're kept for real-mode trampolines (SMP startup)
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Start of the part of every address space that belongs to ring 3: level 4 entries 64 to 254,
+/// which the bootloader leaves free and the kernel, in the upper half (see `layout`), doesn't use
+pub const USER_START: u64 = 0x0000_2000_0000_0000;
+pub const USER_END: u64 = 0x0000_7f80_0000_0000;
+
+/// Physical address of the level 4 table each CPU runs on, by CPU index
+pub static PAGE_TABLE_ROOTS: Sealed<Vec<(usize, u64)>> =
+    Sealed::new("page table roots", Vec::new());
+
+/// The active level 4 table, wrapped so drivers can map MMIO regions after boot
+pub static MAPPER: WatchedMutex<Option<OffsetPageTable<'static>>> =
+    WatchedMutex::new("mapper", None);
+/// Hands out the usable frames from the boot memory map
+pub static FRAME_ALLOCATOR: WatchedMutex<Option<BootInfoFrameAllocator>> =
+    WatchedMutex::new("frame allocator", None);
+
+/// Sets up the global mapper and frame allocator from the boot information.
+///
+/// # Safety
+///
+/// The caller must guarantee that the loader mapped the complete physical memory at
+/// `boot_info.physical_memory_offset`, and this must only be called once.
+#[link_section = ".init.text"]
+pub unsafe fn init(boot_info: &'static BootInfo) {
+    init_offset(boot_info);
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(&boot_info.memory_map));
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
+}
+
+/// Makes `phys_to_virt` work, e.g. for the VGA text buffer; the boot does this first of all
+#[link_section = ".init.text"]
+pub fn init_offset(boot_info: &BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+}
+
+/// The regions of physical memory as the loader found them, `None` before `init`
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    MEMORY_MAP.r#try().copied()
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// Unsafe because the caller must make sure this is only called once to avoid aliasing `&mut`
+/// references
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read(); // CR3 holds the physical frame of the level 4 table
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// Translates a physical address into the bootloader's physical memory mapping
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+/// Maps a device register region as uncached memory and returns its virtual address
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    map_mmio_as(phys, size, MemoryType::Uncacheable)
+}
+
+/// Maps a device memory region with `memory_type` and returns its virtual address.
+///
+/// The region is placed inside the physical memory window (at `phys_to_virt(phys)`). Pages the
+/// bootloader already mapped get `memory_type` as well, except those covered by its huge pages,
+/// which stay write-back; the MTRRs are what keeps device memory there uncached, and a warning
+/// says so if they don't.
+pub fn map_mmio_as(
+    phys: PhysAddr,
+    size: u64,
+    memory_type: MemoryType,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | caching::flags(memory_type);
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed), // memory::init hasn't run yet
+    };
+
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end_frame = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let mut cached = false;
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {
+                unsafe { mapper.update_flags(page, flags) }
+                    .map_err(|_| MapToError::ParentEntryHugePage)?
+                    .flush();
+            }
+            Err(MapToError::ParentEntryHugePage) => cached = true,
+            Err(err) => return Err(err),
+        }
+    }
+    if cached && memory_type != MemoryType::WriteBack {
+        let mtrrs = caching::Mtrrs::read();
+        let actual = mtrrs.map_or(MemoryType::WriteBack, |mtrrs| mtrrs.memory_type(phys));
+        if actual == MemoryType::WriteBack {
+            println!(
+                "memory: {:#x} stays {} instead of {}, a huge page maps it",
+                phys.as_u64(),
+                actual,
+                memory_type
+            );
+        }
+    }
+
+    Ok(phys_to_virt(phys))
+}
+
+/// Where `allocate_kernel_stack` puts kernel stacks: a level 4 entry of their own (see `layout`),
+/// one `KERNEL_STACK_SLOT` per stack
+const KERNEL_STACKS_START: u64 = layout::KERNEL_STACKS;
+const KERNEL_STACKS_END: u64 = KERNEL_STACKS_START + (1 << 39);
+const KERNEL_STACK_SLOT: u64 = 64 * 1024;
+
+/// Unmaps the kernel's init sections (see `sections`) and hands their frames to the frame
+/// allocator; returns how many bytes that freed. Called once the boot is over, on the boot CPU,
+/// as none of the code there may run again and only the boot CPU ever ran it, so no other CPU can
+/// have the pages in its TLB.
+pub fn reclaim_init() -> u64 {
+    let range = sections::init();
+    if range.start == range.end {
+        return 0;
+    }
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (Some(mapper), Some(frame_allocator)) = (mapper.as_mut(), frame_allocator.as_mut()) else {
+        return 0;
+    };
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end),
+    );
+    let mut freed = 0;
+    for page in pages {
+        // a page the bootloader mapped some other way is left alone
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.add_frame(frame) };
+            freed += page.size();
+        }
+    }
+    freed
+}
+
+/// Gives the kernel image's pages the permissions their sections call for, and has the CPU
+/// enforce them: code read-only and executable, read-only data read-only, everything else
+/// writable but not executable, so a stray write can't change code or constants and data can't
+/// be run. Returns the first page it couldn't change. Called once the boot is over; the init
+/// sections are left alone, they're about to be freed.
+///
+/// The physical memory window still maps the image's frames writable, through huge pages this
+/// leaves alone; a write has to go out of its way to get there, though.
+pub fn protect_kernel() -> Result<(), VirtAddr> {
+    let read_only = PageTableFlags::PRESENT;
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let no_execute = PageTableFlags::NO_EXECUTE;
+    let sections = [
+        (sections::text(), read_only),
+        (sections::rodata(), read_only | no_execute),
+        (sections::symbols(), read_only | no_execute),
+        (sections::data(), writable | no_execute),
+        (sections::percpu(), writable | no_execute),
+        (sections::bss(), writable | no_execute),
+    ];
+    unsafe {
+        Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    let mut mapper = MAPPER.lock();
+    let Some(mapper) = mapper.as_mut() else {
+        return Ok(());
+    };
+    for (range, flags) in sections {
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(range.start),
+            Page::containing_address(range.end),
+        );
+        for page in pages {
+            unsafe { mapper.update_flags(page, flags) }
+                .map_err(|_| page.start_address())?
+                .ignore();
+        }
+    }
+    tlb::flush_all();
+    Ok(())
+}
+
+/// Start of the first slot no stack has taken yet
+static NEXT_KERNEL_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+/// Maps a kernel stack of `size` bytes, at most `KERNEL_STACK_SLOT` less a page, and returns its
+/// top. The stack sits at the top of a slot of its own whose rest stays unmapped, so overflowing
+/// it faults instead of overwriting whatever came below. The CPU can't push the page fault's frame
+/// onto the full stack either and raises a double fault, whose handler runs on a stack of its own
+/// and reports the overflow (see `in_kernel_stack_guard`). Kernel stacks don't grow on demand
+/// like user stacks do: the fault that would grow one has no stack left to be handled on.
+///
+/// The first stack is allocated during boot, so the level 4 entry exists before any
+/// `AddressSpace` copies the kernel's.
+pub fn allocate_kernel_stack(size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let size = size.next_multiple_of(Page::<Size4KiB>::SIZE);
+    assert!(
+        size < KERNEL_STACK_SLOT,
+        "a kernel stack of {} bytes leaves no room for a guard page",
+        size
+    );
+    let slot = NEXT_KERNEL_STACK.fetch_add(KERNEL_STACK_SLOT, Ordering::Relaxed);
+    if slot + KERNEL_STACK_SLOT > KERNEL_STACKS_END {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    let top = VirtAddr::new(slot + KERNEL_STACK_SLOT);
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let pages = Page::range(
+        Page::<Size4KiB>::containing_address(top - size),
+        Page::containing_address(top),
+    );
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)? }.flush();
+    }
+    Ok(top)
+}
+
+/// Whether `address` is in the unmapped part of a kernel stack's slot, where only a stack
+/// overflow would take the CPU. Takes no locks, so the double fault handler can ask.
+pub fn in_kernel_stack_guard(address: VirtAddr) -> bool {
+    let allocated = KERNEL_STACKS_START..NEXT_KERNEL_STACK.load(Ordering::Relaxed);
+    allocated.contains(&address.as_u64()) && translate_in(Cr3::read().0, address).is_none()
+}
+
+/// Maps `size` bytes from `start` to fresh frames ring 3 can reach, with `flags` on top of
+/// `PRESENT | USER_ACCESSIBLE`, in the kernel's own page tables. The memory starts out as
+/// `contents` followed by zeros; it's filled through the physical memory mapping, so read-only
+/// pages can be filled too.
+///
+/// The range must lie in a part of the address space the kernel doesn't use: `map_to` only sets
+/// `USER_ACCESSIBLE` on the page tables it creates, not on existing ones. Programs that need an
+/// address space of their own use `address_space::AddressSpace` instead.
+pub fn map_user(
+    start: VirtAddr,
+    size: u64,
+    contents: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+    map_user_pages(mapper, frame_allocator, start, size, contents, flags)
+}
+
+/// Backs the pages covering `size` bytes at `start` with user-accessible frames and copies
+/// `contents` to `start`. Pages that are already mapped (two ELF segments may share one) are
+/// kept, with their flags widened to include `flags`; new ones are zeroed first.
+fn map_user_pages(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    contents: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let end = start + size.max(contents.len() as u64).max(1);
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(end - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        let frame = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags: existing,
+                ..
+            } => {
+                // executable if either user is; writable if either is
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe { mapper.update_flags(page, merged) }
+                    .map_err(|_| MapToError::ParentEntryHugePage)?
+                    .flush();
+                frame
+            }
+            TranslateResult::Mapped { .. } => return Err(MapToError::ParentEntryHugePage),
+            _ => {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                // usable frames may still hold whatever firmware or the bootloader left in them
+                let memory = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+                unsafe {
+                    core::ptr::write_bytes(memory, 0, 4096);
+                    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+                }
+                frame
+            }
+        };
+
+        // the part of `contents` that lands in this page
+        let page_start = page.start_address().as_u64();
+        let from = page_start.max(start.as_u64());
+        let to = (page_start + 4096).min(start.as_u64() + contents.len() as u64);
+        if from < to {
+            let source =
+                &contents[(from - start.as_u64()) as usize..(to - start.as_u64()) as usize];
+            let target = phys_to_virt(frame.start_address() + (from - page_start));
+            unsafe {
+                core::ptr::copy_nonoverlapping(source.as_ptr(), target.as_mut_ptr(), source.len())
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Walks the page tables rooted at `root` and returns the physical address `address` maps to,
+/// along with the flags in effect for it: `USER_ACCESSIBLE` and `WRITABLE` only if every level
+/// grants them, `NO_EXECUTE` if any level sets it, `COPY_ON_WRITE` if the page has it
+pub fn translate_in(root: PhysFrame, address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let mut effective = PageTableFlags::PRESENT | inherited;
+    let indexes = [
+        address.p4_index(),
+        address.p3_index(),
+        address.p2_index(),
+        address.p1_index(),
+    ];
+    let mut table_address = root.start_address();
+    for (level, index) in indexes.into_iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(table_address).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let sticky = PageTableFlags::NO_EXECUTE | address_space::COPY_ON_WRITE;
+        effective = (effective & (flags | !inherited)) | (flags & sticky);
+        // level 0 is the level 4 table, which can't map huge pages
+        let huge = level > 0 && level < 3 && flags.contains(PageTableFlags::HUGE_PAGE);
+        if huge || level == 3 {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            let offset = address.as_u64() & (page_size - 1);
+            return Some((entry.addr() + offset, effective));
+        }
+        table_address = entry.addr();
+    }
+    unreachable!()
+}
+
+/// Whether ring 3 may read (and with `write`, also write) every byte of `len` bytes at `start`
+/// in the address space this CPU is running in; system calls check user pointers with this
+/// before touching them
+pub fn user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) if len > 0 => end,
+        Some(_) => return true,
+        None => return false,
+    };
+    if start.as_u64() < USER_START || end > USER_END {
+        return false;
+    }
+
+    let root = Cr3::read().0;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        translate_in(root, page.start_address()).is_some_and(|(_, mut flags)| {
+            // writing one faults, and the page fault handler makes it writable
+            if flags.contains(address_space::COPY_ON_WRITE) {
+                flags.insert(PageTableFlags::WRITABLE);
+            }
+            flags.contains(required)
+        })
+    })
+}
+
+/// A FrameAllocator that returns usable frames from the boot memory map.
+///
+/// Frames given back with `deallocate_frame` are kept in a list threaded through the frames
+/// themselves, and handed out again before any new one.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize, // index of the next frame that should be returned
+    /// Once `split_by_node` ran: each node's next frame, counting only that node's frames from
+    /// index `next` on
+    node_next: Option<[usize; numa::MAX_NODES]>,
+    /// The last frame given back; each free frame starts with the address of the one before
+    free: Option<PhysFrame>,
+    free_count: usize,
+    /// Frames given with `add_frame`, which the memory map didn't count as usable
+    added: usize,
+}
+
+/// How many frames there are and how many are in use
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub allocated: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the passed memory map is valid, mainly that
+    /// all frames marked as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+            node_next: None,
+            free: None,
+            free_count: 0,
+            added: 0,
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let usable = self.usable_frames().count();
+        let handed_out = self.next + self.node_next.map_or(0, |next| next.iter().sum());
+        FrameStats {
+            total: usable + self.added,
+            allocated: handed_out.min(usable) + self.added - self.free_count,
+        }
+    }
+
+    /// Hands out `frame` from now on, though the memory map didn't list it as usable, e.g. one
+    /// that held the kernel's init sections.
+    ///
+    /// # Safety
+    ///
+    /// The frame must be RAM nothing uses any more, and not already in this allocator's hands.
+    pub unsafe fn add_frame(&mut self, frame: PhysFrame) {
+        self.added += 1;
+        self.deallocate_frame(frame);
+    }
+
+    /// Takes the last frame off the free list
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        let frame = self.free?;
+        let link = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+        self.free = (link != 0).then(|| PhysFrame::containing_address(PhysAddr::new(link)));
+        self.free_count -= 1;
+        Some(frame)
+    }
+
+    /// Makes `allocate_frame` prefer frames of the calling CPU's NUMA node; see `numa`
+    pub fn split_by_node(&mut self) {
+        self.node_next.get_or_insert([0; numa::MAX_NODES]);
+    }
+
+    /// A frame of NUMA `node`, if it has any left
+    pub fn allocate_frame_on(&mut self, node: usize) -> Option<PhysFrame> {
+        let Some(node_next) = self.node_next else {
+            return self.allocate_frame(); // everything is node 0
+        };
+        let frame = self
+            .usable_frames()
+            .skip(self.next)
+            .filter(|frame| numa::frame_node(frame.start_address()) == node)
+            .nth(node_next[node]);
+        if let (Some(_), Some(node_next)) = (frame, self.node_next.as_mut()) {
+            node_next[node] += 1;
+        }
+        frame
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.kind == MemoryKind::Usable);
+        let addr_ranges = usable_regions.map(|r| r.start..r.end);
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // one address per 4KiB frame
+        let frame_addresses = frame_addresses.filter(|&addr| addr >= LOW_MEMORY_END);
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.pop_free() {
+            return Some(frame);
+        }
+        if self.node_next.is_some() {
+            let node = percpu::try_current().map_or(0, |cpu| cpu.node);
+            return numa::by_distance(node).find_map(|node| self.allocate_frame_on(node));
+        }
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Puts `frame` on the free list, whatever NUMA node it's on.
+    ///
+    /// # Safety
+    ///
+    /// The frame must have come from this allocator and nothing may use it any more.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let link = self.free.map_or(0, |free| free.start_address().as_u64());
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u64>()
+            .write(link);
+        self.free = Some(frame);
+        self.free_count += 1;
+    }
+}
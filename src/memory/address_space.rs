@@ -0,0 +1,590 @@
+//! Address spaces of their own for user programs.
+//!
+//! An `AddressSpace` is a level 4 table whose kernel entries are copied from the kernel's table,
+//! so everything below them is shared with the kernel and every other space, while the user part
+//! between `USER_START` and `USER_END` belongs to the space alone. A kernel mapping that needed a
+//! new level 4 entry wouldn't show up in spaces created before it; the kernel sets up all of its
+//! regions during boot, before any space exists.
+//!
+//! `fork` copies a space without copying its memory: both spaces map the same frames, and the
+//! writable ones are marked `COPY_ON_WRITE` instead of writable in both. The first write to such
+//! a page faults, and `resolve_write_fault` gives the writing space a copy of its own, or, if no
+//! other space maps the frame any more, just makes it writable again. That goes for the kernel
+//! writing to user memory in a system call as well, since CR0.WP is set (by the bootloader, and
+//! by the AP trampoline).
+//!
+//! Memory can also be reserved without mapping it, see `reserve`: a page of a reserved region is
+//! mapped, zeroed or read from a file, the first time it's touched. `populate` does that for the
+//! page fault handler, and for system calls about to access user memory. Stacks are reserved
+//! that way too, see `reserve_stack`: they grow a page at a time as far as their limit, where a
+//! guard page that's never mapped turns running past it into a fault of its own.
+//!
+//! Frames aren't given back when a space is dropped, as the frame allocator can't take them back
+//! yet. For the same reason a dropped space doesn't count as letting go of its shared frames; the
+//! spaces left copy them once more than they'd have to.
+//!
+//! A space counts the pages it maps, and charges them to a `PageAccount` once it's given one
+//! (see `set_account`), which may refuse them: `map` and `populate` then fail as if memory ran
+//! out. Frames shared by forked spaces count in each of them, as they may all end up with copies;
+//! the copies themselves aren't counted again.
+
+use super::{
+    map_user_pages, phys_to_virt, translate_in, FRAME_ALLOCATOR, MAPPER, USER_END, USER_START,
+};
+use crate::fs::Inode;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Marks a page that's writable for the program, but shared with another space until it writes;
+/// one of the bits the CPU leaves to the OS
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// How many spaces map each copy-on-write frame, by physical address
+static SHARED: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// The level 4 entries covering the user part
+const USER_ENTRIES: core::ops::Range<usize> =
+    (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// The part of the user address space `find_free` hands out, well away from where executables
+/// are loaded and from the stack
+pub const MMAP_START: u64 = USER_START + 0x1000_0000_0000;
+pub const MMAP_END: u64 = USER_END - 0x100_0000_0000;
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+/// Where the pages of a reserved region come from
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero-filled memory
+    Anonymous,
+    /// The file's contents from `offset` on, zeros past its end. Every space gets copies of its
+    /// own, writing them doesn't change the file.
+    File { inode: Arc<dyn Inode>, offset: u64 },
+    /// Nothing: the page below a stack, which touching means the stack overflowed
+    Guard,
+}
+
+/// A page-aligned range whose pages are mapped when first touched
+#[derive(Clone)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+/// Where a space's pages are counted, and possibly capped
+pub trait PageAccount: Send + Sync {
+    /// Counts `pages` more pages, or nothing and `false` if that would go over a limit
+    fn try_charge(&self, pages: u64) -> bool;
+
+    fn uncharge(&self, pages: u64);
+}
+
+pub struct AddressSpace {
+    root: PhysFrame,
+    /// Reserved regions, by start address
+    regions: Mutex<Vec<Region>>,
+    /// Pages mapped in the user part
+    pages: AtomicU64,
+    /// Where `pages` are charged; locked while they change
+    account: Mutex<Option<Arc<dyn PageAccount>>>,
+}
+
+impl AddressSpace {
+    /// A space with the kernel's mappings and nothing in the user part
+    pub fn new() -> Result<Self, &'static str> {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or("paging is not initialized")?;
+        let root = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|frame_allocator| frame_allocator.allocate_frame())
+            .ok_or("out of memory")?;
+
+        let table = unsafe { &mut *phys_to_virt(root.start_address()).as_mut_ptr::<PageTable>() };
+        table.zero();
+        for (index, entry) in mapper.level_4_table().iter().enumerate() {
+            if !USER_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace {
+            root,
+            regions: Mutex::new(Vec::new()),
+            pages: AtomicU64::new(0),
+            account: Mutex::new(None),
+        })
+    }
+
+    /// The level 4 table, as loaded into CR3
+    pub fn root(&self) -> PhysFrame {
+        self.root
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *phys_to_virt(self.root.start_address()).as_mut_ptr() };
+        unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Pages mapped in the user part
+    pub fn pages(&self) -> u64 {
+        self.pages.load(Ordering::Relaxed)
+    }
+
+    /// Charges the pages mapped so far and from now on to `account` instead of the previous
+    /// account, if any; fails, changing nothing, if `account` refuses them
+    pub fn set_account(&self, account: Arc<dyn PageAccount>) -> Result<(), &'static str> {
+        let mut current = self.account.lock();
+        let pages = self.pages();
+        if !account.try_charge(pages) {
+            return Err("the pages would go over the memory limit");
+        }
+        if let Some(previous) = current.replace(account) {
+            previous.uncharge(pages);
+        }
+        Ok(())
+    }
+
+    /// Counts `pages` about to be mapped, if the account takes them
+    fn charge(&self, pages: u64) -> Result<(), &'static str> {
+        let account = self.account.lock();
+        if let Some(account) = account.as_ref() {
+            if !account.try_charge(pages) {
+                return Err("memory limit reached");
+            }
+        }
+        self.pages.fetch_add(pages, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes back a `charge` for pages that didn't get mapped after all
+    fn uncharge(&self, pages: u64) {
+        let account = self.account.lock();
+        if let Some(account) = account.as_ref() {
+            account.uncharge(pages);
+        }
+        self.pages.fetch_sub(pages, Ordering::Relaxed);
+    }
+
+    /// Like `memory::map_user`, but in this space: backs `size` bytes at `start` with
+    /// user-accessible memory holding `contents` followed by zeros. `start` needn't be page
+    /// aligned, and pages already mapped are reused.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        contents: &[u8],
+        flags: PageTableFlags,
+    ) -> Result<(), &'static str> {
+        let end = start
+            .as_u64()
+            .checked_add(size.max(contents.len() as u64))
+            .ok_or("region wraps around")?;
+        if start.as_u64() < USER_START || end > USER_END {
+            return Err("region is outside the user part of the address space");
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last =
+            Page::<Size4KiB>::containing_address(VirtAddr::new(end.max(start.as_u64() + 1) - 1));
+        let new_pages = Page::range_inclusive(first, last)
+            .filter(|page| translate_in(self.root, page.start_address()).is_none())
+            .count() as u64;
+        self.charge(new_pages)?;
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator
+            .as_mut()
+            .ok_or("paging is not initialized")?;
+        map_user_pages(
+            &mut self.mapper(),
+            frame_allocator,
+            start,
+            size,
+            contents,
+            flags,
+        )
+        .map_err(|_| "out of memory")
+        .inspect_err(|_| self.uncharge(new_pages))
+    }
+
+    /// Copies `data` to `address`, which must be mapped; works whether or not the space is active
+    pub fn write(&mut self, address: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+        let mut written = 0;
+        while written < data.len() {
+            let at = address + written as u64;
+            // the physical memory mapping doesn't fault on shared frames
+            if let Some(entry) = leaf_entry(self.root, at) {
+                if entry.flags().contains(COPY_ON_WRITE) {
+                    unshare(entry)?;
+                    tlb::flush(at);
+                }
+            }
+            let (phys, _) = translate_in(self.root, at).ok_or("address is not mapped")?;
+            let in_page = (Page::<Size4KiB>::SIZE - (at.as_u64() & 0xfff)) as usize;
+            let chunk = &data[written..data.len().min(written + in_page)];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    chunk.as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr(),
+                    chunk.len(),
+                )
+            };
+            written += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Reserves the `size` bytes at `start`, both page aligned, without mapping anything yet;
+    /// each page gets mapped with `flags` and filled from `backing` when it's first touched
+    pub fn reserve(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), &'static str> {
+        let start = start.as_u64();
+        let end = start.checked_add(size).ok_or("region wraps around")?;
+        if size == 0 || !start.is_multiple_of(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+            return Err("region is not page aligned");
+        }
+        if start < USER_START || end > USER_END {
+            return Err("region is outside the user part of the address space");
+        }
+        let mut regions = self.regions.lock();
+        if regions
+            .iter()
+            .any(|region| region.start < end && start < region.end)
+        {
+            return Err("region overlaps a reserved one");
+        }
+        let at = regions.partition_point(|region| region.start < start);
+        regions.insert(
+            at,
+            Region {
+                start,
+                end,
+                flags,
+                backing,
+            },
+        );
+        Ok(())
+    }
+
+    /// Reserves a stack that grows down from `top` by up to `limit` bytes, both page aligned,
+    /// and a guard page below it
+    pub fn reserve_stack(&self, top: VirtAddr, limit: u64) -> Result<(), &'static str> {
+        let bottom = top
+            .as_u64()
+            .checked_sub(limit)
+            .ok_or("region wraps around")?;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        self.reserve(VirtAddr::new(bottom), limit, flags, Backing::Anonymous)?;
+        let guard = VirtAddr::new(bottom.saturating_sub(PAGE_SIZE));
+        self.reserve(guard, PAGE_SIZE, PageTableFlags::empty(), Backing::Guard)
+            .inspect_err(|_| self.release(bottom))
+    }
+
+    /// Forgets the reserved region starting at `start`; pages of it already mapped stay mapped
+    fn release(&self, start: u64) {
+        self.regions.lock().retain(|region| region.start != start);
+    }
+
+    /// Whether `address` is in the guard page of one of the stacks (see `reserve_stack`)
+    pub fn is_guard(&self, address: VirtAddr) -> bool {
+        self.regions.lock().iter().any(|region| {
+            matches!(region.backing, Backing::Guard)
+                && (region.start..region.end).contains(&address.as_u64())
+        })
+    }
+
+    /// The reserved regions, by start address
+    pub fn regions(&self) -> Vec<Region> {
+        self.regions.lock().clone()
+    }
+
+    /// Calls `f` with the address, flags and contents of every page mapped in the user part, by
+    /// address. Copy-on-write pages show up with their `COPY_ON_WRITE` flag, not as writable.
+    pub fn for_each_page(&self, mut f: impl FnMut(VirtAddr, PageTableFlags, &[u8])) {
+        let table = table_mut(self.root.start_address());
+        for index in USER_ENTRIES {
+            visit_pages(&table[index], 4, (index as u64) << 39, &mut f);
+        }
+    }
+
+    /// The lowest address from `MMAP_START` on where `size` bytes can be reserved
+    pub fn find_free(&self, size: u64) -> Option<VirtAddr> {
+        let mut start = MMAP_START;
+        for region in self.regions.lock().iter() {
+            if region.end <= start {
+                continue;
+            }
+            if region.start >= start.checked_add(size)? {
+                break;
+            }
+            start = region.end;
+        }
+        match start.checked_add(size)? <= MMAP_END {
+            true => Some(VirtAddr::new(start)),
+            false => None,
+        }
+    }
+
+    /// Maps the pages among the `len` bytes at `start` that are reserved but not mapped yet.
+    /// Fails if a page is neither, or there's no memory for it.
+    pub fn populate(&self, start: VirtAddr, len: u64) -> Result<(), &'static str> {
+        let end = start
+            .as_u64()
+            .checked_add(len.max(1))
+            .ok_or("range wraps around")?;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last) {
+            let address = page.start_address();
+            if translate_in(self.root, address).is_some() {
+                continue;
+            }
+            let regions = self.regions.lock();
+            let region = regions
+                .iter()
+                .find(|region| (region.start..region.end).contains(&address.as_u64()))
+                .ok_or("address is not mapped")?;
+            if let Backing::Guard = region.backing {
+                return Err("address is in a stack's guard page");
+            }
+
+            let mut contents = [0u8; PAGE_SIZE as usize];
+            let mut filled = 0;
+            if let Backing::File { inode, offset } = &region.backing {
+                let offset = offset + (address.as_u64() - region.start);
+                while filled < contents.len() {
+                    let read = inode
+                        .read_at(offset + filled as u64, &mut contents[filled..])
+                        .map_err(|_| "failed to read the mapped file")?;
+                    if read == 0 {
+                        break;
+                    }
+                    filled += read;
+                }
+            }
+            self.charge(1)?;
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator
+                .as_mut()
+                .ok_or("paging is not initialized")?;
+            map_user_pages(
+                &mut self.mapper(),
+                frame_allocator,
+                address,
+                PAGE_SIZE,
+                &contents[..filled],
+                region.flags,
+            )
+            .map_err(|_| "out of memory")
+            .inspect_err(|_| self.uncharge(1))?;
+        }
+        Ok(())
+    }
+
+    /// A new space with the same user mappings, sharing every frame; the writable ones become
+    /// copy-on-write in both spaces. Reserved regions are reserved in the new space too.
+    pub fn fork(&self) -> Result<AddressSpace, &'static str> {
+        let child = AddressSpace::new()?;
+        *child.regions.lock() = self.regions.lock().clone();
+        // counted, but not charged until the child is given an account
+        child.pages.store(self.pages(), Ordering::Relaxed);
+        let mut shared = SHARED.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator
+            .as_mut()
+            .ok_or("paging is not initialized")?;
+        let parent_table = table_mut(self.root.start_address());
+        let child_table = table_mut(child.root.start_address());
+        for index in USER_ENTRIES {
+            fork_entry(
+                &mut parent_table[index],
+                &mut child_table[index],
+                4,
+                frame_allocator,
+                &mut shared,
+            )?;
+        }
+        if Cr3::read().0 == self.root {
+            tlb::flush_all(); // writable pages just became read-only
+        }
+        Ok(child)
+    }
+
+    /// Runs `f` with this space loaded on the calling CPU, then switches back
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let (previous, flags) = Cr3::read();
+        unsafe { Cr3::write(self.root, flags) };
+        let result = f();
+        unsafe { Cr3::write(previous, flags) };
+        result
+    }
+}
+
+impl Drop for AddressSpace {
+    /// The frames stay in use (see the module documentation), but no longer count against the
+    /// account
+    fn drop(&mut self) {
+        if let Some(account) = self.account.lock().take() {
+            account.uncharge(self.pages());
+        }
+    }
+}
+
+fn table_mut<'a>(address: PhysAddr) -> &'a mut PageTable {
+    unsafe { &mut *phys_to_virt(address).as_mut_ptr() }
+}
+
+/// Fills `child`, an entry of a level `level` table, with a copy of `parent` and what's below it.
+/// The page tables are copied, the pages they map are shared.
+fn fork_entry(
+    parent: &mut PageTableEntry,
+    child: &mut PageTableEntry,
+    level: usize,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    shared: &mut BTreeMap<u64, usize>,
+) -> Result<(), &'static str> {
+    let mut flags = parent.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Ok(());
+    }
+    if level == 1 {
+        if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+            parent.set_flags(flags);
+            *shared.entry(parent.addr().as_u64()).or_insert(1) += 1;
+        }
+        child.set_addr(parent.addr(), flags);
+        return Ok(());
+    }
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err("huge pages can't be shared copy-on-write");
+    }
+
+    let frame = frame_allocator.allocate_frame().ok_or("out of memory")?;
+    let table = table_mut(frame.start_address());
+    table.zero();
+    child.set_addr(frame.start_address(), flags);
+    let parent_table = table_mut(parent.addr());
+    for (parent, child) in parent_table.iter_mut().zip(table.iter_mut()) {
+        fork_entry(parent, child, level - 1, frame_allocator, shared)?;
+    }
+    Ok(())
+}
+
+/// Calls `f` for the pages under `entry`, an entry of a level `level` table covering the
+/// addresses from `base` on
+fn visit_pages(
+    entry: &PageTableEntry,
+    level: usize,
+    base: u64,
+    f: &mut impl FnMut(VirtAddr, PageTableFlags, &[u8]),
+) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    if level == 1 {
+        let contents = unsafe {
+            core::slice::from_raw_parts(
+                phys_to_virt(entry.addr()).as_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            )
+        };
+        f(VirtAddr::new(base), flags, contents);
+        return;
+    }
+    let table = table_mut(entry.addr());
+    let shift = 12 + 9 * (level as u64 - 2);
+    for (index, entry) in table.iter().enumerate() {
+        visit_pages(entry, level - 1, base + ((index as u64) << shift), f);
+    }
+}
+
+/// The level 1 entry for `address` under `root`, if the tables down to it exist
+fn leaf_entry<'a>(root: PhysFrame, address: VirtAddr) -> Option<&'a mut PageTableEntry> {
+    let mut table = table_mut(root.start_address());
+    for index in [address.p4_index(), address.p3_index(), address.p2_index()] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = table_mut(table[index].addr());
+    }
+    Some(&mut table[address.p1_index()])
+}
+
+/// Makes the copy-on-write page `entry` maps writable, copying it first unless no other space
+/// maps its frame any more. The caller flushes the TLB entry.
+fn unshare(entry: &mut PageTableEntry) -> Result<(), &'static str> {
+    let frame = entry.addr();
+    let mut flags = entry.flags();
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    let mut shared = SHARED.lock();
+    match shared.get_mut(&frame.as_u64()) {
+        Some(sharers) if *sharers > 1 => {
+            let copy = FRAME_ALLOCATOR
+                .lock()
+                .as_mut()
+                .and_then(|frame_allocator| frame_allocator.allocate_frame())
+                .ok_or("out of memory")?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    phys_to_virt(frame).as_ptr::<u8>(),
+                    phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                    Page::<Size4KiB>::SIZE as usize,
+                )
+            };
+            *sharers -= 1;
+            entry.set_addr(copy.start_address(), flags);
+        }
+        _ => {
+            shared.remove(&frame.as_u64());
+            entry.set_flags(flags);
+        }
+    }
+    Ok(())
+}
+
+/// Handles a write fault at `address` in the space loaded on this CPU, if it hit a
+/// copy-on-write page, by giving the space a writable page of its own. Returns whether the
+/// write can be retried; `false` for any other fault, or if there was no memory for the copy.
+pub fn resolve_write_fault(address: VirtAddr) -> bool {
+    if !(USER_START..USER_END).contains(&address.as_u64()) {
+        return false;
+    }
+    let Some(entry) = leaf_entry(Cr3::read().0, address) else {
+        return false;
+    };
+    if !entry
+        .flags()
+        .contains(PageTableFlags::PRESENT | COPY_ON_WRITE)
+    {
+        return false;
+    }
+    match unshare(entry) {
+        Ok(()) => {
+            tlb::flush(address);
+            true
+        }
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,281 @@
+//! Memory types: how the CPU caches accesses to each physical page.
+//!
+//! Two mechanisms decide it together. The MTRRs, which the firmware sets up, give every physical
+//! range a type: write-back for RAM, uncacheable for the legacy VGA window and most device memory.
+//! The PAT lets each page table entry ask for a type of its own through its PWT, PCD and PAT bits.
+//! Where the two disagree the stricter type wins, except that a page asking for write-combining
+//! gets it whatever the MTRRs say. The kernel only reads the MTRRs, it never changes them.
+//!
+//! `init` programs the PAT on each CPU. Its first four entries keep their power-on meaning, which
+//! the bootloader's mappings rely on; the upper four provide write-combining, which the power-on
+//! PAT lacks, and write-protected. `flags` gives the page table bits for a type:
+//! `memory::map_mmio` maps device registers uncacheable, so every access reaches the device in
+//! order, and `memory::map_mmio_as` takes any type, e.g. write-combining for a framebuffer, which
+//! fills about ten times faster than an uncached one.
+
+use crate::println;
+use core::fmt;
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+const IA32_MTRRCAP: u32 = 0xfe;
+const IA32_PAT: u32 = 0x277;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+
+/// Bit 7 of a 4 KiB page's entry selects the upper half of the PAT; in the other levels it's the
+/// huge page bit, which is what the x86_64 crate calls it
+const PAT_4K: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Memory types, with the encoding the PAT and the MTRRs use for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    /// Every access goes to memory, in program order; for device registers
+    Uncacheable = 0,
+    /// Writes are buffered and combined into bursts, reads aren't cached; for framebuffers
+    WriteCombining = 1,
+    /// Reads are cached, writes go to memory right away
+    WriteThrough = 4,
+    /// Reads are cached, writes go to memory and don't update the cache
+    WriteProtected = 5,
+    /// Normal memory
+    WriteBack = 6,
+    /// Uncacheable, unless the MTRRs say write-combining; PAT only
+    UncachedMinus = 7,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u8) -> Option<MemoryType> {
+        match bits {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MemoryType::Uncacheable => "UC",
+            MemoryType::WriteCombining => "WC",
+            MemoryType::WriteThrough => "WT",
+            MemoryType::WriteProtected => "WP",
+            MemoryType::WriteBack => "WB",
+            MemoryType::UncachedMinus => "UC-",
+        })
+    }
+}
+
+/// The PAT `init` programs, indexed by PAT << 2 | PCD << 1 | PWT
+const PAT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::WriteProtected,
+    MemoryType::Uncacheable,
+];
+
+/// Whether the CPUs have a PAT, i.e. whether `init` programmed it
+static HAS_PAT: Once<bool> = Once::new();
+
+/// Programs the PAT on the calling CPU; run it on every CPU before it maps anything with a type
+/// other than write-back, as every CPU must agree on what the bits mean
+pub fn init() {
+    let has_pat = *HAS_PAT.call_once(|| {
+        CpuId::new()
+            .get_feature_info()
+            .is_some_and(|info| info.has_pat())
+    });
+    if !has_pat {
+        return;
+    }
+    let value = PAT
+        .iter()
+        .enumerate()
+        .fold(0u64, |value, (index, &memory_type)| {
+            value | ((memory_type as u64) << (8 * index))
+        });
+    interrupts::without_interrupts(|| unsafe {
+        // no line may stay cached under a type that's about to change
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(value);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+    });
+}
+
+/// The bits to set in a 4 KiB page's entry for `memory_type`. Without a PAT write-combining and
+/// write-protected pages make do with uncached-minus, which lets an MTRR's type through.
+pub fn flags(memory_type: MemoryType) -> PageTableFlags {
+    let has_pat = HAS_PAT.r#try().copied().unwrap_or(false);
+    let index = PAT
+        .iter()
+        .position(|&entry| entry == memory_type)
+        .filter(|&index| index < 4 || has_pat)
+        .unwrap_or(2);
+    let mut flags = PageTableFlags::empty();
+    if index & 1 != 0 {
+        flags |= PageTableFlags::WRITE_THROUGH;
+    }
+    if index & 2 != 0 {
+        flags |= PageTableFlags::NO_CACHE;
+    }
+    if index & 4 != 0 {
+        flags |= PAT_4K;
+    }
+    flags
+}
+
+/// One variable-range MTRR
+#[derive(Debug, Clone, Copy)]
+pub struct VariableRange {
+    pub base: u64,
+    pub mask: u64,
+    pub memory_type: MemoryType,
+}
+
+impl VariableRange {
+    fn contains(&self, address: u64) -> bool {
+        address & self.mask == self.base & self.mask
+    }
+
+    /// Size of the range, if the mask describes a contiguous one (it needn't)
+    pub fn size(&self) -> u64 {
+        1 << self.mask.trailing_zeros()
+    }
+}
+
+/// The MTRR settings of the calling CPU; the firmware sets up every CPU alike
+pub struct Mtrrs {
+    /// Type of whatever no range covers
+    pub default: MemoryType,
+    /// Whether the fixed ranges below 1 MiB are in use
+    fixed_enabled: bool,
+    variable: [Option<VariableRange>; 16],
+}
+
+impl Mtrrs {
+    /// Reads the MTRRs; `None` if the CPU has none or the firmware left them disabled
+    pub fn read() -> Option<Mtrrs> {
+        let cpuid = CpuId::new();
+        if !cpuid.get_feature_info().is_some_and(|info| info.has_mtrr()) {
+            return None;
+        }
+        let capabilities = unsafe { Msr::new(IA32_MTRRCAP).read() };
+        let default_type = unsafe { Msr::new(IA32_MTRR_DEF_TYPE).read() };
+        if default_type & (1 << 11) == 0 {
+            return None; // everything is uncacheable; the firmware would never leave it so
+        }
+        let physical_bits = cpuid
+            .get_processor_capacity_feature_info()
+            .map_or(36, |info| info.physical_address_bits());
+        let address_mask = ((1u64 << physical_bits) - 1) & !0xfff;
+
+        let mut variable = [None; 16];
+        let count = (capabilities & 0xff) as usize;
+        for (index, slot) in variable.iter_mut().enumerate().take(count) {
+            let msr = IA32_MTRR_PHYSBASE0 + 2 * index as u32;
+            let base = unsafe { Msr::new(msr).read() };
+            let mask = unsafe { Msr::new(msr + 1).read() };
+            if mask & (1 << 11) == 0 {
+                continue;
+            }
+            *slot = MemoryType::from_bits(base as u8).map(|memory_type| VariableRange {
+                base: base & address_mask,
+                mask: mask & address_mask,
+                memory_type,
+            });
+        }
+        Some(Mtrrs {
+            default: MemoryType::from_bits(default_type as u8)?,
+            fixed_enabled: capabilities & (1 << 8) != 0 && default_type & (1 << 10) != 0,
+            variable,
+        })
+    }
+
+    /// The variable ranges in use
+    pub fn variable(&self) -> impl Iterator<Item = &VariableRange> {
+        self.variable.iter().flatten()
+    }
+
+    /// The type the MTRRs give the byte at `address`
+    pub fn memory_type(&self, address: PhysAddr) -> MemoryType {
+        let address = address.as_u64();
+        if address < 0x10_0000 && self.fixed_enabled {
+            if let Some(memory_type) = fixed_type(address) {
+                return memory_type;
+            }
+        }
+        let mut matched: Option<MemoryType> = None;
+        for range in self.variable().filter(|range| range.contains(address)) {
+            // overlapping ranges: uncacheable wins, then write-through over write-back
+            matched = Some(match (matched, range.memory_type) {
+                (Some(MemoryType::Uncacheable), _) | (_, MemoryType::Uncacheable) => {
+                    MemoryType::Uncacheable
+                }
+                (Some(MemoryType::WriteThrough), _) | (_, MemoryType::WriteThrough) => {
+                    MemoryType::WriteThrough
+                }
+                (Some(previous), _) => previous,
+                (None, memory_type) => memory_type,
+            });
+        }
+        matched.unwrap_or(self.default)
+    }
+}
+
+/// The type a fixed-range MTRR gives `address`, below 1 MiB
+fn fixed_type(address: u64) -> Option<MemoryType> {
+    // each MSR holds eight ranges of `size` bytes, from `start` on
+    let (msr, start, size) = match address {
+        0..=0x7_ffff => (IA32_MTRR_FIX64K_00000, 0, 0x1_0000),
+        0x8_0000..=0xb_ffff => (IA32_MTRR_FIX16K_80000, 0x8_0000, 0x4000),
+        _ => (IA32_MTRR_FIX4K_C0000, 0xc_0000, 0x1000),
+    };
+    let range = (address - start) / size;
+    let value = unsafe { Msr::new(msr + (range / 8) as u32).read() };
+    MemoryType::from_bits((value >> (8 * (range % 8))) as u8)
+}
+
+/// `mtrr`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    let mtrrs = Mtrrs::read().ok_or("no MTRRs, or the firmware left them disabled")?;
+    println!("default {}", mtrrs.default);
+    if mtrrs.fixed_enabled {
+        println!(
+            "fixed ranges: VGA window {}, BIOS {}",
+            mtrrs.memory_type(PhysAddr::new(0xa_0000)),
+            mtrrs.memory_type(PhysAddr::new(0xf_0000))
+        );
+    }
+    for range in mtrrs.variable() {
+        println!(
+            "{:#014x}  {:>8} KiB  {}",
+            range.base,
+            range.size() / 1024,
+            range.memory_type
+        );
+    }
+    let pat = match HAS_PAT.r#try() {
+        Some(true) => "programmed",
+        _ => "not supported, write-combining falls back to UC-",
+    };
+    println!("PAT: {}", pat);
+    Ok(())
+}
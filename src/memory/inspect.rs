@@ -0,0 +1,364 @@
+//! Reading and writing memory by hand from the shell: `peek`, `poke`, `dump` and `watch`, for
+//! bringing up a device whose registers don't do what the datasheet says.
+//!
+//! Addresses are virtual ones in the kernel's half, unless `-p` makes them physical. A virtual
+//! range must be mapped in the running address space, and writable for `poke`; user memory is
+//! refused, since which program's it is depends on what's running. A physical range is read
+//! through the physical memory window where the bootloader mapped it, RAM for the most part, and
+//! mapped uncached like any device's registers otherwise (see `map_mmio`), so the command reads
+//! or writes the device and not a cached copy.
+//!
+//! `peek` and `poke` access 1, 2, 4 or 8 bytes at once, aligned, since registers may only answer
+//! accesses of their own size; `dump` and `watch` read byte by byte. A watch re-reads its range
+//! every interval and prints it again each time it changed, from the `watcher` task.
+
+use super::{map_mmio, phys_to_virt, translate_in, USER_END, USER_START};
+use crate::{println, timer, warnln};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+/// What `dump` shows without a length, and the most it shows
+const DEFAULT_DUMP: u64 = 64;
+const MAX_DUMP: u64 = 4096;
+/// Most bytes a watch covers, so a change fits on the screen
+const MAX_WATCH: u64 = 256;
+const MAX_WATCHES: usize = 8;
+const DEFAULT_INTERVAL: u64 = 1000;
+const MIN_INTERVAL: u64 = 10;
+/// How often `watcher` looks for watches that are due
+const WATCH_TICK: Duration = Duration::from_millis(10);
+
+fn parse_number(text: &str) -> Result<u64, &'static str> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| "not a number")
+}
+
+/// Splits off a leading `-p`
+fn physical_flag<'a, 'b>(args: &'a [&'b str]) -> (bool, &'a [&'b str]) {
+    match args.split_first() {
+        Some((&"-p", rest)) => (true, rest),
+        _ => (false, args),
+    }
+}
+
+/// A range to read or write, as given
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Target {
+    address: u64,
+    len: u64,
+    physical: bool,
+}
+
+impl Target {
+    /// Where the kernel can access the range, after the checks in the module docs
+    fn resolve(&self, write: bool) -> Result<*mut u8, &'static str> {
+        let end = self
+            .address
+            .checked_add(self.len)
+            .ok_or("the range wraps around")?;
+        let virt = match self.physical {
+            false => self.address,
+            true => {
+                if end > 1 << 52 {
+                    return Err("beyond the largest physical address");
+                }
+                let phys = PhysAddr::new(self.address);
+                let virt = phys_to_virt(phys).as_u64();
+                // mapping RAM uncached as well would leave it cached one way and not another
+                match pages(virt, self.len).filter(Option::is_some).count() {
+                    0 => {
+                        map_mmio(phys, self.len).map_err(|_| "can't map it")?;
+                    }
+                    mapped if mapped == pages(virt, self.len).count() => {}
+                    _ => return Err("the range is partly RAM and partly not"),
+                }
+                virt
+            }
+        };
+        let start = VirtAddr::try_new(virt).map_err(|_| "not a canonical address")?;
+        if start.as_u64() < USER_END && virt.saturating_add(self.len) > USER_START {
+            return Err("that's user memory");
+        }
+        if VirtAddr::try_new(virt + self.len.max(1) - 1).is_err() {
+            return Err("the range leaves the canonical addresses");
+        }
+        if !mapped(virt, self.len, write) {
+            return Err(match write {
+                true => "not mapped writable",
+                false => "not mapped",
+            });
+        }
+        Ok(start.as_mut_ptr())
+    }
+}
+
+/// The flags of each page of `len` bytes at `start` in the running address space, `None` for
+/// those that aren't mapped
+fn pages(start: u64, len: u64) -> impl Iterator<Item = Option<PageTableFlags>> {
+    let root = Cr3::read().0;
+    let first = start & !(PAGE_SIZE - 1);
+    let last = start.saturating_add(len.max(1) - 1);
+    (first..=last).step_by(PAGE_SIZE as usize).map(move |page| {
+        let page = VirtAddr::try_new(page).ok()?;
+        translate_in(root, page).map(|(_, flags)| flags)
+    })
+}
+
+/// Whether every page of `len` bytes at `start` is mapped, and writable if `write`
+fn mapped(start: u64, len: u64, write: bool) -> bool {
+    pages(start, len)
+        .all(|flags| flags.is_some_and(|flags| !write || flags.contains(PageTableFlags::WRITABLE)))
+}
+
+/// Copies `len` bytes from `source` a byte at a time
+fn read_bytes(source: *const u8, len: u64) -> Vec<u8> {
+    (0..len as usize)
+        .map(|offset| unsafe { source.add(offset).read_volatile() })
+        .collect()
+}
+
+/// Prints `bytes`, which were read at `address`, 16 to a line with their characters
+fn print_bytes(address: u64, bytes: &[u8]) {
+    let start = address & !15;
+    let end = address + bytes.len() as u64;
+    for line in (start..end).step_by(16) {
+        let mut hex = [b' '; 48];
+        let mut text = [b' '; 16];
+        for column in 0..16 {
+            let Some(offset) = (line + column).checked_sub(address) else {
+                continue;
+            };
+            let Some(&byte) = bytes.get(offset as usize) else {
+                break;
+            };
+            let digits = b"0123456789abcdef";
+            hex[column as usize * 3 + 1] = digits[byte as usize >> 4];
+            hex[column as usize * 3 + 2] = digits[byte as usize & 15];
+            text[column as usize] = match byte {
+                0x20..=0x7e => byte,
+                _ => b'.',
+            };
+        }
+        println!(
+            "{:016x} {}  |{}|",
+            line,
+            core::str::from_utf8(&hex).unwrap(),
+            core::str::from_utf8(&text).unwrap()
+        );
+    }
+}
+
+/// The access size given as 1, 2, 4 or 8; 8 if not given
+fn parse_width(text: Option<&&str>) -> Result<u64, &'static str> {
+    match text.map(|text| parse_number(text)).transpose()? {
+        None => Ok(8),
+        Some(width @ (1 | 2 | 4 | 8)) => Ok(width),
+        Some(_) => Err("the width must be 1, 2, 4 or 8"),
+    }
+}
+
+/// `peek [-p] <address> [1|2|4|8]`
+pub fn peek_command(args: &[&str]) -> Result<(), &'static str> {
+    let (physical, args) = physical_flag(args);
+    let (address, width) = match args {
+        [address] | [address, _] => (parse_number(address)?, parse_width(args.get(1))?),
+        _ => return Err("usage: peek [-p] <address> [1|2|4|8]"),
+    };
+    if !address.is_multiple_of(width) {
+        return Err("the address isn't aligned to the width");
+    }
+    let target = Target {
+        address,
+        len: width,
+        physical,
+    };
+    let pointer = target.resolve(false)?;
+    let value = unsafe {
+        match width {
+            1 => pointer.read_volatile() as u64,
+            2 => pointer.cast::<u16>().read_volatile() as u64,
+            4 => pointer.cast::<u32>().read_volatile() as u64,
+            _ => pointer.cast::<u64>().read_volatile(),
+        }
+    };
+    println!(
+        "{:#x}: {:#0width$x} ({})",
+        address,
+        value,
+        value,
+        width = 2 + 2 * width as usize
+    );
+    Ok(())
+}
+
+/// `poke [-p] <address> <value> [1|2|4|8]`
+pub fn poke_command(args: &[&str]) -> Result<(), &'static str> {
+    let (physical, args) = physical_flag(args);
+    let (address, value, width) = match args {
+        [address, value] | [address, value, _] => (
+            parse_number(address)?,
+            parse_number(value)?,
+            parse_width(args.get(2))?,
+        ),
+        _ => return Err("usage: poke [-p] <address> <value> [1|2|4|8]"),
+    };
+    if !address.is_multiple_of(width) {
+        return Err("the address isn't aligned to the width");
+    }
+    if width < 8 && value >> (8 * width) != 0 {
+        return Err("the value doesn't fit in the width");
+    }
+    let target = Target {
+        address,
+        len: width,
+        physical,
+    };
+    let pointer = target.resolve(true)?;
+    unsafe {
+        match width {
+            1 => pointer.write_volatile(value as u8),
+            2 => pointer.cast::<u16>().write_volatile(value as u16),
+            4 => pointer.cast::<u32>().write_volatile(value as u32),
+            _ => pointer.cast::<u64>().write_volatile(value),
+        }
+    }
+    Ok(())
+}
+
+/// `dump [-p] <address> [len]`
+pub fn dump_command(args: &[&str]) -> Result<(), &'static str> {
+    let (physical, args) = physical_flag(args);
+    let (address, len) = match args {
+        [address] => (parse_number(address)?, DEFAULT_DUMP),
+        [address, len] => (parse_number(address)?, parse_number(len)?),
+        _ => return Err("usage: dump [-p] <address> [len]"),
+    };
+    let target = Target {
+        address,
+        len: len.min(MAX_DUMP),
+        physical,
+    };
+    let bytes = read_bytes(target.resolve(false)?, target.len);
+    print_bytes(address, &bytes);
+    Ok(())
+}
+
+struct Watch {
+    id: usize,
+    target: Target,
+    /// Milliseconds between reads
+    interval: u64,
+    /// When it's read next, in milliseconds of `watcher`'s time
+    due: u64,
+    /// What it read last, `None` before the first read
+    last: Option<Vec<u8>>,
+}
+
+static WATCHES: Mutex<Vec<Watch>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// `watch [[-p] <address> [len] [interval ms] | stop <id>|all]`
+pub fn watch_command(args: &[&str]) -> Result<(), &'static str> {
+    let (physical, args) = physical_flag(args);
+    match (physical, args) {
+        (false, []) => {
+            let watches = WATCHES.lock();
+            if watches.is_empty() {
+                println!("no watches");
+            }
+            for watch in watches.iter() {
+                println!(
+                    "{}: {}{:#x}, {} bytes every {} ms",
+                    watch.id,
+                    if watch.target.physical {
+                        "physical "
+                    } else {
+                        ""
+                    },
+                    watch.target.address,
+                    watch.target.len,
+                    watch.interval
+                );
+            }
+        }
+        (false, ["stop", "all"]) => WATCHES.lock().clear(),
+        (false, ["stop", id]) => {
+            let id: usize = id.parse().map_err(|_| "not a watch number")?;
+            let mut watches = WATCHES.lock();
+            let index = watches
+                .iter()
+                .position(|watch| watch.id == id)
+                .ok_or("no such watch")?;
+            watches.remove(index);
+        }
+        (_, [address, rest @ ..]) if rest.len() <= 2 => {
+            let len = rest.first().map_or(Ok(8), |len| parse_number(len))?;
+            let interval = rest
+                .get(1)
+                .map_or(Ok(DEFAULT_INTERVAL), |interval| parse_number(interval))?;
+            if len == 0 || len > MAX_WATCH {
+                return Err("a watch covers 1 to 256 bytes");
+            }
+            let target = Target {
+                address: parse_number(address)?,
+                len,
+                physical,
+            };
+            // refused now rather than by the watcher later
+            target.resolve(false)?;
+            let mut watches = WATCHES.lock();
+            if watches.len() >= MAX_WATCHES {
+                return Err("too many watches");
+            }
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            watches.push(Watch {
+                id,
+                target,
+                interval: interval.max(MIN_INTERVAL),
+                due: 0,
+                last: None,
+            });
+            println!("watch {} added", id);
+        }
+        _ => return Err("usage: watch [[-p] <address> [len] [interval ms] | stop <id>|all]"),
+    }
+    Ok(())
+}
+
+/// Reads the watches that are due and prints those that changed; meant to be spawned as a task
+pub async fn watcher() {
+    let tick = WATCH_TICK.as_millis() as u64;
+    let mut now = 0;
+    loop {
+        timer::sleep(WATCH_TICK).await;
+        now += tick;
+        let mut watches = WATCHES.lock();
+        let mut failed = Vec::new();
+        for watch in watches.iter_mut().filter(|watch| watch.due <= now) {
+            watch.due = now + watch.interval;
+            let bytes = match watch.target.resolve(false) {
+                Ok(pointer) => read_bytes(pointer, watch.target.len),
+                Err(err) => {
+                    warnln!("watch {}: {}, stopped", watch.id, err);
+                    failed.push(watch.id);
+                    continue;
+                }
+            };
+            if watch.last.as_ref() != Some(&bytes) {
+                println!("watch {}:", watch.id);
+                print_bytes(watch.target.address, &bytes);
+                watch.last = Some(bytes);
+            }
+        }
+        watches.retain(|watch| !failed.contains(&watch.id));
+    }
+}
@@ -0,0 +1,56 @@
+//! Where things are in the kernel's half of the address space.
+//!
+//! The kernel lives in the upper half, level 4 entries 256 to 511, so that the lower half is
+//! free for user programs (see `USER_START`) bar what the bootloader identity-maps for itself:
+//!
+//! | level 4 entry | start                   | what                                        |
+//! |---------------|-------------------------|---------------------------------------------|
+//! | 256           | `PHYSICAL_MEMORY`       | all of physical memory, see `phys_to_virt`  |
+//! | 384           | `HEAP_AREA`             | the heap, somewhere in the entry            |
+//! | 416           | `KERNEL_STACKS`         | kernel stacks, see `allocate_kernel_stack`  |
+//! | 508           | `BOOT_INFO`             | the bootloader's `BootInfo`                 |
+//! | 509           | `BOOT_STACK`            | the stack the boot CPU starts on            |
+//! | 511           | `KERNEL_BASE`           | the kernel image, see `linker.ld`           |
+//!
+//! The image sits in the top 2 GiB so it can be built with the `kernel` code model (see
+//! `x86_64-rust_os.json`). The bootloader places the image where it was linked and maps the
+//! rest where `[package.metadata.bootloader]` in `Cargo.toml` tells it to, so those addresses are
+//! fixed at build time and have to agree with the ones here.
+//!
+//! The heap's start is not: with `kaslr` on the command line, `init` moves it to a random 2 MiB
+//! boundary in `HEAP_AREA` on every boot, so a bug that hands out a heap address can't be aimed
+//! at a known one. The flag is read before there's a heap to parse the command line with, so only
+//! the loader's or the `-append` text counts for it (see `cmdline::early_flag`).
+
+use crate::{cmdline, rand};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+
+pub const PHYSICAL_MEMORY: u64 = 0xffff_8000_0000_0000;
+pub const HEAP_AREA: u64 = 0xffff_c000_0000_0000;
+/// Size of `HEAP_AREA`, one level 4 entry
+pub const HEAP_AREA_SIZE: u64 = 1 << 39;
+pub const KERNEL_STACKS: u64 = 0xffff_d000_0000_0000;
+pub const BOOT_INFO: u64 = 0xffff_fe00_0000_0000;
+pub const BOOT_STACK: u64 = 0xffff_fe80_0000_0000;
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// What the heap start is a multiple of
+const HEAP_ALIGN: u64 = 2 * 1024 * 1024;
+
+static HEAP_START: AtomicU64 = AtomicU64::new(HEAP_AREA);
+
+/// Picks the heap's start, at random with `kaslr`; before the heap is mapped
+#[link_section = ".init.text"]
+pub fn init(heap_size: u64) {
+    if !cmdline::early_flag("kaslr") {
+        return;
+    }
+    let slots = (HEAP_AREA_SIZE - heap_size) / HEAP_ALIGN;
+    let start = HEAP_AREA + rand::next_u64() % slots * HEAP_ALIGN;
+    HEAP_START.store(start, Ordering::Relaxed);
+}
+
+pub fn heap_start() -> VirtAddr {
+    VirtAddr::new(HEAP_START.load(Ordering::Relaxed))
+}
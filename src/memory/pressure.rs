@@ -0,0 +1,236 @@
+//! Memory pressure: how close the kernel is to running out, and the caches that give memory back
+//! when it is.
+//!
+//! The level is `Medium` once the free share of the heap or of physical memory, whichever is
+//! lower, drops below `memory.pressure_medium_percent`, and `Critical` below
+//! `memory.pressure_critical_percent`. A reclaim that fell short of its target within the last
+//! `SHORT_RECLAIM_WINDOW` raises `Medium` to `Critical` too: the caches have little left to give.
+//! `monitor` measures every `INTERVAL`, posts an `event::Event::MemoryPressure` whenever the level
+//! changes, and while there's pressure asks the shrinkers for memory.
+//!
+//! A subsystem that keeps memory it could do without, like a cache, `register`s a `Shrinker`.
+//! Shrinkers are asked in the order they registered until the target is met, by `monitor` and by
+//! the heap itself before it fails an allocation (see `allocator::quota`). That may be with any
+//! lock held, the shrinker's own included, so a shrinker only ever `try_lock`s, and skips what it
+//! can't get at.
+
+use super::FRAME_ALLOCATOR;
+use crate::event::{self, Event};
+use crate::sysctl::Tunable;
+use crate::{allocator, interrupts, println, timer, warnln};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+/// How often `monitor` measures
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a reclaim that fell short keeps the level up
+const SHORT_RECLAIM_WINDOW: Duration = Duration::from_secs(5);
+
+/// The least a reclaim asks for, as freeing just the bytes of a failed allocation seldom frees a
+/// hole it fits in
+const MIN_RECLAIM: usize = 64 * 1024;
+
+pub static MEDIUM_PERCENT: Tunable = Tunable::new(
+    "memory.pressure_medium_percent",
+    "free share of the heap or of memory below which caches are shrunk",
+    20,
+    1,
+    90,
+);
+
+pub static CRITICAL_PERCENT: Tunable = Tunable::new(
+    "memory.pressure_critical_percent",
+    "free share of the heap or of memory below which caches give back all they can",
+    5,
+    1,
+    90,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    None,
+    /// Caches should keep only what's in use
+    Medium,
+    /// The next allocations may fail; caches should give back everything they can
+    Critical,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Level {
+        match value {
+            0 => Level::None,
+            1 => Level::Medium,
+            _ => Level::Critical,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::None => "none",
+            Level::Medium => "medium",
+            Level::Critical => "critical",
+        })
+    }
+}
+
+/// Something holding memory it can give back
+pub trait Shrinker: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Bytes it could give back at `Critical`, roughly; 0 if it can't tell right now
+    fn count(&self) -> usize;
+
+    /// Gives back about `target` bytes, more readily the higher `level` is, and returns how many
+    /// it did. Mustn't wait for a lock or allocate, see the module's documentation.
+    fn shrink(&self, level: Level, target: usize) -> usize;
+}
+
+static SHRINKERS: Mutex<Vec<&'static dyn Shrinker>> = Mutex::new(Vec::new());
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::None as u8);
+/// Set while shrinkers run, so that an allocation failing in one doesn't start another reclaim
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+static RECLAIMS: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+/// Reclaims that freed less than they were asked to, and the tick of the last one
+static SHORT_RECLAIMS: AtomicU64 = AtomicU64::new(0);
+static LAST_SHORT_RECLAIM: AtomicU64 = AtomicU64::new(0);
+
+/// Asks `shrinker` for memory from now on
+pub fn register(shrinker: &'static dyn Shrinker) {
+    SHRINKERS.lock().push(shrinker);
+}
+
+/// The level `monitor` measured last
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Free and total bytes of the heap and of physical memory
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub heap_free: usize,
+    pub heap_size: usize,
+    pub frames_free: usize,
+    pub frames_total: usize,
+}
+
+impl Usage {
+    /// The free share of whichever of the two is shorter, in percent
+    pub fn free_percent(&self) -> u64 {
+        let percent = |free: usize, total: usize| match total {
+            0 => 100,
+            total => free as u64 * 100 / total as u64,
+        };
+        percent(self.heap_free, self.heap_size).min(percent(self.frames_free, self.frames_total))
+    }
+}
+
+/// How much is free right now; `None` if the heap or the frame allocator is locked
+pub fn usage() -> Option<Usage> {
+    let heap = allocator::try_stats()?;
+    let frames = FRAME_ALLOCATOR.try_lock()?.as_ref()?.stats();
+    Some(Usage {
+        heap_free: allocator::HEAP_SIZE.saturating_sub(heap.in_use() as usize),
+        heap_size: allocator::HEAP_SIZE,
+        frames_free: frames.total - frames.allocated,
+        frames_total: frames.total,
+    })
+}
+
+/// The level `usage` amounts to
+fn measure(usage: &Usage) -> Level {
+    let free = usage.free_percent();
+    let level = if free < CRITICAL_PERCENT.get() {
+        Level::Critical
+    } else if free < MEDIUM_PERCENT.get() {
+        Level::Medium
+    } else {
+        Level::None
+    };
+    let since_short = interrupts::ticks() - LAST_SHORT_RECLAIM.load(Ordering::Relaxed);
+    let short = SHORT_RECLAIMS.load(Ordering::Relaxed) > 0
+        && since_short < timer::duration_to_ticks(SHORT_RECLAIM_WINDOW);
+    match (level, short) {
+        (Level::Medium, true) => Level::Critical,
+        (level, _) => level,
+    }
+}
+
+/// Asks the shrinkers for `target` bytes, at least `MIN_RECLAIM`, at the current level but at
+/// least `Medium`; returns the bytes they gave back. Does nothing while another reclaim runs.
+pub fn reclaim(target: usize) -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let target = target.max(MIN_RECLAIM);
+    let level = level().max(Level::Medium);
+    let mut freed = 0;
+    if let Some(shrinkers) = SHRINKERS.try_lock() {
+        for shrinker in shrinkers.iter() {
+            if freed >= target {
+                break;
+            }
+            freed += shrinker.shrink(level, target - freed);
+        }
+    }
+    RECLAIMS.fetch_add(1, Ordering::Relaxed);
+    RECLAIMED.fetch_add(freed as u64, Ordering::Relaxed);
+    if freed < target {
+        SHORT_RECLAIMS.fetch_add(1, Ordering::Relaxed);
+        LAST_SHORT_RECLAIM.store(interrupts::ticks(), Ordering::Relaxed);
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+/// Measures, and reclaims while there's pressure, every `INTERVAL`; meant to be spawned as a task
+pub async fn monitor() {
+    loop {
+        if let Some(usage) = usage() {
+            let level = measure(&usage);
+            let old = Level::from_u8(LEVEL.swap(level as u8, Ordering::Relaxed));
+            if level != old {
+                event::post(Event::MemoryPressure(level));
+                let free = usage.free_percent();
+                match level > old {
+                    true => warnln!("memory: pressure {}, {}% free", level, free),
+                    false => println!("memory: pressure {}, {}% free", level, free),
+                }
+            }
+            if level > Level::None {
+                // enough to get the heap back above the medium mark
+                let wanted = usage.heap_size / 100 * MEDIUM_PERCENT.get() as usize;
+                reclaim(wanted.saturating_sub(usage.heap_free));
+            }
+        }
+        timer::sleep(INTERVAL).await;
+    }
+}
+
+/// What `mem` shows: the level, the reclaims so far and what each shrinker holds
+pub fn print() {
+    println!(
+        "pressure: {}; {} reclaims gave back {} bytes, {} fell short",
+        level(),
+        RECLAIMS.load(Ordering::Relaxed),
+        RECLAIMED.load(Ordering::Relaxed),
+        SHORT_RECLAIMS.load(Ordering::Relaxed)
+    );
+    // copied, as printing may allocate and a reclaim then skip them all
+    let shrinkers = SHRINKERS.lock().clone();
+    for shrinker in shrinkers {
+        println!(
+            "shrinker {}: {} bytes to give",
+            shrinker.name(),
+            shrinker.count()
+        );
+    }
+}
@@ -0,0 +1,44 @@
+//! Networking protocols.
+//!
+//! Protocols are written against `Stream`, a reliable byte stream, so they don't care what
+//! carries them: a TCP connection, a virtio-console port or a serial line.
+
+use core::fmt;
+
+pub mod console;
+pub mod dhcp;
+pub mod nic;
+pub mod stack;
+pub mod tls;
+
+/// Why a `Stream` operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamError {
+    /// The peer or the network aborted the connection
+    Reset,
+    /// Nothing arrived in time
+    TimedOut,
+    /// The stream was already shut down on this side
+    Closed,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            StreamError::Reset => "connection reset",
+            StreamError::TimedOut => "timed out",
+            StreamError::Closed => "stream is closed",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A reliable, ordered, bidirectional byte stream
+pub trait Stream {
+    /// Sends all of `data`
+    fn send(&mut self, data: &[u8]) -> Result<(), StreamError>;
+
+    /// Waits for data and receives at least one byte of it into `buf`; returns 0 once the peer
+    /// has closed its side
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, StreamError>;
+}
@@ -0,0 +1,38 @@
+//! The kernel console over a `Stream`, e.g. a connection a TCP listener accepted: attached with
+//! `console::attach`, it gets a copy of everything printed, as a terminal expects it.
+//!
+//! It's output only for now: `Stream::recv` blocks, so reading what the peer types takes a task
+//! that can wait for the stream without holding up the others.
+
+use super::Stream;
+use crate::console::{self, Sink, Terminal};
+use alloc::boxed::Box;
+
+pub struct StreamConsole {
+    stream: Box<dyn Stream + Send>,
+    /// Cleared when sending fails; the console drops the backend then
+    open: bool,
+}
+
+impl StreamConsole {
+    pub fn new(stream: Box<dyn Stream + Send>) -> StreamConsole {
+        StreamConsole { stream, open: true }
+    }
+}
+
+impl Sink for StreamConsole {
+    fn send(&mut self, data: &[u8]) {
+        if self.open && self.stream.send(data).is_err() {
+            self.open = false;
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+}
+
+/// Mirrors the console to `stream` until the connection fails
+pub fn serve(stream: Box<dyn Stream + Send>) {
+    console::attach(Box::new(Terminal(StreamConsole::new(stream))));
+}
@@ -0,0 +1,509 @@
+//! DHCP client (RFC 2131): interfaces get their address, subnet and gateway from a server.
+//!
+//! `client` manages every interface that has no address when it starts. It broadcasts a
+//! DISCOVER, asks for the first address offered with a REQUEST, and configures the interface
+//! (see `stack::configure`) once the server acknowledges. Halfway through the lease it asks the
+//! server that granted it to extend it, from 7/8 of the lease any server, and it starts over
+//! should the lease run out all the same. An interface configured by hand in the meantime is left
+//! alone from then on.
+//!
+//! Replies are matched to interfaces by transaction id and hardware address; they all arrive on
+//! the one socket bound to the client port. Until an interface has an address, servers are asked
+//! to broadcast their replies, as the stack drops unicast packets to addresses it doesn't have.
+
+use super::nic::{self, Interface, MacAddress};
+use super::stack::udp::{self, UdpSocket};
+use super::stack::{self, protocol, Config, Ipv4Addr, Ipv4Header, SocketAddr};
+use crate::endian::{read_u32_be, write_u16_be, write_u32_be};
+use crate::interrupts::{self, TIMER_HZ};
+use crate::timer::{self, Timeout};
+use crate::{println, rand, warnln};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+/// The fixed part of a message, up to the options
+const HEADER_SIZE: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Asks the server to broadcast its reply
+const FLAG_BROADCAST: u16 = 0x8000;
+
+/// Values of the message type option
+mod message_type {
+    pub const DISCOVER: u8 = 1;
+    pub const OFFER: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const ACK: u8 = 5;
+    pub const NAK: u8 = 6;
+}
+
+/// Option codes (RFC 2132)
+mod option {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const REQUESTED_ADDRESS: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_LIST: u8 = 55;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const END: u8 = 255;
+}
+
+/// Options the client asks servers for
+const PARAMETERS: &[u8] = &[option::SUBNET_MASK, option::ROUTER];
+
+/// The first wait for a reply; it doubles with every retransmission, up to `MAX_BACKOFF`
+const INITIAL_BACKOFF: Duration = Duration::from_secs(4);
+const MAX_BACKOFF: Duration = Duration::from_secs(64);
+/// REQUESTs sent for an offer before starting over with a DISCOVER
+const REQUEST_ATTEMPTS: u32 = 4;
+/// The shortest wait between REQUESTs while renewing or rebinding
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// The lease time that means forever
+const INFINITE_LEASE: u32 = u32::MAX;
+
+/// The parts of a server's reply the client uses
+struct Reply {
+    xid: u32,
+    chaddr: MacAddress,
+    /// The address offered or granted
+    yiaddr: Ipv4Addr,
+    message_type: u8,
+    server: Option<Ipv4Addr>,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    /// In seconds, like the two times after it
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl Reply {
+    fn parse(data: &[u8]) -> Option<Reply> {
+        if data.len() < HEADER_SIZE + MAGIC_COOKIE.len()
+            || data[0] != BOOTREPLY
+            || data[HEADER_SIZE..HEADER_SIZE + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut reply = Reply {
+            xid: read_u32_be(data, 4),
+            chaddr: MacAddress(data[28..34].try_into().unwrap()),
+            yiaddr: Ipv4Addr(data[16..20].try_into().unwrap()),
+            message_type: 0,
+            server: None,
+            subnet_mask: None,
+            router: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+        };
+        let mut options = &data[HEADER_SIZE + 4..];
+        while let [code, rest @ ..] = options {
+            match *code {
+                option::PAD => {
+                    options = rest;
+                    continue;
+                }
+                option::END => break,
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first()?;
+            let value = rest.get(..len as usize)?;
+            options = &rest[len as usize..];
+            let address = value
+                .get(..4)
+                .map(|bytes| Ipv4Addr(bytes.try_into().unwrap()));
+            let seconds = value.get(..4).map(|bytes| read_u32_be(bytes, 0));
+            match *code {
+                option::MESSAGE_TYPE => reply.message_type = *value.first()?,
+                option::SERVER_ID => reply.server = address,
+                option::SUBNET_MASK => reply.subnet_mask = address,
+                option::ROUTER => reply.router = address,
+                option::LEASE_TIME => reply.lease_time = seconds,
+                option::RENEWAL_TIME => reply.renewal_time = seconds,
+                option::REBINDING_TIME => reply.rebinding_time = seconds,
+                _ => {}
+            }
+        }
+        Some(reply)
+    }
+}
+
+/// A message from the client with `options` after the message type
+fn message(
+    xid: u32,
+    mac: MacAddress,
+    message_type: u8,
+    ciaddr: Ipv4Addr,
+    options: &[(u8, &[u8])],
+) -> Vec<u8> {
+    let mut message = vec![0; HEADER_SIZE];
+    message[0] = BOOTREQUEST;
+    message[1] = HARDWARE_ETHERNET;
+    message[2] = 6;
+    write_u32_be(&mut message, 4, xid);
+    if ciaddr == Ipv4Addr::UNSPECIFIED {
+        write_u16_be(&mut message, 10, FLAG_BROADCAST);
+    }
+    message[12..16].copy_from_slice(&ciaddr.0);
+    message[28..34].copy_from_slice(&mac.0);
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[option::MESSAGE_TYPE, 1, message_type]);
+    for (code, value) in options {
+        message.extend_from_slice(&[*code, value.len() as u8]);
+        message.extend_from_slice(value);
+    }
+    message.push(option::END);
+    message
+}
+
+/// An address granted by a server, with the timer ticks at which to act on it
+struct Lease {
+    config: Config,
+    server: Ipv4Addr,
+    renew_at: u64,
+    rebind_at: u64,
+    expires_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// A DISCOVER is out, waiting for offers
+    Selecting,
+    /// Asking for the address `offer` from `server`
+    Requesting {
+        offer: Ipv4Addr,
+        server: Ipv4Addr,
+        attempts: u32,
+    },
+    Bound,
+    /// Asking the server that granted the lease to extend it
+    Renewing,
+    /// Asking any server to extend the lease
+    Rebinding,
+    /// Configured by hand, not the client's business any more
+    Unmanaged,
+}
+
+/// The client's state for one interface
+struct Client {
+    interface: Arc<Interface>,
+    state: State,
+    xid: u32,
+    lease: Option<Lease>,
+    /// The tick at which `timeout` is due
+    deadline: u64,
+    backoff: Duration,
+}
+
+impl Client {
+    fn new(interface: Arc<Interface>) -> Client {
+        Client {
+            interface,
+            state: State::Selecting,
+            xid: 0,
+            lease: None,
+            deadline: 0,
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Waits `delay` for the next `timeout`
+    fn wait(&mut self, delay: Duration) {
+        self.deadline = interrupts::ticks() + timer::duration_to_ticks(delay);
+    }
+
+    /// Waits for a reply, a little longer each time
+    fn back_off(&mut self) {
+        self.wait(self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// Waits half the time left until `end`, but not less than `MIN_RENEW_INTERVAL` or past `end`
+    fn wait_half_until(&mut self, end: u64) {
+        let now = interrupts::ticks();
+        let half = end.saturating_sub(now) / 2;
+        let least = timer::duration_to_ticks(MIN_RENEW_INTERVAL);
+        self.deadline = now.saturating_add(half.max(least)).min(end);
+    }
+
+    /// Broadcasts `message` on the interface, from `source`
+    fn broadcast(&self, source: Ipv4Addr, message: &[u8]) {
+        let destination = SocketAddr {
+            address: Ipv4Addr::BROADCAST,
+            port: SERVER_PORT,
+        };
+        let header = Ipv4Header {
+            source,
+            destination: Ipv4Addr::BROADCAST,
+            protocol: protocol::UDP,
+        };
+        let datagram = udp::build(source, CLIENT_PORT, destination, message);
+        if let Err(err) = stack::send_on(&self.interface, MacAddress::BROADCAST, &header, &datagram)
+        {
+            warnln!("dhcp: {}: {}", self.interface.name, err);
+        }
+    }
+
+    /// Starts over, without an address
+    fn discover(&mut self) {
+        if self.lease.take().is_some() {
+            warnln!("dhcp: {}: lost the lease", self.interface.name);
+            stack::unconfigure(&self.interface);
+        }
+        self.xid = rand::next_u32();
+        self.state = State::Selecting;
+        let discover = message(
+            self.xid,
+            self.interface.mac(),
+            message_type::DISCOVER,
+            Ipv4Addr::UNSPECIFIED,
+            &[(option::PARAMETER_LIST, PARAMETERS)],
+        );
+        self.broadcast(Ipv4Addr::UNSPECIFIED, &discover);
+        self.back_off();
+    }
+
+    /// Asks for the lease to be extended: by its server, or by any if `rebind`
+    async fn extend(&mut self, socket: &UdpSocket, rebind: bool) {
+        let Some(lease) = &self.lease else {
+            return;
+        };
+        let (address, server) = (lease.config.address, lease.server);
+        let request = message(
+            self.xid,
+            self.interface.mac(),
+            message_type::REQUEST,
+            address,
+            &[(option::PARAMETER_LIST, PARAMETERS)],
+        );
+        if rebind {
+            self.broadcast(address, &request);
+            return;
+        }
+        let server = SocketAddr {
+            address: server,
+            port: SERVER_PORT,
+        };
+        if let Err(err) = socket.send_to(&request, server).await {
+            warnln!("dhcp: {}: {}", self.interface.name, err);
+        }
+    }
+
+    /// Does what's due at the deadline: sends the next message, or gives up on a lease
+    async fn timeout(&mut self, socket: &UdpSocket) {
+        let now = interrupts::ticks();
+        let managed = match &self.lease {
+            Some(lease) => stack::config(&self.interface) == Some(lease.config),
+            None => stack::config(&self.interface).is_none(),
+        };
+        if !managed {
+            self.state = State::Unmanaged;
+        }
+        match self.state {
+            State::Selecting => self.discover(),
+            State::Requesting {
+                offer,
+                server,
+                attempts,
+            } if attempts < REQUEST_ATTEMPTS => {
+                let request = message(
+                    self.xid,
+                    self.interface.mac(),
+                    message_type::REQUEST,
+                    Ipv4Addr::UNSPECIFIED,
+                    &[
+                        (option::REQUESTED_ADDRESS, &offer.0),
+                        (option::SERVER_ID, &server.0),
+                        (option::PARAMETER_LIST, PARAMETERS),
+                    ],
+                );
+                self.broadcast(Ipv4Addr::UNSPECIFIED, &request);
+                self.state = State::Requesting {
+                    offer,
+                    server,
+                    attempts: attempts + 1,
+                };
+                self.back_off();
+            }
+            State::Requesting { .. } => self.discover(),
+            State::Bound | State::Renewing | State::Rebinding => {
+                let Some(lease) = &self.lease else {
+                    return self.discover();
+                };
+                let (rebind_at, expires_at) = (lease.rebind_at, lease.expires_at);
+                if now >= expires_at {
+                    return self.discover();
+                }
+                if self.state == State::Bound {
+                    self.xid = rand::next_u32();
+                }
+                let rebind = now >= rebind_at;
+                self.state = match rebind {
+                    true => State::Rebinding,
+                    false => State::Renewing,
+                };
+                self.extend(socket, rebind).await;
+                self.wait_half_until(if rebind { expires_at } else { rebind_at });
+            }
+            State::Unmanaged => self.deadline = u64::MAX,
+        }
+    }
+
+    /// Takes in a reply to one of the client's messages
+    fn handle(&mut self, reply: &Reply) {
+        match (self.state, reply.message_type) {
+            (State::Selecting, message_type::OFFER) => {
+                let Some(server) = reply.server else {
+                    return;
+                };
+                self.state = State::Requesting {
+                    offer: reply.yiaddr,
+                    server,
+                    attempts: 0,
+                };
+                self.backoff = INITIAL_BACKOFF;
+                self.deadline = 0; // request it right away
+            }
+            (State::Requesting { .. } | State::Renewing | State::Rebinding, message_type::ACK) => {
+                self.bind(reply)
+            }
+            (State::Requesting { .. } | State::Renewing | State::Rebinding, message_type::NAK) => {
+                println!(
+                    "dhcp: {}: the server refused the address",
+                    self.interface.name
+                );
+                self.backoff = INITIAL_BACKOFF;
+                self.discover();
+            }
+            _ => {}
+        }
+    }
+
+    /// Configures the interface with the address `ack` grants
+    fn bind(&mut self, ack: &Reply) {
+        let address = ack.yiaddr;
+        let Some(server) = ack.server.or(self.lease.as_ref().map(|lease| lease.server)) else {
+            return;
+        };
+        let prefix = match ack.subnet_mask {
+            Some(mask) => mask.to_u32().leading_ones() as u8,
+            // the address's class, as RFC 2131 suggests without a mask
+            None => match address.0[0] {
+                0..=127 => 8,
+                128..=191 => 16,
+                _ => 24,
+            },
+        };
+        let mut config = Config {
+            address,
+            prefix,
+            gateway: None,
+        };
+        config.gateway = ack.router.filter(|&router| config.is_local(router));
+
+        let now = interrupts::ticks();
+        let lease_time = ack.lease_time.unwrap_or(INFINITE_LEASE);
+        let at = |seconds: Option<u32>, default: u64| match lease_time {
+            INFINITE_LEASE => u64::MAX,
+            _ => {
+                let seconds = seconds.map_or(default, u64::from);
+                now + timer::duration_to_ticks(Duration::from_secs(seconds))
+            }
+        };
+        let lease = Lease {
+            config,
+            server,
+            renew_at: at(ack.renewal_time, lease_time as u64 / 2),
+            rebind_at: at(ack.rebinding_time, lease_time as u64 * 7 / 8),
+            expires_at: at(Some(lease_time), 0),
+        };
+        let renewed = self
+            .lease
+            .as_ref()
+            .is_some_and(|previous| previous.config == config);
+        if !renewed {
+            if let Err(err) = stack::configure(&self.interface, config) {
+                warnln!("dhcp: {}: {}", self.interface.name, err);
+                self.backoff = INITIAL_BACKOFF;
+                return self.discover();
+            }
+            print_lease(&self.interface, &config, lease_time);
+        }
+        self.deadline = lease.renew_at;
+        self.lease = Some(lease);
+        self.state = State::Bound;
+        self.backoff = INITIAL_BACKOFF;
+    }
+}
+
+fn print_lease(interface: &Interface, config: &Config, lease_time: u32) {
+    let gateway = config.gateway.unwrap_or(Ipv4Addr::UNSPECIFIED);
+    match lease_time {
+        INFINITE_LEASE => println!(
+            "dhcp: {}: {}/{} via {}, for good",
+            interface.name, config.address, config.prefix, gateway
+        ),
+        seconds => println!(
+            "dhcp: {}: {}/{} via {}, for {} s",
+            interface.name, config.address, config.prefix, gateway, seconds
+        ),
+    }
+}
+
+/// `ticks` as a duration, up to some months so sleeping that long can't overflow
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_millis(ticks.min(u32::MAX as u64) * 1000 / TIMER_HZ as u64)
+}
+
+/// Configures the interfaces without an address and keeps their leases, forever; meant to be
+/// spawned as a task after `stack::worker`. Returns at once if every interface has an address.
+pub async fn client() {
+    let mut clients: Vec<Client> = nic::interfaces()
+        .into_iter()
+        .filter(|interface| stack::config(interface).is_none())
+        .map(Client::new)
+        .collect();
+    if clients.is_empty() {
+        return;
+    }
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(err) => return warnln!("dhcp: {}", err),
+    };
+    loop {
+        for client in &mut clients {
+            if client.deadline <= interrupts::ticks() {
+                client.timeout(&socket).await;
+            }
+        }
+        let next = clients.iter().map(|client| client.deadline).min().unwrap();
+        let wait = ticks_to_duration(next.saturating_sub(interrupts::ticks()));
+        let Ok((data, source)) = Timeout::wrap(socket.recv_from(), wait).await else {
+            continue;
+        };
+        if source.port != SERVER_PORT {
+            continue;
+        }
+        let Some(reply) = Reply::parse(&data) else {
+            continue;
+        };
+        if let Some(client) = clients
+            .iter_mut()
+            .find(|client| client.xid == reply.xid && client.interface.mac() == reply.chaddr)
+        {
+            client.handle(&reply);
+        }
+    }
+}
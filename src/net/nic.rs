@@ -0,0 +1,251 @@
+//! Network interfaces: whatever sends and receives raw Ethernet frames, as seen by the protocols
+//! above.
+//!
+//! A driver implements `Nic` and hands its device to `register`, which wraps it in an
+//! `Interface`. Frames are sent straight through the driver; received ones are collected by the
+//! `receiver` task every `POLL_INTERVAL`, like the virtio drivers' completions, and wait in each
+//! interface's channel for `Interface::recv`. Frames are without the frame check sequence, which
+//! the devices add and strip.
+//!
+//! Received frames are copied into buffers from a slab cache, `FRAMES`, rather than the heap, as
+//! they come and go all the time and are all the same size.
+
+use crate::allocator::slab::{SlabBox, SlabCache};
+use crate::sync::mpsc::Channel;
+use crate::sysctl::Tunable;
+use crate::{println, timer};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use spin::Mutex;
+
+/// Largest frame without the frame check sequence: 14 bytes of header and 1500 of payload
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// Received frames an interface can hold for its reader
+const RECEIVE_BACKLOG: usize = 64;
+
+/// How many of the `RECEIVE_BACKLOG` frames an interface holds before it drops new ones
+pub static RECEIVE_LIMIT: Tunable = Tunable::new(
+    "net.rx_backlog",
+    "received frames an interface holds for its reader before it drops new ones",
+    RECEIVE_BACKLOG as u64,
+    1,
+    RECEIVE_BACKLOG as u64,
+);
+
+/// How often `receiver` collects what the devices received
+pub const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Room for a received frame
+struct FrameBuffer {
+    len: usize,
+    bytes: [u8; MAX_FRAME_SIZE],
+}
+
+static FRAMES: SlabCache<FrameBuffer> = SlabCache::new("net-frames");
+
+/// A received frame, header included
+pub struct Frame(SlabBox<FrameBuffer>);
+
+impl Frame {
+    /// A copy of `bytes`, at most `MAX_FRAME_SIZE` of them; `None` if there's no memory for it
+    fn new(bytes: &[u8]) -> Option<Frame> {
+        let mut buffer = FRAMES
+            .alloc(FrameBuffer {
+                len: 0,
+                bytes: [0; MAX_FRAME_SIZE],
+            })
+            .ok()?;
+        let len = bytes.len().min(MAX_FRAME_SIZE);
+        buffer.bytes[..len].copy_from_slice(&bytes[..len]);
+        buffer.len = len;
+        Some(Frame(buffer))
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0.bytes[..self.0.len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// A network device's driver
+pub trait Nic: Send + Sync {
+    /// The driver's name, e.g. "virtio-net"
+    fn name(&self) -> &'static str;
+
+    fn mac(&self) -> MacAddress;
+
+    /// Whether a cable is plugged in, as far as the device knows
+    fn link_up(&self) -> bool;
+
+    /// Queues `frame` for sending; fails if the device has no room for it right now
+    fn transmit(&self, frame: &[u8]) -> Result<(), &'static str>;
+
+    /// Passes every frame received since the last call to `deliver`
+    fn receive(&self, deliver: &mut dyn FnMut(&[u8]));
+}
+
+/// A registered device with its received frames
+pub struct Interface {
+    /// "eth0", "eth1", ... in the order the devices were found
+    pub name: &'static str,
+    nic: Arc<dyn Nic>,
+    received: Channel<Frame, RECEIVE_BACKLOG>,
+    sent: AtomicU64,
+    delivered: AtomicU64,
+    /// Frames dropped because nobody read them in time
+    dropped: AtomicU64,
+}
+
+impl Interface {
+    pub fn nic(&self) -> &dyn Nic {
+        &*self.nic
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.nic.mac()
+    }
+
+    /// Sends one frame, header included; at most `MAX_FRAME_SIZE` bytes
+    pub fn send(&self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("frame too large");
+        }
+        self.nic.transmit(frame)?;
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Waits for the next frame received
+    pub async fn recv(&self) -> Frame {
+        self.received.recv().await
+    }
+
+    /// The next frame received, if one is waiting
+    pub fn try_recv(&self) -> Option<Frame> {
+        self.received.try_recv()
+    }
+
+    /// `recv` for futures waiting on several interfaces at once
+    pub fn poll_recv(&self, context: &mut Context) -> Poll<Frame> {
+        Pin::new(&mut self.received.recv()).poll(context)
+    }
+
+    /// Moves what the device received to the channel
+    fn poll(&self) {
+        let limit = RECEIVE_LIMIT.get() as usize;
+        self.nic.receive(&mut |frame| {
+            let queued = self.received.len() < limit
+                && Frame::new(frame).is_some_and(|frame| self.received.send(frame).is_ok());
+            if queued {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+const NAMES: [&str; 4] = ["eth0", "eth1", "eth2", "eth3"];
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Adds a device as the next `ethN`; fails once every name is taken
+pub fn register(nic: Arc<dyn Nic>) -> Result<Arc<Interface>, &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let name = NAMES
+        .get(interfaces.len())
+        .ok_or("too many network interfaces")?;
+    let interface = Arc::new(Interface {
+        name,
+        nic,
+        received: Channel::new(),
+        sent: AtomicU64::new(0),
+        delivered: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    interfaces.push(interface.clone());
+    Ok(interface)
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// The interface called `name`
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.name == name)
+        .cloned()
+}
+
+/// Collects received frames from every interface every `POLL_INTERVAL`; meant to be spawned as
+/// a task
+pub async fn receiver() {
+    loop {
+        for interface in interfaces() {
+            interface.poll();
+        }
+        timer::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// `ifconfig`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err("usage: ifconfig");
+    }
+    let interfaces = interfaces();
+    if interfaces.is_empty() {
+        println!("no network interfaces");
+    }
+    for interface in interfaces {
+        println!(
+            "{}: {} {}, link {}",
+            interface.name,
+            interface.nic.name(),
+            interface.mac(),
+            if interface.nic.link_up() {
+                "up"
+            } else {
+                "down"
+            }
+        );
+        println!(
+            "  {} frames sent, {} received, {} dropped",
+            interface.sent.load(Ordering::Relaxed),
+            interface.delivered.load(Ordering::Relaxed),
+            interface.dropped.load(Ordering::Relaxed)
+        );
+    }
+    Ok(())
+}
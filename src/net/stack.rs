@@ -0,0 +1,459 @@
+//! A small IPv4 stack over the interfaces of `nic`: ARP, IPv4 without fragments, ICMP echo and
+//! UDP sockets.
+//!
+//! Interfaces get an address with `configure`, usually from `dhcp`. `worker` reads the frames
+//! every interface receives and hands them to the protocols: ARP requests for an interface's
+//! address are answered, pings too, and UDP datagrams go to the socket bound to their port (see
+//! `udp::UdpSocket`). Packets are sent on the interface whose subnet holds the destination, or
+//! otherwise on the first one with a gateway, after resolving the next hop with ARP; replies made
+//! by `worker` itself go straight back to the sender's hardware address instead, so it never
+//! waits.
+//!
+//! Fragmented packets are dropped, and packets too large to go out in one frame aren't sent.
+
+use super::nic::{self, Frame, Interface, MacAddress};
+use crate::endian::{read_u16_be, write_u16_be};
+use crate::memory::pressure;
+use crate::{print, println};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use spin::Mutex;
+
+pub mod arp;
+pub mod icmp;
+pub mod udp;
+
+/// EtherTypes
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Shorter frames are padded with zeros, as Ethernet requires
+const MIN_FRAME_SIZE: usize = 60;
+
+/// IPv4 header without options, as sent
+const IPV4_HEADER_SIZE: usize = 20;
+/// Largest payload that goes out in one frame
+pub const MAX_PAYLOAD: usize = nic::MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - IPV4_HEADER_SIZE;
+const DEFAULT_TTL: u8 = 64;
+
+/// IP protocol numbers
+pub mod protocol {
+    pub const ICMP: u8 = 1;
+    pub const UDP: u8 = 17;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(value: u32) -> Ipv4Addr {
+        Ipv4Addr(value.to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = &'static str;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0; 4];
+        let mut parts = text.split('.');
+        for octet in &mut octets {
+            *octet = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or("not an IPv4 address")?;
+        }
+        match parts.next() {
+            None => Ok(Ipv4Addr(octets)),
+            Some(_) => Err("not an IPv4 address"),
+        }
+    }
+}
+
+/// An address and port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocketAddr {
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+/// An interface's address and how to leave its subnet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub address: Ipv4Addr,
+    /// Bits of the subnet prefix, 0 to 32
+    pub prefix: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Config {
+    fn netmask(&self) -> u32 {
+        match self.prefix {
+            0 => 0,
+            prefix => u32::MAX << (32 - prefix.min(32)),
+        }
+    }
+
+    /// Whether `address` is in the interface's subnet
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        (address.to_u32() ^ self.address.to_u32()) & self.netmask() == 0
+    }
+
+    /// The subnet's broadcast address
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.address.to_u32() | !self.netmask())
+    }
+}
+
+/// An interface with its configuration
+#[derive(Clone)]
+struct Binding {
+    interface: Arc<Interface>,
+    config: Config,
+}
+
+static BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+
+/// Gives `interface` an address, replacing the one it had
+pub fn configure(interface: &Arc<Interface>, config: Config) -> Result<(), &'static str> {
+    if config.prefix > 32 {
+        return Err("the prefix is longer than 32 bits");
+    }
+    if config
+        .gateway
+        .is_some_and(|gateway| !config.is_local(gateway))
+    {
+        return Err("the gateway isn't in the interface's subnet");
+    }
+    let mut bindings = BINDINGS.lock();
+    bindings.retain(|binding| !Arc::ptr_eq(&binding.interface, interface));
+    bindings.push(Binding {
+        interface: interface.clone(),
+        config,
+    });
+    Ok(())
+}
+
+/// Takes the address away from `interface`
+pub fn unconfigure(interface: &Arc<Interface>) {
+    BINDINGS
+        .lock()
+        .retain(|binding| !Arc::ptr_eq(&binding.interface, interface));
+}
+
+/// The configuration of `interface`, if it has an address
+pub fn config(interface: &Interface) -> Option<Config> {
+    BINDINGS
+        .lock()
+        .iter()
+        .find(|binding| core::ptr::eq(&*binding.interface, interface))
+        .map(|binding| binding.config)
+}
+
+/// Where a packet to `destination` goes out, and the host on that link to hand it to
+fn route(destination: Ipv4Addr) -> Option<(Binding, Ipv4Addr)> {
+    let bindings = BINDINGS.lock();
+    if let Some(binding) = bindings
+        .iter()
+        .find(|binding| destination == Ipv4Addr::BROADCAST || binding.config.is_local(destination))
+    {
+        return Some((binding.clone(), destination));
+    }
+    bindings.iter().find_map(|binding| {
+        let gateway = binding.config.gateway?;
+        Some((binding.clone(), gateway))
+    })
+}
+
+/// The internet checksum (RFC 1071) over `parts` one after the other; every part but the last
+/// must have an even length
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let high = pair[0];
+            let low = pair.get(1).copied().unwrap_or(0);
+            sum += u16::from_be_bytes([high, low]) as u64;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// An Ethernet frame around `payload`
+fn ethernet_frame(
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let len = (ETHERNET_HEADER_SIZE + payload.len()).max(MIN_FRAME_SIZE);
+    let mut frame = vec![0; len];
+    frame[0..6].copy_from_slice(&destination.0);
+    frame[6..12].copy_from_slice(&source.0);
+    write_u16_be(&mut frame, 12, ethertype);
+    frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    frame
+}
+
+/// The fields of a received IPv4 header the protocols need
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+}
+
+/// An IPv4 packet around `payload`
+fn ipv4_packet(header: &Ipv4Header, payload: &[u8]) -> Vec<u8> {
+    static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+    let mut packet = vec![0; IPV4_HEADER_SIZE + payload.len()];
+    packet[0] = 0x45; // version 4, 5 words of header
+    write_u16_be(&mut packet, 2, (IPV4_HEADER_SIZE + payload.len()) as u16);
+    let identification = IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    write_u16_be(&mut packet, 4, identification);
+    packet[8] = DEFAULT_TTL;
+    packet[9] = header.protocol;
+    packet[12..16].copy_from_slice(&header.source.0);
+    packet[16..20].copy_from_slice(&header.destination.0);
+    let sum = checksum(&[&packet[..IPV4_HEADER_SIZE]]);
+    write_u16_be(&mut packet, 10, sum);
+    packet[IPV4_HEADER_SIZE..].copy_from_slice(payload);
+    packet
+}
+
+/// Sends `payload` to `destination` with `protocol`, resolving the next hop first
+pub async fn send(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err("packet too large");
+    }
+    let (binding, next_hop) = route(destination).ok_or("no route to the host")?;
+    let config = binding.config;
+    let mac = if destination == Ipv4Addr::BROADCAST || destination == config.broadcast() {
+        MacAddress::BROADCAST
+    } else {
+        arp::resolve(&binding.interface, &config, next_hop).await?
+    };
+    let header = Ipv4Header {
+        source: config.address,
+        destination,
+        protocol,
+    };
+    send_on(&binding.interface, mac, &header, payload)
+}
+
+/// Sends an IPv4 packet on `interface` to the host with hardware address `mac`
+pub fn send_on(
+    interface: &Interface,
+    mac: MacAddress,
+    header: &Ipv4Header,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err("packet too large");
+    }
+    let packet = ipv4_packet(header, payload);
+    interface.send(&ethernet_frame(
+        mac,
+        interface.mac(),
+        ETHERTYPE_IPV4,
+        &packet,
+    ))
+}
+
+/// A received packet, for protocols answering it
+pub struct Received<'a> {
+    pub interface: &'a Interface,
+    /// The hardware address it came from: the sender's, or the router's that passed it on
+    pub source_mac: MacAddress,
+    pub header: Ipv4Header,
+}
+
+impl Received<'_> {
+    /// Sends `payload` back where the packet came from, from the address it was sent to
+    fn reply(&self, protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+        let header = Ipv4Header {
+            source: self.header.destination,
+            destination: self.header.source,
+            protocol,
+        };
+        send_on(self.interface, self.source_mac, &header, payload)
+    }
+}
+
+/// Hands one received frame to its protocol
+fn handle_frame(interface: &Interface, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let source_mac = MacAddress(frame[6..12].try_into().unwrap());
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match read_u16_be(frame, 12) {
+        ETHERTYPE_ARP => arp::handle(interface, payload),
+        ETHERTYPE_IPV4 => handle_ipv4(interface, source_mac, payload),
+        _ => {}
+    }
+}
+
+fn handle_ipv4(interface: &Interface, source_mac: MacAddress, packet: &[u8]) {
+    if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = read_u16_be(packet, 2) as usize;
+    if header_len < IPV4_HEADER_SIZE
+        || total_len < header_len
+        || total_len > packet.len()
+        || checksum(&[&packet[..header_len]]) != 0
+    {
+        return;
+    }
+    let fragment = read_u16_be(packet, 6);
+    if fragment & 0x3fff != 0 {
+        return; // more fragments follow, or this isn't the first
+    }
+    let header = Ipv4Header {
+        source: Ipv4Addr(packet[12..16].try_into().unwrap()),
+        destination: Ipv4Addr(packet[16..20].try_into().unwrap()),
+        protocol: packet[9],
+    };
+    let config = config(interface);
+    let for_us = header.destination == Ipv4Addr::BROADCAST
+        || config.is_some_and(|config| {
+            header.destination == config.address || header.destination == config.broadcast()
+        });
+    if !for_us {
+        return;
+    }
+    let received = Received {
+        interface,
+        source_mac,
+        header,
+    };
+    let payload = &packet[header_len..total_len];
+    match header.protocol {
+        protocol::ICMP => icmp::handle(&received, payload),
+        protocol::UDP => udp::handle(&received, payload),
+        _ => {}
+    }
+}
+
+/// Resolves to the next frame any of `interfaces` received
+struct NextFrame<'a> {
+    interfaces: &'a [Arc<Interface>],
+}
+
+impl<'a> Future for NextFrame<'a> {
+    type Output = (&'a Arc<Interface>, Frame);
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let interfaces = self.interfaces;
+        for interface in interfaces {
+            if let Poll::Ready(frame) = interface.poll_recv(context) {
+                return Poll::Ready((interface, frame));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Handles what the interfaces receive, forever; meant to be spawned as a task. Returns at once
+/// if there are no interfaces.
+pub async fn worker() {
+    let interfaces = nic::interfaces();
+    if interfaces.is_empty() {
+        return;
+    }
+    pressure::register(&arp::SHRINKER);
+    pressure::register(&udp::SHRINKER);
+    loop {
+        let (interface, frame) = NextFrame {
+            interfaces: &interfaces,
+        }
+        .await;
+        handle_frame(interface, &frame);
+    }
+}
+
+/// Parses `<address>/<prefix>`
+fn parse_cidr(text: &str) -> Result<(Ipv4Addr, u8), &'static str> {
+    let (address, prefix) = text.split_once('/').ok_or("expected <address>/<prefix>")?;
+    let prefix = prefix
+        .parse()
+        .ok()
+        .filter(|&prefix| prefix <= 32)
+        .ok_or("the prefix must be 0 to 32")?;
+    Ok((address.parse()?, prefix))
+}
+
+/// `ip [addr <interface> <address>/<prefix> [<gateway>] | del <interface> | arp]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for binding in BINDINGS.lock().iter() {
+                let config = binding.config;
+                print!(
+                    "{}: {}/{}",
+                    binding.interface.name, config.address, config.prefix
+                );
+                match config.gateway {
+                    Some(gateway) => println!(" via {}", gateway),
+                    None => println!(),
+                }
+            }
+        }
+        ["addr", name, cidr, gateway @ ..] if gateway.len() <= 1 => {
+            let interface = nic::interface(name).ok_or("no such interface")?;
+            let (address, prefix) = parse_cidr(cidr)?;
+            let gateway = match gateway {
+                [gateway] => Some(gateway.parse()?),
+                _ => None,
+            };
+            configure(
+                &interface,
+                Config {
+                    address,
+                    prefix,
+                    gateway,
+                },
+            )?;
+        }
+        ["del", name] => unconfigure(&nic::interface(name).ok_or("no such interface")?),
+        ["arp"] => arp::list(),
+        _ => return Err(
+            "usage: ip [addr <interface> <address>/<prefix> [<gateway>] | del <interface> | arp]",
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! ARP: finding the hardware address of a host on the link from its IPv4 address (RFC 826).
+//!
+//! Answers are kept in a cache for `LIFETIME`. Requests for one of our addresses are answered,
+//! and, as RFC 826 suggests, also put their sender in the cache, since it's about to talk to us.
+//! Under memory pressure the cache drops what has expired, and at `Critical` everything, to be
+//! asked for again (see `SHRINKER`).
+
+use super::{ethernet_frame, Config, Ipv4Addr, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::endian::{read_u16_be, write_u16_be};
+use crate::interrupts;
+use crate::memory::pressure::{Level, Shrinker};
+use crate::net::nic::{Interface, MacAddress};
+use crate::{println, timer};
+use alloc::collections::BTreeMap;
+use core::mem;
+use core::time::Duration;
+use spin::Mutex;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+/// An Ethernet/IPv4 ARP packet
+const PACKET_SIZE: usize = 28;
+
+/// How long an answer is trusted
+const LIFETIME: Duration = Duration::from_secs(300);
+/// Requests sent before a host is given up on, and the wait for an answer to each
+const RETRIES: usize = 3;
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+struct Entry {
+    mac: MacAddress,
+    /// The timer tick after which it's asked for again
+    expires: u64,
+}
+
+static CACHE: Mutex<BTreeMap<Ipv4Addr, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Gives the cache's memory back, see the module's documentation
+pub static SHRINKER: CacheShrinker = CacheShrinker;
+
+pub struct CacheShrinker;
+
+impl Shrinker for CacheShrinker {
+    fn name(&self) -> &'static str {
+        "arp cache"
+    }
+
+    fn count(&self) -> usize {
+        CACHE
+            .try_lock()
+            .map_or(0, |cache| cache.len() * mem::size_of::<(Ipv4Addr, Entry)>())
+    }
+
+    fn shrink(&self, level: Level, _target: usize) -> usize {
+        let Some(mut cache) = CACHE.try_lock() else {
+            return 0;
+        };
+        let before = cache.len();
+        let now = interrupts::ticks();
+        cache.retain(|_, entry| level < Level::Critical && entry.expires > now);
+        (before - cache.len()) * mem::size_of::<(Ipv4Addr, Entry)>()
+    }
+}
+
+fn lookup(address: Ipv4Addr) -> Option<MacAddress> {
+    let cache = CACHE.lock();
+    let entry = cache.get(&address)?;
+    (entry.expires > interrupts::ticks()).then_some(entry.mac)
+}
+
+fn insert(address: Ipv4Addr, mac: MacAddress) {
+    let expires = interrupts::ticks() + timer::duration_to_ticks(LIFETIME);
+    CACHE.lock().insert(address, Entry { mac, expires });
+}
+
+fn build(
+    operation: u16,
+    sender: (MacAddress, Ipv4Addr),
+    target: (MacAddress, Ipv4Addr),
+) -> [u8; PACKET_SIZE] {
+    let mut packet = [0; PACKET_SIZE];
+    write_u16_be(&mut packet, 0, HARDWARE_ETHERNET);
+    write_u16_be(&mut packet, 2, ETHERTYPE_IPV4);
+    packet[4] = 6;
+    packet[5] = 4;
+    write_u16_be(&mut packet, 6, operation);
+    packet[8..14].copy_from_slice(&sender.0 .0);
+    packet[14..18].copy_from_slice(&sender.1 .0);
+    packet[18..24].copy_from_slice(&target.0 .0);
+    packet[24..28].copy_from_slice(&target.1 .0);
+    packet
+}
+
+/// The hardware address of `address`, a host on `interface`'s link, asking for it if it isn't
+/// known
+pub async fn resolve(
+    interface: &Interface,
+    config: &Config,
+    address: Ipv4Addr,
+) -> Result<MacAddress, &'static str> {
+    for _ in 0..RETRIES {
+        if let Some(mac) = lookup(address) {
+            return Ok(mac);
+        }
+        let request = build(
+            OPERATION_REQUEST,
+            (interface.mac(), config.address),
+            (MacAddress([0; 6]), address),
+        );
+        interface.send(&ethernet_frame(
+            MacAddress::BROADCAST,
+            interface.mac(),
+            ETHERTYPE_ARP,
+            &request,
+        ))?;
+        timer::sleep(RETRY_INTERVAL).await;
+    }
+    lookup(address).ok_or("host unreachable: no ARP reply")
+}
+
+/// Handles an ARP packet `interface` received
+pub fn handle(interface: &Interface, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read_u16_be(packet, 0) != HARDWARE_ETHERNET
+        || read_u16_be(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender = Ipv4Addr(packet[14..18].try_into().unwrap());
+    let target = Ipv4Addr(packet[24..28].try_into().unwrap());
+    let ours = super::config(interface).filter(|config| config.address == target);
+
+    if sender != Ipv4Addr::UNSPECIFIED && (ours.is_some() || lookup(sender).is_some()) {
+        insert(sender, sender_mac);
+    }
+    if let (Some(config), OPERATION_REQUEST) = (ours, read_u16_be(packet, 6)) {
+        let reply = build(
+            OPERATION_REPLY,
+            (interface.mac(), config.address),
+            (sender_mac, sender),
+        );
+        let _ = interface.send(&ethernet_frame(
+            sender_mac,
+            interface.mac(),
+            ETHERTYPE_ARP,
+            &reply,
+        ));
+    }
+}
+
+/// Prints the cache
+pub fn list() {
+    let now = interrupts::ticks();
+    for (address, entry) in CACHE.lock().iter() {
+        if entry.expires > now {
+            println!("{} is at {}", address, entry.mac);
+        }
+    }
+}
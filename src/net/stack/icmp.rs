@@ -0,0 +1,33 @@
+//! ICMP: just enough to answer pings (RFC 792).
+
+use super::{checksum, protocol, Received};
+use crate::endian::write_u16_be;
+use alloc::vec::Vec;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+/// Type, code, checksum, and the identifier and sequence number of an echo
+const HEADER_SIZE: usize = 8;
+
+/// Handles an ICMP message; echo requests get their data back in a reply
+pub fn handle(received: &Received, message: &[u8]) {
+    if message.len() < HEADER_SIZE || checksum(&[message]) != 0 {
+        return;
+    }
+    if message[0] != ECHO_REQUEST || message[1] != 0 {
+        return;
+    }
+    // pings to a broadcast address go unanswered, like Linux's by default
+    if received.header.destination == super::Ipv4Addr::BROADCAST
+        || super::config(received.interface)
+            .is_some_and(|config| received.header.destination == config.broadcast())
+    {
+        return;
+    }
+    let mut reply = Vec::from(message);
+    reply[0] = ECHO_REPLY;
+    write_u16_be(&mut reply, 2, 0);
+    let sum = checksum(&[&reply]);
+    write_u16_be(&mut reply, 2, sum);
+    let _ = received.reply(protocol::ICMP, &reply);
+}
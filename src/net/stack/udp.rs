@@ -0,0 +1,178 @@
+//! UDP (RFC 768): datagrams to and from ports.
+//!
+//! A `UdpSocket` owns a local port until it's dropped; datagrams arriving for the port wait in
+//! the socket, up to `BACKLOG` of them, for `recv_from`. Datagrams for ports nobody bound are
+//! dropped silently. At `Critical` memory pressure, the datagrams waiting are dropped too (see
+//! `SHRINKER`).
+
+use super::{checksum, protocol, Ipv4Addr, Received, SocketAddr, MAX_PAYLOAD};
+use crate::endian::{read_u16_be, write_u16_be};
+use crate::memory::pressure::{Level, Shrinker};
+use crate::sync::mpsc::Channel;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const HEADER_SIZE: usize = 8;
+/// Largest datagram that fits in one frame
+pub const MAX_DATAGRAM: usize = MAX_PAYLOAD - HEADER_SIZE;
+
+/// Datagrams a socket holds for its reader before it drops new ones
+const BACKLOG: usize = 16;
+/// Where ports for sockets bound to port 0 come from, as IANA suggests
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+struct Socket {
+    received: Channel<(Vec<u8>, SocketAddr), BACKLOG>,
+}
+
+/// Sockets by local port
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Socket>>> = Mutex::new(BTreeMap::new());
+
+/// Drops the datagrams waiting in sockets when memory is about to run out
+pub static SHRINKER: BacklogShrinker = BacklogShrinker;
+
+pub struct BacklogShrinker;
+
+impl Shrinker for BacklogShrinker {
+    fn name(&self) -> &'static str {
+        "udp backlog"
+    }
+
+    fn count(&self) -> usize {
+        SOCKETS.try_lock().map_or(0, |sockets| {
+            let waiting: usize = sockets.values().map(|socket| socket.received.len()).sum();
+            waiting * MAX_DATAGRAM
+        })
+    }
+
+    fn shrink(&self, level: Level, target: usize) -> usize {
+        if level < Level::Critical {
+            return 0;
+        }
+        let Some(sockets) = SOCKETS.try_lock() else {
+            return 0;
+        };
+        let mut freed = 0;
+        for socket in sockets.values() {
+            while freed < target {
+                let Some((datagram, _)) = socket.received.try_recv() else {
+                    break;
+                };
+                freed += datagram.capacity();
+            }
+        }
+        freed
+    }
+}
+
+pub struct UdpSocket {
+    port: u16,
+    socket: Arc<Socket>,
+}
+
+impl UdpSocket {
+    /// Takes `port` on every interface; port 0 picks a free ephemeral one
+    pub fn bind(port: u16) -> Result<UdpSocket, &'static str> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !sockets.contains_key(port))
+                .ok_or("no free ports")?,
+            port if sockets.contains_key(&port) => return Err("the port is in use"),
+            port => port,
+        };
+        let socket = Arc::new(Socket {
+            received: Channel::new(),
+        });
+        sockets.insert(port, socket.clone());
+        Ok(UdpSocket { port, socket })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` as one datagram, at most `MAX_DATAGRAM` bytes
+    pub async fn send_to(&self, data: &[u8], destination: SocketAddr) -> Result<(), &'static str> {
+        if data.len() > MAX_DATAGRAM {
+            return Err("datagram too large");
+        }
+        let (route, _) = super::route(destination.address).ok_or("no route to the host")?;
+        let datagram = build(route.config.address, self.port, destination, data);
+        super::send(destination.address, protocol::UDP, &datagram).await
+    }
+
+    /// Waits for the next datagram and returns it with where it came from
+    pub async fn recv_from(&self) -> (Vec<u8>, SocketAddr) {
+        self.socket.received.recv().await
+    }
+
+    /// The next datagram, if one is waiting
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.socket.received.try_recv()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// The sum of the pseudo-header's fields and the datagram, for the checksum
+fn datagram_checksum(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut pseudo = [0; 12];
+    pseudo[0..4].copy_from_slice(&source.0);
+    pseudo[4..8].copy_from_slice(&destination.0);
+    pseudo[9] = protocol::UDP;
+    write_u16_be(&mut pseudo, 10, datagram.len() as u16);
+    checksum(&[&pseudo, datagram])
+}
+
+/// A datagram from `source` and `port` with `data`, checksum included
+pub fn build(source: Ipv4Addr, port: u16, destination: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0; HEADER_SIZE + data.len()];
+    write_u16_be(&mut datagram, 0, port);
+    write_u16_be(&mut datagram, 2, destination.port);
+    write_u16_be(&mut datagram, 4, (HEADER_SIZE + data.len()) as u16);
+    datagram[HEADER_SIZE..].copy_from_slice(data);
+    let sum = match datagram_checksum(source, destination.address, &datagram) {
+        0 => 0xffff, // 0 means there's no checksum
+        sum => sum,
+    };
+    write_u16_be(&mut datagram, 6, sum);
+    datagram
+}
+
+/// Hands a received datagram to the socket bound to its port
+pub fn handle(received: &Received, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = read_u16_be(datagram, 4) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    let header = received.header;
+    if read_u16_be(datagram, 6) != 0
+        && datagram_checksum(header.source, header.destination, datagram) != 0
+    {
+        return;
+    }
+    let Some(socket) = SOCKETS.lock().get(&read_u16_be(datagram, 2)).cloned() else {
+        return;
+    };
+    let source = SocketAddr {
+        address: header.source,
+        port: read_u16_be(datagram, 0),
+    };
+    // a full socket drops the datagram, as UDP may
+    let _ = socket
+        .received
+        .send((Vec::from(&datagram[HEADER_SIZE..]), source));
+}
@@ -0,0 +1,696 @@
+//! A minimal TLS 1.3 client (RFC 8446).
+//!
+//! Exactly one of everything is offered: the TLS_CHACHA20_POLY1305_SHA256 cipher suite, X25519
+//! key exchange and Ed25519 signatures. There's no certificate chain validation; instead the
+//! caller pins the SHA-256 hash of the server's certificate, and the server then proves it holds
+//! the matching private key. That fits fetching files from a handful of known hosts, and spares
+//! the kernel an X.509 parser and a CA store. Servers that insist on client certificates, RSA or
+//! ECDSA keys, or a HelloRetryRequest are refused with `TlsError::Unsupported`.
+//!
+//! ```ignore
+//! let mut tls = TlsStream::connect(tcp, "files.example", &PINNED_CERTIFICATE)?;
+//! tls.write(b"GET / HTTP/1.0\r\n\r\n")?;
+//! ```
+
+use super::{Stream, StreamError};
+use crate::crypto::chacha20poly1305::{self, NONCE_SIZE, TAG_SIZE};
+use crate::crypto::{self, ed25519, hkdf_expand, hkdf_extract, hmac_sha256, x25519, Sha256};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+const TLS_13: u16 = 0x0304;
+/// The version every record and the ClientHello claim, for the sake of middleboxes
+const LEGACY_VERSION: u16 = 0x0303;
+const TLS_CHACHA20_POLY1305_SHA256: u16 = 0x1303;
+const GROUP_X25519: u16 = 0x001d;
+const SIGNATURE_ED25519: u16 = 0x0807;
+
+mod content {
+    pub const CHANGE_CIPHER_SPEC: u8 = 20;
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+}
+
+mod handshake {
+    pub const CLIENT_HELLO: u8 = 1;
+    pub const SERVER_HELLO: u8 = 2;
+    pub const NEW_SESSION_TICKET: u8 = 4;
+    pub const ENCRYPTED_EXTENSIONS: u8 = 8;
+    pub const CERTIFICATE: u8 = 11;
+    pub const CERTIFICATE_REQUEST: u8 = 13;
+    pub const CERTIFICATE_VERIFY: u8 = 15;
+    pub const FINISHED: u8 = 20;
+    pub const KEY_UPDATE: u8 = 24;
+}
+
+mod extension {
+    pub const SERVER_NAME: u16 = 0;
+    pub const SUPPORTED_GROUPS: u16 = 10;
+    pub const SIGNATURE_ALGORITHMS: u16 = 13;
+    pub const SUPPORTED_VERSIONS: u16 = 43;
+    pub const KEY_SHARE: u16 = 51;
+}
+
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+const ALERT_LEVEL_WARNING: u8 = 1;
+
+const RECORD_HEADER_SIZE: usize = 5;
+const MAX_PLAINTEXT: usize = 1 << 14;
+/// Largest ciphertext a record may carry: plaintext, content type, padding and tag
+const MAX_CIPHERTEXT: usize = MAX_PLAINTEXT + 256;
+
+/// The ServerHello random that marks a HelloRetryRequest
+#[rustfmt::skip]
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// What precedes an Ed25519 public key in a certificate's SubjectPublicKeyInfo
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsError {
+    Stream(StreamError),
+    /// The peer sent something malformed or out of place
+    Protocol,
+    /// The server picked a version, algorithm or feature this client doesn't implement
+    Unsupported,
+    /// The server's certificate isn't the pinned one
+    CertificateMismatch,
+    /// The server's signature or Finished message didn't check out
+    HandshakeFailed,
+    /// A record failed authentication
+    BadRecordMac,
+    /// The peer sent a fatal alert with this description
+    Alert(u8),
+    /// The connection was closed, cleanly or not, before the operation completed
+    Closed,
+}
+
+impl From<StreamError> for TlsError {
+    fn from(err: StreamError) -> Self {
+        TlsError::Stream(err)
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Stream(err) => write!(f, "transport error: {}", err),
+            TlsError::Protocol => f.write_str("protocol violation by the peer"),
+            TlsError::Unsupported => f.write_str("server requires an unsupported feature"),
+            TlsError::CertificateMismatch => f.write_str("certificate does not match the pin"),
+            TlsError::HandshakeFailed => f.write_str("server failed to authenticate"),
+            TlsError::BadRecordMac => f.write_str("record failed authentication"),
+            TlsError::Alert(description) => write!(f, "peer sent alert {}", description),
+            TlsError::Closed => f.write_str("connection closed"),
+        }
+    }
+}
+
+// --- key schedule --------------------------------------------------------------------------
+//
+// Public so `tests/crypto.rs` can check each step against the RFC 8448 trace.
+
+/// HKDF-Expand-Label from RFC 8446 section 7.1
+pub fn expand_label(secret: &[u8; 32], label: &[u8], context: &[u8], out: &mut [u8]) {
+    let mut info = Vec::with_capacity(4 + 6 + label.len() + context.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label);
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+    hkdf_expand(secret, &info, out);
+}
+
+/// Derive-Secret from RFC 8446 section 7.1
+pub fn derive_secret(secret: &[u8; 32], label: &[u8], transcript_hash: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0; 32];
+    expand_label(secret, label, transcript_hash, &mut out);
+    out
+}
+
+/// The handshake secret the (EC)DHE `shared` secret makes; without a PSK the early secret is
+/// that of zeros
+pub fn handshake_secret(shared: &[u8; 32]) -> [u8; 32] {
+    let early_secret = hkdf_extract(&[0; 32], &[0; 32]);
+    hkdf_extract(
+        &derive_secret(&early_secret, b"derived", &empty_hash()),
+        shared,
+    )
+}
+
+/// The master secret that follows `handshake_secret`
+pub fn master_secret(handshake_secret: &[u8; 32]) -> [u8; 32] {
+    hkdf_extract(
+        &derive_secret(handshake_secret, b"derived", &empty_hash()),
+        &[0; 32],
+    )
+}
+
+/// Hash of the empty transcript, the context of the "derived" secrets
+fn empty_hash() -> [u8; 32] {
+    crypto::sha256(b"")
+}
+
+/// The verify_data of a Finished message sent under the handshake traffic secret `secret`
+pub fn finished_mac(secret: &[u8; 32], transcript_hash: &[u8; 32]) -> [u8; 32] {
+    let mut finished_key = [0; 32];
+    expand_label(secret, b"finished", b"", &mut finished_key);
+    hmac_sha256(&finished_key, transcript_hash)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Keys protecting one direction of the connection
+struct TrafficKeys {
+    secret: [u8; 32],
+    key: [u8; 32],
+    iv: [u8; NONCE_SIZE],
+    sequence: u64,
+}
+
+impl TrafficKeys {
+    fn new(secret: [u8; 32]) -> Self {
+        let mut key = [0; 32];
+        let mut iv = [0; NONCE_SIZE];
+        expand_label(&secret, b"key", b"", &mut key);
+        expand_label(&secret, b"iv", b"", &mut iv);
+        TrafficKeys {
+            secret,
+            key,
+            iv,
+            sequence: 0,
+        }
+    }
+
+    /// The keys after a KeyUpdate
+    fn next(&self) -> Self {
+        let mut secret = [0; 32];
+        expand_label(&self.secret, b"traffic upd", b"", &mut secret);
+        TrafficKeys::new(secret)
+    }
+
+    /// The per-record nonce, which also advances the sequence number
+    fn next_nonce(&mut self) -> [u8; NONCE_SIZE] {
+        let mut nonce = self.iv;
+        for (byte, sequence) in nonce[4..].iter_mut().zip(self.sequence.to_be_bytes()) {
+            *byte ^= sequence;
+        }
+        self.sequence += 1;
+        nonce
+    }
+}
+
+// --- parsing -------------------------------------------------------------------------------
+
+/// Reads big-endian fields and length-prefixed vectors out of a message
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TlsError> {
+        if len > self.data.len() {
+            return Err(TlsError::Protocol);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn uint(&mut self, size: usize) -> Result<usize, TlsError> {
+        Ok(self
+            .bytes(size)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    fn u8(&mut self) -> Result<u8, TlsError> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    fn u16(&mut self) -> Result<u16, TlsError> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    /// A vector whose length takes `size` bytes
+    fn vector(&mut self, size: usize) -> Result<Reader<'a>, TlsError> {
+        let len = self.uint(size)?;
+        Ok(Reader::new(self.bytes(len)?))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn finish(&self) -> Result<(), TlsError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(TlsError::Protocol)
+        }
+    }
+}
+
+/// Appends a vector with a `size`-byte length prefix, filled in by `body`
+fn put_vector(out: &mut Vec<u8>, size: usize, body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.resize(start + size, 0);
+    body(out);
+    let len = out.len() - start - size;
+    out[start..start + size].copy_from_slice(&len.to_be_bytes()[8 - size..]);
+}
+
+fn put_extension(out: &mut Vec<u8>, kind: u16, body: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(&kind.to_be_bytes());
+    put_vector(out, 2, body);
+}
+
+fn client_hello(random: &[u8; 32], server_name: &str, key_share: &[u8; 32]) -> Vec<u8> {
+    let mut message = vec![handshake::CLIENT_HELLO];
+    put_vector(&mut message, 3, |out| {
+        out.extend_from_slice(&LEGACY_VERSION.to_be_bytes());
+        out.extend_from_slice(random);
+        out.push(0); // no legacy session id
+        put_vector(out, 2, |out| {
+            out.extend_from_slice(&TLS_CHACHA20_POLY1305_SHA256.to_be_bytes())
+        });
+        out.extend_from_slice(&[1, 0]); // the null compression method
+        put_vector(out, 2, |out| {
+            if !server_name.is_empty() {
+                put_extension(out, extension::SERVER_NAME, |out| {
+                    put_vector(out, 2, |out| {
+                        out.push(0); // host_name
+                        put_vector(out, 2, |out| out.extend_from_slice(server_name.as_bytes()));
+                    })
+                });
+            }
+            put_extension(out, extension::SUPPORTED_VERSIONS, |out| {
+                put_vector(out, 1, |out| out.extend_from_slice(&TLS_13.to_be_bytes()))
+            });
+            put_extension(out, extension::SUPPORTED_GROUPS, |out| {
+                put_vector(out, 2, |out| {
+                    out.extend_from_slice(&GROUP_X25519.to_be_bytes())
+                })
+            });
+            put_extension(out, extension::SIGNATURE_ALGORITHMS, |out| {
+                put_vector(out, 2, |out| {
+                    out.extend_from_slice(&SIGNATURE_ED25519.to_be_bytes())
+                })
+            });
+            put_extension(out, extension::KEY_SHARE, |out| {
+                put_vector(out, 2, |out| {
+                    out.extend_from_slice(&GROUP_X25519.to_be_bytes());
+                    put_vector(out, 2, |out| out.extend_from_slice(key_share));
+                })
+            });
+        });
+    });
+    message
+}
+
+/// The server's X25519 key share from its ServerHello
+fn parse_server_hello(body: &[u8]) -> Result<[u8; 32], TlsError> {
+    let mut reader = Reader::new(body);
+    reader.u16()?; // legacy version, the real one is in an extension
+    if reader.bytes(32)? == HELLO_RETRY_REQUEST {
+        return Err(TlsError::Unsupported);
+    }
+    if !reader.vector(1)?.is_empty() {
+        return Err(TlsError::Protocol); // must echo our empty session id
+    }
+    if reader.u16()? != TLS_CHACHA20_POLY1305_SHA256 || reader.u8()? != 0 {
+        return Err(TlsError::Protocol);
+    }
+    let mut extensions = reader.vector(2)?;
+    reader.finish()?;
+
+    let mut version = None;
+    let mut key_share = None;
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vector(2)?;
+        match kind {
+            extension::SUPPORTED_VERSIONS => version = Some(data.u16()?),
+            extension::KEY_SHARE => {
+                if data.u16()? != GROUP_X25519 {
+                    return Err(TlsError::Protocol);
+                }
+                key_share = Some(data.vector(2)?.bytes(32)?);
+            }
+            _ => continue,
+        }
+        data.finish()?;
+    }
+    if version != Some(TLS_13) {
+        return Err(TlsError::Unsupported);
+    }
+    let key_share = key_share.ok_or(TlsError::Protocol)?;
+    Ok(key_share.try_into().unwrap())
+}
+
+/// The leaf certificate from a Certificate message
+fn parse_certificate(body: &[u8]) -> Result<&[u8], TlsError> {
+    let mut reader = Reader::new(body);
+    reader.vector(1)?; // request context, empty outside client authentication
+    let mut list = reader.vector(3)?;
+    reader.finish()?;
+    let leaf = list.vector(3)?.data;
+    list.vector(2)?; // per-certificate extensions
+    Ok(leaf)
+}
+
+/// The Ed25519 public key in a certificate, found by its SubjectPublicKeyInfo encoding. Good
+/// enough without a DER parser because the certificate has already been matched against the pin.
+fn certificate_key(certificate: &[u8]) -> Option<[u8; ed25519::PUBLIC_KEY_SIZE]> {
+    let at = certificate
+        .windows(ED25519_SPKI_PREFIX.len())
+        .position(|window| window == ED25519_SPKI_PREFIX)?;
+    let start = at + ED25519_SPKI_PREFIX.len();
+    certificate
+        .get(start..start + ed25519::PUBLIC_KEY_SIZE)?
+        .try_into()
+        .ok()
+}
+
+fn verify_certificate_signature(
+    key: &[u8; ed25519::PUBLIC_KEY_SIZE],
+    body: &[u8],
+    transcript_hash: &[u8; 32],
+) -> Result<(), TlsError> {
+    let mut reader = Reader::new(body);
+    if reader.u16()? != SIGNATURE_ED25519 {
+        return Err(TlsError::Unsupported);
+    }
+    let signature = reader.vector(2)?.data;
+    reader.finish()?;
+    let signature = signature.try_into().map_err(|_| TlsError::Protocol)?;
+    let signed: [&[u8]; 4] = [
+        &[0x20; 64],
+        b"TLS 1.3, server CertificateVerify",
+        &[0],
+        transcript_hash,
+    ];
+    if ed25519::verify_parts(key, &signed, signature) {
+        Ok(())
+    } else {
+        Err(TlsError::HandshakeFailed)
+    }
+}
+
+// --- the connection ------------------------------------------------------------------------
+
+/// An established TLS connection over `S`
+pub struct TlsStream<S: Stream> {
+    stream: S,
+    /// `None` until the server's handshake keys are known
+    read_keys: Option<TrafficKeys>,
+    write_keys: Option<TrafficKeys>,
+    /// Bytes received from the stream that don't yet make up a whole record
+    received: Vec<u8>,
+    /// Handshake bytes not yet making up a whole message
+    handshake: Vec<u8>,
+    /// Decrypted application data not yet handed to `read`
+    plaintext: Vec<u8>,
+    /// The server sent close_notify
+    eof: bool,
+}
+
+impl<S: Stream> TlsStream<S> {
+    /// Performs the handshake with the server at the other end of `stream`, which must present
+    /// the certificate whose SHA-256 hash is `pinned_certificate`. `server_name` is sent as SNI
+    /// unless empty.
+    pub fn connect(
+        stream: S,
+        server_name: &str,
+        pinned_certificate: &[u8; 32],
+    ) -> Result<Self, TlsError> {
+        let mut tls = TlsStream {
+            stream,
+            read_keys: None,
+            write_keys: None,
+            received: Vec::new(),
+            handshake: Vec::new(),
+            plaintext: Vec::new(),
+            eof: false,
+        };
+
+        let private_key = crypto::random_seed();
+        let hello = client_hello(
+            &crypto::random_seed(),
+            server_name,
+            &x25519::public_key(&private_key),
+        );
+        let mut transcript = Sha256::new();
+        transcript.update(&hello);
+        tls.write_record(content::HANDSHAKE, &hello)?;
+
+        let server_hello = tls.read_handshake()?;
+        if server_hello[0] != handshake::SERVER_HELLO {
+            return Err(TlsError::Protocol);
+        }
+        let server_share = parse_server_hello(&server_hello[4..])?;
+        transcript.update(&server_hello);
+
+        let shared = x25519::x25519(&private_key, &server_share);
+        if shared == [0; 32] {
+            return Err(TlsError::Protocol); // a low-order point
+        }
+        let handshake_secret = handshake_secret(&shared);
+        let hello_hash = transcript.clone().finish();
+        let client_secret = derive_secret(&handshake_secret, b"c hs traffic", &hello_hash);
+        let server_secret = derive_secret(&handshake_secret, b"s hs traffic", &hello_hash);
+        tls.read_keys = Some(TrafficKeys::new(server_secret));
+        tls.write_keys = Some(TrafficKeys::new(client_secret));
+
+        let expect = |tls: &mut Self, kind: u8, transcript: &mut Sha256| {
+            let message = tls.read_handshake()?;
+            if message[0] == handshake::CERTIFICATE_REQUEST {
+                return Err(TlsError::Unsupported);
+            }
+            if message[0] != kind {
+                return Err(TlsError::Protocol);
+            }
+            let hash_before = transcript.clone().finish();
+            transcript.update(&message);
+            Ok((message, hash_before))
+        };
+        expect(&mut tls, handshake::ENCRYPTED_EXTENSIONS, &mut transcript)?;
+        let (certificate, _) = expect(&mut tls, handshake::CERTIFICATE, &mut transcript)?;
+        let leaf = parse_certificate(&certificate[4..])?;
+        if !constant_time_eq(&crypto::sha256(leaf), pinned_certificate) {
+            return Err(TlsError::CertificateMismatch);
+        }
+        let key = certificate_key(leaf).ok_or(TlsError::Unsupported)?;
+        let (verify, hash) = expect(&mut tls, handshake::CERTIFICATE_VERIFY, &mut transcript)?;
+        verify_certificate_signature(&key, &verify[4..], &hash)?;
+        let (finished, hash) = expect(&mut tls, handshake::FINISHED, &mut transcript)?;
+        if !constant_time_eq(&finished[4..], &finished_mac(&server_secret, &hash)) {
+            return Err(TlsError::HandshakeFailed);
+        }
+
+        let server_finished_hash = transcript.finish();
+        let mut client_finished = vec![handshake::FINISHED, 0, 0, 32];
+        client_finished.extend_from_slice(&finished_mac(&client_secret, &server_finished_hash));
+        tls.write_record(content::HANDSHAKE, &client_finished)?;
+
+        let master_secret = master_secret(&handshake_secret);
+        let client_secret = derive_secret(&master_secret, b"c ap traffic", &server_finished_hash);
+        let server_secret = derive_secret(&master_secret, b"s ap traffic", &server_finished_hash);
+        tls.read_keys = Some(TrafficKeys::new(server_secret));
+        tls.write_keys = Some(TrafficKeys::new(client_secret));
+        Ok(tls)
+    }
+
+    /// Reads decrypted application data; returns 0 once the server has closed the connection
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        while self.plaintext.is_empty() && !self.eof {
+            let (kind, data) = self.read_record()?;
+            match kind {
+                content::APPLICATION_DATA => self.plaintext = data,
+                content::ALERT => {} // close_notify, which set `eof`
+                content::HANDSHAKE => {
+                    self.handshake.extend_from_slice(&data);
+                    while let Some(message) = self.take_handshake_message()? {
+                        self.post_handshake(&message)?;
+                    }
+                }
+                _ => return Err(TlsError::Protocol),
+            }
+        }
+        let len = buf.len().min(self.plaintext.len());
+        buf[..len].copy_from_slice(&self.plaintext[..len]);
+        self.plaintext.drain(..len);
+        Ok(len)
+    }
+
+    /// Encrypts and sends all of `data`
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, TlsError> {
+        for chunk in data.chunks(MAX_PLAINTEXT) {
+            self.write_record(content::APPLICATION_DATA, chunk)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Tells the server we're done and hands back the underlying stream
+    pub fn close(mut self) -> Result<S, TlsError> {
+        self.write_record(content::ALERT, &[ALERT_LEVEL_WARNING, ALERT_CLOSE_NOTIFY])?;
+        Ok(self.stream)
+    }
+
+    /// Handles a handshake message arriving after the handshake
+    fn post_handshake(&mut self, message: &[u8]) -> Result<(), TlsError> {
+        match message[0] {
+            // session resumption isn't implemented, so tickets are of no use
+            handshake::NEW_SESSION_TICKET => Ok(()),
+            handshake::KEY_UPDATE => {
+                let update_requested = match &message[4..] {
+                    [0] => false,
+                    [1] => true,
+                    _ => return Err(TlsError::Protocol),
+                };
+                self.read_keys = self.read_keys.as_ref().map(TrafficKeys::next);
+                if update_requested {
+                    // answered under the old keys, then ours move on too
+                    self.write_record(content::HANDSHAKE, &[handshake::KEY_UPDATE, 0, 0, 1, 0])?;
+                    self.write_keys = self.write_keys.as_ref().map(TrafficKeys::next);
+                }
+                Ok(())
+            }
+            _ => Err(TlsError::Protocol),
+        }
+    }
+
+    // --- records --------------------------------------------------------------------------
+
+    fn write_record(&mut self, kind: u8, data: &[u8]) -> Result<(), TlsError> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len() + 1 + TAG_SIZE);
+        match &mut self.write_keys {
+            None => {
+                record.push(kind);
+                // the very first record claims TLS 1.0, as RFC 8446 recommends
+                record.extend_from_slice(&0x0301u16.to_be_bytes());
+                record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                record.extend_from_slice(data);
+            }
+            Some(keys) => {
+                let len = data.len() + 1 + TAG_SIZE;
+                record.push(content::APPLICATION_DATA);
+                record.extend_from_slice(&LEGACY_VERSION.to_be_bytes());
+                record.extend_from_slice(&(len as u16).to_be_bytes());
+                record.extend_from_slice(data);
+                record.push(kind);
+                let nonce = keys.next_nonce();
+                let (header, body) = record.split_at_mut(RECORD_HEADER_SIZE);
+                let tag = chacha20poly1305::seal(&keys.key, &nonce, header, body);
+                record.extend_from_slice(&tag);
+            }
+        }
+        Ok(self.stream.send(&record)?)
+    }
+
+    /// Receives until `received` holds at least `len` bytes
+    fn fill(&mut self, len: usize) -> Result<(), TlsError> {
+        let mut buf = [0; 2048];
+        while self.received.len() < len {
+            let n = self.stream.recv(&mut buf)?;
+            if n == 0 {
+                return Err(TlsError::Closed);
+            }
+            self.received.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    }
+
+    /// The next record's content type and (decrypted) contents, skipping the ChangeCipherSpec
+    /// records servers send for middlebox compatibility and handling alerts
+    fn read_record(&mut self) -> Result<(u8, Vec<u8>), TlsError> {
+        loop {
+            self.fill(RECORD_HEADER_SIZE)?;
+            let header: [u8; RECORD_HEADER_SIZE] =
+                self.received[..RECORD_HEADER_SIZE].try_into().unwrap();
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if len > MAX_CIPHERTEXT {
+                return Err(TlsError::Protocol);
+            }
+            self.fill(RECORD_HEADER_SIZE + len)?;
+            let mut data: Vec<u8> = self
+                .received
+                .drain(..RECORD_HEADER_SIZE + len)
+                .skip(RECORD_HEADER_SIZE)
+                .collect();
+
+            let mut kind = header[0];
+            if kind == content::CHANGE_CIPHER_SPEC {
+                continue;
+            }
+            if let Some(keys) = &mut self.read_keys {
+                if kind != content::APPLICATION_DATA || len < TAG_SIZE + 1 {
+                    return Err(TlsError::Protocol);
+                }
+                let tag: [u8; TAG_SIZE] = data[len - TAG_SIZE..].try_into().unwrap();
+                data.truncate(len - TAG_SIZE);
+                let nonce = keys.next_nonce();
+                if !chacha20poly1305::open(&keys.key, &nonce, &header, &mut data, &tag) {
+                    return Err(TlsError::BadRecordMac);
+                }
+                // the real content type is the last non-zero byte, padding follows it
+                let end = data.iter().rposition(|&byte| byte != 0);
+                let end = end.ok_or(TlsError::Protocol)?;
+                kind = data[end];
+                data.truncate(end);
+            }
+
+            if kind == content::ALERT {
+                match data[..] {
+                    [_, ALERT_CLOSE_NOTIFY] => {
+                        self.eof = true;
+                        return Ok((kind, Vec::new()));
+                    }
+                    [_, description] => return Err(TlsError::Alert(description)),
+                    _ => return Err(TlsError::Protocol),
+                }
+            }
+            return Ok((kind, data));
+        }
+    }
+
+    /// Splits a complete message off the front of the handshake buffer, if there is one
+    fn take_handshake_message(&mut self) -> Result<Option<Vec<u8>>, TlsError> {
+        if self.handshake.len() < 4 {
+            return Ok(None);
+        }
+        let len = Reader::new(&self.handshake[1..4]).uint(3)?;
+        if self.handshake.len() < 4 + len {
+            return Ok(None);
+        }
+        Ok(Some(self.handshake.drain(..4 + len).collect()))
+    }
+
+    /// The next whole handshake message, header included, during the handshake
+    fn read_handshake(&mut self) -> Result<Vec<u8>, TlsError> {
+        loop {
+            if let Some(message) = self.take_handshake_message()? {
+                return Ok(message);
+            }
+            match self.read_record()? {
+                (content::HANDSHAKE, data) => self.handshake.extend_from_slice(&data),
+                (content::ALERT, _) => return Err(TlsError::Closed),
+                _ => return Err(TlsError::Protocol),
+            }
+        }
+    }
+}
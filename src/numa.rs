@@ -0,0 +1,171 @@
+//! Non-uniform memory access: which node each CPU and each physical frame belongs to.
+//!
+//! `init` reads the proximity domains from the SRAT and numbers them as nodes 0, 1, ... in
+//! ascending order; the SLIT, if there is one, says how far apart they are. From then on the
+//! frame allocator gives every CPU frames of its own node while there are any, and of the nearest
+//! other node after that. That covers what's built from frames on the CPU that uses it: page
+//! tables, process memory, DMA buffers. The kernel heap is mapped before `init` runs, so heap
+//! allocations, the per-CPU data and kernel stacks among them, land wherever the boot CPU's first
+//! frames came from.
+//!
+//! Without an SRAT, or with a single domain in it, everything is on node 0 and the frame
+//! allocator hands out frames in plain address order.
+
+use crate::{acpi, memory, print, println};
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// Nodes past this many are folded into node 0
+pub const MAX_NODES: usize = 8;
+
+/// Distance to a node's own memory, and to another node's if the SLIT doesn't say
+const LOCAL_DISTANCE: u8 = 10;
+const REMOTE_DISTANCE: u8 = 20;
+
+struct Topology {
+    /// Proximity domain of each node
+    domains: Vec<u32>,
+    /// Physical address ranges and the node they belong to
+    memory: Vec<(Range<u64>, usize)>,
+    /// APIC id and node of each processor
+    cpus: Vec<(u32, usize)>,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Reads the node layout from the SRAT and, if there's more than one node, makes the frame
+/// allocator node-aware. Needs the heap; run it before the other CPUs come up.
+#[link_section = ".init.text"]
+pub fn init() {
+    let Some(srat) = acpi::srat() else {
+        return;
+    };
+    let mut domains: Vec<u32> = srat
+        .processors
+        .iter()
+        .map(|processor| processor.proximity_domain)
+        .chain(srat.memory.iter().map(|range| range.proximity_domain))
+        .collect();
+    domains.sort_unstable();
+    domains.dedup();
+    if domains.len() > MAX_NODES {
+        println!(
+            "numa: {} nodes, treating those past {} as node 0",
+            domains.len(),
+            MAX_NODES
+        );
+        domains.truncate(MAX_NODES);
+    }
+    if domains.len() < 2 {
+        return;
+    }
+
+    let node = |domain| domains.iter().position(|&d| d == domain).unwrap_or(0);
+    let memory = srat
+        .memory
+        .iter()
+        .map(|range| {
+            let end = range.base.saturating_add(range.length);
+            (range.base..end, node(range.proximity_domain))
+        })
+        .collect();
+    let cpus = srat
+        .processors
+        .iter()
+        .map(|processor| (processor.apic_id, node(processor.proximity_domain)))
+        .collect();
+    let topology = TOPOLOGY.call_once(|| Topology {
+        domains,
+        memory,
+        cpus,
+    });
+
+    if let Some(frame_allocator) = memory::FRAME_ALLOCATOR.lock().as_mut() {
+        frame_allocator.split_by_node();
+    }
+    println!("numa: {} nodes", topology.domains.len());
+}
+
+/// Number of nodes, 1 on machines that aren't NUMA
+pub fn node_count() -> usize {
+    TOPOLOGY
+        .r#try()
+        .map_or(1, |topology| topology.domains.len())
+}
+
+/// The node the frame at `address` belongs to; 0 for memory the SRAT doesn't mention
+pub fn frame_node(address: PhysAddr) -> usize {
+    let Some(topology) = TOPOLOGY.r#try() else {
+        return 0;
+    };
+    topology
+        .memory
+        .iter()
+        .find(|(range, _)| range.contains(&address.as_u64()))
+        .map_or(0, |&(_, node)| node)
+}
+
+/// The node of the processor with `apic_id`; 0 if the SRAT doesn't mention it
+pub fn cpu_node(apic_id: u32) -> usize {
+    let Some(topology) = TOPOLOGY.r#try() else {
+        return 0;
+    };
+    topology
+        .cpus
+        .iter()
+        .find(|&&(id, _)| id == apic_id)
+        .map_or(0, |&(_, node)| node)
+}
+
+/// How far `to`'s memory is from `from`, relative to the 10 of a node's own memory
+pub fn distance(from: usize, to: usize) -> u8 {
+    let slit_distance = TOPOLOGY.r#try().and_then(|topology| {
+        let slit = acpi::slit()?;
+        slit.distance(*topology.domains.get(from)?, *topology.domains.get(to)?)
+    });
+    match slit_distance {
+        Some(distance) => distance,
+        None if from == to => LOCAL_DISTANCE,
+        None => REMOTE_DISTANCE,
+    }
+}
+
+/// Every node, nearest to `node` first; `node` itself comes first unless the SLIT is strange
+pub fn by_distance(node: usize) -> impl Iterator<Item = usize> {
+    let count = node_count();
+    let mut nodes: [usize; MAX_NODES] = core::array::from_fn(|index| index);
+    nodes[..count].sort_unstable_by_key(|&other| (distance(node, other), other));
+    nodes.into_iter().take(count)
+}
+
+/// `numa`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    let count = node_count();
+    let Some(topology) = TOPOLOGY.r#try() else {
+        println!("one node: no SRAT, or a single proximity domain in it");
+        return Ok(());
+    };
+    for node in 0..count {
+        let memory: u64 = topology
+            .memory
+            .iter()
+            .filter(|&&(_, n)| n == node)
+            .map(|(range, _)| range.end - range.start)
+            .sum();
+        let cpus = topology.cpus.iter().filter(|&&(_, n)| n == node).count();
+        print!(
+            "node {} (domain {}): {} MiB, {} CPUs, distances",
+            node,
+            topology.domains[node],
+            memory / (1024 * 1024),
+            cpus
+        );
+        for other in 0..count {
+            print!(" {}", distance(node, other));
+        }
+        println!();
+    }
+    Ok(())
+}
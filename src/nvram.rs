@@ -0,0 +1,213 @@
+//! Settings that survive a reboot, kept in the CMOS's battery-backed memory.
+//!
+//! The command line is the place for settings that are picked per boot, but a machine booted from
+//! a fixed image, or one that reboots by itself, has nobody to type it. What's here is instead
+//! changed with the `nvram` command and read back by the next boot, and the one after, until it's
+//! changed again:
+//!
+//! - `console`: `text` for the VGA text console, `graphics` to switch to a framebuffer once the
+//!   display adapter is found (see `gfx::enable`)
+//! - `loglevel`: `normal`, or `quiet` to print the boot's messages only to the kernel log and the
+//!   serial console, not to the display, unless the boot fails
+//!
+//! Every boot also counts itself here when it starts and sets the count back to 0 once the boot
+//! phases are done, so the next boot can tell how many before it in a row didn't get that far, see
+//! `failed_boots`; after a few the kernel boots in safe mode (see `boot::safe_mode`).
+//!
+//! The settings take `SIZE` bytes from `BASE` on, which firmware usually leaves alone, behind a
+//! magic byte and a checksum: bytes that don't add up, e.g. on the first boot or after the CMOS
+//! battery ran out, read as the defaults. QEMU keeps its CMOS across a reboot of the guest but
+//! starts it afresh with every run.
+
+use crate::{println, rtc};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+/// First CMOS byte used
+const BASE: u8 = 0x70;
+/// Bytes used, the checksum included
+const SIZE: usize = 8;
+/// The first byte, with the layout's version in the low bits
+const MAGIC: u8 = 0xA1;
+
+mod offset {
+    pub const MAGIC: u8 = 0;
+    /// Boots in a row that started and didn't complete, this one included while it runs
+    pub const UNFINISHED_BOOTS: u8 = 1;
+    pub const CONSOLE: u8 = 2;
+    pub const LOG_LEVEL: u8 = 3;
+    /// Makes all the bytes add up to 0
+    pub const CHECKSUM: u8 = 7;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Text,
+    Graphics,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Normal,
+    Quiet,
+}
+
+impl Console {
+    fn from_u8(value: u8) -> Console {
+        match value {
+            1 => Console::Graphics,
+            _ => Console::Text,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Console::Text => "text",
+            Console::Graphics => "graphics",
+        }
+    }
+}
+
+impl LogLevel {
+    fn from_u8(value: u8) -> LogLevel {
+        match value {
+            1 => LogLevel::Quiet,
+            _ => LogLevel::Normal,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Normal => "normal",
+            LogLevel::Quiet => "quiet",
+        }
+    }
+}
+
+/// What the next boot starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub console: Console,
+    pub log_level: LogLevel,
+}
+
+impl Settings {
+    pub const DEFAULT: Settings = Settings {
+        console: Console::Text,
+        log_level: LogLevel::Normal,
+    };
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "console={} loglevel={}",
+            self.console.name(),
+            self.log_level.name()
+        )
+    }
+}
+
+/// The settings as stored, and the boot count besides
+struct State {
+    settings: Settings,
+    unfinished_boots: u8,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    settings: Settings::DEFAULT,
+    unfinished_boots: 0,
+});
+
+/// How many boots in a row before this one started and never finished
+static FAILED_BOOTS: AtomicU8 = AtomicU8::new(0);
+
+/// The stored bytes, `None` if they aren't a valid copy
+fn load() -> Option<[u8; SIZE]> {
+    let mut bytes = [0; SIZE];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = rtc::read_register(BASE + index as u8);
+    }
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    (bytes[offset::MAGIC as usize] == MAGIC && sum == 0).then_some(bytes)
+}
+
+fn store(state: &State) {
+    let mut bytes = [0; SIZE];
+    bytes[offset::MAGIC as usize] = MAGIC;
+    bytes[offset::UNFINISHED_BOOTS as usize] = state.unfinished_boots;
+    bytes[offset::CONSOLE as usize] = state.settings.console as u8;
+    bytes[offset::LOG_LEVEL as usize] = state.settings.log_level as u8;
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    bytes[offset::CHECKSUM as usize] = sum.wrapping_neg();
+    for (index, &byte) in bytes.iter().enumerate() {
+        rtc::write_register(BASE + index as u8, byte);
+    }
+}
+
+/// Reads the settings and counts the boot as in progress; needs nothing but port I/O
+#[link_section = ".init.text"]
+pub fn init() {
+    let mut state = STATE.lock();
+    if let Some(bytes) = load() {
+        state.unfinished_boots = bytes[offset::UNFINISHED_BOOTS as usize];
+        state.settings = Settings {
+            console: Console::from_u8(bytes[offset::CONSOLE as usize]),
+            log_level: LogLevel::from_u8(bytes[offset::LOG_LEVEL as usize]),
+        };
+    }
+    FAILED_BOOTS.store(state.unfinished_boots, Ordering::Relaxed);
+    state.unfinished_boots = state.unfinished_boots.saturating_add(1);
+    store(&state);
+}
+
+pub fn settings() -> Settings {
+    STATE.lock().settings
+}
+
+/// Stores `settings` for the boots to come
+pub fn set(settings: Settings) {
+    let mut state = STATE.lock();
+    state.settings = settings;
+    store(&state);
+}
+
+/// How many boots in a row just before this one counted themselves in and never got to
+/// `boot_complete`, because they hung, panicked or were reset halfway
+pub fn failed_boots() -> u8 {
+    FAILED_BOOTS.load(Ordering::Relaxed)
+}
+
+/// Takes back the count `init` added, and those of the boots that failed before, once the boot
+/// phases are done
+pub fn boot_complete() {
+    let mut state = STATE.lock();
+    state.unfinished_boots = 0;
+    store(&state);
+}
+
+/// `nvram [console text|graphics | loglevel normal|quiet | reset]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let mut settings = settings();
+    match args {
+        [] => {}
+        ["console", "text"] => settings.console = Console::Text,
+        ["console", "graphics"] => settings.console = Console::Graphics,
+        ["loglevel", "normal"] => settings.log_level = LogLevel::Normal,
+        ["loglevel", "quiet"] => settings.log_level = LogLevel::Quiet,
+        ["reset"] => settings = Settings::DEFAULT,
+        _ => return Err("usage: nvram [console text|graphics | loglevel normal|quiet | reset]"),
+    }
+    if !args.is_empty() {
+        set(settings);
+    }
+    println!("{}", settings);
+    match failed_boots() {
+        0 => {}
+        1 => println!("the boot before this one didn't complete"),
+        count => println!("the {} boots before this one didn't complete", count),
+    }
+    Ok(())
+}
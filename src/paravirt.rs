@@ -0,0 +1,248 @@
+//! Which hypervisor the kernel runs under, if any, and the paravirtual shortcuts it offers.
+//!
+//! Hypervisors announce themselves in the CPUID leaves from 0x4000_0000 on: the hypervisor bit
+//! in leaf 1, then a 12-byte signature at the base of each block of 0x100 leaves. KVM, Hyper-V
+//! and VMware are recognized. KVM with Hyper-V enlightenments has both, Hyper-V's block first.
+//!
+//! Subsystems ask `enabled` before taking a shortcut, so on bare metal nothing changes:
+//! - `Feature::Clock`: `clock` uses kvmclock or Hyper-V's reference TSC page.
+//! - `Feature::SpinYield`: a lock that spins for long hands the rest of the time slice to the
+//!   holder's vCPU (KVM's `SCHED_YIELD` hypercall), as the holder is most likely preempted by the
+//!   host rather than slow.
+//! - `Feature::SerialBurst`: the serial console fills the UART's FIFO every time it finds it
+//!   empty instead of checking before every byte; each check is a trip to the hypervisor.
+//!
+//! Each can be switched off with `paravirt <feature> off`, e.g. to compare or to work around a
+//! hypervisor bug.
+
+use crate::{percpu, println};
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use raw_cpuid::CpuId;
+use spin::Once;
+
+const FIRST_LEAF: u32 = 0x4000_0000;
+const LAST_LEAF: u32 = 0x4001_0000;
+
+const KVM_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+const HYPERV_SIGNATURE: &[u8; 12] = b"Microsoft Hv";
+const VMWARE_SIGNATURE: &[u8; 12] = b"VMwareVMware";
+
+/// KVM's leaf after the base with the features (`eax`) and hints (`edx`)
+const KVM_FEATURES_LEAF: u32 = 1;
+const KVM_FEATURE_PV_SCHED_YIELD: u32 = 1 << 13;
+/// vCPUs are never preempted, so yielding to one is pointless
+const KVM_HINTS_REALTIME: u32 = 1 << 0;
+const KVM_HC_SCHED_YIELD: u64 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    HyperV,
+    VMware,
+    /// A hypervisor that signs with something else, or not at all
+    Other,
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Hypervisor::Kvm => "KVM",
+            Hypervisor::HyperV => "Hyper-V",
+            Hypervisor::VMware => "VMware",
+            Hypervisor::Other => "unknown hypervisor",
+        })
+    }
+}
+
+/// What `detect` found
+#[derive(Debug, Clone, Copy)]
+pub struct Paravirt {
+    /// `None` on bare metal
+    pub hypervisor: Option<Hypervisor>,
+    /// First CPUID leaf of each interface, if offered
+    pub kvm_base: Option<u32>,
+    pub hyperv_base: Option<u32>,
+    /// KVM's feature bits, 0 without KVM
+    pub kvm_features: u32,
+    kvm_hints: u32,
+    /// Whether hypercalls are `vmmcall` (AMD) rather than `vmcall` (Intel)
+    vmmcall: bool,
+}
+
+impl Paravirt {
+    fn detect() -> Paravirt {
+        let cpuid = CpuId::new();
+        let in_guest = cpuid
+            .get_feature_info()
+            .is_some_and(|info| info.has_hypervisor());
+        let mut paravirt = Paravirt {
+            hypervisor: in_guest.then_some(Hypervisor::Other),
+            kvm_base: None,
+            hyperv_base: None,
+            kvm_features: 0,
+            kvm_hints: 0,
+            vmmcall: cpuid
+                .get_vendor_info()
+                .is_some_and(|vendor| vendor.as_str() == "AuthenticAMD"),
+        };
+        if !in_guest {
+            return paravirt;
+        }
+        for base in (FIRST_LEAF..LAST_LEAF).step_by(0x100) {
+            let leaf = raw_cpuid::cpuid!(base);
+            let mut signature = [0; 12];
+            signature[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+            signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+            signature[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+            let found = match &signature {
+                KVM_SIGNATURE => {
+                    paravirt.kvm_base.get_or_insert(base);
+                    Hypervisor::Kvm
+                }
+                HYPERV_SIGNATURE => {
+                    paravirt.hyperv_base.get_or_insert(base);
+                    Hypervisor::HyperV
+                }
+                VMWARE_SIGNATURE => Hypervisor::VMware,
+                _ => continue,
+            };
+            // KVM is KVM, whatever it emulates besides
+            if paravirt.hypervisor != Some(Hypervisor::Kvm) {
+                paravirt.hypervisor = Some(found);
+            }
+        }
+        if let Some(base) = paravirt.kvm_base {
+            let leaf = raw_cpuid::cpuid!(base + KVM_FEATURES_LEAF);
+            paravirt.kvm_features = leaf.eax;
+            paravirt.kvm_hints = leaf.edx;
+        }
+        paravirt
+    }
+
+    /// Whether the hypervisor offers `feature`
+    pub fn offers(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Clock => self.kvm_base.is_some() || self.hyperv_base.is_some(),
+            Feature::SpinYield => {
+                self.kvm_features & KVM_FEATURE_PV_SCHED_YIELD != 0
+                    && self.kvm_hints & KVM_HINTS_REALTIME == 0
+            }
+            Feature::SerialBurst => self.hypervisor.is_some(),
+        }
+    }
+}
+
+static PARAVIRT: Once<Paravirt> = Once::new();
+
+/// What the machine is; detected the first time it's asked
+pub fn get() -> &'static Paravirt {
+    PARAVIRT.call_once(Paravirt::detect)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Clock,
+    SpinYield,
+    SerialBurst,
+}
+
+impl Feature {
+    const ALL: [Feature; 3] = [Feature::Clock, Feature::SpinYield, Feature::SerialBurst];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Clock => "clock",
+            Feature::SpinYield => "spin-yield",
+            Feature::SerialBurst => "serial-burst",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Features switched off, one bit each
+static DISABLED: AtomicU32 = AtomicU32::new(0);
+
+/// Whether to use `feature`: the hypervisor offers it and it wasn't switched off
+pub fn enabled(feature: Feature) -> bool {
+    DISABLED.load(Ordering::Relaxed) & feature.bit() == 0 && get().offers(feature)
+}
+
+/// Allows or forbids `feature`. The clock source is chosen once, by `clock::init`; switching
+/// `Clock` later changes nothing.
+pub fn set_enabled(feature: Feature, enabled: bool) {
+    match enabled {
+        true => DISABLED.fetch_and(!feature.bit(), Ordering::Relaxed),
+        false => DISABLED.fetch_or(feature.bit(), Ordering::Relaxed),
+    };
+}
+
+/// Gives up the rest of this vCPU's time slice in favour of CPU `cpu`'s, if `SpinYield` is
+/// enabled; for spinning on a lock `cpu` holds
+pub fn yield_to(cpu: usize) {
+    if !enabled(Feature::SpinYield) {
+        return;
+    }
+    let Some(target) = percpu::get(cpu) else {
+        return;
+    };
+    unsafe { hypercall(KVM_HC_SCHED_YIELD, target.apic_id as u64) };
+}
+
+/// Makes KVM hypercall `number` with one argument and returns its result
+unsafe fn hypercall(number: u64, argument: u64) -> i64 {
+    let result: i64;
+    // LLVM keeps rbx for itself, so the argument is swapped in and out
+    match get().vmmcall {
+        true => asm!(
+            "xchg {argument}, rbx",
+            "vmmcall",
+            "xchg {argument}, rbx",
+            argument = inout(reg) argument => _,
+            inlateout("rax") number as i64 => result,
+            options(nostack),
+        ),
+        false => asm!(
+            "xchg {argument}, rbx",
+            "vmcall",
+            "xchg {argument}, rbx",
+            argument = inout(reg) argument => _,
+            inlateout("rax") number as i64 => result,
+            options(nostack),
+        ),
+    }
+    result
+}
+
+/// `paravirt [<feature> on|off]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {}
+        [name, setting @ ("on" | "off")] => {
+            let feature = Feature::ALL
+                .into_iter()
+                .find(|feature| feature.name() == *name)
+                .ok_or("no such feature")?;
+            set_enabled(feature, *setting == "on");
+        }
+        _ => return Err("usage: paravirt [<feature> on|off]"),
+    }
+    let paravirt = get();
+    match paravirt.hypervisor {
+        Some(hypervisor) => println!("paravirt: running on {}", hypervisor),
+        None => println!("paravirt: running on bare metal"),
+    }
+    for feature in Feature::ALL {
+        let state = match (paravirt.offers(feature), enabled(feature)) {
+            (false, _) => "not offered",
+            (true, true) => "on",
+            (true, false) => "off",
+        };
+        println!("  {:<14} {}", feature.name(), state);
+    }
+    Ok(())
+}
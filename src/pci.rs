@@ -0,0 +1,672 @@
+//! PCI bus enumeration through the legacy configuration mechanism (ports 0xCF8/0xCFC).
+//!
+//! `init` scans every bus once and caches what it finds; `devices()` iterates that list. Drivers
+//! register with `register_driver` and get `probe`d for each unclaimed device they match, whether
+//! it was found before or after they registered.
+//!
+//! `lspci` and `/proc/pci` show what was found, and with `-v` (always, for `/proc/pci`) the
+//! details of each function: its driver, interrupt, BARs and decoded capabilities (see
+//! `capability`).
+
+use crate::arch::port::Port;
+use crate::{println, warnln};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use capability::Capabilities;
+use core::fmt::{self, Write};
+use spin::{Mutex, Once};
+
+pub mod capability;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Configuration space offsets shared by every header type
+mod reg {
+    pub const VENDOR_ID: u8 = 0x00;
+    pub const COMMAND: u8 = 0x04;
+    pub const REVISION_CLASS: u8 = 0x08;
+    pub const HEADER_TYPE: u8 = 0x0E;
+    pub const BAR0: u8 = 0x10;
+    pub const SECONDARY_BUS: u8 = 0x19;
+    pub const CAPABILITIES_POINTER: u8 = 0x34;
+    pub const INTERRUPT_LINE: u8 = 0x3C;
+}
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+
+/// The address/data port pair, locked because a configuration access takes two port writes
+struct ConfigSpace {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+static CONFIG_SPACE: Mutex<ConfigSpace> = Mutex::new(unsafe {
+    ConfigSpace {
+        address: Port::new(CONFIG_ADDRESS),
+        data: Port::new(CONFIG_DATA),
+    }
+});
+
+/// Writes the command register. The status register shares its doubleword and has write-one-
+/// to-clear bits, so it gets zeros rather than whatever was read back.
+fn write_command(address: PciAddress, command: u16) {
+    address.write_config(reg::COMMAND, command as u32);
+}
+
+/// Location of a function on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31 // enable bit
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Reads the aligned doubleword containing `offset`
+    pub fn read_config(&self, offset: u8) -> u32 {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut config = CONFIG_SPACE.lock();
+            config.address.write(self.config_address(offset));
+            config.data.read()
+        })
+    }
+
+    /// Writes the aligned doubleword containing `offset`
+    pub fn write_config(&self, offset: u8, value: u32) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut config = CONFIG_SPACE.lock();
+            config.address.write(self.config_address(offset));
+            config.data.write(value);
+        })
+    }
+
+    pub fn read_config_u16(&self, offset: u8) -> u16 {
+        (self.read_config(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_config_u8(&self, offset: u8) -> u8 {
+        (self.read_config(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_config_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_config(offset) & !(0xFFFF << shift);
+        self.write_config(offset, old | (value as u32) << shift);
+    }
+
+    pub fn write_config_u8(&self, offset: u8, value: u8) {
+        let shift = (offset & 3) * 8;
+        let old = self.read_config(offset) & !(0xFF << shift);
+        self.write_config(offset, old | (value as u32) << shift);
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A decoded Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// A PCI function as found during enumeration
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Layout of the rest of the header: 0 for devices, 1 for PCI-to-PCI bridges
+    pub header_type: u8,
+    /// Legacy (PIC/IO-APIC) interrupt line the firmware routed, 0xFF if none
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the function doesn't use a pin
+    pub interrupt_pin: u8,
+    /// BARs by index; a 64-bit BAR takes two slots, the upper one is `None`
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let ids = address.read_config(reg::VENDOR_ID);
+        let vendor_id = ids as u16;
+        if vendor_id == 0xFFFF {
+            return None; // nothing answers at this address
+        }
+        let class = address.read_config(reg::REVISION_CLASS);
+        let interrupt = address.read_config(reg::INTERRUPT_LINE);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: address.read_config_u8(reg::HEADER_TYPE) & !HEADER_TYPE_MULTIFUNCTION,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: [None; 6],
+        };
+        let bar_count = match device.header_type {
+            0x00 => 6,
+            HEADER_TYPE_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = device.decode_bar(index);
+            device.bars[index] = bar;
+            index += slots;
+        }
+        Some(device)
+    }
+
+    /// Decodes BAR `index` and returns it with the number of slots it occupies.
+    ///
+    /// Sizing writes all ones to the register, so decoding is switched off meanwhile; otherwise
+    /// the device would briefly claim a bogus address range.
+    fn decode_bar(&self, index: usize) -> (Option<Bar>, usize) {
+        let offset = reg::BAR0 + index as u8 * 4;
+        let address = self.address;
+        let command = address.read_config_u16(reg::COMMAND);
+        write_command(
+            address,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let size_of = |offset: u8| {
+            let original = address.read_config(offset);
+            address.write_config(offset, 0xFFFF_FFFF);
+            let mask = address.read_config(offset);
+            address.write_config(offset, original);
+            (original, mask)
+        };
+
+        let (low, low_mask) = size_of(offset);
+        let result = if low & 1 == 1 {
+            let mask = low_mask & !0b11;
+            let size = (!mask).wrapping_add(1) & 0xFFFF;
+            let bar = (mask != 0).then_some(Bar::Io {
+                port: (low & !0b11) as u16,
+                size,
+            });
+            (bar, 1)
+        } else {
+            let is_64bit = (low >> 1) & 0b11 == 0b10 && index < 5;
+            let prefetchable = low & 0b1000 != 0;
+            // a 32-bit BAR behaves like a 64-bit one whose upper half is hardwired to ones
+            let (high, high_mask) = if is_64bit {
+                size_of(offset + 4)
+            } else {
+                (0, 0xFFFF_FFFF)
+            };
+            let mask = (high_mask as u64) << 32 | (low_mask & !0xF) as u64;
+            let bar = (low_mask & !0xF != 0).then_some(Bar::Memory {
+                address: (high as u64) << 32 | (low & !0xF) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+                is_64bit,
+            });
+            (bar, if is_64bit { 2 } else { 1 })
+        };
+
+        write_command(address, command);
+        result
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_config_u16(reg::COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        write_command(self.address, command);
+    }
+
+    /// Lets the device decode its BARs and act as a DMA master
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    /// Offset of the first entry in the capability list, if the device has one
+    pub fn capabilities_pointer(&self) -> Option<u8> {
+        const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+        if self.address.read_config(reg::COMMAND) & STATUS_CAPABILITIES_LIST == 0 {
+            return None;
+        }
+        match self.address.read_config_u8(reg::CAPABILITIES_POINTER) & 0xFC {
+            0 => None,
+            pointer => Some(pointer),
+        }
+    }
+
+    /// The entries of the capability list, in list order
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            address: self.address,
+            next: self.capabilities_pointer(),
+            seen: 0,
+        }
+    }
+
+    /// Human-readable class name from the built-in table
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+
+    /// The `lspci -v` view: the summary line, then the driver, interrupt, BARs and capabilities
+    pub fn describe(&self, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{}", self)?;
+        if let Some(driver) = driver_of(self.address) {
+            writeln!(out, "    driver: {}", driver)?;
+        }
+        if self.interrupt_pin != 0 {
+            let pin = (b'A' + self.interrupt_pin - 1) as char;
+            match self.interrupt_line {
+                0xFF => writeln!(out, "    interrupt: pin {}, not routed", pin)?,
+                line => writeln!(out, "    interrupt: pin {}, routed to IRQ {}", pin, line)?,
+            }
+        }
+        let command = self.command();
+        for (index, bar) in self.bars.iter().enumerate() {
+            match *bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                }) => writeln!(
+                    out,
+                    "    BAR{}: memory at {:#x} ({}-bit, {}prefetchable) [size={}]{}",
+                    index,
+                    address,
+                    if is_64bit { 64 } else { 32 },
+                    if prefetchable { "" } else { "non-" },
+                    Size(size),
+                    if command & COMMAND_MEMORY_SPACE == 0 {
+                        " [disabled]"
+                    } else {
+                        ""
+                    }
+                )?,
+                Some(Bar::Io { port, size }) => writeln!(
+                    out,
+                    "    BAR{}: I/O ports at {:#x} [size={}]{}",
+                    index,
+                    port,
+                    size,
+                    if command & COMMAND_IO_SPACE == 0 {
+                        " [disabled]"
+                    } else {
+                        ""
+                    }
+                )?,
+                None => {}
+            }
+        }
+        for capability in self.capabilities() {
+            write!(out, "    ")?;
+            capability::describe(self.address, capability, out)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// A BAR size the way `lspci` writes it: in K, M or G where it's a whole number of them
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.0;
+        for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+            if size >= 1 << shift && size.is_multiple_of(1 << shift) {
+                return write!(f, "{}{}", size >> shift, unit);
+            }
+        }
+        write!(f, "{}", size)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {} [{:04x}:{:04x}] (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            vendor_name(self.vendor_id).unwrap_or("Unknown vendor"),
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
+
+/// Names for the class/subclass pairs a small kernel is likely to meet
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0x0D, _) => "Wireless controller",
+        _ => "Unknown class",
+    }
+}
+
+/// Names for common vendor IDs
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x1022 => "AMD",
+        0x10DE => "NVIDIA",
+        0x10EC => "Realtek",
+        0x1234 => "QEMU",
+        0x15AD => "VMware",
+        0x1AF4 => "Red Hat (virtio)",
+        0x1B36 => "Red Hat (QEMU)",
+        0x8086 => "Intel",
+        0x80EE => "VirtualBox",
+        _ => return None,
+    })
+}
+
+/// Which devices a driver handles; `None` fields match anything
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    Id {
+        vendor_id: u16,
+        device_id: Option<u16>,
+    },
+    Class {
+        class: u8,
+        subclass: Option<u8>,
+        prog_if: Option<u8>,
+    },
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            DeviceMatch::Id {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device_id.is_none_or(|id| id == device.device_id),
+            DeviceMatch::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && subclass.is_none_or(|subclass| subclass == device.subclass)
+                    && prog_if.is_none_or(|prog_if| prog_if == device.prog_if)
+            }
+        }
+    }
+}
+
+/// A PCI driver: `probe` is called once per matching device not claimed by another driver, and
+/// claims it by returning `Ok`
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    pub probe: fn(&PciDevice) -> Result<(), &'static str>,
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+struct Drivers {
+    registered: Vec<&'static Driver>,
+    /// Which driver owns which device
+    claimed: BTreeMap<PciAddress, &'static str>,
+}
+
+static DRIVERS: Mutex<Drivers> = Mutex::new(Drivers {
+    registered: Vec::new(),
+    claimed: BTreeMap::new(),
+});
+
+fn scan_function(address: PciAddress, found: &mut Vec<PciDevice>) {
+    let device = match PciDevice::probe(address) {
+        Some(device) => device,
+        None => return,
+    };
+    let bridge_to = (device.class == 0x06 && device.subclass == 0x04)
+        .then(|| address.read_config_u8(reg::SECONDARY_BUS));
+    found.push(device);
+    if let Some(secondary) = bridge_to {
+        if secondary > address.bus {
+            scan_bus(secondary, found); // the firmware numbers buses below bridges upwards
+        }
+    }
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let address = PciAddress::new(bus, device, 0);
+        if address.read_config(reg::VENDOR_ID) as u16 == 0xFFFF {
+            continue;
+        }
+        scan_function(address, found);
+        if address.read_config_u8(reg::HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION != 0 {
+            for function in 1..8 {
+                scan_function(PciAddress::new(bus, device, function), found);
+            }
+        }
+    }
+}
+
+fn enumerate() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    let host = PciAddress::new(0, 0, 0);
+    if host.read_config_u8(reg::HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION == 0 {
+        scan_bus(0, &mut found);
+    } else {
+        // several host controllers, function n of the host bridge is responsible for bus n
+        for function in 0..8 {
+            if PciAddress::new(0, 0, function).read_config(reg::VENDOR_ID) as u16 != 0xFFFF {
+                scan_bus(function, &mut found);
+            }
+        }
+    }
+    found
+}
+
+/// Scans the buses and probes the drivers registered so far
+pub fn init() {
+    DEVICES.call_once(enumerate);
+    let drivers: Vec<&'static Driver> = DRIVERS.lock().registered.clone();
+    for driver in drivers {
+        probe_driver(driver);
+    }
+}
+
+/// The standard header of every device, kept across sleep by `save_config` and `restore_config`
+static SAVED_CONFIG: Mutex<Vec<(PciAddress, [u32; 16])>> = Mutex::new(Vec::new());
+
+/// Saves the configuration header of every device, whose BARs and command register are gone
+/// after waking from sleep
+pub fn save_config() {
+    let saved = devices()
+        .map(|device| {
+            let address = device.address;
+            let header = core::array::from_fn(|index| address.read_config(index as u8 * 4));
+            (address, header)
+        })
+        .collect();
+    *SAVED_CONFIG.lock() = saved;
+}
+
+/// Writes back what `save_config` saved. Bridges come before the devices behind them, and in each
+/// header the BARs go before the command register that lets the device decode them.
+pub fn restore_config() {
+    for (address, header) in SAVED_CONFIG.lock().drain(..) {
+        // the first three doublewords are the read-only ids and class, and the command register
+        for (index, &value) in header.iter().enumerate().skip(3).rev() {
+            address.write_config(index as u8 * 4, value);
+        }
+        write_command(address, header[reg::COMMAND as usize / 4] as u16);
+    }
+}
+
+/// Every function found by `init`, in bus order; empty before `init`
+pub fn devices() -> impl Iterator<Item = &'static PciDevice> {
+    DEVICES
+        .r#try()
+        .map(|devices| devices.iter())
+        .into_iter()
+        .flatten()
+}
+
+/// Finds the device at `address`
+pub fn device(address: PciAddress) -> Option<&'static PciDevice> {
+    devices().find(|device| device.address == address)
+}
+
+/// Adds a driver and probes it against the devices already found
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().registered.push(driver);
+    if DEVICES.r#try().is_some() {
+        probe_driver(driver);
+    }
+}
+
+/// The driver that claimed `address`, if any
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    DRIVERS.lock().claimed.get(&address).copied()
+}
+
+fn probe_driver(driver: &'static Driver) {
+    for device in devices() {
+        if !driver.matches.iter().any(|m| m.matches(device)) {
+            continue;
+        }
+        if DRIVERS.lock().claimed.contains_key(&device.address) {
+            continue;
+        }
+        // probe without the lock held, drivers may look up other devices or register more drivers
+        match (driver.probe)(device) {
+            Ok(()) => {
+                DRIVERS.lock().claimed.insert(device.address, driver.name);
+            }
+            Err(err) => warnln!("pci: {} at {}: {}", driver.name, device.address, err),
+        }
+    }
+}
+
+/// Parses `bus:device.function` in hex, as `PciAddress` displays it
+fn parse_address(text: &str) -> Option<PciAddress> {
+    let (bus, rest) = text.split_once(':')?;
+    let (device, function) = rest.split_once('.')?;
+    let device = u8::from_str_radix(device, 16)
+        .ok()
+        .filter(|&device| device < 32)?;
+    let function = function.parse().ok().filter(|&function| function < 8)?;
+    Some(PciAddress::new(
+        u8::from_str_radix(bus, 16).ok()?,
+        device,
+        function,
+    ))
+}
+
+/// Every function with its details, as `/proc/pci` shows them
+pub fn text() -> String {
+    let mut text = String::new();
+    for device in devices() {
+        let _ = device.describe(&mut text);
+    }
+    text
+}
+
+/// `lspci [-v] [<bus:device.function>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let (verbose, args) = match args {
+        ["-v", rest @ ..] => (true, rest),
+        _ => (false, args),
+    };
+    let selected: Vec<&PciDevice> = match args {
+        [] => devices().collect(),
+        [address] => {
+            let address = parse_address(address).ok_or("expected <bus:device.function>")?;
+            alloc::vec![device(address).ok_or("no such device")?]
+        }
+        _ => return Err("usage: lspci [-v] [<bus:device.function>]"),
+    };
+    // a single device is shown in full anyway
+    let verbose = verbose || !args.is_empty();
+    for device in selected {
+        match verbose {
+            true => {
+                let mut text = String::new();
+                let _ = device.describe(&mut text);
+                crate::print!("{}", text);
+            }
+            false => println!("{}", device),
+        }
+    }
+    Ok(())
+}
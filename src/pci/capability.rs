@@ -0,0 +1,196 @@
+//! The capability list: the linked list of extra register blocks a function advertises in its
+//! configuration space, found through `PciDevice::capabilities`.
+//!
+//! Every entry starts with its id and the offset of the next one. `describe` decodes the ones a
+//! driver author most often needs to look at: power management, MSI, MSI-X and PCI Express.
+//! Others are only named.
+
+use super::PciAddress;
+use core::fmt::{self, Write};
+
+/// Capability ids
+pub mod id {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const AGP: u8 = 0x02;
+    pub const VPD: u8 = 0x03;
+    pub const SLOT_ID: u8 = 0x04;
+    pub const MSI: u8 = 0x05;
+    pub const HYPERTRANSPORT: u8 = 0x08;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const DEBUG_PORT: u8 = 0x0A;
+    pub const BRIDGE_SUBSYSTEM: u8 = 0x0D;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSI_X: u8 = 0x11;
+    pub const SATA: u8 = 0x12;
+    pub const ADVANCED_FEATURES: u8 = 0x13;
+}
+
+/// Most entries a list is followed for, in case a broken device links it into a loop
+const MAX_ENTRIES: usize = 48;
+
+pub fn name(id: u8) -> &'static str {
+    match id {
+        id::POWER_MANAGEMENT => "Power Management",
+        id::AGP => "AGP",
+        id::VPD => "Vital Product Data",
+        id::SLOT_ID => "Slot Identification",
+        id::MSI => "MSI",
+        id::HYPERTRANSPORT => "HyperTransport",
+        id::VENDOR_SPECIFIC => "Vendor Specific",
+        id::DEBUG_PORT => "Debug Port",
+        id::BRIDGE_SUBSYSTEM => "Bridge Subsystem ID",
+        id::PCI_EXPRESS => "PCI Express",
+        id::MSI_X => "MSI-X",
+        id::SATA => "SATA",
+        id::ADVANCED_FEATURES => "Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+/// One entry of the list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// Where it starts in configuration space
+    pub offset: u8,
+    pub id: u8,
+}
+
+/// Iterator over a function's capability list
+pub struct Capabilities {
+    pub(super) address: PciAddress,
+    pub(super) next: Option<u8>,
+    pub(super) seen: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        let offset = self.next?;
+        self.seen += 1;
+        self.next = match self.address.read_config_u8(offset + 1) & 0xFC {
+            next if next != 0 && self.seen < MAX_ENTRIES => Some(next),
+            _ => None,
+        };
+        Some(Capability {
+            offset,
+            id: self.address.read_config_u8(offset),
+        })
+    }
+}
+
+/// `+` or `-` for a flag, as `lspci` shows them
+fn sign(set: bool) -> char {
+    if set {
+        '+'
+    } else {
+        '-'
+    }
+}
+
+/// Writes what `capability` of the function at `address` says, on one line without its end
+pub fn describe(address: PciAddress, capability: Capability, out: &mut dyn Write) -> fmt::Result {
+    let offset = capability.offset;
+    write!(out, "[{:02x}] {}", offset, name(capability.id))?;
+    match capability.id {
+        id::POWER_MANAGEMENT => {
+            let capabilities = address.read_config_u16(offset + 2);
+            let status = address.read_config_u16(offset + 4);
+            write!(
+                out,
+                ": version {}, D1{} D2{}, PME from D0{} D1{} D2{} D3hot{} D3cold{}; in D{}",
+                capabilities & 0b111,
+                sign(capabilities & 1 << 9 != 0),
+                sign(capabilities & 1 << 10 != 0),
+                sign(capabilities & 1 << 11 != 0),
+                sign(capabilities & 1 << 12 != 0),
+                sign(capabilities & 1 << 13 != 0),
+                sign(capabilities & 1 << 14 != 0),
+                sign(capabilities & 1 << 15 != 0),
+                status & 0b11
+            )
+        }
+        id::MSI => {
+            let control = address.read_config_u16(offset + 2);
+            let is_64bit = control & 1 << 7 != 0;
+            let low = address.read_config(offset + 4) as u64;
+            let (message, data) = match is_64bit {
+                true => (
+                    (address.read_config(offset + 8) as u64) << 32 | low,
+                    address.read_config_u16(offset + 12),
+                ),
+                false => (low, address.read_config_u16(offset + 8)),
+            };
+            write!(
+                out,
+                ": enable{} {}-bit, vectors {}/{}, masking{}; address {:#x} data {:#06x}",
+                sign(control & 1 != 0),
+                if is_64bit { 64 } else { 32 },
+                1 << ((control >> 4) & 0b111),
+                1 << ((control >> 1) & 0b111),
+                sign(control & 1 << 8 != 0),
+                message,
+                data
+            )
+        }
+        id::MSI_X => {
+            let control = address.read_config_u16(offset + 2);
+            let table = address.read_config(offset + 4);
+            let pending = address.read_config(offset + 8);
+            write!(
+                out,
+                ": enable{} masked{}, {} vectors; table at BAR{}+{:#x}, pending bits at BAR{}+{:#x}",
+                sign(control & 1 << 15 != 0),
+                sign(control & 1 << 14 != 0),
+                (control & 0x7FF) + 1,
+                table & 0b111,
+                table & !0b111,
+                pending & 0b111,
+                pending & !0b111
+            )
+        }
+        id::PCI_EXPRESS => describe_express(address, offset, out),
+        id::VENDOR_SPECIFIC => write!(out, ", {} bytes", address.read_config_u8(offset + 2)),
+        _ => Ok(()),
+    }
+}
+
+/// The PCI Express capability: what kind of port or endpoint, and its link
+fn describe_express(address: PciAddress, offset: u8, out: &mut dyn Write) -> fmt::Result {
+    let capabilities = address.read_config_u16(offset + 2);
+    let kind = match (capabilities >> 4) & 0xF {
+        0 => "Endpoint",
+        1 => "Legacy Endpoint",
+        4 => "Root Port",
+        5 => "Upstream Port",
+        6 => "Downstream Port",
+        7 => "PCIe to PCI bridge",
+        8 => "PCI to PCIe bridge",
+        9 => "Root Complex Integrated Endpoint",
+        10 => "Root Complex Event Collector",
+        _ => "Unknown type",
+    };
+    write!(out, " v{}: {}", capabilities & 0xF, kind)?;
+    let link_capabilities = address.read_config(offset + 0x0C);
+    if link_capabilities == 0 {
+        return Ok(()); // no link, e.g. integrated in the root complex
+    }
+    let link_status = address.read_config_u16(offset + 0x12) as u32;
+    let speed = |code: u32| match code {
+        1 => "2.5GT/s",
+        2 => "5GT/s",
+        3 => "8GT/s",
+        4 => "16GT/s",
+        5 => "32GT/s",
+        6 => "64GT/s",
+        _ => "unknown speed",
+    };
+    write!(
+        out,
+        "; link {} x{} of {} x{}",
+        speed(link_status & 0xF),
+        (link_status >> 4) & 0x3F,
+        speed(link_capabilities & 0xF),
+        (link_capabilities >> 4) & 0x3F
+    )
+}
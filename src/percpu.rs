@@ -0,0 +1,145 @@
+//! Per-CPU state reached through the GS segment base.
+//!
+//! Every CPU leaks one `PerCpu` during bring-up and points its GS base at it. The struct starts
+//! with a pointer to itself, so `current()` is a single `gs`-relative load and never needs a lock.
+//! That holds in ring 0 only: in ring 3 the GS base is the program's, and the kernel's waits in
+//! `IA32_KERNEL_GS_BASE` for the `swapgs` that every entry from ring 3 starts with (see
+//! `usermode`).
+//!
+//! A CPU keeps its index and its `PerCpu` for good, also across being taken offline and back
+//! online (see `smp::offline`); the online ones are tracked in a bitmask next to them.
+
+use crate::allocator::quota::Account;
+use crate::process::Thread;
+use crate::usermode::Context;
+use crate::{memory, numa};
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+/// Upper bound on the number of CPUs the kernel brings up
+pub const MAX_CPUS: usize = 64;
+
+/// Number of CPUs that ever finished `init`; they have indices below it
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Bit `index` is set while that CPU is online
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+/// Every CPU's data by index, null for indices no CPU has
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: *const PerCpu, // must stay the first field, see `current`
+    /// Dense CPU number, 0 is the boot CPU
+    pub index: usize,
+    /// Local APIC id, which is what IPIs and IO-APIC routes are addressed to
+    pub apic_id: u32,
+    /// The NUMA node the CPU belongs to, whose frames it gets first
+    pub node: usize,
+    /// The heap account allocations are charged to, null for the kernel's (see `allocator::quota`)
+    pub heap_account: AtomicPtr<Account>,
+    /// Where to go back to when the program running in ring 3 exits, null if none is (see
+    /// `usermode::run`)
+    pub user_context: AtomicPtr<Context>,
+    /// Top of the stack `syscall` switches to; read by the entry code at a fixed offset
+    pub syscall_stack: AtomicU64,
+    /// The user stack pointer while a system call runs, saved by the entry code
+    pub user_rsp: AtomicU64,
+    /// The process thread this CPU is running, null between time slices (see `process`)
+    pub thread: AtomicPtr<Thread>,
+    /// Id of the thread whose FPU state the registers hold, 0 for none (see `fpu`)
+    pub fpu_owner: AtomicU64,
+    /// How often preemption is disabled, and whether a reschedule waits for it to be enabled
+    /// (see `sync::preempt`)
+    pub preempt_count: AtomicUsize,
+    pub preempt_pending: AtomicBool,
+}
+
+/// Allocates this CPU's `PerCpu`, or finds the one it had before it went offline, installs it in
+/// the GS base and marks the CPU online
+pub fn init(index: usize, apic_id: u32) {
+    if let Some(per_cpu) = get(index) {
+        set_bases(per_cpu);
+        set_online(index, true);
+        return;
+    }
+
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: ptr::null(),
+        index,
+        apic_id,
+        node: numa::cpu_node(apic_id),
+        heap_account: AtomicPtr::new(ptr::null_mut()),
+        user_context: AtomicPtr::new(ptr::null_mut()),
+        syscall_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+        thread: AtomicPtr::new(ptr::null_mut()),
+        fpu_owner: AtomicU64::new(0),
+        preempt_count: AtomicUsize::new(0),
+        preempt_pending: AtomicBool::new(false),
+    }));
+    per_cpu.self_ptr = per_cpu;
+    set_bases(per_cpu);
+
+    let root = Cr3::read().0.start_address().as_u64();
+    memory::PAGE_TABLE_ROOTS.update(|roots| roots.push((index, root)));
+    CPUS[index].store(per_cpu, Ordering::SeqCst);
+    CPU_COUNT.fetch_max(index + 1, Ordering::SeqCst);
+    set_online(index, true);
+}
+
+/// Points this CPU's GS base at `per_cpu`, and zeroes the one ring 3 starts with
+fn set_bases(per_cpu: &PerCpu) {
+    GsBase::write(VirtAddr::from_ptr(per_cpu as *const PerCpu));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// The calling CPU's data; `init` must have run on this CPU
+pub fn current() -> &'static PerCpu {
+    let per_cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) per_cpu, options(nostack, readonly, preserves_flags));
+        &*per_cpu
+    }
+}
+
+/// The calling CPU's data, or `None` if `init` hasn't run on it yet. Slower than `current`, as it
+/// has to read the GS base MSR.
+pub fn try_current() -> Option<&'static PerCpu> {
+    match GsBase::read().as_u64() {
+        0 => None,
+        _ => Some(current()),
+    }
+}
+
+/// The data of the CPU with `index`, if one ever finished `init`
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    let per_cpu = CPUS.get(index)?.load(Ordering::SeqCst);
+    unsafe { per_cpu.as_ref() }
+}
+
+/// Number of CPUs brought up since boot, online or not; every index below it has a `PerCpu`
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Number of CPUs online right now
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::SeqCst).count_ones() as usize
+}
+
+pub fn is_online(index: usize) -> bool {
+    index < MAX_CPUS && ONLINE.load(Ordering::SeqCst) & 1 << index != 0
+}
+
+/// Records that the CPU with `index` went online or offline
+pub fn set_online(index: usize, online: bool) {
+    match online {
+        true => ONLINE.fetch_or(1 << index, Ordering::SeqCst),
+        false => ONLINE.fetch_and(!(1 << index), Ordering::SeqCst),
+    };
+}
@@ -0,0 +1,100 @@
+use crate::arch::port::{Port, WriteOnlyPort};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Input clock of the 8253/8254 Programmable Interval Timer in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Bit 0 gates channel 2, bit 1 feeds its output to the speaker, bit 5 reads it
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Channel 0's reload value, 0 until `set_frequency` ran
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+/// Channel 2's reload value while it drives the speaker, 0 while it doesn't
+static TONE: AtomicU32 = AtomicU32::new(0);
+
+/// Programs channel 0 as a rate generator firing IRQ0 `hz` times per second
+pub fn set_frequency(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz).clamp(1, u16::MAX as u32) as u16;
+    DIVISOR.store(divisor as u32, Ordering::Relaxed);
+
+    let mut command = unsafe { WriteOnlyPort::<u8>::new(COMMAND) };
+    let mut channel_0 = unsafe { Port::<u8>::new(CHANNEL_0) };
+    command.write(0b0011_0100); // channel 0, lobyte/hibyte, mode 2 (rate generator), binary
+    channel_0.write(divisor as u8);
+    channel_0.write((divisor >> 8) as u8);
+}
+
+/// Nanoseconds since channel 0 last reached terminal count and raised IRQ0, read off its latched
+/// count; `None` before `set_frequency`. A count that ran out again since can't be told apart.
+pub fn elapsed_ns() -> Option<u64> {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    if divisor == 0 {
+        return None;
+    }
+    let mut command = unsafe { WriteOnlyPort::<u8>::new(COMMAND) };
+    let mut channel_0 = unsafe { Port::<u8>::new(CHANNEL_0) };
+    command.write(0b0000_0000); // channel 0, latch the current count
+    let count = u16::from_le_bytes([channel_0.read(), channel_0.read()]);
+    let elapsed = divisor.saturating_sub(count as u32) as u64;
+    Some(elapsed * 1_000_000_000 / PIT_FREQUENCY as u64)
+}
+
+/// Busy-waits for `ms` milliseconds (at most 54) using channel 2 in one-shot mode.
+///
+/// Channel 2 isn't wired to an IRQ, so this works before interrupts are set up and is what the
+/// APIC timer calibration measures against. A tone playing stops for the wait and goes on after.
+pub fn wait_ms(ms: u32) {
+    let count = (PIT_FREQUENCY / 1000 * ms).min(u16::MAX as u32) as u16;
+
+    let mut command = unsafe { WriteOnlyPort::<u8>::new(COMMAND) };
+    let mut channel_2 = unsafe { Port::<u8>::new(CHANNEL_2) };
+    let mut speaker_control = unsafe { Port::<u8>::new(SPEAKER_CONTROL) };
+
+    let control = speaker_control.read();
+    speaker_control.write((control & !0b10) | 0b01); // gate high, speaker data off
+
+    command.write(0b1011_0000); // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+
+    // re-trigger the gate so counting starts now
+    let control = speaker_control.read();
+    speaker_control.write(control & !0b01);
+    speaker_control.write(control | 0b01);
+
+    while speaker_control.read() & 0b10_0000 == 0 {} // output goes high on terminal count
+
+    let tone = TONE.load(Ordering::Relaxed);
+    if tone != 0 {
+        play(tone as u16);
+    }
+}
+
+/// Has channel 2 drive the speaker with a square wave of `hz` (from 19 Hz up) until `stop_tone`
+pub fn start_tone(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz.max(1)).clamp(1, u16::MAX as u32) as u16;
+    TONE.store(divisor as u32, Ordering::Relaxed);
+    play(divisor);
+}
+
+pub fn stop_tone() {
+    TONE.store(0, Ordering::Relaxed);
+    let mut speaker_control = unsafe { Port::<u8>::new(SPEAKER_CONTROL) };
+    let control = speaker_control.read();
+    speaker_control.write(control & !0b11);
+}
+
+/// Programs channel 2 as a square wave generator with `divisor` and connects it to the speaker
+fn play(divisor: u16) {
+    let mut command = unsafe { WriteOnlyPort::<u8>::new(COMMAND) };
+    let mut channel_2 = unsafe { Port::<u8>::new(CHANNEL_2) };
+    let mut speaker_control = unsafe { Port::<u8>::new(SPEAKER_CONTROL) };
+    command.write(0b1011_0110); // channel 2, lobyte/hibyte, mode 3 (square wave), binary
+    channel_2.write(divisor as u8);
+    channel_2.write((divisor >> 8) as u8);
+    let control = speaker_control.read();
+    speaker_control.write(control | 0b11); // gate high, speaker data on
+}
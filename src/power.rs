@@ -0,0 +1,508 @@
+//! Power management: suspend to RAM, turning the machine off and restarting it.
+//!
+//! `shutdown` and `restart` first take the system down in order, so nothing buffered is lost:
+//!
+//! 1. every process ends, so no program writes anything after its files were written back;
+//! 2. the filesystems write back what they hold, through the block cache, and every block device
+//!    flushes its write cache;
+//! 3. registered `Device`s shut down, which stops the drivers' DMA into memory a restarted kernel
+//!    will reuse;
+//! 4. the application processors go offline.
+//!
+//! A step that fails is reported and the next one taken anyway. Then `shutdown` tries the ACPI
+//! soft-off state, S5, and failing that the ports emulators turn off at; `reboot` tries the
+//! FADT's reset register, the keyboard controller's reset line and at last a triple fault. Both
+//! are shell commands, and programs have them through the `reboot` system call.
+//!
+//! Suspend to RAM is the ACPI S3 sleep state.
+//! In S3 only memory keeps its contents; the CPUs, the interrupt controllers, the timers and the
+//! PCI devices all lose power. `suspend` gets everything ready for that, one layer after the
+//! other:
+//!
+//! 1. the application processors go offline, and registered `Device`s flush what they hold and
+//!    may refuse, which calls the whole thing off;
+//! 2. with interrupts disabled, the PCI configuration headers and the interrupt controllers'
+//!    programming are saved, the clock is noted, and the FACS's waking vector is pointed at the
+//!    SMP trampoline, set up to enter `resume_entry`;
+//! 3. `power_sleep` saves the callee-saved registers and the stack pointer and writes the sleep
+//!    type from the DSDT's `\_S3` package to the PM1 control registers.
+//!
+//! On waking the firmware jumps to the waking vector in real mode. The trampoline brings the boot
+//! CPU into long mode on the kernel's page tables, and `resume_entry` loads the GDT, IDT and PAT
+//! again and switches back to the stack `power_sleep` was called on, which then returns as if the
+//! machine had never slept. Everything else is put back in the opposite order it was saved in.
+//!
+//! The AML methods firmware may want run around sleep (`\_PTS`, `\_WAK`) aren't, as there's no
+//! interpreter. QEMU and many boards don't need them; those that use them to arm wake-up sources
+//! might not wake, or not by every means.
+
+use crate::acpi::{self, Fadt, GenericAddress};
+use crate::arch::port::{inb, inw, outb, outl, outw};
+use crate::{
+    block, clock, errorln, fpu, fs, gdt, interrupts, memory, pci, percpu, pit, println, process,
+    smp, syscall, time, warnln,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod battery;
+pub mod events;
+
+/// Bits of the PM1 control registers
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+/// Bit of the PM1 status registers the hardware sets on waking; written with a one to clear it
+const WAK_STS: u16 = 1 << 15;
+
+/// Offsets in the Firmware ACPI Control Structure
+mod facs {
+    pub const WAKING_VECTOR: usize = 12;
+    pub const X_WAKING_VECTOR: usize = 24;
+    pub const VERSION: usize = 32;
+}
+
+const RESUME_STACK_SIZE: u64 = 4096 * 4;
+
+/// A device whose driver has to act around sleep or before the machine goes down. Most have to set
+/// the device up again after waking, as it comes out of sleep reset.
+pub trait Device: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Gets the device ready to lose power, e.g. by writing back caches. An error calls the
+    /// suspend off.
+    fn suspend(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Brings the device back after waking; its PCI configuration is already restored
+    fn resume(&self) -> Result<(), &'static str>;
+
+    /// Stops the device for good before the machine turns off or restarts; by default the same
+    /// as `suspend`. An error is only reported.
+    fn shutdown(&self) -> Result<(), &'static str> {
+        self.suspend()
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn Device>>> = Mutex::new(Vec::new());
+
+/// Has `device` suspended, resumed and shut down with the machine. Devices suspend and shut down
+/// in the order they registered in and resume in the opposite one.
+pub fn register(device: Arc<dyn Device>) {
+    DEVICES.lock().push(device);
+}
+
+global_asm!(
+    r#"
+    .global power_sleep
+    power_sleep:
+        pushq %rbx
+        pushq %rbp
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        movq %rsp, (%r8)
+        wbinvd                          # memory is all that's left, no line may stay dirty
+
+        movl %edx, %eax
+        movl %edi, %edx
+        outw %ax, %dx
+        testq %rsi, %rsi
+        jz 1f
+        movl %ecx, %eax
+        movl %esi, %edx
+        outw %ax, %dx
+
+    1:  movl $10000000, %ecx            # the power is long gone before this runs out
+    2:  pause
+        decl %ecx
+        jnz 2b
+        xorl %eax, %eax
+        jmp 3f
+
+    .global power_resume
+    power_resume:
+        movq %rdi, %rsp
+        movl $1, %eax
+    3:  popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %rbp
+        popq %rbx
+        retq
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    /// Saves the callee-saved registers and then the stack pointer to `saved_rsp`, and writes
+    /// `value_a` and, unless `pm1b` is 0, `value_b` to the PM1 control registers. Returns 0 if
+    /// the machine stays awake, and 1 when `power_resume` gets back here after it slept.
+    fn power_sleep(pm1a: u64, pm1b: u64, value_a: u64, value_b: u64, saved_rsp: *mut u64) -> u64;
+
+    /// Returns from `power_sleep` on the stack `saved_rsp` points into
+    fn power_resume(saved_rsp: u64) -> !;
+}
+
+/// Stack pointer `power_sleep` left behind, for `power_resume`
+static SAVED_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// The stack `resume_entry` runs on, until it's back on the one `power_sleep` was called on
+fn resume_stack() -> Result<VirtAddr, &'static str> {
+    static STACK: Once<Option<VirtAddr>> = Once::new();
+    STACK
+        .call_once(|| memory::allocate_kernel_stack(RESUME_STACK_SIZE).ok())
+        .ok_or("no memory for the resume stack")
+}
+
+/// Where the trampoline takes the boot CPU on waking: in long mode, on the kernel's page tables
+/// and the resume stack, with interrupts disabled, but without the CPU state sleep lost
+extern "C" fn resume_entry(_arg: u64) -> ! {
+    gdt::reload();
+    interrupts::init_idt();
+    fpu::init();
+    memory::caching::init();
+    unsafe { power_resume(SAVED_RSP.load(Ordering::SeqCst)) }
+}
+
+/// Puts the machine to sleep in S3 and returns once it has woken up again. Only the boot CPU may
+/// call it.
+pub fn suspend() -> Result<(), &'static str> {
+    if percpu::current().index != 0 {
+        return Err("only the boot CPU can put the machine to sleep");
+    }
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    let facs = fadt.firmware_ctrl.ok_or("the FADT has no FACS")?;
+    if fadt.pm1a_control_block == 0 || fadt.pm1a_event_block == 0 {
+        return Err("the FADT has no PM1 registers");
+    }
+    let sleep_type = acpi::sleep_type(3).ok_or("the DSDT has no \\_S3 package")?;
+    let stack_top = resume_stack()?;
+    enable_acpi_mode(fadt)?;
+    fs::sync().map_err(|_| "failed to write back the filesystems")?;
+
+    let stopped = stop_aps()?;
+    let devices = DEVICES.lock().clone();
+    for (count, device) in devices.iter().enumerate() {
+        if let Err(err) = device.suspend() {
+            warnln!("power: {} refused to suspend: {}", device.name(), err);
+            resume_devices(&devices[..count]);
+            start_aps(&stopped);
+            return Err("a device refused to suspend");
+        }
+    }
+
+    let result = x86_64::instructions::interrupts::without_interrupts(|| {
+        sleep(fadt, facs, stack_top, sleep_type)
+    });
+    if result.is_ok() {
+        time::init(); // the clock didn't move while asleep, the RTC did
+    }
+    resume_devices(&devices);
+    start_aps(&stopped);
+    result
+}
+
+/// Saves what's lost in sleep, sleeps and restores it; called with interrupts disabled
+fn sleep(
+    fadt: &Fadt,
+    facs: PhysAddr,
+    stack_top: VirtAddr,
+    (sleep_type_a, sleep_type_b): (u8, u8),
+) -> Result<(), &'static str> {
+    let vector = smp::waking_vector(resume_entry, stack_top.as_u64(), 0)?;
+    set_waking_vector(facs, vector)?;
+    pci::save_config();
+    let controller = interrupts::save_controller();
+    clock::suspend();
+
+    let (pm1a, pm1b) = (
+        fadt.pm1a_control_block as u16,
+        fadt.pm1b_control_block as u16,
+    );
+    let slept = unsafe {
+        outw(fadt.pm1a_event_block as u16, WAK_STS);
+        if fadt.pm1b_event_block != 0 {
+            outw(fadt.pm1b_event_block as u16, WAK_STS);
+        }
+        // the sleep type goes in first, SLP_EN starts the transition
+        let value_a = set_sleep_type(pm1a, sleep_type_a);
+        let value_b = match pm1b {
+            0 => 0,
+            _ => set_sleep_type(pm1b, sleep_type_b),
+        };
+        power_sleep(
+            pm1a as u64,
+            pm1b as u64,
+            (value_a | SLP_EN) as u64,
+            (value_b | SLP_EN) as u64,
+            SAVED_RSP.as_ptr(),
+        ) == 1
+    };
+    if !slept {
+        return Err("the machine didn't go to sleep");
+    }
+
+    // back from `resume_entry`, which restored what every other step needs
+    let boot_cpu = percpu::get(0).expect("the boot CPU has per-CPU data");
+    percpu::init(0, boot_cpu.apic_id);
+    syscall::init();
+    pci::restore_config();
+    interrupts::restore_controller(&controller);
+    clock::resume();
+    events::resume(fadt);
+    Ok(())
+}
+
+/// Turns the machine off through the ACPI soft-off state, S5, right away. Only returns if that's
+/// impossible or the machine stays on.
+pub fn power_off() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("the FADT has no PM1 registers");
+    }
+    let (sleep_type_a, sleep_type_b) =
+        acpi::sleep_type(5).ok_or("the DSDT has no \\_S5 package")?;
+    enable_acpi_mode(fadt)?;
+    let (pm1a, pm1b) = (
+        fadt.pm1a_control_block as u16,
+        fadt.pm1b_control_block as u16,
+    );
+    unsafe {
+        let value_a = set_sleep_type(pm1a, sleep_type_a);
+        let value_b = match pm1b {
+            0 => 0,
+            _ => set_sleep_type(pm1b, sleep_type_b),
+        };
+        outw(pm1a, value_a | SLP_EN);
+        if pm1b != 0 {
+            outw(pm1b, value_b | SLP_EN);
+        }
+    }
+    for _ in 0..50 {
+        pit::wait_ms(10);
+    }
+    Err("the machine didn't turn off")
+}
+
+/// Ports that turn emulators off when written the value, tried when ACPI doesn't work: QEMU's
+/// PM1 control register on current machine types, then on old ones and Bochs, then VirtualBox's
+const EMULATOR_POWER_OFF: [(u16, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+/// QEMU's `isa-debug-exit` device as usually set up (`iobase=0xf4,iosize=0x04`); QEMU exits
+/// with status `value << 1 | 1` when it's written
+const QEMU_DEBUG_EXIT: u16 = 0xf4;
+
+/// What a test kernel tells QEMU through `isa-debug-exit`. QEMU exits with `value << 1 | 1`,
+/// 33 for `Success`, which can't be mistaken for QEMU's own 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Ends QEMU with `code`, for test kernels; halts if there's no `isa-debug-exit` device
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    x86_64::instructions::interrupts::disable();
+    unsafe { outl(QEMU_DEBUG_EXIT, code as u32) };
+    crate::hlt_loop();
+}
+
+/// Takes the system down and turns the machine off by whatever means works: ACPI S5, then the
+/// emulators' own ports. If the machine is still on after all that, says so and halts.
+pub fn shutdown() -> ! {
+    teardown();
+    if let Err(err) = power_off() {
+        warnln!("power: no ACPI power off: {}", err);
+    }
+    x86_64::instructions::interrupts::disable();
+    for (port, value) in EMULATOR_POWER_OFF {
+        unsafe { outw(port, value) };
+    }
+    unsafe { outl(QEMU_DEBUG_EXIT, 0) };
+    println!("power: the machine can be turned off now");
+    crate::hlt_loop();
+}
+
+/// Takes the system down, then `reboot`s
+pub fn restart() -> ! {
+    teardown();
+    reboot();
+}
+
+/// Ends the processes, writes back the filesystems and block devices, shuts the registered
+/// devices down and takes the APs offline, in that order; see the module documentation
+fn teardown() {
+    let ended = process::kill_all();
+    if ended != 0 {
+        println!("power: ended {} processes", ended);
+    }
+    if let Err(err) = fs::sync() {
+        errorln!("power: failed to write back the filesystems: {}", err);
+    }
+    for name in block::device_names() {
+        let flushed = block::device(&name).map_or(Ok(()), |device| device.flush());
+        if flushed.is_err() {
+            errorln!("power: {} failed to flush its write cache", name);
+        }
+    }
+    let devices = DEVICES.lock().clone();
+    for device in &devices {
+        if let Err(err) = device.shutdown() {
+            warnln!("power: {} failed to shut down: {}", device.name(), err);
+        }
+    }
+    if let Err(err) = stop_aps() {
+        warnln!("power: failed to take the other CPUs offline: {}", err);
+    }
+}
+
+/// Restarts the machine right away: through the FADT's reset register if it has one, the
+/// keyboard controller's reset line otherwise, and if neither works by triple-faulting the CPU.
+/// Nothing is written back or shut down first; callers that can should `restart` instead.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset) {
+        match register {
+            GenericAddress::Io(port) => unsafe { outb(port, value) },
+            GenericAddress::Memory(address) => {
+                if let Ok(base) = memory::map_mmio(PhysAddr::new(address), 1) {
+                    unsafe { base.as_mut_ptr::<u8>().write_volatile(value) };
+                }
+            }
+        }
+        pit::wait_ms(50);
+    }
+
+    // pulse the CPU's reset line through the 8042's output port
+    const PS2_CONTROL: u16 = 0x64;
+    const PS2_INPUT_FULL: u8 = 1 << 1;
+    const PS2_PULSE_RESET: u8 = 0xfe;
+    for _ in 0..100 {
+        if unsafe { inb(PS2_CONTROL) } & PS2_INPUT_FULL == 0 {
+            unsafe { outb(PS2_CONTROL, PS2_PULSE_RESET) };
+            break;
+        }
+        pit::wait_ms(1);
+    }
+    pit::wait_ms(50);
+
+    // with an empty IDT the breakpoint can't be delivered, nor the double fault that follows
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    crate::hlt_loop();
+}
+
+/// Writes sleep type `sleep_type` to the PM1 control register at `port` and returns the
+/// register's new value
+unsafe fn set_sleep_type(port: u16, sleep_type: u8) -> u16 {
+    let value = inw(port) & !(SLP_TYP_MASK | SLP_EN) | ((sleep_type as u16) << SLP_TYP_SHIFT);
+    outw(port, value);
+    value
+}
+
+/// Points the FACS's waking vector at `vector`, which firmware jumps to in real mode
+fn set_waking_vector(facs: PhysAddr, vector: PhysAddr) -> Result<(), &'static str> {
+    let base = memory::phys_to_virt(facs).as_mut_ptr::<u8>();
+    unsafe {
+        if base.cast::<[u8; 4]>().read() != *b"FACS" {
+            return Err("the FACS has a bad signature");
+        }
+        base.add(facs::WAKING_VECTOR)
+            .cast::<u32>()
+            .write_volatile(vector.as_u64() as u32);
+        // firmware would jump to a 64-bit vector instead, in protected or long mode
+        if base.add(facs::VERSION).read_volatile() >= 1 {
+            base.add(facs::X_WAKING_VECTOR)
+                .cast::<u64>()
+                .write_volatile(0);
+        }
+    }
+    Ok(())
+}
+
+/// Switches the chipset from legacy mode, where the firmware handles power management events, to
+/// ACPI mode; the sleep registers only work in the latter
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), &'static str> {
+    let pm1a = fadt.pm1a_control_block as u16;
+    let enabled = || unsafe { inw(pm1a) } & SCI_EN != 0;
+    if enabled() || fadt.smi_command_port == 0 {
+        return Ok(()); // without the port the chipset is always in ACPI mode
+    }
+    unsafe { outb(fadt.smi_command_port as u16, fadt.acpi_enable) };
+    for _ in 0..300 {
+        if enabled() {
+            return Ok(());
+        }
+        pit::wait_ms(10);
+    }
+    Err("the firmware didn't switch to ACPI mode")
+}
+
+/// Takes every online AP offline and returns their indices
+fn stop_aps() -> Result<Vec<usize>, &'static str> {
+    let mut stopped = Vec::new();
+    for index in 1..percpu::count() {
+        if !percpu::is_online(index) {
+            continue;
+        }
+        if let Err(err) = smp::offline(index) {
+            start_aps(&stopped);
+            return Err(err);
+        }
+        stopped.push(index);
+    }
+    Ok(stopped)
+}
+
+fn start_aps(stopped: &[usize]) {
+    for &index in stopped {
+        if let Err(err) = smp::online(index) {
+            errorln!("power: CPU {}: {}", index, err);
+        }
+    }
+}
+
+fn resume_devices(devices: &[Arc<dyn Device>]) {
+    for device in devices.iter().rev() {
+        if let Err(err) = device.resume() {
+            errorln!("power: {} failed to resume: {}", device.name(), err);
+        }
+    }
+}
+
+/// `reboot`
+pub fn reboot_command(_args: &[&str]) -> Result<(), &'static str> {
+    println!("rebooting");
+    restart();
+}
+
+/// `shutdown`
+pub fn shutdown_command(_args: &[&str]) -> Result<(), &'static str> {
+    println!("shutting down");
+    shutdown();
+}
+
+/// `suspend`
+pub fn command(_args: &[&str]) -> Result<(), &'static str> {
+    println!("suspending to RAM");
+    suspend()?;
+    println!("resumed");
+    Ok(())
+}
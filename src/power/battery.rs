@@ -0,0 +1,194 @@
+//! Batteries and the AC adapter, and what to do when the battery runs low.
+//!
+//! ACPI describes both in the namespace, as devices whose `_BIF`/`_BIX`, `_BST` and `_PSR`
+//! methods report capacity, charge and whether mains power is connected. Those are AML methods,
+//! and on laptops they mostly read embedded controller registers, so there is no way to get at
+//! them without an AML interpreter. The numbers therefore come from `Provider`s a driver
+//! registers: `sbs` talks to a Smart Battery System on the embedded controller's SMBus directly,
+//! and a driver for a particular embedded controller could add another. Without one the power
+//! source is unknown, and the policy does nothing.
+//!
+//! `monitor` checks the providers every `CHECK_INTERVAL`. Running on battery, it warns once the
+//! charge drops to the warning level, and turns the machine off at the shutdown level rather
+//! than let it die with unwritten data.
+
+use crate::console::{self, Colors};
+use crate::{errorln, print_colored, println, timer, warnln};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+pub mod sbs;
+
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const DEFAULT_WARN_PERCENT: u8 = 10;
+const DEFAULT_SHUTDOWN_PERCENT: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeState {
+    Charging,
+    Discharging,
+    /// Neither, e.g. full and on mains power
+    Idle,
+}
+
+impl fmt::Display for ChargeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ChargeState::Charging => "charging",
+            ChargeState::Discharging => "discharging",
+            ChargeState::Idle => "idle",
+        })
+    }
+}
+
+/// One battery's state, what `_BST` reports scaled by `_BIF`'s full charge capacity
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    /// Remaining charge, in percent of the last full charge
+    pub percent: u8,
+    pub state: ChargeState,
+    /// Minutes until empty while discharging, or until full while charging, if known
+    pub minutes_left: Option<u32>,
+}
+
+/// A source of battery and AC adapter readings
+pub trait Provider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether mains power is connected (`_PSR`), `None` if the provider can't tell
+    fn on_ac(&self) -> Option<bool>;
+
+    /// The batteries present
+    fn batteries(&self) -> Vec<Battery>;
+}
+
+static PROVIDERS: Mutex<Vec<&'static dyn Provider>> = Mutex::new(Vec::new());
+
+/// Charge, in percent, at which `monitor` warns
+static WARN_PERCENT: AtomicU8 = AtomicU8::new(DEFAULT_WARN_PERCENT);
+/// Charge, in percent, at which `monitor` turns the machine off; 0 never does
+static SHUTDOWN_PERCENT: AtomicU8 = AtomicU8::new(DEFAULT_SHUTDOWN_PERCENT);
+/// Set once the warning was given, until the charge is back above the warning level
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+pub fn register_provider(provider: &'static dyn Provider) {
+    PROVIDERS.lock().push(provider);
+}
+
+/// Where the machine's power comes from, as far as the providers know
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Ac,
+    Battery,
+    Unknown,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Ac => "AC adapter",
+            Source::Battery => "battery",
+            Source::Unknown => "unknown",
+        })
+    }
+}
+
+/// AC if any provider says mains power is connected, battery if one says it isn't and none says
+/// it is
+pub fn source() -> Source {
+    let providers = PROVIDERS.lock();
+    let readings = providers.iter().filter_map(|provider| provider.on_ac());
+    readings.fold(Source::Unknown, |source, on_ac| match (source, on_ac) {
+        (_, true) | (Source::Ac, _) => Source::Ac,
+        _ => Source::Battery,
+    })
+}
+
+/// Every battery of every provider
+pub fn batteries() -> Vec<Battery> {
+    let providers = PROVIDERS.lock();
+    providers
+        .iter()
+        .flat_map(|provider| provider.batteries())
+        .collect()
+}
+
+/// Remaining charge of all batteries together, the average of their percentages
+pub fn charge_percent() -> Option<u8> {
+    let batteries = batteries();
+    let total: u32 = batteries.iter().map(|battery| battery.percent as u32).sum();
+    (!batteries.is_empty()).then(|| (total / batteries.len() as u32) as u8)
+}
+
+/// Applies the low-battery policy to the current readings
+fn check() {
+    let Some(percent) = charge_percent() else {
+        return;
+    };
+    if source() != Source::Battery {
+        WARNED.store(false, Ordering::Relaxed);
+        return;
+    }
+    let shutdown_percent = SHUTDOWN_PERCENT.load(Ordering::Relaxed);
+    if shutdown_percent != 0 && percent <= shutdown_percent {
+        errorln!("battery: {}% left, turning the machine off", percent);
+        if let Err(err) = super::power_off() {
+            errorln!("battery: {}", err);
+        }
+    } else if percent <= WARN_PERCENT.load(Ordering::Relaxed) {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warnln!("battery: low, {}% left", percent);
+        }
+    } else {
+        WARNED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Watches the battery charge every `CHECK_INTERVAL`; meant to be spawned as a task
+pub async fn monitor() {
+    loop {
+        timer::sleep(CHECK_INTERVAL).await;
+        check();
+    }
+}
+
+/// `battery [warn <percent> | shutdown <percent>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let parse = |percent: &str| match percent.parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err("not a percentage"),
+    };
+    match args {
+        [] => {}
+        ["warn", percent] => WARN_PERCENT.store(parse(percent)?, Ordering::Relaxed),
+        ["shutdown", percent] => SHUTDOWN_PERCENT.store(parse(percent)?, Ordering::Relaxed),
+        _ => return Err("usage: battery [warn <percent> | shutdown <percent>]"),
+    }
+
+    println!("power source: {}", source());
+    let batteries = batteries();
+    if batteries.is_empty() {
+        println!("no batteries known");
+    }
+    for (index, battery) in batteries.iter().enumerate() {
+        let colors = match battery.percent <= WARN_PERCENT.load(Ordering::Relaxed) {
+            true => console::theme("battery"),
+            false => Colors::DEFAULT,
+        };
+        print_colored!(colors; "battery {}: {}%, {}", index, battery.percent, battery.state);
+        match battery.minutes_left {
+            Some(minutes) => println!(", {}:{:02} left", minutes / 60, minutes % 60),
+            None => println!(),
+        }
+    }
+    println!(
+        "warn at {}%, turn off at {}%",
+        WARN_PERCENT.load(Ordering::Relaxed),
+        SHUTDOWN_PERCENT.load(Ordering::Relaxed)
+    );
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! A Smart Battery System behind the ACPI embedded controller, the one battery `Provider` that
+//! needs no AML.
+//!
+//! Laptops built to the Smart Battery specification put the battery and its charger on an SMBus
+//! the embedded controller (EC) drives, through the SMBus host controller interface the ACPI
+//! specification lays out in EC space. The EC's ports come from the ECDT, the host controller's
+//! registers from its `_EC` object (see `acpi::smbus_hc_base`), and the battery and the charger
+//! answer the standard Smart Battery commands. Only the battery at the default address is read;
+//! with a battery selector and several batteries, that's whichever one the selector picked.
+
+use super::{Battery, ChargeState, Provider};
+use crate::acpi;
+use crate::arch::port::{inb, outb};
+use crate::println;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+/// Polls of the EC status register before giving up on it, well over the EC's response time
+const EC_SPINS: usize = 100_000;
+/// Polls of the host controller's protocol register before giving up on a transaction
+const SMBUS_SPINS: usize = 10_000;
+
+/// EC status register: a byte is waiting in the data register
+const EC_OUTPUT_FULL: u8 = 1 << 0;
+/// EC status register: the EC hasn't taken the last byte written yet
+const EC_INPUT_FULL: u8 = 1 << 1;
+const EC_READ: u8 = 0x80;
+const EC_WRITE: u8 = 0x81;
+
+/// Host controller registers, offsets from its base in EC space
+const SMB_PROTOCOL: u8 = 0x00;
+const SMB_STATUS: u8 = 0x01;
+const SMB_ADDRESS: u8 = 0x02;
+const SMB_COMMAND: u8 = 0x03;
+const SMB_DATA: u8 = 0x04;
+const PROTOCOL_READ_WORD: u8 = 0x09;
+/// Low bits of the status register: 0 once a transaction succeeded
+const STATUS_CODE: u8 = 0x1F;
+
+/// SMBus addresses of the charger and the battery
+const CHARGER: u8 = 0x09;
+const BATTERY: u8 = 0x0B;
+
+/// Smart Battery commands, each reading a word
+const CURRENT: u8 = 0x0A;
+const RELATIVE_STATE_OF_CHARGE: u8 = 0x0D;
+const AVERAGE_TIME_TO_EMPTY: u8 = 0x12;
+const AVERAGE_TIME_TO_FULL: u8 = 0x13;
+const BATTERY_STATUS: u8 = 0x16;
+const CHARGER_STATUS: u8 = 0x13;
+
+const STATUS_DISCHARGING: u16 = 1 << 6;
+const CHARGER_AC_PRESENT: u16 = 1 << 15;
+/// What the time commands return when the battery isn't discharging or charging
+const NO_TIME: u16 = 0xFFFF;
+
+struct SmartBattery {
+    command_port: u16,
+    data_port: u16,
+    base: u8,
+    /// One EC transaction at a time
+    lock: Mutex<()>,
+}
+
+static SMART_BATTERY: Once<SmartBattery> = Once::new();
+
+impl SmartBattery {
+    /// Waits until `ready` holds for the EC status register
+    fn wait(&self, ready: impl Fn(u8) -> bool) -> Result<(), &'static str> {
+        for _ in 0..EC_SPINS {
+            if ready(unsafe { inb(self.command_port) }) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err("the embedded controller doesn't respond")
+    }
+
+    fn ec_read(&self, address: u8) -> Result<u8, &'static str> {
+        self.wait(|status| status & EC_INPUT_FULL == 0)?;
+        unsafe { outb(self.command_port, EC_READ) };
+        self.wait(|status| status & EC_INPUT_FULL == 0)?;
+        unsafe { outb(self.data_port, address) };
+        self.wait(|status| status & EC_OUTPUT_FULL != 0)?;
+        Ok(unsafe { inb(self.data_port) })
+    }
+
+    fn ec_write(&self, address: u8, value: u8) -> Result<(), &'static str> {
+        self.wait(|status| status & EC_INPUT_FULL == 0)?;
+        unsafe { outb(self.command_port, EC_WRITE) };
+        self.wait(|status| status & EC_INPUT_FULL == 0)?;
+        unsafe { outb(self.data_port, address) };
+        self.wait(|status| status & EC_INPUT_FULL == 0)?;
+        unsafe { outb(self.data_port, value) };
+        Ok(())
+    }
+
+    fn smb_read(&self, register: u8) -> Result<u8, &'static str> {
+        self.ec_read(self.base.wrapping_add(register))
+    }
+
+    fn smb_write(&self, register: u8, value: u8) -> Result<(), &'static str> {
+        self.ec_write(self.base.wrapping_add(register), value)
+    }
+
+    /// Runs an SMBus read word transaction of `command` against the device at `address`
+    fn read_word(&self, address: u8, command: u8) -> Result<u16, &'static str> {
+        let _guard = self.lock.lock();
+        self.smb_write(SMB_ADDRESS, address << 1)?;
+        self.smb_write(SMB_COMMAND, command)?;
+        self.smb_write(SMB_PROTOCOL, PROTOCOL_READ_WORD)?;
+        // the host controller clears the protocol register once the transaction is over
+        let mut done = false;
+        for _ in 0..SMBUS_SPINS {
+            if self.smb_read(SMB_PROTOCOL)? == 0 {
+                done = true;
+                break;
+            }
+        }
+        if !done {
+            return Err("the SMBus transaction timed out");
+        }
+        if self.smb_read(SMB_STATUS)? & STATUS_CODE != 0 {
+            return Err("the SMBus device didn't answer");
+        }
+        let low = self.smb_read(SMB_DATA)?;
+        let high = self.smb_read(SMB_DATA + 1)?;
+        Ok(u16::from_le_bytes([low, high]))
+    }
+
+    fn battery(&self) -> Result<Battery, &'static str> {
+        let percent = self.read_word(BATTERY, RELATIVE_STATE_OF_CHARGE)?.min(100) as u8;
+        let status = self.read_word(BATTERY, BATTERY_STATUS)?;
+        let current = self.read_word(BATTERY, CURRENT)? as i16;
+        let (state, time) = if status & STATUS_DISCHARGING != 0 {
+            (ChargeState::Discharging, Some(AVERAGE_TIME_TO_EMPTY))
+        } else if current > 0 {
+            (ChargeState::Charging, Some(AVERAGE_TIME_TO_FULL))
+        } else {
+            (ChargeState::Idle, None)
+        };
+        let minutes_left = match time {
+            Some(command) => match self.read_word(BATTERY, command)? {
+                NO_TIME => None,
+                minutes => Some(minutes as u32),
+            },
+            None => None,
+        };
+        Ok(Battery {
+            percent,
+            state,
+            minutes_left,
+        })
+    }
+}
+
+impl Provider for SmartBattery {
+    fn name(&self) -> &'static str {
+        "smart battery"
+    }
+
+    fn on_ac(&self) -> Option<bool> {
+        let status = self.read_word(CHARGER, CHARGER_STATUS).ok()?;
+        Some(status & CHARGER_AC_PRESENT != 0)
+    }
+
+    fn batteries(&self) -> Vec<Battery> {
+        self.battery().into_iter().collect()
+    }
+}
+
+/// Registers the smart battery as a `Provider` if the ACPI tables describe an embedded controller
+/// with an SMBus host controller and a battery or charger answers on it
+pub fn init() {
+    let (Some(ecdt), Some(base)) = (acpi::ecdt(), acpi::smbus_hc_base()) else {
+        return;
+    };
+    let candidate = SmartBattery {
+        command_port: ecdt.command_port,
+        data_port: ecdt.data_port,
+        base,
+        lock: Mutex::new(()),
+    };
+    let battery = candidate.battery();
+    if battery.is_err() && candidate.on_ac().is_none() {
+        return;
+    }
+    println!(
+        "battery: smart battery on the EC SMBus at 0x{:02x}{}",
+        base,
+        if battery.is_ok() {
+            ""
+        } else {
+            ", no battery present"
+        }
+    );
+    super::register_provider(SMART_BATTERY.call_once(|| candidate));
+}
@@ -0,0 +1,352 @@
+//! The power and sleep buttons and the lid switch, as ACPI reports them through the System
+//! Control Interrupt (SCI).
+//!
+//! Buttons that are fixed features set a status bit in the PM1 event registers. Everything else,
+//! the lid always and the buttons on many laptops, is a device in the namespace: the chipset
+//! raises a general-purpose event (GPE), and firmware's `_Lxx`/`_Exx` method finds out what
+//! happened and notifies the device. Those methods are AML, which can't be run, so which GPE
+//! belongs to which device has to be configured with `buttons gpe` (the number is in the device's
+//! `_PRW` package, or the name of the method in a DSDT dump), and the GPE firing is taken as the
+//! event. Whether the lid is closed comes from `_LID`, another method; it's assumed open at boot,
+//! and every lid event flips it.
+//!
+//! The interrupt handler posts `event::Event`s, and `handle`, subscribed to the bus, carries out
+//! the configured `Action`.
+
+use crate::acpi::{self, Fadt};
+use crate::arch::port::{inb, inw, outb, outw};
+use crate::event::{self, Event};
+use crate::{interrupts, println, warnln};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Bits of the PM1 status and enable registers
+const PWRBTN: u16 = 1 << 8;
+const SLPBTN: u16 = 1 << 9;
+
+/// Times a GPE may fire within one timer tick before it's taken for a level-triggered one whose
+/// source nobody clears, and disabled
+const STORM_LIMIT: u32 = 16;
+
+/// What to do about an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Ignore,
+    Suspend,
+    PowerOff,
+}
+
+impl Action {
+    fn parse(name: &str) -> Option<Action> {
+        match name {
+            "ignore" => Some(Action::Ignore),
+            "suspend" => Some(Action::Suspend),
+            "off" => Some(Action::PowerOff),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Action::Ignore => "ignore",
+            Action::Suspend => "suspend",
+            Action::PowerOff => "off",
+        })
+    }
+}
+
+/// The devices a GPE can be configured for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    PowerButton,
+    SleepButton,
+    Lid,
+}
+
+impl Source {
+    fn parse(name: &str) -> Option<Source> {
+        match name {
+            "power" => Some(Source::PowerButton),
+            "sleep" => Some(Source::SleepButton),
+            "lid" => Some(Source::Lid),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::PowerButton => "power",
+            Source::SleepButton => "sleep",
+            Source::Lid => "lid",
+        })
+    }
+}
+
+struct Actions {
+    power_button: Action,
+    sleep_button: Action,
+    lid_closed: Action,
+}
+
+static ACTIONS: Mutex<Actions> = Mutex::new(Actions {
+    power_button: Action::PowerOff,
+    sleep_button: Action::Suspend,
+    lid_closed: Action::Suspend,
+});
+
+/// A GPE configured for a device
+struct Gpe {
+    number: u16,
+    source: Source,
+    /// Tick it last fired in, and how often in that tick
+    last_tick: u64,
+    fires: u32,
+}
+
+/// Taken by the SCI handler, so only ever locked with interrupts disabled
+static GPES: Mutex<Vec<Gpe>> = Mutex::new(Vec::new());
+
+static LID_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Switches to ACPI mode, enables the fixed-feature buttons and starts handling the SCI
+pub fn init() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    if fadt.pm1a_event_block == 0 || fadt.sci_interrupt >= 16 {
+        warnln!("power: no PM1 event registers or SCI, button events won't be seen");
+        return;
+    }
+    if let Err(err) = super::enable_acpi_mode(fadt) {
+        warnln!("power: {}", err);
+        return;
+    }
+    enable_fixed(fadt);
+    if let Err(err) = interrupts::set_irq_handler(fadt.sci_interrupt as u8, sci_interrupt) {
+        warnln!("power: SCI on IRQ {}: {}", fadt.sci_interrupt, err);
+        return;
+    }
+    event::subscribe(handle);
+}
+
+/// Clears the button presses that may have woken the machine, so they don't count as new ones,
+/// and enables the buttons again. Called with interrupts disabled, right after waking.
+pub(super) fn resume(fadt: &Fadt) {
+    enable_fixed(fadt);
+    for gpe in GPES.lock().iter() {
+        set_gpe_enabled(fadt, gpe.number, true);
+    }
+}
+
+/// The status and enable registers of the PM1 event blocks that exist
+fn pm1_registers(fadt: &Fadt) -> impl Iterator<Item = (u16, u16)> {
+    let half = (fadt.pm1_event_length / 2).max(2) as u32;
+    [fadt.pm1a_event_block, fadt.pm1b_event_block]
+        .into_iter()
+        .filter(|&block| block != 0)
+        .map(move |block| (block as u16, (block + half) as u16))
+}
+
+/// Clears the status of the fixed-feature buttons there are and enables them
+fn enable_fixed(fadt: &Fadt) {
+    let mut bits = 0;
+    if fadt.fixed_power_button {
+        bits |= PWRBTN;
+    }
+    if fadt.fixed_sleep_button {
+        bits |= SLPBTN;
+    }
+    for (status, enable) in pm1_registers(fadt) {
+        unsafe {
+            outw(status, bits); // status bits clear when written with a one
+            outw(enable, inw(enable) | bits);
+        }
+    }
+}
+
+/// Status and enable port of the byte holding GPE `number`, and its bit there
+fn gpe_register(fadt: &Fadt, number: u16) -> Option<(u16, u16, u8)> {
+    let blocks = [
+        (fadt.gpe0_block, fadt.gpe0_block_length, 0),
+        (
+            fadt.gpe1_block,
+            fadt.gpe1_block_length,
+            fadt.gpe1_base as u16,
+        ),
+    ];
+    blocks.into_iter().find_map(|(block, length, base)| {
+        let half = (length / 2) as u16;
+        let index = number.checked_sub(base)?;
+        if block == 0 || index >= half * 8 {
+            return None;
+        }
+        let status = block as u16 + index / 8;
+        Some((status, status + half, 1 << (index % 8)))
+    })
+}
+
+fn set_gpe_enabled(fadt: &Fadt, number: u16, enabled: bool) {
+    if let Some((_, enable, bit)) = gpe_register(fadt, number) {
+        unsafe {
+            let value = inb(enable);
+            outb(enable, if enabled { value | bit } else { value & !bit });
+        }
+    }
+}
+
+/// Whether GPE `number` fired, clearing its status if so
+fn take_gpe_status(fadt: &Fadt, number: u16) -> bool {
+    let Some((status, _, bit)) = gpe_register(fadt, number) else {
+        return false;
+    };
+    unsafe {
+        if inb(status) & bit == 0 {
+            return false;
+        }
+        outb(status, bit);
+    }
+    true
+}
+
+/// The SCI's handler: turns whatever fired into events
+fn sci_interrupt() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    for (status_port, enable_port) in pm1_registers(fadt) {
+        let status = unsafe { inw(status_port) & inw(enable_port) } & (PWRBTN | SLPBTN);
+        unsafe { outw(status_port, status) };
+        if status & PWRBTN != 0 {
+            event::post(Event::PowerButton);
+        }
+        if status & SLPBTN != 0 {
+            event::post(Event::SleepButton);
+        }
+    }
+
+    let tick = interrupts::ticks();
+    for gpe in GPES.lock().iter_mut() {
+        if !take_gpe_status(fadt, gpe.number) {
+            continue;
+        }
+        gpe.fires = if gpe.last_tick == tick {
+            gpe.fires + 1
+        } else {
+            1
+        };
+        gpe.last_tick = tick;
+        if gpe.fires == STORM_LIMIT {
+            set_gpe_enabled(fadt, gpe.number, false);
+            continue;
+        }
+        event::post(match gpe.source {
+            Source::PowerButton => Event::PowerButton,
+            Source::SleepButton => Event::SleepButton,
+            Source::Lid => match !LID_CLOSED.fetch_xor(true, Ordering::Relaxed) {
+                true => Event::LidClosed,
+                false => Event::LidOpened,
+            },
+        });
+    }
+}
+
+/// Carries out the action configured for `event`
+fn handle(event: Event) {
+    let action = {
+        let actions = ACTIONS.lock();
+        match event {
+            Event::PowerButton => actions.power_button,
+            Event::SleepButton => actions.sleep_button,
+            Event::LidClosed => actions.lid_closed,
+            _ => return,
+        }
+    };
+    println!("power: {}, action: {}", event, action);
+    let result = match action {
+        Action::Ignore => Ok(()),
+        Action::Suspend => super::suspend(),
+        Action::PowerOff => super::power_off(),
+    };
+    if let Err(err) = result {
+        warnln!("power: {}", err);
+    }
+}
+
+/// Has GPE `number` report events of `source`
+fn configure_gpe(source: Source, number: u16) -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    gpe_register(fadt, number).ok_or("no such GPE")?;
+    without_interrupts(|| {
+        let mut gpes = GPES.lock();
+        gpes.retain(|gpe| gpe.number != number);
+        gpes.push(Gpe {
+            number,
+            source,
+            last_tick: 0,
+            fires: 0,
+        });
+        take_gpe_status(fadt, number); // whatever it said before doesn't count
+        set_gpe_enabled(fadt, number, true);
+    });
+    Ok(())
+}
+
+/// `buttons [<power|sleep|lid> <ignore|suspend|off> | gpe <power|sleep|lid> <number>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str =
+        "usage: buttons [<power|sleep|lid> <ignore|suspend|off> | gpe <power|sleep|lid> <number>]";
+    match args {
+        [] => {}
+        ["gpe", source, number] => {
+            let source = Source::parse(source).ok_or(USAGE)?;
+            configure_gpe(source, number.parse().map_err(|_| "bad GPE number")?)?;
+        }
+        [source, action] => {
+            let source = Source::parse(source).ok_or(USAGE)?;
+            let action = Action::parse(action).ok_or(USAGE)?;
+            let mut actions = ACTIONS.lock();
+            match source {
+                Source::PowerButton => actions.power_button = action,
+                Source::SleepButton => actions.sleep_button = action,
+                Source::Lid => actions.lid_closed = action,
+            }
+        }
+        _ => return Err(USAGE),
+    }
+
+    let fadt = acpi::fadt();
+    let fixed = |present: fn(&Fadt) -> bool| match fadt.map(present) {
+        Some(true) => "fixed",
+        _ => "not fixed",
+    };
+    let actions = ACTIONS.lock();
+    println!(
+        "power button ({}): {}",
+        fixed(|fadt| fadt.fixed_power_button),
+        actions.power_button
+    );
+    println!(
+        "sleep button ({}): {}",
+        fixed(|fadt| fadt.fixed_sleep_button),
+        actions.sleep_button
+    );
+    let closed = LID_CLOSED.load(Ordering::Relaxed);
+    println!(
+        "lid ({}): {} on closing",
+        if closed { "closed" } else { "open" },
+        actions.lid_closed
+    );
+    without_interrupts(|| {
+        for gpe in GPES.lock().iter() {
+            println!("GPE {}: {}", gpe.number, gpe.source);
+        }
+    });
+    Ok(())
+}
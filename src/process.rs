@@ -0,0 +1,590 @@
+//! Processes: programs with an address space, open files and threads of their own.
+//!
+//! `Process::spawn` loads an ELF executable into a fresh `AddressSpace` and starts its main
+//! thread; the `spawn` system call adds more threads to the caller's process, and `fork` copies
+//! it. Threads are run by `scheduler`, a single executor task: it takes the next ready thread,
+//! loads its process's level 4 table into CR3 and continues it in ring 3 until the timer preempts
+//! it after `TIME_SLICE` ticks, it exits or it faults, and lets the other tasks run before
+//! picking the next thread. System calls aren't preempted: a slice that runs out during one ends
+//! as the call returns (see `sync::preempt`), so a thread sleeping in one holds up the rest
+//! meanwhile.
+//!
+//! Processes are organized in groups (see `group`), which split the CPU time by shares and may
+//! cap the memory of their processes: the scheduler picks the ready thread whose groups are
+//! furthest behind, and threads of one group take turns.
+//!
+//! A single-threaded process can be saved to a file and restored from it later, even after a
+//! reboot, see `checkpoint`.
+//!
+//! A process ends when its last thread exits, with that thread's exit code, or as soon as any of
+//! its threads faults, unless it handles the fault's signal (see `signal`); the fault is reported
+//! on the console. A signal it doesn't handle ends it too. `Process::wait` completes once it has
+//! ended.
+//!
+//! Stacks grow on demand: the main thread's as far as `elf::STACK_LIMIT`, those of threads the
+//! kernel provides the stack for as far as `THREAD_STACK_LIMIT`. A fault in the guard page below
+//! one is a stack overflow rather than growth (see `in_stack_guard`).
+
+use crate::allocator::quota;
+use crate::audit::{self, Name};
+use crate::clock::tsc;
+use crate::fpu::{self, FpuState};
+use crate::loader::{elf, LoadError};
+use crate::memory::address_space::AddressSpace;
+use crate::memory::{USER_END, USER_START};
+use crate::scrub::Sealed;
+use crate::sysctl::Tunable;
+use crate::trace::{self, Event};
+use crate::tty::{self, Tty};
+use crate::usermode::{self, Registers, UserExit};
+use crate::{cputime, fs, percpu, println, task};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::hash::{Hash, Hasher};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use files::FileTable;
+use group::{Group, SchedKey};
+use signal::{Signal, Signals};
+use spin::{Mutex, MutexGuard};
+use x86_64::VirtAddr;
+
+pub mod checkpoint;
+pub mod files;
+pub mod group;
+pub mod signal;
+
+/// How far the stacks `reserve_thread_stack` hands out may grow
+pub const THREAD_STACK_LIMIT: u64 = 1024 * 1024;
+
+/// How long a thread runs before the next ready one gets the CPU
+pub static TIME_SLICE: Tunable = Tunable::new(
+    "sched.slice_ticks",
+    "timer ticks a thread runs before the next ready thread gets the CPU",
+    1,
+    1,
+    1000,
+);
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u64);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Its last thread made the `exit` system call with this code
+    Exited(u64),
+    /// One of its threads left ring 3 any other way
+    Killed(UserExit),
+    /// A signal it didn't handle, e.g. `Signal::Interrupt` for Ctrl+C on its terminal
+    Signaled(Signal),
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Killed(reason) => write!(f, "killed: {}", reason),
+            ExitStatus::Signaled(signal) => write!(f, "killed by {}", signal),
+        }
+    }
+}
+
+/// Processes that haven't ended yet, sealed over their headers (see the `Hash` for `Process`)
+pub static PROCESSES: Sealed<BTreeMap<Pid, Arc<Process>>> =
+    Sealed::new("process table", BTreeMap::new());
+
+/// Threads waiting for their next time slice, in order
+static READY: Mutex<VecDeque<Arc<Thread>>> = Mutex::new(VecDeque::new());
+
+/// The scheduler task, while it waits for a thread to become ready
+static SCHEDULER_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    space: AddressSpace,
+    files: Mutex<FileTable>,
+    /// Ids of the threads that haven't exited yet
+    threads: Mutex<Vec<u64>>,
+    state: Mutex<State>,
+    group: Mutex<Arc<Group>>,
+    /// Whether its system calls are logged, see `syscall::strace`
+    traced: AtomicBool,
+    /// Where its console descriptors read and write
+    tty: &'static Tty,
+    signals: Mutex<Signals>,
+    /// TSC cycles its threads ran, see `cputime`
+    cpu_cycles: AtomicU64,
+}
+
+/// The header the process table is sealed over: what never changes for a process's life, so a
+/// change is corruption
+impl Hash for Process {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pid.hash(state);
+        self.name.hash(state);
+        self.space.root().start_address().as_u64().hash(state);
+    }
+}
+
+struct State {
+    status: Option<ExitStatus>,
+    /// Tasks blocked in `wait`
+    waiters: Vec<Waker>,
+}
+
+impl Process {
+    /// Loads the ELF executable in `elf_bytes` into a new process and makes its main thread
+    /// ready; `argv` ends up on its initial stack, and its first element names the process
+    pub fn spawn(elf_bytes: &[u8], argv: &[&str]) -> Result<Arc<Process>, LoadError> {
+        Process::spawn_with_files(elf_bytes, argv, FileTable::with_console())
+    }
+
+    /// Like `spawn`, with the descriptors in `files` instead of just the console, e.g. one end of
+    /// a pipe
+    pub fn spawn_with_files(
+        elf_bytes: &[u8],
+        argv: &[&str],
+        files: FileTable,
+    ) -> Result<Arc<Process>, LoadError> {
+        let mut space = AddressSpace::new().map_err(|_| LoadError::OutOfMemory)?;
+        let image = elf::load(&mut space, elf_bytes, argv)?;
+        let name = String::from(argv.first().copied().unwrap_or("?"));
+        let group = current().map_or_else(group::root, |parent| parent.group());
+        let process =
+            Process::create(name, space, files, group).map_err(|_| LoadError::OutOfMemory)?;
+        audit::record(audit::Event::Exec {
+            pid: process.pid,
+            name: Name::new(&process.name),
+        });
+        process.spawn_thread(Registers::new(image.entry, image.stack_pointer, 0));
+        Ok(process)
+    }
+
+    /// A copy of this process in the same group whose only thread continues with `registers`:
+    /// its address space is shared copy-on-write (see `AddressSpace::fork`), its descriptors
+    /// refer to the same files and pipes, and its signals do what they do here
+    pub fn fork(&self, registers: Registers) -> Result<Arc<Process>, &'static str> {
+        let space = self.space.fork()?;
+        let files = self.files().clone();
+        let child = Process::create(self.name.clone(), space, files, self.group())?;
+        child.set_traced(self.is_traced());
+        *child.signals.lock() = self.signals.lock().inherited();
+        child.spawn_thread(registers);
+        Ok(child)
+    }
+
+    /// Registers a process without threads in `group`; fails if its memory doesn't fit there
+    fn create(
+        name: String,
+        space: AddressSpace,
+        files: FileTable,
+        group: Arc<Group>,
+    ) -> Result<Arc<Process>, &'static str> {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+        space.set_account(group.clone())?;
+        let tty = current().map_or_else(tty::active, |parent| parent.tty);
+        let process = Arc::new(Process {
+            pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            space,
+            files: Mutex::new(files),
+            threads: Mutex::new(Vec::new()),
+            state: Mutex::new(State {
+                status: None,
+                waiters: Vec::new(),
+            }),
+            group: Mutex::new(group),
+            traced: AtomicBool::new(false),
+            tty,
+            signals: Mutex::new(Signals::new()),
+            cpu_cycles: AtomicU64::new(0),
+        });
+        PROCESSES.update(|processes| processes.insert(process.pid, process.clone()));
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The terminal it was started on, or its parent's
+    pub fn tty(&self) -> &'static Tty {
+        self.tty
+    }
+
+    pub fn space(&self) -> &AddressSpace {
+        &self.space
+    }
+
+    pub fn group(&self) -> Arc<Group> {
+        self.group.lock().clone()
+    }
+
+    /// Moves the process to `group`, with the memory it has; fails if that doesn't fit there
+    pub fn set_group(&self, group: Arc<Group>) -> Result<(), &'static str> {
+        let mut current = self.group.lock();
+        self.space.set_account(group.clone())?;
+        *current = group;
+        Ok(())
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.traced.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops logging the process's system calls
+    pub fn set_traced(&self, traced: bool) {
+        self.traced.store(traced, Ordering::Relaxed);
+    }
+
+    /// The descriptor table; held only briefly, system calls of other threads need it too
+    pub fn files(&self) -> MutexGuard<'_, FileTable> {
+        self.files.lock()
+    }
+
+    /// Number of threads that haven't exited yet
+    pub fn thread_count(&self) -> usize {
+        self.threads.lock().len()
+    }
+
+    /// How the process ended, `None` while it's still running
+    /// How long its threads ran, system calls included
+    pub fn cpu_time(&self) -> Duration {
+        cputime::cycles_to_duration(self.cpu_cycles.load(Ordering::Relaxed))
+    }
+
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.state.lock().status
+    }
+
+    /// Starts another thread in this process with `registers` (see `Registers::new`) and returns
+    /// its id
+    pub fn spawn_thread(self: &Arc<Self>, registers: Registers) -> u64 {
+        self.spawn_thread_with(registers, FpuState::new())
+    }
+
+    /// Like `spawn_thread`, with `fpu` in the FPU and vector registers
+    fn spawn_thread_with(self: &Arc<Self>, registers: Registers, fpu: FpuState) -> u64 {
+        static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+        let id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+        self.threads.lock().push(id);
+        make_ready(Arc::new(Thread {
+            id,
+            process: self.clone(),
+            registers: Mutex::new(registers),
+            fpu: Mutex::new(fpu),
+            fault: Mutex::new(None),
+        }));
+        id
+    }
+
+    /// Reserves a stack for another thread, see `AddressSpace::reserve_stack`, and returns its top
+    pub fn reserve_thread_stack(&self) -> Result<VirtAddr, &'static str> {
+        let guard = self
+            .space
+            .find_free(PAGE_SIZE + THREAD_STACK_LIMIT)
+            .ok_or("no room for another stack")?;
+        let top = guard + PAGE_SIZE + THREAD_STACK_LIMIT;
+        self.space.reserve_stack(top, THREAD_STACK_LIMIT)?;
+        Ok(top)
+    }
+
+    /// Completes with the exit status once the process has ended
+    pub fn wait(self: &Arc<Self>) -> Wait {
+        Wait {
+            process: self.clone(),
+        }
+    }
+
+    fn thread_exited(&self, id: u64, code: u64) {
+        let mut threads = self.threads.lock();
+        threads.retain(|&thread| thread != id);
+        if threads.is_empty() {
+            drop(threads);
+            self.end(ExitStatus::Exited(code));
+        }
+    }
+
+    /// Records how the process ended, the first time; threads still queued are dropped when
+    /// their turn comes
+    fn end(&self, status: ExitStatus) {
+        let waiters = {
+            let mut state = self.state.lock();
+            if state.status.is_some() {
+                return;
+            }
+            state.status = Some(status);
+            core::mem::take(&mut state.waiters)
+        };
+        self.threads.lock().clear();
+        PROCESSES.update(|processes| processes.remove(&self.pid));
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Process::wait`
+#[must_use = "futures do nothing unless polled"]
+pub struct Wait {
+    process: Arc<Process>,
+}
+
+impl Future for Wait {
+    type Output = ExitStatus;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<ExitStatus> {
+        let mut state = self.process.state.lock();
+        match state.status {
+            Some(status) => Poll::Ready(status),
+            None => {
+                if !state.waiters.iter().any(|w| w.will_wake(context.waker())) {
+                    state.waiters.push(context.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct Thread {
+    id: u64,
+    process: Arc<Process>,
+    /// Where the thread continues; only the CPU running it touches them
+    registers: Mutex<Registers>,
+    /// Its FPU and vector registers, the same way
+    fpu: Mutex<FpuState>,
+    /// A fault whose signal handler it runs before continuing, see `signal::fault`
+    fault: Mutex<Option<UserExit>>,
+}
+
+impl Thread {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    pub(crate) fn fpu(&self) -> &Mutex<FpuState> {
+        &self.fpu
+    }
+}
+
+/// The process whose thread this CPU is running, e.g. for the system call being handled
+pub fn current() -> Option<Arc<Process>> {
+    let thread = percpu::current().thread.load(Ordering::SeqCst);
+    match thread.is_null() {
+        true => None,
+        false => Some(unsafe { &*thread }.process.clone()),
+    }
+}
+
+/// Maps the page at `address` if the process running on this CPU reserved it and hasn't touched
+/// it yet (see `AddressSpace::reserve`); returns whether it did
+pub fn resolve_fault(address: VirtAddr) -> bool {
+    if !(USER_START..USER_END).contains(&address.as_u64()) {
+        return false; // nor is it worth looking up the process for a kernel fault
+    }
+    current().is_some_and(|process| process.space.populate(address, 1).is_ok())
+}
+
+/// Whether `address` is in the guard page below one of the stacks of the process running on this
+/// CPU, so that a fault there is a stack overflow
+pub fn in_stack_guard(address: VirtAddr) -> bool {
+    current().is_some_and(|process| process.space.is_guard(address))
+}
+
+/// The process with id `pid`, if it hasn't ended
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.read(|processes| processes.get(&pid).cloned())
+}
+
+/// Every process that hasn't ended, by pid
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.read(|processes| processes.values().cloned().collect())
+}
+
+/// Ends every process as if killed, for shutting down; returns how many there were. Their threads
+/// are dropped as they come up, so none of them runs again.
+pub fn kill_all() -> usize {
+    let processes = list();
+    for process in &processes {
+        process.end(ExitStatus::Signaled(Signal::Kill));
+    }
+    processes.len()
+}
+
+/// Runs `f` on every process that hasn't ended, without allocating; `false` if the process
+/// table is locked. For the crash debugger, which can't wait for anybody.
+pub fn try_for_each(mut f: impl FnMut(&Process)) -> bool {
+    PROCESSES
+        .try_read(|processes| processes.values().for_each(|process| f(process)))
+        .is_some()
+}
+
+fn make_ready(thread: Arc<Thread>) {
+    READY.lock().push_back(thread);
+    if let Some(waker) = SCHEDULER_WAKER.lock().take() {
+        waker.wake();
+    }
+}
+
+/// Runs the ready threads one time slice at a time, forever; spawn it on the executor once
+pub async fn scheduler() {
+    loop {
+        let (thread, key) = NextReady.await;
+        run_slice(thread, key);
+        task::yield_now().await;
+    }
+}
+
+/// Continues `thread` in its address space until it's preempted, exits or faults, and charges
+/// the time to its groups, and the kernel heap it allocated meanwhile to its group's account
+fn run_slice(thread: Arc<Thread>, key: SchedKey) {
+    let process = &thread.process;
+    if process.status().is_some() {
+        return; // another thread faulted and took the process down
+    }
+
+    let cpu = percpu::current();
+    cpu.thread
+        .store(Arc::as_ptr(&thread) as *mut Thread, Ordering::SeqCst);
+    let (pid, id) = (process.pid.0, thread.id);
+    trace::record(Event::SwitchIn { pid, thread: id });
+    let start = tsc::read();
+    let group = process.group();
+    let exit = quota::with_account(group.heap(), || {
+        let mut registers = thread.registers.lock();
+        fpu::switch_to(&thread);
+        let exit = process.space.enter(|| {
+            signal::deliver(&thread, &mut registers)?;
+            Ok(usermode::resume(&mut registers, TIME_SLICE.get()))
+        });
+        fpu::save(&thread);
+        exit
+    });
+    let cycles = tsc::read().saturating_sub(start);
+    process.cpu_cycles.fetch_add(cycles, Ordering::Relaxed);
+    let ticks_per_us = (tsc::ticks_per_ms() / 1000).max(1);
+    key.ran(cycles / ticks_per_us);
+    trace::record(Event::SwitchOut { pid, thread: id });
+    cpu.thread.store(ptr::null_mut(), Ordering::SeqCst);
+
+    let status = match exit {
+        Err(status) => status,
+        Ok(UserExit::Preempted) => return make_ready(thread),
+        Ok(UserExit::Exit { code }) => return process.thread_exited(thread.id, code),
+        Ok(reason) if signal::fault(&thread, reason) => return make_ready(thread),
+        Ok(reason) => ExitStatus::Killed(reason),
+    };
+    if let ExitStatus::Killed(reason) = status {
+        println!(
+            "process {} ({}), thread {}: {}",
+            process.pid, process.name, thread.id, reason
+        );
+    }
+    process.end(status);
+}
+
+/// Takes the ready thread whose groups are furthest behind on their shares; the first of those,
+/// so threads of one group take turns
+fn take_next() -> Option<(Arc<Thread>, SchedKey)> {
+    let mut ready = READY.lock();
+    let mut best: Option<(usize, SchedKey)> = None;
+    for (index, thread) in ready.iter().enumerate() {
+        let key = SchedKey::new(&thread.process.group());
+        if best
+            .as_ref()
+            .is_none_or(|(_, best)| key.compare(best).is_lt())
+        {
+            best = Some((index, key));
+        }
+    }
+    let (index, key) = best?;
+    let thread = ready.remove(index)?;
+    key.picked();
+    Some((thread, key))
+}
+
+/// Resolves to the next ready thread to run
+struct NextReady;
+
+impl Future for NextReady {
+    type Output = (Arc<Thread>, SchedKey);
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Some(next) = take_next() {
+            return Poll::Ready(next);
+        }
+        *SCHEDULER_WAKER.lock() = Some(context.waker().clone());
+        // a thread made ready before the waker was in place would otherwise go unnoticed
+        match take_next() {
+            Some(next) => Poll::Ready(next),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// `ps`
+pub fn ps_command(_args: &[&str]) -> Result<(), &'static str> {
+    println!(
+        "{:>5} {:>7} {:>10}  {:<16} NAME",
+        "PID", "THREADS", "TIME", "GROUP"
+    );
+    for process in list() {
+        let time = process.cpu_time();
+        println!(
+            "{:>5} {:>7} {:>6}.{:03}  {:<16} {}",
+            process.pid.0,
+            process.thread_count(),
+            time.as_secs(),
+            time.subsec_millis(),
+            process.group().path(),
+            process.name
+        );
+    }
+    Ok(())
+}
+
+/// `exec <path> [args...]`
+pub fn exec_command(args: &[&str]) -> Result<(), &'static str> {
+    let [path, ..] = args else {
+        return Err("usage: exec <path> [args...]");
+    };
+    let result = fs::read(path)
+        .map_err(LoadError::from)
+        .and_then(|bytes| Process::spawn(&bytes, args));
+    match result {
+        Ok(process) => {
+            process.tty.set_foreground(Some(process.pid));
+            println!("started process {}", process.pid)
+        }
+        Err(err) => println!("exec: {}: {}", path, err),
+    }
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! Saving a process to a file and starting it again from there, possibly after a reboot.
+//!
+//! Only processes with a single thread can be saved. `save` takes the thread off the ready queue
+//! while it copies the registers, the mapped pages, the reserved regions and the descriptors, so
+//! the image is consistent, and puts it back afterwards: the process goes on running. `restore`
+//! starts a new process, with a pid of its own, in the state the image describes.
+//!
+//! Descriptors on the console and on regular files are saved, the latter by path and position, so
+//! the files must still be there when the process is restored; a process with a pipe open can't
+//! be saved. Pages of file-backed regions are read in before saving, which turns the regions into
+//! anonymous memory. The FPU state is saved in the CPU's own format and only restores on a CPU
+//! with the same registers. What the process does about signals isn't saved: the restored one
+//! starts with the defaults.
+//!
+//! The image is little-endian: a header, the registers, the FPU state, then the regions, the
+//! descriptors and the pages, each list preceded by its length.
+
+use super::files::{Descriptor, FileTable, MAX_FILES};
+use super::{current, group, make_ready, Pid, Process, Thread, READY};
+use crate::endian::{read_u16_le, read_u32_le, read_u64_le};
+use crate::fpu::FpuState;
+use crate::fs::{self, SeekFrom};
+use crate::memory::address_space::{AddressSpace, Backing, COPY_ON_WRITE};
+use crate::memory::{USER_END, USER_START};
+use crate::println;
+use crate::usermode::Registers;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const MAGIC: &[u8; 8] = b"RSOSCKPT";
+const VERSION: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Descriptor kinds
+const DESCRIPTOR_CONSOLE: u8 = 0;
+const DESCRIPTOR_FILE: u8 = 1;
+
+/// Region kinds
+const REGION_ANONYMOUS: u8 = 0;
+const REGION_GUARD: u8 = 1;
+
+/// Page flags worth saving; the rest are set by mapping the page again
+const PAGE_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+/// Saves `process` to `path`, leaving it running
+pub fn save(process: &Arc<Process>, path: &str) -> Result<(), &'static str> {
+    if process.thread_count() != 1 {
+        return Err("only processes with a single thread can be saved");
+    }
+    let thread = stop(process)?;
+    let image = image(process, &thread);
+    make_ready(thread);
+    fs::write(path, &image?).map_err(|_| "failed to write the checkpoint")
+}
+
+/// Takes the process's thread off the ready queue, so it stays put while it's saved
+fn stop(process: &Process) -> Result<Arc<Thread>, &'static str> {
+    let mut ready = READY.lock();
+    let index = ready
+        .iter()
+        .position(|thread| ptr::eq(&**thread.process(), process))
+        .ok_or("the process is running")?;
+    Ok(ready.remove(index).unwrap())
+}
+
+/// The image of `process`, whose only thread is `thread`
+fn image(process: &Process, thread: &Thread) -> Result<Vec<u8>, &'static str> {
+    let space = process.space();
+    for region in space.regions() {
+        if let Backing::File { .. } = region.backing {
+            space.populate(VirtAddr::new(region.start), region.end - region.start)?;
+        }
+    }
+
+    let mut image = Encoder::default();
+    image.bytes(MAGIC);
+    image.u32(VERSION);
+    image.string(process.name());
+
+    let registers = *thread.registers.lock();
+    for value in registers_to_array(&registers) {
+        image.u64(value);
+    }
+    let fpu = thread.fpu().lock();
+    image.u32(fpu.area().len() as u32);
+    image.bytes(fpu.area());
+    drop(fpu);
+
+    let regions = space.regions();
+    image.u32(regions.len() as u32);
+    for region in regions {
+        image.u64(region.start);
+        image.u64(region.end);
+        image.u64(region.flags.bits());
+        image.u8(match region.backing {
+            Backing::Guard => REGION_GUARD,
+            _ => REGION_ANONYMOUS,
+        });
+    }
+
+    let files = process.files().clone();
+    image.u32(files.len() as u32);
+    for (fd, descriptor) in files.iter() {
+        image.u32(fd as u32);
+        match descriptor {
+            Descriptor::Console => image.u8(DESCRIPTOR_CONSOLE),
+            Descriptor::File(file) => {
+                let mut file = file.lock();
+                let path = file.path().ok_or("a descriptor's file has no path")?;
+                image.u8(DESCRIPTOR_FILE);
+                image.string(path);
+                let position = file
+                    .seek(SeekFrom::Current(0))
+                    .map_err(|_| "failed to find a file's position")?;
+                image.u64(position);
+            }
+            Descriptor::PipeReader(_) | Descriptor::PipeWriter(_) => {
+                return Err("processes with pipes can't be saved");
+            }
+        }
+    }
+
+    let count_at = image.reserve_u32();
+    let mut count = 0;
+    space.for_each_page(|address, flags, contents| {
+        let mut saved = flags & PAGE_FLAGS;
+        if flags.contains(COPY_ON_WRITE) {
+            saved |= PageTableFlags::WRITABLE;
+        }
+        image.u64(address.as_u64());
+        image.u64(saved.bits());
+        image.bytes(contents);
+        count += 1;
+    });
+    image.set_u32(count_at, count);
+    Ok(image.data)
+}
+
+/// Starts the process saved in `path`; it gets a new pid, in the group of the calling process
+pub fn restore(path: &str) -> Result<Arc<Process>, &'static str> {
+    let data = fs::read(path).map_err(|_| "failed to read the checkpoint")?;
+    let mut image = Decoder { data: &data, at: 0 };
+    if image.bytes(MAGIC.len())? != MAGIC {
+        return Err("not a checkpoint");
+    }
+    if image.u32()? != VERSION {
+        return Err("the checkpoint is of another version");
+    }
+    let name = String::from(image.string()?);
+
+    let mut values = [0; REGISTER_COUNT];
+    for value in &mut values {
+        *value = image.u64()?;
+    }
+    let registers = registers_from_array(values).sanitized();
+    if !(USER_START..USER_END).contains(&registers.rip)
+        || !(USER_START..=USER_END).contains(&registers.rsp)
+    {
+        return Err("the saved registers point outside the user part");
+    }
+    let mut fpu = FpuState::new();
+    let fpu_size = image.u32()? as usize;
+    fpu.set_area(image.bytes(fpu_size)?)?;
+
+    let mut space = AddressSpace::new()?;
+    for _ in 0..image.u32()? {
+        let start = image.u64()?;
+        let end = image.u64()?;
+        let flags = PageTableFlags::from_bits_truncate(image.u64()?) & PAGE_FLAGS;
+        let backing = match image.u8()? {
+            REGION_ANONYMOUS => Backing::Anonymous,
+            REGION_GUARD => Backing::Guard,
+            _ => return Err("the checkpoint has an unknown kind of region"),
+        };
+        let size = end
+            .checked_sub(start)
+            .ok_or("the checkpoint has a bad region")?;
+        space.reserve(VirtAddr::new_truncate(start), size, flags, backing)?;
+    }
+
+    let mut files = FileTable::default();
+    for _ in 0..image.u32()? {
+        let fd = image.u32()? as u64;
+        if fd as usize >= MAX_FILES {
+            return Err("the checkpoint has a descriptor out of range");
+        }
+        let descriptor = match image.u8()? {
+            DESCRIPTOR_CONSOLE => Descriptor::Console,
+            DESCRIPTOR_FILE => {
+                let path = image.string()?;
+                let position = image.u64()?;
+                let mut file = fs::open(path).map_err(|_| "a saved descriptor's file is gone")?;
+                file.seek(SeekFrom::Start(position))
+                    .map_err(|_| "failed to restore a file's position")?;
+                Descriptor::File(Arc::new(Mutex::new(file)))
+            }
+            _ => return Err("the checkpoint has an unknown kind of descriptor"),
+        };
+        files.set(fd, descriptor);
+    }
+
+    for _ in 0..image.u32()? {
+        let address = image.u64()?;
+        let flags = PageTableFlags::from_bits_truncate(image.u64()?) & PAGE_FLAGS;
+        let contents = image.bytes(PAGE_SIZE)?;
+        if !address.is_multiple_of(PAGE_SIZE as u64) {
+            return Err("the checkpoint has a page that isn't aligned");
+        }
+        space.map(
+            VirtAddr::new_truncate(address),
+            PAGE_SIZE as u64,
+            contents,
+            flags,
+        )?;
+    }
+
+    let group = current().map_or_else(group::root, |parent| parent.group());
+    let process = Process::create(name, space, files, group)?;
+    process.spawn_thread_with(registers, fpu);
+    Ok(process)
+}
+
+const REGISTER_COUNT: usize = core::mem::size_of::<Registers>() / 8;
+
+fn registers_to_array(registers: &Registers) -> [u64; REGISTER_COUNT] {
+    let r = registers;
+    [
+        r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rbp, r.r8, r.r9, r.r10, r.r11, r.r12, r.r13,
+        r.r14, r.r15, r.rip, r.cs, r.rflags, r.rsp, r.ss,
+    ]
+}
+
+fn registers_from_array(values: [u64; REGISTER_COUNT]) -> Registers {
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, r8, r9, r10, r11, r12, r13, r14, r15, rip, cs, rflags, rsp, ss] =
+        values;
+    Registers {
+        rax,
+        rbx,
+        rcx,
+        rdx,
+        rsi,
+        rdi,
+        rbp,
+        r8,
+        r9,
+        r10,
+        r11,
+        r12,
+        r13,
+        r14,
+        r15,
+        rip,
+        cs,
+        rflags,
+        rsp,
+        ss,
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.bytes(&(value.len() as u16).to_le_bytes());
+        self.bytes(value.as_bytes());
+    }
+
+    /// Room for a count that's only known later, see `set_u32`
+    fn reserve_u32(&mut self) -> usize {
+        self.u32(0);
+        self.data.len() - 4
+    }
+
+    fn set_u32(&mut self, at: usize, value: u32) {
+        self.data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .data
+            .get(self.at..self.at.saturating_add(len))
+            .ok_or("the checkpoint is truncated")?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(read_u32_le(self.bytes(4)?, 0))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(read_u64_le(self.bytes(8)?, 0))
+    }
+
+    fn string(&mut self) -> Result<&'a str, &'static str> {
+        let len = read_u16_le(self.bytes(2)?, 0) as usize;
+        core::str::from_utf8(self.bytes(len)?)
+            .map_err(|_| "the checkpoint has a string that isn't UTF-8")
+    }
+}
+
+/// `checkpoint save <pid> <path> | restore <path>`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        ["save", pid, path] => {
+            let pid = pid.parse().map_err(|_| "not a pid")?;
+            let process = super::get(Pid(pid)).ok_or("no such process")?;
+            save(&process, path)?;
+            println!("saved process {} to {}", process.pid(), path);
+        }
+        ["restore", path] => {
+            let process = restore(path)?;
+            println!("restored {} as process {}", process.name(), process.pid());
+        }
+        _ => return Err("usage: checkpoint save <pid> <path> | restore <path>"),
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! File descriptor tables: what the small integers a process passes to `read` and `write` stand for.
+
+use crate::fs::File;
+use crate::ipc::pipe;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Most descriptors a process may have open at once
+pub const MAX_FILES: usize = 64;
+
+/// What a descriptor refers to. Clones refer to the same thing; for a file that includes its
+/// position, like descriptors inherited across `fork` on Unix.
+#[derive(Clone)]
+pub enum Descriptor {
+    /// The process's terminal (see `tty`): writing prints on the console, reading takes what's
+    /// typed
+    Console,
+    File(Arc<Mutex<Box<dyn File>>>),
+    PipeReader(pipe::Reader),
+    PipeWriter(pipe::Writer),
+}
+
+#[derive(Clone, Default)]
+pub struct FileTable {
+    /// Indexed by descriptor number; closed descriptors are `None` until reused
+    entries: Vec<Option<Descriptor>>,
+}
+
+impl FileTable {
+    /// A table with standard input, output and error (descriptors 0 to 2) on the console
+    pub fn with_console() -> Self {
+        FileTable {
+            entries: (0..3).map(|_| Some(Descriptor::Console)).collect(),
+        }
+    }
+
+    /// Stores `descriptor` under the lowest free number, or hands it back if the table is full
+    pub fn insert(&mut self, descriptor: Descriptor) -> Result<u64, Descriptor> {
+        if let Some(fd) = self.entries.iter().position(Option::is_none) {
+            self.entries[fd] = Some(descriptor);
+            return Ok(fd as u64);
+        }
+        if self.entries.len() >= MAX_FILES {
+            return Err(descriptor);
+        }
+        self.entries.push(Some(descriptor));
+        Ok(self.entries.len() as u64 - 1)
+    }
+
+    /// Puts `descriptor` at `fd`, e.g. to redirect standard output before a process starts, and
+    /// returns what was there; `fd` must be below `MAX_FILES`
+    pub fn set(&mut self, fd: u64, descriptor: Descriptor) -> Option<Descriptor> {
+        let fd = fd as usize;
+        assert!(fd < MAX_FILES, "descriptor {} is out of range", fd);
+        if fd >= self.entries.len() {
+            self.entries.resize_with(fd + 1, || None);
+        }
+        self.entries[fd].replace(descriptor)
+    }
+
+    pub fn get_mut(&mut self, fd: u64) -> Option<&mut Descriptor> {
+        self.entries.get_mut(fd as usize)?.as_mut()
+    }
+
+    /// Closes `fd`, returning what it referred to
+    pub fn remove(&mut self, fd: u64) -> Option<Descriptor> {
+        self.entries.get_mut(fd as usize)?.take()
+    }
+
+    /// The open descriptors with their numbers, lowest first
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Descriptor)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, entry)| Some((fd as u64, entry.as_ref()?)))
+    }
+
+    /// Number of open descriptors
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
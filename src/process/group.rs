@@ -0,0 +1,422 @@
+//! Groups of processes that share CPU time and memory, like Linux's cgroups.
+//!
+//! Groups form a tree under the root group, `/`, and are named by their path: `/services`,
+//! `/services/net`. Every process is in one group; it starts in its parent's, or the root group
+//! if the kernel started it, and can be moved with `Process::set_group`.
+//!
+//! CPU time is divided by shares: among the groups under one parent with threads ready to run,
+//! each gets time in proportion to its `shares`, and the threads directly in the parent count as
+//! one more such group with `DEFAULT_SHARES`. Like Linux's CFS, every group keeps a virtual
+//! runtime that grows by the time its threads ran divided by its shares, and the scheduler picks,
+//! level by level, the group furthest behind. A group that was idle rejoins at the smallest
+//! virtual runtime among its siblings rather than catching up on the time it didn't use.
+//!
+//! Memory is counted in pages mapped by the processes in a group and the groups below it (see
+//! `AddressSpace::set_account`); a group with a limit refuses pages past it, which for a process
+//! touching new memory ends in a fault.
+//!
+//! Kernel heap allocated while a thread of a process runs, by its system calls and the faults it
+//! takes, is charged to the heap account of the process's group (see `allocator::quota`). A group
+//! may cap that too; unlike pages, it isn't counted towards the groups above.
+
+use crate::allocator::quota::HeapAccount;
+use crate::memory::address_space::PageAccount;
+use crate::println;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+
+/// What a new group gets, and what the threads directly in a group weigh against its children
+pub const DEFAULT_SHARES: u64 = 1024;
+/// The range `set_shares` accepts, as Linux's
+pub const MIN_SHARES: u64 = 2;
+pub const MAX_SHARES: u64 = 262_144;
+
+const PAGE_SIZE: u64 = 4096;
+
+pub struct Group {
+    /// The full path
+    path: String,
+    parent: Option<Arc<Group>>,
+    shares: AtomicU64,
+    /// In pages, 0 for none
+    memory_limit: AtomicU64,
+    /// Pages charged to this group and the ones below it
+    memory_used: AtomicU64,
+    /// Pages refused because of a limit here
+    memory_failures: AtomicU64,
+    /// Kernel heap used for the processes directly in this group
+    heap: HeapAccount,
+    /// Microseconds run by threads in this group and the ones below it
+    cpu_time: AtomicU64,
+    /// Virtual runtime among its siblings
+    vruntime: AtomicU64,
+    /// Virtual runtime of the threads directly in this group, among its children
+    own_vruntime: AtomicU64,
+    /// The smallest virtual runtime its children (and its own threads) can have; idle ones are
+    /// brought up to it
+    floor: AtomicU64,
+}
+
+impl Group {
+    fn new(path: String, parent: Option<Arc<Group>>) -> Group {
+        let floor = parent
+            .as_ref()
+            .map_or(0, |parent| parent.floor.load(Ordering::Relaxed));
+        Group {
+            path,
+            parent,
+            shares: AtomicU64::new(DEFAULT_SHARES),
+            memory_limit: AtomicU64::new(0),
+            memory_used: AtomicU64::new(0),
+            memory_failures: AtomicU64::new(0),
+            heap: HeapAccount::new("cgroup", None),
+            cpu_time: AtomicU64::new(0),
+            vruntime: AtomicU64::new(floor),
+            own_vruntime: AtomicU64::new(0),
+            floor: AtomicU64::new(0),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn parent(&self) -> Option<&Arc<Group>> {
+        self.parent.as_ref()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    pub fn shares(&self) -> u64 {
+        self.shares.load(Ordering::Relaxed)
+    }
+
+    pub fn set_shares(&self, shares: u64) -> Result<(), &'static str> {
+        if self.is_root() {
+            return Err("the root group has no siblings to share with");
+        }
+        if !(MIN_SHARES..=MAX_SHARES).contains(&shares) {
+            return Err("shares out of range");
+        }
+        self.shares.store(shares, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The memory limit in bytes, if there is one
+    pub fn memory_limit(&self) -> Option<u64> {
+        match self.memory_limit.load(Ordering::Relaxed) {
+            0 => None,
+            pages => Some(pages * PAGE_SIZE),
+        }
+    }
+
+    /// Limits the memory of the group and the ones below it to `limit` bytes, rounded up to
+    /// pages; what's already in use stays
+    pub fn set_memory_limit(&self, limit: Option<u64>) -> Result<(), &'static str> {
+        if self.is_root() {
+            return Err("the root group can't be limited");
+        }
+        let pages = limit.map_or(0, |limit| limit.div_ceil(PAGE_SIZE).max(1));
+        self.memory_limit.store(pages, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Bytes mapped by the processes in the group and the ones below it
+    pub fn memory_used(&self) -> u64 {
+        self.memory_used.load(Ordering::Relaxed) * PAGE_SIZE
+    }
+
+    /// Times pages were refused because of this group's limit
+    pub fn memory_failures(&self) -> u64 {
+        self.memory_failures.load(Ordering::Relaxed)
+    }
+
+    /// The account of the kernel heap allocated for the processes directly in the group
+    pub fn heap(&self) -> &HeapAccount {
+        &self.heap
+    }
+
+    /// Limits the kernel heap allocated for the processes directly in the group to `limit`
+    /// bytes; what's already allocated stays
+    pub fn set_heap_limit(&self, limit: Option<u64>) -> Result<(), &'static str> {
+        if self.is_root() {
+            return Err("the root group can't be limited");
+        }
+        self.heap
+            .set_limit(limit.map(|limit| limit.max(1) as usize));
+        Ok(())
+    }
+
+    /// Microseconds the threads in the group and the ones below it have run
+    pub fn cpu_time(&self) -> u64 {
+        self.cpu_time.load(Ordering::Relaxed)
+    }
+
+    /// The groups from the root down to this one
+    fn lineage(self: &Arc<Self>) -> Vec<Arc<Group>> {
+        let mut lineage = Vec::new();
+        let mut group = Some(self.clone());
+        while let Some(current) = group {
+            group = current.parent.clone();
+            lineage.push(current);
+        }
+        lineage.reverse();
+        lineage
+    }
+
+    fn effective(&self, parent_floor: u64) -> u64 {
+        self.vruntime.load(Ordering::Relaxed).max(parent_floor)
+    }
+
+    fn own_effective(&self) -> u64 {
+        let floor = self.floor.load(Ordering::Relaxed);
+        self.own_vruntime.load(Ordering::Relaxed).max(floor)
+    }
+}
+
+impl PageAccount for Group {
+    fn try_charge(&self, pages: u64) -> bool {
+        let mut group = Some(self);
+        while let Some(current) = group {
+            let used = current.memory_used.fetch_add(pages, Ordering::Relaxed) + pages;
+            let limit = current.memory_limit.load(Ordering::Relaxed);
+            if limit != 0 && used > limit {
+                current.memory_failures.fetch_add(1, Ordering::Relaxed);
+                // back out of this group and the ones charged below it
+                let mut undo = Some(self);
+                while let Some(charged) = undo {
+                    charged.memory_used.fetch_sub(pages, Ordering::Relaxed);
+                    if core::ptr::eq(charged, current) {
+                        break;
+                    }
+                    undo = charged.parent.as_deref();
+                }
+                return false;
+            }
+            group = current.parent.as_deref();
+        }
+        true
+    }
+
+    fn uncharge(&self, pages: u64) {
+        let mut group = Some(self);
+        while let Some(current) = group {
+            current.memory_used.fetch_sub(pages, Ordering::Relaxed);
+            group = current.parent.as_deref();
+        }
+    }
+}
+
+/// Where a ready thread stands in the share scheduling; see `SchedKey::compare`
+pub(super) struct SchedKey {
+    /// The thread's group and the ones above it, the root first
+    lineage: Vec<Arc<Group>>,
+}
+
+impl SchedKey {
+    pub(super) fn new(group: &Arc<Group>) -> SchedKey {
+        SchedKey {
+            lineage: group.lineage(),
+        }
+    }
+
+    fn group(&self) -> &Group {
+        self.lineage.last().expect("a lineage ends with the group")
+    }
+
+    /// Virtual runtimes from below the root down: those of the groups on the way, and last that
+    /// of the group's own threads
+    fn levels(&self) -> impl Iterator<Item = u64> + '_ {
+        self.lineage
+            .windows(2)
+            .map(|pair| pair[1].effective(pair[0].floor.load(Ordering::Relaxed)))
+            .chain(core::iter::once(self.group().own_effective()))
+    }
+
+    /// Which of two threads is further behind; where their paths part, this compares two
+    /// siblings, so the first difference decides
+    pub(super) fn compare(&self, other: &SchedKey) -> CmpOrdering {
+        self.levels().cmp(other.levels())
+    }
+
+    /// The thread was picked: its groups' siblings that were idle mustn't fall behind them
+    pub(super) fn picked(&self) {
+        for pair in self.lineage.windows(2) {
+            let (parent, group) = (&pair[0], &pair[1]);
+            let effective = group.effective(parent.floor.load(Ordering::Relaxed));
+            parent.floor.fetch_max(effective, Ordering::Relaxed);
+        }
+        let group = self.group();
+        group
+            .floor
+            .fetch_max(group.own_effective(), Ordering::Relaxed);
+    }
+
+    /// The thread ran for `micros`: charges it to its groups, weighted by their shares
+    pub(super) fn ran(&self, micros: u64) {
+        self.lineage[0]
+            .cpu_time
+            .fetch_add(micros, Ordering::Relaxed);
+        for pair in self.lineage.windows(2) {
+            let (parent, group) = (&pair[0], &pair[1]);
+            group.cpu_time.fetch_add(micros, Ordering::Relaxed);
+            let effective = group.effective(parent.floor.load(Ordering::Relaxed));
+            let weighted = micros * DEFAULT_SHARES / group.shares();
+            group
+                .vruntime
+                .store(effective + weighted, Ordering::Relaxed);
+        }
+        let group = self.group();
+        group
+            .own_vruntime
+            .store(group.own_effective() + micros, Ordering::Relaxed);
+    }
+}
+
+/// Every group but the root, by path
+static GROUPS: Mutex<BTreeMap<String, Arc<Group>>> = Mutex::new(BTreeMap::new());
+static ROOT: Once<Arc<Group>> = Once::new();
+
+pub fn root() -> Arc<Group> {
+    ROOT.call_once(|| Arc::new(Group::new(String::from("/"), None)))
+        .clone()
+}
+
+/// The group at `path`
+pub fn get(path: &str) -> Option<Arc<Group>> {
+    match path {
+        "/" => Some(root()),
+        _ => GROUPS.lock().get(path.trim_end_matches('/')).cloned(),
+    }
+}
+
+/// Every group, the root first, each before the ones below it
+pub fn list() -> Vec<Arc<Group>> {
+    let groups = GROUPS.lock();
+    core::iter::once(root())
+        .chain(groups.values().cloned())
+        .collect()
+}
+
+/// Creates the group at `path`, below an existing group
+pub fn create(path: &str) -> Result<Arc<Group>, &'static str> {
+    let path = path.trim_end_matches('/');
+    let (parent_path, name) = path.rsplit_once('/').ok_or("group paths start with /")?;
+    if name.is_empty() {
+        return Err("the root group already exists");
+    }
+    let parent = match parent_path {
+        "" => root(),
+        _ => get(parent_path).ok_or("the parent group doesn't exist")?,
+    };
+    let mut groups = GROUPS.lock();
+    if groups.contains_key(path) {
+        return Err("the group already exists");
+    }
+    let group = Arc::new(Group::new(String::from(path), Some(parent)));
+    groups.insert(String::from(path), group.clone());
+    Ok(group)
+}
+
+/// Removes the group at `path`, which must have no processes and no groups below it
+pub fn remove(path: &str) -> Result<(), &'static str> {
+    let path = path.trim_end_matches('/');
+    let mut groups = GROUPS.lock();
+    let group = groups.get(path).ok_or("no such group")?;
+    if groups
+        .values()
+        .any(|other| other.parent.as_ref().is_some_and(|p| Arc::ptr_eq(p, group)))
+    {
+        return Err("the group has groups below it");
+    }
+    if super::list()
+        .iter()
+        .any(|process| Arc::ptr_eq(&process.group(), group))
+    {
+        return Err("the group has processes");
+    }
+    groups.remove(path);
+    Ok(())
+}
+
+fn format_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(limit) => format!("{} KiB", limit / 1024),
+        None => String::from("none"),
+    }
+}
+
+/// A limit argument of the command, in KiB, in bytes
+fn parse_limit(limit: &str) -> Result<Option<u64>, &'static str> {
+    match limit {
+        "none" => Ok(None),
+        kib => Ok(Some(
+            kib.parse::<u64>().map_err(|_| "the limit must be in KiB")? * 1024,
+        )),
+    }
+}
+
+/// `cgroup [create|remove <path> | shares <path> <n> | limit|heap <path> <KiB>|none |
+/// move <pid> <path>]`; REFUSED counts pages and heap allocations together
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            println!(
+                "{:<24} {:>6} {:>10} {:>10} {:>10} {:>10} {:>7} {:>8} {:>6}",
+                "GROUP", "SHARES", "MEMORY", "LIMIT", "HEAP", "HEAP LIMIT", "REFUSED", "CPU ms",
+                "PROCS"
+            );
+            let processes = super::list();
+            for group in list() {
+                let members = processes
+                    .iter()
+                    .filter(|process| Arc::ptr_eq(&process.group(), &group))
+                    .count();
+                let heap = group.heap.stats();
+                println!(
+                    "{:<24} {:>6} {:>6} KiB {:>10} {:>6} KiB {:>10} {:>7} {:>8} {:>6}",
+                    group.path,
+                    group.shares(),
+                    group.memory_used() / 1024,
+                    format_limit(group.memory_limit()),
+                    heap.used / 1024,
+                    format_limit(heap.limit.map(|limit| limit as u64)),
+                    group.memory_failures() + heap.failures,
+                    group.cpu_time() / 1000,
+                    members
+                );
+            }
+        }
+        ["create", path] => drop(create(path)?),
+        ["remove", path] => remove(path)?,
+        ["shares", path, shares] => {
+            let shares = shares.parse().map_err(|_| "shares must be a number")?;
+            get(path).ok_or("no such group")?.set_shares(shares)?;
+        }
+        ["limit", path, limit] => {
+            let limit = parse_limit(limit)?;
+            get(path).ok_or("no such group")?.set_memory_limit(limit)?;
+        }
+        ["heap", path, limit] => {
+            let limit = parse_limit(limit)?;
+            get(path).ok_or("no such group")?.set_heap_limit(limit)?;
+        }
+        ["move", pid, path] => {
+            let pid = pid.parse().map_err(|_| "not a process id")?;
+            let process = super::get(super::Pid(pid)).ok_or("no such process")?;
+            process.set_group(get(path).ok_or("no such group")?)?;
+        }
+        _ => {
+            return Err("usage: cgroup [create|remove <path> | shares <path> <n> | limit|heap <path> <KiB>|none | move <pid> <path>]")
+        }
+    }
+    Ok(())
+}
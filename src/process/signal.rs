@@ -0,0 +1,345 @@
+//! Signals: telling a process that something happened, from outside or to one of its threads.
+//!
+//! A signal is sent to a process by Ctrl+C on its terminal (`Interrupt`), by the `kill` system
+//! call or command, or by a thread faulting (see `for_fault`). The numbers and names are Linux's.
+//! What the process does about one is its `Action`, which it sets with the `signal` system call:
+//! by default every signal ends it, or the signal can be ignored, or a handler run. `Kill` can't
+//! be ignored or handled and ends the process as soon as it's sent, and a fault can't be ignored
+//! either: the faulting instruction would only fault again.
+//!
+//! Signals are delivered as a thread is about to continue in ring 3, at the start of its time
+//! slice: a fault to the thread that faulted, anything else to whichever thread of the process
+//! gets there first. A system call returning with a signal pending ends the slice early so the
+//! signal doesn't wait for the next one.
+//!
+//! A handler is called on the thread's stack, below its red zone, as `handler(signal, frame)`:
+//! `frame` points to a `Frame`, with the registers the thread had when the signal came. The
+//! handler continues from there with the `sigreturn` system call, passing `frame` back, whose
+//! registers it may have changed, e.g. to skip a faulting instruction. It must not return: its
+//! return address is 0. Nothing is blocked while a handler runs, so another signal starts its
+//! handler on top.
+
+use super::{ExitStatus, Process, Thread};
+use crate::memory::{USER_END, USER_START};
+use crate::println;
+use crate::syscall;
+use crate::usermode::{Registers, UserExit};
+use core::fmt;
+use core::mem::size_of;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// Signal numbers go up to this
+const COUNT: usize = 32;
+
+/// Bytes below the stack pointer that code may use without moving it, per the System V ABI
+const RED_ZONE: u64 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Interrupt = 2,
+    IllegalInstruction = 4,
+    Trap = 5,
+    BusError = 7,
+    ArithmeticError = 8,
+    Kill = 9,
+    SegmentationFault = 11,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 7] = [
+        Signal::Interrupt,
+        Signal::IllegalInstruction,
+        Signal::Trap,
+        Signal::BusError,
+        Signal::ArithmeticError,
+        Signal::Kill,
+        Signal::SegmentationFault,
+    ];
+
+    pub fn number(self) -> u64 {
+        self as u64
+    }
+
+    pub fn from_number(number: u64) -> Option<Signal> {
+        Signal::ALL
+            .into_iter()
+            .find(|signal| signal.number() == number)
+    }
+
+    /// "SIGINT" and so on
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "SIGINT",
+            Signal::IllegalInstruction => "SIGILL",
+            Signal::Trap => "SIGTRAP",
+            Signal::BusError => "SIGBUS",
+            Signal::ArithmeticError => "SIGFPE",
+            Signal::Kill => "SIGKILL",
+            Signal::SegmentationFault => "SIGSEGV",
+        }
+    }
+
+    /// A signal by number or name, with or without the "SIG"
+    pub fn parse(text: &str) -> Option<Signal> {
+        if let Ok(number) = text.parse() {
+            return Signal::from_number(number);
+        }
+        let name = text.strip_prefix("SIG").unwrap_or(text);
+        Signal::ALL
+            .into_iter()
+            .find(|signal| signal.name()[3..].eq_ignore_ascii_case(name))
+    }
+
+    /// The signal for a thread leaving ring 3 with `exit`, with the address it's about: the one
+    /// accessed for a page fault, the instruction's for the rest. `None` for a regular exit.
+    pub fn for_fault(exit: &UserExit) -> Option<(Signal, u64)> {
+        Some(match *exit {
+            UserExit::PageFault { address, .. } | UserExit::StackOverflow { address, .. } => {
+                (Signal::SegmentationFault, address)
+            }
+            UserExit::GeneralProtection { rip, .. } => (Signal::SegmentationFault, rip),
+            UserExit::InvalidOpcode { rip, .. } => (Signal::IllegalInstruction, rip),
+            UserExit::DivideError { rip } => (Signal::ArithmeticError, rip),
+            UserExit::AlignmentCheck { rip } => (Signal::BusError, rip),
+            UserExit::Breakpoint { rip } => (Signal::Trap, rip),
+            UserExit::Exit { .. } | UserExit::Preempted => return None,
+        })
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a process does when it gets a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// End the process
+    Default,
+    Ignore,
+    /// Call the function at this address
+    Handler(VirtAddr),
+}
+
+impl Action {
+    /// How the `signal` system call passes an action: 0 for the default, 1 to ignore, or the
+    /// handler's address
+    pub fn from_raw(raw: u64) -> Option<Action> {
+        match raw {
+            0 => Some(Action::Default),
+            1 => Some(Action::Ignore),
+            address if (USER_START..USER_END).contains(&address) => {
+                Some(Action::Handler(VirtAddr::new(address)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> u64 {
+        match self {
+            Action::Default => 0,
+            Action::Ignore => 1,
+            Action::Handler(address) => address.as_u64(),
+        }
+    }
+}
+
+/// What a handler finds at its second argument
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Frame {
+    pub signal: u64,
+    /// For a fault, the address `Signal::for_fault` says it's about; 0 otherwise
+    pub address: u64,
+    /// Where `sigreturn` continues the thread
+    pub registers: Registers,
+}
+
+/// A process's actions and the signals sent to it that no thread took yet
+#[derive(Clone)]
+pub struct Signals {
+    actions: [Action; COUNT],
+    /// Bit `n` for signal `n`
+    pending: u32,
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            actions: [Action::Default; COUNT],
+            pending: 0,
+        }
+    }
+
+    pub fn action(&self, signal: Signal) -> Action {
+        self.actions[signal.number() as usize]
+    }
+
+    /// Sets what `signal` does and returns what it did
+    pub fn set_action(&mut self, signal: Signal, action: Action) -> Result<Action, &'static str> {
+        if signal == Signal::Kill && action != Action::Default {
+            return Err("SIGKILL can't be ignored or handled");
+        }
+        if action == Action::Ignore {
+            self.pending &= !(1 << signal.number());
+        }
+        Ok(core::mem::replace(
+            &mut self.actions[signal.number() as usize],
+            action,
+        ))
+    }
+
+    /// The same actions with nothing pending, for a copy of the process
+    pub fn inherited(&self) -> Signals {
+        Signals {
+            actions: self.actions,
+            pending: 0,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending != 0
+    }
+
+    fn raise(&mut self, signal: Signal) {
+        if self.action(signal) != Action::Ignore {
+            self.pending |= 1 << signal.number();
+        }
+    }
+
+    /// The lowest-numbered pending signal
+    fn take(&mut self) -> Option<Signal> {
+        let number = self.pending.trailing_zeros();
+        let signal = Signal::from_number(number.into())?;
+        self.pending &= !(1 << number);
+        Some(signal)
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals::new()
+    }
+}
+
+impl Process {
+    /// Sends `signal` to the process
+    pub fn signal(&self, signal: Signal) {
+        if signal == Signal::Kill {
+            return self.end(ExitStatus::Signaled(Signal::Kill));
+        }
+        self.signals.lock().raise(signal);
+    }
+
+    /// Sets what `signal` does to the process and returns what it did
+    pub fn set_signal_action(
+        &self,
+        signal: Signal,
+        action: Action,
+    ) -> Result<Action, &'static str> {
+        self.signals.lock().set_action(signal, action)
+    }
+
+    pub fn signals_pending(&self) -> bool {
+        self.signals.lock().is_pending()
+    }
+}
+
+/// Records that `thread` faulted with `exit`, for its process's handler to run before the thread
+/// continues; `false` if there's no handler and the fault ends the process
+pub(super) fn fault(thread: &Thread, exit: UserExit) -> bool {
+    let Some((signal, _)) = Signal::for_fault(&exit) else {
+        return false;
+    };
+    let handled = matches!(
+        thread.process.signals.lock().action(signal),
+        Action::Handler(_)
+    );
+    if handled {
+        *thread.fault.lock() = Some(exit);
+    }
+    handled
+}
+
+/// Sets `registers`, which `thread` is about to continue from, up to run the handlers of the
+/// signals due for it, or returns how the process ends for one that ends it. Runs in the
+/// process's address space.
+pub(super) fn deliver(thread: &Thread, registers: &mut Registers) -> Result<(), ExitStatus> {
+    let process = &thread.process;
+    if let Some(exit) = thread.fault.lock().take() {
+        let (signal, address) = Signal::for_fault(&exit).ok_or(ExitStatus::Killed(exit))?;
+        // the action may have changed since the fault
+        match process.signals.lock().action(signal) {
+            Action::Handler(handler) => enter_handler(registers, signal, address, handler)
+                .map_err(|_| ExitStatus::Killed(exit))?,
+            _ => return Err(ExitStatus::Killed(exit)),
+        }
+    }
+    loop {
+        let (signal, action) = {
+            let mut signals = process.signals.lock();
+            let Some(signal) = signals.take() else {
+                return Ok(());
+            };
+            (signal, signals.action(signal))
+        };
+        match action {
+            Action::Default => return Err(ExitStatus::Signaled(signal)),
+            Action::Ignore => {}
+            Action::Handler(handler) => enter_handler(registers, signal, 0, handler)
+                .map_err(|_| ExitStatus::Signaled(Signal::SegmentationFault))?,
+        }
+    }
+}
+
+/// Puts a `Frame` and a return address of 0 on the stack `registers` point to and has them call
+/// `handler` on it; fails if the stack can't take them
+fn enter_handler(
+    registers: &mut Registers,
+    signal: Signal,
+    address: u64,
+    handler: VirtAddr,
+) -> Result<(), ()> {
+    let size = size_of::<Frame>() as u64;
+    let frame_address = registers.rsp.checked_sub(RED_ZONE + size).ok_or(())? & !0xf;
+    // as if `call` had pushed it, leaving the stack pointer 8 off 16-byte alignment
+    let stack_pointer = frame_address - 8;
+    let start = VirtAddr::try_new(stack_pointer).map_err(|_| ())?;
+    if stack_pointer < USER_START || !syscall::user_accessible(start, 8 + size, true) {
+        return Err(());
+    }
+    let frame = Frame {
+        signal: signal.number(),
+        address,
+        registers: *registers,
+    };
+    unsafe {
+        start.as_mut_ptr::<u64>().write(0);
+        (frame_address as *mut Frame).write(frame);
+    }
+    registers.rip = handler.as_u64();
+    registers.rdi = signal.number();
+    registers.rsi = frame_address;
+    registers.rsp = stack_pointer;
+    registers.rflags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+    Ok(())
+}
+
+/// `kill <pid> [<signal>]`
+pub fn kill_command(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: kill <pid> [<signal>]";
+    let (pid, signal) = match args {
+        [pid] => (pid, Signal::Kill),
+        [pid, signal] => (pid, Signal::parse(signal).ok_or("no such signal")?),
+        _ => return Err(USAGE),
+    };
+    let pid = super::Pid(pid.parse().map_err(|_| USAGE)?);
+    let process = super::get(pid).ok_or("no such process")?;
+    process.signal(signal);
+    println!("sent {} to process {}", signal, pid);
+    Ok(())
+}
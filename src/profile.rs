@@ -0,0 +1,285 @@
+//! A sampling profiler: every `profile.interval_ticks` timer interrupts, each CPU records where
+//! the interrupt found it into the trace rings, as a `Sample` event followed by the interrupted
+//! code's callers as `Frame`s (see `trace` and `unwind`). In a process only the RIP is recorded,
+//! as user stacks aren't walked.
+//!
+//! `profile on` starts sampling and `profile` shows the functions the samples still in the rings
+//! were taken in most. For a flame graph, the samples go to COM1 one per line, as
+//! `profile: outermost;...;innermost`: `profile dump` sends the ones in the rings, and
+//! `profile stream on` sends them as they come, from the `streamer` task. `tools/flamegraph.py`
+//! folds a serial log of them into the input of `flamegraph.pl` or speedscope.
+//!
+//! `profile` on the command line samples and streams from the start, to see where the boot goes;
+//! `profile=<ticks>` sets the interval as well. The streamer starts with the scheduler, by when
+//! the rings may have overwritten the earliest samples, which it says. COM1 carries a few dozen
+//! deep stacks a second, so a short interval with several CPUs falls behind the same way.
+
+use crate::console::Terminal;
+use crate::drivers::serial;
+use crate::percpu::{self, MAX_CPUS};
+use crate::symbols;
+use crate::sysctl::Tunable;
+use crate::trace::{self, Category, Event, Followed, Follower, Record, SerialWriter};
+use crate::unwind::Frames;
+use crate::{cmdline, memory, println, process, timer};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+pub static INTERVAL: Tunable = Tunable::new(
+    "profile.interval_ticks",
+    "timer interrupts between two of the profiler's samples on a CPU",
+    5,
+    1,
+    1000,
+);
+
+/// Callers recorded under a sample
+const DEPTH: usize = 16;
+/// How often the streamer looks for new samples
+const STREAM_INTERVAL: Duration = Duration::from_millis(10);
+/// Functions `profile` lists
+const TOP: usize = 10;
+
+/// Timer interrupts each CPU took while sampling
+static TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Whether the streamer sends samples to COM1
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+/// Starts sampling and streaming if the command line says so
+pub fn init() {
+    if !cmdline::flag("profile") {
+        return;
+    }
+    if let Some(ticks) = cmdline::parse("profile") {
+        if INTERVAL.set(ticks).is_err() {
+            println!("profile: interval out of range");
+        }
+    }
+    trace::set_enabled(Category::Sample, true);
+    STREAMING.store(true, Ordering::Relaxed);
+}
+
+/// Whether this timer interrupt takes a sample
+fn due() -> bool {
+    if !trace::is_enabled(Category::Sample) {
+        return false;
+    }
+    let Some(cpu) = percpu::try_current() else {
+        return false;
+    };
+    TICKS[cpu.index]
+        .fetch_add(1, Ordering::Relaxed)
+        .is_multiple_of(INTERVAL.get())
+}
+
+fn current_pid() -> u64 {
+    process::current().map_or(0, |process| process.pid().0)
+}
+
+/// Samples the kernel code the timer interrupted at `rip`; `frames` are the interrupt handler's,
+/// which start at `rip`
+pub fn kernel_tick(rip: u64, frames: Frames) {
+    if !due() {
+        return;
+    }
+    let mut frames = frames.peekable();
+    if frames.peek() == Some(&rip) {
+        frames.next();
+    }
+    trace::record_sample(rip, current_pid(), frames, DEPTH);
+}
+
+/// Samples the process code the timer interrupted at `rip`
+pub fn user_tick(rip: u64) {
+    if due() {
+        trace::record_sample(rip, current_pid(), core::iter::empty(), 0);
+    }
+}
+
+/// A sample put back together from its events
+struct Sample {
+    rip: u64,
+    pid: u64,
+    /// Innermost first
+    callers: Vec<u64>,
+    user: bool,
+}
+
+impl Sample {
+    /// `outermost;...;innermost`, functions by name where there's one
+    fn fold(&self) -> String {
+        let mut folded = String::new();
+        if self.user {
+            let _ = write!(folded, "[pid {}];{:#x}", self.pid, self.rip);
+            return folded;
+        }
+        for &address in self.callers.iter().rev().chain([&self.rip]) {
+            if !folded.is_empty() {
+                folded.push(';');
+            }
+            match symbols::lookup(address) {
+                Some(symbol) => folded.push_str(symbol.name),
+                None => {
+                    let _ = write!(folded, "{:#x}", address);
+                }
+            }
+        }
+        folded
+    }
+}
+
+/// Collects each CPU's samples from the trace events, which come in order on each CPU
+struct Assembler {
+    pending: Vec<Option<Sample>>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            pending: (0..MAX_CPUS).map(|_| None).collect(),
+        }
+    }
+
+    /// Takes the next event of `record.cpu`; returns the sample it completes, if any
+    fn push(&mut self, record: &Record) -> Option<Sample> {
+        let pending = &mut self.pending[record.cpu];
+        match record.event {
+            Event::Sample { rip, pid } => pending.replace(Sample {
+                rip,
+                pid,
+                callers: Vec::new(),
+                user: (memory::USER_START..memory::USER_END).contains(&rip),
+            }),
+            Event::Frame { address, .. } => {
+                if let Some(sample) = pending.as_mut() {
+                    sample.callers.push(address);
+                }
+                None
+            }
+            _ => pending.take(),
+        }
+    }
+
+    /// Takes the samples still waiting for the event after them
+    fn drain(&mut self) -> impl Iterator<Item = Sample> + '_ {
+        self.pending.iter_mut().filter_map(Option::take)
+    }
+}
+
+/// The samples still in the trace rings, oldest first on each CPU
+fn samples() -> Vec<Sample> {
+    let mut assembler = Assembler::new();
+    let mut samples: Vec<Sample> = trace::snapshot()
+        .iter()
+        .filter_map(|record| assembler.push(record))
+        .collect();
+    samples.extend(assembler.drain());
+    samples
+}
+
+fn serial_writer() -> Result<SerialWriter, &'static str> {
+    let port = serial::console_port().ok_or("no serial port")?;
+    Ok(SerialWriter(Terminal(port)))
+}
+
+/// Sends new samples to COM1 while streaming is on; meant to be spawned as a task. Returns at
+/// once if there's no serial port.
+pub async fn streamer() {
+    let Ok(mut out) = serial_writer() else {
+        return;
+    };
+    let mut follower = Follower::default();
+    let mut assembler = Assembler::new();
+    loop {
+        timer::sleep(STREAM_INTERVAL).await;
+        if !STREAMING.load(Ordering::Relaxed) {
+            follower.skip();
+            for sample in assembler.drain() {
+                let _ = writeln!(out, "profile: {}", sample.fold());
+            }
+            continue;
+        }
+        follower.read(|followed| match followed {
+            Followed::Record(record) => {
+                if let Some(sample) = assembler.push(&record) {
+                    let _ = writeln!(out, "profile: {}", sample.fold());
+                }
+            }
+            Followed::Lost { cpu, count } => {
+                let _ = writeln!(out, "profile: cpu {} lost {} events", cpu, count);
+            }
+        });
+    }
+}
+
+/// The status and where the samples in the rings were taken most
+fn show() {
+    let state = match trace::is_enabled(Category::Sample) {
+        true => "on",
+        false => "off",
+    };
+    println!(
+        "sampling {}, every {} timer interrupts, streaming {}",
+        state,
+        INTERVAL.get(),
+        match STREAMING.load(Ordering::Relaxed) {
+            true => "on",
+            false => "off",
+        }
+    );
+    let samples = samples();
+    if samples.is_empty() {
+        println!("profile: no samples");
+        return;
+    }
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for sample in &samples {
+        let name = match sample.user {
+            true => alloc::format!("[pid {}]", sample.pid),
+            false => symbols::lookup(sample.rip).map_or_else(
+                || alloc::format!("{:#x}", sample.rip),
+                |symbol| String::from(symbol.name),
+            ),
+        };
+        *counts.entry(name).or_default() += 1;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|(_, count)| core::cmp::Reverse(*count));
+    println!("{} samples in the buffers", samples.len());
+    println!("{:>7} {:>4}  FUNCTION", "SAMPLES", "%");
+    for (name, count) in counts.iter().take(TOP) {
+        println!("{:>7} {:>4}  {}", count, count * 100 / samples.len(), name);
+    }
+}
+
+/// `profile [on [<ticks>] | off | dump | stream on|off]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => show(),
+        ["on"] => trace::set_enabled(Category::Sample, true),
+        ["on", ticks] => {
+            INTERVAL.set(ticks.parse().map_err(|_| "not a number")?)?;
+            trace::set_enabled(Category::Sample, true);
+        }
+        ["off"] => trace::set_enabled(Category::Sample, false),
+        ["dump"] => {
+            let mut out = serial_writer()?;
+            let samples = samples();
+            for sample in &samples {
+                let _ = writeln!(out, "profile: {}", sample.fold());
+            }
+            println!("profile: {} samples sent to COM1", samples.len());
+        }
+        ["stream", "on"] => {
+            serial_writer()?;
+            STREAMING.store(true, Ordering::Relaxed);
+        }
+        ["stream", "off"] => STREAMING.store(false, Ordering::Relaxed),
+        _ => return Err("usage: profile [on [<ticks>] | off | dump | stream on|off]"),
+    }
+    Ok(())
+}
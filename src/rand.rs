@@ -0,0 +1,227 @@
+//! Random numbers: for address space layout, network sequence numbers, keys and fuzzing.
+//!
+//! `fill_bytes` and friends draw from the CPU's random number generator (RDRAND) where CPUID says
+//! there is one. Without it, or should it keep failing, they fall back to a `ChaChaRng` seeded
+//! from TSC jitter: how long the same few memory accesses take varies with the state of caches,
+//! pipelines and buses, and those variations, hashed, make the seed. The fallback erases its key
+//! with every block it produces, so its past output can't be recovered from its state.
+//!
+//! `RngCore` is what code that takes a generator should accept: `KernelRng` is the one above,
+//! and `ChaChaRng::from_seed` gives reproducible streams, e.g. to replay a fuzzing run.
+
+use crate::crypto::chacha20poly1305::{chacha20, KEY_SIZE, NONCE_SIZE};
+use crate::crypto::Sha256;
+use crate::{interrupts, time};
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::hint::black_box;
+use raw_cpuid::CpuId;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Intel's advice: a healthy RDRAND practically never fails 10 times in a row
+const RDRAND_RETRIES: usize = 10;
+/// Timing measurements that go into a jitter seed
+const JITTER_SAMPLES: usize = 1024;
+/// ChaCha20 blocks generated at once; the first 32 bytes become the next key
+const CHACHA_BLOCKS: usize = 4;
+
+/// A source of random numbers
+pub trait RngCore {
+    fn fill_bytes(&mut self, dest: &mut [u8]);
+
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// A number in `0..bound`, without the bias of a plain `%`; `bound` must not be 0
+    fn below(&mut self, bound: u64) -> u64 {
+        // reject the values past the last whole multiple of `bound`
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+}
+
+/// A ChaCha20 keystream used as a generator, with fast key erasure
+pub struct ChaChaRng {
+    key: [u8; KEY_SIZE],
+    buffer: [u8; 64 * CHACHA_BLOCKS],
+    /// Bytes of `buffer` handed out; the key's share counts as used
+    used: usize,
+}
+
+impl ChaChaRng {
+    /// The generator `seed` determines; the same seed gives the same numbers
+    pub fn from_seed(seed: [u8; KEY_SIZE]) -> ChaChaRng {
+        ChaChaRng {
+            key: seed,
+            buffer: [0; 64 * CHACHA_BLOCKS],
+            used: 64 * CHACHA_BLOCKS,
+        }
+    }
+
+    /// A generator seeded by the hardware if it can, by TSC jitter otherwise
+    pub fn from_entropy() -> ChaChaRng {
+        let mut seed = [0; KEY_SIZE];
+        if !hardware_fill(&mut seed) {
+            seed = jitter_seed();
+        }
+        ChaChaRng::from_seed(seed)
+    }
+
+    fn refill(&mut self) {
+        self.buffer.fill(0);
+        // every key is used for one run of blocks only, so the nonce can stay 0
+        chacha20(&self.key, 0, &[0; NONCE_SIZE], &mut self.buffer);
+        self.key.copy_from_slice(&self.buffer[..KEY_SIZE]);
+        self.buffer[..KEY_SIZE].fill(0);
+        self.used = KEY_SIZE;
+    }
+}
+
+impl RngCore for ChaChaRng {
+    fn fill_bytes(&mut self, mut dest: &mut [u8]) {
+        while !dest.is_empty() {
+            if self.used == self.buffer.len() {
+                self.refill();
+            }
+            let count = dest.len().min(self.buffer.len() - self.used);
+            let (head, tail) = dest.split_at_mut(count);
+            let output = &mut self.buffer[self.used..self.used + count];
+            head.copy_from_slice(output);
+            output.fill(0); // handed out once only
+            self.used += count;
+            dest = tail;
+        }
+    }
+}
+
+/// The kernel's generator, the one `fill_bytes` uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelRng;
+
+impl RngCore for KernelRng {
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_bytes(dest);
+    }
+}
+
+/// Whether the CPU has RDRAND and RDSEED
+struct Hardware {
+    rdrand: bool,
+    rdseed: bool,
+}
+
+fn hardware() -> &'static Hardware {
+    static HARDWARE: Once<Hardware> = Once::new();
+    HARDWARE.call_once(|| {
+        let cpuid = CpuId::new();
+        Hardware {
+            rdrand: cpuid
+                .get_feature_info()
+                .is_some_and(|info| info.has_rdrand()),
+            rdseed: cpuid
+                .get_extended_feature_info()
+                .is_some_and(|info| info.has_rdseed()),
+        }
+    })
+}
+
+/// The fallback generator, created on first use
+static FALLBACK: Mutex<Option<ChaChaRng>> = Mutex::new(None);
+
+/// Fills `dest` with random bytes
+pub fn fill_bytes(dest: &mut [u8]) {
+    if hardware().rdrand && fill_with(dest, rdrand) {
+        return;
+    }
+    // interrupt handlers may want numbers too
+    without_interrupts(|| {
+        FALLBACK
+            .lock()
+            .get_or_insert_with(ChaChaRng::from_entropy)
+            .fill_bytes(dest)
+    });
+}
+
+pub fn next_u32() -> u32 {
+    KernelRng.next_u32()
+}
+
+pub fn next_u64() -> u64 {
+    KernelRng.next_u64()
+}
+
+/// Whether the numbers come from the CPU rather than the fallback
+pub fn is_hardware() -> bool {
+    hardware().rdrand
+}
+
+/// Fills `dest` with seed-grade bytes from RDSEED, or RDRAND without it; false if the CPU has
+/// neither or they keep failing
+fn hardware_fill(dest: &mut [u8]) -> bool {
+    let hardware = hardware();
+    (hardware.rdseed && fill_with(dest, rdseed)) || (hardware.rdrand && fill_with(dest, rdrand))
+}
+
+/// Fills `dest` from `step`, giving up if it fails too often
+fn fill_with(dest: &mut [u8], step: fn() -> Option<u64>) -> bool {
+    for chunk in dest.chunks_mut(8) {
+        let Some(value) = (0..RDRAND_RETRIES).find_map(|_| step()) else {
+            return false;
+        };
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+    true
+}
+
+/// One RDRAND; `None` when the generator had nothing ready
+fn rdrand() -> Option<u64> {
+    let (value, ok): (u64, u8);
+    unsafe {
+        asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    }
+    (ok != 0).then_some(value)
+}
+
+/// One RDSEED, straight from the entropy source; fails more often than RDRAND
+fn rdseed() -> Option<u64> {
+    let (value, ok): (u64, u8);
+    unsafe {
+        asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    }
+    (ok != 0).then_some(value)
+}
+
+/// A seed hashed from how long identical bits of work take
+fn jitter_seed() -> [u8; KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(&time::now().to_le_bytes());
+    hasher.update(&interrupts::ticks().to_le_bytes());
+    let mut scratch = [0u8; 4096];
+    let mut previous = unsafe { _rdtsc() };
+    for sample in 0..JITTER_SAMPLES {
+        // strided writes, so the time depends on which lines are cached
+        for index in (0..scratch.len()).step_by(64 + sample % 64) {
+            scratch[index] = scratch[index].wrapping_add(previous as u8);
+        }
+        black_box(&mut scratch);
+        let now = unsafe { _rdtsc() };
+        hasher.update(&now.wrapping_sub(previous).to_le_bytes());
+        previous = now;
+    }
+    hasher.finish()
+}
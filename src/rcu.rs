@@ -0,0 +1,240 @@
+//! Read-copy-update: read-mostly data that readers reach without taking a lock.
+//!
+//! An `Rcu<T>` holds a pointer to the current version of its value. Readers enter a read-side
+//! section with `read_lock` and dereference the pointer; writers copy the value, change the copy
+//! and publish it with one atomic store. The old version can't be freed right away, since a
+//! reader on another CPU may still be looking at it, so it's retired and dropped once every CPU
+//! has passed a quiescent state, a point where it holds no references into any `Rcu`.
+//!
+//! Grace periods are tracked with epochs. Retiring a value bumps the global epoch; each CPU
+//! records the epoch it last saw while outside a read-side section, from the timer interrupt
+//! and between tasks. Once every online CPU has recorded an epoch at or past the one a value
+//! was retired in, nobody can still see it; offline CPUs hold no references and don't count.
+//! Retired values are dropped by `reclaim`, which the executor runs in task context, never from
+//! an interrupt handler.
+//!
+//! A CPU halted in `interrupts::tickless::idle` may not see a timer interrupt for a long time;
+//! it marks itself idle instead, which counts as quiescent for as long as no interrupt handler on
+//! it is inside a read-side section.
+//!
+//! A read-side section must not span an `.await`: it would hold up every grace period until the
+//! task is polled again.
+
+use crate::percpu::{self, MAX_CPUS};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Bumped every time something is retired; starts above the 0 every CPU starts out with
+static EPOCH: AtomicU64 = AtomicU64::new(1);
+/// The epoch each CPU last saw while outside a read-side section
+static QUIESCENT: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// How deeply each CPU is nested in read-side sections; interrupts may start their own
+static NESTING: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+/// Which CPUs are halted without a timer tick (see `enter_idle`)
+static IDLE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Deferred callbacks with the epoch whose grace period they wait for, oldest first
+static DEFERRED: Mutex<VecDeque<(u64, Callback)>> = Mutex::new(VecDeque::new());
+
+/// Marks a read-side section on this CPU; references obtained through it can't outlive it.
+/// Not `Send`, since the section belongs to the CPU it was entered on.
+pub struct ReadGuard {
+    cpu: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enters a read-side section; sections nest
+pub fn read_lock() -> ReadGuard {
+    let cpu = percpu::current().index;
+    // SeqCst orders the increment before every load of a protected pointer that follows
+    NESTING[cpu].fetch_add(1, Ordering::SeqCst);
+    ReadGuard {
+        cpu,
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        NESTING[self.cpu].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reports that this CPU holds no RCU references, unless it's inside a read-side section (an
+/// interrupt may arrive in one). Called from the timer interrupt and the executor loop.
+pub fn quiescent_state() {
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+    if NESTING[cpu.index].load(Ordering::SeqCst) == 0 {
+        QUIESCENT[cpu.index].store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+/// Marks this CPU as idle until `exit_idle`: it holds no references and may not report
+/// quiescent states in the meantime
+pub fn enter_idle() {
+    quiescent_state();
+    if let Some(cpu) = percpu::try_current() {
+        IDLE[cpu.index].store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn exit_idle() {
+    if let Some(cpu) = percpu::try_current() {
+        IDLE[cpu.index].store(false, Ordering::SeqCst);
+    }
+    quiescent_state();
+}
+
+/// Starts a grace period: everything unpublished before this call is unreachable once the epoch
+/// returned here has been observed by every CPU
+fn start_grace_period() -> u64 {
+    EPOCH.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+fn grace_period_over(epoch: u64) -> bool {
+    (0..percpu::count()).all(|cpu| {
+        !percpu::is_online(cpu)
+            || QUIESCENT[cpu].load(Ordering::SeqCst) >= epoch
+            || (IDLE[cpu].load(Ordering::SeqCst) && NESTING[cpu].load(Ordering::SeqCst) == 0)
+    })
+}
+
+/// Waits until every reader that might still see a value unpublished before the call is gone.
+/// Must not be called inside a read-side section, which would wait for itself.
+pub fn synchronize() {
+    let epoch = start_grace_period();
+    if let Some(cpu) = percpu::try_current() {
+        assert!(
+            NESTING[cpu.index].load(Ordering::SeqCst) == 0,
+            "rcu::synchronize inside a read-side section"
+        );
+    }
+    quiescent_state();
+    while !grace_period_over(epoch) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs `callback` from `reclaim` after a grace period, without waiting for it here
+pub fn defer(callback: impl FnOnce() + Send + 'static) {
+    let epoch = start_grace_period();
+    DEFERRED.lock().push_back((epoch, Box::new(callback)));
+}
+
+/// Runs the deferred callbacks whose grace period is over; returns how many ran
+pub fn reclaim() -> usize {
+    let mut ran = 0;
+    loop {
+        // callbacks may free memory or retire more values, so they run without the lock held
+        let callback = {
+            let mut deferred = DEFERRED.lock();
+            match deferred.front() {
+                Some(&(epoch, _)) if grace_period_over(epoch) => deferred.pop_front().unwrap().1,
+                _ => return ran,
+            }
+        };
+        callback();
+        ran += 1;
+    }
+}
+
+/// Number of deferred callbacks still waiting for their grace period
+pub fn pending() -> usize {
+    DEFERRED.lock().len()
+}
+
+/// An old version on its way to `reclaim`
+struct Retired<T>(*mut T);
+
+// only the reclaiming CPU touches the value again, to drop it
+unsafe impl<T: Send> Send for Retired<T> {}
+
+impl<T> Retired<T> {
+    /// Takes `self` whole, so a closure calling this captures all of it rather than the pointer
+    unsafe fn free(self) {
+        drop(Box::from_raw(self.0));
+    }
+}
+
+/// A value readers access without locks and writers replace wholesale
+pub struct Rcu<T: Send + Sync + 'static> {
+    current: AtomicPtr<T>,
+    /// Serializes writers, so no update is lost to a concurrent one
+    writer: Mutex<()>,
+}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    pub fn new(value: T) -> Self {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current version, valid for as long as the read-side section `guard` stands for
+    pub fn get<'g>(&self, _guard: &'g ReadGuard) -> &'g T {
+        unsafe { &*self.current.load(Ordering::SeqCst) }
+    }
+
+    /// Runs `f` on the current version inside a read-side section of its own
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let guard = read_lock();
+        f(self.get(&guard))
+    }
+
+    /// Installs `new` as the current version and retires the previous one
+    fn publish(&self, new: T) {
+        let old = self
+            .current
+            .swap(Box::into_raw(Box::new(new)), Ordering::SeqCst);
+        let old = Retired(old);
+        defer(move || unsafe { old.free() });
+    }
+
+    /// Replaces the value; readers see either the old or the new one, never a mix
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.publish(value);
+    }
+
+    /// Changes a copy of the value with `f` and publishes the copy
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock();
+        // writers are serialized, so the current version can't be retired under us
+        let mut copy = unsafe { (*self.current.load(Ordering::SeqCst)).clone() };
+        let result = f(&mut copy);
+        self.publish(copy);
+        result
+    }
+
+    /// Like `update`, but publishes the copy only if `f` succeeds
+    pub fn try_update<R, E>(&self, f: impl FnOnce(&mut T) -> Result<R, E>) -> Result<R, E>
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock();
+        let mut copy = unsafe { (*self.current.load(Ordering::SeqCst)).clone() };
+        let result = f(&mut copy)?;
+        self.publish(copy);
+        Ok(result)
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // `&mut self` means no reader can hold a reference any more
+        let current = core::mem::replace(self.current.get_mut(), ptr::null_mut());
+        drop(unsafe { Box::from_raw(current) });
+    }
+}
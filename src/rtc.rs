@@ -0,0 +1,178 @@
+use crate::acpi;
+use crate::arch::port::{Port, WriteOnlyPort};
+use crate::sync::spinlock::IrqSpinLock;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const NMI_DISABLE: u8 = 0x80; // keep NMIs off while a CMOS register is selected
+
+mod reg {
+    pub const SECONDS: u8 = 0x00;
+    pub const MINUTES: u8 = 0x02;
+    pub const HOURS: u8 = 0x04;
+    pub const DAY: u8 = 0x07;
+    pub const MONTH: u8 = 0x08;
+    pub const YEAR: u8 = 0x09;
+    pub const STATUS_A: u8 = 0x0A;
+    pub const STATUS_B: u8 = 0x0B;
+}
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// A calendar date and time as kept by the RTC, which by convention runs on UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The calendar date and time `seconds` after 1970-01-01 00:00:00 UTC
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        // civil_from_days, the inverse of the conversion below
+        let days = (seconds / 86_400) as i64;
+        let second_of_day = seconds % 86_400;
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+        }
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix_seconds(&self) -> u64 {
+        // days_from_civil, see http://howardhinnant.github.io/date_algorithms.html
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds =
+            days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+}
+
+/// Serializes uses of the address port, which selects the register the data port reads and writes
+static CMOS: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// Reads a byte of CMOS: a clock register or, from 0x0E on, battery-backed memory
+pub fn read_register(register: u8) -> u8 {
+    let _cmos = CMOS.lock();
+    let mut address = unsafe { WriteOnlyPort::<u8>::new(CMOS_ADDRESS) };
+    let mut data = unsafe { Port::<u8>::new(CMOS_DATA) };
+    address.write(NMI_DISABLE | register);
+    data.read()
+}
+
+/// Writes a byte of CMOS, see `read_register`
+pub fn write_register(register: u8, value: u8) {
+    let _cmos = CMOS.lock();
+    let mut address = unsafe { WriteOnlyPort::<u8>::new(CMOS_ADDRESS) };
+    let mut data = unsafe { Port::<u8>::new(CMOS_DATA) };
+    address.write(NMI_DISABLE | register);
+    data.write(value);
+}
+
+/// The raw register values; reading twice and comparing avoids tearing across an update
+fn read_raw(century_register: u8) -> [u8; 7] {
+    while read_register(reg::STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century = match century_register {
+        0 => 0,
+        register => read_register(register),
+    };
+    [
+        read_register(reg::SECONDS),
+        read_register(reg::MINUTES),
+        read_register(reg::HOURS),
+        read_register(reg::DAY),
+        read_register(reg::MONTH),
+        read_register(reg::YEAR),
+        century,
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Reads the current date and time from the CMOS real-time clock
+pub fn read() -> DateTime {
+    // the FADT says where (and whether) the firmware keeps the century
+    let century_register = acpi::fadt().map_or(0, |fadt| fadt.century_register);
+
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+
+    let status_b = read_register(reg::STATUS_B);
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour = match (hour, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (hour, true) => hour + 12,
+            (hour, false) => hour,
+        };
+    }
+
+    let year = match century {
+        0 => 2000 + year as u16, // no century register, assume the current one
+        century => century as u16 * 100 + year as u16,
+    };
+    DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
@@ -0,0 +1,195 @@
+//! Checksums over long-lived kernel structures, verified periodically by `scrubber`.
+//!
+//! Two kinds of structures are covered:
+//!
+//! * tables that never change once set up (IDT, GDT) are registered with `protect_static`, which
+//!   checksums their bytes in place;
+//! * data the kernel does update is kept in a `Sealed<T>`, whose checksum is recomputed on every
+//!   `update` and so only goes stale when something writes behind the kernel's back: the page
+//!   table roots (`memory::PAGE_TABLE_ROOTS`), the process table and the kernel log's header.
+//!
+//! A mismatch means memory was corrupted, e.g. by a device DMAing to a stale address or a driver
+//! scribbling through a bad pointer, and is reported long before the damage would be noticed.
+
+use crate::sysctl::Tunable;
+use crate::{errorln, timer};
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// How often `scrubber` re-verifies everything, in seconds
+pub static INTERVAL: Tunable = Tunable::new(
+    "scrub.interval_s",
+    "seconds between checks of the protected kernel structures",
+    10,
+    1,
+    3600,
+);
+
+/// 64-bit FNV-1a, fast and good enough to catch flipped bits and overwritten words
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn checksum_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = Fnv1a::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Something the scrubber can verify
+pub trait Scrub: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Recomputes the checksum, returning false if it no longer matches
+    fn verify(&self) -> bool;
+}
+
+struct SealedInner<T> {
+    value: T,
+    /// `None` until the first `update`, when the value is still its compile-time initializer
+    checksum: Option<u64>,
+}
+
+/// A value whose every legitimate change goes through `update`, so any other change is corruption
+pub struct Sealed<T> {
+    name: &'static str,
+    inner: Mutex<SealedInner<T>>,
+}
+
+impl<T: Hash> Sealed<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Sealed {
+            name,
+            inner: Mutex::new(SealedInner {
+                value,
+                checksum: None,
+            }),
+        }
+    }
+
+    /// Runs `f` on the value
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.inner.lock().value)
+    }
+
+    /// Runs `f` on the value unless it's locked, for the crash debugger, which can't wait
+    pub fn try_read<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.inner.try_lock().map(|inner| f(&inner.value))
+    }
+
+    /// Modifies the value and reseals it with a fresh checksum
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut inner = self.inner.lock();
+        let result = f(&mut inner.value);
+        inner.checksum = Some(checksum_of(&inner.value));
+        result
+    }
+}
+
+impl<T: Hash + Send> Scrub for Sealed<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Runs with interrupts off, as some values are updated from interrupt handlers
+    fn verify(&self) -> bool {
+        without_interrupts(|| {
+            let inner = self.inner.lock();
+            inner
+                .checksum
+                .is_none_or(|checksum| checksum == checksum_of(&inner.value))
+        })
+    }
+}
+
+/// Bytes of a structure that must stay exactly as they were when registered
+struct StaticRegion {
+    name: &'static str,
+    start: usize,
+    len: usize,
+    checksum: u64,
+}
+
+impl StaticRegion {
+    fn bytes(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, self.len) }
+    }
+}
+
+static STATIC_REGIONS: Mutex<Vec<StaticRegion>> = Mutex::new(Vec::new());
+static SEALED: Mutex<Vec<&'static dyn Scrub>> = Mutex::new(Vec::new());
+static CORRUPTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Checksums `value` as it is now; any later change to its bytes counts as corruption
+pub fn protect_static<T>(name: &'static str, value: &'static T) {
+    let region = StaticRegion {
+        name,
+        start: value as *const T as usize,
+        len: core::mem::size_of::<T>(),
+        checksum: 0,
+    };
+    let checksum = checksum_of(region.bytes());
+    STATIC_REGIONS
+        .lock()
+        .push(StaticRegion { checksum, ..region });
+}
+
+/// Adds a `Sealed` (or any other `Scrub` implementation) to the periodic checks
+pub fn register(structure: &'static dyn Scrub) {
+    SEALED.lock().push(structure);
+}
+
+/// Verifies everything once and returns the names of the structures found corrupted
+pub fn verify_all() -> Vec<&'static str> {
+    let mut corrupted: Vec<&'static str> = STATIC_REGIONS
+        .lock()
+        .iter()
+        .filter(|region| checksum_of(region.bytes()) != region.checksum)
+        .map(|region| region.name)
+        .collect();
+    let sealed: Vec<&'static dyn Scrub> = SEALED.lock().clone();
+    corrupted.extend(
+        sealed
+            .iter()
+            .filter(|structure| !structure.verify())
+            .map(|structure| structure.name()),
+    );
+    corrupted
+}
+
+/// Number of corrupted structures the scrubber has found since boot
+pub fn corruptions() -> u64 {
+    CORRUPTIONS.load(Ordering::Relaxed)
+}
+
+/// Re-verifies every protected structure each `INTERVAL` seconds; meant to be spawned as a task
+pub async fn scrubber() {
+    loop {
+        timer::sleep(Duration::from_secs(INTERVAL.get())).await;
+        for name in verify_all() {
+            CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+            errorln!("scrub: checksum mismatch in {}, memory was corrupted", name);
+        }
+    }
+}
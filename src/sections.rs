@@ -0,0 +1,90 @@
+//! The parts of the kernel image, as `linker.ld` lays them out.
+//!
+//! The kernel is linked with its own linker script (passed by `build.rs`) rather than the
+//! linker's default layout, so that it knows where its parts are and they don't share pages:
+//!
+//! - `.text`, the code; `.rodata`, with the unwind tables; `.data` and `.bss`;
+//! - `.symbols`, the function name table `tools/symbols.py` fills in (see `symbols`);
+//! - `.percpu`, the statics every CPU should have a copy of, put there with
+//!   `#[link_section = ".percpu"]`;
+//! - `.init.text` and `.init.data`, code and data only the boot needs (`#[link_section =
+//!   ".init.text"]`, `".init.rodata"` or `".init.data"`), which can be freed afterwards.
+//!
+//! Each starts and ends on a page boundary, and the image's segments follow the same lines, with
+//! code executable, read-only data read-only and the rest writable. The functions here give the
+//! bounds from symbols the script defines.
+
+use core::ops::Range;
+use core::ptr;
+use x86_64::VirtAddr;
+
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __symbols_start: u8;
+    static __symbols_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    static __init_start: u8;
+    static __init_end: u8;
+}
+
+/// The addresses from `start` up to `end`, two of the script's symbols
+fn range(start: *const u8, end: *const u8) -> Range<VirtAddr> {
+    VirtAddr::from_ptr(start)..VirtAddr::from_ptr(end)
+}
+
+/// The whole image
+pub fn kernel() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__kernel_start), ptr::addr_of!(__kernel_end))
+}
+
+pub fn text() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__text_start), ptr::addr_of!(__text_end))
+}
+
+pub fn rodata() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__rodata_start), ptr::addr_of!(__rodata_end))
+}
+
+pub fn symbols() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__symbols_start), ptr::addr_of!(__symbols_end))
+}
+
+pub fn data() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__data_start), ptr::addr_of!(__data_end))
+}
+
+pub fn percpu() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__percpu_start), ptr::addr_of!(__percpu_end))
+}
+
+pub fn bss() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__bss_start), ptr::addr_of!(__bss_end))
+}
+
+/// Init code and data, both
+pub fn init() -> Range<VirtAddr> {
+    range(ptr::addr_of!(__init_start), ptr::addr_of!(__init_end))
+}
+
+/// Every section by name, in the order they're in
+pub fn all() -> [(&'static str, Range<VirtAddr>); 7] {
+    [
+        (".text", text()),
+        (".rodata", rodata()),
+        (".symbols", symbols()),
+        (".data", data()),
+        (".percpu", percpu()),
+        (".bss", bss()),
+        (".init", init()),
+    ]
+}
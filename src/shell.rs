@@ -0,0 +1,329 @@
+//! The kernel's command interpreter.
+//!
+//! Commands are plain functions in a static table, each getting the words after its name and
+//! printing its own output. Nothing reads lines from a keyboard yet; `execute` runs one command
+//! line from wherever it came from.
+
+#[cfg(feature = "bench")]
+use crate::bench;
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub;
+#[cfg(feature = "gfx")]
+use crate::gfx;
+#[cfg(feature = "net")]
+use crate::net;
+use crate::{
+    allocator, audit, block, bootinfo, cputime, crash, drivers, fs, interrupts, kdb, klog, memory, numa,
+    nvram, paravirt, pci, power, println, process, profile, smp, syscall, sysctl, sysinfo, time,
+    trace, tty, watchdog,
+};
+use alloc::vec::Vec;
+
+/// One entry of the command table
+pub struct Command {
+    pub name: &'static str,
+    /// One line, shown by `help`
+    pub help: &'static str,
+    pub run: fn(&[&str]) -> Result<(), &'static str>,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "audit",
+        help: "show the audit log: programs started, system-wide changes, refused calls and loads",
+        run: audit::command,
+    },
+    Command {
+        name: "balloon",
+        help: "how much memory the host took back through the virtio balloon",
+        run: drivers::virtio::balloon::command,
+    },
+    #[cfg(feature = "bench")]
+    Command {
+        name: "bench",
+        help: "time kernel primitives in TSC cycles: `bench [<filter>]`",
+        run: bench::command,
+    },
+    Command {
+        name: "battery",
+        help: "power source and battery charge; set the low-battery levels with `battery warn|shutdown <percent>`",
+        run: power::battery::command,
+    },
+    Command {
+        name: "beep",
+        help: "sound the PC speaker: `beep [<hz> [<ms>]]`, 880 Hz for 200 ms by default",
+        run: drivers::pcspeaker::command,
+    },
+    Command {
+        name: "bootinfo",
+        help: "what the bootloader passed: its protocol, the framebuffer, ACPI, initrd and command line",
+        run: bootinfo::command,
+    },
+    Command {
+        name: "buttons",
+        help: "what the power and sleep buttons and the lid do: `buttons [<power|sleep|lid> <ignore|suspend|off> | gpe <power|sleep|lid> <number>]`",
+        run: power::events::command,
+    },
+    Command {
+        name: "cgroup",
+        help: "process groups with their CPU shares, memory and kernel heap: `cgroup [create|remove <path> | shares <path> <n> | limit|heap <path> <KiB>|none | move <pid> <path>]`",
+        run: process::group::command,
+    },
+    Command {
+        name: "checkpoint",
+        help: "save a single-threaded process to a file, or start it again from one: `checkpoint save <pid> <path> | restore <path>`",
+        run: process::checkpoint::command,
+    },
+    Command {
+        name: "cpu",
+        help: "list the CPUs, or take one offline or back online: `cpu [online|offline <index>]`",
+        run: smp::cpu_command,
+    },
+    Command {
+        name: "crashdump",
+        help: "show the crash dump a panic wrote to the `crashdump=` device, or clear it: `crashdump [clear]`",
+        run: crash::command,
+    },
+    Command {
+        name: "crypt",
+        help: "unlock an encrypted block device as `<device>-crypt`, e.g. to mount it: `crypt unlock <device> <passphrase>`",
+        run: block::crypt::command,
+    },
+    Command {
+        name: "date",
+        help: "show the date and time, or set the time zone: `date [tz <offset>]`, e.g. `date tz -05:00`",
+        run: time::command,
+    },
+    Command {
+        name: "dmesg",
+        help: "show the kernel log, everything printed since boot as far as it still holds; `dmesg -T` stamps it with the time of day",
+        run: klog::command,
+    },
+    Command {
+        name: "drivers",
+        help: "list the built-in drivers in the order they're set up, with what they found",
+        run: drivers::command,
+    },
+    Command {
+        name: "dump",
+        help: "show memory in hex and as text; `-p` for a physical address: `dump [-p] <address> [len]`",
+        run: memory::inspect::dump_command,
+    },
+    Command {
+        name: "fwcfg",
+        help: "list what QEMU passed through fw_cfg, or show one file: `fwcfg [<file>]`",
+        run: drivers::fw_cfg::command,
+    },
+    #[cfg(feature = "gdbstub")]
+    Command {
+        name: "gdb",
+        help: "stop the kernel and wait for gdb on COM2",
+        run: gdbstub::command,
+    },
+    #[cfg(feature = "gfx")]
+    Command {
+        name: "gfx",
+        help: "switch the display to graphics and draw a test card: `gfx [<width> <height>]`",
+        run: gfx::command,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "ifconfig",
+        help: "list the network interfaces with their addresses, links and frame counts",
+        run: net::nic::command,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "ip",
+        help: "show or set the IPv4 addresses of the interfaces, or list the ARP cache: `ip [addr <interface> <address>/<prefix> [<gateway>] | del <interface> | arp]`",
+        run: net::stack::command,
+    },
+    Command {
+        name: "irqs",
+        help: "how often each interrupt vector was taken, per CPU",
+        run: interrupts::stats::command,
+    },
+    Command {
+        name: "kdb",
+        help: "whether a panic enters the crash debugger (see the `panic.action` sysctl): `kdb [on|off]`",
+        run: kdb::command,
+    },
+    Command {
+        name: "keymap",
+        help: "list the keyboard layouts or switch to one: `keymap [us|uk|de|dvorak]`",
+        run: drivers::ps2::keymap::command,
+    },
+    Command {
+        name: "kill",
+        help: "send a signal to a process, SIGKILL unless another is named: `kill <pid> [<signal>]`",
+        run: process::signal::kill_command,
+    },
+    Command {
+        name: "latency",
+        help: "timer interrupt and wake-up latency histograms; `latency reset` clears them",
+        run: interrupts::latency::command,
+    },
+    Command {
+        name: "lspci",
+        help: "list the PCI devices; `-v` or an address for their BARs, interrupts and capabilities: `lspci [-v] [<bus:device.function>]`",
+        run: pci::command,
+    },
+    Command {
+        name: "mem",
+        help: "heap usage and allocations by size, and the heap's debug mode: `mem [debug on|off]`",
+        run: allocator::command,
+    },
+    Command {
+        name: "mount",
+        help: "list the mount points, or mount the filesystem on a block device: `mount [<device> <path>]`",
+        run: fs::mount_command,
+    },
+    Command {
+        name: "mtrr",
+        help: "list the MTRRs: the memory type of each physical range",
+        run: memory::caching::command,
+    },
+    Command {
+        name: "numa",
+        help: "list the NUMA nodes with their memory, CPUs and distances",
+        run: numa::command,
+    },
+    Command {
+        name: "nvram",
+        help: "show or change the settings kept for the next boot",
+        run: nvram::command,
+    },
+    Command {
+        name: "paravirt",
+        help: "the hypervisor and its shortcuts the kernel takes: `paravirt [<feature> on|off]`",
+        run: paravirt::command,
+    },
+    Command {
+        name: "peek",
+        help: "read 1, 2, 4 or 8 bytes of memory: `peek [-p] <address> [1|2|4|8]`",
+        run: memory::inspect::peek_command,
+    },
+    Command {
+        name: "poke",
+        help: "write 1, 2, 4 or 8 bytes of memory: `poke [-p] <address> <value> [1|2|4|8]`",
+        run: memory::inspect::poke_command,
+    },
+    Command {
+        name: "ps",
+        help: "list the running processes",
+        run: process::ps_command,
+    },
+    Command {
+        name: "profile",
+        help: "sample where the CPUs spend their time: `profile [on [<ticks>] | off | dump | stream on|off]`",
+        run: profile::command,
+    },
+    Command {
+        name: "reboot",
+        help: "write back the filesystems and restart the machine",
+        run: power::reboot_command,
+    },
+    Command {
+        name: "rx",
+        help: "receive a file over the serial console with XMODEM: `rx <path>`",
+        run: drivers::serial::xmodem::command,
+    },
+    Command {
+        name: "shutdown",
+        help: "write back the filesystems and turn the machine off",
+        run: power::shutdown_command,
+    },
+    Command {
+        name: "strace",
+        help: "log a process's system calls to the kernel log, see `dmesg`: `strace [<pid> on|off]`",
+        run: syscall::strace::command,
+    },
+    Command {
+        name: "suspend",
+        help: "suspend to RAM (ACPI S3) until the machine is woken up",
+        run: power::command,
+    },
+    Command {
+        name: "sysctl",
+        help: "list the kernel parameters, or show or set one: `sysctl [<name> [<value>]]`",
+        run: sysctl::command,
+    },
+    Command {
+        name: "sysinfo",
+        help: "the CPU, memory and kernel features as the boot banner shows them; `sysinfo memory` adds the memory map",
+        run: sysinfo::command,
+    },
+    Command {
+        name: "top",
+        help: "the CPU time since boot of each CPU, kernel task and process",
+        run: cputime::top_command,
+    },
+    Command {
+        name: "trace",
+        help: "event tracing: `trace [on|off [irq|sched|alloc|stack|sample]... | dump [count] | stream on|off | clear]`",
+        run: trace::command,
+    },
+    Command {
+        name: "tty",
+        help: "list the terminals, switch to one or change the active one's settings: `tty [<n> | raw | cooked | echo | noecho]`",
+        run: tty::command,
+    },
+    Command {
+        name: "umount",
+        help: "write back and detach the filesystem mounted at a path: `umount <path>`",
+        run: fs::umount_command,
+    },
+    Command {
+        name: "watch",
+        help: "show a memory range again whenever it changes: `watch [[-p] <address> [len] [interval ms] | stop <id>|all]`",
+        run: memory::inspect::watch_command,
+    },
+    Command {
+        name: "watchdog",
+        help: "stuck locks, stalled CPUs and heap corruption: `watchdog [on|off|check]`",
+        run: watchdog::command,
+    },
+    Command {
+        name: "zram",
+        help: "compressed RAM disks and their memory use: `zram [create <KiB>]`",
+        run: block::zram::command,
+    },
+    Command {
+        name: "exec",
+        help: "start a process from an ELF executable: `exec <path> [args...]`",
+        run: process::exec_command,
+    },
+];
+
+/// The command called `name`, if there is one
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Splits `line` into words and runs the command the first one names, printing any error
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((name, args)) = words.split_first() else {
+        return;
+    };
+    match find(name) {
+        Some(command) => {
+            if let Err(err) = (command.run)(args) {
+                println!("{}: {}", name, err);
+            }
+        }
+        None => println!("{}: command not found", name),
+    }
+}
+
+fn help(_args: &[&str]) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        println!("{:<10} {}", command.name, command.help);
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Signature checks for what the kernel loads after boot: modules and the initramfs.
+//!
+//! A signed artifact carries its signature in a trailer after the payload:
+//!
+//! ```text
+//! payload | Ed25519 signature (64 bytes) | payload length (u64 LE) | "~rust_os signed~"
+//! ```
+//!
+//! The signature covers a context string naming the kind of artifact followed by the payload, so
+//! a signed module can't be passed off as an initramfs or the other way round.
+//!
+//! The public key is embedded at build time from the `RUST_OS_SIGNING_KEY` environment variable
+//! (64 hex digits). Unsigned artifacts, and signed ones on a kernel built without a key, are
+//! refused unless `allow_unsigned` was switched on, which the command line flag of that name
+//! does; a signature that doesn't verify is always refused.
+
+use crate::audit::{self, Event};
+use crate::crypto::ed25519::{self, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use crate::warnln;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MAGIC: &[u8; 16] = b"~rust_os signed~";
+const TRAILER_SIZE: usize = SIGNATURE_SIZE + 8 + MAGIC.len();
+
+const SIGNING_KEY: Option<[u8; PUBLIC_KEY_SIZE]> = match option_env!("RUST_OS_SIGNING_KEY") {
+    Some(hex) => Some(decode_key(hex)),
+    None => None,
+};
+
+/// Parses the key at compile time, so a malformed one fails the build
+const fn decode_key(hex: &str) -> [u8; PUBLIC_KEY_SIZE] {
+    const fn digit(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("RUST_OS_SIGNING_KEY contains a non-hex character"),
+        }
+    }
+    let hex = hex.as_bytes();
+    assert!(
+        hex.len() == 2 * PUBLIC_KEY_SIZE,
+        "RUST_OS_SIGNING_KEY must be 64 hex digits"
+    );
+    let mut key = [0; PUBLIC_KEY_SIZE];
+    let mut i = 0;
+    while i < PUBLIC_KEY_SIZE {
+        key[i] = digit(hex[2 * i]) << 4 | digit(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+static ALLOW_UNSIGNED: AtomicBool = AtomicBool::new(false);
+
+/// What a signature is checked for; each kind is signed under its own context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Module,
+    Initramfs,
+}
+
+impl ArtifactKind {
+    fn context(self) -> &'static [u8] {
+        match self {
+            ArtifactKind::Module => b"rust_os module\0",
+            ArtifactKind::Initramfs => b"rust_os initramfs\0",
+        }
+    }
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ArtifactKind::Module => "module",
+            ArtifactKind::Initramfs => "initramfs",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    /// There is no signature trailer
+    Unsigned,
+    /// The artifact is signed, but the kernel was built without a key to check it against
+    NoKey,
+    /// The trailer is present but inconsistent
+    Malformed,
+    /// The signature doesn't match the payload and the embedded key
+    BadSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SignatureError::Unsigned => "not signed",
+            SignatureError::NoKey => "kernel has no signing key",
+            SignatureError::Malformed => "malformed signature trailer",
+            SignatureError::BadSignature => "signature verification failed",
+        })
+    }
+}
+
+/// Whether this build has a key to check signatures against
+pub const HAS_KEY: bool = SIGNING_KEY.is_some();
+
+/// The key signatures are checked against, if one was built in
+pub fn signing_key() -> Option<&'static [u8; PUBLIC_KEY_SIZE]> {
+    SIGNING_KEY.as_ref()
+}
+
+/// Whether artifacts that can't be verified are let through (with a warning); off by default
+pub fn allow_unsigned(allow: bool) {
+    ALLOW_UNSIGNED.store(allow, Ordering::Relaxed);
+}
+
+/// An artifact taken apart at its trailer
+struct Signed<'a> {
+    payload: &'a [u8],
+    signature: &'a [u8; SIGNATURE_SIZE],
+}
+
+/// Splits off the trailer, `Ok(None)` if there is none
+fn split(data: &[u8]) -> Result<Option<Signed<'_>>, SignatureError> {
+    if data.len() < TRAILER_SIZE || !data.ends_with(MAGIC) {
+        return Ok(None);
+    }
+    let payload_len = data.len() - TRAILER_SIZE;
+    let (payload, trailer) = data.split_at(payload_len);
+    let recorded_len = u64::from_le_bytes(
+        trailer[SIGNATURE_SIZE..SIGNATURE_SIZE + 8]
+            .try_into()
+            .unwrap(),
+    );
+    if recorded_len != payload_len as u64 {
+        return Err(SignatureError::Malformed);
+    }
+    Ok(Some(Signed {
+        payload,
+        signature: trailer[..SIGNATURE_SIZE].try_into().unwrap(),
+    }))
+}
+
+/// Checks a loaded artifact and returns its payload, with the trailer stripped; the outcome goes
+/// to the audit log
+pub fn verify(kind: ArtifactKind, data: &[u8]) -> Result<&[u8], SignatureError> {
+    let result = check(kind, data);
+    audit::record(Event::Load {
+        kind,
+        result: result.map(|_| ()),
+    });
+    result
+}
+
+fn check(kind: ArtifactKind, data: &[u8]) -> Result<&[u8], SignatureError> {
+    let (payload, err) = match (split(data)?, signing_key()) {
+        (Some(signed), Some(key)) => {
+            let message = [kind.context(), signed.payload];
+            return if ed25519::verify_parts(key, &message, signed.signature) {
+                Ok(signed.payload)
+            } else {
+                Err(SignatureError::BadSignature)
+            };
+        }
+        (Some(signed), None) => (signed.payload, SignatureError::NoKey),
+        (None, _) => (data, SignatureError::Unsigned),
+    };
+    if !ALLOW_UNSIGNED.load(Ordering::Relaxed) {
+        return Err(err);
+    }
+    warnln!("signing: loading unverified {} ({})", kind, err);
+    Ok(payload)
+}
@@ -0,0 +1,413 @@
+//! Application processor (AP) startup.
+//!
+//! APs wake up in 16-bit real mode at the page named in the startup IPI. The trampoline below is
+//! copied to `TRAMPOLINE_BASE`, switches straight from real mode to long mode using the boot
+//! CPU's page tables, and calls `ap_entry` on a freshly allocated stack. APs are started one at a
+//! time because they all share the trampoline's parameter block. The boot CPU reuses the
+//! trampoline to get back into long mode after sleeping (see `waking_vector`).
+//!
+//! Once up, an AP polls the tasks spawned with `task::shared::spawn`, which any CPU may run, and
+//! sleeps while there are none. The executor's own tasks and the process threads stay on the boot
+//! CPU.
+//!
+//! APs can be taken offline and brought back at runtime with `offline` and `online`, e.g. to
+//! find out whether a bug needs a certain number of CPUs without rebooting. Shared tasks aren't
+//! tied to a CPU, so the interrupt lines routed to an AP are all there is to move off it; it then
+//! stops its timer and halts with interrupts disabled. Bringing it
+//! back goes through INIT and STARTUP again, with the index, stack and tables it had before.
+//! An idle AP can also be handed a function to call with `run_on`, for work that needs another
+//! CPU to be measured at all, like contention on a lock.
+
+use crate::apic::{self, LocalApic};
+use crate::percpu::{self, MAX_CPUS};
+use crate::{
+    acpi, fpu, gdt, interrupts, memory, pit, println, rcu, syscall, sysinfo, task, usermode, warnln,
+};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Physical (and identity-mapped virtual) address the trampoline runs at. It must be page
+/// aligned and below 1 MiB; the frame allocator never hands out that range.
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+const AP_STACK_SIZE: u64 = 4096 * 8;
+
+global_asm!(
+    r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .code16
+    .global ap_trampoline_start
+    ap_trampoline_start:
+        cli
+        cld
+        xorw %ax, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+
+        lgdtl (0x8000 + ap_trampoline_gdt_ptr - ap_trampoline_start)
+
+        movl %cr4, %eax
+        orl $0x20, %eax                 # CR4.PAE
+        movl %eax, %cr4
+
+        movl (0x8000 + ap_trampoline_cr3 - ap_trampoline_start), %eax
+        movl %eax, %cr3
+
+        movl $0xC0000080, %ecx          # IA32_EFER
+        rdmsr
+        orl $0x900, %eax                # LME | NXE, the kernel's page tables use the NX bit
+        wrmsr
+
+        movl %cr0, %eax
+        orl $0x80010001, %eax           # PG | WP | PE
+        movl %eax, %cr0
+
+        ljmpl $0x08, $(0x8000 + ap_trampoline_long_mode - ap_trampoline_start)
+
+    .code64
+    ap_trampoline_long_mode:
+        movw $0x10, %ax
+        movw %ax, %ds
+        movw %ax, %es
+        movw %ax, %ss
+        movq (0x8000 + ap_trampoline_stack - ap_trampoline_start), %rsp
+        movq (0x8000 + ap_trampoline_arg - ap_trampoline_start), %rdi
+        movq (0x8000 + ap_trampoline_entry - ap_trampoline_start), %rax
+        callq *%rax
+        ud2
+
+    .balign 8
+    ap_trampoline_gdt:
+        .quad 0
+        .quad 0x00AF9A000000FFFF        # 64-bit code
+        .quad 0x00CF92000000FFFF        # data
+    ap_trampoline_gdt_ptr:
+        .word ap_trampoline_gdt_ptr - ap_trampoline_gdt - 1
+        .long 0x8000 + ap_trampoline_gdt - ap_trampoline_start
+
+    .balign 8
+    .global ap_trampoline_cr3
+    ap_trampoline_cr3:
+        .quad 0
+    .global ap_trampoline_stack
+    ap_trampoline_stack:
+        .quad 0
+    .global ap_trampoline_entry
+    ap_trampoline_entry:
+        .quad 0
+    .global ap_trampoline_arg
+    ap_trampoline_arg:
+        .quad 0
+    .global ap_trampoline_end
+    ap_trampoline_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+/// Set by an AP once it no longer needs the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// Held while an AP starts, as the trampoline's parameter block is shared
+static STARTING: Mutex<()> = Mutex::new(());
+/// Top of each AP's stack, by CPU index; it gets the same one every time it's started
+static AP_STACKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Set for an AP that's to go offline the next time it wakes up
+static STOP: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// A `fn()` each AP is to run the next time it wakes up, 0 for none; cleared once it returns
+static WORK: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Address of a trampoline symbol once copied to `TRAMPOLINE_BASE`
+fn relocated(symbol: &u8) -> *mut u64 {
+    let offset = symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 };
+    crate::memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE + offset)).as_mut_ptr()
+}
+
+/// Identity-maps the trampoline page so the AP can keep executing once it enables paging
+fn map_trampoline() -> Result<(), &'static str> {
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    let mut mapper = crate::memory::MAPPER.lock();
+    let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err("paging is not initialized"),
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(existing)) if existing == frame => {}
+        Err(_) => return Err("failed to identity-map the AP trampoline"),
+    }
+    Ok(())
+}
+
+/// Copies the trampoline to `TRAMPOLINE_BASE` and points it at the current page tables
+fn install_trampoline() -> Result<(), &'static str> {
+    map_trampoline()?;
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let target = crate::memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_BASE)).as_mut_ptr();
+        core::ptr::copy_nonoverlapping(start, target, len);
+        relocated(&ap_trampoline_cr3).write(Cr3::read().0.start_address().as_u64());
+    }
+    Ok(())
+}
+
+/// Fills in the trampoline's parameter block; the caller holds `STARTING`
+fn set_trampoline(entry: extern "C" fn(u64) -> !, stack_top: u64, arg: u64) {
+    unsafe {
+        relocated(&ap_trampoline_entry).write(entry as *const () as u64);
+        relocated(&ap_trampoline_stack).write(stack_top);
+        relocated(&ap_trampoline_arg).write(arg);
+    }
+}
+
+/// Sets the trampoline up to run `entry(arg)` on the stack ending at `stack_top` and returns its
+/// physical address, for firmware to jump to in real mode like a started AP does. This is how the
+/// boot CPU gets back into long mode when it wakes from sleep (see `power`). No AP may be
+/// starting meanwhile.
+pub fn waking_vector(
+    entry: extern "C" fn(u64) -> !,
+    stack_top: u64,
+    arg: u64,
+) -> Result<PhysAddr, &'static str> {
+    let _starting = STARTING.lock();
+    install_trampoline()?;
+    set_trampoline(entry, stack_top, arg);
+    Ok(PhysAddr::new(TRAMPOLINE_BASE))
+}
+
+/// Starts every enabled processor listed in the MADT and returns how many CPUs are online
+pub fn init() -> usize {
+    let local_apic = match apic::local_apic() {
+        Some(local_apic) => local_apic,
+        None => return percpu::online_count(), // IPIs need the Local APIC
+    };
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return percpu::online_count(),
+    };
+    if let Err(err) = install_trampoline() {
+        warnln!("smp: {}", err);
+        return percpu::online_count();
+    }
+
+    let bsp_apic_id = local_apic.id();
+    let mut next_index = percpu::count();
+    for processor in madt.processors.iter() {
+        if !processor.enabled || processor.apic_id == bsp_apic_id {
+            continue;
+        }
+        if next_index >= MAX_CPUS {
+            warnln!("smp: ignoring CPUs past the first {}", MAX_CPUS);
+            break;
+        }
+
+        let Ok(stack_top) = memory::allocate_kernel_stack(AP_STACK_SIZE) else {
+            println!("smp: no memory for more CPUs' stacks");
+            break;
+        };
+        AP_STACKS[next_index].store(stack_top.as_u64(), Ordering::SeqCst);
+        if start(local_apic, processor.apic_id, next_index) {
+            next_index += 1; // the AP registered itself in percpu before signalling
+        } else {
+            println!("smp: CPU with APIC id {} did not start", processor.apic_id);
+        }
+    }
+
+    percpu::online_count()
+}
+
+/// Runs the AP with `apic_id` through the trampoline into `ap_entry` as CPU `index`; returns
+/// whether it got there
+fn start(local_apic: LocalApic, apic_id: u32, index: usize) -> bool {
+    let _starting = STARTING.lock();
+    let stack_top = AP_STACKS[index].load(Ordering::SeqCst);
+    set_trampoline(ap_entry, stack_top, index as u64);
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    // INIT, then up to two STARTUP IPIs as the MP specification recommends
+    local_apic.send_init(apic_id);
+    pit::wait_ms(10);
+    let vector = (TRAMPOLINE_BASE >> 12) as u8;
+    for _ in 0..2 {
+        local_apic.send_startup(apic_id, vector);
+        if wait_for_ap(100) {
+            break;
+        }
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+fn wait_for_ap(ms: u32) -> bool {
+    for _ in 0..ms {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        pit::wait_ms(1);
+    }
+    AP_STARTED.load(Ordering::SeqCst)
+}
+
+/// First Rust code an AP runs, called by the trampoline with its CPU index
+extern "C" fn ap_entry(index: u64) -> ! {
+    let index = index as usize;
+    gdt::init_ap(index);
+    interrupts::init_idt();
+    fpu::init();
+    usermode::emulate::init();
+    memory::caching::init();
+    let local_apic = apic::local_apic().expect("APs are only started in APIC mode");
+    percpu::init(index, local_apic.id());
+    syscall::init();
+    AP_STARTED.store(true, Ordering::SeqCst); // the trampoline and its stack slot are free again
+
+    apic::init_ap();
+    loop {
+        x86_64::instructions::interrupts::disable();
+        if STOP[index].load(Ordering::SeqCst) {
+            stop(index);
+        }
+        let work = WORK[index].load(Ordering::SeqCst);
+        if work != 0 {
+            let work: fn() = unsafe { core::mem::transmute(work) };
+            work();
+            WORK[index].store(0, Ordering::SeqCst);
+            continue;
+        }
+        x86_64::instructions::interrupts::enable();
+        // no task is running, so this CPU holds no RCU references
+        rcu::quiescent_state();
+        task::shared::run_ready();
+        x86_64::instructions::interrupts::disable();
+        task::shared::idle_unless(|| false);
+    }
+}
+
+/// Takes this AP offline; called with interrupts disabled. Only an INIT IPI gets it going again.
+fn stop(index: usize) -> ! {
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.stop_timer();
+    }
+    STOP[index].store(false, Ordering::SeqCst);
+    percpu::set_online(index, false);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Takes AP `index` offline, after routing its interrupt lines to the boot CPU
+pub fn offline(index: usize) -> Result<(), &'static str> {
+    if index == 0 {
+        return Err("the boot CPU can't go offline");
+    }
+    let cpu = percpu::get(index).ok_or("no such CPU")?;
+    if !percpu::is_online(index) {
+        return Err("CPU is already offline");
+    }
+    let local_apic = apic::local_apic().ok_or("no Local APIC")?;
+    let boot_cpu = percpu::get(0).ok_or("no boot CPU")?;
+
+    if let Some(io_apic) = apic::IO_APIC.lock().as_mut() {
+        io_apic.retarget(cpu.apic_id, boot_cpu.apic_id);
+    }
+    STOP[index].store(true, Ordering::SeqCst);
+    local_apic.send_fixed(cpu.apic_id, apic::WAKEUP_VECTOR);
+    for _ in 0..100 {
+        if !percpu::is_online(index) {
+            return Ok(());
+        }
+        pit::wait_ms(1);
+    }
+    Err("CPU did not stop")
+}
+
+/// Has AP `index` call `work`, with interrupts disabled, instead of idling; returns once it has
+/// started. `is_working` tells when it's done.
+pub fn run_on(index: usize, work: fn()) -> Result<(), &'static str> {
+    if index == 0 {
+        return Err("the boot CPU only runs tasks");
+    }
+    let cpu = percpu::get(index).ok_or("no such CPU")?;
+    if !percpu::is_online(index) {
+        return Err("CPU is offline");
+    }
+    let local_apic = apic::local_apic().ok_or("no Local APIC")?;
+    WORK[index]
+        .compare_exchange(0, work as usize, Ordering::SeqCst, Ordering::SeqCst)
+        .map_err(|_| "CPU is busy")?;
+    local_apic.send_fixed(cpu.apic_id, apic::WAKEUP_VECTOR);
+    Ok(())
+}
+
+/// Whether AP `index` is still running what `run_on` gave it
+pub fn is_working(index: usize) -> bool {
+    WORK.get(index)
+        .is_some_and(|work| work.load(Ordering::SeqCst) != 0)
+}
+
+/// Brings AP `index` back online after `offline`
+pub fn online(index: usize) -> Result<(), &'static str> {
+    let cpu = percpu::get(index).ok_or("no such CPU")?;
+    if percpu::is_online(index) {
+        return Err("CPU is already online");
+    }
+    let local_apic = apic::local_apic().ok_or("no Local APIC")?;
+    match start(local_apic, cpu.apic_id, index) {
+        true => Ok(()),
+        false => Err("CPU did not start"),
+    }
+}
+
+/// `cpu [online|offline <index>]`
+pub fn cpu_command(args: &[&str]) -> Result<(), &'static str> {
+    let parse = |index: &str| index.parse::<usize>().map_err(|_| "not a CPU index");
+    match args {
+        [] => {
+            let cpu = sysinfo::cpu();
+            println!("{}", cpu);
+            println!("features: {}", cpu.features.join(" "));
+            println!("{:>3} {:>7}  STATE", "CPU", "APIC ID");
+            for index in 0..percpu::count() {
+                let Some(cpu) = percpu::get(index) else {
+                    continue;
+                };
+                let state = match percpu::is_online(index) {
+                    true => "online",
+                    false => "offline",
+                };
+                println!("{:>3} {:>7}  {}", index, cpu.apic_id, state);
+            }
+            Ok(())
+        }
+        ["online", index] => {
+            let index = parse(index)?;
+            online(index)?;
+            println!("CPU {} is online", index);
+            Ok(())
+        }
+        ["offline", index] => {
+            let index = parse(index)?;
+            offline(index)?;
+            println!("CPU {} is offline", index);
+            Ok(())
+        }
+        _ => Err("usage: cpu [online|offline <index>]"),
+    }
+}
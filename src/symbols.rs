@@ -0,0 +1,131 @@
+//! The kernel's own function names, to turn code addresses into `name+offset`.
+//!
+//! The table lives in a `.symbols` section reserved at its full size in the image, on pages of its
+//! own (see `sections`). The compiler can't know the kernel's addresses before it has linked it,
+//! so the table is filled in after the link: `tools/symbols.py` reads the function symbols from
+//! the kernel ELF and writes them into the section in place, e.g.
+//!
+//! ```text
+//! cargo build && python3 tools/symbols.py target/x86_64-rust_os/debug/rust_os && cargo bootimage
+//! ```
+//!
+//! Patching changes no address, so the table describes the very image it's in. An image that
+//! wasn't patched has an empty table and addresses print as they are.
+//!
+//! Layout, all little-endian: a 16-byte header (`MAGIC`, entry count, offset of the names, bytes
+//! used), the entries sorted by start address (start `u64`, size, name offset, name length, each
+//! `u32`), then the names, demangled and without their hashes.
+
+use core::fmt;
+
+/// Bytes reserved for the table; the tool says so when a kernel outgrows it
+pub const CAPACITY: usize = 1 << 20;
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+#[repr(C, align(8))]
+struct Table([u8; CAPACITY]);
+
+/// An empty table: just the magic the tool looks for
+const fn empty() -> Table {
+    let mut table = [0; CAPACITY];
+    let mut index = 0;
+    while index < MAGIC.len() {
+        table[index] = MAGIC[index];
+        index += 1;
+    }
+    Table(table)
+}
+
+#[used]
+#[link_section = ".symbols"]
+static TABLE: Table = empty();
+
+/// One function
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+}
+
+fn table() -> &'static [u8] {
+    // the contents change after compiling, so they must not be folded in as the empty table
+    &core::hint::black_box(&TABLE).0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The entries and the names, if the table was filled in and makes sense
+fn parts() -> Option<(&'static [u8], &'static [u8])> {
+    let table = table();
+    let count = read_u32(table, 4);
+    let names = read_u32(table, 8);
+    let used = read_u32(table, 12);
+    let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+    if count == 0 || entries_end > names || names > used || used > CAPACITY {
+        return None;
+    }
+    Some((&table[HEADER_SIZE..entries_end], &table[names..used]))
+}
+
+fn entry(entries: &[u8], names: &'static [u8], index: usize) -> Option<Symbol> {
+    let entry = &entries[index * ENTRY_SIZE..][..ENTRY_SIZE];
+    let offset = read_u32(entry, 12);
+    let len = read_u32(entry, 16);
+    let name = names.get(offset..offset.checked_add(len)?)?;
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        start: read_u64(entry, 0),
+        size: read_u32(entry, 8) as u64,
+    })
+}
+
+/// Whether the image was patched with a table
+pub fn is_loaded() -> bool {
+    parts().is_some()
+}
+
+/// Functions in the table
+pub fn count() -> usize {
+    parts().map_or(0, |(entries, _)| entries.len() / ENTRY_SIZE)
+}
+
+/// The function `address` is in
+pub fn lookup(address: u64) -> Option<Symbol> {
+    let (entries, names) = parts()?;
+    let count = entries.len() / ENTRY_SIZE;
+    // the entries before the first one that starts past the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        match read_u64(entries, middle * ENTRY_SIZE) <= address {
+            true => low = middle + 1,
+            false => high = middle,
+        }
+    }
+    let symbol = entry(entries, names, low.checked_sub(1)?)?;
+    (address - symbol.start < symbol.size).then_some(symbol)
+}
+
+/// Formats a code address as `0x... (name+0x12)`, or on its own when no function holds it
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some(symbol) = lookup(self.0) {
+            write!(f, " ({}+{:#x})", symbol.name, self.0 - symbol.start)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+//! Synchronization primitives: locks fairer or more watchful than `spin`'s, queues that interrupt
+//! handlers can use without taking a lock, and the count that keeps the scheduler away while any
+//! of the locks is held.
+
+pub mod mpmc;
+pub mod mpsc;
+pub mod preempt;
+pub mod rwlock;
+pub mod spinlock;
+pub mod waker;
+pub mod watched;
@@ -0,0 +1,145 @@
+//! A bounded lock-free multi-producer multi-consumer queue (Dmitry Vyukov's design).
+//!
+//! Every slot carries a sequence number saying whose turn it is: a producer at position `p` may
+//! fill slot `p % N` once its sequence is `p`, a consumer may empty it once it is `p + 1`. Each
+//! side claims positions with a compare-and-swap on its own index, so producers and consumers only
+//! contend among themselves, and nobody ever waits for a lock a preempted CPU holds. That, and
+//! never allocating, makes it safe to push from interrupt handlers.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keeps the producer and consumer indexes on separate cache lines
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+struct Slot<T> {
+    /// The slot's sequence number minus its index, so that every slot starts at 0 and the queue
+    /// can be built in a `const` context
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A queue of at most `N` values; `N` must be a power of two
+pub struct Queue<T, const N: usize> {
+    /// Next position to pop
+    head: CachePadded<AtomicUsize>,
+    /// Next position to push
+    tail: CachePadded<AtomicUsize>,
+    slots: [Slot<T>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Send for Queue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    const CAPACITY_IS_POWER_OF_TWO: () = assert!(N.is_power_of_two());
+
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CAPACITY_IS_POWER_OF_TWO;
+        Queue {
+            head: CachePadded(AtomicUsize::new(0)),
+            tail: CachePadded(AtomicUsize::new(0)),
+            slots: [const {
+                Slot {
+                    stamp: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+        }
+    }
+
+    /// The slot `position` maps to and its current sequence number
+    fn slot(&self, position: usize) -> (&Slot<T>, usize) {
+        let index = position & (N - 1);
+        let slot = &self.slots[index];
+        (slot, slot.stamp.load(Ordering::Acquire).wrapping_add(index))
+    }
+
+    /// Appends `value`, or hands it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.0.load(Ordering::Relaxed);
+        loop {
+            let (slot, sequence) = self.slot(position);
+            match sequence.wrapping_sub(position) as isize {
+                0 => match self.tail.0.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        let index = position & (N - 1);
+                        let stamp = position.wrapping_add(1).wrapping_sub(index);
+                        slot.stamp.store(stamp, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                },
+                // the slot still holds the value from one lap ago
+                difference if difference < 0 => return Err(value),
+                // another producer claimed this position; catch up
+                _ => position = self.tail.0.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Removes the oldest value, if there is one
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.0.load(Ordering::Relaxed);
+        loop {
+            let (slot, sequence) = self.slot(position);
+            match sequence.wrapping_sub(position.wrapping_add(1)) as isize {
+                0 => match self.head.0.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        // free for the producer one lap ahead
+                        let index = position & (N - 1);
+                        let stamp = position.wrapping_add(N).wrapping_sub(index);
+                        slot.stamp.store(stamp, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                },
+                // nothing has been pushed to this position yet
+                difference if difference < 0 => return None,
+                _ => position = self.head.0.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Number of values queued; only a snapshot while other CPUs push and pop
+    pub fn len(&self) -> usize {
+        let tail = self.tail.0.load(Ordering::Acquire);
+        let head = self.head.0.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
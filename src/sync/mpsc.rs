@@ -0,0 +1,81 @@
+//! A bounded channel from any number of senders, interrupt handlers included, to one async task.
+//!
+//! Values go through an `mpmc::Queue` and the receiving task's waker sits in an `AtomicWaker`,
+//! so sending takes no lock at all: a handler that interrupted the receiver, or another sender,
+//! can't be kept waiting. When the receiver falls `N` values behind, `send` hands the value back.
+
+use super::mpmc;
+use super::waker::AtomicWaker;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// A channel for at most `N` values in flight; `N` must be a power of two
+pub struct Channel<T, const N: usize> {
+    queue: mpmc::Queue<T, N>,
+    receiver: AtomicWaker,
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Channel {
+            queue: mpmc::Queue::new(),
+            receiver: AtomicWaker::new(),
+        }
+    }
+
+    /// Queues `value` and wakes the receiver, or hands `value` back if the channel is full
+    pub fn send(&self, value: T) -> Result<(), T> {
+        self.queue.push(value)?;
+        self.receiver.wake();
+        Ok(())
+    }
+
+    /// The oldest value, if one is waiting
+    pub fn try_recv(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    /// Waits for the next value. Meant for one task; with several, each value still goes to just
+    /// one of them, but only the last to wait is woken.
+    pub fn recv(&self) -> Recv<'_, T, N> {
+        Recv { channel: self }
+    }
+
+    /// Values waiting
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Future returned by `Channel::recv`
+#[must_use = "futures do nothing unless polled"]
+pub struct Recv<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Future for Recv<'_, T, N> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        if let Some(value) = self.channel.try_recv() {
+            return Poll::Ready(value);
+        }
+        self.channel.receiver.register(context.waker());
+        // a value sent before the waker was in place would otherwise wait for the next one
+        match self.channel.try_recv() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Keeping the scheduler off a CPU while it's in a critical section.
+//!
+//! Each CPU counts how often preemption was disabled on it and not enabled again yet. While the
+//! count is nonzero a preemption point only records that a reschedule is due; the next point
+//! reached with a count of zero takes it. The points are where the scheduler can take the CPU
+//! from a thread: the timer interrupting ring 3, and the return from a system call, which is
+//! where a time slice that ran out during the call ends (see `usermode::preempt`). Kernel code
+//! can't be preempted between them yet, but once it can, nothing holding a spinlock may be:
+//! a thread waiting on a CPU for a lock whose holder was switched away spins for a whole slice.
+//! So every spinlock in `sync` disables preemption while it's held.
+//!
+//! Before a CPU has its per-CPU data there's no scheduler on it and nothing is counted.
+
+use crate::percpu;
+use core::sync::atomic::Ordering;
+
+/// Preemption stays disabled on this CPU until the guard is dropped
+#[must_use = "preemption is enabled again when the guard is dropped"]
+pub struct Disabled(());
+
+impl Drop for Disabled {
+    fn drop(&mut self) {
+        if let Some(cpu) = percpu::try_current() {
+            let count = cpu.preempt_count.fetch_sub(1, Ordering::Relaxed);
+            debug_assert!(count != 0, "preemption enabled more often than disabled");
+        }
+    }
+}
+
+/// Disables preemption on this CPU; calls nest
+pub fn disable() -> Disabled {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.preempt_count.fetch_add(1, Ordering::Relaxed);
+    }
+    Disabled(())
+}
+
+/// Runs `f` with preemption disabled
+pub fn without_preemption<R>(f: impl FnOnce() -> R) -> R {
+    let _disabled = disable();
+    f()
+}
+
+/// How many times preemption is disabled on this CPU
+pub fn count() -> usize {
+    percpu::try_current().map_or(0, |cpu| cpu.preempt_count.load(Ordering::Relaxed))
+}
+
+pub fn is_preemptible() -> bool {
+    count() == 0
+}
+
+/// Records that the scheduler wants this CPU, for the next preemption point to act on
+pub fn request() {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.preempt_pending.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether a reschedule is due and may happen now; clears the request if so
+pub fn take_request() -> bool {
+    let Some(cpu) = percpu::try_current() else {
+        return false;
+    };
+    cpu.preempt_count.load(Ordering::Relaxed) == 0
+        && cpu.preempt_pending.swap(false, Ordering::Relaxed)
+}
+
+/// Drops a request, once the CPU changed hands anyway
+pub fn clear_request() {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.preempt_pending.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,172 @@
+//! A spinning reader-writer lock for data that's read far more often than changed.
+//!
+//! The state is one word: a reader count, a bit for the writer holding the lock, and a bit for a
+//! writer waiting for it. New readers stay out while a writer waits, so a steady stream of them
+//! can't starve it. Either way of holding it disables preemption. Debug builds panic when a CPU asks for the lock while it holds it for writing.
+
+use super::preempt::{self, Disabled};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(debug_assertions)]
+use super::spinlock::cpu_tag;
+
+const WRITER: usize = 1 << 0;
+const WAITING: usize = 1 << 1;
+/// One reader; readers are counted above the writer bits
+const READER: usize = 1 << 2;
+
+pub struct RwSpinLock<T: ?Sized> {
+    state: AtomicUsize,
+    /// `cpu_tag` of the writer, 0 if none or unknown
+    #[cfg(debug_assertions)]
+    writer: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            state: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            writer: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    /// Waits until no writer holds or wants the lock, then shares it with the other readers
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        self.check_not_writing();
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let preempt = preempt::disable();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(ReadGuard {
+            lock: self,
+            _preempt: preempt,
+        })
+    }
+
+    /// Waits until the lock is free, keeping new readers out meanwhile, and takes it alone
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        self.check_not_writing();
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WAITING == 0 {
+                self.state.fetch_or(WAITING, Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Takes the lock alone if nobody holds it; clears the waiting bit, which another waiting
+    /// writer sets again
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let preempt = preempt::disable();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WAITING != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        #[cfg(debug_assertions)]
+        self.writer.store(cpu_tag(), Ordering::Relaxed);
+        Some(WriteGuard {
+            lock: self,
+            _preempt: preempt,
+        })
+    }
+
+    /// Readers holding the lock now
+    pub fn readers(&self) -> usize {
+        self.state.load(Ordering::Relaxed) / READER
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_not_writing(&self) {
+        let cpu = cpu_tag();
+        if cpu != 0 && self.writer.load(Ordering::Relaxed) == cpu {
+            panic!("cpu {} takes a lock it holds for writing", cpu - 1);
+        }
+    }
+}
+
+pub struct ReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _preempt: Disabled,
+}
+
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+pub struct WriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _preempt: Disabled,
+}
+
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.writer.store(0, Ordering::Relaxed);
+        // waiting writers keep their bit
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
@@ -0,0 +1,240 @@
+//! Spinlocks that hand the lock out in the order it was asked for.
+//!
+//! `SpinLock` is a ticket lock: every CPU that wants it draws the next ticket and waits until
+//! that ticket is served, so a CPU can't be starved by others that keep winning the race for the
+//! cache line, as with `spin::Mutex`. `IrqSpinLock` also disables interrupts on the CPU while it
+//! holds the lock, for data interrupt handlers touch too: a handler that spins on a lock the code
+//! it interrupted holds never gets it.
+//!
+//! Holding either disables preemption (see `preempt`).
+//!
+//! Debug builds remember which CPU holds a lock and panic when the same CPU asks for it again,
+//! which would otherwise spin forever without a word.
+
+use super::preempt::{self, Disabled};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicUsize;
+
+/// The calling CPU's index plus one, 0 before it has per-CPU data
+#[cfg(debug_assertions)]
+pub(super) fn cpu_tag() -> usize {
+    crate::percpu::try_current().map_or(0, |cpu| cpu.index + 1)
+}
+
+pub struct SpinLock<T: ?Sized> {
+    /// The ticket the next CPU to ask gets
+    next: AtomicU32,
+    /// The ticket whose holder has the lock
+    serving: AtomicU32,
+    /// `cpu_tag` of the holder, 0 if free or unknown
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Waits for the lock, in turn with the other CPUs waiting for it
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        self.check_not_held();
+        let preempt = preempt::disable();
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        self.acquired(preempt)
+    }
+
+    /// Takes the lock if nobody holds or waits for it
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let preempt = preempt::disable();
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        Some(self.acquired(preempt))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Frees the lock, whoever holds it or waits for it; harmless if it's free.
+    ///
+    /// # Safety
+    ///
+    /// The holder and the waiters must never run again, e.g. because the CPUs are stopped.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        self.serving
+            .store(self.next.load(Ordering::Relaxed), Ordering::Release);
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn acquired(&self, preempt: Disabled) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        self.owner.store(cpu_tag(), Ordering::Relaxed);
+        SpinLockGuard {
+            lock: self,
+            _preempt: preempt,
+        }
+    }
+
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_not_held(&self) {
+        let cpu = cpu_tag();
+        if cpu != 0 && self.owner.load(Ordering::Relaxed) == cpu {
+            panic!("cpu {} takes a spinlock it already holds", cpu - 1);
+        }
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    /// Dropped after `drop` released the lock
+    _preempt: Disabled,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+/// A `SpinLock` that keeps interrupts disabled on the holding CPU
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            inner: SpinLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Disables interrupts, then waits for the lock; the guard enables them again if they were
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard {
+            guard: Some(self.inner.lock()),
+            enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                guard: Some(guard),
+                enabled,
+            }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// As `SpinLock::force_unlock`; interrupts are left as they are.
+    ///
+    /// # Safety
+    ///
+    /// The holder and the waiters must never run again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    /// Always `Some` until dropped, to release the lock before interrupts come back
+    guard: Option<SpinLockGuard<'a, T>>,
+    /// Whether interrupts were enabled before locking
+    enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
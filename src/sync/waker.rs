@@ -0,0 +1,79 @@
+//! A slot for one task's waker that can be woken from an interrupt handler without a lock.
+//!
+//! Like `futures`' `AtomicWaker`, except that the waker is woken by reference and stays in the
+//! slot: taking it out would drop it in the interrupt handler, and dropping a waker may free
+//! memory, which an interrupt handler mustn't, as the heap's lock may be held by the code it
+//! interrupted. Wakers are replaced and dropped by `register`, in task context only.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Waker;
+
+/// Nobody is touching the slot
+const IDLE: u8 = 0;
+/// `register` is replacing the waker
+const REGISTERING: u8 = 1 << 0;
+/// `wake` is waking the waker, or wanted to while `register` was busy
+const WAKING: u8 = 1 << 1;
+
+pub struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AtomicWaker {
+    pub const fn new() -> AtomicWaker {
+        AtomicWaker {
+            state: AtomicU8::new(IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Makes `waker` the one `wake` wakes; for the one task waiting. If a wake comes in
+    /// meanwhile, `waker` is woken at once, so it isn't lost.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(IDLE, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                let slot = unsafe { &mut *self.waker.get() };
+                let previous = match slot {
+                    Some(current) if current.will_wake(waker) => None,
+                    _ => slot.replace(waker.clone()),
+                };
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` came while the slot was being written and left the waking to us
+                    self.state.store(IDLE, Ordering::Release);
+                    waker.wake_by_ref();
+                }
+                drop(previous);
+            }
+            // a wake is under way and reads the old waker; the task is to look again anyway
+            Err(_) => waker.wake_by_ref(),
+        }
+    }
+
+    /// Wakes the registered waker, if any; safe in interrupt handlers
+    pub fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == IDLE {
+            if let Some(waker) = unsafe { &*self.waker.get() } {
+                waker.wake_by_ref();
+            }
+            self.state.fetch_and(!WAKING, Ordering::Release);
+        }
+    }
+}
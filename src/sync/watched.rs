@@ -0,0 +1,150 @@
+//! A spinlock that keeps track of who holds it and since when, so `watchdog` can name a lock
+//! that's been held far too long, and the CPU holding it, instead of the machine just hanging.
+//! Like the other spinlocks here it disables preemption while it's held.
+
+use super::preempt::{self, Disabled};
+use crate::clock::tsc;
+use crate::{paravirt, percpu};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+/// Failed attempts between offers to yield to the holder's vCPU (see `paravirt::yield_to`)
+const SPINS_BEFORE_YIELD: u32 = 1 << 12;
+
+/// Who holds a lock; shared by every `WatchedMutex` whatever it protects
+pub struct LockState {
+    pub name: &'static str,
+    /// Index of the holding CPU plus one; 0 if the lock is free or was taken before the CPU had
+    /// its per-CPU data
+    holder: AtomicUsize,
+    /// The TSC when the lock was taken, 0 while it's free
+    since: AtomicU64,
+    /// `since` of the last holding the watchdog reported
+    reported: AtomicU64,
+}
+
+impl LockState {
+    const fn new(name: &'static str) -> LockState {
+        LockState {
+            name,
+            holder: AtomicUsize::new(0),
+            since: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// The TSC when the lock was taken and the CPU holding it, if it's held
+    pub fn held(&self) -> Option<(u64, Option<usize>)> {
+        let since = self.since.load(Ordering::Relaxed);
+        let holder = self.holder.load(Ordering::Relaxed);
+        (since != 0).then(|| (since, holder.checked_sub(1)))
+    }
+
+    /// Whether the holding that started at `since` is news; `true` only the first time
+    pub fn report_once(&self, since: u64) -> bool {
+        self.reported.swap(since, Ordering::Relaxed) != since
+    }
+
+    fn acquired(&self) {
+        let holder = percpu::try_current().map_or(0, |cpu| cpu.index + 1);
+        self.holder.store(holder, Ordering::Relaxed);
+        self.since.store(tsc::read(), Ordering::Relaxed);
+    }
+
+    fn released(&self) {
+        self.since.store(0, Ordering::Relaxed);
+        self.holder.store(0, Ordering::Relaxed);
+    }
+}
+
+/// `spin::Mutex` with a `LockState` kept up to date
+pub struct WatchedMutex<T> {
+    state: LockState,
+    inner: Mutex<T>,
+}
+
+impl<T> WatchedMutex<T> {
+    pub const fn new(name: &'static str, value: T) -> WatchedMutex<T> {
+        WatchedMutex {
+            state: LockState::new(name),
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> WatchedGuard<'_, T> {
+        let preempt = preempt::disable();
+        let mut spins = 0u32;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            spins = spins.wrapping_add(1);
+            if spins.is_multiple_of(SPINS_BEFORE_YIELD) {
+                if let Some((_, Some(holder))) = self.state.held() {
+                    paravirt::yield_to(holder);
+                }
+            }
+            core::hint::spin_loop();
+        };
+        self.state.acquired();
+        WatchedGuard {
+            guard,
+            state: &self.state,
+            _preempt: preempt,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<WatchedGuard<'_, T>> {
+        let preempt = preempt::disable();
+        let guard = self.inner.try_lock()?;
+        self.state.acquired();
+        Some(WatchedGuard {
+            guard,
+            state: &self.state,
+            _preempt: preempt,
+        })
+    }
+
+    /// Releases the lock, whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// As for `spin::Mutex::force_unlock`: the holder must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        self.state.released();
+        self.inner.force_unlock();
+    }
+
+    pub fn state(&self) -> &LockState {
+        &self.state
+    }
+}
+
+pub struct WatchedGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    state: &'a LockState,
+    /// After `guard`, so preemption is enabled once the lock is released
+    _preempt: Disabled,
+}
+
+impl<T> Deref for WatchedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for WatchedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for WatchedGuard<'_, T> {
+    fn drop(&mut self) {
+        // before the lock itself is released, so the next holder's state isn't wiped
+        self.state.released();
+    }
+}
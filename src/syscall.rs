@@ -0,0 +1,905 @@
+//! System calls: how a program in ring 3 asks the kernel for something.
+//!
+//! Programs use the `syscall` instruction with the call's number in rax and up to six arguments
+//! in rdi, rsi, rdx, r10, r8 and r9, like Linux. The result comes back in rax; failures are
+//! returned as a negated `SyscallError` code. Every register but rax, rcx and r11 is preserved.
+//!
+//! `syscall` doesn't switch stacks, so the entry code moves to this CPU's system call stack
+//! through the per-CPU data. It starts with `swapgs`, which puts the kernel's GS base in place of
+//! the program's, and ends with another just before `sysretq`; until the first, GS is whatever
+//! the program made it, so nothing is read through it (see `percpu`). Interrupts are masked on
+//! entry and enabled again once the kernel stack is in place.
+
+use crate::abi;
+use crate::audit::{self, Event, Name};
+use crate::fs::{self, FileKind, FsError, Inode};
+use crate::ipc::{self, pipe::PipeError};
+use crate::memory::address_space::Backing;
+use crate::percpu::{self, PerCpu};
+use crate::process::files::Descriptor;
+use crate::process::signal::{self, Action, Signal};
+use crate::process::Pid;
+use crate::sync::preempt;
+use crate::tty::{self, Tty};
+use crate::usermode::{self, Registers, UserExit};
+use crate::{cputime, gdt, interrupts, memory, power, process, sysctl, timer};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::fmt;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub mod strace;
+
+use strace::Arg;
+
+/// System call numbers, passed in rax
+pub mod number {
+    /// `read(fd, buffer, len) -> bytes read`
+    pub const READ: u64 = 0;
+    /// `write(fd, buffer, len) -> bytes written`
+    pub const WRITE: u64 = 1;
+    /// `exit(code) -> !`
+    pub const EXIT: u64 = 2;
+    /// `sleep(milliseconds) -> 0`
+    pub const SLEEP: u64 = 3;
+    /// `spawn(entry, stack_top, arg) -> thread id`; a `stack_top` of 0 asks for a stack that grows
+    /// on demand, up to `process::THREAD_STACK_LIMIT`
+    pub const SPAWN: u64 = 4;
+    /// `open(path, path_len) -> fd`
+    pub const OPEN: u64 = 5;
+    /// `close(fd) -> 0`
+    pub const CLOSE: u64 = 6;
+    /// `pipe(fds: *mut [u32; 2]) -> 0`, storing the read end's descriptor in `fds[0]` and the
+    /// write end's in `fds[1]`
+    pub const PIPE: u64 = 7;
+    /// `fork() -> child pid`, returning 0 in the child
+    pub const FORK: u64 = 8;
+    /// `mmap(address, len, prot, fd, offset) -> address`: reserves `len` bytes, zero-filled if
+    /// `fd` is -1 or else read from the file at `offset`, which are mapped when first touched;
+    /// with `address` 0 the kernel picks where. `prot` is a combination of the `prot` flags.
+    pub const MMAP: u64 = 9;
+    /// `abi(buffer, len) -> manifest length`: copies as much of the ABI manifest (see `abi`) as
+    /// fits into the buffer; the result says whether it all did
+    pub const ABI: u64 = 10;
+    /// `sysctl(name, name_len, value) -> previous value`: sets the kernel parameter called `name`
+    /// (see `sysctl`) to `value` and returns what it was; a `value` of -1 only reads it
+    pub const SYSCTL: u64 = 11;
+    /// `reboot(command) -> !`: ends every process, the caller's too, writes back the
+    /// filesystems and restarts or turns off the machine, as `command` from `reboot` says
+    pub const REBOOT: u64 = 12;
+    /// `signal(signal, action) -> previous action`: sets what the signal numbered `signal` does
+    /// to the calling process (see `process::signal`): 0 ends it, 1 ignores the signal, anything
+    /// else is the address of a handler
+    pub const SIGNAL: u64 = 13;
+    /// `sigreturn(frame) -> !`: ends a signal handler, continuing the thread with the registers
+    /// in the `signal::Frame` the handler got
+    pub const SIGRETURN: u64 = 14;
+    /// `kill(pid, signal) -> 0`: sends the signal numbered `signal` to process `pid`
+    pub const KILL: u64 = 15;
+    /// `times(times: *mut [u64; 4]) -> 0`: stores, in nanoseconds, the CPU time of the calling
+    /// process, of the kernel's tasks and of all CPUs halted, and the time since boot (see
+    /// `cputime`)
+    pub const TIMES: u64 = 16;
+    /// `utimes(path, path_len, atime, mtime) -> 0`: sets the access and modification times of the
+    /// file or directory at `path`, in seconds since the Unix epoch; -1 leaves one as it is. The
+    /// change time becomes now if the modification time changes.
+    pub const UTIMES: u64 = 17;
+    /// `getdents(fd, buffer, len, cursor) -> bytes filled`: fills the buffer with entries of the
+    /// directory open as `fd`, starting at `cursor` (0 for the start), laid out as `dirent`
+    /// says. The next call passes the `next` cursor of the last entry it got; 0 bytes means none
+    /// are left. Cursors stay valid while the directory changes, see `Inode::readdir`.
+    pub const GETDENTS: u64 = 18;
+}
+
+/// Protection flags for `mmap`; memory is always readable
+pub mod prot {
+    pub const READ: u64 = 1;
+    pub const WRITE: u64 = 2;
+    pub const EXEC: u64 = 4;
+}
+
+/// The entries `getdents` fills its buffer with, one after the other: `inode[8]`, `next[8]`,
+/// `name_len[2]`, `kind[1]` and the UTF-8 name, padded with zeros to a multiple of 8 bytes; all
+/// little-endian
+pub mod dirent {
+    pub const HEADER_SIZE: usize = 19;
+    /// Values of `kind`
+    pub const UNKNOWN: u8 = 0;
+    pub const FILE: u8 = 1;
+    pub const DIRECTORY: u8 = 2;
+}
+
+/// Commands for `reboot`
+pub mod reboot {
+    pub const RESTART: u64 = 0;
+    pub const POWER_OFF: u64 = 1;
+}
+
+/// Size of each CPU's system call stack
+const SYSCALL_STACK_SIZE: u64 = 4096 * 8;
+
+/// What the entry code saved, lowest address first
+#[repr(C)]
+struct SyscallFrame {
+    number: u64,
+    args: [u64; 6],
+    /// The callee-saved registers, only needed to restart the call (see `dispatch`)
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The program's registers as they were when it made the call, with rip pointing at the
+    /// `syscall` instruction again
+    fn restart_registers(&self) -> Registers {
+        let [rdi, rsi, rdx, r10, r8, r9] = self.args;
+        let mut registers = Registers::new(
+            VirtAddr::new(self.rip - 2), // `syscall` is two bytes long
+            VirtAddr::new(self.rsp),
+            rdi,
+        );
+        registers.rax = self.number;
+        registers.rbx = self.rbx;
+        registers.rcx = self.rip;
+        registers.rdx = rdx;
+        registers.rsi = rsi;
+        registers.rbp = self.rbp;
+        registers.r8 = r8;
+        registers.r9 = r9;
+        registers.r10 = r10;
+        registers.r11 = self.rflags;
+        registers.r12 = self.r12;
+        registers.r13 = self.r13;
+        registers.r14 = self.r14;
+        registers.r15 = self.r15;
+        registers.rflags = self.rflags;
+        registers
+    }
+
+    /// The program's registers as they'll be once the call has returned `value`
+    fn return_registers(&self, value: u64) -> Registers {
+        let mut registers = self.restart_registers();
+        registers.rip = self.rip;
+        registers.rax = value;
+        registers
+    }
+}
+
+global_asm!(
+    r#"
+    .global syscall_entry
+    syscall_entry:
+        swapgs
+        movq %rsp, %gs:{user_rsp}
+        movq %gs:{syscall_stack}, %rsp
+
+        # a SyscallFrame; 16 quadwords keep the stack 16-byte aligned for the call
+        pushq %gs:{user_rsp}
+        pushq %rcx                  # user rip
+        pushq %r11                  # user rflags
+        pushq %r15
+        pushq %r14
+        pushq %r13
+        pushq %r12
+        pushq %rbp
+        pushq %rbx
+        pushq %r9
+        pushq %r8
+        pushq %r10
+        pushq %rdx
+        pushq %rsi
+        pushq %rdi
+        pushq %rax
+        movq %rsp, %rdi
+        callq {dispatch}
+
+        # the dispatcher returns with interrupts off again, as the stack is about to be the user's;
+        # the argument registers are restored so no kernel values leak out through them
+        addq $8, %rsp
+        popq %rdi
+        popq %rsi
+        popq %rdx
+        popq %r10
+        popq %r8
+        popq %r9
+        addq $48, %rsp              # the callee-saved registers were preserved anyway
+        popq %r11
+        popq %rcx
+        popq %rsp
+        swapgs
+        sysretq
+    "#,
+    user_rsp = const offset_of!(PerCpu, user_rsp),
+    syscall_stack = const offset_of!(PerCpu, syscall_stack),
+    dispatch = sym dispatch,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Why a system call failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// There is no system call with that number
+    NoSuchCall,
+    /// A pointer argument doesn't point into memory the program may access
+    BadAddress,
+    /// The file descriptor doesn't refer to anything
+    BadDescriptor,
+    InvalidArgument,
+    /// The process's descriptor table is full
+    TooManyFiles,
+    /// The call can't make progress yet; threads of a process retry it instead of seeing this
+    WouldBlock,
+    /// Writing to a pipe without readers
+    BrokenPipe,
+    NoSuchProcess,
+    OutOfMemory,
+    /// The filesystem refused
+    Fs(FsError),
+}
+
+impl SyscallError {
+    /// The code returned (negated) in rax; Linux's errno values, so they're familiar
+    pub fn code(self) -> u64 {
+        match self {
+            SyscallError::NoSuchCall => 38,
+            SyscallError::BadAddress => 14,
+            SyscallError::BadDescriptor => 9,
+            SyscallError::InvalidArgument => 22,
+            SyscallError::TooManyFiles => 24,
+            SyscallError::WouldBlock => 11,
+            SyscallError::BrokenPipe => 32,
+            SyscallError::NoSuchProcess => 3,
+            SyscallError::OutOfMemory => 12,
+            SyscallError::Fs(err) => match err {
+                FsError::NotFound => 2,
+                FsError::AlreadyExists => 17,
+                FsError::NotADirectory => 20,
+                FsError::IsADirectory => 21,
+                FsError::DirectoryNotEmpty => 39,
+                FsError::NameTooLong => 36,
+                FsError::InvalidArgument => 22,
+                FsError::NoSpace => 28,
+                FsError::PermissionDenied => 13,
+                FsError::Busy => 16,
+                FsError::Corrupted | FsError::Block(_) => 5,
+            },
+        }
+    }
+}
+
+impl From<FsError> for SyscallError {
+    fn from(err: FsError) -> Self {
+        SyscallError::Fs(err)
+    }
+}
+
+impl From<PipeError> for SyscallError {
+    fn from(err: PipeError) -> Self {
+        match err {
+            PipeError::WouldBlock => SyscallError::WouldBlock,
+            PipeError::Broken => SyscallError::BrokenPipe,
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            SyscallError::NoSuchCall => "no such system call",
+            SyscallError::BadAddress => "bad address",
+            SyscallError::BadDescriptor => "bad file descriptor",
+            SyscallError::InvalidArgument => "invalid argument",
+            SyscallError::TooManyFiles => "too many open files",
+            SyscallError::WouldBlock => "operation would block",
+            SyscallError::BrokenPipe => "broken pipe",
+            SyscallError::NoSuchProcess => "no such process",
+            SyscallError::OutOfMemory => "out of memory",
+            SyscallError::Fs(err) => return write!(f, "{}", err),
+        };
+        f.write_str(message)
+    }
+}
+
+/// One entry of the dispatch table; `handler` turns the raw argument registers into the typed
+/// arguments of the function doing the work
+struct Syscall {
+    name: &'static str,
+    /// How `strace` shows the arguments
+    args: &'static [Arg],
+    handler: fn(&SyscallFrame) -> Result<u64, SyscallError>,
+}
+
+/// Indexed by system call number
+static TABLE: [Syscall; 19] = [
+    Syscall {
+        name: "read",
+        args: &[Arg::Fd, Arg::Out],
+        handler: |frame| read(frame.args[0], user_bytes_mut(frame.args[1], frame.args[2])?),
+    },
+    Syscall {
+        name: "write",
+        args: &[Arg::Fd, Arg::In],
+        handler: |frame| write(frame.args[0], user_bytes(frame.args[1], frame.args[2])?),
+    },
+    Syscall {
+        name: "exit",
+        args: &[Arg::Int],
+        handler: |frame| exit(frame.args[0]),
+    },
+    Syscall {
+        name: "sleep",
+        args: &[Arg::Int],
+        handler: |frame| sleep(Duration::from_millis(frame.args[0])),
+    },
+    Syscall {
+        name: "spawn",
+        args: &[Arg::Hex, Arg::Hex, Arg::Hex],
+        handler: |frame| {
+            spawn(
+                user_address(frame.args[0])?,
+                user_address(frame.args[1])?,
+                frame.args[2],
+            )
+        },
+    },
+    Syscall {
+        name: "open",
+        args: &[Arg::In],
+        handler: |frame| open(user_str(frame.args[0], frame.args[1])?),
+    },
+    Syscall {
+        name: "close",
+        args: &[Arg::Fd],
+        handler: |frame| close(frame.args[0]),
+    },
+    Syscall {
+        name: "pipe",
+        args: &[Arg::Hex],
+        handler: |frame| pipe(user_bytes_mut(frame.args[0], 8)?),
+    },
+    Syscall {
+        name: "fork",
+        args: &[],
+        handler: fork,
+    },
+    Syscall {
+        name: "mmap",
+        args: &[Arg::Hex, Arg::Int, Arg::Hex, Arg::Fd, Arg::Int],
+        handler: |frame| {
+            let [address, len, prot, fd, offset, _] = frame.args;
+            mmap(address, len, prot, fd, offset)
+        },
+    },
+    Syscall {
+        name: "abi",
+        args: &[Arg::Out],
+        handler: |frame| Ok(abi(user_bytes_mut(frame.args[0], frame.args[1])?)),
+    },
+    Syscall {
+        name: "sysctl",
+        args: &[Arg::In, Arg::Int],
+        handler: |frame| sysctl(user_str(frame.args[0], frame.args[1])?, frame.args[2]),
+    },
+    Syscall {
+        name: "reboot",
+        args: &[Arg::Int],
+        handler: |frame| reboot(frame.args[0]),
+    },
+    Syscall {
+        name: "signal",
+        args: &[Arg::Int, Arg::Hex],
+        handler: |frame| signal(frame.args[0], frame.args[1]),
+    },
+    Syscall {
+        name: "sigreturn",
+        args: &[Arg::Hex],
+        handler: |frame| {
+            sigreturn(user_bytes(
+                frame.args[0],
+                size_of::<signal::Frame>() as u64,
+            )?)
+        },
+    },
+    Syscall {
+        name: "kill",
+        args: &[Arg::Int, Arg::Int],
+        handler: |frame| kill(frame.args[0], frame.args[1]),
+    },
+    Syscall {
+        name: "times",
+        args: &[Arg::Hex],
+        handler: |frame| times(user_bytes_mut(frame.args[0], 32)?),
+    },
+    Syscall {
+        name: "utimes",
+        args: &[Arg::In, Arg::Int, Arg::Int],
+        handler: |frame| {
+            let [path, path_len, atime, mtime, _, _] = frame.args;
+            utimes(user_str(path, path_len)?, atime, mtime)
+        },
+    },
+    Syscall {
+        name: "getdents",
+        args: &[Arg::Fd, Arg::Out, Arg::Int],
+        handler: |frame| {
+            let [fd, buffer, len, cursor, _, _] = frame.args;
+            getdents(fd, user_bytes_mut(buffer, len)?, cursor)
+        },
+    },
+];
+
+/// Names of the calls by number, for the ABI manifest
+pub const NAMES: [&str; TABLE.len()] = {
+    let mut names = [""; TABLE.len()];
+    let mut number = 0;
+    while number < TABLE.len() {
+        names[number] = TABLE[number].name;
+        number += 1;
+    }
+    names
+};
+
+/// Name of system call `number`, if there is one
+pub fn name(number: u64) -> Option<&'static str> {
+    TABLE.get(number as usize).map(|call| call.name)
+}
+
+/// Sets up `syscall`/`sysret` and this CPU's system call stack; run once on every CPU after
+/// `percpu::init`
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("the GDT segments are laid out for sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+
+    let syscall_stack = &percpu::current().syscall_stack;
+    if syscall_stack.load(Ordering::SeqCst) == 0 {
+        // a CPU coming back online still has the one it got the first time
+        let top = memory::allocate_kernel_stack(SYSCALL_STACK_SIZE)
+            .expect("failed to map the system call stack");
+        syscall_stack.store(top.as_u64(), Ordering::SeqCst);
+    }
+
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Runs the call `frame` describes. One that would block is, for a thread of a process, started
+/// over on the thread's next time slice; the scheduler runs the other threads meanwhile. A slice
+/// that ran out during the call ends on the way back to ring 3, as does one with a signal to
+/// deliver.
+extern "C" fn dispatch(frame: &SyscallFrame) -> u64 {
+    x86_64::instructions::interrupts::enable();
+    let traced = process::current().filter(|process| process.is_traced());
+    if let Some(process) = &traced {
+        strace::enter(process, frame);
+    }
+    let result = match TABLE.get(frame.number as usize) {
+        Some(call) => (call.handler)(frame),
+        None => Err(SyscallError::NoSuchCall),
+    };
+    if let Some(process) = traced {
+        strace::leave(&process, frame, result);
+    }
+    if result == Err(SyscallError::Fs(FsError::PermissionDenied)) {
+        audit::record(Event::Denied {
+            syscall: name(frame.number).unwrap_or("?"),
+        });
+    }
+    x86_64::instructions::interrupts::disable();
+    if result == Err(SyscallError::WouldBlock) && process::current().is_some() {
+        usermode::preempt(&frame.restart_registers());
+    }
+    let value = match result {
+        Ok(value) => value,
+        Err(err) => err.code().wrapping_neg(),
+    };
+    // the time slice ran out during the call, or a signal wants handling before the program
+    // goes on
+    let signaled = process::current().is_some_and(|process| process.signals_pending());
+    if preempt::take_request() || signaled {
+        usermode::preempt(&frame.return_registers(value));
+    }
+    value
+}
+
+// --- argument checking ---------------------------------------------------------------------
+
+/// A pointer argument, which must point into user memory
+fn user_address(address: u64) -> Result<VirtAddr, SyscallError> {
+    match VirtAddr::try_new(address) {
+        Ok(address) if (memory::USER_START..memory::USER_END).contains(&address.as_u64()) => {
+            Ok(address)
+        }
+        _ => Err(SyscallError::BadAddress),
+    }
+}
+
+/// The `len` bytes at `address`, which the program must be allowed to read.
+///
+/// The slice stays valid for the rest of the system call: the program is stopped while the
+/// kernel runs it, and nothing else unmaps user memory.
+fn user_bytes<'a>(address: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    let start = user_address(address)?;
+    if !user_accessible(start, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), len as usize) })
+}
+
+/// Like `user_bytes`, for memory the program must be allowed to write
+fn user_bytes_mut<'a>(address: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+    let start = user_address(address)?;
+    if !user_accessible(start, len, true) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr(), len as usize) })
+}
+
+/// `memory::user_accessible`, after mapping what the process reserved in the range but hasn't
+/// touched yet. Faulting those pages in while the call runs would do too, but not with a
+/// filesystem lock held, which the page might have to be read through.
+pub fn user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    if memory::user_accessible(start, len, write) {
+        return true;
+    }
+    process::current().is_some_and(|process| {
+        process.space().populate(start, len).is_ok() && memory::user_accessible(start, len, write)
+    })
+}
+
+/// A string argument: `len` bytes of UTF-8 at `address`
+fn user_str<'a>(address: u64, len: u64) -> Result<&'a str, SyscallError> {
+    core::str::from_utf8(user_bytes(address, len)?).map_err(|_| SyscallError::InvalidArgument)
+}
+
+// --- the calls -----------------------------------------------------------------------------
+
+/// Runs `f` on what `fd` refers to in the calling process's descriptor table. Programs run
+/// outside a process (see `usermode::run`) have only the console, as descriptors 0 to 2.
+fn with_descriptor<R>(
+    fd: u64,
+    f: impl FnOnce(&mut Descriptor) -> Result<R, SyscallError>,
+) -> Result<R, SyscallError> {
+    match process::current() {
+        Some(process) => f(process
+            .files()
+            .get_mut(fd)
+            .ok_or(SyscallError::BadDescriptor)?),
+        None if fd <= 2 => f(&mut Descriptor::Console),
+        None => Err(SyscallError::BadDescriptor),
+    }
+}
+
+/// The terminal behind the caller's console descriptors: its process's, or the active one for
+/// programs outside a process
+fn console_tty() -> &'static Tty {
+    process::current().map_or_else(tty::active, |process| process.tty())
+}
+
+fn read(fd: u64, buffer: &mut [u8]) -> Result<u64, SyscallError> {
+    with_descriptor(fd, |descriptor| match descriptor {
+        Descriptor::Console => console_tty()
+            .try_read(buffer)
+            .map(|read| read as u64)
+            .ok_or(SyscallError::WouldBlock),
+        Descriptor::File(file) => Ok(file.lock().read(buffer)? as u64),
+        Descriptor::PipeReader(reader) => Ok(reader.try_read(buffer)? as u64),
+        Descriptor::PipeWriter(_) => Err(SyscallError::BadDescriptor),
+    })
+}
+
+fn write(fd: u64, buffer: &[u8]) -> Result<u64, SyscallError> {
+    with_descriptor(fd, |descriptor| match descriptor {
+        Descriptor::Console => {
+            console_tty().write(buffer);
+            Ok(buffer.len() as u64)
+        }
+        Descriptor::File(file) => Ok(file.lock().write(buffer)? as u64),
+        Descriptor::PipeWriter(writer) => Ok(writer.try_write(buffer)? as u64),
+        Descriptor::PipeReader(_) => Err(SyscallError::BadDescriptor),
+    })
+}
+
+/// Opens the regular file at `path` for reading and writing; only processes have a descriptor
+/// table to put it in
+fn open(path: &str) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let file = fs::open(path)?;
+    let fd = process
+        .files()
+        .insert(Descriptor::File(Arc::new(Mutex::new(file))));
+    fd.map_err(|_| SyscallError::TooManyFiles)
+}
+
+/// Creates a pipe and stores the descriptors of its ends in `fds`, as two little-endian `u32`s
+fn pipe(fds: &mut [u8]) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let (reader, writer) = ipc::pipe::pipe(ipc::pipe::PIPE_CAPACITY);
+    let mut files = process.files();
+    let read_fd = files
+        .insert(Descriptor::PipeReader(reader))
+        .map_err(|_| SyscallError::TooManyFiles)?;
+    let write_fd = match files.insert(Descriptor::PipeWriter(writer)) {
+        Ok(fd) => fd,
+        Err(_) => {
+            files.remove(read_fd);
+            return Err(SyscallError::TooManyFiles);
+        }
+    };
+    fds[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    fds[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+    Ok(0)
+}
+
+fn close(fd: u64) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::BadDescriptor)?;
+    let descriptor = process.files().remove(fd);
+    descriptor.map(|_| 0).ok_or(SyscallError::BadDescriptor)
+}
+
+/// Copies the calling process; the copy's only thread continues from the same call, which
+/// returns 0 there
+fn fork(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let child = process
+        .fork(frame.return_registers(0))
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    Ok(child.pid().0)
+}
+
+fn mmap(address: u64, len: u64, prot: u64, fd: u64, offset: u64) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let size = len
+        .checked_next_multiple_of(4096)
+        .ok_or(SyscallError::InvalidArgument)?;
+    if size == 0 || !address.is_multiple_of(4096) || !offset.is_multiple_of(4096) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let backing = match fd {
+        u64::MAX => Backing::Anonymous,
+        fd => {
+            let inode = descriptor_inode(fd)?;
+            if inode.metadata()?.kind == FileKind::Directory {
+                return Err(SyscallError::Fs(FsError::IsADirectory));
+            }
+            Backing::File { inode, offset }
+        }
+    };
+    let mut flags = PageTableFlags::empty();
+    if prot & prot::WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & prot::EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let space = process.space();
+    let start = match address {
+        0 => space.find_free(size).ok_or(SyscallError::OutOfMemory)?,
+        address => user_address(address)?,
+    };
+    space
+        .reserve(start, size, flags, backing)
+        .map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(start.as_u64())
+}
+
+fn abi(buffer: &mut [u8]) -> u64 {
+    let manifest = abi::MANIFEST.as_bytes();
+    let len = buffer.len().min(manifest.len());
+    buffer[..len].copy_from_slice(&manifest[..len]);
+    manifest.len() as u64
+}
+
+fn sysctl(name: &str, value: u64) -> Result<u64, SyscallError> {
+    let tunable = sysctl::find(name).ok_or(SyscallError::Fs(FsError::NotFound))?;
+    let previous = tunable.get();
+    if value != u64::MAX {
+        tunable
+            .set(value)
+            .map_err(|_| SyscallError::InvalidArgument)?;
+        audit::record(Event::Sysctl {
+            name: Name::new(tunable.name),
+            old: previous,
+            new: value,
+        });
+    }
+    Ok(previous)
+}
+
+/// Only returns if `command` isn't one
+fn reboot(command: u64) -> Result<u64, SyscallError> {
+    audit::record(Event::Reboot { command });
+    match command {
+        reboot::RESTART => power::restart(),
+        reboot::POWER_OFF => power::shutdown(),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+fn signal(number: u64, action: u64) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let signal = Signal::from_number(number).ok_or(SyscallError::InvalidArgument)?;
+    let action = Action::from_raw(action).ok_or(SyscallError::InvalidArgument)?;
+    let previous = process.set_signal_action(signal, action);
+    previous
+        .map(Action::to_raw)
+        .map_err(|_| SyscallError::InvalidArgument)
+}
+
+/// Continues the thread with the registers in `frame`, a `signal::Frame`, from the next time
+/// slice on
+fn sigreturn(frame: &[u8]) -> Result<u64, SyscallError> {
+    process::current().ok_or(SyscallError::NoSuchCall)?;
+    let frame = unsafe { (frame.as_ptr() as *const signal::Frame).read_unaligned() };
+    let registers = frame.registers.sanitized();
+    user_address(registers.rip)?;
+    x86_64::instructions::interrupts::disable();
+    usermode::preempt(&registers);
+    unreachable!("a thread of a process is always resumed with a place for its registers")
+}
+
+fn kill(pid: u64, number: u64) -> Result<u64, SyscallError> {
+    let signal = Signal::from_number(number).ok_or(SyscallError::InvalidArgument)?;
+    let process = process::get(Pid(pid)).ok_or(SyscallError::NoSuchProcess)?;
+    process.signal(signal);
+    Ok(0)
+}
+
+/// Stores the times `number::TIMES` lists in `times`, as little-endian `u64`s
+fn times(times: &mut [u8]) -> Result<u64, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchCall)?;
+    let values = [
+        process.cpu_time(),
+        cputime::total_tasks(),
+        cputime::total_idle(),
+        cputime::uptime(),
+    ];
+    for (bytes, value) in times.chunks_exact_mut(8).zip(values) {
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        bytes.copy_from_slice(&nanos.to_le_bytes());
+    }
+    Ok(0)
+}
+
+/// The inode behind descriptor `fd`, which must be an open file or directory
+fn descriptor_inode(fd: u64) -> Result<Arc<dyn Inode>, SyscallError> {
+    with_descriptor(fd, |descriptor| match descriptor {
+        Descriptor::File(file) => file.lock().inode().ok_or(SyscallError::BadDescriptor),
+        _ => Err(SyscallError::BadDescriptor),
+    })
+}
+
+/// Fills `buffer` with entries of the directory `fd` refers to, from `cursor` on, as
+/// `number::GETDENTS` describes; fails if not even the first one fits
+fn getdents(fd: u64, buffer: &mut [u8], cursor: u64) -> Result<u64, SyscallError> {
+    let inode = descriptor_inode(fd)?;
+    let mut filled = 0;
+    let mut too_small = false;
+    inode.readdir(cursor, &mut |entry| {
+        let name = entry.name.as_bytes();
+        let len = (dirent::HEADER_SIZE + name.len()).next_multiple_of(8);
+        let Some(record) = buffer.get_mut(filled..filled + len) else {
+            too_small = filled == 0;
+            return false;
+        };
+        let kind = match entry.kind {
+            Some(FileKind::File) => dirent::FILE,
+            Some(FileKind::Directory) => dirent::DIRECTORY,
+            None => dirent::UNKNOWN,
+        };
+        record.fill(0);
+        record[..8].copy_from_slice(&entry.inode.to_le_bytes());
+        record[8..16].copy_from_slice(&entry.next.to_le_bytes());
+        record[16..18].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        record[18] = kind;
+        record[dirent::HEADER_SIZE..dirent::HEADER_SIZE + name.len()].copy_from_slice(name);
+        filled += len;
+        true
+    })?;
+    match too_small {
+        true => Err(SyscallError::InvalidArgument),
+        false => Ok(filled as u64),
+    }
+}
+
+/// Sets the times of the file at `path` as `number::UTIMES` describes
+fn utimes(path: &str, atime: u64, mtime: u64) -> Result<u64, SyscallError> {
+    process::current().ok_or(SyscallError::NoSuchCall)?;
+    let time = |value| (value != u64::MAX).then_some(value);
+    fs::set_times(path, time(atime), time(mtime))?;
+    Ok(0)
+}
+
+fn exit(code: u64) -> Result<u64, SyscallError> {
+    x86_64::instructions::interrupts::disable();
+    usermode::exit(UserExit::Exit { code })
+}
+
+/// Halts until the timer has ticked past the deadline; the program has the CPU to itself anyway
+fn sleep(duration: Duration) -> Result<u64, SyscallError> {
+    let deadline = interrupts::ticks() + timer::duration_to_ticks(duration);
+    while interrupts::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    Ok(0)
+}
+
+/// A program started with `spawn` outside a process, waiting for the kernel to run it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spawned {
+    pub id: u64,
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+    pub arg: u64,
+}
+
+static SPAWNED: Mutex<VecDeque<Spawned>> = Mutex::new(VecDeque::new());
+
+/// Starts a thread in the caller's process. Programs run outside a process get theirs queued
+/// instead, sharing the caller's address space; it runs once the kernel gets to it, after the
+/// caller has left ring 3.
+fn spawn(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> Result<u64, SyscallError> {
+    if !user_accessible(entry, 1, false) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if let Some(process) = process::current() {
+        let stack_top = match stack_top.is_null() {
+            true => process
+                .reserve_thread_stack()
+                .map_err(|_| SyscallError::OutOfMemory)?,
+            false => stack_top,
+        };
+        if !user_accessible(VirtAddr::new(stack_top.as_u64().saturating_sub(8)), 8, true) {
+            return Err(SyscallError::InvalidArgument);
+        }
+        return Ok(process.spawn_thread(Registers::new(entry, stack_top, arg)));
+    }
+    if !memory::user_accessible(VirtAddr::new(stack_top.as_u64().saturating_sub(8)), 8, true) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    SPAWNED.lock().push_back(Spawned {
+        id,
+        entry,
+        stack_top,
+        arg,
+    });
+    Ok(id)
+}
+
+/// The oldest program waiting to be run, if any
+pub fn take_spawned() -> Option<Spawned> {
+    SPAWNED.lock().pop_front()
+}
@@ -0,0 +1,130 @@
+//! System call tracing, like `strace`: every call a traced process makes goes to the kernel log
+//! as a line with its arguments and result.
+//!
+//! `strace <pid> on` starts it and a fork's child is traced if its parent was. Lines are tagged
+//! `strace` in the log (see `klog::Source`), and stay off the display, where they'd mix with what
+//! the program prints; `dmesg` shows them. Arguments are decoded as the call's `Arg`s say: buffers
+//! the program passes in show their first `SHOWN_BYTES` bytes, ones the kernel fills show only
+//! their address and length.
+
+use super::{number, SyscallError, SyscallFrame, TABLE};
+use crate::process::{self, Process};
+use crate::{klog, println};
+use core::fmt;
+
+/// Bytes of a buffer or string argument shown
+const SHOWN_BYTES: usize = 32;
+
+/// How a call's argument registers are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    Int,
+    Hex,
+    /// A file descriptor, with -1 shown as such
+    Fd,
+    /// Address and length, two registers, of bytes the program passes in: shown quoted
+    In,
+    /// Address and length, two registers, of memory the kernel writes to
+    Out,
+}
+
+/// A call as the program made it, `name(arg, ...)`
+struct Call<'a>(&'a SyscallFrame);
+
+impl fmt::Display for Call<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.0;
+        let Some(call) = TABLE.get(frame.number as usize) else {
+            return write!(f, "syscall_{}(...)", frame.number);
+        };
+        write!(f, "{}(", call.name)?;
+        let mut registers = frame.args.iter().copied();
+        for (index, arg) in call.args.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            let value = registers.next().unwrap_or(0);
+            match arg {
+                Arg::Int => write!(f, "{}", value)?,
+                Arg::Hex => write!(f, "{:#x}", value)?,
+                Arg::Fd if value == u64::MAX => f.write_str("-1")?,
+                Arg::Fd => write!(f, "{}", value)?,
+                Arg::In => {
+                    let len = registers.next().unwrap_or(0);
+                    match super::user_bytes(value, len) {
+                        Ok(bytes) => {
+                            let shown = &bytes[..bytes.len().min(SHOWN_BYTES)];
+                            write!(f, "\"{}\"", shown.escape_ascii())?;
+                            if bytes.len() > shown.len() {
+                                f.write_str("...")?;
+                            }
+                        }
+                        Err(_) => write!(f, "{:#x}", value)?,
+                    }
+                    write!(f, ", {}", len)?;
+                }
+                Arg::Out => {
+                    let len = registers.next().unwrap_or(0);
+                    write!(f, "{:#x}, {}", value, len)?;
+                }
+            }
+        }
+        f.write_str(")")
+    }
+}
+
+/// A call's result, the way the program sees it
+struct Return(Result<u64, SyscallError>);
+
+impl fmt::Display for Return {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Ok(value) if value > u32::MAX as u64 => write!(f, "{:#x}", value),
+            Ok(value) => write!(f, "{}", value),
+            Err(err) => write!(f, "-{} ({})", err.code(), err),
+        }
+    }
+}
+
+/// Logs a call that doesn't come back, before it's made
+pub(super) fn enter(process: &Process, frame: &SyscallFrame) {
+    if matches!(
+        frame.number,
+        number::EXIT | number::REBOOT | number::SIGRETURN
+    ) {
+        log(format_args!("pid {}: {} = ?", process.pid(), Call(frame)));
+    }
+}
+
+/// Logs a call and what it returned
+pub(super) fn leave(process: &Process, frame: &SyscallFrame, result: Result<u64, SyscallError>) {
+    log(format_args!(
+        "pid {}: {} = {}",
+        process.pid(),
+        Call(frame),
+        Return(result)
+    ));
+}
+
+fn log(args: fmt::Arguments) {
+    klog::write(klog::Source::Strace, format_args!("{}\n", args));
+}
+
+/// `strace [<pid> on|off]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let (pid, on) = match args {
+        [] => {
+            for process in process::list().iter().filter(|process| process.is_traced()) {
+                println!("{} {}", process.pid(), process.name());
+            }
+            return Ok(());
+        }
+        [pid, "on"] => (pid, true),
+        [pid, "off"] => (pid, false),
+        _ => return Err("usage: strace [<pid> on|off]"),
+    };
+    let pid = pid.parse().map_err(|_| "the pid must be a number")?;
+    let process = process::get(process::Pid(pid)).ok_or("no such process")?;
+    process.set_traced(on);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! Kernel parameters that can be changed while the kernel runs, named by subsystem like Linux's
+//! sysctls: `sched.slice_ticks`, `watchdog.lock_timeout_ms` and so on.
+//!
+//! A subsystem keeps each of its parameters in a `Tunable` static, reads it where it used to read
+//! a constant, and lists it in `TUNABLES`. Values are integers with a range fixed by the
+//! subsystem; they can be read and set with the `sysctl` system call, through the files in
+//! `/proc/sys` and with the `sysctl` shell command. Reading one is a relaxed atomic load, so
+//! interrupt handlers may too. Values are back at their defaults after every boot.
+
+use crate::allocator::quota;
+use crate::memory::pressure;
+#[cfg(feature = "net")]
+use crate::net::nic;
+use crate::{crash, println, process, profile, scrub, watchdog};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// One parameter: an integer between `min` and `max`, both included
+pub struct Tunable {
+    /// `<subsystem>.<parameter>`
+    pub name: &'static str,
+    /// One line, shown by the shell command
+    pub help: &'static str,
+    pub min: u64,
+    pub max: u64,
+    value: AtomicU64,
+}
+
+impl Tunable {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        default: u64,
+        min: u64,
+        max: u64,
+    ) -> Tunable {
+        assert!(min <= default && default <= max);
+        Tunable {
+            name,
+            help,
+            min,
+            max,
+            value: AtomicU64::new(default),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Changes the value, unless it's out of range
+    pub fn set(&self, value: u64) -> Result<(), &'static str> {
+        if !(self.min..=self.max).contains(&value) {
+            return Err("value out of range");
+        }
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Every parameter, sorted by name
+static TUNABLES: &[&Tunable] = &[
+    &quota::GLOBAL_LIMIT,
+    &pressure::CRITICAL_PERCENT,
+    &pressure::MEDIUM_PERCENT,
+    #[cfg(feature = "net")]
+    &nic::RECEIVE_LIMIT,
+    &crash::ACTION,
+    &crash::REBOOT_DELAY,
+    &process::TIME_SLICE,
+    &profile::INTERVAL,
+    &scrub::INTERVAL,
+    &watchdog::HEARTBEAT_TIMEOUT,
+    &watchdog::LOCK_TIMEOUT,
+];
+
+pub fn tunables() -> &'static [&'static Tunable] {
+    TUNABLES
+}
+
+/// The parameter called `name`
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    TUNABLES
+        .iter()
+        .copied()
+        .find(|tunable| tunable.name == name)
+}
+
+/// `sysctl [<name> [<value>]]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for tunable in TUNABLES {
+                println!(
+                    "{} = {} ({}..={}): {}",
+                    tunable.name,
+                    tunable.get(),
+                    tunable.min,
+                    tunable.max,
+                    tunable.help
+                );
+            }
+        }
+        [name] => {
+            let tunable = find(name).ok_or("no such parameter")?;
+            println!("{} = {}", tunable.name, tunable.get());
+        }
+        [name, value] => {
+            let tunable = find(name).ok_or("no such parameter")?;
+            let value = value.parse().map_err(|_| "the value must be a number")?;
+            tunable.set(value)?;
+            println!("{} = {}", tunable.name, tunable.get());
+        }
+        _ => return Err("usage: sysctl [<name> [<value>]]"),
+    }
+    Ok(())
+}
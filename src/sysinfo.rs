@@ -0,0 +1,216 @@
+//! What the kernel booted on, summed up: the CPU, the RAM the bootloader found, the firmware
+//! tables and which of the kernel's optional parts are in use.
+//!
+//! `print_banner` shows it once the boot phases are done, the way GRUB and Linux show theirs, and
+//! `sysinfo` prints it again with the memory map. `cpu` and `memory` hand out the same facts for
+//! other commands to show, like `cpu` and `mem`.
+
+use crate::bootinfo::MemoryKind;
+use crate::{acpi, apic, boot, clock, memory, numa, paravirt, percpu, println, signing};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use raw_cpuid::CpuId;
+
+/// The boot CPU as CPUID describes it
+pub struct Cpu {
+    pub vendor: String,
+    /// E.g. "Intel(R) Core(TM) i7-8700 CPU @ 3.20GHz"; empty if the CPU doesn't say
+    pub brand: String,
+    pub family: u8,
+    pub model: u8,
+    pub stepping: u8,
+    /// Names of the features of note it has, as Linux's `/proc/cpuinfo` calls them
+    pub features: Vec<&'static str>,
+}
+
+pub fn cpu() -> Cpu {
+    let cpuid = CpuId::new();
+    let mut features = Vec::new();
+    let mut add = |name: &'static str, present: bool| {
+        if present {
+            features.push(name);
+        }
+    };
+    let info = cpuid.get_feature_info();
+    if let Some(info) = &info {
+        add("sse4_2", info.has_sse42());
+        add("popcnt", info.has_popcnt());
+        add("aes", info.has_aesni());
+        add("avx", info.has_avx());
+        add("rdrand", info.has_rdrand());
+        add("x2apic", info.has_x2apic());
+        add("hypervisor", info.has_hypervisor());
+    }
+    if let Some(extended) = cpuid.get_extended_feature_info() {
+        add("avx2", extended.has_avx2());
+        add("smep", extended.has_smep());
+        add("smap", extended.has_smap());
+    }
+    if let Some(extended) = cpuid.get_extended_processor_and_feature_identifiers() {
+        add("nx", extended.has_execute_disable());
+        add("pdpe1gb", extended.has_1gib_pages());
+    }
+    Cpu {
+        vendor: cpuid
+            .get_vendor_info()
+            .map_or_else(String::new, |vendor| String::from(vendor.as_str())),
+        brand: cpuid
+            .get_processor_brand_string()
+            .map_or_else(String::new, |brand| String::from(brand.as_str().trim())),
+        family: info.as_ref().map_or(0, |info| info.family_id()),
+        model: info.as_ref().map_or(0, |info| info.model_id()),
+        stepping: info.as_ref().map_or(0, |info| info.stepping_id()),
+        features,
+    }
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.brand.is_empty() {
+            true => f.write_str(&self.vendor)?,
+            false => f.write_str(&self.brand)?,
+        }
+        write!(
+            f,
+            " (family {:#x} model {:#x} stepping {})",
+            self.family, self.model, self.stepping
+        )
+    }
+}
+
+/// A stretch of physical memory of one kind, adjacent ones of the same kind merged
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+/// The boot memory map, summed up
+pub struct Memory {
+    /// Bytes of RAM, whatever holds them
+    pub total: u64,
+    /// Bytes of RAM nothing had taken when the kernel started
+    pub usable: u64,
+    pub regions: Vec<Region>,
+}
+
+/// What the memory map says; `None` before the memory phase read it
+pub fn memory() -> Option<Memory> {
+    let map = memory::memory_map()?;
+    let mut regions: Vec<Region> = Vec::new();
+    for region in map.iter() {
+        match regions.last_mut() {
+            Some(last) if last.kind == region.kind && last.end == region.start => {
+                last.end = region.end
+            }
+            _ => regions.push(Region {
+                start: region.start,
+                end: region.end,
+                kind: region.kind,
+            }),
+        }
+    }
+    let size_of = |filter: fn(MemoryKind) -> bool| -> u64 {
+        regions
+            .iter()
+            .filter(|region| filter(region.kind))
+            .map(|region| region.end - region.start)
+            .sum()
+    };
+    Some(Memory {
+        total: size_of(MemoryKind::is_ram),
+        usable: size_of(|kind| kind == MemoryKind::Usable),
+        regions,
+    })
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} MiB of RAM, {} MiB usable",
+            self.total >> 20,
+            self.usable >> 20
+        )
+    }
+}
+
+/// The memory map as a table, a region per line
+pub struct RegionTable<'a>(pub &'a [Region]);
+
+impl fmt::Display for RegionTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>18} {:>18} {:>10}  KIND", "START", "END", "KIB")?;
+        for region in self.0 {
+            write!(
+                f,
+                "\n{:#018x} {:#018x} {:>10}  {}",
+                region.start,
+                region.end,
+                (region.end - region.start) / 1024,
+                region.kind.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The kernel's optional parts, and which are in use, for the banner
+fn features() -> Vec<(&'static str, bool)> {
+    alloc::vec![
+        ("acpi", acpi::fadt().is_some()),
+        ("apic", apic::local_apic().is_some()),
+        ("numa", numa::node_count() > 1),
+        ("signing-key", signing::HAS_KEY),
+        ("safe-mode", boot::safe_mode()),
+    ]
+}
+
+/// Prints the summary; `regions` adds the memory map
+pub fn print(regions: bool) {
+    println!("cpu:      {}", cpu());
+    println!(
+        "          {} of {} CPUs online, clock source {}",
+        percpu::online_count(),
+        percpu::count(),
+        clock::source().name()
+    );
+    match paravirt::get().hypervisor {
+        Some(hypervisor) => println!("machine:  virtual, {}", hypervisor),
+        None => println!("machine:  bare metal"),
+    }
+    let memory = memory();
+    match &memory {
+        Some(memory) => println!("memory:   {}", memory),
+        None => println!("memory:   no memory map"),
+    }
+    let mut line = String::from("kernel:  ");
+    for (name, enabled) in features() {
+        line.push(' ');
+        line.push(if enabled { '+' } else { '-' });
+        line.push_str(name);
+    }
+    println!("{}", line);
+    if let (true, Some(memory)) = (regions, memory) {
+        println!("{}", RegionTable(&memory.regions));
+    }
+}
+
+/// The summary, boxed in, once the boot phases are done
+pub fn print_banner() {
+    println!("{:=<78}", "== rust_os ");
+    print(false);
+    println!("{:=<78}", "");
+}
+
+/// `sysinfo [memory]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => print(false),
+        ["memory"] => print(true),
+        _ => return Err("usage: sysinfo [memory]"),
+    }
+    Ok(())
+}
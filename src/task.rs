@@ -0,0 +1,93 @@
+//! Cooperative kernel tasks: futures polled by `executor::Executor`, or by any CPU for those
+//! spawned with `shared::spawn`.
+
+use crate::allocator::quota::{self, HeapAccount};
+use crate::cputime;
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+pub mod executor;
+pub mod shared;
+pub mod stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    /// The function the future came from, for `cputime`
+    name: &'static str,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    /// What the task's allocations are charged to, `None` for the kernel's account
+    account: Option<HeapAccount>,
+}
+
+impl Task {
+    /// A task charging its allocations to the heap account current where it's created
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_account(quota::current(), future)
+    }
+
+    /// A task charging its allocations, the future itself included, to `account`
+    pub fn with_account(
+        account: Option<HeapAccount>,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Task {
+        let name = cputime::task_name(core::any::type_name_of_val(&future));
+        let future: Pin<Box<dyn Future<Output = ()>>> = match &account {
+            Some(account) => quota::with_account(account, || Box::pin(future)),
+            None => Box::pin(future),
+        };
+        Task {
+            id: TaskId::new(),
+            name,
+            future,
+            account,
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        match &self.account {
+            Some(account) => quota::with_account(account, || self.future.as_mut().poll(context)),
+            None => self.future.as_mut().poll(context),
+        }
+    }
+}
+
+/// Lets the executor poll the other ready tasks before this one continues
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now`
+#[must_use = "futures do nothing unless polled"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
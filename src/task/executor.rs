@@ -0,0 +1,129 @@
+use super::{shared, Task, TaskId};
+use crate::clock::tsc;
+use crate::sync::mpmc;
+use crate::{cputime, event, rcu, timer};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Poll, Waker};
+
+/// How many wake-ups can be pending at once; wakers never allocate, so the queue is fixed-size
+const TASK_QUEUE_SIZE: usize = 128;
+
+/// Task IDs whose wakers have fired; pushed from interrupt handlers, so it must not lock
+type TaskQueue = mpmc::Queue<TaskId, TASK_QUEUE_SIZE>;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<TaskQueue>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue::new()),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("queue full");
+    }
+
+    /// Polls tasks forever, halting the CPU whenever none of them can make progress
+    pub fn run(&mut self) -> ! {
+        loop {
+            timer::wake_expired();
+            event::dispatch();
+            // no task is running, so this CPU holds no RCU references
+            rcu::quiescent_state();
+            rcu::reclaim();
+            self.run_ready_tasks();
+            shared::run_ready();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // destructure `self` to avoid borrow checker errors
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            let start = tsc::read();
+            let poll = task.poll(&mut context);
+            cputime::charge_task(task_id, task.name, tsc::read().saturating_sub(start));
+            match poll {
+                Poll::Ready(()) => {
+                    // task done -> remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                    cputime::task_ended(task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts;
+
+        // an interrupt between the check and `hlt` could queue a wake-up we'd then sleep through
+        interrupts::disable();
+        // until the next timer deadline at the latest
+        shared::idle_unless(|| {
+            !self.task_queue.is_empty() || timer::has_expired() || event::pending()
+        });
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<TaskQueue>,
+}
+
+impl TaskWaker {
+    fn waker(task_id: TaskId, task_queue: Arc<TaskQueue>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.task_id).expect("task_queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,140 @@
+//! Tasks any CPU may poll, which is how kernel work gets onto the APs.
+//!
+//! `spawn` puts a `Send` future here rather than on one executor. The boot CPU's executor polls
+//! the ready ones between its own tasks, and every AP does nothing else while it's online (see
+//! `smp`), so a task runs on whichever CPU gets to it first. It never runs on two at once: a task
+//! being polled is out of `TASKS`, and a wake-up that comes meanwhile is remembered and queued
+//! again once the poll is over. A wake-up also interrupts a CPU that's asleep in `idle_unless`, so
+//! the task doesn't wait for that CPU's next timer interrupt.
+
+use super::{Task, TaskId};
+use crate::clock::tsc;
+use crate::interrupts::tickless;
+use crate::percpu;
+use crate::sync::mpmc;
+use crate::{apic, cputime};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How many wake-ups can be pending at once, as for an executor's own tasks
+const QUEUE_SIZE: usize = 128;
+
+/// A task made from a `Send` future
+struct SendTask(Task);
+
+// `spawn` only makes these from `Send` futures, and the rest of a `Task` is `Send` anyway
+unsafe impl Send for SendTask {}
+
+enum Slot {
+    /// Waiting for a CPU to poll it, or for a wake-up
+    Idle(SendTask),
+    /// Being polled; `woken` once a wake-up came meanwhile
+    Polled { woken: bool },
+}
+
+struct Entry {
+    slot: Slot,
+    waker: Waker,
+}
+
+static TASKS: Mutex<BTreeMap<TaskId, Entry>> = Mutex::new(BTreeMap::new());
+/// Ids of the tasks whose wakers have fired; pushed from interrupt handlers
+static QUEUE: mpmc::Queue<TaskId, QUEUE_SIZE> = mpmc::Queue::new();
+/// CPUs halted in `idle_unless`, a bit per CPU index
+static SLEEPING: AtomicU64 = AtomicU64::new(0);
+
+/// Runs `future` as a task on whichever CPU is free
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let task = Task::new(future);
+    let id = task.id;
+    let entry = Entry {
+        slot: Slot::Idle(SendTask(task)),
+        waker: Waker::from(Arc::new(SharedWaker(id))),
+    };
+    TASKS.lock().insert(id, entry);
+    wake(id);
+}
+
+/// Queues task `id` to be polled, and interrupts a sleeping CPU to do it
+fn wake(id: TaskId) {
+    QUEUE.push(id).expect("shared task queue full");
+    // pairs with the `fetch_or` in `idle_unless`: either that CPU sees the task or we see it asleep
+    fence(Ordering::SeqCst);
+    let sleeping = SLEEPING.load(Ordering::SeqCst);
+    if sleeping == 0 {
+        return;
+    }
+    let cpu = percpu::get(sleeping.trailing_zeros() as usize);
+    if let (Some(local_apic), Some(cpu)) = (apic::local_apic(), cpu) {
+        local_apic.send_fixed(cpu.apic_id, apic::WAKEUP_VECTOR);
+    }
+}
+
+/// Polls the shared tasks that are ready until none is
+pub fn run_ready() {
+    while let Some(id) = QUEUE.pop() {
+        let (SendTask(mut task), waker) = {
+            let mut tasks = TASKS.lock();
+            let Some(entry) = tasks.get_mut(&id) else {
+                continue; // the task is done
+            };
+            match core::mem::replace(&mut entry.slot, Slot::Polled { woken: false }) {
+                Slot::Idle(task) => (task, entry.waker.clone()),
+                Slot::Polled { .. } => {
+                    entry.slot = Slot::Polled { woken: true };
+                    continue;
+                }
+            }
+        };
+        let start = tsc::read();
+        let poll = task.poll(&mut Context::from_waker(&waker));
+        cputime::charge_task(id, task.name, tsc::read().saturating_sub(start));
+
+        let mut tasks = TASKS.lock();
+        match poll {
+            Poll::Ready(()) => {
+                tasks.remove(&id);
+                cputime::task_ended(id);
+            }
+            Poll::Pending => {
+                let entry = tasks.get_mut(&id).expect("a polled task is in TASKS");
+                let slot = core::mem::replace(&mut entry.slot, Slot::Idle(SendTask(task)));
+                if let Slot::Polled { woken: true } = slot {
+                    drop(tasks);
+                    wake(id);
+                }
+            }
+        }
+    }
+}
+
+/// Halts this CPU until the next interrupt (see `tickless::idle`) unless a shared task is ready
+/// or `busy` says there's other work. Called with interrupts disabled; returns with them enabled.
+pub fn idle_unless(busy: impl FnOnce() -> bool) {
+    let cpu = 1 << percpu::current().index;
+    SLEEPING.fetch_or(cpu, Ordering::SeqCst);
+    if QUEUE.is_empty() && !busy() {
+        tickless::idle();
+    } else {
+        interrupts::enable();
+    }
+    SLEEPING.fetch_and(!cpu, Ordering::SeqCst);
+}
+
+struct SharedWaker(TaskId);
+
+impl Wake for SharedWaker {
+    fn wake(self: Arc<Self>) {
+        wake(self.0);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        wake(self.0);
+    }
+}
@@ -0,0 +1,53 @@
+//! Values an interrupt handler produces and an async task consumes, e.g. input device events.
+//!
+//! A `sync::mpsc::Channel` that counts what it had to drop: the handler can't wait for the
+//! consumer to catch up, and sending takes no lock, so it never waits for anything else either.
+
+use crate::sync::mpsc::{Channel, Recv};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A stream of at most `N` undelivered values, for one consumer at a time; `N` must be a power
+/// of two
+pub struct InterruptStream<T, const N: usize> {
+    channel: Channel<T, N>,
+    /// Values lost because the queue was full
+    dropped: AtomicU64,
+}
+
+impl<T, const N: usize> Default for InterruptStream<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> InterruptStream<T, N> {
+    pub const fn new() -> Self {
+        InterruptStream {
+            channel: Channel::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Appends `value` and wakes the consumer; safe in interrupt handlers. The value is dropped
+    /// if the consumer has fallen `N` values behind.
+    pub fn push(&self, value: T) {
+        if self.channel.send(value).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The oldest value, if one is waiting
+    pub fn try_next(&self) -> Option<T> {
+        self.channel.try_recv()
+    }
+
+    /// Waits for the next value
+    pub fn next(&self) -> Recv<'_, T, N> {
+        self.channel.recv()
+    }
+
+    /// Number of values dropped because nobody consumed them in time
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
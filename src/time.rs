@@ -0,0 +1,145 @@
+//! Wall-clock time.
+//!
+//! The RTC only has one-second resolution and is slow to read, so it's read once at boot and the
+//! monotonic clock is added on top of it.
+//!
+//! The RTC, and so `now`, runs on UTC; what's shown to people is in the local time zone, a fixed
+//! offset from UTC given with `tz=<offset>` on the command line or with `date tz <offset>`, and
+//! UTC otherwise. There's no time zone database, so the offset doesn't follow daylight saving
+//! time by itself. `Local` formats a time with it.
+
+use crate::clock::Instant;
+use crate::rtc::DateTime;
+use crate::{cmdline, println, rtc, warnln};
+use core::fmt;
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+/// Unix time of the clock's zero point
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Minutes the local time zone is ahead of UTC
+static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+
+/// The furthest any time zone is from UTC, 14 hours, in minutes
+const MAX_OFFSET: i32 = 14 * 60;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Reads the RTC and the time zone. Must run after ACPI is reachable (for the century register)
+/// and the clock source is chosen, and before `now` is expected to return anything meaningful.
+pub fn init() {
+    let boot_time = rtc::read().to_unix_seconds();
+    let elapsed = Instant::now().as_nanos() / NANOSECONDS_PER_SECOND;
+    BOOT_TIME.store(boot_time.saturating_sub(elapsed), Ordering::Relaxed);
+    if let Some(tz) = cmdline::get("tz") {
+        if let Err(err) = parse_offset(tz).and_then(set_utc_offset) {
+            warnln!("time: ignoring tz={}: {}", tz, err);
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + Instant::now().as_nanos() / NANOSECONDS_PER_SECOND
+}
+
+/// Unix time of the boot, or near enough: when the monotonic clock started
+pub fn boot_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed)
+}
+
+/// Minutes the local time zone is ahead of UTC, negative west of Greenwich
+pub fn utc_offset() -> i32 {
+    UTC_OFFSET.load(Ordering::Relaxed)
+}
+
+pub fn set_utc_offset(minutes: i32) -> Result<(), &'static str> {
+    if minutes.abs() > MAX_OFFSET {
+        return Err("offset beyond 14 hours");
+    }
+    UTC_OFFSET.store(minutes, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Parses an offset from UTC in minutes: `UTC`, or a sign and hours with optional minutes,
+/// as in `+2`, `-05:00` or `+0530`
+pub fn parse_offset(text: &str) -> Result<i32, &'static str> {
+    const INVALID: &str = "offsets look like +2, -05:00 or +0530";
+    if text.eq_ignore_ascii_case("utc") || text == "Z" {
+        return Ok(0);
+    }
+    let (sign, rest) = match text.as_bytes().first() {
+        Some(b'+') => (1, &text[1..]),
+        Some(b'-') => (-1, &text[1..]),
+        _ => return Err(INVALID),
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some(split) => split,
+        None if rest.len() > 2 => rest.split_at(rest.len() - 2),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().map_err(|_| INVALID)?;
+    let minutes: i32 = minutes.parse().map_err(|_| INVALID)?;
+    if hours < 0 || !(0..60).contains(&minutes) {
+        return Err(INVALID);
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// An offset from UTC in minutes, as `+hh:mm`
+pub struct Offset(pub i32);
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let minutes = self.0.unsigned_abs();
+        write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+/// A Unix time shown in the local time zone, `YYYY-MM-DD hh:mm:ss`; `{:#}` adds the offset
+pub struct Local(pub u64);
+
+impl Local {
+    pub fn now() -> Local {
+        Local(now())
+    }
+
+    /// The calendar date and time it is at `self` in the local time zone
+    pub fn date_time(&self) -> DateTime {
+        let seconds = self.0 as i64 + utc_offset() as i64 * 60;
+        DateTime::from_unix_seconds(seconds.max(0) as u64)
+    }
+}
+
+impl fmt::Display for Local {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self.date_time();
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            time.year, time.month, time.day, time.hour, time.minute, time.second
+        )?;
+        if f.alternate() {
+            write!(f, " {}", Offset(utc_offset()))?;
+        }
+        Ok(())
+    }
+}
+
+/// `date [tz <offset>]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {}
+        ["tz", offset] => set_utc_offset(parse_offset(offset)?)?,
+        _ => return Err("usage: date [tz <offset>]"),
+    }
+    let now = now();
+    let utc = DateTime::from_unix_seconds(now);
+    println!("{:#}", Local(now));
+    println!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        utc.year, utc.month, utc.day, utc.hour, utc.minute, utc.second
+    );
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Timed wake-ups for async tasks: `sleep` and `Timeout`.
+//!
+//! Pending deadlines sit in a binary heap keyed by timer tick. Wakers are never called from the
+//! tick interrupt itself; the interrupt only ends the executor's `hlt`, and the executor then runs
+//! `wake_expired` from task context. That keeps waker code (and the drop of its last reference,
+//! which may free memory) out of interrupt handlers.
+
+use crate::interrupts::{self, latency, TIMER_HZ};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+
+struct Timers {
+    /// `(deadline, id)`, earliest first; entries of cancelled sleeps are dropped lazily
+    deadlines: BinaryHeap<Reverse<(u64, u64)>>,
+    wakers: BTreeMap<u64, Waker>,
+    next_id: u64,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    deadlines: BinaryHeap::new(),
+    wakers: BTreeMap::new(),
+    next_id: 0,
+});
+
+/// Earliest pending deadline, `u64::MAX` if there is none; lets `has_expired` skip the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Number of timer ticks covering `duration`, rounded up so a sleep never ends early
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * TIMER_HZ as u128).div_ceil(1_000_000_000);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Whether some sleep's deadline has passed and `wake_expired` has work to do
+pub fn has_expired() -> bool {
+    interrupts::ticks() >= NEXT_DEADLINE.load(Ordering::Acquire)
+}
+
+/// The earliest tick some sleep is waiting for, if any
+pub fn next_deadline() -> Option<u64> {
+    match NEXT_DEADLINE.load(Ordering::Acquire) {
+        u64::MAX => None,
+        deadline => Some(deadline),
+    }
+}
+
+/// Wakes every task whose deadline has passed. Called by the executor, not from interrupts.
+pub fn wake_expired() {
+    if !has_expired() {
+        return;
+    }
+    let now = interrupts::ticks();
+    let mut expired = Vec::new();
+    {
+        let mut timers = TIMERS.lock();
+        while let Some(&Reverse((deadline, id))) = timers.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            timers.deadlines.pop();
+            if let Some(waker) = timers.wakers.remove(&id) {
+                expired.push((deadline, waker));
+            }
+        }
+        let next = timers
+            .deadlines
+            .peek()
+            .map_or(u64::MAX, |Reverse((deadline, _))| *deadline);
+        NEXT_DEADLINE.store(next, Ordering::Release);
+    }
+    // wake outside the lock, a waker is free to poll or sleep again right away
+    for (deadline, waker) in expired {
+        latency::record_wakeup(deadline);
+        waker.wake();
+    }
+}
+
+/// Completes once `duration` has passed, rounded up to whole timer ticks
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: interrupts::ticks().saturating_add(duration_to_ticks(duration)),
+        id: None,
+    }
+}
+
+/// Future returned by `sleep`
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    deadline: u64,
+    /// Registration in `TIMERS`, made on the first pending poll
+    id: Option<u64>,
+}
+
+impl Sleep {
+    fn cancel(&mut self) {
+        if let Some(id) = self.id.take() {
+            TIMERS.lock().wakers.remove(&id);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if interrupts::ticks() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        let mut timers = TIMERS.lock();
+        match self.id {
+            Some(id) => {
+                if let Some(waker) = timers.wakers.get_mut(&id) {
+                    if !waker.will_wake(context.waker()) {
+                        *waker = context.waker().clone();
+                    }
+                }
+            }
+            None => {
+                let id = timers.next_id;
+                timers.next_id += 1;
+                timers.deadlines.push(Reverse((self.deadline, id)));
+                timers.wakers.insert(id, context.waker().clone());
+                NEXT_DEADLINE.fetch_min(self.deadline, Ordering::AcqRel);
+                self.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Error returned by `Timeout` when the deadline passed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+/// Runs a future with a deadline, resolving to `Err(Elapsed)` if it doesn't finish in time
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Timeout<F> {
+    pub fn wrap(future: F, duration: Duration) -> Self {
+        Timeout {
+            future: Box::pin(future),
+            sleep: sleep(duration),
+        }
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! Event tracing: subsystems record typed events into per-CPU ring buffers, cheaply enough to
+//! leave in the interrupt, scheduling and allocation paths where printing would change the very
+//! timing being looked at.
+//!
+//! Each CPU only writes its own ring, claiming a slot with one atomic add, so recording takes no
+//! lock and nests safely in the interrupts that arrive meanwhile. A slot's sequence number is
+//! written last: readers on other CPUs check it before and after copying the slot and skip slots
+//! that were being rewritten. The rings are static, as allocations are traced themselves, and old
+//! events are overwritten once a ring is full.
+//!
+//! Recording is off until a category is switched on with `trace on`; `stack` adds the callers of
+//! where each event was recorded, found with `unwind`. `trace dump` prints the
+//! newest events of all CPUs in time order; `trace stream on` sends them to COM1 as they come,
+//! from the `streamer` task. `sample` is the profiler's, see `profile`.
+
+use crate::clock::tsc;
+use crate::console::{Backend, Terminal};
+use crate::drivers::serial::{self, SerialPort};
+use crate::percpu::{self, MAX_CPUS};
+use crate::symbols::Symbolized;
+use crate::unwind::Frames;
+use crate::{println, timer};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Events kept per CPU; a power of two
+const RING_SIZE: usize = 512;
+/// Frames recorded after an event with `stack` on
+const STACK_DEPTH: usize = 6;
+/// Events `trace dump` shows when not told
+const DEFAULT_DUMP: usize = 50;
+/// How often the streamer looks for new events
+const STREAM_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What an event is about; each can be switched on and off on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Irq,
+    Sched,
+    Alloc,
+    /// The callers of where each event of the other categories was recorded
+    Stack,
+    /// Where timer interrupts found the CPUs, see `profile`
+    Sample,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [
+        Category::Irq,
+        Category::Sched,
+        Category::Alloc,
+        Category::Stack,
+        Category::Sample,
+    ];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Irq => "irq",
+            Category::Sched => "sched",
+            Category::Alloc => "alloc",
+            Category::Stack => "stack",
+            Category::Sample => "sample",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    IrqEntry {
+        vector: u8,
+    },
+    IrqExit {
+        vector: u8,
+    },
+    /// A thread's time slice starts on this CPU
+    SwitchIn {
+        pid: u64,
+        thread: u64,
+    },
+    /// The time slice ended
+    SwitchOut {
+        pid: u64,
+        thread: u64,
+    },
+    Alloc {
+        address: u64,
+        size: u64,
+    },
+    Free {
+        address: u64,
+        size: u64,
+    },
+    /// A return address on the stack of the event before, `depth` frames out
+    Frame {
+        depth: u64,
+        address: u64,
+    },
+    /// A timer interrupt found the CPU at `rip`, running process `pid`, 0 for the kernel's own
+    /// tasks; the interrupted code's callers follow as `Frame`s
+    Sample {
+        rip: u64,
+        pid: u64,
+    },
+}
+
+impl Event {
+    fn category(&self) -> Category {
+        match self {
+            Event::IrqEntry { .. } | Event::IrqExit { .. } => Category::Irq,
+            Event::SwitchIn { .. } | Event::SwitchOut { .. } => Category::Sched,
+            Event::Alloc { .. } | Event::Free { .. } => Category::Alloc,
+            Event::Frame { .. } => Category::Stack,
+            Event::Sample { .. } => Category::Sample,
+        }
+    }
+
+    /// The event as the three words a slot holds: its kind and two arguments
+    fn encode(&self) -> [u64; 3] {
+        match *self {
+            Event::IrqEntry { vector } => [0, vector as u64, 0],
+            Event::IrqExit { vector } => [1, vector as u64, 0],
+            Event::SwitchIn { pid, thread } => [2, pid, thread],
+            Event::SwitchOut { pid, thread } => [3, pid, thread],
+            Event::Alloc { address, size } => [4, address, size],
+            Event::Free { address, size } => [5, address, size],
+            Event::Frame { depth, address } => [6, depth, address],
+            Event::Sample { rip, pid } => [7, rip, pid],
+        }
+    }
+
+    fn decode([kind, a, b]: [u64; 3]) -> Option<Event> {
+        Some(match kind {
+            0 => Event::IrqEntry { vector: a as u8 },
+            1 => Event::IrqExit { vector: a as u8 },
+            2 => Event::SwitchIn { pid: a, thread: b },
+            3 => Event::SwitchOut { pid: a, thread: b },
+            4 => Event::Alloc {
+                address: a,
+                size: b,
+            },
+            5 => Event::Free {
+                address: a,
+                size: b,
+            },
+            6 => Event::Frame {
+                depth: a,
+                address: b,
+            },
+            7 => Event::Sample { rip: a, pid: b },
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::IrqEntry { vector } => write!(f, "irq entry  vector {}", vector),
+            Event::IrqExit { vector } => write!(f, "irq exit   vector {}", vector),
+            Event::SwitchIn { pid, thread } => {
+                write!(f, "switch in  pid {} thread {}", pid, thread)
+            }
+            Event::SwitchOut { pid, thread } => {
+                write!(f, "switch out pid {} thread {}", pid, thread)
+            }
+            Event::Alloc { address, size } => write!(f, "alloc      {:#x} {} bytes", address, size),
+            Event::Free { address, size } => write!(f, "free       {:#x} {} bytes", address, size),
+            Event::Frame { depth, address } => {
+                write!(f, "  #{:<2}     {}", depth, Symbolized(*address))
+            }
+            Event::Sample { rip, pid } => write!(f, "sample     pid {} {}", pid, Symbolized(*rip)),
+        }
+    }
+}
+
+/// An event as read back from a ring
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    pub cpu: usize,
+    /// The TSC when it was recorded
+    pub tsc: u64,
+    pub event: Event,
+}
+
+struct Slot {
+    /// Index of the event in the slot plus one, 0 while it's being written
+    sequence: AtomicU64,
+    tsc: AtomicU64,
+    words: [AtomicU64; 3],
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            sequence: AtomicU64::new(0),
+            tsc: AtomicU64::new(0),
+            words: [const { AtomicU64::new(0) }; 3],
+        }
+    }
+}
+
+struct Ring {
+    /// Events ever recorded; the next one goes in slot `head % RING_SIZE`
+    head: AtomicU64,
+    slots: [Slot; RING_SIZE],
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            head: AtomicU64::new(0),
+            slots: [const { Slot::new() }; RING_SIZE],
+        }
+    }
+
+    fn push(&self, event: &Event) {
+        let index = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[index as usize % RING_SIZE];
+        slot.sequence.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.tsc.store(tsc::read(), Ordering::Relaxed);
+        for (word, value) in slot.words.iter().zip(event.encode()) {
+            word.store(value, Ordering::Relaxed);
+        }
+        slot.sequence.store(index + 1, Ordering::Release);
+    }
+
+    /// Event number `index`, if it's still there and wasn't being rewritten
+    fn get(&self, cpu: usize, index: u64) -> Option<Record> {
+        let slot = &self.slots[index as usize % RING_SIZE];
+        if slot.sequence.load(Ordering::Acquire) != index + 1 {
+            return None;
+        }
+        let tsc = slot.tsc.load(Ordering::Relaxed);
+        let words = [0, 1, 2].map(|word| slot.words[word].load(Ordering::Relaxed));
+        fence(Ordering::Acquire);
+        if slot.sequence.load(Ordering::Relaxed) != index + 1 {
+            return None;
+        }
+        Some(Record {
+            cpu,
+            tsc,
+            event: Event::decode(words)?,
+        })
+    }
+
+    /// Indices of the events still in the ring
+    fn available(&self) -> core::ops::Range<u64> {
+        let head = self.head.load(Ordering::Acquire);
+        head.saturating_sub(RING_SIZE as u64)..head
+    }
+}
+
+static RINGS: [Ring; MAX_CPUS] = [const { Ring::new() }; MAX_CPUS];
+
+/// Categories being recorded, by `Category::bit`
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+/// Whether the streamer sends events to COM1
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled(category: Category) -> bool {
+    ENABLED.load(Ordering::Relaxed) & category.bit() != 0
+}
+
+pub fn set_enabled(category: Category, enabled: bool) {
+    match enabled {
+        true => ENABLED.fetch_or(category.bit(), Ordering::Relaxed),
+        false => ENABLED.fetch_and(!category.bit(), Ordering::Relaxed),
+    };
+}
+
+/// Records `event` on the calling CPU if its category is switched on
+#[inline]
+pub fn record(event: Event) {
+    if !is_enabled(event.category()) {
+        return;
+    }
+    // allocations start before the CPU has its per-CPU data
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+    RINGS[cpu.index].push(&event);
+    if is_enabled(Category::Stack) && event.category() != Category::Stack {
+        record_stack(&RINGS[cpu.index]);
+    }
+}
+
+/// Records a `Sample` and up to `depth` of `callers` after it, if sampling is switched on
+pub fn record_sample(rip: u64, pid: u64, callers: impl Iterator<Item = u64>, depth: usize) {
+    if !is_enabled(Category::Sample) {
+        return;
+    }
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+    let ring = &RINGS[cpu.index];
+    ring.push(&Event::Sample { rip, pid });
+    for (depth, address) in callers.take(depth).enumerate() {
+        ring.push(&Event::Frame {
+            depth: depth as u64,
+            address,
+        });
+    }
+}
+
+/// Records the callers of where an event was recorded, after it
+#[inline(never)]
+fn record_stack(ring: &Ring) {
+    for (depth, address) in Frames::here().take(STACK_DEPTH).enumerate() {
+        ring.push(&Event::Frame {
+            depth: depth as u64,
+            address,
+        });
+    }
+}
+
+/// Empties every ring
+pub fn clear() {
+    for ring in &RINGS {
+        let head = ring.head.load(Ordering::Relaxed);
+        for slot in &ring.slots {
+            slot.sequence.store(0, Ordering::Relaxed);
+        }
+        // old indices never come back, so nothing cleared reappears
+        ring.head.store(head + RING_SIZE as u64, Ordering::Release);
+    }
+}
+
+/// Every event still in the rings, oldest first
+pub fn snapshot() -> Vec<Record> {
+    let mut records: Vec<Record> = (0..percpu::count())
+        .flat_map(|cpu| {
+            let ring = &RINGS[cpu];
+            ring.available()
+                .filter_map(move |index| ring.get(cpu, index))
+        })
+        .collect();
+    records.sort_by_key(|record| record.tsc);
+    records
+}
+
+/// What a `Follower` read
+pub enum Followed {
+    Record(Record),
+    /// The ring of `cpu` overwrote `count` events before they were read
+    Lost {
+        cpu: usize,
+        count: u64,
+    },
+}
+
+/// Reads the events recorded since it last read, for readers that keep up as they come
+pub struct Follower {
+    /// Index of the next event to read, by CPU
+    next: [u64; MAX_CPUS],
+}
+
+/// A follower that reads what's still in the rings first
+impl Default for Follower {
+    fn default() -> Follower {
+        Follower {
+            next: [0; MAX_CPUS],
+        }
+    }
+}
+
+impl Follower {
+    /// Skips what's been recorded so far
+    pub fn skip(&mut self) {
+        for (cpu, next) in self.next.iter_mut().enumerate() {
+            *next = RINGS[cpu].head.load(Ordering::Acquire);
+        }
+    }
+
+    /// Calls `f` with each event recorded since the last call, CPU by CPU, each in order
+    pub fn read(&mut self, mut f: impl FnMut(Followed)) {
+        for (cpu, next) in self.next.iter_mut().enumerate().take(percpu::count()) {
+            let available = RINGS[cpu].available();
+            if *next < available.start {
+                f(Followed::Lost {
+                    cpu,
+                    count: available.start - *next,
+                });
+                *next = available.start;
+            }
+            for index in *next..available.end {
+                if let Some(record) = RINGS[cpu].get(cpu, index) {
+                    f(Followed::Record(record));
+                }
+            }
+            *next = available.end;
+        }
+    }
+}
+
+/// TSC ticks as nanoseconds
+pub fn tsc_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000 / tsc::ticks_per_ms().max(1) as u128) as u64
+}
+
+/// Writes to COM1, formatting on the stack
+pub struct SerialWriter(pub Terminal<SerialPort>);
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.0.write(text);
+        Ok(())
+    }
+}
+
+/// Sends new events to COM1 while streaming is on; meant to be spawned as a task. Returns at
+/// once if there's no serial port.
+pub async fn streamer() {
+    let Some(port) = serial::console_port() else {
+        return;
+    };
+    let mut out = SerialWriter(Terminal(port));
+    let mut follower = Follower::default();
+    loop {
+        timer::sleep(STREAM_INTERVAL).await;
+        if !STREAMING.load(Ordering::Relaxed) {
+            follower.skip();
+            continue;
+        }
+        follower.read(|followed| {
+            let _ = match followed {
+                Followed::Record(record) => writeln!(
+                    out,
+                    "{:>14} cpu {:<2} {}",
+                    tsc_to_ns(record.tsc),
+                    record.cpu,
+                    record.event
+                ),
+                Followed::Lost { cpu, count } => {
+                    writeln!(out, "trace: cpu {} lost {} events", cpu, count)
+                }
+            };
+        });
+    }
+}
+
+fn parse_categories(names: &[&str]) -> Result<Vec<Category>, &'static str> {
+    if names.is_empty() {
+        // stacks multiply the events and samples are the profiler's, so only when asked for
+        return Ok(Category::ALL[..3].to_vec());
+    }
+    names
+        .iter()
+        .map(|name| {
+            Category::ALL
+                .into_iter()
+                .find(|category| category.name() == *name)
+                .ok_or("categories are irq, sched, alloc, stack and sample")
+        })
+        .collect()
+}
+
+fn dump(count: usize) {
+    let records = snapshot();
+    let shown = &records[records.len().saturating_sub(count)..];
+    let Some(first) = shown.first() else {
+        println!("trace: no events");
+        return;
+    };
+    println!("{:>12}  CPU  EVENT", "NS");
+    for record in shown {
+        println!(
+            "{:>12}  {:<3}  {}",
+            tsc_to_ns(record.tsc - first.tsc),
+            record.cpu,
+            record.event
+        );
+    }
+}
+
+/// `trace [on|off [irq|sched|alloc|stack|sample]... | dump [count] | stream on|off | clear]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            for category in Category::ALL {
+                let state = match is_enabled(category) {
+                    true => "on",
+                    false => "off",
+                };
+                println!("{:<6} {}", category.name(), state);
+            }
+            println!(
+                "{} events in the buffers, {} kept per CPU",
+                snapshot().len(),
+                RING_SIZE
+            );
+        }
+        ["on", names @ ..] | ["off", names @ ..] => {
+            for category in parse_categories(names)? {
+                set_enabled(category, args[0] == "on");
+            }
+        }
+        ["dump"] => dump(DEFAULT_DUMP),
+        ["dump", count] => dump(count.parse().map_err(|_| "not a number")?),
+        ["stream", "on"] => {
+            if serial::console_port().is_none() {
+                return Err("no serial port to stream to");
+            }
+            STREAMING.store(true, Ordering::Relaxed);
+        }
+        ["stream", "off"] => STREAMING.store(false, Ordering::Relaxed),
+        ["clear"] => clear(),
+        _ => {
+            return Err(
+                "usage: trace [on|off [irq|sched|alloc|stack|sample]... | dump [count] | stream on|off | clear]",
+            )
+        }
+    }
+    Ok(())
+}
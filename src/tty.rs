@@ -0,0 +1,373 @@
+//! Terminals: the line discipline between the devices that type and the programs that read.
+//!
+//! Input drivers put what's typed on `console::INPUT`, whatever the device; `input`, a task,
+//! hands each byte to the active `Tty`, which `switch` changes at any time. There's one display,
+//! so every terminal writes to the console; what sets them apart is their input, each with its
+//! own settings, half-typed line and foreground process.
+//!
+//! In cooked mode a terminal collects a line before a reader gets any of it: backspace takes back
+//! the last character, Ctrl+U the whole line, Enter finishes it and Ctrl+D finishes it without a
+//! newline, or reads as end of file on an empty line. Ctrl+C drops the line and sends SIGINT to
+//! the foreground process. In raw mode every byte goes to the reader as it comes, control characters
+//! included. Either way what's typed is echoed unless echo is off.
+//!
+//! Processes read and write their terminal through the console descriptors (see
+//! `process::files`); kernel tasks use `Tty::read`.
+
+use crate::console::unicode;
+use crate::process::signal::Signal;
+use crate::process::{self, Pid};
+use crate::{print, println};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Number of terminals
+pub const COUNT: usize = 4;
+
+/// Most bytes of typed input a terminal holds, the line being typed included; more are dropped
+const INPUT_LIMIT: usize = 4096;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Line by line, with editing and Ctrl+C
+    Cooked,
+    /// Byte by byte, as typed
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub mode: Mode,
+    pub echo: bool,
+}
+
+impl Settings {
+    pub const DEFAULT: Settings = Settings {
+        mode: Mode::Cooked,
+        echo: true,
+    };
+}
+
+pub struct Tty {
+    pub index: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    settings: Settings,
+    /// The line being typed, in cooked mode
+    line: Vec<u8>,
+    /// What readers may take: finished lines in cooked mode, every byte in raw mode
+    ready: VecDeque<u8>,
+    /// Ctrl+D on an empty line, which the next read returns as end of file
+    eof: bool,
+    /// Bytes of a character echoed only once it's whole
+    echo_pending: Vec<u8>,
+    /// The process Ctrl+C sends SIGINT to
+    foreground: Option<Pid>,
+    /// Tasks waiting for something to read
+    readers: Vec<Waker>,
+}
+
+static TTYS: [Tty; COUNT] = [Tty::new(0), Tty::new(1), Tty::new(2), Tty::new(3)];
+
+/// Index of the terminal that gets what's typed
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn get(index: usize) -> Option<&'static Tty> {
+    TTYS.get(index)
+}
+
+/// The terminal that gets what's typed
+pub fn active() -> &'static Tty {
+    &TTYS[ACTIVE.load(Ordering::Relaxed)]
+}
+
+/// Sends what's typed from now on to terminal `index`
+pub fn switch(index: usize) -> Result<(), &'static str> {
+    get(index).ok_or("no such terminal")?;
+    ACTIVE.store(index, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Hands what's typed on any console to the active terminal, forever; spawn it on the executor
+/// once
+pub async fn input() {
+    loop {
+        let byte = crate::console::INPUT.next().await;
+        active().receive(byte);
+    }
+}
+
+impl Tty {
+    const fn new(index: usize) -> Tty {
+        Tty {
+            index,
+            state: Mutex::new(State {
+                settings: Settings::DEFAULT,
+                line: Vec::new(),
+                ready: VecDeque::new(),
+                eof: false,
+                echo_pending: Vec::new(),
+                foreground: None,
+                readers: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.state.lock().settings
+    }
+
+    /// Changes the settings; a line half typed in cooked mode goes to the reader on switching to
+    /// raw mode
+    pub fn set_settings(&self, settings: Settings) {
+        let mut state = self.state.lock();
+        if settings.mode == Mode::Raw {
+            let line = core::mem::take(&mut state.line);
+            state.ready.extend(line);
+            state.wake_readers();
+        }
+        state.settings = settings;
+    }
+
+    pub fn foreground(&self) -> Option<Pid> {
+        self.state.lock().foreground
+    }
+
+    /// Makes `pid` the process Ctrl+C sends SIGINT to
+    pub fn set_foreground(&self, pid: Option<Pid>) {
+        self.state.lock().foreground = pid;
+    }
+
+    /// Drops everything typed that nobody read yet
+    pub fn flush_input(&self) {
+        let mut state = self.state.lock();
+        state.line.clear();
+        state.ready.clear();
+        state.eof = false;
+    }
+
+    /// Shows `bytes` on the console
+    pub fn write(&self, bytes: &[u8]) {
+        print!("{}", String::from_utf8_lossy(bytes));
+    }
+
+    /// Moves what there is to read, up to `buffer.len()` bytes, into `buffer`, and at most one
+    /// line in cooked mode; `Some(0)` is end of file, `None` means nothing was typed yet
+    pub fn try_read(&self, buffer: &mut [u8]) -> Option<usize> {
+        self.poll_read(buffer, None)
+    }
+
+    /// Like `try_read`, but waits for something to be typed
+    pub async fn read(&self, buffer: &mut [u8]) -> usize {
+        poll_fn(|context| match self.poll_read(buffer, Some(context)) {
+            Some(read) => Poll::Ready(read),
+            None => Poll::Pending,
+        })
+        .await
+    }
+
+    /// The next byte typed; in cooked mode `None` is end of file
+    pub async fn read_byte(&self) -> Option<u8> {
+        let mut byte = [0];
+        match self.read(&mut byte).await {
+            0 => None,
+            _ => Some(byte[0]),
+        }
+    }
+
+    fn poll_read(&self, buffer: &mut [u8], context: Option<&mut Context>) -> Option<usize> {
+        let mut state = self.state.lock();
+        if state.ready.is_empty() {
+            if core::mem::take(&mut state.eof) || buffer.is_empty() {
+                return Some(0);
+            }
+            if let Some(context) = context {
+                state.readers.push(context.waker().clone());
+            }
+            return None;
+        }
+        let mut len = buffer.len().min(state.ready.len());
+        if state.settings.mode == Mode::Cooked {
+            if let Some(newline) = state.ready.iter().take(len).position(|&b| b == b'\n') {
+                len = newline + 1;
+            }
+        }
+        for (byte, value) in buffer.iter_mut().zip(state.ready.drain(..len)) {
+            *byte = value;
+        }
+        Some(len)
+    }
+
+    /// Takes one byte typed on the terminal
+    pub fn receive(&self, byte: u8) {
+        let mut echo = String::new();
+        let mut interrupt = None;
+        {
+            let mut state = self.state.lock();
+            let settings = state.settings;
+            if settings.mode == Mode::Raw {
+                if state.ready.len() < INPUT_LIMIT {
+                    state.ready.push_back(byte);
+                    if settings.echo {
+                        state.echo(byte, &mut echo);
+                    }
+                }
+                state.wake_readers();
+            } else {
+                match byte {
+                    CTRL_C => {
+                        state.line.clear();
+                        interrupt = state.foreground;
+                        echo.push_str("^C\n");
+                    }
+                    BACKSPACE | DELETE => {
+                        if let Some(width) = state.erase() {
+                            erase_echo(width, &mut echo);
+                        }
+                    }
+                    CTRL_U => {
+                        while let Some(width) = state.erase() {
+                            erase_echo(width, &mut echo);
+                        }
+                    }
+                    CTRL_D => {
+                        if state.line.is_empty() {
+                            state.eof = true;
+                        }
+                        state.finish_line();
+                    }
+                    b'\r' | b'\n' => {
+                        state.line.push(b'\n');
+                        state.finish_line();
+                        echo.push('\n');
+                    }
+                    byte => {
+                        if state.line.len() + state.ready.len() < INPUT_LIMIT {
+                            state.line.push(byte);
+                            state.echo(byte, &mut echo);
+                        }
+                    }
+                }
+                if !settings.echo {
+                    echo.clear();
+                }
+            }
+        }
+        print!("{}", echo);
+        if let Some(process) = interrupt.and_then(process::get) {
+            process.signal(Signal::Interrupt);
+        }
+    }
+}
+
+impl State {
+    fn wake_readers(&mut self) {
+        self.readers.drain(..).for_each(Waker::wake);
+    }
+
+    /// Hands the line to the readers
+    fn finish_line(&mut self) {
+        let line = core::mem::take(&mut self.line);
+        self.ready.extend(line);
+        self.wake_readers();
+    }
+
+    /// Takes the last character off the line, returning how many columns it took on screen
+    fn erase(&mut self) -> Option<usize> {
+        let start = self.line.iter().rposition(|&byte| byte & 0xc0 != 0x80)?;
+        let erased: Vec<u8> = self.line.drain(start..).collect();
+        let ch = core::str::from_utf8(&erased)
+            .ok()
+            .and_then(|text| text.chars().next());
+        Some(match ch {
+            Some(ch) if ch.is_control() => 2, // echoed as ^X
+            Some(ch) => unicode::width(ch),
+            None => 1,
+        })
+    }
+
+    /// Adds `byte` to `echo` once the character it's part of is whole; control characters
+    /// show as ^X
+    fn echo(&mut self, byte: u8, echo: &mut String) {
+        if byte.is_ascii_control() && byte != b'\t' && byte != b'\n' {
+            echo.push('^');
+            echo.push((byte ^ 0x40) as char);
+            return;
+        }
+        self.echo_pending.push(byte);
+        match core::str::from_utf8(&self.echo_pending) {
+            Ok(text) => echo.push_str(text),
+            Err(err) if err.error_len().is_none() && self.echo_pending.len() < 4 => return,
+            Err(_) => echo.push_str(&String::from_utf8_lossy(&self.echo_pending)),
+        }
+        self.echo_pending.clear();
+    }
+}
+
+/// Rubs out `width` columns before the cursor
+fn erase_echo(width: usize, echo: &mut String) {
+    for _ in 0..width {
+        echo.push_str("\x08 \x08");
+    }
+}
+
+/// `tty [<n> | raw | cooked | echo | noecho]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    let tty = active();
+    let settings = tty.settings();
+    match args {
+        [] => {
+            for tty in &TTYS {
+                let settings = tty.settings();
+                println!(
+                    "{} tty{} {:<6} {:<6} foreground {}",
+                    if tty.index == active().index {
+                        '*'
+                    } else {
+                        ' '
+                    },
+                    tty.index,
+                    match settings.mode {
+                        Mode::Cooked => "cooked",
+                        Mode::Raw => "raw",
+                    },
+                    if settings.echo { "echo" } else { "noecho" },
+                    tty.foreground()
+                        .map_or_else(|| String::from("none"), |pid| alloc::format!("{}", pid))
+                );
+            }
+        }
+        ["raw"] => tty.set_settings(Settings {
+            mode: Mode::Raw,
+            ..settings
+        }),
+        ["cooked"] => tty.set_settings(Settings {
+            mode: Mode::Cooked,
+            ..settings
+        }),
+        ["echo"] => tty.set_settings(Settings {
+            echo: true,
+            ..settings
+        }),
+        ["noecho"] => tty.set_settings(Settings {
+            echo: false,
+            ..settings
+        }),
+        [index] => switch(index.parse().map_err(|_| "not a terminal number")?)?,
+        _ => return Err("usage: tty [<n> | raw | cooked | echo | noecho]"),
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Walking the kernel stack, for backtraces in panics, the crash debugger and traces.
+//!
+//! The kernel is built with frame pointers (`frame-pointer` in `x86_64-rust_os.json`): every
+//! function pushes the caller's RBP on entry and points RBP at the saved copy, so the frames form
+//! a chain up the stack, each link next to the return address into its caller. `Frames` follows
+//! that chain. It needs no unwind tables, no heap and no locks, so it works from a panic, an
+//! exception handler or an allocation alike; what it can't see is a function that doesn't set up
+//! a frame, like the assembly entry points, where the chain ends.
+//!
+//! Every word is read only if the page tables map it, and the chain has to run up the stack, so a
+//! corrupted stack ends the walk rather than faulting again. An exception handler's frame links to
+//! the interrupted function's caller: the interrupted function itself is the one at the RIP the
+//! CPU saved, which the exception's report names. Words that aren't addresses in the kernel's
+//! code, like the error code some exceptions push where a return address would be, are skipped.
+
+use crate::symbols::Symbolized;
+use crate::{memory, sections};
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+
+/// Frames followed at most, in case the chain loops
+pub const MAX_FRAMES: usize = 32;
+
+/// RBP of the function this is inlined into
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// The return addresses on a stack, innermost first
+pub struct Frames {
+    /// Frame pointer of the next frame, 0 once the walk is over
+    rbp: u64,
+    left: usize,
+}
+
+impl Frames {
+    /// The frames of the calling function's callers
+    #[inline(always)]
+    pub fn here() -> Frames {
+        Frames::from_frame_pointer(frame_pointer())
+    }
+
+    /// The frames from the frame pointer `rbp` out, e.g. one saved by a context switch
+    pub fn from_frame_pointer(rbp: u64) -> Frames {
+        Frames {
+            rbp,
+            left: MAX_FRAMES,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.rbp != 0 && self.left > 0 {
+            self.left -= 1;
+            let (Some(next), Some(rip)) =
+                (read_word(self.rbp), read_word(self.rbp.wrapping_add(8)))
+            else {
+                self.rbp = 0;
+                return None;
+            };
+            self.rbp = match next > self.rbp {
+                true => next,
+                false => 0,
+            };
+            if is_code(rip) {
+                return Some(rip);
+            }
+        }
+        None
+    }
+}
+
+/// Reads the word at `address` if it's aligned and the page tables map it
+fn read_word(address: u64) -> Option<u64> {
+    if !address.is_multiple_of(8) {
+        return None;
+    }
+    let address = VirtAddr::try_new(address).ok()?;
+    memory::translate_in(Cr3::read().0, address)?;
+    Some(unsafe { address.as_ptr::<u64>().read_volatile() })
+}
+
+fn is_code(address: u64) -> bool {
+    let address = VirtAddr::new_truncate(address);
+    sections::text().contains(&address) || sections::init().contains(&address)
+}
+
+/// The frames of a stack one per line, `#<n> name+offset`, for printing
+pub struct Backtrace(u64);
+
+impl Backtrace {
+    /// The stack of the calling function
+    #[inline(always)]
+    pub fn here() -> Backtrace {
+        Backtrace(frame_pointer())
+    }
+
+    pub fn from_frame_pointer(rbp: u64) -> Backtrace {
+        Backtrace(rbp)
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, rip) in Frames::from_frame_pointer(self.0).enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "#{:<2} {}", index, Symbolized(rip))?;
+        }
+        Ok(())
+    }
+}
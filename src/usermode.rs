@@ -0,0 +1,707 @@
+//! Running code in ring 3.
+//!
+//! `run` enters a program at user privilege with either `iretq` or `sysretq` and returns once the
+//! program leaves ring 3 for good: through the `exit` system call, with `int3`, or because it
+//! faulted. The exception handlers recognise a fault from ring 3 by the privilege level in the
+//! saved code segment. If `emulate` can carry out the faulting instruction, the program resumes
+//! after it; otherwise the handler calls `exit`, which drops the interrupted program and resumes
+//! the kernel stack `run` was called on. `resume` does the same for a program continued from saved
+//! `Registers`, and also gives the CPU back when the timer interrupts the program, with its
+//! registers saved to continue it later; that's what the `process` scheduler builds on.
+//!
+//! Interrupts stay enabled in ring 3 and land on the TSS's privilege stack (see `gdt`). Programs
+//! see only what was mapped for them in the user part of the address space, between
+//! `memory::USER_START` and `memory::USER_END`: in the kernel's own page tables with
+//! `memory::map_user`, or in an `AddressSpace` of their own.
+//!
+//! The GS base is the kernel's per-CPU data (see `percpu`) only while the CPU is in ring 0: each
+//! way into ring 3 does `swapgs` right before its `iretq` or `sysretq`, with interrupts off, and
+//! each way back, the system call entry and every interrupt and exception entry the program can
+//! reach, does `swapgs` first thing, if it came from ring 3 (see `interrupts`). So ring 3 runs
+//! with the program's own GS base, 0 until it loads GS, and whatever it loads never reaches the
+//! kernel. NMIs and double faults can arrive in between a `swapgs` and the return, so they look
+//! at the base itself. The program's base isn't kept per thread.
+
+use crate::interrupts::page_fault::Cause;
+use crate::sync::preempt;
+use crate::{fpu, gdt, memory, percpu, println, syscall};
+use core::arch::global_asm;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::Ordering;
+use emulate::Instruction;
+use spin::Once;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub mod emulate;
+
+/// RFLAGS a program starts with: just the interrupt flag (and the always-set bit 1)
+const USER_RFLAGS: u64 = 0x202;
+/// The RFLAGS bits a program may change itself: the arithmetic flags, the direction flag and the
+/// trap and alignment check flags
+const USER_RFLAGS_WRITABLE: u64 = 0x4_0dd5;
+
+global_asm!(
+    r#"
+    # Both enter functions take (entry, user stack, argument, &kernel_rsp, ...) and save the
+    # kernel's callee-saved registers and flags, then its stack pointer in *kernel_rsp, where
+    # usermode_resume picks it up again. General purpose registers are cleared so the program
+    # sees none of the kernel's values; the argument arrives in rdi. Every way into ring 3 ends
+    # with `swapgs`, which the entry from it undoes (see the module docs).
+
+    .global usermode_enter_iretq
+    usermode_enter_iretq:           # ..., %r8 = user code selector, %r9 = user data selector
+        pushq %rbx
+        pushq %rbp
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        pushfq
+        movq %rsp, (%rcx)
+
+        pushq %r9                   # ss
+        pushq %rsi                  # rsp
+        pushq ${rflags}             # rflags
+        pushq %r8                   # cs
+        pushq %rdi                  # rip
+        movq %rdx, %rdi
+        xorl %eax, %eax
+        xorl %ebx, %ebx
+        xorl %ecx, %ecx
+        xorl %edx, %edx
+        xorl %esi, %esi
+        xorl %ebp, %ebp
+        xorl %r8d, %r8d
+        xorl %r9d, %r9d
+        xorl %r10d, %r10d
+        xorl %r11d, %r11d
+        xorl %r12d, %r12d
+        xorl %r13d, %r13d
+        xorl %r14d, %r14d
+        xorl %r15d, %r15d
+        cli                         # an interrupt must not find the program's GS base in ring 0
+        swapgs
+        iretq
+
+    .global usermode_enter_sysretq
+    usermode_enter_sysretq:         # the selectors come from the STAR MSR
+        pushq %rbx
+        pushq %rbp
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        pushfq
+        movq %rsp, (%rcx)
+
+        # sysretq doesn't switch stacks, so the kernel briefly runs on the user stack; an
+        # interrupt must not arrive in that window
+        cli
+        movq %rdi, %rcx             # rip
+        movq ${rflags}, %r11        # rflags
+        movq %rdx, %rdi
+        movq %rsi, %rsp
+        xorl %eax, %eax
+        xorl %ebx, %ebx
+        xorl %edx, %edx
+        xorl %esi, %esi
+        xorl %ebp, %ebp
+        xorl %r8d, %r8d
+        xorl %r9d, %r9d
+        xorl %r10d, %r10d
+        xorl %r12d, %r12d
+        xorl %r13d, %r13d
+        xorl %r14d, %r14d
+        xorl %r15d, %r15d
+        swapgs
+        sysretq
+
+    # (registers, &kernel_rsp): continues a program from a saved Registers through iretq
+    .global usermode_enter_registers
+    usermode_enter_registers:
+        pushq %rbx
+        pushq %rbp
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        pushfq
+        movq %rsp, (%rsi)
+
+        # copy the registers to the stack, where they make a general purpose register save area
+        # followed by an interrupt frame
+        subq ${registers_size}, %rsp
+        movq %rdi, %rsi
+        movq %rsp, %rdi
+        movl ${registers_words}, %ecx
+        cld
+        rep movsq
+        popq %rax
+        popq %rbx
+        popq %rcx
+        popq %rdx
+        popq %rsi
+        popq %rdi
+        popq %rbp
+        popq %r8
+        popq %r9
+        popq %r10
+        popq %r11
+        popq %r12
+        popq %r13
+        popq %r14
+        popq %r15
+        cli
+        swapgs
+        iretq
+
+    # (kernel rsp): returns from the enter call that saved it
+    .global usermode_resume
+    usermode_resume:
+        movq %rdi, %rsp
+        popfq
+        popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %rbp
+        popq %rbx
+        retq
+    "#,
+    rflags = const USER_RFLAGS,
+    registers_size = const core::mem::size_of::<Registers>(),
+    registers_words = const core::mem::size_of::<Registers>() / 8,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn usermode_enter_iretq(
+        entry: u64,
+        stack: u64,
+        arg: u64,
+        kernel_rsp: *mut u64,
+        code_selector: u64,
+        data_selector: u64,
+    );
+    fn usermode_enter_sysretq(entry: u64, stack: u64, arg: u64, kernel_rsp: *mut u64);
+    fn usermode_enter_registers(registers: *const Registers, kernel_rsp: *mut u64);
+    fn usermode_resume(kernel_rsp: u64) -> !;
+}
+
+/// How `run` gets into ring 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Builds an interrupt frame and returns through it; works everywhere
+    Iretq,
+    /// The fast path system calls return through; needs `syscall::init` to have run on this CPU
+    Sysretq,
+}
+
+/// Everything a program running in ring 3 has in its registers, laid out like the timer's entry
+/// code saves them: the general purpose registers followed by the interrupt frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Registers {
+    /// A program about to start at `entry` with its stack pointer at `stack_top` and `arg` in
+    /// rdi; every other register is zero
+    pub fn new(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> Self {
+        let selectors = gdt::selectors();
+        Registers {
+            rdi: arg,
+            rip: entry.as_u64(),
+            cs: selectors.user_code_selector.0.into(),
+            rflags: USER_RFLAGS,
+            rsp: stack_top.as_u64(),
+            ss: selectors.user_data_selector.0.into(),
+            ..Registers::default()
+        }
+    }
+
+    /// The same registers with the segments and privileged flags of a program in ring 3, for
+    /// registers that come from outside the kernel, like a checkpoint
+    pub fn sanitized(self) -> Self {
+        let selectors = gdt::selectors();
+        Registers {
+            cs: selectors.user_code_selector.0.into(),
+            rflags: self.rflags & USER_RFLAGS_WRITABLE | USER_RFLAGS,
+            ss: selectors.user_data_selector.0.into(),
+            ..self
+        }
+    }
+}
+
+/// Why a program left ring 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The program made the `exit` system call
+    Exit {
+        code: u64,
+    },
+    /// The program executed `int3`; `rip` points past it
+    Breakpoint {
+        rip: u64,
+    },
+    PageFault {
+        address: u64,
+        error_code: u64,
+        rip: u64,
+    },
+    /// A page fault in the guard page below a stack: the stack outgrew its limit
+    StackOverflow {
+        address: u64,
+        rsp: u64,
+        rip: u64,
+    },
+    GeneralProtection {
+        error_code: u64,
+        rip: u64,
+    },
+    /// An instruction the CPU doesn't have, or only in ring 0, that `emulate` couldn't stand in
+    /// for
+    InvalidOpcode {
+        rip: u64,
+        instruction: Instruction,
+    },
+    /// A misaligned access with alignment checking on
+    AlignmentCheck {
+        rip: u64,
+    },
+    DivideError {
+        rip: u64,
+    },
+    /// The timer interrupted a program started with `resume`, whose registers now say where to
+    /// continue
+    Preempted,
+}
+
+impl fmt::Display for UserExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UserExit::Exit { code } => write!(f, "exited with code {}", code),
+            UserExit::Breakpoint { rip } => write!(f, "breakpoint at {:#x}", rip),
+            UserExit::PageFault {
+                address,
+                error_code,
+                rip,
+            } => write!(
+                f,
+                "page fault: {} at {:#x}, from {:#x}",
+                Cause(PageFaultErrorCode::from_bits_truncate(error_code)),
+                address,
+                rip
+            ),
+            UserExit::StackOverflow { address, rsp, rip } => write!(
+                f,
+                "stack overflow accessing {:#x} at {:#x} (stack pointer {:#x})",
+                address, rip, rsp
+            ),
+            UserExit::GeneralProtection { error_code, rip } => write!(
+                f,
+                "general protection fault at {:#x} (error code {:#x})",
+                rip, error_code
+            ),
+            UserExit::InvalidOpcode { rip, instruction } => {
+                write!(f, "invalid opcode at {:#x} ({})", rip, instruction)
+            }
+            UserExit::AlignmentCheck { rip } => write!(f, "alignment check at {:#x}", rip),
+            UserExit::DivideError { rip } => write!(f, "divide error at {:#x}", rip),
+            UserExit::Preempted => f.write_str("preempted"),
+        }
+    }
+}
+
+/// What `exit` needs to get back to `run`; lives on `run`'s stack while ring 3 runs
+pub struct Context {
+    kernel_rsp: u64,
+    exit: Option<UserExit>,
+    /// Where `preempt` saves the program's registers, null if it mustn't be preempted
+    registers: *mut Registers,
+    /// Timer ticks before `preempt` takes the CPU away
+    ticks_left: u64,
+}
+
+/// Runs the program at `entry` in ring 3 with its stack pointer at `stack_top` and `arg` in rdi,
+/// until it leaves ring 3 again.
+///
+/// Both addresses must be in memory mapped with `memory::map_user`; if they aren't, the program
+/// faults right away and the fault is returned like any other. The program isn't preempted.
+pub fn run(entry: VirtAddr, stack_top: VirtAddr, arg: u64, transition: Transition) -> UserExit {
+    let selectors = gdt::selectors();
+    // the program has no FPU state of its own and uses whatever the registers hold
+    fpu::discard();
+    enter(ptr::null_mut(), 0, |kernel_rsp| unsafe {
+        match transition {
+            Transition::Iretq => usermode_enter_iretq(
+                entry.as_u64(),
+                stack_top.as_u64(),
+                arg,
+                kernel_rsp,
+                selectors.user_code_selector.0.into(),
+                selectors.user_data_selector.0.into(),
+            ),
+            Transition::Sysretq => {
+                usermode_enter_sysretq(entry.as_u64(), stack_top.as_u64(), arg, kernel_rsp)
+            }
+        }
+    })
+}
+
+/// Continues a program from `registers` until it leaves ring 3 again, which includes the timer
+/// preempting it at the `ticks`th tick (at least one); `registers` then holds the state to resume
+/// it from next time, or after a fault the state it faulted in.
+///
+/// The segment selectors in `registers` must be the user ones, as `Registers::new` sets them.
+pub fn resume(registers: &mut Registers, ticks: u64) -> UserExit {
+    let selectors = gdt::selectors();
+    assert!(
+        registers.cs as u16 == selectors.user_code_selector.0
+            && registers.ss as u16 == selectors.user_data_selector.0,
+        "resuming a program with kernel segments"
+    );
+    let registers: *mut Registers = registers;
+    enter(registers, ticks, |kernel_rsp| unsafe {
+        usermode_enter_registers(registers, kernel_rsp)
+    })
+}
+
+/// Publishes a `Context` for this CPU, lets `go` switch to ring 3 and returns why the program
+/// came back
+fn enter(registers: *mut Registers, ticks: u64, go: impl FnOnce(*mut u64)) -> UserExit {
+    let mut context = Context {
+        kernel_rsp: 0,
+        exit: None,
+        registers,
+        ticks_left: ticks,
+    };
+    let context_ptr = ptr::addr_of_mut!(context);
+    assert!(
+        preempt::is_preemptible(),
+        "entering ring 3 with preemption disabled"
+    );
+    preempt::clear_request(); // the last program's
+    let slot = &percpu::current().user_context;
+    let previous = slot.swap(context_ptr, Ordering::SeqCst);
+    assert!(
+        previous.is_null(),
+        "a program is already running in ring 3 on this CPU"
+    );
+
+    go(unsafe { ptr::addr_of_mut!((*context_ptr).kernel_rsp) });
+
+    slot.store(ptr::null_mut(), Ordering::SeqCst);
+    unsafe { (*context_ptr).exit }.expect("returned from ring 3 without an exit reason")
+}
+
+/// Called by the timer interrupt when it arrived in ring 3, with the program's registers;
+/// preempts the program once the ticks it was resumed for are used up
+pub fn timer_tick(registers: &Registers) {
+    if slice_ended() {
+        preempt(registers);
+    }
+}
+
+/// Called by the timer interrupt when it arrived in ring 0, e.g. in a system call of the program
+/// running on this CPU; a time slice that ends there is taken when the call returns
+pub fn kernel_timer_tick() {
+    if slice_ended() {
+        preempt::request();
+    }
+}
+
+/// Counts a tick against the program running on this CPU; true once its ticks are used up
+fn slice_ended() -> bool {
+    let context = percpu::current().user_context.load(Ordering::SeqCst);
+    if context.is_null() {
+        return false;
+    }
+    let ticks_left = unsafe { &mut (*context).ticks_left };
+    *ticks_left = ticks_left.saturating_sub(1);
+    *ticks_left == 0
+}
+
+/// Takes the CPU away from a program started with `resume`, saving `registers` to resume it from;
+/// returns for any other. With preemption disabled (see `sync::preempt`) it only asks for the
+/// next preemption point to do it.
+pub fn preempt(registers: &Registers) {
+    let context = percpu::current().user_context.load(Ordering::SeqCst);
+    if context.is_null() || unsafe { (*context).registers.is_null() } {
+        return;
+    }
+    if !preempt::is_preemptible() {
+        return preempt::request();
+    }
+    preempt::clear_request();
+    unsafe { *(*context).registers = *registers };
+    exit(UserExit::Preempted)
+}
+
+/// `exit` for a fault, keeping `registers`, the program's as it faulted, where `preempt` does: a
+/// signal handler (see `process::signal`) may yet run on them and continue the program
+pub fn fault(registers: &Registers, reason: UserExit) -> ! {
+    let context = percpu::current().user_context.load(Ordering::SeqCst);
+    if !context.is_null() && unsafe { !(*context).registers.is_null() } {
+        unsafe { *(*context).registers = *registers };
+    }
+    exit(reason)
+}
+
+/// Abandons the program running in ring 3 on this CPU and makes its `run` return `reason`.
+/// Called by the `exit` system call and by the exception handlers for faults whose saved code
+/// segment has RPL 3.
+pub fn exit(reason: UserExit) -> ! {
+    let context = percpu::current().user_context.load(Ordering::SeqCst);
+    assert!(
+        !context.is_null(),
+        "exception from ring 3 with no program running"
+    );
+    unsafe {
+        (*context).exit = Some(reason);
+        usermode_resume((*context).kernel_rsp)
+    }
+}
+
+// --- the demo program ----------------------------------------------------------------------
+
+global_asm!(
+    r#"
+    .pushsection .text.user_demo, "ax"
+    .global user_demo_start
+    user_demo_start:
+
+    # (result: *mut u64): stores 1 + 2 + ... + 10 through the stack into *result
+    .global user_demo_sum
+    user_demo_sum:
+        xorl %eax, %eax
+        movl $1, %ecx
+    1:  addq %rcx, %rax
+        incq %rcx
+        cmpq $10, %rcx
+        jbe 1b
+        pushq %rax
+        popq %rdx
+        movq %rdx, (%rdi)
+        int3
+        ud2
+
+    # (address): reads it, which faults for a kernel address
+    .global user_demo_peek
+    user_demo_peek:
+        movq (%rdi), %rax
+        int3
+        ud2
+
+    # halting the CPU is reserved for ring 0
+    .global user_demo_halt
+    user_demo_halt:
+        hlt
+        int3
+        ud2
+
+    # (): checks the kernel's ABI, greets through the system calls, starts a second program and
+    # exits; exits with 1 right away if the kernel has another ABI than it was built for
+    .global user_demo_hello
+    user_demo_hello:
+        movq %rsp, %r12             # the child reuses the stack once this program is gone
+        subq $64, %rsp
+        movl ${abi}, %eax
+        movq %rsp, %rdi
+        movl $64, %esi
+        syscall
+        movq %rsp, %rdi
+        leaq user_demo_abi(%rip), %rsi
+        movl $(user_demo_abi_end - user_demo_abi), %ecx
+        cld
+        repe cmpsb
+        jne 1f
+        movq %r12, %rsp
+        movl ${write}, %eax
+        movl $1, %edi
+        leaq user_demo_message(%rip), %rsi
+        movl $(user_demo_message_end - user_demo_message), %edx
+        syscall
+        movl ${sleep}, %eax
+        movl $10, %edi
+        syscall
+        movl ${spawn}, %eax
+        leaq user_demo_child(%rip), %rdi
+        movq %r12, %rsi
+        movl $42, %edx
+        syscall
+        movl ${exit}, %eax
+        xorl %edi, %edi
+        syscall
+        ud2
+
+    1:  movl ${write}, %eax
+        movl $1, %edi
+        leaq user_demo_abi_mismatch(%rip), %rsi
+        movl $(user_demo_abi_mismatch_end - user_demo_abi_mismatch), %edx
+        syscall
+        movl ${exit}, %eax
+        movl $1, %edi
+        syscall
+        ud2
+
+    # (code): exits with it
+    user_demo_child:
+        movl ${exit}, %eax
+        syscall
+        ud2
+
+    user_demo_message:
+        .ascii "usermode: hello from ring 3\n"
+    user_demo_message_end:
+
+    # the first line of the ABI manifest of the kernels this program works with
+    user_demo_abi:
+        .ascii "rust_os-abi 1\n"
+    user_demo_abi_end:
+    user_demo_abi_mismatch:
+        .ascii "usermode: the kernel's ABI isn't the one this program was built for\n"
+    user_demo_abi_mismatch_end:
+
+    .global user_demo_end
+    user_demo_end:
+    .popsection
+    "#,
+    write = const syscall::number::WRITE,
+    sleep = const syscall::number::SLEEP,
+    spawn = const syscall::number::SPAWN,
+    exit = const syscall::number::EXIT,
+    abi = const syscall::number::ABI,
+    options(att_syntax)
+);
+
+extern "C" {
+    static user_demo_start: u8;
+    static user_demo_sum: u8;
+    static user_demo_peek: u8;
+    static user_demo_halt: u8;
+    static user_demo_hello: u8;
+    static user_demo_end: u8;
+}
+
+/// Where the demo's code, data and stack are mapped
+const DEMO_CODE: u64 = memory::USER_START;
+const DEMO_DATA: u64 = memory::USER_START + 0x1000;
+const DEMO_STACK: u64 = memory::USER_START + 0x10_0000;
+const DEMO_STACK_SIZE: u64 = 4096 * 4;
+
+static DEMO_MAPPED: Once<Result<(), &'static str>> = Once::new();
+
+/// Copies the demo program into user memory, the first time it's needed
+fn map_demo() -> Result<(), &'static str> {
+    *DEMO_MAPPED.call_once(|| {
+        let code = unsafe {
+            let start = ptr::addr_of!(user_demo_start);
+            let length = ptr::addr_of!(user_demo_end) as usize - start as usize;
+            core::slice::from_raw_parts(start, length)
+        };
+        let data = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        memory::map_user(
+            VirtAddr::new(DEMO_CODE),
+            code.len() as u64,
+            code,
+            PageTableFlags::empty(),
+        )
+        .and_then(|()| memory::map_user(VirtAddr::new(DEMO_DATA), 4096, &[], data))
+        .and_then(|()| memory::map_user(VirtAddr::new(DEMO_STACK), DEMO_STACK_SIZE, &[], data))
+        .map_err(|_| "failed to map the demo program")
+    })
+}
+
+/// Address of a demo routine in the user mapping
+fn demo_entry(symbol: *const u8) -> VirtAddr {
+    let offset = symbol as u64 - ptr::addr_of!(user_demo_start) as u64;
+    VirtAddr::new(DEMO_CODE + offset)
+}
+
+/// Runs the embedded demo program: a computation that returns through `int3`, a read of kernel
+/// memory and a privileged instruction, the last two of which must fault, and finally one that
+/// uses the system calls and starts another program
+pub fn run_demo() -> Result<(), &'static str> {
+    map_demo()?;
+    let stack_top = VirtAddr::new(DEMO_STACK + DEMO_STACK_SIZE);
+    let result = DEMO_DATA as *mut u64;
+
+    unsafe { result.write_volatile(0) };
+    let exit = run(
+        demo_entry(ptr::addr_of!(user_demo_sum)),
+        stack_top,
+        DEMO_DATA,
+        Transition::Sysretq,
+    );
+    let sum = unsafe { result.read_volatile() };
+    println!("usermode: sum program: {}, result {}", exit, sum);
+    if !matches!(exit, UserExit::Breakpoint { .. }) || sum != 55 {
+        return Err("the sum program didn't finish");
+    }
+
+    let kernel_address = ptr::addr_of!(DEMO_MAPPED) as u64;
+    let exit = run(
+        demo_entry(ptr::addr_of!(user_demo_peek)),
+        stack_top,
+        kernel_address,
+        Transition::Iretq,
+    );
+    println!("usermode: reading kernel memory: {}", exit);
+    if !matches!(exit, UserExit::PageFault { address, .. } if address == kernel_address) {
+        return Err("ring 3 could read kernel memory");
+    }
+
+    let exit = run(
+        demo_entry(ptr::addr_of!(user_demo_halt)),
+        stack_top,
+        0,
+        Transition::Iretq,
+    );
+    println!("usermode: executing hlt: {}", exit);
+    if !matches!(exit, UserExit::GeneralProtection { .. }) {
+        return Err("ring 3 could execute a privileged instruction");
+    }
+
+    let exit = run(
+        demo_entry(ptr::addr_of!(user_demo_hello)),
+        stack_top,
+        0,
+        Transition::Sysretq,
+    );
+    println!("usermode: system call program {}", exit);
+    if exit == (UserExit::Exit { code: 1 }) {
+        return Err("the system call program was built for another kernel ABI");
+    }
+    if exit != (UserExit::Exit { code: 0 }) {
+        return Err("the system call program didn't exit");
+    }
+    while let Some(child) = syscall::take_spawned() {
+        let exit = run(child.entry, child.stack_top, child.arg, Transition::Sysretq);
+        println!("usermode: spawned program {} {}", child.id, exit);
+    }
+    Ok(())
+}
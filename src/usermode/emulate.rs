@@ -0,0 +1,398 @@
+//! Instructions the CPU faults on in ring 3 that the kernel carries out in its place.
+//!
+//! The #UD and #GP handlers pass a fault from ring 3 here before ending the program. If the
+//! instruction is one of these, it's emulated and the program continues after it:
+//!
+//! - `rdpid`, which cores before Ice Lake and Zen 2 don't have, reads the CPU's index, which is
+//!   what other kernels keep in the TSC_AUX it stands for
+//! - `cpuid`, which faults in ring 3 on cores where `init` could turn on CPUID faulting, answers
+//!   what the CPU would, less what a program can't use here: MONITOR and VMX, which are for ring
+//!   0, and AVX and AVX-512 unless XCR0 has their registers switched (see `fpu`). RDPID is
+//!   advertised, as it's emulated either way.
+//! - the aligned SSE moves, `movaps`, `movapd` and `movdqa`, on memory that isn't 16-byte aligned
+//!   after all, which are done unaligned
+//!
+//! Anything else still ends the program, whose `UserExit::InvalidOpcode` then has the bytes it
+//! stopped at, to tell what it was.
+
+use crate::usermode::Registers;
+use crate::{percpu, syscall};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use x86_64::VirtAddr;
+
+/// Longest an instruction can be
+const MAX_LEN: usize = 15;
+
+const MSR_PLATFORM_INFO: u32 = 0xce;
+const PLATFORM_INFO_CPUID_FAULTING: u64 = 1 << 32;
+const MSR_MISC_FEATURES_ENABLES: u32 = 0x140;
+const CPUID_FAULTING: u64 = 1 << 0;
+
+/// REX prefix bits: 64-bit operands, and the extra high bit of ModRM.reg, SIB.index and
+/// ModRM.rm or SIB.base
+const REX_R: u8 = 1 << 2;
+const REX_X: u8 = 1 << 1;
+const REX_B: u8 = 1 << 0;
+
+/// CPUID feature bits the filter touches
+mod feature {
+    /// Leaf 1, ECX
+    pub const MONITOR: u32 = 1 << 3;
+    pub const VMX: u32 = 1 << 5;
+    pub const FMA: u32 = 1 << 12;
+    pub const AVX: u32 = 1 << 28;
+    pub const F16C: u32 = 1 << 29;
+    /// Leaf 7, EBX
+    pub const AVX2: u32 = 1 << 5;
+    pub const AVX512: u32 =
+        1 << 16 | 1 << 17 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 30 | 1 << 31;
+    /// Leaf 7, ECX
+    pub const AVX512_ECX: u32 = 1 << 1 | 1 << 6 | 1 << 11 | 1 << 12 | 1 << 14;
+    pub const RDPID: u32 = 1 << 22;
+}
+
+/// Whether `cpuid` in ring 3 faults, on every CPU
+static CPUID_FAULTS: AtomicBool = AtomicBool::new(false);
+
+/// Sets this CPU up for programs: alignment checking for those that turn on RFLAGS.AC, and CPUID
+/// faulting if the CPU has it. Only Intel cores from Ivy Bridge on do, and only theirs are sure
+/// to have the MSR that says so; reading it elsewhere may fault itself.
+pub fn init() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK)) };
+    let cpuid = CpuId::new();
+    let intel = cpuid
+        .get_vendor_info()
+        .is_some_and(|vendor| vendor.as_str() == "GenuineIntel");
+    let ivy_bridge = cpuid
+        .get_feature_info()
+        .is_some_and(|info| info.family_id() == 6 && info.model_id() >= 0x3a);
+    if !intel || !ivy_bridge {
+        return;
+    }
+    unsafe {
+        if Msr::new(MSR_PLATFORM_INFO).read() & PLATFORM_INFO_CPUID_FAULTING == 0 {
+            return;
+        }
+        let mut misc = Msr::new(MSR_MISC_FEATURES_ENABLES);
+        misc.write(misc.read() | CPUID_FAULTING);
+    }
+    CPUID_FAULTS.store(true, Ordering::Relaxed);
+}
+
+/// Whether `cpuid` in ring 3 goes through the filter
+pub fn cpuid_faults() -> bool {
+    CPUID_FAULTS.load(Ordering::Relaxed)
+}
+
+/// The bytes a faulting instruction starts with, as far as ring 3 may read them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    bytes: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl Instruction {
+    pub fn fetch(rip: u64) -> Instruction {
+        let mut instruction = Instruction {
+            bytes: [0; MAX_LEN],
+            len: 0,
+        };
+        for (offset, byte) in instruction.bytes.iter_mut().enumerate() {
+            match VirtAddr::try_new(rip.wrapping_add(offset as u64)) {
+                Ok(address) if syscall::user_accessible(address, 1, false) => {
+                    *byte = unsafe { address.as_ptr::<u8>().read_volatile() };
+                    instruction.len += 1;
+                }
+                _ => break,
+            }
+        }
+        instruction
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.len == 0 {
+            return f.write_str("unreadable");
+        }
+        for (index, byte) in self.bytes().iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handles #UD from ring 3; true if the instruction was emulated and the program goes on
+pub fn invalid_opcode(registers: &mut Registers) -> bool {
+    let instruction = Instruction::fetch(registers.rip);
+    let Some(decoded) = Decoded::new(instruction.bytes(), registers) else {
+        return false;
+    };
+    match decoded {
+        // rdpid r64: F3 0F C7 /7 with a register operand
+        Decoded {
+            opcode: 0xc7,
+            prefixes: Prefixes { repz: true, .. },
+            reg: 7,
+            operand: Operand::Register(rm),
+            ..
+        } => *gpr(registers, rm) = percpu::current().index as u64,
+        _ => return false,
+    }
+    registers.rip += decoded.len as u64;
+    true
+}
+
+/// Handles #GP from ring 3; true if the instruction was emulated and the program goes on
+pub fn general_protection(registers: &mut Registers, error_code: u64) -> bool {
+    if error_code != 0 {
+        return false; // about a segment, which no emulated instruction loads
+    }
+    let instruction = Instruction::fetch(registers.rip);
+    if instruction.bytes().starts_with(&[0x0f, 0xa2]) && cpuid_faults() {
+        cpuid(registers);
+        registers.rip += 2;
+        return true;
+    }
+    let Some(decoded) = Decoded::new(instruction.bytes(), registers) else {
+        return false;
+    };
+    let Operand::Memory(address) = decoded.operand else {
+        return false;
+    };
+    let prefixes = decoded.prefixes;
+    if prefixes.repz || prefixes.repnz || address % 16 == 0 {
+        return false; // not an aligned move, or not its alignment that faulted
+    }
+    let load = match (decoded.opcode, prefixes.operand_size) {
+        // movaps and movapd, movdqa
+        (0x28, _) | (0x6f, true) => true,
+        (0x29, _) | (0x7f, true) => false,
+        _ => return false,
+    };
+    let Ok(address) = VirtAddr::try_new(address) else {
+        return false;
+    };
+    if !syscall::user_accessible(address, 16, !load) {
+        return false;
+    }
+    let memory = address.as_mut_ptr::<u8>();
+    unsafe { move_xmm(decoded.reg, memory, load) };
+    registers.rip += decoded.len as u64;
+    true
+}
+
+/// `cpuid` for ring 3, through the filter described at the top
+fn cpuid(registers: &mut Registers) {
+    let (leaf, subleaf) = (registers.rax as u32, registers.rcx as u32);
+    let result = __cpuid_count(leaf, subleaf);
+    let (eax, mut ebx, mut ecx, edx) = (result.eax, result.ebx, result.ecx, result.edx);
+    let xcr0 = match Cr4::read().contains(Cr4Flags::OSXSAVE) {
+        true => XCr0::read(),
+        false => XCr0Flags::empty(),
+    };
+    let avx = xcr0.contains(XCr0Flags::SSE | XCr0Flags::AVX);
+    let avx512 =
+        avx && xcr0.contains(XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM);
+    match leaf {
+        1 => {
+            ecx &= !(feature::MONITOR | feature::VMX);
+            if !avx {
+                ecx &= !(feature::FMA | feature::AVX | feature::F16C);
+            }
+        }
+        7 if subleaf == 0 => {
+            if !avx {
+                ebx &= !feature::AVX2;
+            }
+            if !avx512 {
+                ebx &= !feature::AVX512;
+                ecx &= !feature::AVX512_ECX;
+            }
+            ecx |= feature::RDPID;
+        }
+        _ => {}
+    }
+    registers.rax = eax as u64;
+    registers.rbx = ebx as u64;
+    registers.rcx = ecx as u64;
+    registers.rdx = edx as u64;
+}
+
+/// Copies 16 bytes between XMM register `index` and `memory`, which needn't be aligned
+///
+/// # Safety
+///
+/// `memory` must be readable, and writable unless `load`. The registers must hold the state of
+/// the program that faulted, as they do in its fault handler: an SSE instruction with CR0.TS set
+/// raises #NM before it could fault on its operand.
+unsafe fn move_xmm(index: u8, memory: *mut u8, load: bool) {
+    macro_rules! move_xmm {
+        ($($n:literal)*) => {
+            match index {
+                $($n => match load {
+                    true => asm!(
+                        concat!("movdqu xmm", $n, ", [{}]"),
+                        in(reg) memory,
+                        options(nostack, preserves_flags, readonly)
+                    ),
+                    false => asm!(
+                        concat!("movdqu [{}], xmm", $n),
+                        in(reg) memory,
+                        options(nostack, preserves_flags)
+                    ),
+                },)*
+                _ => unreachable!("there are 16 XMM registers"),
+            }
+        };
+    }
+    move_xmm!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+}
+
+/// The general purpose register numbered `number` in instruction encodings
+fn gpr(registers: &mut Registers, number: u8) -> &mut u64 {
+    match number {
+        0 => &mut registers.rax,
+        1 => &mut registers.rcx,
+        2 => &mut registers.rdx,
+        3 => &mut registers.rbx,
+        4 => &mut registers.rsp,
+        5 => &mut registers.rbp,
+        6 => &mut registers.rsi,
+        7 => &mut registers.rdi,
+        8 => &mut registers.r8,
+        9 => &mut registers.r9,
+        10 => &mut registers.r10,
+        11 => &mut registers.r11,
+        12 => &mut registers.r12,
+        13 => &mut registers.r13,
+        14 => &mut registers.r14,
+        _ => &mut registers.r15,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Prefixes {
+    /// 0x66
+    operand_size: bool,
+    /// 0xf3
+    repz: bool,
+    /// 0xf2
+    repnz: bool,
+    rex: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Memory(u64),
+}
+
+/// A two-byte opcode (0x0f and `opcode`) with a ModRM byte and no immediate, the only kind
+/// emulated
+#[derive(Debug, Clone, Copy)]
+struct Decoded {
+    prefixes: Prefixes,
+    opcode: u8,
+    /// ModRM.reg, extended by REX.R
+    reg: u8,
+    operand: Operand,
+    len: usize,
+}
+
+impl Decoded {
+    /// Decodes `bytes`; `None` for what isn't of the kind, or has prefixes (LOCK, FS or GS,
+    /// address size) no emulated instruction takes
+    fn new(bytes: &[u8], registers: &mut Registers) -> Option<Decoded> {
+        let mut prefixes = Prefixes::default();
+        let mut position = 0;
+        loop {
+            match *bytes.get(position)? {
+                0x66 => prefixes.operand_size = true,
+                0xf3 => prefixes.repz = true,
+                0xf2 => prefixes.repnz = true,
+                // segment overrides that mean nothing in 64-bit mode
+                0x26 | 0x2e | 0x36 | 0x3e => {}
+                _ => break,
+            }
+            position += 1;
+        }
+        if let rex @ 0x40..=0x4f = *bytes.get(position)? {
+            prefixes.rex = rex;
+            position += 1;
+        }
+        if *bytes.get(position)? != 0x0f {
+            return None;
+        }
+        let opcode = *bytes.get(position + 1)?;
+        position += 2;
+
+        let modrm = *bytes.get(position)?;
+        position += 1;
+        let rex = prefixes.rex;
+        let (mode, rm) = (modrm >> 6, modrm & 7);
+        let reg = (modrm >> 3 & 7) | (rex & REX_R) << 1;
+        let mut read = |len: usize| -> Option<i64> {
+            let field = bytes.get(position..position + len)?;
+            position += len;
+            Some(match len {
+                1 => field[0] as i8 as i64,
+                _ => i32::from_le_bytes(field.try_into().ok()?) as i64,
+            })
+        };
+        let operand = match (mode, rm) {
+            (3, rm) => Operand::Register(rm | (rex & REX_B) << 3),
+            // RIP-relative, from the end of the instruction, which is here without an immediate
+            (0, 5) => {
+                let displacement = read(4)?;
+                let end = registers.rip + position as u64;
+                Operand::Memory(end.wrapping_add_signed(displacement))
+            }
+            (mode, rm) => {
+                let base = if rm == 4 {
+                    let sib = read(1)? as u8;
+                    let (scale, base) = (sib >> 6, sib & 7);
+                    let index = (sib >> 3 & 7) | (rex & REX_X) << 2;
+                    let index = match index {
+                        4 => 0, // none
+                        index => *gpr(registers, index) << scale,
+                    };
+                    let base = match (mode, base) {
+                        (0, 5) => read(4)? as u64, // no base, just a displacement
+                        (_, base) => *gpr(registers, base | (rex & REX_B) << 3),
+                    };
+                    base.wrapping_add(index)
+                } else {
+                    *gpr(registers, rm | (rex & REX_B) << 3)
+                };
+                let displacement = match mode {
+                    1 => read(1)?,
+                    2 => read(4)?,
+                    _ => 0,
+                };
+                Operand::Memory(base.wrapping_add_signed(displacement))
+            }
+        };
+        Some(Decoded {
+            prefixes,
+            opcode,
+            reg,
+            operand,
+            len: position,
+        })
+    }
+}
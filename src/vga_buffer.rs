@@ -1,5 +1,6 @@
-
-use spin::Mutex;
+use crate::console::{cp437, unicode, Colors};
+use crate::sync::spinlock::IrqSpinLock;
+use x86_64::PhysAddr;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,35 +41,64 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+/// Rows of the text mode screen
+pub const BUFFER_HEIGHT: usize = 25;
+/// Columns of the text mode screen
+pub const BUFFER_WIDTH: usize = 80;
 
 use volatile::Volatile;
 
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT]
+    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+impl Buffer {
+    /// The cell at `row`, `col`, or an error if that's off the screen; all access goes through
+    /// here, so a bad position can't panic with WRITER held
+    fn at(&mut self, row: usize, col: usize) -> Result<&mut Volatile<ScreenChar>, &'static str> {
+        self.chars
+            .get_mut(row)
+            .and_then(|cells| cells.get_mut(col))
+            .ok_or("position off the screen")
+    }
+
+    fn read(&mut self, row: usize, col: usize) -> Result<ScreenChar, &'static str> {
+        Ok(self.at(row, col)?.read())
+    }
+
+    fn write(
+        &mut self,
+        row: usize,
+        col: usize,
+        character: ScreenChar,
+    ) -> Result<(), &'static str> {
+        self.at(row, col)?.write(character);
+        Ok(())
+    }
 }
 
 pub struct Writer {
-    column_position: usize, // keeps track of current position in last row
-    color_code: ColorCode, // holds the foreground and background color
+    column_position: usize,      // keeps track of current position in last row
+    color_code: ColorCode,       // holds the foreground and background color
     buffer: &'static mut Buffer, // reference to VGA buffer ('static specifies that this reference is valid for the duration of the programs run time
 }
 
 /// Only link something if it is called, allowing us to compute the static's value at runtime
 use lazy_static::lazy_static;
-/// Can be used as an interface from other modules without carrying a Writer instance around
+// Can be used as an interface from other modules without carrying a Writer instance around
 lazy_static! { // Declare this function as lazily linked
     /* since it's a static reference it must provide asynchronous access to it
     by all member functions so as not to create a race for the data, we can do this with a "spinlock"
     which basically means instead of blocking, a thread may attempt to acquire a lock on the data over and over again until the
     Mutex is freed from the last thread that had a lock on it. We use this version of synchronized
-    interior mutability because we have no underlying OS that handles Mutexes or threads*/
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    interior mutability because we have no underlying OS that handles Mutexes or threads.
+    It's an IrqSpinLock: CPUs get it in turn, and interrupts stay off while it's held, so a
+    handler that prints can't spin on a lock the code it interrupted holds*/
+    pub static ref WRITER: IrqSpinLock<Writer> = IrqSpinLock::new(Writer {
       column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe {&mut *(0xb8000 as *mut Buffer)},
+        color_code: ColorCode::new(Colors::DEFAULT.foreground, Colors::DEFAULT.background),
+        buffer: unsafe {&mut *crate::memory::phys_to_virt(PhysAddr::new(0xb8000)).as_mut_ptr::<Buffer>()},
     });
 }
 
@@ -77,7 +107,8 @@ impl Writer {
         match byte {
             b'\n' => self.new_line(), // If byte is new line, call new_line()
             byte => {
-                if self.column_position >= BUFFER_WIDTH { // Is line full? If so, call new_line()
+                if self.column_position >= BUFFER_WIDTH {
+                    // Is line full? If so, call new_line()
                     self.new_line();
                 }
 
@@ -85,7 +116,9 @@ impl Writer {
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar { // Write new ScreenChar to buffer
+                // Write new ScreenChar to buffer; the check above keeps it on the screen, and
+                // if it didn't, the byte is dropped rather than panicking with the lock held
+                let _ = self.buffer.write(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code,
                 });
@@ -95,21 +128,35 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Not part of printable ASCII range, print a ■ character
-                _ => self.write_byte(0xfe),
+        for ch in s.chars() {
+            match (ch, unicode::width(ch)) {
+                ('\n', _) => self.write_byte(b'\n'),
+                // back a column, as on a terminal; what's there stays until overwritten
+                ('\x08', _) => self.column_position = self.column_position.saturating_sub(1),
+                // the card's font is code page 437; a ■ for what it lacks a glyph for, and for ◙,
+                // whose byte `write_byte` takes for a newline
+                (ch, 1) => {
+                    let byte = cp437::encode(ch).filter(|&byte| byte != b'\n');
+                    self.write_byte(byte.unwrap_or(0xfe))
+                }
+                // a ■ for every cell anything else takes, so combining marks vanish and wide
+                // characters keep their two columns
+                (_, width) => {
+                    for _ in 0..width {
+                        self.write_byte(0xfe);
+                    }
+                }
             }
         }
     }
 
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT { // Omit first row as it is the row that is shifted off screen
+        for row in 1..BUFFER_HEIGHT {
+            // Omit first row as it is the row that is shifted off screen
             for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read(); // grabbing the char at that position
-                self.buffer.chars[row - 1][col].write(character); // moving that char up a row
+                if let Ok(character) = self.buffer.read(row, col) { // grabbing the char at that position
+                    let _ = self.buffer.write(row - 1, col, character); // moving that char up a row
+                }
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1); // clearing the duplicates from the previous row
@@ -118,12 +165,14 @@ impl Writer {
 
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
-            ascii_character: b' ', // Space
+            ascii_character: b' ',       // Space
             color_code: self.color_code, // Get vga_buffer's current color_code
         };
 
-        for col in 0..BUFFER_WIDTH { // iterate through columns in the row and write the space character (which is blank)
-            self.buffer.chars[row][col].write(blank);
+        for col in 0..BUFFER_WIDTH {
+            // iterate through columns in the row and write the space character (which is blank);
+            // a row off the screen has nothing to clear
+            let _ = self.buffer.write(row, col, blank);
         }
     }
 }
@@ -137,19 +186,19 @@ impl fmt::Write for Writer {
     }
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
-}
+impl crate::console::Backend for Writer {
+    fn write(&mut self, text: &str) {
+        self.write_string(text);
+    }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
-}
+    fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
-}
\ No newline at end of file
+    fn clear(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+}
@@ -0,0 +1,297 @@
+//! The watchdog: notices the kernel wedging itself and says so, rather than leaving a machine
+//! that silently stops responding.
+//!
+//! Hangs are looked for from the timer interrupt, as a hung CPU never gets back to the executor:
+//! every CPU's tick leaves a heartbeat, and about once per `CHECK_INTERVAL` whichever CPU ticks
+//! first looks at the others' heartbeats and at the kernel's watched locks (`WatchedMutex`). A
+//! CPU whose heartbeat is older than `HEARTBEAT_TIMEOUT` is spinning with interrupts
+//! disabled; a lock held longer than `LOCK_TIMEOUT` is named along with the CPU holding it. A
+//! hang on the only CPU with interrupts disabled stops its ticks as well, and goes unnoticed.
+//!
+//! Heap corruption is found on the way: frees that find the canary behind an allocation
+//! overwritten (see `allocator::quota`), free lists pointing outside the heap, which the
+//! `monitor` task walks every `CHECK_INTERVAL`, and in the heap's debug mode double frees and
+//! freed blocks written to.
+//!
+//! Reports go straight to COM1, and to the console only if its lock can be taken, as the stuck
+//! lock may well be the console's.
+
+use crate::allocator;
+use crate::clock::tsc;
+use crate::console::{self, Backend, Terminal};
+use crate::drivers::serial;
+use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+use crate::percpu::{self, MAX_CPUS};
+use crate::sync::watched::LockState;
+use crate::sysctl::Tunable;
+use crate::{println, timer};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// How often the checks run
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a lock may be held before it's reported
+pub static LOCK_TIMEOUT: Tunable = Tunable::new(
+    "watchdog.lock_timeout_ms",
+    "milliseconds a lock may be held before the watchdog reports it",
+    2000,
+    100,
+    60_000,
+);
+/// How long a CPU may go without a tick; kept over the longest tickless idle
+pub static HEARTBEAT_TIMEOUT: Tunable = Tunable::new(
+    "watchdog.heartbeat_timeout_ms",
+    "milliseconds a CPU may go without a timer tick before the watchdog reports it",
+    3000,
+    2000,
+    60_000,
+);
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+/// TSC ticks per millisecond, 0 until `init`
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// The TSC at which the checks run next
+static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+/// The TSC at each CPU's last tick
+static HEARTBEAT: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// CPUs reported as stalled, one bit each, so each stall is reported once
+static STALLED: AtomicU64 = AtomicU64::new(0);
+
+/// Frees that found their allocation's canary overwritten, and the last such allocation
+static CORRUPTIONS: AtomicU64 = AtomicU64::new(0);
+static LAST_CORRUPTION: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the checks; the TSC is calibrated by then
+pub fn init() {
+    TSC_PER_MS.store(tsc::ticks_per_ms(), Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Switches the checks on or off; heartbeats are kept either way
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Milliseconds from `since` to `now` by the TSC; 0 if `since` is later, as after a wake-up that
+/// reset the counter
+fn elapsed_ms(since: u64, now: u64) -> u64 {
+    match TSC_PER_MS.load(Ordering::Relaxed) {
+        0 => 0,
+        tsc_per_ms => now.saturating_sub(since) / tsc_per_ms,
+    }
+}
+
+/// Called on every CPU's timer tick
+pub fn tick() {
+    let now = tsc::read();
+    let Some(cpu) = percpu::try_current() else {
+        return;
+    };
+    HEARTBEAT[cpu.index].store(now, Ordering::Relaxed);
+    if STALLED.load(Ordering::Relaxed) & (1 << cpu.index) != 0 {
+        STALLED.fetch_and(!(1 << cpu.index), Ordering::Relaxed);
+        report(format_args!("cpu {} is ticking again", cpu.index));
+    }
+
+    let tsc_per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    if tsc_per_ms == 0 || !is_enabled() {
+        return;
+    }
+    let interval = CHECK_INTERVAL.as_millis() as u64 * tsc_per_ms;
+    let next = NEXT_CHECK.load(Ordering::Relaxed);
+    // a check far in the future means the TSC was reset by sleep
+    let due = now >= next || next - now > interval;
+    if due
+        && NEXT_CHECK
+            .compare_exchange(next, now + interval, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        check_cpus(now);
+        check_locks(now);
+    }
+}
+
+/// Reports CPUs that are online but stopped ticking
+fn check_cpus(now: u64) {
+    for (index, heartbeat) in HEARTBEAT.iter().enumerate().take(percpu::count()) {
+        if !percpu::is_online(index) {
+            // so it isn't taken for stalled the moment it comes back
+            heartbeat.store(now, Ordering::Relaxed);
+            continue;
+        }
+        let silent = elapsed_ms(heartbeat.load(Ordering::Relaxed), now);
+        if silent > HEARTBEAT_TIMEOUT.get()
+            && STALLED.fetch_or(1 << index, Ordering::Relaxed) & (1 << index) == 0
+        {
+            report(format_args!(
+                "cpu {} has not had a timer tick for {} ms; it's spinning with interrupts disabled",
+                index, silent
+            ));
+        }
+    }
+}
+
+fn watched_locks() -> [&'static LockState; 5] {
+    let [console, attached] = console::lock_states();
+    [
+        MAPPER.state(),
+        FRAME_ALLOCATOR.state(),
+        allocator::lock_state(),
+        console,
+        attached,
+    ]
+}
+
+/// Reports locks held for too long, each holding once
+fn check_locks(now: u64) {
+    for lock in watched_locks() {
+        let Some((since, holder)) = lock.held() else {
+            continue;
+        };
+        let held = elapsed_ms(since, now);
+        if held > LOCK_TIMEOUT.get() && lock.report_once(since) {
+            report(format_args!(
+                "the {} lock has been held for {} ms by {}",
+                lock.name,
+                held,
+                Holder(holder)
+            ));
+        }
+    }
+}
+
+struct Holder(Option<usize>);
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(cpu) => write!(f, "cpu {}", cpu),
+            None => f.write_str("an unknown cpu"),
+        }
+    }
+}
+
+/// Called by a free that found the canary behind the allocation at `address` overwritten
+pub fn heap_corrupted(address: u64) {
+    LAST_CORRUPTION.store(address, Ordering::Relaxed);
+    CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Writes a report to COM1 and, if it's free, the console; formats on the stack, as the heap may
+/// be what's stuck
+struct Reporter;
+
+impl Write for Reporter {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        if let Some(port) = serial::console_port() {
+            Terminal(port).write(text);
+        }
+        console::try_with_backend(|backend| backend.write(text));
+        Ok(())
+    }
+}
+
+fn report(args: fmt::Arguments) {
+    let _ = writeln!(Reporter, "watchdog: {}", args);
+}
+
+/// Heap errors reported so far
+#[derive(Default)]
+struct HeapErrors {
+    overruns: u64,
+    double_frees: u64,
+    damaged_free_blocks: u64,
+}
+
+/// Reports heap corruption found since the last look; returns whether there was any
+fn check_heap(seen: &mut HeapErrors) -> bool {
+    let mut found = false;
+    let count = CORRUPTIONS.load(Ordering::Relaxed);
+    if count != seen.overruns {
+        report(format_args!(
+            "{} allocations overrun, the last at {:#x}",
+            count - seen.overruns,
+            LAST_CORRUPTION.load(Ordering::Relaxed)
+        ));
+        seen.overruns = count;
+        found = true;
+    }
+    // only counted in the heap's debug mode
+    if let Some(stats) = allocator::try_stats() {
+        if (stats.double_frees, stats.damaged_free_blocks)
+            != (seen.double_frees, seen.damaged_free_blocks)
+        {
+            report(format_args!(
+                "{} double frees and {} freed blocks written to, the last at {:#x}",
+                stats.double_frees - seen.double_frees,
+                stats.damaged_free_blocks - seen.damaged_free_blocks,
+                stats.last_bad_block
+            ));
+            seen.double_frees = stats.double_frees;
+            seen.damaged_free_blocks = stats.damaged_free_blocks;
+            found = true;
+        }
+    }
+    if let Some(Err(node)) = allocator::check_free_lists() {
+        report(format_args!(
+            "the heap's free lists are corrupt: they lead to {:#x}",
+            node
+        ));
+        found = true;
+    }
+    found
+}
+
+/// Looks for heap corruption every `CHECK_INTERVAL`; meant to be spawned as a task
+pub async fn monitor() {
+    let mut seen = HeapErrors::default();
+    loop {
+        timer::sleep(CHECK_INTERVAL).await;
+        if is_enabled() {
+            check_heap(&mut seen);
+        }
+    }
+}
+
+/// `watchdog [on|off|check]`
+pub fn command(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        [] => {
+            println!("watchdog: {}", if is_enabled() { "on" } else { "off" });
+            let now = tsc::read();
+            for lock in watched_locks() {
+                match lock.held() {
+                    Some((since, holder)) => println!(
+                        "  {:<18} held for {} ms by {}",
+                        lock.name,
+                        elapsed_ms(since, now),
+                        Holder(holder)
+                    ),
+                    None => println!("  {:<18} free", lock.name),
+                }
+            }
+            for index in (0..percpu::count()).filter(|&index| percpu::is_online(index)) {
+                let heartbeat = HEARTBEAT[index].load(Ordering::Relaxed);
+                println!(
+                    "  cpu {:<14} last tick {} ms ago",
+                    index,
+                    elapsed_ms(heartbeat, now)
+                );
+            }
+        }
+        ["on"] => set_enabled(true),
+        ["off"] => set_enabled(false),
+        ["check"] => {
+            // counts corruptions from the start, unlike `monitor`
+            if !check_heap(&mut HeapErrors::default()) {
+                println!("watchdog: the heap looks fine");
+            }
+        }
+        _ => return Err("usage: watchdog [on|off|check]"),
+    }
+    Ok(())
+}
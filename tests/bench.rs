@@ -0,0 +1,36 @@
+//! Prints the `bench` table on COM1 and ends QEMU.
+//!
+//! A test kernel like `exceptions`, built only with the `bench` feature: `cargo test --features
+//! bench --test bench`. It boots through the drivers phase, so the other CPUs are up for the
+//! contended benchmarks, and always passes unless it panics; the numbers are for reading, or for
+//! comparing between builds.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::console::{self, Terminal};
+use rust_os::drivers::serial::{SerialPort, COM1};
+use rust_os::power::{exit_qemu, QemuExitCode};
+use rust_os::{bench, boot, bootinfo, println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    boot::run_until(bootinfo::from_bootloader(boot_info), "drivers");
+    if let Some(port) = SerialPort::init(COM1) {
+        console::attach(Box::new(Terminal(port)));
+    }
+    bench::run_all("");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[failed] {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,337 @@
+//! Checks the `crypto` primitives against the known answers their standards publish.
+//!
+//! A test kernel like `exceptions`: it boots through the interrupts phase, runs each case, prints
+//! the results on COM1 and ends QEMU with `Success` only if every one passed. `cargo test --test
+//! crypto` runs it. The vectors are copied from the RFCs and FIPS publications named with each
+//! case, so an edit that breaks a round function, a carry or a byte order fails here rather than
+//! as a disk that won't decrypt. AES runs whichever implementation the CPU picks: the software
+//! one under QEMU's default CPU, AES-NI with `-cpu host`.
+//!
+//! With the `net` feature, the TLS 1.3 key schedule is checked too, against the secrets, keys and
+//! server Finished of RFC 8448's simple 1-RTT handshake. The trace gives the transcript hashes
+//! along with the secrets, so no handshake messages have to be replayed.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::console::{self, Terminal};
+use rust_os::crypto::{self, chacha20poly1305, ed25519, x25519, Aes, AesXts, Sha256, Sha512};
+use rust_os::drivers::serial::{SerialPort, COM1};
+#[cfg(feature = "net")]
+use rust_os::net::tls;
+use rust_os::power::{exit_qemu, QemuExitCode};
+use rust_os::{boot, bootinfo, println};
+
+/// A primitive and where its expected output comes from
+struct Case {
+    name: &'static str,
+    check: fn() -> bool,
+}
+
+static CASES: &[Case] = &[
+    Case {
+        name: "CRC-32 check value",
+        check: || crypto::crc32(b"123456789") == 0xcbf4_3926,
+    },
+    Case {
+        name: "CRC-32C check value (RFC 3720)",
+        check: || crypto::crc32c(b"123456789") == 0xe306_9283,
+    },
+    Case {
+        name: "SHA-256 one block (FIPS 180-2)",
+        check: || {
+            crypto::sha256(b"abc")[..]
+                == hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        },
+    },
+    Case {
+        name: "SHA-256 two blocks (FIPS 180-2)",
+        check: || {
+            crypto::sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")[..]
+                == hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        },
+    },
+    Case {
+        name: "SHA-256 a million a's in pieces (FIPS 180-2)",
+        check: || {
+            let mut hasher = Sha256::new();
+            for _ in 0..1000 {
+                hasher.update(&[b'a'; 1000]);
+            }
+            hasher.finish()[..]
+                == hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        },
+    },
+    Case {
+        name: "SHA-512 one block (FIPS 180-2)",
+        check: || {
+            crypto::sha512(b"abc")[..]
+                == hex(concat!(
+                    "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+                    "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+                ))
+        },
+    },
+    Case {
+        name: "SHA-512 two blocks (FIPS 180-2)",
+        check: || {
+            let message = concat!(
+                "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn",
+                "hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+            );
+            crypto::sha512(message.as_bytes())[..]
+                == hex(concat!(
+                    "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018",
+                    "501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909",
+                ))
+        },
+    },
+    Case {
+        name: "SHA-512 a million a's in pieces (FIPS 180-2)",
+        check: || {
+            let mut hasher = Sha512::new();
+            for _ in 0..1000 {
+                hasher.update(&[b'a'; 1000]);
+            }
+            hasher.finish()[..]
+                == hex(concat!(
+                    "e718483d0ce769644e2e42c7bc15b4638e1f98b13b2044285632a803afa973eb",
+                    "de0ff244877ea60a4cb0432ce577c31beb009c5c2c49aa2e4eadb217ad8cc09b",
+                ))
+        },
+    },
+    Case {
+        name: "HMAC-SHA256 test case 2 (RFC 4231)",
+        check: || {
+            crypto::hmac_sha256(b"Jefe", b"what do ya want for nothing?")[..]
+                == hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        },
+    },
+    Case {
+        name: "HKDF-SHA256 test case 1 (RFC 5869)",
+        check: || {
+            let prk = crypto::hkdf_extract(&hex("000102030405060708090a0b0c"), &[0x0b; 22]);
+            let mut okm = [0; 42];
+            crypto::hkdf_expand(&prk, &hex("f0f1f2f3f4f5f6f7f8f9"), &mut okm);
+            prk[..] == hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5")
+                && okm[..]
+                    == hex(concat!(
+                        "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf",
+                        "34007208d5b887185865",
+                    ))
+        },
+    },
+    Case {
+        name: "PBKDF2-HMAC-SHA256 (RFC 7914)",
+        check: || {
+            let mut key = [0; 64];
+            crypto::pbkdf2_sha256(b"passwd", b"salt", 1, &mut key);
+            key[..]
+                == hex(concat!(
+                    "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                    "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783",
+                ))
+        },
+    },
+    Case {
+        name: "AES-128 (FIPS 197 appendix C.1)",
+        check: || aes_round_trip(16, "69c4e0d86a7b0430d8cdb78070b4c55a"),
+    },
+    Case {
+        name: "AES-256 (FIPS 197 appendix C.3)",
+        check: || aes_round_trip(32, "8ea2b7ca516745bfeafc49904b496089"),
+    },
+    Case {
+        name: "XTS-AES-128 vector 2 (IEEE 1619)",
+        check: || {
+            let mut key = [0x11; 32];
+            key[16..].fill(0x22);
+            let Some(xts) = AesXts::new(&key) else {
+                return false;
+            };
+            let mut sector = [0x44; 32];
+            xts.encrypt_sector(0x33_3333_3333, &mut sector);
+            let encrypted = sector[..]
+                == hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0");
+            xts.decrypt_sector(0x33_3333_3333, &mut sector);
+            encrypted && sector == [0x44; 32]
+        },
+    },
+    Case {
+        name: "ChaCha20-Poly1305 AEAD (RFC 8439 section 2.8.2)",
+        check: chacha20poly1305_aead,
+    },
+    Case {
+        name: "X25519 key agreement (RFC 7748 section 6.1)",
+        check: || {
+            let alice = key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+            let bob = key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+            let alice_public = x25519::public_key(&alice);
+            let bob_public = x25519::public_key(&bob);
+            let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+            alice_public[..]
+                == hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+                && bob_public[..]
+                    == hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+                && x25519::x25519(&alice, &bob_public)[..] == shared
+                && x25519::x25519(&bob, &alice_public)[..] == shared
+        },
+    },
+    Case {
+        name: "Ed25519 tests 1 and 2 (RFC 8032 section 7.1)",
+        check: || {
+            let empty = ed25519_verifies(
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                b"",
+                concat!(
+                    "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+                    "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+                ),
+            );
+            let one_byte = |message: &[u8]| {
+                ed25519_verifies(
+                    "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                    message,
+                    concat!(
+                        "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+                        "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+                    ),
+                )
+            };
+            empty && one_byte(&[0x72]) && !one_byte(&[0x73])
+        },
+    },
+    #[cfg(feature = "net")]
+    Case {
+        name: "TLS 1.3 key schedule, simple 1-RTT (RFC 8448 section 3)",
+        check: tls_key_schedule,
+    },
+];
+
+/// The bytes `text` spells in hex; the vectors are written the way the standards print them
+fn hex(text: &str) -> Vec<u8> {
+    let digit = |c: u8| (c as char).to_digit(16).expect("not a hex digit") as u8;
+    text.as_bytes()
+        .chunks_exact(2)
+        .map(|pair| digit(pair[0]) << 4 | digit(pair[1]))
+        .collect()
+}
+
+fn key(text: &str) -> [u8; 32] {
+    hex(text).try_into().expect("not 32 bytes")
+}
+
+/// Encrypts the FIPS 197 plaintext under the key 00 01 02 ... of `key_size` bytes, checks it
+/// comes out as `expected`, and decrypts it back
+fn aes_round_trip(key_size: usize, expected: &str) -> bool {
+    let key: Vec<u8> = (0..key_size as u8).collect();
+    let Some(aes) = Aes::new(&key) else {
+        return false;
+    };
+    let plaintext: [u8; 16] = hex("00112233445566778899aabbccddeeff").try_into().unwrap();
+    let mut block = plaintext;
+    aes.encrypt_block(&mut block);
+    let encrypted = block[..] == hex(expected);
+    aes.decrypt_block(&mut block);
+    encrypted && block == plaintext
+}
+
+fn chacha20poly1305_aead() -> bool {
+    let key = key("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f");
+    let nonce: [u8; 12] = hex("070000004041424344454647").try_into().unwrap();
+    let aad = hex("50515253c0c1c2c3c4c5c6c7");
+    let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+        one tip for the future, sunscreen would be it.";
+    let ciphertext = hex(concat!(
+        "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6",
+        "3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36",
+        "92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc",
+        "3ff4def08e4b7a9de576d26586cec64b6116",
+    ));
+    let tag: [u8; 16] = hex("1ae10b594f09e26a7e902ecbd0600691").try_into().unwrap();
+
+    let mut data = plaintext.to_vec();
+    if chacha20poly1305::seal(&key, &nonce, &aad, &mut data) != tag || data != ciphertext {
+        return false;
+    }
+    let mut forged = tag;
+    forged[0] ^= 1;
+    if chacha20poly1305::open(&key, &nonce, &aad, &mut data, &forged) {
+        return false;
+    }
+    chacha20poly1305::open(&key, &nonce, &aad, &mut data, &tag) && data == plaintext
+}
+
+/// Follows the key schedule of RFC 8448's first handshake from the X25519 shared secret. Its
+/// cipher suite is AES-128-GCM, so the traffic keys are 16 bytes rather than `TlsStream`'s 32.
+#[cfg(feature = "net")]
+fn tls_key_schedule() -> bool {
+    let shared = key("8bd4054fb55b9d63fdfbacf9f04b9f0d35e6d63f537563efd46272900f89492d");
+    // ClientHello and ServerHello
+    let hello_hash = key("860c06edc07858ee8e78f0e7428c58edd6b43f2ca3e6e95f02ed063cf0e1cad8");
+    // ClientHello up to CertificateVerify
+    let verify_hash = key("edb7725fa7a3473b031ec8ef65a2485493900138a2b91291407d7951a06110ed");
+
+    let handshake_secret = tls::handshake_secret(&shared);
+    let client_secret = tls::derive_secret(&handshake_secret, b"c hs traffic", &hello_hash);
+    let server_secret = tls::derive_secret(&handshake_secret, b"s hs traffic", &hello_hash);
+    let expand = |secret: &[u8; 32], label: &[u8], len: usize| {
+        let mut out = alloc::vec![0; len];
+        tls::expand_label(secret, label, b"", &mut out);
+        out
+    };
+    handshake_secret[..] == hex("1dc826e93606aa6fdc0aadc12f741b01046aa6b99f691ed221a9f0ca043fbeac")
+        && client_secret[..]
+            == hex("b3eddb126e067f35a780b3abf45e2d8f3b1a950738f52e9600746a0e27a55a21")
+        && server_secret[..]
+            == hex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38")
+        && expand(&client_secret, b"key", 16) == hex("dbfaa693d1762c5b666af5d950258d01")
+        && expand(&client_secret, b"iv", 12) == hex("5bd3c71b836e0b76bb73265f")
+        && expand(&server_secret, b"key", 16) == hex("3fce516009c21727d0f2e4e86ee403bc")
+        && expand(&server_secret, b"iv", 12) == hex("5d313eb2671276ee13000b30")
+        && expand(&client_secret, b"finished", 32)
+            == hex("b80ad01015fb2f0bd65ff7d4da5d6bf83f84821d1f87fdc7d3c75b5a7b42d9c4")
+        && tls::finished_mac(&server_secret, &verify_hash)[..]
+            == hex("9b9b141d906337fbd2cbdce71df4deda4ab42c309572cb7fffee5454b78f0718")
+        && tls::master_secret(&handshake_secret)[..]
+            == hex("18df06843d13a08bf2a449844c5f8a478001bc4d4c627984d5a41da8d0402919")
+}
+
+fn ed25519_verifies(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let signature: [u8; ed25519::SIGNATURE_SIZE] = hex(signature).try_into().unwrap();
+    ed25519::verify(&key(public_key), message, &signature)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    boot::run_until(bootinfo::from_bootloader(boot_info), "interrupts");
+    if let Some(port) = SerialPort::init(COM1) {
+        console::attach(Box::new(Terminal(port)));
+    }
+    let mut failed = 0;
+    for case in CASES {
+        if (case.check)() {
+            println!("[ok] {}", case.name);
+        } else {
+            println!("[failed] {}", case.name);
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => exit_qemu(QemuExitCode::Success),
+        _ => exit_qemu(QemuExitCode::Failed),
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[failed] {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,137 @@
+//! Reads the test disk back through the VFS: `build.rs` makes a FAT32 image of
+//! `tests/fixtures/disk`, the kernel finds it as the virtio disk `vda` and mounts it on `/mnt/vda`
+//! during the drivers phase, and every fixture has to come back the way it is on the host.
+//!
+//! A test kernel like `crypto`: it prints each check's result on COM1 and ends QEMU with
+//! `Success` only if all of them passed. `cargo test --test disk` runs it. The last checks write
+//! files and remove them again, so the disk is left as `build.rs` made it.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::fs::{self, FileKind};
+use rust_os::power::{exit_qemu, QemuExitCode};
+use rust_os::{boot, bootinfo, println};
+
+const MOUNT_POINT: &str = "/mnt/vda";
+
+struct Check {
+    name: &'static str,
+    check: fn() -> bool,
+}
+
+static CHECKS: &[Check] = &[
+    Check {
+        name: "vda is mounted as FAT32",
+        check: || {
+            fs::mounts()
+                .iter()
+                .any(|(path, name)| path == MOUNT_POINT && *name == "fat32")
+        },
+    },
+    Check {
+        name: "the root lists the fixtures",
+        check: || {
+            let names = names(MOUNT_POINT);
+            names.iter().any(|name| name == "hello.txt") && names.iter().any(|name| name == "docs")
+        },
+    },
+    Check {
+        name: "hello.txt",
+        check: || fixture("hello.txt", include_bytes!("fixtures/disk/hello.txt")),
+    },
+    Check {
+        name: "a long name in a subdirectory",
+        check: || {
+            fixture(
+                "docs/Long file name.txt",
+                include_bytes!("fixtures/disk/docs/Long file name.txt"),
+            )
+        },
+    },
+    Check {
+        name: "an empty file",
+        check: || fixture("docs/empty", b""),
+    },
+    Check {
+        name: "docs lists exactly its fixtures",
+        check: || {
+            let mut names = names("/mnt/vda/docs");
+            names.sort();
+            names == ["Long file name.txt", "empty"]
+        },
+    },
+    Check {
+        name: "write, read back and remove a file",
+        check: || {
+            let path = "/mnt/vda/docs/Written by the kernel.txt";
+            let contents: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+            let written = fs::write(path, &contents).is_ok()
+                && fs::read(path).is_ok_and(|read| read == contents);
+            fs::remove(path).is_ok() && written && names("/mnt/vda/docs").len() == 2
+        },
+    },
+    Check {
+        name: "times set on a file are kept",
+        check: || {
+            let path = "/mnt/vda/docs/Stamped.txt";
+            // 2001-09-09 01:46:40 UTC, an even second as FAT needs; of the access only the day stays
+            let time = 1_000_000_000;
+            let kept = fs::write(path, b"stamped").is_ok()
+                && fs::set_times(path, Some(time), Some(time)).is_ok()
+                && fs::lookup(path)
+                    .and_then(|inode| inode.metadata())
+                    .is_ok_and(|metadata| {
+                        metadata.mtime == time && metadata.atime == time - time % 86_400
+                    });
+            fs::remove(path).is_ok() && kept
+        },
+    },
+];
+
+/// The names in the directory at `path`, none if it can't be read
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .map(|entries| entries.into_iter().map(|entry| entry.name).collect())
+        .unwrap_or_default()
+}
+
+/// Whether the file `path` below the mount point holds `expected`
+fn fixture(path: &str, expected: &[u8]) -> bool {
+    let path = alloc::format!("{}/{}", MOUNT_POINT, path);
+    let is_file = fs::lookup(&path)
+        .and_then(|inode| inode.metadata())
+        .is_ok_and(|metadata| metadata.kind == FileKind::File);
+    is_file && fs::read(&path).is_ok_and(|contents| contents == expected)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    boot::run_until(bootinfo::from_bootloader(boot_info), "drivers");
+    let mut failed = 0;
+    for check in CHECKS {
+        if (check.check)() {
+            println!("[ok] {}", check.name);
+        } else {
+            println!("[failed] {}", check.name);
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => exit_qemu(QemuExitCode::Success),
+        _ => exit_qemu(QemuExitCode::Failed),
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[failed] {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,331 @@
+//! Triggers each class of CPU exception and checks that the kernel's handler dealt with it.
+//!
+//! A test kernel: it boots through the interrupts phase (see `boot::run_until`), prints its
+//! results on COM1 and ends QEMU through the `isa-debug-exit` device, with `Success` only if
+//! every case passed. `cargo test --test exceptions` runs it, with the QEMU arguments in
+//! `Cargo.toml`.
+//!
+//! The faults a kernel survives are raised by small programs in ring 3, which must come back from
+//! `usermode::run` with the right `UserExit`: a divide error, an invalid opcode, a privileged
+//! instruction, a breakpoint, and page faults on a page that isn't mapped, on a read-only page and
+//! on the unmapped page below the stack. One more program's misaligned `movaps` fault too, but are
+//! emulated, so it must get on to the breakpoint after them. Last the kernel overflows a stack of
+//! its own, which can only end in the double fault handler's panic; the panic handler checks that
+//! it's the report of a stack overflow.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rust_os::console::{self, Terminal};
+use rust_os::drivers::serial::{SerialPort, COM1};
+use rust_os::power::{exit_qemu, QemuExitCode};
+use rust_os::usermode::{self, Transition, UserExit};
+use rust_os::{boot, bootinfo, memory, println};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+global_asm!(
+    r#"
+    .pushsection .text.exceptions_user, "ax"
+    .global exceptions_user_start
+    exceptions_user_start:
+
+    .global exceptions_divide
+    exceptions_divide:
+        xorl %ecx, %ecx
+        divl %ecx
+        ud2
+
+    .global exceptions_invalid_opcode
+    exceptions_invalid_opcode:
+        ud2
+
+    # misaligned aligned moves, which the kernel does for the program
+    .global exceptions_misaligned
+    exceptions_misaligned:
+        movaps -31(%rsp), %xmm0
+        movaps %xmm0, -63(%rsp)
+        int3
+        ud2
+
+    .global exceptions_privileged
+    exceptions_privileged:
+        hlt
+        ud2
+
+    .global exceptions_breakpoint
+    exceptions_breakpoint:
+        int3
+        ud2
+
+    # (address): reads it
+    .global exceptions_read
+    exceptions_read:
+        movq (%rdi), %rax
+        ud2
+
+    # (address): writes to it
+    .global exceptions_write
+    exceptions_write:
+        movq %rax, (%rdi)
+        ud2
+
+    .global exceptions_push
+    exceptions_push:
+        pushq %rax
+        jmp exceptions_push
+
+    .global exceptions_user_end
+    exceptions_user_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static exceptions_user_start: u8;
+    static exceptions_divide: u8;
+    static exceptions_invalid_opcode: u8;
+    static exceptions_misaligned: u8;
+    static exceptions_privileged: u8;
+    static exceptions_breakpoint: u8;
+    static exceptions_read: u8;
+    static exceptions_write: u8;
+    static exceptions_push: u8;
+    static exceptions_user_end: u8;
+}
+
+/// Where the programs' code and stack are mapped, and an address nothing is mapped at
+const CODE: u64 = memory::USER_START;
+const STACK: u64 = memory::USER_START + 0x10_0000;
+const STACK_SIZE: u64 = 4096 * 4;
+const UNMAPPED: u64 = memory::USER_START + 0x80_0000;
+
+/// A program that faults in ring 3, and what it must leave ring 3 with
+struct Case {
+    name: &'static str,
+    entry: fn() -> *const u8,
+    arg: u64,
+    check: fn(entry: u64, exit: UserExit) -> bool,
+}
+
+static CASES: &[Case] = &[
+    Case {
+        name: "divide error",
+        entry: || ptr::addr_of!(exceptions_divide),
+        arg: 0,
+        check: |entry, exit| exit == UserExit::DivideError { rip: entry + 2 },
+    },
+    Case {
+        name: "invalid opcode",
+        entry: || ptr::addr_of!(exceptions_invalid_opcode),
+        arg: 0,
+        check: |entry, exit| {
+            matches!(
+                exit,
+                UserExit::InvalidOpcode { rip, instruction }
+                    if rip == entry && instruction.bytes().starts_with(&[0x0f, 0x0b])
+            )
+        },
+    },
+    Case {
+        name: "misaligned movaps",
+        entry: || ptr::addr_of!(exceptions_misaligned),
+        arg: 0,
+        check: |entry, exit| exit == UserExit::Breakpoint { rip: entry + 11 },
+    },
+    Case {
+        name: "privileged instruction",
+        entry: || ptr::addr_of!(exceptions_privileged),
+        arg: 0,
+        check: |entry, exit| {
+            exit == UserExit::GeneralProtection {
+                error_code: 0,
+                rip: entry,
+            }
+        },
+    },
+    Case {
+        name: "breakpoint",
+        entry: || ptr::addr_of!(exceptions_breakpoint),
+        arg: 0,
+        check: |entry, exit| exit == UserExit::Breakpoint { rip: entry + 1 },
+    },
+    Case {
+        name: "page fault on an unmapped page",
+        entry: || ptr::addr_of!(exceptions_read),
+        arg: UNMAPPED,
+        check: |_, exit| page_fault(exit, UNMAPPED, PageFaultErrorCode::USER_MODE),
+    },
+    Case {
+        name: "page fault on a read-only page",
+        entry: || ptr::addr_of!(exceptions_write),
+        arg: CODE,
+        check: |_, exit| {
+            page_fault(
+                exit,
+                CODE,
+                PageFaultErrorCode::USER_MODE
+                    | PageFaultErrorCode::CAUSED_BY_WRITE
+                    | PageFaultErrorCode::PROTECTION_VIOLATION,
+            )
+        },
+    },
+    Case {
+        name: "page fault below the stack",
+        entry: || ptr::addr_of!(exceptions_push),
+        arg: 0,
+        check: |_, exit| {
+            page_fault(
+                exit,
+                STACK - 8,
+                PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE,
+            )
+        },
+    },
+];
+
+/// Whether `exit` is a page fault at `address` with the error code `expected`
+fn page_fault(exit: UserExit, address: u64, expected: PageFaultErrorCode) -> bool {
+    matches!(
+        exit,
+        UserExit::PageFault { address: at, error_code, .. }
+            if at == address && error_code == expected.bits()
+    )
+}
+
+/// Whether the kernel stack overflow is underway, the one case that's meant to panic
+static OVERFLOWING: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    boot::run_until(bootinfo::from_bootloader(boot_info), "interrupts");
+    if let Some(port) = SerialPort::init(COM1) {
+        console::attach(Box::new(Terminal(port)));
+    }
+    if let Err(err) = map_programs() {
+        fail(format_args!("{}", err));
+    }
+    for case in CASES {
+        let entry = user_address((case.entry)());
+        let exit = usermode::run(
+            VirtAddr::new(entry),
+            VirtAddr::new(STACK + STACK_SIZE),
+            case.arg,
+            Transition::Iretq,
+        );
+        if !(case.check)(entry, exit) {
+            fail(format_args!(
+                "{}: the program left ring 3 with: {}",
+                case.name, exit
+            ));
+        }
+        println!("[ok] {}: {}", case.name, exit);
+    }
+    OVERFLOWING.store(true, Ordering::SeqCst);
+    overflow_kernel_stack();
+}
+
+/// Copies the programs to user memory, with a stack below which nothing is mapped
+fn map_programs() -> Result<(), &'static str> {
+    let code = unsafe {
+        let start = ptr::addr_of!(exceptions_user_start);
+        let length = ptr::addr_of!(exceptions_user_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    };
+    let stack = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::map_user(
+        VirtAddr::new(CODE),
+        code.len() as u64,
+        code,
+        PageTableFlags::empty(),
+    )
+    .and_then(|()| memory::map_user(VirtAddr::new(STACK), STACK_SIZE, &[], stack))
+    .map_err(|_| "failed to map the test programs")
+}
+
+/// Address of a program in the user mapping
+fn user_address(symbol: *const u8) -> u64 {
+    CODE + (symbol as u64 - ptr::addr_of!(exceptions_user_start) as u64)
+}
+
+/// Switches to a fresh kernel stack, with the guard page below it, and recurses until it's full
+fn overflow_kernel_stack() -> ! {
+    let top = match memory::allocate_kernel_stack(4096 * 4) {
+        Ok(top) => top,
+        Err(_) => fail(format_args!("failed to allocate a kernel stack")),
+    };
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {recurse}",
+            top = in(reg) top.as_u64(),
+            recurse = sym recurse,
+            in("rdi") 0,
+            options(noreturn)
+        )
+    }
+}
+
+/// Calls itself until the stack runs out; the addition keeps it from being a tail call
+#[allow(unconditional_recursion)]
+extern "C" fn recurse(depth: u64) -> u64 {
+    recurse(black_box(depth) + 1) + black_box(1)
+}
+
+fn fail(args: fmt::Arguments) -> ! {
+    println!("[failed] {}", args);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+/// The start of a panic message, enough to tell which report it is
+struct Message {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let take = text.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + take].copy_from_slice(&text.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+impl Message {
+    fn contains(&self, text: &str) -> bool {
+        self.bytes[..self.len]
+            .windows(text.len())
+            .any(|window| window == text.as_bytes())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !OVERFLOWING.load(Ordering::SeqCst) {
+        fail(format_args!("{}", info));
+    }
+    let mut message = Message {
+        bytes: [0; 128],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+    if !message.contains("KERNEL STACK OVERFLOW") {
+        fail(format_args!("kernel stack overflow: {}", info));
+    }
+    println!("[ok] kernel stack overflow: reported by the double fault handler");
+    exit_qemu(QemuExitCode::Success);
+}
@@ -0,0 +1,188 @@
+//! Runs a few fixed scenarios and compares what each prints with its expectation in
+//! `tests/fixtures/golden`.
+//!
+//! A test kernel like `disk`: a console backend keeps a copy of each scenario's output, which is
+//! normalized the way `tools/golden.py` does it and compared line by line; the kernel prints
+//! `[ok]` or `[failed]` with the lines that differ for each, and ends QEMU with `Success` only if
+//! all of them matched. The output also goes to COM1, between a `golden: <name>` and a
+//! `golden: end` line, so `tools/golden.py` can show a diff or `--bless` new expectations.
+//! The kernel boots through the drivers phase, whose serial driver is what puts the console on
+//! COM1, so the scenarios can print the driver table and run shell commands.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::console::{self, Backend};
+use rust_os::drivers::ps2::{keyboard, keymap};
+use rust_os::power::{exit_qemu, QemuExitCode};
+use rust_os::vga_buffer::Color;
+use rust_os::{boot, bootinfo, println, shell};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// A scenario, printed under its name, and `tests/fixtures/golden/<name>.txt`
+struct Scenario {
+    name: &'static str,
+    run: fn(),
+    expected: &'static str,
+}
+
+static SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "drivers",
+        run: || shell::execute("drivers"),
+        expected: include_str!("fixtures/golden/drivers.txt"),
+    },
+    Scenario {
+        name: "errors",
+        run: errors,
+        expected: include_str!("fixtures/golden/errors.txt"),
+    },
+    Scenario {
+        name: "keyboard",
+        run: keyboard,
+        expected: include_str!("fixtures/golden/keyboard.txt"),
+    },
+];
+
+/// What was printed since the current scenario started, `None` between scenarios
+static CAPTURED: Mutex<Option<String>> = Mutex::new(None);
+
+/// A console backend that keeps a copy of the output in `CAPTURED`, without colors
+struct Capture;
+
+impl Backend for Capture {
+    fn write(&mut self, text: &str) {
+        if let Some(captured) = CAPTURED.lock().as_mut() {
+            captured.push_str(text);
+        }
+    }
+
+    fn set_colors(&mut self, _foreground: Color, _background: Color) {}
+
+    fn clear(&mut self) {}
+}
+
+/// The lines of `text` as `tools/golden.py` compares them: without carriage returns, ANSI
+/// escapes or trailing blanks. The kernel log timestamps it also drops never reach a backend.
+fn normalize(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| {
+            let mut normalized = String::new();
+            let mut chars = line.chars();
+            while let Some(ch) = chars.next() {
+                match ch {
+                    '\r' => {}
+                    // an escape runs up to its final letter
+                    '\x1b' => {
+                        let _ = chars.by_ref().find(|ch| ch.is_ascii_alphabetic());
+                    }
+                    ch => normalized.push(ch),
+                }
+            }
+            normalized.truncate(normalized.trim_end().len());
+            normalized
+        })
+        .collect()
+}
+
+/// Runs `scenario` and prints whether its output matched, with the lines that didn't
+fn check(scenario: &Scenario) -> bool {
+    println!("golden: {}", scenario.name);
+    without_interrupts(|| *CAPTURED.lock() = Some(String::new()));
+    (scenario.run)();
+    let captured = without_interrupts(|| CAPTURED.lock().take()).unwrap_or_default();
+    // so that `tools/golden.py` doesn't take the verdict for part of the output
+    println!("golden: end");
+    let actual = normalize(&captured);
+    let expected = normalize(scenario.expected);
+    if actual == expected {
+        println!("[ok] {}", scenario.name);
+        return true;
+    }
+    println!("[failed] {}", scenario.name);
+    for line in 0..actual.len().max(expected.len()) {
+        let (expected, actual) = (expected.get(line), actual.get(line));
+        if expected != actual {
+            println!("  line {}: expected {:?}", line + 1, expected);
+            println!("  line {}: got      {:?}", line + 1, actual);
+        }
+    }
+    false
+}
+
+/// Commands that fail, for the messages they fail with
+fn errors() {
+    for line in [
+        "nosuch",
+        "exec",
+        "keymap colemak",
+        "keymap us uk",
+        "peek",
+        "peek 0 3",
+        "sysctl no.such",
+    ] {
+        shell::execute(line);
+    }
+}
+
+/// The same keys typed on every layout, then the events of a few keys that type nothing
+fn keyboard() {
+    // q w e r t y, shift+2, the key left of 1, e, AltGr+q
+    const KEYS: &[u8] = &[
+        0x10, 0x90, 0x11, 0x91, 0x12, 0x92, 0x13, 0x93, 0x14, 0x94, 0x15, 0x95, 0x2a, 0x03, 0x83,
+        0xaa, 0x29, 0xa9, 0x12, 0x92, 0xe0, 0x38, 0x10, 0x90, 0xe0, 0xb8,
+    ];
+    for name in ["us", "uk", "de", "dvorak"] {
+        keymap::set(name).unwrap();
+        let mut decoder = keyboard::Decoder::new();
+        let typed: String = KEYS.iter().flat_map(|&code| decoder.feed(code)).collect();
+        println!("{}: {:?}", name, typed);
+    }
+    keymap::set("us").unwrap();
+
+    // control+c, then the up arrow
+    let mut decoder = keyboard::Decoder::new();
+    for &code in &[0x1d, 0x2e, 0xae, 0x9d, 0xe0, 0x48, 0xe0, 0xc8] {
+        if let Some(event) = decoder.key(code) {
+            let typed: String = event.typed.collect();
+            println!(
+                "{:04x} {} ctrl={} {:?}",
+                event.key,
+                if event.pressed { "down" } else { "up" },
+                event.modifiers.ctrl,
+                typed
+            );
+        }
+    }
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    boot::run_until(bootinfo::from_bootloader(boot_info), "drivers");
+    console::attach(Box::new(Capture));
+    let mut failed = 0;
+    for scenario in SCENARIOS {
+        if !check(scenario) {
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => exit_qemu(QemuExitCode::Success),
+        _ => exit_qemu(QemuExitCode::Failed),
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[failed] {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
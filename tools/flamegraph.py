@@ -0,0 +1,55 @@
+#!/usr/bin/env python3
+"""Folds the profiler's samples in a serial log into stacks for a flame graph, see src/profile.rs.
+
+Usage: flamegraph.py [<log>]
+
+The log is the kernel's serial output, read from stdin without a file. Each `profile: a;b;c`
+line is one sample; identical stacks are counted and printed as `a;b;c <count>`, most frequent
+first, which is what flamegraph.pl and speedscope read. Lines the kernel printed about lost
+samples are counted and reported on stderr.
+"""
+
+import collections
+import re
+import sys
+
+ESCAPE = re.compile(r"\x1b\[[0-9;?]*[A-Za-z]")
+SAMPLE = re.compile(r"^profile: (\S.*)$")
+LOST = re.compile(r"^profile: cpu \d+ lost (\d+) events$")
+
+
+def fold(log):
+    """({stack: count}, events lost) of the samples in the log"""
+    stacks = collections.Counter()
+    lost = 0
+    for line in log.splitlines():
+        line = ESCAPE.sub("", line).replace("\r", "").rstrip()
+        missed = LOST.match(line)
+        if missed:
+            lost += int(missed.group(1))
+            continue
+        sample = SAMPLE.match(line)
+        if sample:
+            stacks[sample.group(1)] += 1
+    return stacks, lost
+
+
+def main(args):
+    if len(args) > 1:
+        sys.exit(__doc__.splitlines()[2])
+    if args:
+        with open(args[0], encoding="utf-8", errors="replace") as file:
+            log = file.read()
+    else:
+        log = sys.stdin.buffer.read().decode("utf-8", errors="replace")
+    stacks, lost = fold(log)
+    if not stacks:
+        sys.exit("flamegraph: no samples in the log; was `profile stream on`?")
+    for stack, count in stacks.most_common():
+        print(f"{stack} {count}")
+    if lost:
+        print(f"flamegraph: the kernel lost {lost} events to full trace rings", file=sys.stderr)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])
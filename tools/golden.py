@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""Compares the scenarios the `golden` test kernel prints with tests/fixtures/golden.
+
+Usage: cargo test --test golden | golden.py [--bless] [<log>]
+
+The log is the kernel's serial output, read from stdin without a file. Everything between a
+`golden: <name>` line and the next such line is the output of scenario <name>, which must match
+tests/fixtures/golden/<name>.txt once both are normalized: ANSI escapes, carriage returns,
+kernel log timestamps and trailing blanks are dropped. With --bless the expectations are
+rewritten from the log instead.
+
+Exits with 1 if a scenario's output differs, has no expectation, or an expectation's scenario
+didn't run, e.g. because the kernel panicked halfway. Expectations are recorded from a real run
+with --bless, so a new scenario fails until one is.
+
+The kernel compares the scenarios itself too and fails the test on a mismatch; this is for the
+diff of each scenario that changed, and for recording new expectations.
+"""
+
+import difflib
+import os
+import re
+import sys
+
+EXPECTATIONS = os.path.join(os.path.dirname(__file__), "..", "tests", "fixtures", "golden")
+MARKER = re.compile(r"^golden: (\S+)$")
+END = "end"
+
+ESCAPE = re.compile(r"\x1b\[[0-9;?]*[A-Za-z]")
+# `[    1.25] ` in front of kernel log lines, see klog::Record
+TIMESTAMP = re.compile(r"^\[\s*\d+\.\d+\] ")
+
+
+def normalize(line):
+    line = ESCAPE.sub("", line).replace("\r", "")
+    return TIMESTAMP.sub("", line).rstrip()
+
+
+def scenarios(log):
+    """{name: [line, ...]} of the scenarios in the log, in the order they ran"""
+    found = {}
+    current = None
+    for line in log.splitlines():
+        line = normalize(line)
+        marker = MARKER.match(line)
+        if marker:
+            current = None if marker.group(1) == END else found.setdefault(marker.group(1), [])
+        elif current is not None:
+            current.append(line)
+    return found
+
+
+def expectation_path(name):
+    return os.path.join(EXPECTATIONS, name + ".txt")
+
+
+def expected(name):
+    with open(expectation_path(name), encoding="utf-8") as file:
+        return [normalize(line) for line in file.read().splitlines()]
+
+
+def bless(found):
+    os.makedirs(EXPECTATIONS, exist_ok=True)
+    for name, lines in found.items():
+        with open(expectation_path(name), "w", encoding="utf-8") as file:
+            file.write("".join(line + "\n" for line in lines))
+        print(f"golden: {name}: recorded")
+
+
+def compare(found):
+    failed = False
+    for name, lines in found.items():
+        if not os.path.exists(expectation_path(name)):
+            print(f"golden: {name}: no expectation, record one with --bless")
+            failed = True
+            continue
+        diff = list(difflib.unified_diff(
+            expected(name), lines, f"expected/{name}", f"actual/{name}", lineterm=""))
+        if diff:
+            print(f"golden: {name}: differs")
+            print("\n".join(diff))
+            failed = True
+        else:
+            print(f"golden: {name}: ok")
+    for file in sorted(os.listdir(EXPECTATIONS) if os.path.isdir(EXPECTATIONS) else []):
+        name, extension = os.path.splitext(file)
+        if extension == ".txt" and name not in found:
+            print(f"golden: {name}: didn't run")
+            failed = True
+    return not failed
+
+
+def main(args):
+    blessing = "--bless" in args
+    args = [arg for arg in args if arg != "--bless"]
+    if len(args) > 1:
+        sys.exit(__doc__.splitlines()[2])
+    if args:
+        with open(args[0], encoding="utf-8", errors="replace") as file:
+            log = file.read()
+    else:
+        log = sys.stdin.buffer.read().decode("utf-8", errors="replace")
+    found = scenarios(log)
+    if not found:
+        sys.exit("golden: no scenarios in the log; did the kernel boot?")
+    if blessing:
+        bless(found)
+    elif not compare(found):
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])
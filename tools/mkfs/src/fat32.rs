@@ -0,0 +1,288 @@
+//! FAT32 images the kernel's `fs::fat32` mounts, and so does every other system.
+//!
+//! The volume has two FATs and an FSInfo sector, with backups of both at sector 6, and clusters
+//! of one sector up to 260 MiB, larger ones above as usual. Files and directories each get a
+//! contiguous run of clusters. Names that aren't plain 8.3 names get a long name and a `NAME~1`
+//! alias, by the kernel's own code for that.
+
+use super::{invalid, no_space, Node, Tree, SECTOR_SIZE};
+use crate::endian::{write_u16_le, write_u32_le};
+use layout::{
+    ATTR_ARCHIVE, ATTR_DIRECTORY, END_OF_CHAIN, FIRST_CLUSTER, FSINFO_FREE_COUNT,
+    FSINFO_LEAD_SIGNATURE, FSINFO_NEXT_FREE, FSINFO_STRUCT_OFFSET, FSINFO_STRUCT_SIGNATURE,
+    MIN_CLUSTERS,
+};
+use names::{
+    exact_short_name, generate_short_name, is_forbidden_char, long_entry_count, unix_to_fat,
+    write_long_entries, ENTRY_SIZE, MAX_NAME_LEN,
+};
+use std::io;
+
+/// The kernel's rules for names and timestamps, shared so the two can't drift apart; public so
+/// images can be read back with them
+#[path = "../../../src/fs/fat32/names.rs"]
+pub mod names;
+
+/// The kernel's FAT32 constants
+#[path = "../../../src/fs/fat32/layout.rs"]
+pub mod layout;
+
+const MEDIA_FIXED: u8 = 0xF8;
+
+const RESERVED_SECTORS: u64 = 32;
+const FAT_COUNT: u64 = 2;
+const FSINFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+
+const SECTOR: u64 = SECTOR_SIZE as u64;
+
+struct Writer<'a> {
+    image: &'a mut [u8],
+    cluster_size: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    data_start: u64,
+    cluster_count: u64,
+    next_cluster: u32,
+    /// FAT date and time of every entry
+    date: u16,
+    time: u16,
+}
+
+/// Formats `image` as FAT32 holding `tree`
+pub fn format(image: &mut [u8], tree: &Tree, time: u64) -> io::Result<()> {
+    let total_sectors = image.len() as u64 / SECTOR;
+    if total_sectors > u32::MAX as u64 {
+        return Err(invalid("FAT32 volumes end at 2 TiB".into()));
+    }
+    let sectors_per_cluster: u64 = match total_sectors * SECTOR {
+        size if size < 260 << 20 => 1,
+        size if size < 8 << 30 => 8,
+        size if size < 16 << 30 => 16,
+        size if size < 32 << 30 => 32,
+        _ => 64,
+    };
+    // the FATs take room from the clusters they describe, so settle their size by trying
+    let mut fat_sectors = 1;
+    let cluster_count = loop {
+        let data_start = RESERVED_SECTORS + FAT_COUNT * fat_sectors;
+        let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+        let needed = ((clusters + FIRST_CLUSTER as u64) * 4).div_ceil(SECTOR);
+        if needed <= fat_sectors {
+            break clusters;
+        }
+        fat_sectors = needed;
+    };
+    if cluster_count < MIN_CLUSTERS as u64 {
+        return Err(invalid("FAT32 needs an image of at least 33 MiB".into()));
+    }
+    let data_start = RESERVED_SECTORS + FAT_COUNT * fat_sectors;
+
+    let boot = &mut image[..SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"RUST_OS ");
+    write_u16_le(boot, 11, SECTOR_SIZE as u16);
+    boot[13] = sectors_per_cluster as u8;
+    write_u16_le(boot, 14, RESERVED_SECTORS as u16);
+    boot[16] = FAT_COUNT as u8;
+    boot[21] = MEDIA_FIXED;
+    write_u16_le(boot, 24, 32); // sectors per track and heads, for BIOSes that still ask
+    write_u16_le(boot, 26, 64);
+    write_u32_le(boot, 32, total_sectors as u32);
+    write_u32_le(boot, 36, fat_sectors as u32);
+    write_u32_le(boot, 44, FIRST_CLUSTER);
+    write_u16_le(boot, 48, FSINFO_SECTOR as u16);
+    write_u16_le(boot, 50, BACKUP_BOOT_SECTOR as u16);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    write_u32_le(boot, 67, time as u32); // the volume serial number, usually made from the time
+    boot[71..82].copy_from_slice(b"NO NAME    ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    let (date, time) = unix_to_fat(time);
+    let mut writer = Writer {
+        image,
+        cluster_size: sectors_per_cluster * SECTOR,
+        fat_start: RESERVED_SECTORS,
+        fat_sectors,
+        data_start,
+        cluster_count,
+        next_cluster: FIRST_CLUSTER,
+        date,
+        time,
+    };
+    writer.set_fat(0, 0x0FFF_FF00 | MEDIA_FIXED as u32);
+    writer.set_fat(1, END_OF_CHAIN);
+    let root = writer.allocate(directory_size(tree)?)?;
+    writer.directory(root, None, tree)?;
+
+    let used = (writer.next_cluster - FIRST_CLUSTER) as u64;
+    let info = &mut writer.image[(FSINFO_SECTOR * SECTOR) as usize..][..SECTOR_SIZE];
+    write_u32_le(info, 0, FSINFO_LEAD_SIGNATURE);
+    write_u32_le(info, FSINFO_STRUCT_OFFSET, FSINFO_STRUCT_SIGNATURE);
+    write_u32_le(info, FSINFO_FREE_COUNT, (cluster_count - used) as u32);
+    write_u32_le(info, FSINFO_NEXT_FREE, writer.next_cluster);
+    write_u32_le(info, 508, FSINFO_TRAIL_SIGNATURE);
+    let backup = (BACKUP_BOOT_SECTOR * SECTOR) as usize;
+    writer.image.copy_within(..2 * SECTOR_SIZE, backup);
+    Ok(())
+}
+
+impl Writer<'_> {
+    /// Chains the next clusters enough for `size` bytes, at least one; returns the first
+    fn allocate(&mut self, size: u64) -> io::Result<u32> {
+        let count = size.div_ceil(self.cluster_size).max(1);
+        let first = self.next_cluster;
+        if (first - FIRST_CLUSTER) as u64 + count > self.cluster_count {
+            return Err(no_space("the files"));
+        }
+        for cluster in first..first + count as u32 {
+            let next = match cluster + 1 == first + count as u32 {
+                true => END_OF_CHAIN,
+                false => cluster + 1,
+            };
+            self.set_fat(cluster, next);
+        }
+        self.next_cluster += count as u32;
+        Ok(first)
+    }
+
+    /// Sets entry `cluster` of both FATs
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        for fat in 0..FAT_COUNT {
+            let offset = ((self.fat_start + fat * self.fat_sectors) * SECTOR) as usize;
+            write_u32_le(self.image, offset + cluster as usize * 4, value);
+        }
+    }
+
+    /// Copies `contents` to the clusters starting at `first`, which were allocated for them
+    fn write(&mut self, first: u32, contents: &[u8]) {
+        let offset = self.data_start * SECTOR + (first - FIRST_CLUSTER) as u64 * self.cluster_size;
+        let offset = offset as usize;
+        self.image[offset..offset + contents.len()].copy_from_slice(contents);
+    }
+
+    /// Writes the directory `tree` to the clusters at `cluster`, allocated for it already, and
+    /// everything in it; `parent` is the cluster of the directory it's in, `None` for the root
+    fn directory(&mut self, cluster: u32, parent: Option<u32>, tree: &Tree) -> io::Result<()> {
+        let mut slots = Vec::new();
+        if let Some(parent) = parent {
+            // `..` of a directory in the root points at cluster 0 by convention
+            let parent = if parent == FIRST_CLUSTER { 0 } else { parent };
+            slots.extend_from_slice(&self.short_entry(
+                b".          ",
+                0,
+                ATTR_DIRECTORY,
+                cluster,
+                0,
+            ));
+            slots.extend_from_slice(&self.short_entry(
+                b"..         ",
+                0,
+                ATTR_DIRECTORY,
+                parent,
+                0,
+            ));
+        }
+        let mut taken = Vec::new();
+        for (name, node) in &tree.entries {
+            let (short, nt_flags, long) = names(name, &mut taken)?;
+            let (attributes, first, size) = match node {
+                Node::File(contents) if contents.is_empty() => (ATTR_ARCHIVE, 0, 0),
+                Node::File(contents) => {
+                    let first = self.allocate(contents.len() as u64)?;
+                    self.write(first, contents);
+                    (ATTR_ARCHIVE, first, contents.len())
+                }
+                Node::Directory(subtree) => {
+                    let first = self.allocate(directory_size(subtree)? + 2 * ENTRY_SIZE as u64)?;
+                    self.directory(first, Some(cluster), subtree)?;
+                    (ATTR_DIRECTORY, first, 0)
+                }
+            };
+            let size = u32::try_from(size)
+                .map_err(|_| invalid(format!("{}: FAT32 files end at 4 GiB", name)))?;
+            if let Some(units) = long {
+                let start = slots.len();
+                slots.resize(start + long_entry_count(&units) * ENTRY_SIZE, 0);
+                write_long_entries(&units, &short, &mut slots[start..]);
+            }
+            slots.extend_from_slice(&self.short_entry(&short, nt_flags, attributes, first, size));
+        }
+        self.write(cluster, &slots);
+        Ok(())
+    }
+
+    fn short_entry(
+        &self,
+        short: &[u8; 11],
+        nt_flags: u8,
+        attributes: u8,
+        first: u32,
+        size: u32,
+    ) -> [u8; ENTRY_SIZE] {
+        let mut slot = [0; ENTRY_SIZE];
+        slot[..11].copy_from_slice(short);
+        slot[11] = attributes;
+        slot[12] = nt_flags;
+        write_u16_le(&mut slot, 14, self.time);
+        write_u16_le(&mut slot, 16, self.date);
+        write_u16_le(&mut slot, 18, self.date);
+        write_u16_le(&mut slot, 20, (first >> 16) as u16);
+        write_u16_le(&mut slot, 22, self.time);
+        write_u16_le(&mut slot, 24, self.date);
+        write_u16_le(&mut slot, 26, first as u16);
+        write_u32_le(&mut slot, 28, size);
+        slot
+    }
+}
+
+/// Bytes the entries of `tree` take, long names included
+fn directory_size(tree: &Tree) -> io::Result<u64> {
+    let mut taken = Vec::new();
+    let mut slots = 0;
+    for name in tree.entries.keys() {
+        let (_, _, long) = names(name, &mut taken)?;
+        slots += 1 + long.as_deref().map_or(0, long_entry_count);
+    }
+    Ok((slots * ENTRY_SIZE) as u64)
+}
+
+/// The short name, its NT flags and the long name, if it needs one, for an entry called `name`
+/// in a directory whose short names so far are `taken`, which the short name is added to
+fn names(name: &str, taken: &mut Vec<[u8; 11]>) -> io::Result<([u8; 11], u8, Option<Vec<u16>>)> {
+    validate_name(name)?;
+    let (short, flags, long) = match exact_short_name(name) {
+        Some((short, flags)) => (short, flags, None),
+        None => {
+            let short = generate_short_name(name, |candidate| taken.contains(candidate));
+            (short, 0, Some(name.encode_utf16().collect()))
+        }
+    };
+    if taken.contains(&short) {
+        // FAT compares names without case, which the host may not
+        return Err(invalid(format!(
+            "{}: another name differs only in case",
+            name
+        )));
+    }
+    taken.push(short);
+    Ok((short, flags, long))
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    if name.chars().any(is_forbidden_char) {
+        return Err(invalid(format!("{}: not allowed in a FAT name", name)));
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(invalid(format!(
+            "{}: FAT names are at most {} characters",
+            name, MAX_NAME_LEN
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Disk images for the kernel's filesystems, built on the host from a directory.
+//!
+//! Both filesystems the kernel can write are supported: FAT32 (`fs::fat32` in the kernel) and
+//! its native SFS (`fs::sfs`). There's no ext2, as the kernel has no driver that could read it.
+//! Nothing in an image depends on when or where it was built: directories are written in name
+//! order, every timestamp is `Options::time` and the rest of the disk is zero, so the same
+//! directory makes the same image byte for byte.
+//!
+//! The kernel's build script makes the disk the test kernels boot with this way (see `build.rs`
+//! there); the `rust_os-mkfs` command does the same by hand.
+
+/// The kernel's byte order accessors, which the on-disk layouts shared with it are written with
+#[path = "../../../src/endian.rs"]
+pub mod endian;
+pub mod fat32;
+pub mod sfs;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Sector size of the kernel's disks, which both filesystems must use as their block size
+pub const SECTOR_SIZE: usize = 512;
+
+/// Where `Tree::insert_initramfs` puts the initramfs in the image
+pub const INITRAMFS_PATH: [&str; 2] = ["boot", "initramfs"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Fat32,
+    Sfs,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "fat32" | "fat" => Some(Format::Fat32),
+            "sfs" => Some(Format::Sfs),
+            _ => None,
+        }
+    }
+
+    /// Image size when none is asked for: FAT32 needs about 33 MiB for its minimum cluster
+    /// count, SFS gets by with much less
+    pub fn default_size(self) -> u64 {
+        match self {
+            Format::Fat32 => 40 << 20,
+            Format::Sfs => 4 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub format: Format,
+    /// Bytes, rounded down to whole sectors
+    pub size: u64,
+    /// Every timestamp in the image, in seconds since the Unix epoch
+    pub time: u64,
+}
+
+impl Options {
+    /// The default size, with timestamps from `SOURCE_DATE_EPOCH` if it's set and the epoch if
+    /// it isn't
+    pub fn new(format: Format) -> Options {
+        let time = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(0);
+        Options {
+            format,
+            size: format.default_size(),
+            time,
+        }
+    }
+}
+
+/// A directory's contents, by name
+#[derive(Debug, Default)]
+pub struct Tree {
+    pub entries: BTreeMap<String, Node>,
+}
+
+#[derive(Debug)]
+pub enum Node {
+    File(Vec<u8>),
+    Directory(Tree),
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree::default()
+    }
+
+    /// Reads the directory at `path` on the host and everything below it; links are followed
+    pub fn read(path: &Path) -> io::Result<Tree> {
+        let mut tree = Tree::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                invalid(format!("{}: not a UTF-8 file name", name.to_string_lossy()))
+            })?;
+            let path = entry.path();
+            let metadata = fs::metadata(&path)?;
+            let node = if metadata.is_dir() {
+                Node::Directory(Tree::read(&path)?)
+            } else if metadata.is_file() {
+                Node::File(fs::read(&path)?)
+            } else {
+                return Err(invalid(format!(
+                    "{}: not a file or directory",
+                    path.display()
+                )));
+            };
+            tree.entries.insert(name, node);
+        }
+        Ok(tree)
+    }
+
+    /// Puts a file with `contents` at `path`, making the directories on the way
+    pub fn insert(&mut self, path: &[&str], contents: Vec<u8>) -> io::Result<()> {
+        match path {
+            [] => Err(invalid("an empty path".into())),
+            [name] => {
+                self.entries
+                    .insert(String::from(*name), Node::File(contents));
+                Ok(())
+            }
+            [directory, rest @ ..] => {
+                let node = self
+                    .entries
+                    .entry(String::from(*directory))
+                    .or_insert_with(|| Node::Directory(Tree::new()));
+                match node {
+                    Node::Directory(tree) => tree.insert(rest, contents),
+                    Node::File(_) => Err(invalid(format!("{}: not a directory", directory))),
+                }
+            }
+        }
+    }
+
+    /// Puts the initramfs at `INITRAMFS_PATH`
+    pub fn insert_initramfs(&mut self, contents: Vec<u8>) -> io::Result<()> {
+        self.insert(&INITRAMFS_PATH, contents)
+    }
+
+    /// Files and directories in the tree, this one not included
+    pub fn count(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|node| match node {
+                Node::File(_) => 1,
+                Node::Directory(tree) => 1 + tree.count(),
+            })
+            .sum()
+    }
+}
+
+/// An image of `options.size` bytes holding `tree`
+pub fn build(tree: &Tree, options: &Options) -> io::Result<Vec<u8>> {
+    let mut image = vec![0; (options.size as usize) / SECTOR_SIZE * SECTOR_SIZE];
+    match options.format {
+        Format::Fat32 => fat32::format(&mut image, tree, options.time)?,
+        Format::Sfs => sfs::format(&mut image, tree, options.time)?,
+    }
+    Ok(image)
+}
+
+/// Writes `image` to `path`, leaving holes where it's zero
+pub fn write(path: &Path, image: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.set_len(image.len() as u64)?;
+    for (index, sector) in image.chunks(SECTOR_SIZE).enumerate() {
+        if sector.iter().any(|&byte| byte != 0) {
+            file.seek(SeekFrom::Start((index * SECTOR_SIZE) as u64))?;
+            file.write_all(sector)?;
+        }
+    }
+    file.sync_all()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// An error for what doesn't fit in the image
+fn no_space(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::StorageFull,
+        format!("the image is too small for {}", what),
+    )
+}
@@ -0,0 +1,53 @@
+//! `rust_os-mkfs <fat32|sfs> <image> [--size <MiB>] [--from <directory>] [--initramfs <file>]`
+//!
+//! Creates `image` with an empty filesystem, or one holding what's in `directory`, and the
+//! initramfs at `/boot/initramfs` if there's one. Timestamps come from `SOURCE_DATE_EPOCH`.
+
+use mkfs::{Format, Options, Tree};
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+const USAGE: &str =
+    "usage: rust_os-mkfs <fat32|sfs> <image> [--size <MiB>] [--from <directory>] [--initramfs <file>]";
+
+fn main() {
+    if let Err(err) = run(env::args().skip(1).collect()) {
+        eprintln!("rust_os-mkfs: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let (format, image, flags) = match args.as_slice() {
+        [format, image, flags @ ..] => (format, PathBuf::from(image), flags),
+        _ => return Err(USAGE.into()),
+    };
+    let format = Format::parse(format).ok_or("the formats are fat32 and sfs")?;
+    let mut options = Options::new(format);
+    let mut from = None;
+    let mut initramfs = None;
+    for pair in flags.chunks(2) {
+        match pair {
+            [flag, size] if flag == "--size" => {
+                let mib: u64 = size.parse().map_err(|_| "the size is in MiB")?;
+                options.size = mib << 20;
+            }
+            [flag, directory] if flag == "--from" => from = Some(directory),
+            [flag, file] if flag == "--initramfs" => initramfs = Some(file),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let mut tree = match from {
+        Some(directory) => {
+            Tree::read(directory.as_ref()).map_err(|err| format!("{}: {}", directory, err))?
+        }
+        None => Tree::new(),
+    };
+    if let Some(file) = initramfs {
+        let contents = fs::read(file).map_err(|err| format!("{}: {}", file, err))?;
+        tree.insert_initramfs(contents)
+            .map_err(|err| err.to_string())?;
+    }
+    let bytes = mkfs::build(&tree, &options).map_err(|err| err.to_string())?;
+    mkfs::write(&image, &bytes).map_err(|err| format!("{}: {}", image.display(), err))
+}
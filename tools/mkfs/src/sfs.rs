@@ -0,0 +1,133 @@
+//! SFS images, laid out like the kernel's `fs::sfs::Sfs::format` lays them out; the format is
+//! described there.
+//!
+//! The journal is left empty, so the kernel mounts the image as if it had been shut down
+//! cleanly. Every file and directory is one extent, placed one after the other from the start of
+//! the data area.
+
+use super::{invalid, no_space, Node, Tree, SECTOR_SIZE};
+use journal::{encode_superblock, FIRST_SEQUENCE};
+use layout::{
+    encode_entry, Extent, Inode, Layout, DIR_ENTRY_SIZE, INODE_SIZE, KIND_DIRECTORY, KIND_FILE,
+    MAX_NAME_LEN, ROOT_INODE,
+};
+use std::io;
+
+/// The kernel's SFS format, shared so the two can't drift apart; public so images can be read
+/// back with it
+#[path = "../../../src/fs/sfs/layout.rs"]
+pub mod layout;
+
+/// The kernel's journal superblock, which an image starts with
+#[path = "../../../src/block/journal/layout.rs"]
+pub mod journal;
+
+/// Bytes of disk per inode, like mke2fs's default ratio, so the kernel has inodes to spare
+const BYTES_PER_INODE: u64 = 16 * 1024;
+
+const BLOCK_SIZE: u64 = SECTOR_SIZE as u64;
+
+struct Writer<'a> {
+    image: &'a mut [u8],
+    layout: Layout,
+    next_inode: u64,
+    next_block: u64,
+    time: u64,
+}
+
+/// Formats `image` as SFS holding `tree`
+pub fn format(image: &mut [u8], tree: &Tree, time: u64) -> io::Result<()> {
+    let block_count = image.len() as u64 / BLOCK_SIZE;
+    let inode_count = (tree.count() + 1).max(image.len() as u64 / BYTES_PER_INODE);
+    let layout = Layout::new(BLOCK_SIZE, block_count, inode_count)
+        .ok_or_else(|| no_space("the filesystem's metadata"))?;
+    layout.encode(&mut image[..SECTOR_SIZE]);
+
+    // the journal's superblock; its descriptor block stays zero, so there's nothing to replay
+    let journal = (layout.journal_start * BLOCK_SIZE) as usize;
+    encode_superblock(
+        &mut image[journal..journal + SECTOR_SIZE],
+        layout.journal_len,
+        FIRST_SEQUENCE,
+    );
+
+    let mut writer = Writer {
+        image,
+        layout,
+        next_inode: ROOT_INODE + 1,
+        next_block: layout.data_start,
+        time,
+    };
+    writer.directory(ROOT_INODE, tree)?;
+
+    // the metadata and everything written are in use, the rest of the disk is free
+    let bitmap = &mut writer.image[(layout.bitmap_start * BLOCK_SIZE) as usize..];
+    for block in 0..writer.next_block as usize {
+        bitmap[block / 8] |= 1 << (block % 8);
+    }
+    Ok(())
+}
+
+impl Writer<'_> {
+    /// Writes the entries of `tree`, then the directory itself as inode `inode`
+    fn directory(&mut self, inode: u64, tree: &Tree) -> io::Result<()> {
+        let mut entries = vec![0; tree.entries.len() * DIR_ENTRY_SIZE];
+        for ((name, node), entry) in tree.entries.iter().zip(entries.chunks_mut(DIR_ENTRY_SIZE)) {
+            if name.len() > MAX_NAME_LEN {
+                return Err(invalid(format!(
+                    "{}: SFS names are at most {} bytes",
+                    name, MAX_NAME_LEN
+                )));
+            }
+            if self.next_inode > self.layout.inode_count {
+                return Err(no_space("that many files"));
+            }
+            let child = self.next_inode;
+            self.next_inode += 1;
+            let kind = match node {
+                Node::File(contents) => {
+                    self.inode(child, KIND_FILE, contents)?;
+                    KIND_FILE
+                }
+                Node::Directory(tree) => {
+                    self.directory(child, tree)?;
+                    KIND_DIRECTORY
+                }
+            };
+            encode_entry(entry, child, kind, name);
+        }
+        self.inode(inode, KIND_DIRECTORY, &entries)
+    }
+
+    /// Writes `contents` to the next free blocks and inode `inode` describing them
+    fn inode(&mut self, inode: u64, kind: u16, contents: &[u8]) -> io::Result<()> {
+        let blocks = (contents.len() as u64).div_ceil(BLOCK_SIZE);
+        let start = self.next_block;
+        if start + blocks > self.layout.block_count || blocks > u32::MAX as u64 {
+            return Err(no_space("the files"));
+        }
+        let offset = (start * BLOCK_SIZE) as usize;
+        self.image[offset..offset + contents.len()].copy_from_slice(contents);
+        self.next_block += blocks;
+
+        let mut node = Inode {
+            kind,
+            size: contents.len() as u64,
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+            ..Inode::default()
+        };
+        if blocks > 0 {
+            node.extents[0] = Extent {
+                start,
+                len: blocks as u32,
+            };
+            node.extent_count = 1;
+        }
+        let (block, offset) = self.layout.inode_location(inode);
+        let offset = (block * BLOCK_SIZE) as usize + offset;
+        node.encode(&mut self.image[offset..offset + INODE_SIZE]);
+        Ok(())
+    }
+}
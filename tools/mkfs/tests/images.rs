@@ -0,0 +1,450 @@
+//! Builds images of a small tree and reads them back with the kernel's own on-disk definitions,
+//! which mkfs shares with it: every file has to come back with its contents and name, and the
+//! volume's bookkeeping has to agree with what was written.
+
+use mkfs::endian::{read_u16_le, read_u32_le};
+use mkfs::fat32::layout::{
+    FSINFO_FREE_COUNT, FSINFO_LEAD_SIGNATURE, FSINFO_NEXT_FREE, FSINFO_STRUCT_OFFSET,
+    FSINFO_STRUCT_SIGNATURE,
+};
+use mkfs::fat32::names::{
+    short_name_checksum, unix_to_fat, ATTR_LONG_NAME, ENTRY_SIZE, LFN_CHAR_OFFSETS, LFN_LAST,
+    LOWERCASE_BASE, LOWERCASE_EXT, SLOT_FREE,
+};
+use mkfs::sfs::journal::{decode_superblock, FIRST_SEQUENCE};
+use mkfs::sfs::layout::{
+    decode_entry, entry_kind, Inode, Layout, DIR_ENTRY_SIZE, INODE_SIZE, KIND_DIRECTORY, KIND_FILE,
+    ROOT_INODE,
+};
+use mkfs::{Format, Options, Tree, SECTOR_SIZE};
+use std::io;
+
+/// 2024-02-29 12:34:56 UTC
+const TIME: u64 = 1_709_210_096;
+
+const LONG_CONTENTS: &[u8] = b"A file whose name needs a long name entry on FAT.\n";
+
+/// The tree every test builds: the test disk's files plus one spanning several sectors and a
+/// second long name that maps to the same short name basis
+fn tree() -> Tree {
+    let mut tree = Tree::new();
+    tree.insert(&["hello.txt"], b"Hello from the host!\n".to_vec())
+        .unwrap();
+    tree.insert(&["docs", "Long file name.txt"], LONG_CONTENTS.to_vec())
+        .unwrap();
+    tree.insert(&["docs", "Long file name 2.txt"], b"two".to_vec())
+        .unwrap();
+    tree.insert(&["docs", "empty"], Vec::new()).unwrap();
+    tree.insert(&["big.bin"], big_contents()).unwrap();
+    tree
+}
+
+fn big_contents() -> Vec<u8> {
+    (0..3 * SECTOR_SIZE + 100)
+        .map(|i| (i % 251) as u8)
+        .collect()
+}
+
+fn build(format: Format, tree: &Tree) -> io::Result<Vec<u8>> {
+    let options = Options {
+        format,
+        size: format.default_size(),
+        time: TIME,
+    };
+    mkfs::build(tree, &options)
+}
+
+// --- FAT32 -----------------------------------------------------------------------------------
+
+struct FatEntry {
+    name: String,
+    short: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    date: u16,
+}
+
+/// Just enough of a FAT32 driver to list directories and read files
+struct Fat<'a> {
+    image: &'a [u8],
+    fat_start: usize,
+    data_start: usize,
+    cluster_size: usize,
+    root_cluster: u32,
+}
+
+impl<'a> Fat<'a> {
+    fn new(image: &'a [u8]) -> Fat<'a> {
+        assert_eq!(&image[510..512], &[0x55, 0xAA], "boot sector signature");
+        let sector = read_u16_le(image, 11) as usize;
+        assert_eq!(sector, SECTOR_SIZE);
+        let reserved = read_u16_le(image, 14) as usize;
+        let fats = image[16] as usize;
+        let fat_sectors = read_u32_le(image, 36) as usize;
+        Fat {
+            image,
+            fat_start: reserved * sector,
+            data_start: (reserved + fats * fat_sectors) * sector,
+            cluster_size: image[13] as usize * sector,
+            root_cluster: read_u32_le(image, 44),
+        }
+    }
+
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while (2..0x0FFF_FFF8).contains(&cluster) {
+            chain.push(cluster);
+            cluster = read_u32_le(self.image, self.fat_start + cluster as usize * 4) & 0x0FFF_FFFF;
+        }
+        chain
+    }
+
+    fn read(&self, first: u32, size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for cluster in self.chain(first) {
+            let offset = self.data_start + (cluster as usize - 2) * self.cluster_size;
+            data.extend_from_slice(&self.image[offset..offset + self.cluster_size]);
+        }
+        assert!(data.len() >= size, "chain shorter than the file");
+        data.truncate(size);
+        data
+    }
+
+    /// The entries of the directory at `cluster`, `.` and `..` left out, checking that each long
+    /// name belongs to the short entry after it
+    fn entries(&self, cluster: u32) -> Vec<FatEntry> {
+        let data = self.read(cluster, self.chain(cluster).len() * self.cluster_size);
+        let mut entries = Vec::new();
+        let mut long: Vec<&[u8]> = Vec::new();
+        for slot in data.chunks_exact(ENTRY_SIZE) {
+            if slot[0] == 0 {
+                break;
+            }
+            if slot[0] == SLOT_FREE {
+                continue;
+            }
+            if slot[11] == ATTR_LONG_NAME {
+                long.push(slot);
+                continue;
+            }
+            let short: [u8; 11] = slot[..11].try_into().unwrap();
+            let name = if long.is_empty() {
+                short_name(&short, slot[12])
+            } else {
+                assert_ne!(long[0][0] & LFN_LAST, 0, "first long entry not marked last");
+                let mut units = Vec::new();
+                for part in long.iter().rev() {
+                    assert_eq!(part[13], short_name_checksum(&short), "long name checksum");
+                    for &at in &LFN_CHAR_OFFSETS {
+                        units.push(read_u16_le(part, at));
+                    }
+                }
+                let end = units
+                    .iter()
+                    .position(|&unit| unit == 0)
+                    .unwrap_or(units.len());
+                String::from_utf16(&units[..end]).unwrap()
+            };
+            long.clear();
+            if name == "." || name == ".." {
+                continue;
+            }
+            entries.push(FatEntry {
+                name,
+                short,
+                attributes: slot[11],
+                first_cluster: (read_u16_le(slot, 20) as u32) << 16 | read_u16_le(slot, 26) as u32,
+                size: read_u32_le(slot, 28),
+                date: read_u16_le(slot, 24),
+            });
+        }
+        assert!(long.is_empty(), "long name entries without a short entry");
+        entries
+    }
+
+    fn lookup(&self, path: &[&str]) -> FatEntry {
+        let (name, parents) = path.split_last().unwrap();
+        let mut cluster = self.root_cluster;
+        for parent in parents {
+            cluster = self.find(cluster, parent).first_cluster;
+        }
+        self.find(cluster, name)
+    }
+
+    fn find(&self, cluster: u32, name: &str) -> FatEntry {
+        self.entries(cluster)
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .unwrap_or_else(|| panic!("{} not found", name))
+    }
+}
+
+/// A short name as it's shown: the padding dropped and the case the NT flags ask for
+fn short_name(short: &[u8; 11], flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let text = String::from_utf8_lossy(bytes).trim_end().to_string();
+        if lower {
+            text.to_lowercase()
+        } else {
+            text
+        }
+    };
+    let base = part(&short[..8], flags & LOWERCASE_BASE != 0);
+    let ext = part(&short[8..], flags & LOWERCASE_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+#[test]
+fn fat32_files_read_back() {
+    let image = build(Format::Fat32, &tree()).unwrap();
+    let fat = Fat::new(&image);
+
+    let hello = fat.lookup(&["hello.txt"]);
+    assert_eq!(&hello.short, b"HELLO   TXT");
+    assert_eq!(
+        fat.read(hello.first_cluster, hello.size as usize),
+        b"Hello from the host!\n"
+    );
+    let big = fat.lookup(&["big.bin"]);
+    assert_eq!(
+        fat.read(big.first_cluster, big.size as usize),
+        big_contents()
+    );
+
+    let docs = fat.lookup(&["docs"]);
+    assert_eq!(docs.attributes & 0x10, 0x10, "docs is a directory");
+    let mut names: Vec<String> = fat
+        .entries(docs.first_cluster)
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["Long file name 2.txt", "Long file name.txt", "empty"]
+    );
+
+    let long = fat.lookup(&["docs", "Long file name.txt"]);
+    assert_eq!(
+        fat.read(long.first_cluster, long.size as usize),
+        LONG_CONTENTS
+    );
+    let second = fat.lookup(&["docs", "Long file name 2.txt"]);
+    let mut aliases = [long.short, second.short];
+    aliases.sort();
+    assert_eq!(aliases, [*b"LONGFI~1TXT", *b"LONGFI~2TXT"]);
+
+    let empty = fat.lookup(&["docs", "empty"]);
+    assert_eq!((empty.first_cluster, empty.size), (0, 0));
+    assert_eq!(empty.date, unix_to_fat(TIME).0);
+}
+
+#[test]
+fn fat32_fsinfo_and_backup_agree_with_the_fat() {
+    let image = build(Format::Fat32, &tree()).unwrap();
+    let fat = Fat::new(&image);
+    let info = &image[read_u16_le(&image, 48) as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+    assert_eq!(read_u32_le(info, 0), FSINFO_LEAD_SIGNATURE);
+    assert_eq!(
+        read_u32_le(info, FSINFO_STRUCT_OFFSET),
+        FSINFO_STRUCT_SIGNATURE
+    );
+
+    // clusters are handed out in order, so the hint is the first free one
+    let next_free = read_u32_le(info, FSINFO_NEXT_FREE);
+    let entry = |cluster: u32| read_u32_le(&image, fat.fat_start + cluster as usize * 4);
+    assert_ne!(entry(next_free - 1), 0);
+    assert_eq!(entry(next_free), 0);
+    let total = read_u32_le(&image, 32) as usize;
+    let clusters = (total * SECTOR_SIZE - fat.data_start) / fat.cluster_size;
+    assert_eq!(
+        read_u32_le(info, FSINFO_FREE_COUNT) as usize,
+        clusters - (next_free as usize - 2)
+    );
+
+    let backup = read_u16_le(&image, 50) as usize * SECTOR_SIZE;
+    assert_eq!(
+        image[..2 * SECTOR_SIZE],
+        image[backup..backup + 2 * SECTOR_SIZE]
+    );
+}
+
+#[test]
+fn fat32_images_are_reproducible() {
+    assert_eq!(
+        build(Format::Fat32, &tree()).unwrap(),
+        build(Format::Fat32, &tree()).unwrap()
+    );
+}
+
+#[test]
+fn fat32_refuses_what_it_cant_hold() {
+    let mut clash = Tree::new();
+    clash.insert(&["a.txt"], Vec::new()).unwrap();
+    clash.insert(&["A.TXT"], Vec::new()).unwrap();
+    let err = build(Format::Fat32, &clash).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let small = Options {
+        format: Format::Fat32,
+        size: 16 << 20,
+        time: TIME,
+    };
+    assert!(mkfs::build(&tree(), &small).is_err());
+}
+
+// --- SFS -------------------------------------------------------------------------------------
+
+/// Just enough of an SFS driver to list directories and read files
+struct Sfs<'a> {
+    image: &'a [u8],
+    layout: Layout,
+}
+
+impl<'a> Sfs<'a> {
+    fn new(image: &'a [u8]) -> Sfs<'a> {
+        let layout = Layout::decode(image).expect("no SFS superblock");
+        assert_eq!(layout.block_size as usize, SECTOR_SIZE);
+        Sfs { image, layout }
+    }
+
+    fn inode(&self, inode: u64) -> Inode {
+        let (block, offset) = self.layout.inode_location(inode);
+        let offset = (block * self.layout.block_size) as usize + offset;
+        Inode::decode(&self.image[offset..offset + INODE_SIZE]).expect("bad inode")
+    }
+
+    fn read(&self, node: &Inode) -> Vec<u8> {
+        let mut data = Vec::new();
+        for extent in node.extents() {
+            let start = (extent.start * self.layout.block_size) as usize;
+            let len = extent.len as usize * self.layout.block_size as usize;
+            data.extend_from_slice(&self.image[start..start + len]);
+        }
+        data.truncate(node.size as usize);
+        data
+    }
+
+    /// Names, inode numbers and kinds of the entries of directory `inode`
+    fn entries(&self, inode: u64) -> Vec<(String, u64, u16)> {
+        let node = self.inode(inode);
+        assert_eq!(node.kind, KIND_DIRECTORY);
+        self.read(&node)
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .filter_map(|entry| {
+                let (inode, name) = decode_entry(entry)?;
+                Some((name.to_string(), inode, entry_kind(entry)))
+            })
+            .collect()
+    }
+
+    fn lookup(&self, path: &[&str]) -> Inode {
+        let mut inode = ROOT_INODE;
+        for name in path {
+            inode = self
+                .entries(inode)
+                .into_iter()
+                .find(|(entry, _, _)| entry == name)
+                .unwrap_or_else(|| panic!("{} not found", name))
+                .1;
+        }
+        self.inode(inode)
+    }
+}
+
+#[test]
+fn sfs_files_read_back() {
+    let image = build(Format::Sfs, &tree()).unwrap();
+    let sfs = Sfs::new(&image);
+
+    let names: Vec<(String, u16)> = sfs
+        .entries(ROOT_INODE)
+        .into_iter()
+        .map(|(name, _, kind)| (name, kind))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            ("big.bin".to_string(), KIND_FILE),
+            ("docs".to_string(), KIND_DIRECTORY),
+            ("hello.txt".to_string(), KIND_FILE),
+        ]
+    );
+    assert_eq!(
+        sfs.read(&sfs.lookup(&["hello.txt"])),
+        b"Hello from the host!\n"
+    );
+    assert_eq!(sfs.read(&sfs.lookup(&["big.bin"])), big_contents());
+    let long = sfs.lookup(&["docs", "Long file name.txt"]);
+    assert_eq!(sfs.read(&long), LONG_CONTENTS);
+    assert_eq!((long.atime, long.mtime, long.ctime), (TIME, TIME, TIME));
+    let empty = sfs.lookup(&["docs", "empty"]);
+    assert_eq!(
+        (empty.kind, empty.size, empty.extent_count),
+        (KIND_FILE, 0, 0)
+    );
+}
+
+#[test]
+fn sfs_journal_is_empty_and_bitmap_covers_what_was_written() {
+    let image = build(Format::Sfs, &tree()).unwrap();
+    let sfs = Sfs::new(&image);
+    let layout = sfs.layout;
+    let block = |index: u64| {
+        let start = (index * layout.block_size) as usize;
+        &image[start..start + layout.block_size as usize]
+    };
+    assert_eq!(
+        decode_superblock(block(layout.journal_start), layout.journal_len),
+        Some(FIRST_SEQUENCE)
+    );
+    assert!(
+        block(layout.journal_start + 1)
+            .iter()
+            .all(|&byte| byte == 0),
+        "a descriptor block would be replayed"
+    );
+
+    let mut used = vec![false; layout.block_count as usize];
+    used[..layout.data_start as usize].fill(true);
+    for inode in 1..=layout.inode_count {
+        for extent in sfs.inode(inode).extents() {
+            for index in extent.start..extent.start + extent.len as u64 {
+                assert!(!used[index as usize], "block {} used twice", index);
+                used[index as usize] = true;
+            }
+        }
+    }
+    let bitmap = &image[(layout.bitmap_start * layout.block_size) as usize..];
+    for (index, &used) in used.iter().enumerate() {
+        assert_eq!(
+            bitmap[index / 8] & 1 << (index % 8) != 0,
+            used,
+            "bitmap bit of block {}",
+            index
+        );
+    }
+}
+
+#[test]
+fn sfs_images_are_reproducible() {
+    assert_eq!(
+        build(Format::Sfs, &tree()).unwrap(),
+        build(Format::Sfs, &tree()).unwrap()
+    );
+}
+
+#[test]
+fn sfs_refuses_names_longer_than_an_entry_holds() {
+    let mut tree = Tree::new();
+    tree.insert(&["x".repeat(100).as_str()], Vec::new())
+        .unwrap();
+    let err = build(Format::Sfs, &tree).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
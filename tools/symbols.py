@@ -0,0 +1,153 @@
+#!/usr/bin/env python3
+"""Fills the kernel's `.symbols` section with its function symbols, see src/symbols.rs.
+
+Usage: symbols.py <kernel ELF>
+
+The ELF is patched in place. Only the section's contents change, so running it again after a
+rebuild, or on an image that was patched already, is fine.
+"""
+
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+SECTION = b".symbols"
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QIIII")
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+ESCAPES = {
+    "SP": "@", "BP": "*", "RF": "&", "LT": "<", "GT": ">", "LP": "(", "RP": ")", "C": ",",
+}
+
+
+def sections(elf):
+    """(name, type, address, offset, size, link) of every section"""
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = [
+        struct.unpack_from("<IIQQQQII", elf, shoff + index * shentsize)
+        for index in range(shnum)
+    ]
+    names = headers[shstrndx][4]
+    result = []
+    for name, kind, _flags, address, offset, size, link, _info in headers:
+        end = elf.index(b"\0", names + name)
+        result.append((bytes(elf[names + name:end]), kind, address, offset, size, link))
+    return result
+
+
+def unescape(component):
+    """Undoes the escapes of the legacy mangling within one path component"""
+    if component.startswith("_$"):
+        component = component[1:]
+
+    def replace(match):
+        code = match.group(1)
+        if code.startswith("u"):
+            return chr(int(code[1:], 16))
+        return ESCAPES.get(code, match.group(0))
+
+    return re.sub(r"\$([A-Za-z0-9]+)\$", replace, component).replace("..", "::")
+
+
+def demangle(name):
+    """Demangles a Rust legacy symbol, `_ZN...E`, dropping the hash; other names are kept"""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    components = []
+    rest = name[3:-1]
+    while rest:
+        digits = re.match(r"\d+", rest)
+        if not digits:
+            return name
+        length = int(digits.group(0))
+        start = digits.end()
+        components.append(rest[start:start + length])
+        rest = rest[start + length:]
+    if re.fullmatch(r"h[0-9a-f]{16}", components[-1]):
+        components.pop()
+    return "::".join(unescape(component) for component in components)
+
+
+def demangle_v0(names):
+    """Demangles v0 symbols, `_R...`, with binutils' c++filt when it's installed, dropping the
+    crate hashes; without it they stay mangled"""
+    if not names or shutil.which("c++filt") is None:
+        return names
+    output = subprocess.run(
+        ["c++filt"], input="\n".join(names), capture_output=True, text=True, check=True
+    ).stdout
+    return [re.sub(r"\[[0-9a-f]+\]", "", name) for name in output.splitlines()]
+
+
+def functions(elf, all_sections):
+    """(start, size, name) of every function with a size, sorted by start"""
+    symbols = {}
+    for _name, kind, _address, offset, size, link in all_sections:
+        if kind != SHT_SYMTAB:
+            continue
+        strings = all_sections[link][3]
+        for index in range(size // 24):
+            name, info, _other, _shndx, value, length = struct.unpack_from(
+                "<IBBHQQ", elf, offset + index * 24
+            )
+            if info & 0xF != STT_FUNC or length == 0:
+                continue
+            end = elf.index(b"\0", strings + name)
+            symbols.setdefault(value, (length, demangle(elf[strings + name:end].decode())))
+    v0 = [start for start, (_length, name) in symbols.items() if name.startswith("_R")]
+    for start, name in zip(v0, demangle_v0([symbols[start][1] for start in v0])):
+        symbols[start] = (symbols[start][0], name)
+    return sorted((start, length, name) for start, (length, name) in symbols.items())
+
+
+def build(symbols):
+    names = bytearray()
+    entries = bytearray()
+    for start, size, name in symbols:
+        encoded = name.encode()
+        entries += ENTRY.pack(start, min(size, 0xFFFFFFFF), len(names), len(encoded), 0)
+        names += encoded
+    names_offset = HEADER.size + len(entries)
+    used = names_offset + len(names)
+    return HEADER.pack(MAGIC, len(symbols), names_offset, used) + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__.strip())
+    path = sys.argv[1]
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit(f"{path}: not a 64-bit ELF")
+
+    all_sections = sections(elf)
+    section = next((s for s in all_sections if s[0] == SECTION), None)
+    if section is None:
+        sys.exit(f"{path}: no {SECTION.decode()} section")
+    _name, _kind, _address, offset, size, _link = section
+    if elf[offset:offset + len(MAGIC)] != MAGIC:
+        sys.exit(f"{path}: the {SECTION.decode()} section doesn't start with the table's magic")
+
+    symbols = functions(elf, all_sections)
+    table = build(symbols)
+    if len(table) > size:
+        sys.exit(
+            f"{path}: the table takes {len(table)} bytes, the section has {size}; "
+            "raise symbols::CAPACITY"
+        )
+    elf[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as file:
+        file.write(elf)
+    print(f"{path}: {len(symbols)} functions, {len(table)} of {size} bytes")
+
+
+if __name__ == "__main__":
+    main()